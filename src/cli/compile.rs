@@ -0,0 +1,52 @@
+use std::time::Instant;
+
+use rdns::server::zonedb::compile;
+use rdns::server::zonefile::load_zone_file;
+
+/// `rdns compile-zone ZONEFILE OUTPUT [--origin NAME]`
+/// Compiles a master file into a database `rdns serve --zone-db` maps at startup.
+pub fn run(args: &[String]) -> i32 {
+	let mut origin = String::new();
+	let mut positional = Vec::new();
+
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		match arg.as_str() {
+			"--origin" => match iter.next() {
+				Some(value) => origin = value.clone(),
+				None => {
+					eprintln!("--origin needs a name");
+					return 2;
+				}
+			},
+			_ => positional.push(arg.as_str()),
+		}
+	}
+
+	let (input, output) = match positional.as_slice() {
+		[input, output] => (*input, *output),
+		_ => {
+			eprintln!("Usage: rdns compile-zone ZONEFILE OUTPUT [--origin NAME]");
+			return 2;
+		}
+	};
+
+	let start = Instant::now();
+	let records = match load_zone_file(input, &origin) {
+		Ok(records) => records,
+		Err(e) => {
+			eprintln!("Failed to parse {}: {}", input, e);
+			return 1;
+		}
+	};
+	match compile(&records, output) {
+		Ok(rrsets) => {
+			println!("Compiled {} records in {} RRsets to {} in {} ms", records.len(), rrsets, output, start.elapsed().as_millis());
+			0
+		}
+		Err(e) => {
+			eprintln!("Failed to compile {}: {}", input, e);
+			1
+		}
+	}
+}
@@ -0,0 +1,467 @@
+//! The configuration file of `rdns serve`
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::{ IpAddr, SocketAddr };
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// Everything `rdns serve` can be set up with, read from a TOML file. The keys are named after
+/// the command line options, grouped in sections:
+///
+/// ```toml
+/// [server]
+/// listen = "0.0.0.0:53"
+/// threads = 8
+/// tls-listen = "0.0.0.0"
+///
+/// [tls]
+/// cert = "/etc/rdns/cert.pem"
+/// key = "/etc/rdns/key.pem"
+///
+/// [upstreams]
+/// servers = ["tls://9.9.9.9#dns.quad9.net", "https://1.1.1.1#cloudflare-dns.com"]
+/// strategy = "fastest"
+///
+/// [cache]
+/// size = 200000
+/// prefetch = 10
+///
+/// [zones]
+/// files = ["/etc/rdns/home.lan.zone"]
+///
+/// [internal-overrides]
+/// "nas.example.net" = ["192.168.1.10"]
+///
+/// [views.office]
+/// networks = ["10.0.0.0/8"]
+/// zones = ["/etc/rdns/corp.internal.zone"]
+/// ```
+///
+/// Keys which aren't known and values of the wrong type are rejected with their line, the
+/// values themselves are checked like those of the options they stand for.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+	pub server: ServerSection,
+	pub tls: TlsSection,
+	pub upstreams: UpstreamSection,
+	pub cache: CacheSection,
+	pub zones: ZoneSection,
+	pub geoip: GeoIpSection,
+	pub mdns: MdnsSection,
+	pub limits: LimitSection,
+	pub logging: LoggingSection,
+	pub capture: CaptureSection,
+	pub control: ControlSection,
+	pub address_filters: AddressFilterSection,
+	pub acl: AclSection,
+	pub hosts: HostsSection,
+	pub blocklists: BlocklistSection,
+	pub doctoring: DoctoringSection,
+	pub policies: PolicySection,
+	pub internal_overrides: BTreeMap<String, Vec<IpAddr>>,
+	pub views: BTreeMap<String, ViewSection>,
+}
+
+/// Listeners, threads and connections.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServerSection {
+	pub listen: Option<SocketAddr>,
+	pub listeners: Vec<String>,
+	pub tls_listen: Option<String>,
+	pub https_listen: Option<String>,
+	pub https_path: Option<String>,
+	pub quic_listen: Option<String>,
+	pub admin_listen: Option<String>,
+	pub non_recursive: Option<String>,
+	pub threads: Option<usize>,
+	pub udp_sockets: Option<usize>,
+	pub tcp_max_connections: Option<usize>,
+	pub tcp_idle_timeout: Option<u64>,
+	pub edns_max_payload: Option<u16>,
+	pub root_hints: Option<String>,
+	pub qname_minimization: Option<bool>,
+	pub minimal_responses: bool,
+	pub no_compression: Vec<String>,
+	pub crash_on_panic: bool,
+	pub shutdown_timeout: Option<u64>,
+}
+
+/// Certificate of the TLS, HTTPS and QUIC listeners.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TlsSection {
+	pub cert: Option<String>,
+	pub key: Option<String>,
+}
+
+/// Servers queries are forwarded to.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct UpstreamSection {
+	pub servers: Vec<String>,
+	pub strategy: Option<String>,
+	pub tls_policy: Option<String>,
+	pub health_interval: Option<u64>,
+	pub timeout_ms: Option<u64>,
+	pub attempts: Option<u32>,
+	pub deadline_ms: Option<u64>,
+	pub backoff_ms: Option<u64>,
+	pub pass_through: bool,
+	pub mirror: Option<String>,
+	pub mirror_percent: Option<f64>,
+}
+
+/// Size, TTLs, prefetching and snapshots of the cache.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CacheSection {
+	pub size: Option<usize>,
+	pub memory: Option<usize>,
+	pub hot_entries: Option<usize>,
+	pub file: Option<String>,
+	pub snapshot_interval: Option<u64>,
+	pub min_ttl: Option<u32>,
+	pub max_ttl: Option<u32>,
+	pub max_negative_ttl: Option<u32>,
+	pub max_stale: Option<u64>,
+	pub critical_names: Vec<String>,
+	pub prefetch: Option<u32>,
+	pub prefetch_min_hits: Option<u32>,
+}
+
+/// Local zones, from master files and compiled databases, and the records scheduled in them.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ZoneSection {
+	pub files: Vec<String>,
+	pub databases: Vec<String>,
+	pub schedules: Vec<String>,
+	pub rotate: Vec<String>,
+	pub answer_weights: Vec<String>,
+	pub health_checks: Vec<String>,
+	pub strict: bool,
+	pub delegation_check_interval: Option<u64>,
+	pub publish_delegations: bool,
+}
+
+/// Records served by where the clients are, and the database telling it.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GeoIpSection {
+	pub database: Option<String>,
+	pub records: Vec<String>,
+}
+
+/// Hosts of the .local domain and their DNS-SD services announced over multicast DNS.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MdnsSection {
+	pub hosts: Vec<String>,
+	pub interface: Option<String>,
+	pub services: Vec<String>,
+}
+
+/// Sanity limits on the responses received and sent, and the rate limit of the clients.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LimitSection {
+	pub max_answers: Option<usize>,
+	pub max_response_ttl: Option<u32>,
+	pub max_cname_chain: Option<usize>,
+	pub max_amplification: Option<f64>,
+	pub rate_limit: Option<f64>,
+	pub rate_limit_burst: Option<u32>,
+	pub rate_limit_ipv6_prefix: Option<u8>,
+	pub rate_limit_action: Option<String>,
+	pub rrl: Option<f64>,
+	pub rrl_slip: Option<u32>,
+}
+
+/// Verbosity, slow queries, the latency SLO and the query log.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LoggingSection {
+	pub verbosity: Option<String>,
+	pub slow_query_ms: Option<u64>,
+	pub slo_latency_ms: Option<u64>,
+	pub slo_objective: Option<f64>,
+	pub query_log: Option<String>,
+	pub query_log_percent: Option<f64>,
+	pub trace: Option<String>,
+	pub trace_file: Option<String>,
+}
+
+/// Capture of the queries and responses to a pcapng file.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CaptureSection {
+	pub file: Option<String>,
+	pub filter: Option<String>,
+	pub duration: Option<u64>,
+}
+
+/// Networks the A or AAAA records are withheld from.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AddressFilterSection {
+	pub aaaa: Vec<String>,
+	pub a: Vec<String>,
+}
+
+/// Networks allowed to query, to recurse and to transfer zones.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AclSection {
+	pub query: Vec<String>,
+	pub recursion: Vec<String>,
+	pub transfer: Vec<String>,
+}
+
+/// Names answered with the addresses given, from a hosts file and the entries.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HostsSection {
+	pub file: Option<String>,
+	pub entries: Vec<String>,
+}
+
+/// Blocklists of domains, and how their names are answered.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct BlocklistSection {
+	pub files: Vec<String>,
+	pub action: Option<String>,
+}
+
+/// Addresses of the hosts behind the NAT, and the networks inside it.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DoctoringSection {
+	pub pairs: Vec<String>,
+	pub inside: Vec<String>,
+}
+
+/// A split-horizon view: the networks of its clients, and the zones, upstreams and hosts
+/// they're answered from.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ViewSection {
+	pub networks: Vec<String>,
+	pub zones: Vec<String>,
+	pub forward: Vec<String>,
+	pub strategy: Option<String>,
+	pub hosts: Vec<String>,
+}
+
+/// Policies on the queries of the clients.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PolicySection {
+	pub names: Vec<String>,
+	pub detect_tunnels: bool,
+	pub tunnel_throttle: Option<f64>,
+}
+
+/// Control channel of the running server.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ControlSection {
+	pub listen: Option<String>,
+	pub key_file: Option<String>,
+}
+
+/// A setting of the file as the command line option it stands for, `value` being None for
+/// switches.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigOption {
+	/// Where it's set in the file, like `cache.size`...
+	pub key: String,
+	pub option: &'static str,
+	pub value: Option<String>,
+}
+
+impl Config {
+	/// Read and parse the file at `path`.
+	pub fn load(path: &Path) -> Result<Config> {
+		let text = fs::read_to_string(path)?;
+		toml::from_str(&text).map_err(|e| Error::new(ErrorKind::InvalidData, e.message().trim().replace('\n', ", ") + &location(&text, e.span())))
+	}
+
+	/// The settings of the file, in the order of their sections.
+	pub fn options(&self) -> Vec<ConfigOption> {
+		let mut options = Options::default();
+
+		let server = &self.server;
+		options.value("server.listen", "--listen", &server.listen);
+		options.values("server.listeners", "--listener", &server.listeners);
+		options.value("server.tls-listen", "--tls-listen", &server.tls_listen);
+		options.value("server.https-listen", "--https-listen", &server.https_listen);
+		options.value("server.https-path", "--https-path", &server.https_path);
+		options.value("server.quic-listen", "--quic-listen", &server.quic_listen);
+		options.value("server.admin-listen", "--admin-listen", &server.admin_listen);
+		options.value("server.non-recursive", "--non-recursive", &server.non_recursive);
+		options.value("server.threads", "--threads", &server.threads);
+		options.value("server.udp-sockets", "--udp-sockets", &server.udp_sockets);
+		options.value("server.tcp-max-connections", "--tcp-max-connections", &server.tcp_max_connections);
+		options.value("server.tcp-idle-timeout", "--tcp-idle-timeout", &server.tcp_idle_timeout);
+		options.value("server.edns-max-payload", "--edns-max-payload", &server.edns_max_payload);
+		options.value("server.root-hints", "--root-hints", &server.root_hints);
+		options.switch("server.qname-minimization", "--no-qname-minimization", server.qname_minimization == Some(false));
+		options.switch("server.minimal-responses", "--minimal-responses", server.minimal_responses);
+		options.values("server.no-compression", "--no-compression", &server.no_compression);
+		options.switch("server.crash-on-panic", "--crash-on-panic", server.crash_on_panic);
+		options.value("server.shutdown-timeout", "--shutdown-timeout", &server.shutdown_timeout);
+
+		options.value("tls.cert", "--tls-cert", &self.tls.cert);
+		options.value("tls.key", "--tls-key", &self.tls.key);
+
+		let upstreams = &self.upstreams;
+		options.values("upstreams.servers", "--forward", &upstreams.servers);
+		options.value("upstreams.strategy", "--strategy", &upstreams.strategy);
+		options.value("upstreams.tls-policy", "--tls-upstream-policy", &upstreams.tls_policy);
+		options.value("upstreams.health-interval", "--health-interval", &upstreams.health_interval);
+		options.value("upstreams.timeout-ms", "--upstream-timeout-ms", &upstreams.timeout_ms);
+		options.value("upstreams.attempts", "--upstream-attempts", &upstreams.attempts);
+		options.value("upstreams.deadline-ms", "--upstream-deadline-ms", &upstreams.deadline_ms);
+		options.value("upstreams.backoff-ms", "--upstream-backoff-ms", &upstreams.backoff_ms);
+		options.switch("upstreams.pass-through", "--pass-through", upstreams.pass_through);
+		options.value("upstreams.mirror", "--mirror", &upstreams.mirror);
+		options.value("upstreams.mirror-percent", "--mirror-percent", &upstreams.mirror_percent);
+
+		let cache = &self.cache;
+		options.value("cache.size", "--cache-size", &cache.size);
+		options.value("cache.memory", "--cache-memory", &cache.memory);
+		options.value("cache.hot-entries", "--hot-cache", &cache.hot_entries);
+		options.value("cache.file", "--cache-file", &cache.file);
+		options.value("cache.snapshot-interval", "--cache-snapshot-interval", &cache.snapshot_interval);
+		options.value("cache.min-ttl", "--min-ttl", &cache.min_ttl);
+		options.value("cache.max-ttl", "--max-ttl", &cache.max_ttl);
+		options.value("cache.max-negative-ttl", "--max-negative-ttl", &cache.max_negative_ttl);
+		options.value("cache.max-stale", "--max-stale", &cache.max_stale);
+		options.values("cache.critical-names", "--critical-name", &cache.critical_names);
+		options.value("cache.prefetch", "--prefetch", &cache.prefetch);
+		options.value("cache.prefetch-min-hits", "--prefetch-min-hits", &cache.prefetch_min_hits);
+
+		options.values("zones.files", "--zone", &self.zones.files);
+		options.values("zones.databases", "--zone-db", &self.zones.databases);
+		options.values("zones.schedules", "--schedule", &self.zones.schedules);
+		options.values("zones.rotate", "--rotate", &self.zones.rotate);
+		options.values("zones.answer-weights", "--answer-weight", &self.zones.answer_weights);
+		options.values("zones.health-checks", "--health-check", &self.zones.health_checks);
+		options.switch("zones.strict", "--strict-zones", self.zones.strict);
+		options.value("zones.delegation-check-interval", "--check-delegations", &self.zones.delegation_check_interval);
+		options.switch("zones.publish-delegations", "--publish-delegations", self.zones.publish_delegations);
+
+		options.value("geoip.database", "--geoip-db", &self.geoip.database);
+		options.values("geoip.records", "--geo-record", &self.geoip.records);
+
+		options.values("mdns.hosts", "--mdns-host", &self.mdns.hosts);
+		options.value("mdns.interface", "--mdns-interface", &self.mdns.interface);
+		options.values("mdns.services", "--mdns-service", &self.mdns.services);
+
+		let limits = &self.limits;
+		options.value("limits.max-answers", "--max-answers", &limits.max_answers);
+		options.value("limits.max-response-ttl", "--max-response-ttl", &limits.max_response_ttl);
+		options.value("limits.max-cname-chain", "--max-cname-chain", &limits.max_cname_chain);
+		options.value("limits.max-amplification", "--max-amplification", &limits.max_amplification);
+		options.value("limits.rate-limit", "--rate-limit", &limits.rate_limit);
+		options.value("limits.rate-limit-burst", "--rate-limit-burst", &limits.rate_limit_burst);
+		options.value("limits.rate-limit-ipv6-prefix", "--rate-limit-ipv6-prefix", &limits.rate_limit_ipv6_prefix);
+		options.value("limits.rate-limit-action", "--rate-limit-action", &limits.rate_limit_action);
+		options.value("limits.rrl", "--rrl", &limits.rrl);
+		options.value("limits.rrl-slip", "--rrl-slip", &limits.rrl_slip);
+
+		let logging = &self.logging;
+		options.value("logging.verbosity", "--verbosity", &logging.verbosity);
+		options.value("logging.slow-query-ms", "--slow-query-ms", &logging.slow_query_ms);
+		options.value("logging.slo-latency-ms", "--slo-latency-ms", &logging.slo_latency_ms);
+		options.value("logging.slo-objective", "--slo-objective", &logging.slo_objective);
+		options.value("logging.query-log", "--query-log", &logging.query_log);
+		options.value("logging.query-log-percent", "--query-log-percent", &logging.query_log_percent);
+		options.value("logging.trace", "--trace", &logging.trace);
+		options.value("logging.trace-file", "--trace-file", &logging.trace_file);
+
+		options.value("capture.file", "--capture-file", &self.capture.file);
+		options.value("capture.filter", "--capture", &self.capture.filter);
+		options.value("capture.duration", "--capture-duration", &self.capture.duration);
+
+		options.value("control.listen", "--control", &self.control.listen);
+		options.value("control.key-file", "--control-key", &self.control.key_file);
+
+		options.values("address-filters.aaaa", "--filter-aaaa", &self.address_filters.aaaa);
+		options.values("address-filters.a", "--filter-a", &self.address_filters.a);
+
+		options.values("acl.query", "--allow-query", &self.acl.query);
+		options.values("acl.recursion", "--allow-recursion", &self.acl.recursion);
+		options.values("acl.transfer", "--allow-transfer", &self.acl.transfer);
+
+		options.value("hosts.file", "--hosts-file", &self.hosts.file);
+		options.values("hosts.entries", "--host", &self.hosts.entries);
+
+		options.values("blocklists.files", "--blocklist", &self.blocklists.files);
+		options.value("blocklists.action", "--block-action", &self.blocklists.action);
+
+		options.values("doctoring.pairs", "--doctor", &self.doctoring.pairs);
+		options.values("doctoring.inside", "--doctor-inside", &self.doctoring.inside);
+
+		options.values("policies.names", "--name-policy", &self.policies.names);
+		options.switch("policies.detect-tunnels", "--detect-tunnels", self.policies.detect_tunnels);
+		options.value("policies.tunnel-throttle", "--tunnel-throttle", &self.policies.tunnel_throttle);
+
+		for (name, addrs) in &self.internal_overrides {
+			let addrs: Vec<String> = addrs.iter().map(IpAddr::to_string).collect();
+			let spec = format!("{}={}", name, addrs.join(","));
+			options.value(&format!("internal-overrides.{}", name), "--internal-override", &Some(spec));
+		}
+		for (name, view) in &self.views {
+			let spec = |values: &[String]| -> Vec<String> { values.iter().map(|value| format!("{}:{}", name, value)).collect() };
+			options.values(&format!("views.{}.networks", name), "--view", &spec(&view.networks));
+			options.values(&format!("views.{}.zones", name), "--view-zone", &spec(&view.zones));
+			options.values(&format!("views.{}.forward", name), "--view-forward", &spec(&view.forward));
+			options.value(&format!("views.{}.strategy", name), "--view-strategy", &view.strategy.as_ref().map(|strategy| format!("{}:{}", name, strategy)));
+			options.values(&format!("views.{}.hosts", name), "--view-host", &spec(&view.hosts));
+		}
+
+		options.0
+	}
+}
+
+#[derive(Default)]
+struct Options(Vec<ConfigOption>);
+
+impl Options {
+	fn value<T: ToString>(&mut self, key: &str, option: &'static str, value: &Option<T>) {
+		if let Some(value) = value {
+			self.0.push(ConfigOption { key: key.to_string(), option, value: Some(value.to_string()) });
+		}
+	}
+
+	fn values<T: ToString>(&mut self, key: &str, option: &'static str, values: &[T]) {
+		for value in values {
+			self.0.push(ConfigOption { key: key.to_string(), option, value: Some(value.to_string()) });
+		}
+	}
+
+	fn switch(&mut self, key: &str, option: &'static str, on: bool) {
+		if on {
+			self.0.push(ConfigOption { key: key.to_string(), option, value: None });
+		}
+	}
+}
+
+/// ` at line L column C` of the start of `span` in `text`, if there's one.
+fn location(text: &str, span: Option<std::ops::Range<usize>>) -> String {
+	let start = match span {
+		Some(span) => span.start.min(text.len()),
+		None => return String::new(),
+	};
+	let before = &text[..start];
+	let line = before.matches('\n').count() + 1;
+	let column = before.len() - before.rfind('\n').map_or(0, |newline| newline + 1) + 1;
+	format!(" at line {} column {}", line, column)
+}
@@ -0,0 +1,80 @@
+use std::net::{ IpAddr, Ipv4Addr, SocketAddr };
+use std::path::Path;
+
+use rdns::server::control::{ read_key, send_command, ControlAddr, DEFAULT_CONTROL_PORT };
+
+use crate::cli::config::Config;
+
+/// `rdns control [--server ADDR|PATH] [--key FILE] [--config FILE] COMMAND [ARGS]...`
+/// Sends a command to the control channel of a running server (see `ControlServer`). The
+/// address and key are taken from the `control` section of the configuration file unless
+/// given, the address defaulting to 127.0.0.1:953.
+pub fn run(args: &[String]) -> i32 {
+	let mut server = None;
+	let mut key_file = None;
+	let mut config_file = None;
+	let mut iter = args.iter();
+	let mut command = Vec::new();
+	while let Some(arg) = iter.next() {
+		let target = match arg.as_str() {
+			"--server" | "-s" => &mut server,
+			"--key" | "-k" => &mut key_file,
+			"--config" | "-c" => &mut config_file,
+			_ => {
+				command.push(arg.as_str());
+				command.extend(iter.map(String::as_str));
+				break;
+			}
+		};
+		match iter.next() {
+			Some(value) => *target = Some(value.clone()),
+			None => {
+				eprintln!("{} needs a value", arg);
+				return 2;
+			}
+		}
+	}
+	if command.is_empty() {
+		eprintln!("Usage: rdns control [--server ADDR|PATH] [--key FILE] [--config FILE] COMMAND [ARGS]...");
+		return 2;
+	}
+
+	if let Some(path) = config_file {
+		let config = match Config::load(Path::new(&path)) {
+			Ok(config) => config,
+			Err(e) => {
+				eprintln!("Failed to read {}: {}", path, e);
+				return 2;
+			}
+		};
+		server = server.or(config.control.listen);
+		key_file = key_file.or(config.control.key_file);
+	}
+
+	let addr = match server {
+		Some(server) => ControlAddr::parse(&server),
+		None => ControlAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_CONTROL_PORT)),
+	};
+	let key = match key_file.map(|path| read_key(&path).map_err(|e| format!("Failed to read the control key {}: {}", path, e))) {
+		Some(Ok(key)) => key,
+		Some(Err(e)) => {
+			eprintln!("{}", e);
+			return 1;
+		}
+		None => {
+			eprintln!("The control channel needs its key, given with --key or as control.key-file");
+			return 2;
+		}
+	};
+
+	match send_command(&addr, &key, &command.join(" ")) {
+		Ok(output) => {
+			print!("{}", output);
+			0
+		}
+		Err(e) => {
+			eprintln!("{}: {}", addr, e);
+			1
+		}
+	}
+}
@@ -0,0 +1,46 @@
+use std::io::{ self, Read };
+
+use rdns::server::buffer::VectorPacketBuffer;
+use rdns::server::protocol::DNSPacket;
+use rdns::server::zonefile::decode_hex;
+
+use crate::cli::output::{ print_packet, OutputMode };
+
+/// `rdns decode [HEX] [--json|--short]`
+/// Decodes a hex encoded DNS message given as argument or on stdin. Whitespace is ignored.
+pub fn run(args: &[String]) -> i32 {
+	let (mode, args) = OutputMode::from_args(args);
+
+	let input = match args.first() {
+		Some(hex) => hex.clone(),
+		None => {
+			let mut input = String::new();
+			if let Err(e) = io::stdin().read_to_string(&mut input) {
+				eprintln!("Failed to read stdin: {}", e);
+				return 1;
+			}
+			input
+		}
+	};
+
+	let hex: String = input.split_whitespace().collect();
+	let bytes = match decode_hex(&hex) {
+		Some(bytes) => bytes,
+		None => {
+			eprintln!("Input is not a valid hex string");
+			return 2;
+		}
+	};
+
+	let mut buffer = VectorPacketBuffer::from_bytes(bytes);
+	match DNSPacket::from_buffer(&mut buffer) {
+		Ok(packet) => {
+			print_packet(mode, &packet);
+			0
+		}
+		Err(e) => {
+			eprintln!("Failed to decode packet: {}", e);
+			1
+		}
+	}
+}
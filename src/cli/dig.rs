@@ -0,0 +1,145 @@
+use std::net::SocketAddr;
+use std::time::{ Duration, Instant };
+
+use serde_json::json;
+
+use rdns::server::axfr::ZoneTransfer;
+use rdns::server::client::DNSClient;
+use rdns::server::platform::system_server;
+use rdns::server::protocol::QueryType;
+use rdns::server::retry::RetryPolicy;
+
+use crate::cli::output::{ print_packet, OutputMode };
+use crate::cli::parse_server;
+
+/// `rdns dig [@SERVER] NAME [TYPE] [--norecurse] [--timeout SECS] [--attempts N] [--json|--short]`
+pub fn run(args: &[String]) -> i32 {
+	let (mode, args) = OutputMode::from_args(args);
+
+	let mut server = None;
+	let mut recursive = true;
+	let mut retry = RetryPolicy::new();
+	let mut positional = Vec::new();
+
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		match arg.as_str() {
+			"--norecurse" => recursive = false,
+			"--timeout" => match iter.next().and_then(|secs| secs.parse::<u64>().ok()) {
+				Some(secs) => retry = retry.with_timeout(Duration::from_secs(secs)),
+				None => {
+					eprintln!("--timeout needs a number of seconds");
+					return 2;
+				}
+			},
+			"--attempts" => match iter.next().and_then(|attempts| attempts.parse::<u32>().ok()).filter(|attempts| *attempts > 0) {
+				Some(attempts) => retry = retry.with_attempts(attempts),
+				None => {
+					eprintln!("--attempts needs a number of tries");
+					return 2;
+				}
+			},
+			_ => match arg.strip_prefix('@') {
+				Some(addr) => server = Some(addr.to_string()),
+				None => positional.push(arg.as_str()),
+			},
+		}
+	}
+
+	let name = match positional.first() {
+		Some(name) => name.trim_end_matches('.'),
+		None => {
+			eprintln!("Usage: rdns dig [@SERVER] NAME [TYPE] [--norecurse] [--timeout SECS] [--attempts N] [--json|--short]");
+			return 2;
+		}
+	};
+	let q_type = match positional.get(1) {
+		Some(q_type) => match QueryType::from_name(q_type) {
+			Some(q_type) => q_type,
+			None => {
+				eprintln!("Unknown record type: {}", q_type);
+				return 2;
+			}
+		},
+		None => QueryType::A,
+	};
+	// Without a server, the one the system uses is asked...
+	let server = match server.as_deref().map(parse_server) {
+		Some(Ok(server)) => server,
+		Some(Err(e)) => {
+			eprintln!("{}", e);
+			return 2;
+		}
+		None => system_server(),
+	};
+
+	let client = DNSClient::with_retry(retry);
+	if q_type == QueryType::AXFR {
+		return transfer(mode, &client, name, server);
+	}
+
+	let start = Instant::now();
+	let response = match client.send_query(name, q_type, server, recursive) {
+		Ok(response) => response,
+		Err(e) => {
+			match mode {
+				OutputMode::Json => println!("{}", json!({ "server": server.to_string(), "error": e.to_string() })),
+				_ => eprintln!(";; Query failed: {}", e),
+			}
+			return 1;
+		}
+	};
+	let elapsed = start.elapsed().as_millis() as u64;
+
+	match mode {
+		OutputMode::Json => println!("{}", json!({
+			"server": server.to_string(),
+			"query_time_ms": elapsed,
+			"response": response,
+		})),
+		_ => {
+			print_packet(mode, &response);
+			if mode == OutputMode::Text {
+				println!("\n;; Query time: {} msec", elapsed);
+				println!(";; SERVER: {}", server);
+			}
+		}
+	}
+	0
+}
+
+/// Print a zone transfer as it streams in, one record per line (JSON lines with --json).
+fn transfer(mode: OutputMode, client: &DNSClient, zone: &str, server: SocketAddr) -> i32 {
+	let transfer = match ZoneTransfer::start(client, zone, server) {
+		Ok(transfer) => transfer,
+		Err(e) => {
+			eprintln!(";; Transfer failed: {}", e);
+			return 1;
+		}
+	};
+
+	let mut count = 0;
+	for rrset in transfer {
+		let rrset = match rrset {
+			Ok(rrset) => rrset,
+			Err(e) => {
+				eprintln!(";; Transfer failed: {}", e);
+				return 1;
+			}
+		};
+		for record in &rrset {
+			match mode {
+				OutputMode::Text => println!("{}", record),
+				OutputMode::Json => println!("{}", json!(record)),
+				OutputMode::Short => println!("{}", record.rdata_string()),
+			}
+		}
+		count += rrset.len();
+	}
+
+	if mode == OutputMode::Text {
+		println!(";; XFR size: {} records", count);
+		println!(";; SERVER: {}", server);
+	}
+	0
+}
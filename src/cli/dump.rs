@@ -0,0 +1,43 @@
+use serde_json::json;
+
+use rdns::server::cache::Cache;
+
+use crate::cli::output::OutputMode;
+
+/// `rdns dump-cache FILE [--json|--short]`
+/// Lists the entries of a cache snapshot written by `rdns serve --cache-file`, ordered by name
+/// and type, with the TTLs they'd be served with now. Expired entries aren't listed.
+pub fn run(args: &[String]) -> i32 {
+	let (mode, args) = OutputMode::from_args(args);
+	let path = match args.as_slice() {
+		[path] => path,
+		_ => {
+			eprintln!("Usage: rdns dump-cache FILE [--json|--short]");
+			return 2;
+		}
+	};
+
+	let mut cache = Cache::new();
+	cache.set_limits(usize::MAX, None);
+	if let Err(e) = cache.load(path) {
+		eprintln!("Failed to read {}: {}", path, e);
+		return 1;
+	}
+
+	let entries = cache.dump();
+	match mode {
+		OutputMode::Text => {
+			for entry in &entries {
+				println!("{}", entry);
+			}
+			println!(";; {} entries", entries.len());
+		}
+		OutputMode::Json => println!("{}", json!(entries)),
+		OutputMode::Short => {
+			for entry in &entries {
+				println!("{}. {} {:?} {}", entry.key.qname, entry.key.q_type, entry.rescode, entry.ttl);
+			}
+		}
+	}
+	0
+}
@@ -0,0 +1,90 @@
+use std::fs::File;
+use std::io::{ self, BufReader };
+
+use rdns::server::authority::Zone;
+use rdns::server::protocol::{ DNSRecord, QueryType };
+use rdns::server::structured::{ RecordDocument, StructuredFormat };
+use rdns::server::zonefile::load_zone_file;
+
+/// `rdns export-zone FILE [--origin NAME] [--name NAME [--type TYPE]] [--json|--yaml]`
+/// Prints a zone as a JSON (by default) or YAML document (see `RecordDocument`), read from a
+/// master file or from another structured document. With `--name` only the records of that
+/// name are printed, of a single type with `--type`, and the file doesn't need to hold a
+/// whole zone.
+pub fn run(args: &[String]) -> i32 {
+	let mut origin = String::new();
+	let mut name = None;
+	let mut q_type = None;
+	let mut format = StructuredFormat::Json;
+	let mut positional = Vec::new();
+
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		match arg.as_str() {
+			"--json" => format = StructuredFormat::Json,
+			"--yaml" => format = StructuredFormat::Yaml,
+			"--origin" | "--name" | "--type" => {
+				let value = match iter.next() {
+					Some(value) => value.clone(),
+					None => {
+						eprintln!("{} needs a value", arg);
+						return 2;
+					}
+				};
+				match arg.as_str() {
+					"--origin" => origin = value,
+					"--name" => name = Some(value),
+					_ => match QueryType::from_name(&value) {
+						Some(parsed) => q_type = Some(parsed),
+						None => {
+							eprintln!("Unknown record type: {}", value);
+							return 2;
+						}
+					},
+				}
+			}
+			_ => positional.push(arg.as_str()),
+		}
+	}
+	let input = match positional.as_slice() {
+		[input] => *input,
+		_ => {
+			eprintln!("Usage: rdns export-zone FILE [--origin NAME] [--name NAME [--type TYPE]] [--json|--yaml]");
+			return 2;
+		}
+	};
+
+	let records = match read_records(input, &origin) {
+		Ok(records) => records,
+		Err(e) => {
+			eprintln!("Failed to parse {}: {}", input, e);
+			return 1;
+		}
+	};
+	let document = match name {
+		Some(name) => RecordDocument::record_set(&records, &name, q_type),
+		None => match Zone::from_records(records) {
+			Ok(zone) => RecordDocument::from_zone(&zone),
+			Err(e) => {
+				eprintln!("{} doesn't hold a zone: {}", input, e);
+				return 1;
+			}
+		},
+	};
+
+	match document.write(io::stdout().lock(), format) {
+		Ok(()) => 0,
+		Err(e) => {
+			eprintln!("Failed to write the records: {}", e);
+			1
+		}
+	}
+}
+
+/// The records of a master file, or of a structured document going by the extension.
+fn read_records(path: &str, origin: &str) -> io::Result<Vec<DNSRecord>> {
+	match StructuredFormat::from_path(path) {
+		Some(format) => Ok(RecordDocument::read(BufReader::new(File::open(path)?), format)?.records),
+		None => load_zone_file(path, origin),
+	}
+}
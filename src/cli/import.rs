@@ -0,0 +1,69 @@
+use std::fs::File;
+use std::io::{ self, BufReader, Read };
+
+use rdns::server::structured::{ RecordDocument, StructuredFormat };
+
+/// `rdns import-zone FILE|- [--json|--yaml] [--records]`
+/// Prints the records of a JSON or YAML document (see `RecordDocument`) as a master file, the
+/// format going by the extension of the file unless given (JSON for stdin). The records have
+/// to make up a zone, which is printed SOA first, unless `--records` is given.
+pub fn run(args: &[String]) -> i32 {
+	let mut format = None;
+	let mut records_only = false;
+	let mut positional = Vec::new();
+	for arg in args {
+		match arg.as_str() {
+			"--json" => format = Some(StructuredFormat::Json),
+			"--yaml" => format = Some(StructuredFormat::Yaml),
+			"--records" => records_only = true,
+			_ => positional.push(arg.as_str()),
+		}
+	}
+	let input = match positional.as_slice() {
+		[input] => *input,
+		_ => {
+			eprintln!("Usage: rdns import-zone FILE|- [--json|--yaml] [--records]");
+			return 2;
+		}
+	};
+
+	let format = format.or_else(|| StructuredFormat::from_path(input)).unwrap_or(StructuredFormat::Json);
+	let reader: Box<dyn Read> = if input == "-" {
+		Box::new(io::stdin())
+	} else {
+		match File::open(input) {
+			Ok(file) => Box::new(BufReader::new(file)),
+			Err(e) => {
+				eprintln!("Failed to open {}: {}", input, e);
+				return 1;
+			}
+		}
+	};
+	let document = match RecordDocument::read(reader, format) {
+		Ok(document) => document,
+		Err(e) => {
+			eprintln!("Failed to parse {}: {}", input, e);
+			return 1;
+		}
+	};
+
+	if records_only {
+		for record in &document.records {
+			println!("{}", record);
+		}
+		return 0;
+	}
+	match document.into_zone() {
+		Ok(zone) => {
+			println!("{}", zone.get_soa());
+			for record in zone.records() {
+				println!("{}", record);
+			}
+			0
+		}
+		Err(e) => {
+			eprintln!("{} doesn't hold a zone: {}", input, e);
+			1
+		}
+	}
+}
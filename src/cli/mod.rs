@@ -0,0 +1,84 @@
+use std::net::{ IpAddr, SocketAddr };
+
+pub mod compile;
+pub mod config;
+pub mod control;
+pub mod decode;
+pub mod dig;
+pub mod dump;
+pub mod export;
+pub mod import;
+pub mod output;
+pub mod serve;
+pub mod shell;
+pub mod trace;
+
+const USAGE: &str = "Usage: rdns <command> [options]
+
+Commands:
+    dig [@SERVER] NAME [TYPE] [--norecurse] [--timeout SECS] [--attempts N]
+                             Send a single query and print the response, to the
+                             system's nameserver unless given (also available as
+                             query)
+    trace NAME [TYPE]        Follow the delegations for NAME from the root
+    decode [HEX]             Decode a hex encoded message (from stdin if not given)
+    serve [OPTIONS]          Run the server (see rdns serve --help)
+    check-config [OPTIONS] [--json|--short]
+                             Check the options serve would be started with,
+                             loading the zones and certificates, without serving
+    dump-cache FILE          List the entries of a cache snapshot (--cache-file)
+    control [--server ADDR|PATH] [--key FILE] [--config FILE] COMMAND [ARGS]...
+                             Send a command to the control channel of a server:
+                             reload, cache [NAME], flush [NAME], flush-tree NAME,
+                             add-zone FILE, remove-zone ORIGIN, zones, delegations,
+                             publish-delegation ZONE, capture FILE [SECS [FILTER]],
+                             stop-capture, blocklists, blocklist enable|disable [FILE]
+                             or stats
+    compile-zone ZONEFILE OUTPUT [--origin NAME]
+                             Compile a zone file into a database for --zone-db
+    export-zone FILE [--origin NAME] [--name NAME [--type TYPE]] [--json|--yaml]
+                             Print a zone, or the records of a name, as JSON or YAML
+    import-zone FILE|- [--json|--yaml] [--records]
+                             Print the records of a JSON or YAML document as a zone file
+    shell [--server ADDR] [--control ADDR|PATH --key FILE]
+                             Interactive prompt for sending queries to a server,
+                             and looking into its cache over the control channel
+    help                     Show this message
+
+dig, trace, decode and dump-cache take --json for machine readable output or
+--short for just the answer data; check-config takes them too, --short printing
+ok or the error.";
+
+/// Run the command line in `args` (without the program name) and return the exit code.
+pub fn run(args: &[String]) -> i32 {
+	match args.first().map(String::as_str) {
+		Some("compile-zone") => compile::run(&args[1..]),
+		Some("control") => control::run(&args[1..]),
+		Some("decode") => decode::run(&args[1..]),
+		Some("check-config") => serve::check(&args[1..]),
+		Some("dig") | Some("query") => dig::run(&args[1..]),
+		Some("dump-cache") => dump::run(&args[1..]),
+		Some("export-zone") => export::run(&args[1..]),
+		Some("import-zone") => import::run(&args[1..]),
+		Some("serve") => serve::run(&args[1..]),
+		Some("shell") => shell::run(&args[1..]),
+		Some("trace") => trace::run(&args[1..]),
+		Some("help") | Some("--help") | Some("-h") | None => {
+			println!("{}", USAGE);
+			0
+		}
+		Some(cmd) => {
+			eprintln!("Unknown command: {}\n\n{}", cmd, USAGE);
+			2
+		}
+	}
+}
+
+/// Parse a server address, with the port defaulting to 53.
+/// Ex: `8.8.8.8`, `8.8.8.8:5353`, `::1`, `[::1]:5353`
+pub fn parse_server(addr: &str) -> Result<SocketAddr, String> {
+	if let Ok(ip) = addr.parse::<IpAddr>() {
+		return Ok(SocketAddr::new(ip, 53));
+	}
+	addr.parse::<SocketAddr>().map_err(|_| format!("Invalid server address: {}", addr))
+}
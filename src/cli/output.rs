@@ -0,0 +1,85 @@
+use std::fmt::Write;
+
+use serde_json::json;
+
+use rdns::server::json::flag_names;
+use rdns::server::protocol::DNSPacket;
+
+/// How a subcommand prints its results.
+///
+/// `Json` output follows the stable schema of `rdns::server::json` so scripts can rely on it.
+///
+/// `Short` output prints only the RDATA of the answers, one per line, like `dig +short`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OutputMode {
+	Text,
+	Json,
+	Short,
+}
+
+impl OutputMode {
+	/// Take the `--json` and `--short` flags out of `args`, returning the selected mode and
+	/// the remaining arguments.
+	pub fn from_args(args: &[String]) -> (OutputMode, Vec<String>) {
+		let mut mode = OutputMode::Text;
+		let mut rest = Vec::new();
+		for arg in args {
+			match arg.as_str() {
+				"--json" => mode = OutputMode::Json,
+				"--short" => mode = OutputMode::Short,
+				_ => rest.push(arg.clone()),
+			}
+		}
+		(mode, rest)
+	}
+}
+
+fn fqdn(name: &str) -> String {
+	format!("{}.", name.trim_end_matches('.'))
+}
+
+/// Format a packet the way dig prints it.
+pub fn format_packet(packet: &DNSPacket) -> String {
+	let header = &packet.header;
+	let mut out = String::new();
+
+	let _ = writeln!(out, ";; ->>HEADER<<- opcode: {}, status: {:?}, id: {}", header.opcode, header.rescode, header.id);
+	let _ = writeln!(out, ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+		flag_names(header).join(" "), packet.questions.len(), packet.answers.len(), packet.authorities.len(), packet.additional.len());
+
+	let _ = writeln!(out, "\n;; QUESTION SECTION:");
+	for question in &packet.questions {
+		let _ = writeln!(out, ";{}\t\tIN\t{}", fqdn(&question.name), question.q_type);
+	}
+
+	let sections = [
+		("ANSWER", &packet.answers),
+		("AUTHORITY", &packet.authorities),
+		("ADDITIONAL", &packet.additional),
+	];
+	for (name, records) in sections.iter() {
+		if records.is_empty() {
+			continue;
+		}
+		let _ = writeln!(out, "\n;; {} SECTION:", name);
+		for record in records.iter() {
+			let _ = writeln!(out, "{}", record);
+		}
+	}
+
+	out
+}
+
+/// RDATA of the answers, one per line.
+pub fn format_short(packet: &DNSPacket) -> String {
+	packet.answers.iter().map(|record| format!("{}\n", record.rdata_string())).collect()
+}
+
+/// Print a packet in the given mode.
+pub fn print_packet(mode: OutputMode, packet: &DNSPacket) {
+	match mode {
+		OutputMode::Text => print!("{}", format_packet(packet)),
+		OutputMode::Json => println!("{}", json!(packet)),
+		OutputMode::Short => print!("{}", format_short(packet)),
+	}
+}
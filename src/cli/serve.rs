@@ -0,0 +1,1494 @@
+use std::fmt;
+use std::io;
+use std::net::{ IpAddr, Ipv4Addr, SocketAddr };
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::thread::{ self, JoinHandle };
+use std::time::Duration;
+
+use rdns::server::acl::Access;
+use rdns::server::admin::{ AdminServer, DEFAULT_ADMIN_PORT };
+use rdns::server::amplification::AmplificationGuard;
+use rdns::server::authority::SharedZone;
+use rdns::server::blocklist::{ BlockAction, BlockList };
+use rdns::server::cache::{ Prefetch, TtlLimits, DEFAULT_MAX_ENTRIES };
+use rdns::server::capture::{ CaptureFilter, DEFAULT_CAPTURE_DURATION };
+use rdns::server::context::{ InternalOverride, NonRecursivePolicy, ResolveStrategy, ServerContext, ServerRole };
+use rdns::server::control::{ read_key, ControlAddr, ControlServer };
+use rdns::server::dnssd::ServiceRegistration;
+use rdns::server::doh::{ DNSHttpsServer, DEFAULT_HTTPS_PATH, DEFAULT_HTTPS_PORT };
+use rdns::server::geoip::{ GeoDatabase, GeoRecords };
+use rdns::server::latency::LatencySlo;
+use rdns::server::hints::load_root_hints;
+use rdns::server::loader::{ load_zone, read_zone, LoadProgress };
+use rdns::server::log::{ init_tracing, is_trace_filter, set_verbosity, verbosity, Verbosity };
+use rdns::server::mirror::QueryMirror;
+use rdns::server::policy::{ NamePolicies, NamePolicy };
+use rdns::server::protocol::QueryType;
+use rdns::server::querylog::QueryLog;
+use rdns::server::ratelimit::{ ClientRateLimit, LimitAction, TokenBuckets, DEFAULT_IPV6_PREFIX };
+use rdns::server::quic::{ DNSQuicServer, DEFAULT_QUIC_PORT };
+use rdns::server::retry::MAX_BACKOFF;
+use rdns::server::rrl::{ ResponseRateLimit, DEFAULT_SLIP };
+use rdns::server::schedule::Schedule;
+use rdns::server::selftest::{ run_self_test, CheckOutcome };
+use rdns::server::shutdown::{ Signal, Signals, DEFAULT_SHUTDOWN_TIMEOUT };
+use rdns::server::tcp::DNSTcpServer;
+use rdns::server::tls::{ load_server_config, DNSTlsServer, DEFAULT_TLS_PORT };
+use rustls::ServerConfig;
+use serde_json::json;
+use rdns::server::tls_upstream::TlsPolicy;
+use rdns::server::tunnel::{ TunnelDetector, TunnelThresholds };
+use rdns::server::udp::DNSUdpServer;
+use rdns::server::upstream::{ SelectionStrategy, Upstream, UpstreamPool };
+use rdns::server::view::View;
+use rdns::server::zonedb::ZoneDatabase;
+
+use crate::cli::config::Config;
+use crate::cli::output::OutputMode;
+
+/// Times an entry has to be served before it's worth prefetching...
+const DEFAULT_PREFETCH_MIN_HITS: u32 = 3;
+/// Share of the queries to answer within the SLO latency unless told otherwise...
+const DEFAULT_SLO_OBJECTIVE: f64 = 99.0;
+/// Share of the queries mirrored, in percent, unless told otherwise...
+const DEFAULT_MIRROR_PERCENT: f64 = 10.0;
+/// Share of the queries written to the query log, in percent, unless told otherwise...
+const DEFAULT_QUERY_LOG_PERCENT: f64 = 100.0;
+
+/// What `rdns serve --help` prints: the options of the server and what they do.
+const SERVE_USAGE: &str = "Usage: rdns serve [--config FILE] [--verbose|--quiet|--verbosity LEVEL]
+       [--listen ADDR] [--listener ROLE:ADDR]...
+       [--non-recursive refuse|referral] [--forward UPSTREAM]... [--strategy NAME]
+       [--tls-upstream-policy POLICY] [--health-interval SECS]
+       [--upstream-timeout-ms MS] [--upstream-attempts N] [--upstream-deadline-ms MS]
+       [--upstream-backoff-ms MS] [--threads N]
+       [--udp-sockets N] [--tcp-max-connections N]
+       [--tcp-idle-timeout SECS] [--edns-max-payload BYTES] [--root-hints FILE]
+       [--pass-through] [--no-qname-minimization] [--minimal-responses]
+       [--no-compression NETWORK]...
+       [--max-stale SECS] [--critical-name NAME]... [--prefetch PERCENT]
+       [--prefetch-min-hits N] [--cache-size ENTRIES] [--cache-memory MB]
+       [--hot-cache ENTRIES] [--cache-file FILE]
+       [--cache-snapshot-interval SECS] [--min-ttl SECS] [--max-ttl SECS]
+       [--max-negative-ttl SECS] [--max-amplification RATIO] [--strict-zones]
+       [--max-answers N] [--max-response-ttl SECS] [--max-cname-chain N]
+       [--rate-limit QPS [--rate-limit-burst N] [--rate-limit-ipv6-prefix LEN]
+        [--rate-limit-action drop|truncate]] [--rrl RATE [--rrl-slip N]]
+       [--capture-file FILE [--capture FILTER] [--capture-duration SECS]]
+       [--slow-query-ms MS] [--slo-latency-ms MS [--slo-objective PERCENT]]
+       [--query-log FILE [--query-log-percent PERCENT]]
+       [--trace FILTER [--trace-file FILE]]
+       [--crash-on-panic] [--shutdown-timeout SECS] [--self-test]
+       [--tls-listen ADDR] [--https-listen ADDR [--https-path PATH]]
+       [--quic-listen ADDR] [--tls-cert FILE --tls-key FILE]
+       [--internal-override NAME=ADDR[,ADDR]]...
+       [--filter-aaaa NETWORK]... [--filter-a NETWORK]...
+       [--allow-query NETWORK]... [--allow-recursion NETWORK]...
+       [--allow-transfer NETWORK]...
+       [--hosts-file FILE] [--host NAME=ADDR[,ADDR]]...
+       [--blocklist FILE]... [--block-action nxdomain|null]
+       [--doctor INTERNAL=EXTERNAL]... [--doctor-inside NETWORK]...
+       [--view NAME:NETWORK[,NETWORK]]... [--view-zone NAME:FILE]...
+       [--view-forward NAME:UPSTREAM]... [--view-strategy NAME:STRATEGY]...
+       [--view-host NAME:HOST=ADDR[,ADDR]]...
+       [--name-policy 'ACTION PREDICATE [and PREDICATE]...']...
+       [--detect-tunnels [--tunnel-throttle RATE]]
+       [--control ADDR|PATH --control-key FILE] [--admin-listen ADDR]
+       [--mirror UPSTREAM [--mirror-percent PERCENT]]
+       [--zone FILE]... [--zone-db FILE]... [--schedule 'WINDOW RECORD']...
+       [--geoip-db FILE [--geo-record 'POLICY RECORD']...]
+       [--rotate ZONE[:round-robin|weighted]]... [--answer-weight 'NAME ADDR WEIGHT']...
+       [--health-check 'NAME ADDR tcp:PORT|http:PORT[/PATH]']...
+       [--check-delegations SECS [--publish-delegations]]
+       [--mdns-host 'NAME ADDR...']... [--mdns-interface ADDR]
+       [--mdns-service 'INSTANCE@HOST TYPE PORT [KEY=VALUE]...']...
+
+--forward may be given several times, each upstream as
+[udp|tcp|tls|quic://]ADDR[:PORT] or https://ADDR[:PORT][/PATH], queries to the
+latter being posted over HTTP/2 to /dns-query unless given. TLS, HTTPS and QUIC
+upstreams are authenticated by the name following a #, or by SPKI pins given as
+#pin-sha256=BASE64; with --tls-upstream-policy opportunistic (strict by default)
+those failing authentication are used anyway, and TLS ones which can't be
+reached over TLS are asked over TCP. --strategy picks the upstream of each query:
+failover, round-robin, random, fastest or sticky. Their health is probed every
+--health-interval seconds (10 by default, 0 turns it off). With --pass-through
+queries are relayed to them and their responses back byte for byte, bypassing
+the cache.
+
+Every exchange with an upstream, or with the name servers asked resolving from
+the root, waits up to --upstream-timeout-ms milliseconds (3000 by default) for
+the response, and is tried --upstream-attempts times (1 by default) before the
+next upstream is. Between two tries the wait starts at --upstream-backoff-ms
+milliseconds (100 by default) and doubles every time, with jitter, up to 2
+seconds. With --upstream-deadline-ms all the tries of an exchange, the waits
+included, stop after that long.
+
+With --mirror a sample of --mirror-percent percent (10 by default) of the
+queries answered is sent to that upstream as well, given like those of
+--forward, without the clients waiting for it; its answers differing from those
+the clients got are counted in the stats, and logged with --verbose.
+
+Queries are served over UDP and TCP on the listen address, and on those of the
+extra --listener addresses in their role: full like the listen address,
+authoritative for the local zones only, or recursive for recursive queries only.
+With --udp-sockets (1 by default) every listener binds that many UDP sockets
+with SO_REUSEPORT, the kernel spreading the queries over them, and the --threads
+worker threads (4 by default) are shared out between them. Queries without RD
+for names outside of the local zones, and all of those reaching an authoritative
+listener, are refused or given a referral (--non-recursive, refused by default).
+Up to --tcp-max-connections (100 by default) TCP connections are served at a
+time, each closed once idle for --tcp-idle-timeout seconds (10 by default), or
+once it took longer than that to send a whole query. UDP
+responses are kept to the payload size the client advertises with EDNS, up to
+--edns-max-payload bytes (1232 by default), which is also the size asked of the
+servers queried. Names are compressed in the responses, but to the clients of a
+--no-compression network, say embedded stacks known to mishandle compression
+pointers.
+
+With --tls-listen queries are also served over TLS on that address (port 853
+unless given), and with --https-listen over HTTPS (port 443 unless given) at
+--https-path (/dns-query by default), and with --quic-listen over QUIC (port 853
+unless given). They use the PEM certificate chain of --tls-cert and the key of
+--tls-key, and the connection limits of TCP.
+
+Clients inside the network (private, loopback and link-local addresses) asking
+for the name of an --internal-override get its addresses instead, say the LAN
+address of a home server in place of the public one of its dynamic DNS name,
+which the router may not hairpin. Clients of a --filter-aaaa network (ADDR/LEN),
+say an IPv4 only segment, get no AAAA records, and those of a --filter-a network
+no A records: their queries for them are answered with NODATA.
+
+With --allow-query only the clients of the networks given may query the server,
+with --allow-recursion only they get names outside of the local zones resolved,
+and with --allow-transfer only they may request zone transfers; none allows no
+one. The others are answered with REFUSED and counted in the stats.
+
+Names of the --hosts-file, like /etc/hosts, and of each --host are answered with
+the addresses given rather than resolved, as are the PTR queries for those
+addresses; the file is read again whenever it changes.
+
+Names of a --blocklist, given as a hosts file or a list of domains, are answered
+without being resolved, with NXDOMAIN or, with --block-action null, the
+unspecified address; the queries each list blocked are counted in the stats. The
+lists are loaded again along with the zones.
+
+Each --doctor pairs the internal address of a host behind the NAT with its
+external one: answers holding the external address are rewritten to the internal
+one for the clients inside the network, those of the --doctor-inside networks
+(the private address ranges by default), and the other way around for those
+outside.
+
+Each --view NAME:NETWORK[,NETWORK] puts the clients of those networks in the
+view NAME, the first view given holding a client being its view, for
+split-horizon DNS. They're answered from the zones of its --view-zone options
+and the hosts of its --view-host options ahead of those of the server, and have
+the other names forwarded to its --view-forward upstreams, cached apart and
+picked with its --view-strategy or else --strategy, or resolved the way the
+others are without any. The views are loaded again along with the zones.
+
+Each --name-policy acts on the queries for names meeting its predicates on their
+number of labels, their length, and the label-length and entropy (in bits per
+character) of their leftmost label, like drop entropy>3.8 and label-length>=20
+against tunneling: log logs them, refuse answers them with REFUSED, drop doesn't
+answer them, and limit RATE drops those of each client beyond RATE a second. The
+policies apply in order until one refuses or drops a query, and count the
+queries they matched in the stats.
+
+With --detect-tunnels the query stream of every client is scored for DNS
+tunneling: clients querying many distinct names under one domain, getting long
+TXT or NULL responses or mostly asking for such records are flagged, logged and
+counted. With --tunnel-throttle flagged clients only get RATE queries a second
+answered.
+
+The cache holds up to --cache-size entries (100000 by default) and, with
+--cache-memory, about that many megabytes, evicting the least recently used
+entries. Every thread also keeps a copy of the --hot-cache most popular entries
+(16 by default, 0 turns the copies off), served without locking the cache. The
+TTLs of cached answers are raised to --min-ttl and lowered to --max-ttl,
+negative answers are cached for --max-negative-ttl seconds at most. Cached
+answers are served for up to --max-stale seconds past their expiry when
+resolving them fails (off by default). The last answers for each
+--critical-name, say VPN or SSO endpoints, are kept whatever their TTL and
+served when resolving them fails and there's no stale answer either. Both are
+flagged with the Stale Answer EDE. With --prefetch, entries served within that
+last percentage of their TTL are refreshed in the background once they've been
+served --prefetch-min-hits times (3 by default). With --cache-file the cache is
+restored from that file on start and written to it every
+--cache-snapshot-interval seconds (300 by default).
+
+Responses of upstreams and name servers with more than --max-answers answers or
+a CNAME chain longer than --max-cname-chain records are rejected, and their TTLs
+are lowered to --max-response-ttl before they're cached or relayed (none of
+these is limited by default).
+
+UDP responses sending a client subnet more than --max-amplification times the
+bytes it sent are truncated. With --rate-limit each client gets QPS queries a
+second answered, with bursts of --rate-limit-burst (QPS by default), IPv6
+clients being limited by their --rate-limit-ipv6-prefix (56 bits by default);
+queries over the limit get an empty truncated response over UDP, or none with
+--rate-limit-action drop. With --rrl each client network gets RATE identical
+authoritative answers a second over UDP; of those over, one in --rrl-slip (2 by
+default, 0 for none) is sent truncated and the others are dropped. Zones are
+served from master files (--zone), JSON or YAML documents (--zone with a .json,
+.yaml or .yml file) or compiled databases (--zone-db); with --strict-zones those
+holding obsolete record types (WKS, NULL) are refused. Each --schedule serves a
+record of a local zone during a window of time, [DAYS@]HH:MM-HH:MM in UTC, in
+place of the zone's records of its name and type, like --schedule 'mon-fri@22:00-06:00 www.example.com. 300
+IN A 192.0.2.80'. With --check-delegations the NS, glue and DS records the local
+zones publish for their local child zones are checked against the children every
+that many seconds, drift being logged and counted in the stats of the control
+channel; with --publish-delegations the records of the children are published in
+the parents in place of those drifting.
+
+With --geoip-db, a MaxMind DB like GeoLite2-Country, each --geo-record serves a
+record of a local zone in place of the zone's records of its name and type to
+the clients of a continent or country, like --geo-record 'continent:EU
+www.example.com. 60 IN A 192.0.2.10', a country beating a continent. Clients are
+located by the client subnet of their queries, when their resolver sends one, or
+else by their address; the answers carry the client subnet back, scoped to the
+network the client was located in.
+
+The A and AAAA records of the names of a --rotate zone are answered in a
+different order every time, for the clients to spread over the addresses:
+cycling through them (round-robin, by default), or shuffled for each address to
+come first in proportion to its --answer-weight (weighted, 1 unless given), like
+--answer-weight 'www.example.com 192.0.2.10 3'.
+
+An address of a name of the local zones given a --health-check is probed every
+--health-interval seconds, by connecting to a TCP port or by a GET request over
+HTTP, like --health-check 'www.example.com 192.0.2.10 http:80/health'. While it
+fails its probes it's withheld from the answers for the name, unless all of the
+name's addresses are down, for a simple DNS based failover.
+
+Each --mdns-host is announced on the LAN over multicast DNS, like --mdns-host
+'nas 192.168.1.20 fe80::20' for nas.local, and its A, AAAA and reverse PTR
+records answered to the queries of the multicast groups, once no other host is
+found using the name; a name taken is changed to nas-2.local and so on. The IPv4
+group is joined on the interface of --mdns-interface, the default one unless
+given.
+
+Each --mdns-service is registered for DNS-SD on one of the --mdns-host hosts,
+like --mdns-service 'Files@nas _smb._tcp 445', its PTR, SRV and TXT records
+answered to the browsers of the LAN once its instance name is found free; a name
+taken is changed to Files (2) and so on.
+
+With --capture-file the queries matching --capture (all of them by default) over
+every transport and their responses are written to that pcapng file for
+--capture-duration seconds (60 by default). Captures can also be started and
+stopped on the control channel while the server runs.
+
+Queries taking --slow-query-ms or longer are logged with the time each stage
+took and the server the answer came from. With --slo-latency-ms the share of
+queries answered within that latency is tracked against --slo-objective (99% by
+default).
+
+With --query-log a JSON line is appended to that file (- for stdout) for every
+query answered, or for a sample of --query-log-percent percent of them. With
+--trace the tracing spans matching that filter, like info or rdns=debug, are
+written to stderr or appended to --trace-file as they close: one per query with
+its ID and name, and at debug its cache lookup, upstream sends and parsing.
+
+Queries whose handling panics are answered with SERVFAIL, and messages the
+parser panics on are dropped like other invalid ones; with --crash-on-panic the
+server aborts instead, to catch such bugs while developing.
+
+With --self-test the server checks itself instead of serving: it writes and
+reads back every record type, caches an answer until it expires, and answers
+queries on loopback UDP and TLS listeners, the latter with --tls-cert and
+--tls-key. It prints how each check went and exits with 1 if any failed, for
+packaging smoke tests and container health gates.
+
+On SIGTERM or Ctrl-C the server refuses the queries still coming in, waits up to
+--shutdown-timeout seconds (5 by default) for those it's answering, and flushes
+the query log and writes the last snapshot of the cache before exiting. On
+SIGHUP it reads the configuration file and the command line again and swaps in
+the zones and upstreams they give, keeping the cache and answering the queries
+it's resolving with the previous ones; other options need a restart.
+
+The server prints what it's doing, like the zones it loads and the queries
+failing; with --verbose (--verbosity verbose) it also prints a line for every
+query answered, with --quiet (--verbosity quiet) only the errors keeping it from
+starting.
+
+With --control the server takes commands on that TCP address (port 953 unless
+given) or Unix socket, from clients holding the key in --control-key (see rdns
+control). With --admin-listen it serves a read only HTTP API reporting its
+health, stats, cache, zones and settings as JSON on that address (port 8053
+unless given), which has no authentication of its own.
+
+With --config the options are first read from that TOML file, its keys named
+after them; those given on the command line override the file, and the ones
+which can be given several times add to its lists.";
+
+/// `rdns serve [OPTIONS]`: run the server configured by the command line and the `--config`
+/// file until SIGTERM or Ctrl-C. The options are described in `SERVE_USAGE`.
+pub fn run(args: &[String]) -> i32 {
+	if wants_help(args) {
+		println!("{}", SERVE_USAGE);
+		return 0;
+	}
+	let ServeOptions {
+		mut context, upstreams, strategy, tls_policy, listeners, zone_files, zone_dbs, schedule, geoip_db, geo_records,
+		blocklists, prefetch, prefetch_min_hits, cache_entries, cache_bytes, ttl_limits, capture_file, capture_filter,
+		capture_duration, shutdown_timeout, slo_latency, slo_objective, tls_listen, https_listen,
+		quic_listen, https_path, tls_cert, tls_key, control, control_key, admin_listen, mirror, mirror_percent,
+		query_log, query_log_percent, trace, trace_file, self_test, views, verbosity,
+	} = match parse_options(args) {
+		Ok(options) => options,
+		Err(e) => {
+			eprintln!("{}", e);
+			return e.code;
+		}
+	};
+	set_verbosity(verbosity);
+	if let Some(filter) = trace {
+		if let Err(e) = init_tracing(&filter, trace_file.as_deref()) {
+			eprintln!("Failed to set up tracing: {}", e);
+			return 1;
+		}
+	}
+	if self_test {
+		return report_self_test(tls_cert.as_deref().zip(tls_key.as_deref()));
+	}
+
+	let control = match control_channel(control, control_key) {
+		Ok(control) => control,
+		Err(e) => {
+			eprintln!("{}", e);
+			return e.code;
+		}
+	};
+
+	let tls_config = match tls_config(tls_listen.or(https_listen).or(quic_listen), tls_cert, tls_key) {
+		Ok(config) => config,
+		Err(e) => {
+			eprintln!("{}", e);
+			return e.code;
+		}
+	};
+	context.cache.set_limits(cache_entries, cache_bytes);
+	context.cache.set_ttl_limits(ttl_limits);
+	context.latency.set_slo(slo_latency.map(|target| LatencySlo { target, objective: slo_objective / 100.0 }));
+	context.cache.set_prefetch(prefetch.map(|prefetch| Prefetch { min_hits: prefetch_min_hits, ..prefetch }));
+
+	match resolve_strategy(upstreams, strategy, tls_policy) {
+		Ok(resolve) => context.set_resolve_strategy(resolve),
+		Err(e) => {
+			eprintln!("Failed to set up the TLS upstreams: {}", e);
+			return 1;
+		}
+	}
+	if let Some(upstream) = mirror {
+		match upstream.with_tls_policy(tls_policy).and_then(|upstream| QueryMirror::start(upstream, mirror_percent)) {
+			Ok(mirror) => context.mirror = Some(mirror),
+			Err(e) => {
+				eprintln!("Failed to set up the mirror: {}", e);
+				return 1;
+			}
+		}
+	}
+	if let Some(path) = query_log {
+		match QueryLog::open(&path, query_log_percent) {
+			Ok(query_log) => context.query_log = Some(query_log),
+			Err(e) => {
+				eprintln!("Failed to open the query log {}: {}", path.display(), e);
+				return 1;
+			}
+		}
+	}
+
+	if !context.hosts.is_empty() {
+		if let Err(e) = context.hosts.load() {
+			eprintln!("Failed to load the hosts: {}", e);
+			return 1;
+		}
+	}
+	match load_blocklists(&blocklists) {
+		Ok(lists) => context.blocklists.replace(lists),
+		Err(e) => {
+			eprintln!("{}", e);
+			return 1;
+		}
+	}
+	if let Err(e) = load_views(&views, tls_policy, context.authority.is_strict())
+		.and_then(|loaded| context.views.replace(loaded, strategy).map_err(|e| e.to_string())) {
+		eprintln!("{}", e);
+		return 1;
+	}
+
+	context.authority.set_schedule(schedule);
+	if let Some(path) = geoip_db {
+		match GeoDatabase::open(&path) {
+			Ok(database) => context.geoip = Some(database),
+			Err(e) => {
+				eprintln!("Failed to open the GeoIP database {}: {}", path.display(), e);
+				return 1;
+			}
+		}
+	}
+	context.authority.set_geo_records(geo_records);
+	for path in zone_dbs {
+		match open_zone_db(&path, context.authority.is_strict()) {
+			Ok(db) => context.authority.add_zone(Arc::new(db)),
+			Err(e) => {
+				eprintln!("Failed to open zone database {}: {}", path, e);
+				return 1;
+			}
+		}
+	}
+
+	// Zone files load in the background, the server answers for each zone once it's complete...
+	let mut loads = Vec::new();
+	for path in zone_files {
+		match load_zone(&context.authority, &path, "", print_progress) {
+			Ok(load) => loads.push((path, load)),
+			Err(e) => {
+				eprintln!("Failed to load zone {}: {}", path, e);
+				return 1;
+			}
+		}
+	}
+	thread::spawn(move || {
+		for (path, load) in loads {
+			if let Err(e) = load.join() {
+				eprintln!("Failed to load zone {}: {}", path, e);
+			}
+		}
+	});
+
+	let listen_addr = context.listen_addr;
+	let context = Arc::new(context);
+
+	for &(addr, role) in &listeners {
+		if let Err(e) = start_listener(&context, addr, role) {
+			eprintln!("Failed to start the server on {}: {}", addr, e);
+			return 1;
+		}
+	}
+	if let (Some(addr), Some(config)) = (tls_listen, &tls_config) {
+		if let Err(e) = DNSTlsServer::new(context.clone(), config.clone(), addr).run_server() {
+			eprintln!("Failed to start the TLS server on {}: {}", addr, e);
+			return 1;
+		}
+	}
+	if let (Some(addr), Some(config)) = (https_listen, &tls_config) {
+		if let Err(e) = DNSHttpsServer::new(context.clone(), config.clone(), addr).with_path(&https_path).run_server() {
+			eprintln!("Failed to start the HTTPS server on {}: {}", addr, e);
+			return 1;
+		}
+	}
+	if let (Some(addr), Some(config)) = (quic_listen, &tls_config) {
+		if let Err(e) = DNSQuicServer::new(context.clone(), config.clone(), addr).run_server() {
+			eprintln!("Failed to start the QUIC server on {}: {}", addr, e);
+			return 1;
+		}
+	}
+	if let Some(addr) = admin_listen {
+		if let Err(e) = AdminServer::new(context.clone(), addr).run_server() {
+			eprintln!("Failed to start the admin API on {}: {}", addr, e);
+			return 1;
+		}
+	}
+	if let Some((addr, key)) = control {
+		let args = args.to_vec();
+		let server = ControlServer::new(context.clone(), addr.clone(), key)
+			.with_reload(Arc::new(move |context: &ServerContext| reload(context, &args).map(|reloaded| reloaded + "\n")));
+		if let Err(e) = server.run_server() {
+			eprintln!("Failed to start the control channel on {}: {}", addr, e);
+			return 1;
+		}
+	}
+	let handle = match start_listener(&context, listen_addr, ServerRole::Full) {
+		Ok(handle) => handle,
+		Err(e) => {
+			eprintln!("Failed to start the server on {}: {}", listen_addr, e);
+			return 1;
+		}
+	};
+	// Every listener is bound before the resolver gets ready, which goes on in the background...
+	ServerContext::initialize(&context);
+	if verbosity > Verbosity::Quiet {
+		println!("Listening on {}", listen_addr);
+	}
+	if let Some(path) = capture_file {
+		if let Err(e) = context.start_capture(&path, capture_filter, capture_duration) {
+			eprintln!("Failed to start the capture to {}: {}", path.display(), e);
+		}
+	}
+	let mut signals = match Signals::new() {
+		Ok(signals) => signals,
+		Err(e) => {
+			eprintln!("Failed to listen for signals, the server can't be shut down gracefully: {}", e);
+			let _ = handle.join();
+			return 1;
+		}
+	};
+	loop {
+		match signals.wait() {
+			Ok(Signal::Reload) => match reload(&context, args) {
+				Ok(reloaded) if verbosity > Verbosity::Quiet => println!("{}", reloaded),
+				Ok(_) => (),
+				Err(e) => eprintln!("{}", e),
+			},
+			Ok(Signal::Terminate) => break,
+			Err(e) => {
+				eprintln!("Failed to listen for signals, the server can't be shut down gracefully: {}", e);
+				let _ = handle.join();
+				return 1;
+			}
+		}
+	}
+	if verbosity > Verbosity::Quiet {
+		println!("Shutting down");
+	}
+	context.shut_down(shutdown_timeout);
+	0
+}
+
+/// `rdns check-config [--config FILE] [OPTIONS]... [--json|--short]`
+/// Checks the options `rdns serve` would be started with, from the configuration file and the
+/// command line alike, without serving: beyond parsing them, the TLS certificate, the TLS
+/// upstreams, the zones and the root hints are all loaded. Exits with 0 when they're valid, 2
+/// when an option is wrong and 1 when a file fails to load.
+///
+/// With `--json` it prints `{"valid": true, "listeners": N, "upstreams": N, "zones": N,
+/// "scheduled": N, "warnings": [...]}`, or `{"valid": false, "error": {...}, "warnings":
+/// [...]}` with the `kind` of the error (`invalid` or `load`), its `message`, and the
+/// `option` and `file` it's about when known. With `--short` it prints `ok` or the error.
+pub fn check(args: &[String]) -> i32 {
+	let (mode, args) = OutputMode::from_args(args);
+	if wants_help(&args) {
+		println!("{}", SERVE_USAGE);
+		return 0;
+	}
+
+	let mut warnings = Vec::new();
+	let checked = parse_options(&args).and_then(|options| check_options(options, &mut warnings));
+	match mode {
+		OutputMode::Text => {
+			for warning in &warnings {
+				eprintln!("Warning: {}", warning);
+			}
+			match checked {
+				Ok(ref summary) => println!("Configuration is valid: {} listeners, {} upstreams, {} zones, {} scheduled record sets",
+					summary.listeners, summary.upstreams, summary.zones, summary.scheduled),
+				Err(ref e) => eprintln!("{}", e),
+			}
+		}
+		OutputMode::Json => match checked {
+			Ok(ref summary) => println!("{}", json!({
+				"valid": true,
+				"listeners": summary.listeners,
+				"upstreams": summary.upstreams,
+				"zones": summary.zones,
+				"scheduled": summary.scheduled,
+				"warnings": warnings,
+			})),
+			Err(ref e) => println!("{}", json!({
+				"valid": false,
+				"error": e.to_json(),
+				"warnings": warnings,
+			})),
+		},
+		OutputMode::Short => match checked {
+			Ok(_) => println!("ok"),
+			Err(ref e) => println!("{}", e),
+		},
+	}
+	checked.map_or_else(|e| e.code, |_| 0)
+}
+
+/// What `check-config` found in valid options.
+struct ConfigSummary {
+	listeners: usize,
+	upstreams: usize,
+	zones: usize,
+	scheduled: usize,
+}
+
+/// Load what `options` point to, adding what's worth a warning to `warnings`, and sum up the
+/// options, or return the first error.
+fn check_options(options: ServeOptions, warnings: &mut Vec<String>) -> Result<ConfigSummary, ConfigError> {
+	tls_config(options.tls_listen.or(options.https_listen).or(options.quic_listen), options.tls_cert, options.tls_key)?;
+	control_channel(options.control, options.control_key)?;
+	let upstreams = options.upstreams.len();
+	resolve_strategy(options.upstreams, options.strategy, options.tls_policy)
+		.map_err(|e| ConfigError::load_any(format!("Failed to set up the TLS upstreams: {}", e)).with_option("--forward"))?;
+	let tls_policy = options.tls_policy;
+	if let Some(Err(e)) = options.mirror.map(|upstream| upstream.with_tls_policy(tls_policy)) {
+		return Err(ConfigError::load_any(format!("Failed to set up the mirror: {}", e)).with_option("--mirror"));
+	}
+
+	if let Some(ref path) = options.geoip_db {
+		GeoDatabase::open(path)
+			.map_err(|e| ConfigError::load(path.display(), format!("Failed to open the GeoIP database {}: {}", path.display(), e)))?;
+	}
+
+	let authority = &options.context.authority;
+	let strict = authority.is_strict();
+	for path in &options.zone_dbs {
+		let db = open_zone_db(path, strict).map_err(|e| ConfigError::load(path, format!("Failed to open zone database {}: {}", path, e)))?;
+		authority.add_zone(Arc::new(db));
+	}
+	for path in &options.zone_files {
+		let zone = read_zone(path, "", strict).map_err(|e| ConfigError::load(path, format!("Failed to load zone {}: {}", path, e)))?;
+		authority.add_zone(zone);
+	}
+	// Delegations drifting from their child zones are worth a warning, not a failure...
+	for check in authority.check_delegations() {
+		for issue in &check.issues {
+			warnings.push(format!("delegation of {} from {}: {}", check.child, check.parent, issue));
+		}
+	}
+	options.context.hosts.load().map_err(|e| ConfigError::load_any(format!("Failed to load the hosts: {}", e)))?;
+	load_blocklists(&options.blocklists).map_err(ConfigError::load_any)?;
+	let (views, strategy) = (&options.context.views, options.strategy);
+	load_views(&options.views, tls_policy, strict)
+		.and_then(|loaded| views.replace(loaded, strategy).map_err(|e| e.to_string()))
+		.map_err(ConfigError::load_any)?;
+	if let Some(ref path) = options.context.root_hints_file {
+		load_root_hints(path)
+			.map_err(|e| ConfigError::load(path.display(), format!("Failed to load the root hints from {}: {}", path.display(), e)))?;
+	}
+
+	Ok(ConfigSummary {
+		listeners: 1 + options.listeners.len()
+			+ [options.tls_listen, options.https_listen, options.quic_listen].iter().flatten().count(),
+		upstreams,
+		zones: options.zone_dbs.len() + options.zone_files.len(),
+		scheduled: options.schedule.len(),
+	})
+}
+
+/// The options of `rdns serve`, from the configuration file and the command line.
+struct ServeOptions {
+	context: ServerContext,
+	upstreams: Vec<Upstream>,
+	strategy: SelectionStrategy,
+	tls_policy: TlsPolicy,
+	listeners: Vec<(SocketAddr, ServerRole)>,
+	zone_files: Vec<String>,
+	zone_dbs: Vec<String>,
+	schedule: Schedule,
+	geoip_db: Option<PathBuf>,
+	geo_records: GeoRecords,
+	blocklists: Vec<PathBuf>,
+	prefetch: Option<Prefetch>,
+	prefetch_min_hits: u32,
+	cache_entries: usize,
+	cache_bytes: Option<usize>,
+	ttl_limits: TtlLimits,
+	capture_file: Option<PathBuf>,
+	capture_filter: CaptureFilter,
+	capture_duration: Duration,
+	shutdown_timeout: Duration,
+	slo_latency: Option<Duration>,
+	slo_objective: f64,
+	tls_listen: Option<SocketAddr>,
+	https_listen: Option<SocketAddr>,
+	quic_listen: Option<SocketAddr>,
+	https_path: String,
+	tls_cert: Option<PathBuf>,
+	tls_key: Option<PathBuf>,
+	control: Option<ControlAddr>,
+	control_key: Option<PathBuf>,
+	admin_listen: Option<SocketAddr>,
+	mirror: Option<Upstream>,
+	mirror_percent: f64,
+	query_log: Option<PathBuf>,
+	query_log_percent: f64,
+	trace: Option<String>,
+	trace_file: Option<PathBuf>,
+	self_test: bool,
+	views: Vec<ViewSpec>,
+	verbosity: Verbosity,
+}
+
+/// A view as the options give it, loaded by `load_views`.
+#[derive(Default)]
+struct ViewSpec {
+	name: String,
+	networks: Vec<String>,
+	zones: Vec<String>,
+	upstreams: Vec<Upstream>,
+	strategy: Option<SelectionStrategy>,
+	hosts: Vec<String>,
+}
+
+/// Read the configuration file and the command line, returning the exit code when either
+/// is wrong.
+fn parse_options(args: &[String]) -> Result<ServeOptions, ConfigError> {
+	let mut context = ServerContext::new();
+	let mut upstreams = Vec::new();
+	let mut strategy = SelectionStrategy::Failover;
+	let mut tls_policy = TlsPolicy::Strict;
+	let mut listeners = Vec::new();
+	let mut zone_files = Vec::new();
+	let mut zone_dbs = Vec::new();
+	let mut schedule = Schedule::new();
+	let mut geoip_db = None;
+	let mut geo_records = GeoRecords::new();
+	let mut mdns_services = Vec::new();
+	let mut blocklists = Vec::new();
+	let mut prefetch: Option<Prefetch> = None;
+	let mut prefetch_min_hits = DEFAULT_PREFETCH_MIN_HITS;
+	let mut cache_entries = DEFAULT_MAX_ENTRIES;
+	let mut cache_bytes = None;
+	let mut ttl_limits = TtlLimits::default();
+	let mut capture_file = None;
+	let mut capture_filter = CaptureFilter::default();
+	let mut capture_duration = DEFAULT_CAPTURE_DURATION;
+	let mut shutdown_timeout = DEFAULT_SHUTDOWN_TIMEOUT;
+	let mut slo_latency = None;
+	let mut slo_objective = DEFAULT_SLO_OBJECTIVE;
+	let mut tls_listen = None;
+	let mut https_listen = None;
+	let mut quic_listen = None;
+	let mut https_path = DEFAULT_HTTPS_PATH.to_string();
+	let mut tls_cert = None;
+	let mut tls_key = None;
+	let mut control = None;
+	let mut control_key = None;
+	let mut admin_listen = None;
+	let mut mirror = None;
+	let mut mirror_percent = DEFAULT_MIRROR_PERCENT;
+	let mut query_log = None;
+	let mut query_log_percent = DEFAULT_QUERY_LOG_PERCENT;
+	let mut trace = None;
+	let mut trace_file = None;
+	let mut self_test = false;
+	let mut views = Vec::new();
+	let mut name_policies = NamePolicies::new();
+	let mut rate_limit = None;
+	let mut rrl = None;
+	let mut rrl_slip = DEFAULT_SLIP;
+	let mut rate_limit_burst = None;
+	let mut rate_limit_ipv6_prefix = DEFAULT_IPV6_PREFIX;
+	let mut rate_limit_action = LimitAction::Truncate;
+	let mut detect_tunnels = false;
+	let mut tunnel_throttle = None;
+	let mut verbosity = Verbosity::Normal;
+
+	// Sets an option, from the configuration file or the command line, switches getting an
+	// empty value...
+	let mut set = |arg: &str, value: &str| -> Result<(), String> {
+		match arg {
+			"--no-qname-minimization" => {
+				context.qname_minimization = false;
+				Ok(())
+			}
+			"--strict-zones" => {
+				context.authority.set_strict(true);
+				Ok(())
+			}
+			"--pass-through" => {
+				context.pass_through = true;
+				Ok(())
+			}
+			"--crash-on-panic" => {
+				context.crash_on_panic = true;
+				Ok(())
+			}
+			"--self-test" => {
+				self_test = true;
+				Ok(())
+			}
+			"--minimal-responses" => {
+				context.minimal_responses = true;
+				Ok(())
+			}
+			"--listen" | "-l" => value.parse::<SocketAddr>()
+				.map(|addr| context.listen_addr = addr)
+				.map_err(|_| format!("Invalid listen address: {}", value)),
+			"--listener" => parse_listener(value)
+				.map(|listener| listeners.push(listener))
+				.ok_or_else(|| format!("Invalid listener: {}", value)),
+			"--tls-listen" => value.parse::<SocketAddr>()
+				.or_else(|_| value.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, DEFAULT_TLS_PORT)))
+				.map(|addr| tls_listen = Some(addr))
+				.map_err(|_| format!("Invalid TLS listen address: {}", value)),
+			"--https-listen" => value.parse::<SocketAddr>()
+				.or_else(|_| value.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, DEFAULT_HTTPS_PORT)))
+				.map(|addr| https_listen = Some(addr))
+				.map_err(|_| format!("Invalid HTTPS listen address: {}", value)),
+			"--quic-listen" => value.parse::<SocketAddr>()
+				.or_else(|_| value.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, DEFAULT_QUIC_PORT)))
+				.map(|addr| quic_listen = Some(addr))
+				.map_err(|_| format!("Invalid QUIC listen address: {}", value)),
+			"--https-path" => Some(value)
+				.filter(|path| path.starts_with('/') && !path.contains('?'))
+				.map(|path| https_path = path.to_string())
+				.ok_or_else(|| format!("Invalid HTTPS path: {}", value)),
+			"--tls-cert" => {
+				tls_cert = Some(PathBuf::from(value));
+				Ok(())
+			}
+			"--tls-key" => {
+				tls_key = Some(PathBuf::from(value));
+				Ok(())
+			}
+			"--non-recursive" => match value {
+				"refuse" => Ok(NonRecursivePolicy::Refuse),
+				"referral" => Ok(NonRecursivePolicy::Referral),
+				_ => Err(format!("Unknown non-recursive policy: {}", value)),
+			}.map(|policy| context.non_recursive = policy),
+			"--internal-override" => InternalOverride::parse(value)
+				.map(|entry| match context.internal_overrides.iter_mut().find(|known| known.name == entry.name) {
+					Some(known) => known.addrs.extend(entry.addrs),
+					None => context.internal_overrides.push(entry),
+				})
+				.ok_or_else(|| format!("Invalid internal override: {}", value)),
+			"--no-compression" => context.disable_compression_for(value).map_err(|e| e.to_string()),
+			"--filter-aaaa" => context.family_filter.add(QueryType::AAAA, value).map_err(|e| e.to_string()),
+			"--filter-a" => context.family_filter.add(QueryType::A, value).map_err(|e| e.to_string()),
+			"--allow-query" => context.access_control.allow(Access::Query, value).map_err(|e| e.to_string()),
+			"--allow-recursion" => context.access_control.allow(Access::Recursion, value).map_err(|e| e.to_string()),
+			"--allow-transfer" => context.access_control.allow(Access::Transfer, value).map_err(|e| e.to_string()),
+			"--hosts-file" => {
+				context.hosts.set_file(Path::new(value));
+				Ok(())
+			}
+			"--host" => context.hosts.add_entry(value).map_err(|e| e.to_string()),
+			"--blocklist" => {
+				blocklists.push(PathBuf::from(value));
+				Ok(())
+			}
+			"--doctor" => context.doctor.add_pair(value).map_err(|e| e.to_string()),
+			"--doctor-inside" => context.doctor.add_inside(value).map_err(|e| e.to_string()),
+			"--view" => view_spec(&mut views, value)
+				.map(|(view, networks)| view.networks.extend(networks.split(',').map(str::to_string))),
+			"--view-zone" => view_spec(&mut views, value)
+				.map(|(view, path)| view.zones.push(path.to_string())),
+			"--view-forward" => view_spec(&mut views, value)
+				.and_then(|(view, spec)| Upstream::parse(spec).map(|upstream| view.upstreams.push(upstream)).map_err(|e| e.to_string())),
+			"--view-strategy" => view_spec(&mut views, value)
+				.and_then(|(view, name)| SelectionStrategy::from_name(name)
+					.map(|selected| view.strategy = Some(selected))
+					.ok_or_else(|| format!("Unknown upstream selection strategy: {}", name))),
+			"--view-host" => view_spec(&mut views, value)
+				.map(|(view, entry)| view.hosts.push(entry.to_string())),
+			"--block-action" => BlockAction::from_name(value)
+				.map(|action| context.blocklists.set_action(action))
+				.ok_or_else(|| format!("Unknown block action: {}", value)),
+			"--name-policy" => NamePolicy::parse(value)
+				.map(|policy| name_policies.add(policy))
+				.map_err(|e| e.to_string()),
+			"--detect-tunnels" => {
+				detect_tunnels = true;
+				Ok(())
+			}
+			"--tunnel-throttle" => value.parse::<f64>()
+				.ok()
+				.filter(|rate| *rate > 0.0 && rate.is_finite())
+				.map(|rate| tunnel_throttle = Some(rate))
+				.ok_or_else(|| format!("Invalid tunnel throttle: {}", value)),
+			"--forward" | "-f" => Upstream::parse(value)
+				.map(|upstream| upstreams.push(upstream))
+				.map_err(|e| e.to_string()),
+			"--tls-upstream-policy" => TlsPolicy::from_name(value)
+				.map(|policy| tls_policy = policy)
+				.ok_or_else(|| format!("Unknown TLS upstream policy: {}", value)),
+			"--mirror" => Upstream::parse(value)
+				.map(|upstream| mirror = Some(upstream))
+				.map_err(|e| e.to_string()),
+			"--mirror-percent" => value.parse::<f64>()
+				.ok()
+				.filter(|percent| *percent > 0.0 && *percent <= 100.0)
+				.map(|percent| mirror_percent = percent)
+				.ok_or_else(|| format!("Invalid mirror percentage: {}", value)),
+			"--upstream-timeout-ms" => value.parse::<u64>()
+				.ok()
+				.filter(|ms| *ms > 0)
+				.map(|ms| context.client.set_retry(context.client.retry().with_timeout(Duration::from_millis(ms))))
+				.ok_or_else(|| format!("Invalid upstream timeout: {}", value)),
+			"--upstream-attempts" => value.parse::<u32>()
+				.ok()
+				.filter(|attempts| *attempts > 0)
+				.map(|attempts| context.client.set_retry(context.client.retry().with_attempts(attempts)))
+				.ok_or_else(|| format!("Invalid number of upstream attempts: {}", value)),
+			"--upstream-deadline-ms" => value.parse::<u64>()
+				.ok()
+				.filter(|ms| *ms > 0)
+				.map(|ms| context.client.set_retry(context.client.retry().with_deadline(Some(Duration::from_millis(ms)))))
+				.ok_or_else(|| format!("Invalid upstream deadline: {}", value)),
+			"--upstream-backoff-ms" => value.parse::<u64>()
+				.map(|ms| context.client.set_retry(context.client.retry().with_backoff(Duration::from_millis(ms), MAX_BACKOFF)))
+				.map_err(|_| format!("Invalid upstream backoff: {}", value)),
+			"--strategy" => SelectionStrategy::from_name(value)
+				.map(|selected| strategy = selected)
+				.ok_or_else(|| format!("Unknown upstream selection strategy: {}", value)),
+			"--threads" | "-t" => value.parse::<usize>()
+				.map(|threads| context.worker_threads = threads)
+				.map_err(|_| format!("Invalid number of threads: {}", value)),
+			"--udp-sockets" => value.parse::<usize>()
+				.ok()
+				.filter(|sockets| *sockets > 0)
+				.map(|sockets| context.udp_sockets = sockets)
+				.ok_or_else(|| format!("Invalid number of UDP sockets: {}", value)),
+			"--tcp-max-connections" => value.parse::<usize>()
+				.map(|connections| context.tcp_max_connections = connections)
+				.map_err(|_| format!("Invalid number of connections: {}", value)),
+			"--edns-max-payload" => value.parse::<u16>()
+				.ok()
+				.filter(|size| *size >= 512)
+				.map(|size| context.set_edns_max_payload(size))
+				.ok_or_else(|| format!("Invalid EDNS payload size: {}", value)),
+			"--tcp-idle-timeout" => value.parse::<u64>()
+				.ok()
+				.filter(|secs| *secs > 0)
+				.map(|secs| context.tcp_idle_timeout = Duration::from_secs(secs))
+				.ok_or_else(|| format!("Invalid idle timeout: {}", value)),
+			"--zone" | "-z" => {
+				zone_files.push(value.to_string());
+				Ok(())
+			}
+			"--zone-db" => {
+				zone_dbs.push(value.to_string());
+				Ok(())
+			}
+			"--schedule" => Schedule::parse(value)
+				.map(|(window, record)| schedule.add(window, record))
+				.map_err(|e| format!("Invalid scheduled record {}: {}", value, e)),
+			"--geoip-db" => {
+				geoip_db = Some(PathBuf::from(value));
+				Ok(())
+			}
+			"--rotate" => context.rotation.add_zone(value).map_err(|e| e.to_string()),
+			"--answer-weight" => context.rotation.add_weight(value).map_err(|e| e.to_string()),
+			"--health-check" => context.endpoints.add(value).map_err(|e| e.to_string()),
+			"--mdns-host" => context.mdns.add_host(value).map_err(|e| e.to_string()),
+			"--mdns-interface" => value.parse::<Ipv4Addr>()
+				.map(|addr| context.mdns.set_interface(addr))
+				.map_err(|_| format!("Invalid mDNS interface address: {}", value)),
+			"--mdns-service" => ServiceRegistration::parse(value)
+				.map(|registration| mdns_services.push(registration))
+				.map_err(|e| e.to_string()),
+			"--geo-record" => GeoRecords::parse(value)
+				.map(|(policy, record)| geo_records.add(policy, record))
+				.map_err(|e| format!("Invalid geo record {}: {}", value, e)),
+			"--health-interval" => value.parse::<u64>()
+				.map(|secs| context.health_check_interval = if secs == 0 { None } else { Some(Duration::from_secs(secs)) })
+				.map_err(|_| format!("Invalid health check interval: {}", value)),
+			"--check-delegations" => value.parse::<u64>()
+				.map(|secs| context.delegation_check_interval = if secs == 0 { None } else { Some(Duration::from_secs(secs)) })
+				.map_err(|_| format!("Invalid delegation check interval: {}", value)),
+			"--publish-delegations" => {
+				context.publish_delegations = true;
+				Ok(())
+			}
+			"--max-stale" => value.parse::<u64>()
+				.map(|secs| context.cache.set_max_stale(if secs == 0 { None } else { Some(Duration::from_secs(secs)) }))
+				.map_err(|_| format!("Invalid max stale duration: {}", value)),
+			"--max-amplification" => value.parse::<f64>()
+				.ok()
+				.filter(|ratio| *ratio >= 1.0)
+				.map(|ratio| context.amplification = Some(AmplificationGuard::new(ratio)))
+				.ok_or_else(|| format!("Invalid amplification ratio: {}", value)),
+			"--rrl" => value.parse::<f64>()
+				.ok()
+				.filter(|rate| *rate > 0.0 && rate.is_finite())
+				.map(|rate| rrl = Some(rate))
+				.ok_or_else(|| format!("Invalid response rate: {}", value)),
+			"--rrl-slip" => value.parse::<u32>()
+				.map(|slip| rrl_slip = slip)
+				.map_err(|_| format!("Invalid RRL slip: {}", value)),
+			"--rate-limit" => value.parse::<f64>()
+				.ok()
+				.filter(|rate| *rate > 0.0 && rate.is_finite())
+				.map(|rate| rate_limit = Some(rate))
+				.ok_or_else(|| format!("Invalid rate limit: {}", value)),
+			"--rate-limit-burst" => value.parse::<u32>()
+				.ok()
+				.filter(|burst| *burst > 0)
+				.map(|burst| rate_limit_burst = Some(burst as f64))
+				.ok_or_else(|| format!("Invalid rate limit burst: {}", value)),
+			"--rate-limit-ipv6-prefix" => value.parse::<u8>()
+				.ok()
+				.filter(|len| *len > 0 && *len <= 128)
+				.map(|len| rate_limit_ipv6_prefix = len)
+				.ok_or_else(|| format!("Invalid IPv6 prefix length: {}", value)),
+			"--rate-limit-action" => LimitAction::from_name(value)
+				.map(|action| rate_limit_action = action)
+				.ok_or_else(|| format!("Unknown rate limit action: {}", value)),
+			"--prefetch" => value.parse::<u32>()
+				.ok()
+				.filter(|percent| *percent <= 100)
+				.map(|percent| prefetch = if percent == 0 { None } else { Some(Prefetch { percent, min_hits: 0 }) })
+				.ok_or_else(|| format!("Invalid prefetch percentage: {}", value)),
+			"--prefetch-min-hits" => value.parse::<u32>()
+				.map(|hits| prefetch_min_hits = hits)
+				.map_err(|_| format!("Invalid number of hits: {}", value)),
+			"--cache-size" => value.parse::<usize>()
+				.map(|entries| cache_entries = entries)
+				.map_err(|_| format!("Invalid cache size: {}", value)),
+			"--hot-cache" => value.parse::<usize>()
+				.map(|entries| context.cache.set_hot_entries(entries))
+				.map_err(|_| format!("Invalid hot cache size: {}", value)),
+			"--cache-memory" => value.parse::<usize>()
+				.map(|megabytes| cache_bytes = Some(megabytes * 1024 * 1024))
+				.map_err(|_| format!("Invalid cache memory limit: {}", value)),
+			"--min-ttl" => value.parse::<u32>()
+				.map(|secs| ttl_limits.min = secs)
+				.map_err(|_| format!("Invalid minimum TTL: {}", value)),
+			"--max-ttl" => value.parse::<u32>()
+				.map(|secs| ttl_limits.max = secs)
+				.map_err(|_| format!("Invalid maximum TTL: {}", value)),
+			"--max-answers" => value.parse::<usize>()
+				.ok()
+				.filter(|answers| *answers > 0)
+				.map(|answers| context.response_limits.max_answers = answers)
+				.ok_or_else(|| format!("Invalid number of answers: {}", value)),
+			"--max-response-ttl" => value.parse::<u32>()
+				.map(|secs| context.response_limits.max_ttl = secs)
+				.map_err(|_| format!("Invalid maximum response TTL: {}", value)),
+			"--max-cname-chain" => value.parse::<usize>()
+				.map(|records| context.response_limits.max_cname_chain = records)
+				.map_err(|_| format!("Invalid CNAME chain length: {}", value)),
+			"--max-negative-ttl" => value.parse::<u32>()
+				.map(|secs| ttl_limits.max_negative = secs)
+				.map_err(|_| format!("Invalid maximum negative TTL: {}", value)),
+			"--critical-name" => Some(value)
+				.filter(|name| !name.is_empty())
+				.map(|name| context.last_known_good.add_name(name))
+				.ok_or_else(|| format!("Invalid critical name: {}", value)),
+			"--cache-file" => {
+				context.cache_file = Some(PathBuf::from(value));
+				Ok(())
+			}
+			"--shutdown-timeout" => value.parse::<u64>()
+				.map(|secs| shutdown_timeout = Duration::from_secs(secs))
+				.map_err(|_| format!("Invalid shutdown timeout: {}", value)),
+			"--cache-snapshot-interval" => value.parse::<u64>()
+				.ok()
+				.filter(|secs| *secs > 0)
+				.map(|secs| context.cache_snapshot_interval = Duration::from_secs(secs))
+				.ok_or_else(|| format!("Invalid snapshot interval: {}", value)),
+			"--capture-file" => {
+				capture_file = Some(PathBuf::from(value));
+				Ok(())
+			}
+			"--capture" => CaptureFilter::parse(value)
+				.map(|filter| capture_filter = filter)
+				.map_err(|e| e.to_string()),
+			"--capture-duration" => value.parse::<u64>()
+				.ok()
+				.filter(|secs| *secs > 0)
+				.map(|secs| capture_duration = Duration::from_secs(secs))
+				.ok_or_else(|| format!("Invalid capture duration: {}", value)),
+			"--slow-query-ms" => value.parse::<u64>()
+				.map(|ms| context.latency.set_slow_threshold(Some(Duration::from_millis(ms))))
+				.map_err(|_| format!("Invalid slow query threshold: {}", value)),
+			"--slo-latency-ms" => value.parse::<u64>()
+				.map(|ms| slo_latency = Some(Duration::from_millis(ms)))
+				.map_err(|_| format!("Invalid SLO latency: {}", value)),
+			"--slo-objective" => value.parse::<f64>()
+				.ok()
+				.filter(|percent| *percent > 0.0 && *percent < 100.0)
+				.map(|percent| slo_objective = percent)
+				.ok_or_else(|| format!("Invalid SLO objective: {}", value)),
+			"--query-log" => {
+				query_log = Some(PathBuf::from(value));
+				Ok(())
+			}
+			"--query-log-percent" => value.parse::<f64>()
+				.ok()
+				.filter(|percent| *percent > 0.0 && *percent <= 100.0)
+				.map(|percent| query_log_percent = percent)
+				.ok_or_else(|| format!("Invalid query log percentage: {}", value)),
+			"--trace" => Some(value)
+				.filter(|filter| is_trace_filter(filter))
+				.map(|filter| trace = Some(filter.to_string()))
+				.ok_or_else(|| format!("Invalid trace filter: {}", value)),
+			"--trace-file" => {
+				trace_file = Some(PathBuf::from(value));
+				Ok(())
+			}
+			"--root-hints" => {
+				context.root_hints_file = Some(PathBuf::from(value));
+				Ok(())
+			}
+			"--control" => {
+				control = Some(ControlAddr::parse(value));
+				Ok(())
+			}
+			"--control-key" => {
+				control_key = Some(PathBuf::from(value));
+				Ok(())
+			}
+			"--admin-listen" => value.parse::<SocketAddr>()
+				.or_else(|_| value.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, DEFAULT_ADMIN_PORT)))
+				.map(|addr| admin_listen = Some(addr))
+				.map_err(|_| format!("Invalid admin listen address: {}", value)),
+			"--verbose" | "-v" => {
+				verbosity = Verbosity::Verbose;
+				Ok(())
+			}
+			"--quiet" | "-q" => {
+				verbosity = Verbosity::Quiet;
+				Ok(())
+			}
+			"--verbosity" => Verbosity::from_name(value)
+				.map(|level| verbosity = level)
+				.ok_or_else(|| format!("Invalid verbosity: {}", value)),
+			"--config" => Ok(()),
+			_ => Err(format!("Unknown option: {}", arg)),
+		}
+	};
+
+	// The configuration file goes first, for the command line to override it...
+	if let Some(path) = config_path(args) {
+		let config = match Config::load(Path::new(path)) {
+			Ok(config) => config,
+			Err(e) => return Err(ConfigError::invalid(format!("Failed to read {}: {}", path, e)).with_file(path)),
+		};
+		for option in config.options() {
+			if let Err(e) = set(option.option, option.value.as_deref().unwrap_or("")) {
+				return Err(ConfigError::invalid(format!("{}: {}: {}", path, option.key, e)).with_option(&option.key).with_file(path));
+			}
+		}
+	}
+
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		let value = if is_switch(arg) {
+			""
+		} else {
+			match iter.next() {
+				Some(value) => value.as_str(),
+				None => return Err(ConfigError::invalid(format!("{} needs a value", arg)).with_option(arg)),
+			}
+		};
+		set(arg, value).map_err(|e| ConfigError::invalid(e).with_option(arg))?;
+	}
+
+	if ttl_limits.min > ttl_limits.max {
+		return Err(ConfigError::invalid("--min-ttl (cache.min-ttl) is above --max-ttl (cache.max-ttl)").with_option("--min-ttl"));
+	}
+	if tunnel_throttle.is_some() && !detect_tunnels {
+		return Err(ConfigError::invalid("--tunnel-throttle (policies.tunnel-throttle) needs --detect-tunnels").with_option("--tunnel-throttle"));
+	}
+	if !geo_records.is_empty() && geoip_db.is_none() {
+		return Err(ConfigError::invalid("--geo-record (geoip.records) needs --geoip-db (geoip.database)").with_option("--geo-record"));
+	}
+	if !context.endpoints.is_empty() && context.health_check_interval.is_none() {
+		return Err(ConfigError::invalid("--health-check (zones.health-checks) needs a --health-interval (upstreams.health-interval) above 0")
+			.with_option("--health-check"));
+	}
+	// Services are registered once all the hosts are known, whatever the order given...
+	for registration in mdns_services {
+		context.mdns.add_service(registration).map_err(|e| ConfigError::invalid(e.to_string()).with_option("--mdns-service"))?;
+	}
+	if let Some(rate) = rrl {
+		context.rrl = Some(ResponseRateLimit::new(rate, rrl_slip));
+	}
+	if let Some(rate) = rate_limit {
+		let buckets = TokenBuckets::by_prefix(rate, rate_limit_burst.unwrap_or(rate), 32, rate_limit_ipv6_prefix);
+		context.query_hooks.add(Arc::new(ClientRateLimit::new(buckets, rate_limit_action)));
+	}
+	if !name_policies.is_empty() {
+		context.query_hooks.add(Arc::new(name_policies));
+	}
+	if detect_tunnels {
+		context.query_hooks.add(Arc::new(TunnelDetector::new(TunnelThresholds::default(), tunnel_throttle)));
+	}
+
+	Ok(ServeOptions {
+		context,
+		upstreams,
+		strategy,
+		tls_policy,
+		listeners,
+		zone_files,
+		zone_dbs,
+		schedule,
+		geoip_db,
+		geo_records,
+		blocklists,
+		prefetch,
+		prefetch_min_hits,
+		cache_entries,
+		cache_bytes,
+		ttl_limits,
+		capture_file,
+		capture_filter,
+		capture_duration,
+		shutdown_timeout,
+		slo_latency,
+		slo_objective,
+		tls_listen,
+		https_listen,
+		quic_listen,
+		https_path,
+		tls_cert,
+		tls_key,
+		control,
+		control_key,
+		admin_listen,
+		mirror,
+		mirror_percent,
+		query_log,
+		query_log_percent,
+		trace,
+		trace_file,
+		self_test,
+		views,
+		verbosity,
+	})
+}
+
+/// Read the configuration again, on SIGHUP or the `reload` control command, and swap in its
+/// zones, schedule, geo records, blocklists, views and upstreams. The other options only
+/// change on restart. Nothing changes when the configuration is wrong or a zone or blocklist
+/// fails to load. Returns what was reloaded, or why nothing was.
+fn reload(context: &ServerContext, args: &[String]) -> Result<String, String> {
+	if verbosity() > Verbosity::Quiet {
+		println!("Reloading the configuration");
+	}
+	let options = parse_options(args).map_err(|e| format!("Invalid configuration, keeping the current one: {}", e))?;
+	let strict = options.context.authority.is_strict();
+
+	let mut zones: Vec<SharedZone> = Vec::new();
+	for path in &options.zone_dbs {
+		let db = open_zone_db(path, strict)
+			.map_err(|e| format!("Failed to open zone database {}, keeping the current configuration: {}", path, e))?;
+		zones.push(Arc::new(db));
+	}
+	for path in &options.zone_files {
+		let zone = read_zone(path, "", strict)
+			.map_err(|e| format!("Failed to load zone {}, keeping the current configuration: {}", path, e))?;
+		zones.push(zone);
+	}
+	let blocklists = load_blocklists(&options.blocklists).map_err(|e| format!("{}, keeping the current configuration", e))?;
+	let views = load_views(&options.views, options.tls_policy, strict).map_err(|e| format!("{}, keeping the current configuration", e))?;
+	let view_count = views.len();
+	context.views.replace(views, options.strategy)
+		.map_err(|e| format!("{}, keeping the current configuration", e))?;
+	let resolve = resolve_strategy(options.upstreams, options.strategy, options.tls_policy)
+		.map_err(|e| format!("Failed to set up the TLS upstreams, keeping the current configuration: {}", e))?;
+
+	let count = zones.len();
+	let lists = blocklists.len();
+	context.authority.set_schedule(options.schedule);
+	context.authority.set_geo_records(options.geo_records);
+	context.blocklists.replace(blocklists);
+	context.reload(zones, resolve);
+	Ok(format!("Reloaded {} zones, {} blocklists and {} views", count, lists, view_count))
+}
+
+/// The view named in `spec`, given as `NAME:VALUE`, added to `views` when it's new, along
+/// with the value.
+fn view_spec<'v, 's>(views: &'v mut Vec<ViewSpec>, spec: &'s str) -> Result<(&'v mut ViewSpec, &'s str), String> {
+	let (name, value) = spec.split_once(':')
+		.filter(|(name, value)| !name.is_empty() && !value.is_empty())
+		.ok_or_else(|| format!("Invalid view option, expected NAME:VALUE: {}", spec))?;
+	let index = match views.iter().position(|view| view.name == name) {
+		Some(index) => index,
+		None => {
+			views.push(ViewSpec { name: name.to_string(), ..ViewSpec::default() });
+			views.len() - 1
+		}
+	};
+	Ok((&mut views[index], value))
+}
+
+/// Build the views of `specs`, their zones loaded and their upstreams set up, returning why
+/// when one of them is wrong. Their hosts are loaded by `Views::replace`.
+fn load_views(specs: &[ViewSpec], tls_policy: TlsPolicy, strict: bool) -> Result<Vec<View>, String> {
+	specs.iter().map(|spec| {
+		let invalid = |e: io::Error| format!("Invalid view {}: {}", spec.name, e);
+		let mut view = View::new(&spec.name);
+		for network in &spec.networks {
+			view.add_network(network).map_err(invalid)?;
+		}
+		for entry in &spec.hosts {
+			view.hosts_mut().add_entry(entry).map_err(invalid)?;
+		}
+		for upstream in &spec.upstreams {
+			view.add_upstream(upstream.clone().with_tls_policy(tls_policy).map_err(invalid)?);
+		}
+		if let Some(strategy) = spec.strategy {
+			view.set_strategy(strategy);
+		}
+		for path in &spec.zones {
+			let zone = read_zone(path, "", strict).map_err(|e| format!("Failed to load zone {} of view {}: {}", path, spec.name, e))?;
+			view.add_zone(zone);
+		}
+		Ok(view)
+	}).collect()
+}
+
+/// Read the blocklists of `paths`, returning why when one fails to load.
+fn load_blocklists(paths: &[PathBuf]) -> Result<Vec<BlockList>, String> {
+	paths.iter()
+		.map(|path| BlockList::load(path).map_err(|e| format!("Failed to load blocklist {}: {}", path.display(), e)))
+		.collect()
+}
+
+/// The TLS configuration of the TLS, HTTPS and QUIC listeners when there's one of them,
+/// failing when the certificate is missing or fails to load.
+fn tls_config(listen: Option<SocketAddr>, cert: Option<PathBuf>, key: Option<PathBuf>) -> Result<Option<Arc<ServerConfig>>, ConfigError> {
+	match (listen, cert, key) {
+		(Some(_), Some(cert), Some(key)) => load_server_config(&cert, &key)
+			.map(Some)
+			.map_err(|e| ConfigError::load(cert.display(), format!("Failed to load the TLS certificate: {}", e))),
+		(Some(_), _, _) => Err(ConfigError::invalid("--tls-listen, --https-listen and --quic-listen need --tls-cert and --tls-key (tls.cert and tls.key)")
+			.with_option("--tls-cert")),
+		_ => Ok(None),
+	}
+}
+
+/// The address and key of the control channel when there's one, failing when the key is
+/// missing or can't be read.
+fn control_channel(addr: Option<ControlAddr>, key: Option<PathBuf>) -> Result<Option<(ControlAddr, Vec<u8>)>, ConfigError> {
+	match (addr, key) {
+		(Some(addr), Some(key)) => read_key(&key)
+			.map(|key| Some((addr, key)))
+			.map_err(|e| ConfigError::load(key.display(), format!("Failed to read the control key {}: {}", key.display(), e))),
+		(Some(_), None) => Err(ConfigError::invalid("--control needs --control-key (control.key-file)").with_option("--control-key")),
+		_ => Ok(None),
+	}
+}
+
+/// Why the options of `rdns serve` can't be used: an option that's wrong, or a file failing
+/// to load.
+#[derive(Debug)]
+struct ConfigError {
+	/// Exit code, 2 for an option that's wrong and 1 for a file failing to load...
+	code: i32,
+	/// Option, or key of the configuration file, the error is about...
+	option: Option<String>,
+	/// File the error is about, the configuration file or the one failing to load...
+	file: Option<String>,
+	message: String,
+}
+
+impl ConfigError {
+	fn invalid<M: Into<String>>(message: M) -> Self {
+		ConfigError { code: 2, option: None, file: None, message: message.into() }
+	}
+
+	fn load<F: ToString>(file: F, message: String) -> Self {
+		ConfigError { code: 1, option: None, file: Some(file.to_string()), message }
+	}
+
+	/// Something failing to load, the message telling what.
+	fn load_any(message: String) -> Self {
+		ConfigError { code: 1, option: None, file: None, message }
+	}
+
+	fn with_option(mut self, option: &str) -> Self {
+		self.option = Some(option.to_string());
+		self
+	}
+
+	fn with_file(mut self, file: &str) -> Self {
+		self.file = Some(file.to_string());
+		self
+	}
+
+	fn to_json(&self) -> serde_json::Value {
+		json!({
+			"kind": if self.code == 1 { "load" } else { "invalid" },
+			"message": self.message,
+			"option": self.option,
+			"file": self.file,
+		})
+	}
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.message)
+	}
+}
+
+/// Forward to `upstreams` when there are any, or resolve recursively.
+fn resolve_strategy(upstreams: Vec<Upstream>, strategy: SelectionStrategy, tls_policy: TlsPolicy) -> io::Result<ResolveStrategy> {
+	if upstreams.is_empty() {
+		return Ok(ResolveStrategy::Recursive);
+	}
+	let upstreams = upstreams.into_iter().map(|upstream| upstream.with_tls_policy(tls_policy)).collect::<io::Result<_>>()?;
+	Ok(ResolveStrategy::Forward { upstreams: Arc::new(UpstreamPool::new(upstreams, strategy)) })
+}
+
+/// Open the zone database at `path`, refusing one holding obsolete record types in strict
+/// mode.
+fn open_zone_db(path: &str, strict: bool) -> io::Result<ZoneDatabase> {
+	let db = ZoneDatabase::open(path)?;
+	if strict && db.has_legacy_records().unwrap_or(true) {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "Obsolete record types, refused in strict mode"));
+	}
+	Ok(db)
+}
+
+/// Run the self-test, printing a line for every check and a summary. Returns 1 when a check
+/// failed.
+fn report_self_test(tls: Option<(&Path, &Path)>) -> i32 {
+	let checks = run_self_test(tls);
+	for check in &checks {
+		println!("{}", check);
+	}
+	let count = |outcome: fn(&CheckOutcome) -> bool| checks.iter().filter(|check| outcome(&check.outcome)).count();
+	let failed = count(|outcome| matches!(outcome, CheckOutcome::Failed(_)));
+	println!("{} passed, {} failed, {} skipped", count(|outcome| *outcome == CheckOutcome::Passed), failed,
+		count(|outcome| matches!(outcome, CheckOutcome::Skipped(_))));
+	if failed > 0 { 1 } else { 0 }
+}
+
+/// Start the TCP and UDP servers of a listener, returning the handle of the UDP one.
+fn start_listener(context: &Arc<ServerContext>, addr: SocketAddr, role: ServerRole) -> io::Result<JoinHandle<()>> {
+	DNSTcpServer::new(context.clone()).with_listener(addr, role).run_server()?;
+	DNSUdpServer::new(context.clone()).with_listener(addr, role).run_server()
+}
+
+/// Whether `arg` is an option taking no value.
+fn is_switch(arg: &str) -> bool {
+	matches!(arg, "--no-qname-minimization" | "--strict-zones" | "--pass-through" | "--crash-on-panic" | "--minimal-responses"
+		| "--publish-delegations" | "--self-test" | "--detect-tunnels" | "--verbose" | "-v" | "--quiet" | "-q" | "--help" | "-h")
+}
+
+/// Whether `--help` is among the options, rather than the value of one.
+fn wants_help(args: &[String]) -> bool {
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		if arg == "--help" || arg == "-h" {
+			return true;
+		}
+		if !is_switch(arg) {
+			iter.next();
+		}
+	}
+	false
+}
+
+/// The file given with `--config`, if any.
+fn config_path(args: &[String]) -> Option<&str> {
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		if arg == "--config" {
+			return iter.next().map(String::as_str);
+		}
+		if !is_switch(arg) {
+			iter.next();
+		}
+	}
+	None
+}
+
+/// A listener given as `ROLE:ADDR`.
+fn parse_listener(spec: &str) -> Option<(SocketAddr, ServerRole)> {
+	let (role, addr) = spec.split_once(':')?;
+	Some((addr.parse().ok()?, ServerRole::from_name(role)?))
+}
+
+fn print_progress(progress: &LoadProgress) {
+	if verbosity() == Verbosity::Quiet {
+		return;
+	}
+	if progress.done {
+		println!("Loaded {} records from {}", progress.records, progress.path.display());
+	} else {
+		println!("Loading {}: {} records, {:.1}%", progress.path.display(), progress.records, progress.percent());
+	}
+}
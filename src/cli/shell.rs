@@ -0,0 +1,273 @@
+use std::env;
+use std::fs::{ self, OpenOptions };
+use std::io::{ self, BufRead, Write };
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{ Duration, Instant };
+
+use rdns::server::client::DNSClient;
+use rdns::server::control::{ read_key, send_command, ControlAddr };
+use rdns::server::protocol::QueryType;
+
+use crate::cli::output::format_packet;
+use crate::cli::parse_server;
+
+const HELP: &str = "Commands:
+    <name> [type]           Query <name> for <type> (default A)
+    query <name> [type]     Same as above
+    server [addr]           Show or switch the server queries are sent to
+    set recurse on|off      Toggle the RD flag on queries
+    set timeout <secs>      Time to wait for a response
+    cache                   Show the cache counters of the server
+    cache show [name]       List the cached entries, all of them or those of <name>
+    cache flush [name]      Drop the cached entries, all of them or those of <name>
+    cache flush-tree <name> Drop the cached entries of <name> and the names below it
+    history [n]             Show the last n commands
+    !<n>                    Run command number n from the history again
+    help                    Show this message
+    quit | exit             Leave the shell";
+
+/// Number of history entries kept in the history file...
+const HISTORY_SIZE: usize = 500;
+
+/// `rdns shell [--server ADDR] [--control ADDR|PATH --key FILE]`
+/// The cache commands go through the control channel of the server (see `ControlServer`),
+/// and are only available when its address and key are given.
+pub fn run(args: &[String]) -> i32 {
+	let mut server = None;
+	let mut control = None;
+	let mut key_file = None;
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		let target = match arg.as_str() {
+			"--server" | "-s" => &mut server,
+			"--control" => &mut control,
+			"--key" | "-k" => &mut key_file,
+			_ => {
+				eprintln!("Unknown option: {}", arg);
+				return 2;
+			}
+		};
+		match iter.next() {
+			Some(value) => *target = Some(value.clone()),
+			None => {
+				eprintln!("{} needs a value", arg);
+				return 2;
+			}
+		}
+	}
+
+	let server = match parse_server(server.as_deref().unwrap_or("127.0.0.1:53")) {
+		Ok(server) => server,
+		Err(e) => {
+			eprintln!("{}", e);
+			return 2;
+		}
+	};
+
+	let control = match (control, key_file) {
+		(Some(addr), Some(path)) => match read_key(&path) {
+			Ok(key) => Some((ControlAddr::parse(&addr), key)),
+			Err(e) => {
+				eprintln!("Failed to read the control key {}: {}", path, e);
+				return 1;
+			}
+		},
+		(None, None) => None,
+		_ => {
+			eprintln!("--control and --key go together");
+			return 2;
+		}
+	};
+
+	let mut shell = Shell::new(server, control);
+	shell.load_history();
+	println!("rdns shell, sending queries to {}. Type `help` for the commands.", shell.server);
+
+	let stdin = io::stdin();
+	let mut lines = stdin.lock().lines();
+	loop {
+		print!("rdns> ");
+		let _ = io::stdout().flush();
+
+		let line = match lines.next() {
+			Some(Ok(line)) => line,
+			_ => break,
+		};
+		if !shell.execute(line.trim()) {
+			break;
+		}
+	}
+	0
+}
+
+struct Shell {
+	client: DNSClient,
+	server: SocketAddr,
+	/// Control channel of the server and its key, for the cache commands...
+	control: Option<(ControlAddr, Vec<u8>)>,
+	recursive: bool,
+	history: Vec<String>,
+	history_file: Option<PathBuf>,
+}
+
+impl Shell {
+	fn new(server: SocketAddr, control: Option<(ControlAddr, Vec<u8>)>) -> Self {
+		Self {
+			client: DNSClient::new(),
+			server,
+			control,
+			recursive: true,
+			history: Vec::new(),
+			history_file: env::var_os("HOME").map(|home| PathBuf::from(home).join(".rdns_history")),
+		}
+	}
+
+	fn load_history(&mut self) {
+		if let Some(content) = self.history_file.as_ref().and_then(|path| fs::read_to_string(path).ok()) {
+			self.history = content.lines().map(String::from).collect();
+			let excess = self.history.len().saturating_sub(HISTORY_SIZE);
+			self.history.drain(..excess);
+		}
+	}
+
+	fn add_history(&mut self, line: &str) {
+		self.history.push(line.to_string());
+		if let Some(ref path) = self.history_file {
+			if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+				let _ = writeln!(file, "{}", line);
+			}
+		}
+	}
+
+	/// Execute a single command line, returns false when the shell has to exit.
+	fn execute(&mut self, line: &str) -> bool {
+		if line.is_empty() {
+			return true;
+		}
+
+		// `!n` replays an entry of the history, which is what gets recorded...
+		let line = match line.strip_prefix('!') {
+			Some(num) => match num.parse::<usize>().ok().and_then(|n| self.history.get(n.wrapping_sub(1))) {
+				Some(entry) => {
+					println!("{}", entry);
+					entry.clone()
+				}
+				None => {
+					println!("No such history entry: {}", num);
+					return true;
+				}
+			},
+			None => line.to_string(),
+		};
+		self.add_history(&line);
+
+		let tokens: Vec<&str> = line.split_whitespace().collect();
+		match tokens[0] {
+			"quit" | "exit" => return false,
+			"help" | "?" => println!("{}", HELP),
+			"server" => self.server_command(&tokens[1..]),
+			"set" => self.set_command(&tokens[1..]),
+			"history" => self.history_command(&tokens[1..]),
+			"cache" => self.cache_command(&tokens[1..]),
+			"query" => self.query_command(&tokens[1..]),
+			_ => self.query_command(&tokens),
+		}
+		true
+	}
+
+	fn server_command(&mut self, args: &[&str]) {
+		match args.first() {
+			Some(addr) => match parse_server(addr) {
+				Ok(server) => {
+					self.server = server;
+					println!("Server is now {}", server);
+				}
+				Err(e) => println!("{}", e),
+			},
+			None => println!("{}", self.server),
+		}
+	}
+
+	fn set_command(&mut self, args: &[&str]) {
+		match args {
+			["recurse", "on"] => self.recursive = true,
+			["recurse", "off"] => self.recursive = false,
+			["timeout", secs] => match secs.parse::<u64>() {
+				Ok(secs) => self.client = DNSClient::with_timeout(Duration::from_secs(secs)),
+				Err(_) => println!("Invalid timeout: {}", secs),
+			},
+			_ => println!("Usage: set recurse on|off | set timeout <secs>"),
+		}
+	}
+
+	fn cache_command(&self, args: &[&str]) {
+		let command = match args {
+			[] => "stats".to_string(),
+			["show"] => "cache".to_string(),
+			["show", name] => format!("cache {}", name),
+			["flush"] => "flush".to_string(),
+			["flush", name] => format!("flush {}", name),
+			["flush-tree", name] => format!("flush-tree {}", name),
+			_ => {
+				println!("Usage: cache | cache show [name] | cache flush [name] | cache flush-tree <name>");
+				return;
+			}
+		};
+		let (addr, key) = match self.control {
+			Some((ref addr, ref key)) => (addr, key),
+			None => {
+				println!("The cache commands need the control channel of the server, given with --control and --key");
+				return;
+			}
+		};
+
+		match send_command(addr, key, &command) {
+			// Of the counters of the server, those of the cache...
+			Ok(output) if args.is_empty() => output.lines()
+				.filter(|line| line.starts_with("cache "))
+				.for_each(|line| println!("{}", line)),
+			Ok(output) if output.is_empty() => println!(";; No entries"),
+			Ok(output) => print!("{}", output),
+			Err(e) => println!(";; {}: {}", addr, e),
+		}
+	}
+
+	fn history_command(&self, args: &[&str]) {
+		let count = args.first().and_then(|n| n.parse::<usize>().ok()).unwrap_or(20);
+		let start = self.history.len().saturating_sub(count);
+		for (idx, entry) in self.history.iter().enumerate().skip(start) {
+			println!("{:5}  {}", idx + 1, entry);
+		}
+	}
+
+	fn query_command(&self, args: &[&str]) {
+		let name = match args.first() {
+			Some(name) => name.trim_end_matches('.'),
+			None => {
+				println!("Usage: query <name> [type]");
+				return;
+			}
+		};
+		let q_type = match args.get(1) {
+			Some(name) => match QueryType::from_name(name) {
+				Some(q_type) => q_type,
+				None => {
+					println!("Unknown record type: {}", name);
+					return;
+				}
+			},
+			None => QueryType::A,
+		};
+
+		let start = Instant::now();
+		match self.client.send_query(name, q_type, self.server, self.recursive) {
+			Ok(response) => {
+				print!("{}", format_packet(&response));
+				println!("\n;; Query time: {} msec", start.elapsed().as_millis());
+				println!(";; SERVER: {}", self.server);
+			}
+			Err(e) => println!(";; Query failed: {}", e),
+		}
+	}
+}
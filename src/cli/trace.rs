@@ -0,0 +1,150 @@
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::Arc;
+use std::time::Instant;
+
+use serde_json::{ json, Value };
+
+use rdns::server::client::DNSClient;
+use rdns::server::context::ServerContext;
+use rdns::server::hints::root_hints;
+use rdns::server::protocol::{ DNSPacket, DNSRecord, QueryType, ResultCode };
+use rdns::server::resolve::{ referral, DNSResolver, RecursiveResolver };
+
+use crate::cli::output::{ format_packet, format_short, OutputMode };
+
+const MAX_STEPS: usize = 32;
+
+/// `rdns trace NAME [TYPE] [--json|--short]`
+/// Follows the delegations from the root down to the servers answering NAME, showing the
+/// response received at each step like `dig +trace`.
+pub fn run(args: &[String]) -> i32 {
+	let (mode, args) = OutputMode::from_args(args);
+
+	let name = match args.first() {
+		Some(name) => name.trim_end_matches('.').to_lowercase(),
+		None => {
+			eprintln!("Usage: rdns trace NAME [TYPE] [--json|--short]");
+			return 2;
+		}
+	};
+	let q_type = match args.get(1) {
+		Some(q_type) => match QueryType::from_name(q_type) {
+			Some(q_type) => q_type,
+			None => {
+				eprintln!("Unknown record type: {}", q_type);
+				return 2;
+			}
+		},
+		None => QueryType::A,
+	};
+
+	let client = DNSClient::new();
+	let mut zone = String::new();
+	let mut servers: Vec<IpAddr> = root_hints().into_iter().flat_map(|(_, addrs)| addrs).collect();
+	let mut steps = Vec::new();
+	let mut result = 0;
+
+	for _ in 0..MAX_STEPS {
+		servers.sort_by_key(|addr| addr.is_ipv6());
+
+		let start = Instant::now();
+		let answered = servers.iter()
+			.map(|addr| SocketAddr::new(*addr, 53))
+			.find_map(|server| client.send_query(&name, q_type, server, false).ok().map(|r| (server, r)));
+		let (server, response) = match answered {
+			Some(answered) => answered,
+			None => {
+				eprintln!(";; No name server of zone {}. answered", zone);
+				result = 1;
+				break;
+			}
+		};
+		let elapsed = start.elapsed().as_millis() as u64;
+		print_step(mode, &zone, server, elapsed, &response, &mut steps);
+
+		if !response.answers.is_empty() || response.header.rescode != ResultCode::NOERROR {
+			break;
+		}
+		let next_zone = match referral(&response, &zone, &name) {
+			Some(next_zone) => next_zone,
+			None => break,
+		};
+
+		servers = referral_addresses(&response, &next_zone);
+		zone = next_zone;
+		if servers.is_empty() {
+			eprintln!(";; Could not find an address for any name server of {}.", zone);
+			result = 1;
+			break;
+		}
+	}
+
+	if mode == OutputMode::Json {
+		println!("{}", json!({
+			"query": { "name": format!("{}.", name), "type": q_type.to_string() },
+			"steps": steps,
+		}));
+	}
+	result
+}
+
+fn print_step(mode: OutputMode, zone: &str, server: SocketAddr, elapsed: u64, response: &DNSPacket, steps: &mut Vec<Value>) {
+	match mode {
+		OutputMode::Text => {
+			for record in response.answers.iter().chain(response.authorities.iter()) {
+				println!("{}", record);
+			}
+			if response.answers.is_empty() && response.authorities.is_empty() {
+				print!("{}", format_packet(response));
+			}
+			println!(";; Received {} records from {} for zone {}. in {} ms\n",
+				response.answers.len() + response.authorities.len(), server, zone, elapsed);
+		}
+		OutputMode::Short => {
+			if response.answers.is_empty() {
+				println!("{}. {} {:?}", zone, server.ip(), response.header.rescode);
+			} else {
+				print!("{}", format_short(response));
+			}
+		}
+		OutputMode::Json => steps.push(json!({
+			"zone": format!("{}.", zone),
+			"server": server.to_string(),
+			"time_ms": elapsed,
+			"response": response,
+		})),
+	}
+}
+
+/// Addresses of the name servers a referral points to: the glue if there is any, otherwise
+/// the name servers are resolved recursively.
+fn referral_addresses(response: &DNSPacket, zone: &str) -> Vec<IpAddr> {
+	let nameservers: Vec<String> = response.authorities.iter()
+		.filter_map(|record| match *record {
+			DNSRecord::NS { ref domain, ref host, .. } if domain == zone => Some(host.to_lowercase()),
+			_ => None,
+		})
+		.collect();
+
+	let glue: Vec<IpAddr> = response.additional.iter()
+		.filter_map(|record| match *record {
+			DNSRecord::A { ref domain, addr, .. } if nameservers.contains(domain) => Some(IpAddr::V4(addr)),
+			DNSRecord::AAAA { ref domain, addr, .. } if nameservers.contains(domain) => Some(IpAddr::V6(addr)),
+			_ => None,
+		})
+		.collect();
+	if !glue.is_empty() {
+		return glue;
+	}
+
+	let mut resolver = RecursiveResolver::new(Arc::new(ServerContext::new()));
+	nameservers.iter()
+		.filter_map(|ns| resolver.resolve(ns, QueryType::A, true).ok())
+		.flat_map(|response| response.answers)
+		.filter_map(|record| match record {
+			DNSRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+			_ => None,
+		})
+		.take(4)
+		.collect()
+}
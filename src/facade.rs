@@ -0,0 +1,140 @@
+//! The common cases in a few lines: a query, and a server answering for zones or forwarding
+
+use std::io::Result;
+use std::net::SocketAddr;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+
+use crate::server::authority::Zone;
+use crate::server::client::DNSClient;
+use crate::server::context::{ ResolveStrategy, ServerContext };
+use crate::server::loader::read_zone;
+use crate::server::platform::SystemConfig;
+use crate::server::protocol::{ DNSRecord, QueryType };
+use crate::server::tcp::DNSTcpServer;
+use crate::server::tls_upstream::TlsPolicy;
+use crate::server::udp::DNSUdpServer;
+use crate::server::upstream::{ SelectionStrategy, Upstream, UpstreamPool };
+
+/// The records answering `q_type` for `name`, asked of the nameserver the system uses (see
+/// `SystemConfig`) like `rdns dig`, the search domains of the system being tried for names
+/// with few dots as the system resolver does:
+///
+/// ```no_run
+/// use rdns::prelude::*;
+///
+/// for record in rdns::query("example.com", A)? {
+///     println!("{}", record);
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+///
+/// See `query_at`.
+pub fn query(name: &str, q_type: QueryType) -> Result<Vec<DNSRecord>> {
+	let config = SystemConfig::load().unwrap_or_default();
+	Ok(config.client().checked_query(name, q_type, config.server())?.answers)
+}
+
+/// The records answering `q_type` for `name`, asked of the recursive resolver at `server`.
+/// The CNAMEs followed to the name holding them come along. A name that doesn't exist gives
+/// an error of kind `NotFound`, as does a lookup of `DNSClient`.
+pub fn query_at(name: &str, q_type: QueryType, server: SocketAddr) -> Result<Vec<DNSRecord>> {
+	Ok(DNSClient::new().checked_query(name, q_type, server)?.answers)
+}
+
+/// The addresses to connect to for `service` of `domain`, from its SRV records asked of the
+/// nameserver the system uses, in the order RFC 2782 tries them:
+///
+/// ```no_run
+/// let addrs = rdns::lookup_service("_imaps._tcp", "example.com")?;
+/// let stream = addrs.into_iter().find_map(|addr| std::net::TcpStream::connect(addr).ok());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+///
+/// See `DNSClient::lookup_service`.
+pub fn lookup_service(service: &str, domain: &str) -> Result<Vec<SocketAddr>> {
+	let config = SystemConfig::load().unwrap_or_default();
+	config.client().lookup_service(service, domain, config.server())
+}
+
+/// What `serve` runs: where to listen, the zones to answer for and the upstreams to forward
+/// the other queries to, resolving them from the root without any.
+///
+/// ```no_run
+/// let config = rdns::ServeConfig::new()
+///     .with_listen("127.0.0.1:5353".parse().unwrap())
+///     .with_zone(rdns::zone! {
+///         ("example.com" 3600 IN SOA ns1.example.com hostmaster.example.com 1 3600 600 86400 300)
+///         ("www.example.com" 300 IN A 192.0.2.1)
+///     })
+///     .with_forward("9.9.9.9");
+/// rdns::serve(config)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct ServeConfig {
+	listen: Option<SocketAddr>,
+	zones: Vec<Zone>,
+	zone_files: Vec<PathBuf>,
+	upstreams: Vec<String>,
+}
+
+impl ServeConfig {
+	pub fn new() -> Self {
+		ServeConfig::default()
+	}
+
+	/// Listen on `addr` over UDP and TCP, in place of port 53 of every address.
+	pub fn with_listen(mut self, addr: SocketAddr) -> Self {
+		self.listen = Some(addr);
+		self
+	}
+
+	/// Answer for `zone`, built in code with `zone!` for instance.
+	pub fn with_zone(mut self, zone: Zone) -> Self {
+		self.zones.push(zone);
+		self
+	}
+
+	/// Answer for the zone of the master file, or JSON or YAML document, at `path`.
+	pub fn with_zone_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+		self.zone_files.push(path.as_ref().to_path_buf());
+		self
+	}
+
+	/// Forward to the upstream given like `9.9.9.9` or `tls://9.9.9.9#dns.quad9.net` (see
+	/// `Upstream::parse`), the first ones being tried first.
+	pub fn with_forward(mut self, upstream: &str) -> Self {
+		self.upstreams.push(upstream.to_string());
+		self
+	}
+}
+
+/// Run a server as `config` tells, returning only when it fails to start or its UDP listener
+/// stops. For everything else the server does, set up a `ServerContext` the way `rdns serve`
+/// does.
+pub fn serve(config: ServeConfig) -> Result<()> {
+	let mut context = ServerContext::new();
+	if let Some(addr) = config.listen {
+		context.listen_addr = addr;
+	}
+	for zone in config.zones {
+		context.authority.add_zone(Arc::new(zone));
+	}
+	for path in &config.zone_files {
+		context.authority.add_zone(read_zone(path, "", false)?);
+	}
+	if !config.upstreams.is_empty() {
+		let upstreams = config.upstreams.iter()
+			.map(|spec| Upstream::parse(spec)?.with_tls_policy(TlsPolicy::Strict))
+			.collect::<Result<_>>()?;
+		context.set_resolve_strategy(ResolveStrategy::Forward { upstreams: Arc::new(UpstreamPool::new(upstreams, SelectionStrategy::Failover)) });
+	}
+
+	let context = Arc::new(context);
+	DNSTcpServer::new(context.clone()).run_server()?;
+	let handle = DNSUdpServer::new(context.clone()).run_server()?;
+	ServerContext::initialize(&context);
+	let _ = handle.join();
+	Ok(())
+}
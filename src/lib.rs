@@ -0,0 +1,9 @@
+#[macro_use]
+mod macros;
+mod facade;
+
+pub mod prelude;
+pub mod server;
+
+pub use crate::facade::{ lookup_service, query, query_at, serve, ServeConfig };
+pub use crate::server::dnssd::browse;
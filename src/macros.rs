@@ -0,0 +1,41 @@
+//! Macros building records and zones in code, handy for tests and hard coded configuration
+
+/// Build a `DNSRecord` from master file syntax:
+///
+/// ```
+/// let record = rdns::record!("www.example.com" 300 IN A 1.2.3.4);
+/// let mail = rdns::record!("example.com" 3600 IN MX 10 mail.example.com);
+/// ```
+///
+/// Panics if the RDATA is not valid for the record type.
+#[macro_export]
+macro_rules! record {
+	($domain:literal $ttl:literal IN $r_type:ident $($rdata:tt)+) => {
+		$crate::server::zonefile::parse_record(
+			$domain,
+			$ttl,
+			stringify!($r_type),
+			&$crate::server::zonefile::join_tokens(&[$(stringify!($rdata)),+]),
+		).expect(concat!("Invalid record: ", $domain, " ", stringify!($r_type)))
+	};
+}
+
+/// Build a `Zone` from a list of records in `record!` syntax, each wrapped in parentheses:
+///
+/// ```
+/// let zone = rdns::zone! {
+///     ("example.com" 3600 IN SOA ns1.example.com hostmaster.example.com 1 3600 600 86400 300)
+///     ("example.com" 3600 IN NS ns1.example.com)
+///     ("www.example.com" 300 IN A 1.2.3.4)
+/// };
+/// assert_eq!(zone.len(), 2);
+/// ```
+///
+/// Panics if a record is invalid or the zone doesn't hold exactly one SOA.
+#[macro_export]
+macro_rules! zone {
+	($(( $($record:tt)+ ))+) => {
+		$crate::server::authority::Zone::from_records(vec![$($crate::record!($($record)+)),+])
+			.expect("Invalid zone")
+	};
+}
@@ -0,0 +1,12 @@
+//! What most uses of the crate need, for a glob import: `use rdns::prelude::*;`
+//!
+//! Brings the facade (`query`, `serve`, `browse`, `lookup_service`), the types of messages and
+//! records, and the common record types by their mnemonic, so `rdns::query("example.com", A)`
+//! reads as it would in a zone file.
+
+pub use crate::{ browse, lookup_service, query, query_at, serve, ServeConfig };
+pub use crate::server::authority::Zone;
+pub use crate::server::client::DNSClient;
+pub use crate::server::context::ServerContext;
+pub use crate::server::protocol::{ DNSPacket, DNSQuestion, DNSRecord, ResultCode, TransientTTL };
+pub use crate::server::protocol::QueryType::{ self, A, AAAA, CNAME, MX, NS, PTR, SOA, SRV, TXT };
@@ -0,0 +1,182 @@
+//! Access control lists of the clients allowed to query, to recurse and to transfer zones
+
+use std::io::{ Error, ErrorKind, Result };
+use std::net::IpAddr;
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use crate::server::capture::{ in_prefix, parse_prefix };
+use crate::server::protocol::{ DNSPacket, QueryType };
+
+/// Type of the incremental zone transfer queries (RFC 1995)...
+const TYPE_IXFR: u16 = 251;
+
+/// What a client is allowed to do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+	/// Send queries at all.
+	Query,
+	/// Have names outside of the local zones resolved.
+	Recursion,
+	/// Request zone transfers, AXFR or IXFR.
+	Transfer,
+}
+
+impl Access {
+	pub const ALL: [Access; 3] = [Access::Query, Access::Recursion, Access::Transfer];
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Access::Query => "query",
+			Access::Recursion => "recursion",
+			Access::Transfer => "transfer",
+		}
+	}
+
+	fn index(&self) -> usize {
+		*self as usize
+	}
+}
+
+/// Who may query the server, have names resolved and request zone transfers, each given as
+/// a list of networks (`ADDR/LEN`); clients outside of the list of an access are refused it
+/// and counted. An access without a list is open to everyone, an empty list (`none`) to no
+/// one.
+///
+/// Queries of clients which may not query are refused whatever they ask, as are the zone
+/// transfers of those which may not transfer. Clients which may not recurse are still
+/// answered from the local zones, only the names they'd have resolved are refused.
+#[derive(Debug, Default)]
+pub struct AccessControl {
+	lists: [Option<Vec<(IpAddr, u8)>>; 3],
+	rejected: [AtomicU64; 3],
+}
+
+impl AccessControl {
+	pub fn new() -> Self {
+		AccessControl::default()
+	}
+
+	/// Allow `access` to the clients of `network`, given as `ADDR` or `ADDR/LEN`, or to no one
+	/// but the networks allowed otherwise with `none`.
+	pub fn allow(&mut self, access: Access, network: &str) -> Result<()> {
+		let list = self.lists[access.index()].get_or_insert_with(Vec::new);
+		if network == "none" {
+			return Ok(());
+		}
+		let network = parse_prefix(network)
+			.ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Invalid network: {}", network)))?;
+		list.push(network);
+		Ok(())
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.lists.iter().all(Option::is_none)
+	}
+
+	/// The networks allowed `access`, None when everyone is.
+	pub fn networks(&self, access: Access) -> Option<&[(IpAddr, u8)]> {
+		self.lists[access.index()].as_deref()
+	}
+
+	/// Whether `client` is allowed `access`.
+	pub fn allows(&self, access: Access, client: IpAddr) -> bool {
+		self.networks(access).is_none_or(|networks| networks.iter().any(|(network, len)| in_prefix(client, *network, *len)))
+	}
+
+	/// Whether `client` is allowed `access`, counting it as rejected when it isn't.
+	pub fn check(&self, access: Access, client: IpAddr) -> bool {
+		if self.allows(access, client) {
+			return true;
+		}
+		self.rejected[access.index()].fetch_add(1, Ordering::Relaxed);
+		false
+	}
+
+	/// Whether `client` may send `request`: query, and request a zone transfer if it's one.
+	pub fn check_request(&self, request: &DNSPacket, client: IpAddr) -> bool {
+		let transfer = request.questions.iter().any(|question| matches!(question.q_type, QueryType::AXFR | QueryType::UNKNOWN(TYPE_IXFR)));
+		self.check(Access::Query, client) && (!transfer || self.check(Access::Transfer, client))
+	}
+
+	/// Number of requests refused for want of `access`.
+	pub fn rejected(&self, access: Access) -> u64 {
+		self.rejected[access.index()].load(Ordering::Relaxed)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::{ Ipv4Addr, Ipv6Addr };
+
+	use super::*;
+	use crate::server::client::DNSClient;
+
+	fn allowing(access: Access, networks: &[&str]) -> AccessControl {
+		let mut acl = AccessControl::new();
+		for network in networks {
+			acl.allow(access, network).unwrap();
+		}
+		acl
+	}
+
+	fn addr(addr: &str) -> IpAddr {
+		addr.parse().unwrap()
+	}
+
+	#[test]
+	fn prefixes_match_the_leading_bits() {
+		let acl = allowing(Access::Query, &["192.0.2.0/24", "198.51.100.7", "10.0.0.0/9", "2001:db8::/32"]);
+		for client in ["192.0.2.1", "192.0.2.255", "198.51.100.7", "10.127.255.255", "2001:db8:1::1"] {
+			assert!(acl.allows(Access::Query, addr(client)), "{}", client);
+		}
+		for client in ["192.0.3.1", "198.51.100.8", "10.128.0.0", "2001:db9::1", "::1"] {
+			assert!(!acl.allows(Access::Query, addr(client)), "{}", client);
+		}
+	}
+
+	#[test]
+	fn zero_length_prefix_matches_its_family_only() {
+		let acl = allowing(Access::Query, &["0.0.0.0/0"]);
+		assert!(acl.allows(Access::Query, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1))));
+		assert!(!acl.allows(Access::Query, IpAddr::V6(Ipv6Addr::LOCALHOST)));
+	}
+
+	#[test]
+	fn mapped_clients_match_ipv4_networks() {
+		let acl = allowing(Access::Recursion, &["192.0.2.0/24"]);
+		assert!(acl.allows(Access::Recursion, addr("::ffff:192.0.2.10")));
+		assert!(!acl.allows(Access::Recursion, addr("::ffff:192.0.3.10")));
+
+		let acl = allowing(Access::Recursion, &["::ffff:192.0.2.0/120"]);
+		assert!(acl.allows(Access::Recursion, addr("::ffff:192.0.2.10")));
+		assert!(acl.allows(Access::Recursion, addr("192.0.2.10")));
+		assert!(!acl.allows(Access::Recursion, addr("192.0.3.10")));
+	}
+
+	#[test]
+	fn lists_are_open_unless_given_and_none_closes_them() {
+		let mut acl = AccessControl::new();
+		assert!(acl.is_empty());
+		assert!(acl.allows(Access::Transfer, addr("203.0.113.1")));
+		acl.allow(Access::Transfer, "none").unwrap();
+		assert!(!acl.allows(Access::Transfer, addr("203.0.113.1")));
+		assert!(acl.allows(Access::Query, addr("203.0.113.1")));
+		assert!(acl.allow(Access::Query, "192.0.2.0/33").is_err());
+		assert!(acl.allow(Access::Query, "example.com").is_err());
+	}
+
+	#[test]
+	fn transfers_need_both_accesses() {
+		let mut acl = allowing(Access::Transfer, &["192.0.2.53"]);
+		acl.allow(Access::Query, "192.0.2.0/24").unwrap();
+		let client = DNSClient::new();
+		let axfr = client.build_query("example.com", QueryType::AXFR, false);
+		let query = client.build_query("example.com", QueryType::A, false);
+
+		assert!(acl.check_request(&axfr, addr("192.0.2.53")));
+		assert!(acl.check_request(&query, addr("192.0.2.54")));
+		assert!(!acl.check_request(&axfr, addr("192.0.2.54")));
+		assert!(!acl.check_request(&query, addr("203.0.113.1")));
+		assert_eq!((acl.rejected(Access::Query), acl.rejected(Access::Transfer)), (1, 1));
+	}
+}
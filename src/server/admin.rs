@@ -0,0 +1,469 @@
+//! HTTP API reporting the state of the running server
+
+use std::io::{ BufReader, ErrorKind, Result, Write };
+use std::net::{ SocketAddr, TcpListener, TcpStream };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::Arc;
+use std::thread::{ self, JoinHandle };
+
+use serde_json::{ json, Map, Value };
+
+use crate::server::acl::Access;
+use crate::server::context::{ ResolveStrategy, ServerContext };
+use crate::server::doh::{ read_request, HttpRequest };
+use crate::server::protocol::QueryType;
+use crate::server::servfail::ServfailReason;
+
+/// Port of the admin API unless told otherwise...
+pub const DEFAULT_ADMIN_PORT: u16 = 8053;
+/// Connections served at once...
+pub const MAX_ADMIN_CONNECTIONS: usize = 16;
+
+/// Read only HTTP API on a listener of its own, answering `GET` requests with JSON:
+///
+/// - `/health`: whether the server is up, `503` while it shuts down or when none of the
+///   upstreams it forwards to answers
+/// - `/stats`: the counters of the cache, the UDP sockets, the latency objective, the
+///   upstreams, the mirror, response rate limiting and the query hooks, the SERVFAILs
+///   answered by reason, and what the clients support (see `ClientStats`)
+/// - `/cache`: the entries of the cache, those of a single name with `?name=NAME`, the name
+///   being percent-encoded
+/// - `/zones`: the local zones with their SOA and the state of their delegation, a single
+///   zone with `/zones/ORIGIN`
+/// - `/config`: the settings the server runs with
+///
+/// Packets and records take the representation of `server::json`. There's no
+/// authentication, so the API belongs on a loopback or management address. Each client is
+/// served on a thread of its own, up to `MAX_ADMIN_CONNECTIONS` at once, one request per
+/// connection.
+pub struct AdminServer {
+	context: Arc<ServerContext>,
+	addr: SocketAddr,
+	/// Connections being served...
+	connections: AtomicUsize,
+}
+
+impl AdminServer {
+	pub fn new(context: Arc<ServerContext>, addr: SocketAddr) -> Self {
+		AdminServer { context, addr, connections: AtomicUsize::new(0) }
+	}
+
+	/// Bind the socket and start accepting connections. The returned handle belongs to the
+	/// accepting thread, which runs for as long as the socket does.
+	pub fn run_server(self) -> Result<JoinHandle<()>> {
+		let listener = TcpListener::bind(self.addr)?;
+		let server = Arc::new(self);
+		thread::Builder::new()
+			.name("AdminServer".to_string())
+			.spawn(move || server.accept(listener))
+	}
+
+	/// Serve the connections of `listener` each on a thread of its own, so that a client slow
+	/// to send its request doesn't hold up the others. Connections beyond
+	/// `MAX_ADMIN_CONNECTIONS` at once are closed right away.
+	fn accept(self: Arc<Self>, listener: TcpListener) {
+		for stream in listener.incoming() {
+			let stream = match stream {
+				Ok(stream) => stream,
+				Err(e) => {
+					info!("Admin connection failed: {}", e);
+					continue;
+				}
+			};
+			if self.connections.fetch_add(1, Ordering::AcqRel) >= MAX_ADMIN_CONNECTIONS {
+				self.connections.fetch_sub(1, Ordering::AcqRel);
+				info!("Refused an admin connection, {} are being served", MAX_ADMIN_CONNECTIONS);
+				continue;
+			}
+			let server = self.clone();
+			let spawned = thread::Builder::new()
+				.name("AdminConnection".to_string())
+				.spawn(move || {
+					if let Err(e) = server.serve_connection(stream) {
+						info!("Admin connection failed: {}", e);
+					}
+					server.connections.fetch_sub(1, Ordering::AcqRel);
+				});
+			if let Err(e) = spawned {
+				self.connections.fetch_sub(1, Ordering::AcqRel);
+				info!("Failed to start an admin connection thread: {}", e);
+			}
+		}
+	}
+
+	fn serve_connection(&self, stream: TcpStream) -> Result<()> {
+		stream.set_read_timeout(Some(self.context.tcp_idle_timeout))?;
+		stream.set_write_timeout(Some(self.context.tcp_idle_timeout))?;
+		let mut reader = BufReader::new(stream);
+		let (status, body) = match read_request(&mut reader) {
+			Ok(Some(request)) => self.answer(&request),
+			Ok(None) => return Ok(()),
+			Err(ref e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::UnexpectedEof) => return Ok(()),
+			Err(e) => (400, json!({ "error": e.to_string() })),
+		};
+
+		let body = format!("{}\n", body);
+		let head = format!("HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+			status, reason(status), body.len());
+		let stream = reader.get_mut();
+		stream.write_all(head.as_bytes())?;
+		stream.write_all(body.as_bytes())?;
+		stream.flush()
+	}
+
+	/// The status and the body of the response to a request.
+	fn answer(&self, request: &HttpRequest) -> (u16, Value) {
+		if request.method != "GET" {
+			return (405, json!({ "error": "Only GET is supported" }));
+		}
+		let (path, query) = request.target.split_once('?').unwrap_or((&request.target, ""));
+		let param = |name: &str| query.split('&').find_map(|param| param.strip_prefix(name)?.strip_prefix('='));
+
+		match path.trim_end_matches('/') {
+			"/health" => self.health(),
+			"/stats" => (200, self.stats()),
+			"/cache" => match param("name").map(percent_decode) {
+				Some(None) => (400, json!({ "error": "Malformed name" })),
+				name => (200, self.cache(name.flatten().as_deref())),
+			},
+			"/zones" => (200, json!(self.zones(None))),
+			"/config" => (200, self.config()),
+			path => match path.strip_prefix("/zones/") {
+				Some(origin) => match self.zones(Some(origin)).pop() {
+					Some(zone) => (200, zone),
+					None => (404, json!({ "error": format!("No zone {}", origin) })),
+				},
+				None => (404, json!({ "error": format!("Not found: {}", path) })),
+			},
+		}
+	}
+
+	fn health(&self) -> (u16, Value) {
+		let context = &self.context;
+		let upstreams = match context.resolve_strategy() {
+			ResolveStrategy::Forward { upstreams } => Some(upstreams.health()),
+			ResolveStrategy::Recursive => None,
+		};
+		let up = upstreams.as_ref().map(|upstreams| upstreams.iter().filter(|health| health.up).count());
+
+		let status = if context.shutdown.is_stopping() {
+			"stopping"
+		} else if up == Some(0) {
+			"upstreams down"
+		} else {
+			"ok"
+		};
+		let body = json!({
+			"status": status,
+			"zones": context.authority.origins().len(),
+			"upstreams": upstreams.map(|upstreams| upstreams.len()),
+			"upstreams_up": up,
+		});
+		(if status == "ok" { 200 } else { 503 }, body)
+	}
+
+	fn stats(&self) -> Value {
+		let context = &self.context;
+		let cache = context.cache.stats();
+		let clients = context.client_stats.snapshot();
+		let upstreams = match context.resolve_strategy() {
+			ResolveStrategy::Forward { upstreams } => upstreams.health(),
+			ResolveStrategy::Recursive => Vec::new(),
+		};
+		json!({
+			"cache": {
+				"entries": cache.entries,
+				"bytes": cache.bytes,
+				"hits": cache.hits,
+				"hot_hits": cache.hot_hits,
+				"misses": cache.misses,
+				"evictions": cache.evictions,
+			},
+			"last_known_good": context.last_known_good.len(),
+			"zones": context.authority.origins().len(),
+			"delegation_issues": context.authority.delegation_drift(),
+			"udp": context.udp_stats.sockets().iter().map(|socket| json!({
+				"address": socket.addr.to_string(),
+				"socket": socket.socket,
+				"received": socket.received,
+				"malformed": socket.malformed,
+				"overflowed": socket.overflowed,
+				"sent": socket.sent,
+				"send_errors": socket.send_errors,
+			})).collect::<Vec<_>>(),
+			"slo": context.latency.slo_status().map(|slo| json!({
+				"total": slo.total,
+				"slow": slo.slow,
+				"burn_rate_short": slo.burn_rate_short,
+				"burn_rate_long": slo.burn_rate_long,
+			})),
+			"upstreams": upstreams.iter().map(|health| json!({
+				"upstream": health.upstream.to_string(),
+				"up": health.up,
+				"consecutive_failures": health.consecutive_failures,
+				"last_rtt_ms": health.last_rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+				"srtt_ms": health.srtt.map(|srtt| srtt.as_secs_f64() * 1000.0),
+			})).collect::<Vec<_>>(),
+			"mirror": context.mirror.as_ref().map(|mirror| {
+				let stats = mirror.stats();
+				json!({
+					"upstream": mirror.upstream().to_string(),
+					"mirrored": stats.mirrored,
+					"mismatches": stats.mismatches,
+					"failed": stats.failed,
+					"dropped": stats.dropped,
+				})
+			}),
+			"acl_rejected": Access::ALL.iter()
+				.map(|access| (access.name().to_string(), json!(context.access_control.rejected(*access))))
+				.collect::<Map<_, _>>(),
+			"hosts": {
+				"names": context.hosts.len(),
+				"answered": context.hosts.answered(),
+			},
+			"geoip": context.geoip.as_ref().map(|database| json!({
+				"lookups": database.lookups().0,
+				"unknown": database.lookups().1,
+			})),
+			"endpoints": {
+				"checks": context.endpoints.checks().iter().map(|check| json!({
+					"name": format!("{}.", check.name),
+					"address": check.addr.to_string(),
+					"probe": check.probe.to_string(),
+					"healthy": check.is_healthy(),
+				})).collect::<Vec<_>>(),
+				"withheld": context.endpoints.withheld(),
+			},
+			"mdns": {
+				"hosts": context.mdns.hosts().iter().map(|(name, _, state)| json!({
+					"name": format!("{}.", name),
+					"state": state.name(),
+				})).collect::<Vec<_>>(),
+				"services": context.mdns.services().iter().map(|(registration, state)| json!({
+					"name": format!("{}.", registration.name()),
+					"state": state.name(),
+				})).collect::<Vec<_>>(),
+				"answered": context.mdns.answered(),
+				"conflicts": context.mdns.conflicts(),
+			},
+			"blocklists": context.blocklists.lists().iter().map(|list| json!({
+				"path": list.path().display().to_string(),
+				"domains": list.len(),
+				"enabled": list.is_enabled(),
+				"blocked": list.blocked(),
+			})).collect::<Vec<_>>(),
+			"doctored": {
+				"inside": context.doctor.rewrites().0,
+				"outside": context.doctor.rewrites().1,
+			},
+			"rrl": context.rrl.as_ref().map(|rrl| json!({
+				"limited": rrl.limited(),
+				"slipped": rrl.slipped(),
+			})),
+			"clients": {
+				"queries": clients.queries,
+				"transports": clients.transports.iter().map(|(transport, count)| (transport.to_string(), json!(count))).collect::<Map<_, _>>(),
+				"no_edns": clients.no_edns,
+				"edns_sizes": clients.edns_sizes.iter().map(|(bound, count)| (format!("<={}", bound), json!(count))).collect::<Map<_, _>>(),
+				"dnssec_ok": clients.dnssec_ok,
+				"authed_data": clients.authed_data,
+				"checking_disabled": clients.checking_disabled,
+				"query_types": clients.query_types.iter().map(|(q_type, count)| (q_type.to_string(), json!(count))).collect::<Map<_, _>>(),
+			},
+			"query_log_dropped": context.query_log.as_ref().map(|query_log| query_log.dropped()),
+			"query_hooks": context.query_hooks.hooks().iter()
+				.map(|hook| {
+					let counters = hook.counters().into_iter().map(|(counter, value)| (counter, json!(value))).collect::<Map<_, _>>();
+					(hook.name().to_string(), Value::Object(counters))
+				})
+				.collect::<Map<_, _>>(),
+			"panics": context.panics(),
+			"servfail": ServfailReason::ALL.iter()
+				.map(|reason| (reason.name().to_string(), json!(context.servfails.get(*reason))))
+				.collect::<Map<_, _>>(),
+		})
+	}
+
+	fn cache(&self, name: Option<&str>) -> Value {
+		let name = name.map(|name| name.trim_end_matches('.').to_lowercase());
+		let entries: Vec<_> = self.context.cache.dump().into_iter()
+			.filter(|entry| name.as_ref().is_none_or(|name| entry.key.qname == *name))
+			.collect();
+		json!(entries)
+	}
+
+	/// The local zones, or only the one of `origin`, with their SOA record and the state of
+	/// their delegation when their parent zone is local too.
+	fn zones(&self, origin: Option<&str>) -> Vec<Value> {
+		let authority = &self.context.authority;
+		let origin = origin.map(|origin| origin.trim_end_matches('.').to_lowercase());
+		let checks = authority.check_delegations();
+		authority.origins().into_iter()
+			.filter(|zone| origin.as_ref().is_none_or(|origin| zone == origin))
+			.map(|zone| {
+				let soa = authority.find_zone(&zone).and_then(|zone| zone.soa().ok());
+				let delegation = checks.iter().find(|check| check.child == zone).map(|check| json!({
+					"parent": format!("{}.", check.parent),
+					"ok": check.is_ok(),
+					"issues": check.issues.iter().map(ToString::to_string).collect::<Vec<_>>(),
+				}));
+				json!({ "origin": format!("{}.", zone), "soa": soa, "delegation": delegation })
+			})
+			.collect()
+	}
+
+	fn config(&self) -> Value {
+		let context = &self.context;
+		let resolve = match context.resolve_strategy() {
+			ResolveStrategy::Recursive => json!({ "mode": "recursive", "root_hints": context.root_hints_file.as_ref().map(|path| path.display().to_string()) }),
+			ResolveStrategy::Forward { upstreams } => json!({
+				"mode": "forward",
+				"strategy": upstreams.strategy().to_string(),
+				"upstreams": upstreams.upstreams().iter().map(ToString::to_string).collect::<Vec<_>>(),
+			}),
+		};
+		let ttl_limits = context.cache.ttl_limits();
+		let retry = context.client.retry();
+		let networks = |q_type| context.family_filter.networks(q_type).iter()
+			.map(|(network, len)| format!("{}/{}", network, len))
+			.collect::<Vec<_>>();
+		json!({
+			"listen": context.listen_addr.to_string(),
+			"worker_threads": context.worker_threads,
+			"udp_sockets": context.udp_sockets,
+			"tcp_max_connections": context.tcp_max_connections,
+			"tcp_idle_timeout_secs": context.tcp_idle_timeout.as_secs(),
+			"edns_max_payload": context.edns_max_payload(),
+			"upstream_retry": {
+				"attempts": retry.attempts,
+				"timeout_ms": retry.timeout.as_millis() as u64,
+				"deadline_ms": retry.deadline.map(|deadline| deadline.as_millis() as u64),
+				"backoff_ms": retry.backoff.as_millis() as u64,
+			},
+			"allow_recursive": context.allow_recursive,
+			"non_recursive": format!("{:?}", context.non_recursive).to_lowercase(),
+			"resolve": resolve,
+			"qname_minimization": context.qname_minimization,
+			"pass_through": context.pass_through,
+			"minimal_responses": context.minimal_responses,
+			"uncompressed_clients": context.uncompressed_clients.iter()
+				.map(|(network, len)| format!("{}/{}", network, len))
+				.collect::<Vec<_>>(),
+			"cache": {
+				"file": context.cache_file.as_ref().map(|path| path.display().to_string()),
+				"snapshot_interval_secs": context.cache_snapshot_interval.as_secs(),
+				"max_stale_secs": context.cache.max_stale().map(|stale| stale.as_secs()),
+				"min_ttl": ttl_limits.min,
+				"max_ttl": ttl_limits.max,
+				"max_negative_ttl": ttl_limits.max_negative,
+			},
+			"zones": {
+				"origins": context.authority.origins().iter().map(|origin| format!("{}.", origin)).collect::<Vec<_>>(),
+				"strict": context.authority.is_strict(),
+				"delegation_check_interval_secs": context.delegation_check_interval.map(|interval| interval.as_secs()),
+				"publish_delegations": context.publish_delegations,
+				"rotate": context.rotation.zones().iter()
+					.map(|(zone, mode)| (format!("{}.", zone), json!(mode.name())))
+					.collect::<Map<_, _>>(),
+				"answer_weights": context.rotation.weights(),
+				"health_checks": context.endpoints.checks().len(),
+			},
+			"mdns": {
+				"hosts": context.mdns.hosts().iter()
+					.map(|(name, addrs, _)| (format!("{}.", name), json!(addrs.iter().map(|addr| addr.to_string()).collect::<Vec<_>>())))
+					.collect::<Map<_, _>>(),
+				"interface": context.mdns.interface().to_string(),
+				"services": context.mdns.services().iter().map(|(registration, _)| json!({
+					"instance": registration.instance,
+					"type": registration.service_type,
+					"host": registration.host,
+					"port": registration.port,
+					"txt": registration.txt,
+				})).collect::<Vec<_>>(),
+			},
+			"geoip": context.geoip.as_ref().map(|database| json!({
+				"database": database.path().display().to_string(),
+				"type": database.database_type(),
+				"record_sets": context.authority.geo_records().len(),
+			})),
+			"mirror": context.mirror.as_ref().map(|mirror| json!({
+				"upstream": mirror.upstream().to_string(),
+				"percent": mirror.percent(),
+			})),
+			"address_filters": {
+				"aaaa": networks(QueryType::AAAA),
+				"a": networks(QueryType::A),
+			},
+			"acl": Access::ALL.iter()
+				.map(|access| {
+					let networks = context.access_control.networks(*access).map(|networks| networks.iter()
+						.map(|(network, len)| format!("{}/{}", network, len))
+						.collect::<Vec<_>>());
+					(access.name().to_string(), json!(networks))
+				})
+				.collect::<Map<_, _>>(),
+			"hosts": {
+				"file": context.hosts.file().map(|path| path.display().to_string()),
+				"names": context.hosts.len(),
+			},
+			"blocklists": {
+				"files": context.blocklists.lists().iter().map(|list| list.path().display().to_string()).collect::<Vec<_>>(),
+				"action": context.blocklists.action().name(),
+			},
+			"doctoring": {
+				"pairs": context.doctor.pairs().iter().map(ToString::to_string).collect::<Vec<_>>(),
+				"inside": context.doctor.inside().iter().map(|(network, len)| format!("{}/{}", network, len)).collect::<Vec<_>>(),
+			},
+			"views": context.views.views().iter()
+				.map(|view| json!({
+					"name": view.name(),
+					"networks": view.networks().iter().map(|(network, len)| format!("{}/{}", network, len)).collect::<Vec<_>>(),
+					"zones": view.authority().origins(),
+					"hosts": view.hosts().len(),
+					"upstreams": view.upstreams().map(|pool| pool.upstreams().iter().map(ToString::to_string).collect::<Vec<_>>()),
+				}))
+				.collect::<Vec<_>>(),
+			"rrl": context.rrl.as_ref().map(|rrl| json!({
+				"rate": rrl.rate(),
+				"slip": rrl.slip(),
+			})),
+			"query_hooks": context.query_hooks.hooks().iter().map(|hook| hook.name()).collect::<Vec<_>>(),
+			"query_log": context.query_log.as_ref().map(|query_log| json!({
+				"path": query_log.path().display().to_string(),
+				"percent": query_log.percent(),
+			})),
+			"health_check_interval_secs": context.health_check_interval.map(|interval| interval.as_secs()),
+		})
+	}
+}
+
+/// The value of a query string parameter, `+` standing for a space and `%XX` for the byte
+/// XX. None if it's malformed or doesn't decode to UTF-8.
+fn percent_decode(value: &str) -> Option<String> {
+	let mut bytes = Vec::with_capacity(value.len());
+	let mut rest = value.as_bytes();
+	while let Some((&byte, tail)) = rest.split_first() {
+		rest = tail;
+		match byte {
+			b'+' => bytes.push(b' '),
+			b'%' => {
+				let hex = rest.get(..2).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+				bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+				rest = &rest[2..];
+			}
+			byte => bytes.push(byte),
+		}
+	}
+	String::from_utf8(bytes).ok()
+}
+
+fn reason(status: u16) -> &'static str {
+	match status {
+		200 => "OK",
+		400 => "Bad Request",
+		404 => "Not Found",
+		405 => "Method Not Allowed",
+		503 => "Service Unavailable",
+		_ => "",
+	}
+}
@@ -0,0 +1,85 @@
+//! Limits on the amplification the UDP listener lends itself to
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+/// Period over which the bytes of a subnet are added up...
+const WINDOW: Duration = Duration::from_secs(10);
+/// Number of subnets tracked before idle ones are dropped...
+const MAX_SUBNETS: usize = 10_000;
+
+/// Bytes received from and sent to a subnet in the current window.
+#[derive(Debug)]
+struct Traffic {
+	start: Instant,
+	received: u64,
+	sent: u64,
+}
+
+/// Keeps the bytes sent to each client subnet below a multiple of the bytes received from it.
+/// Responses which would go over get truncated, so a client with a spoofed source address
+/// can't use the server to flood its victim; genuine clients retry over TCP.
+///
+/// Clients are grouped by /24 for IPv4 and /56 for IPv6, the way response rate limiting
+/// does.
+#[derive(Debug)]
+pub struct AmplificationGuard {
+	ratio: f64,
+	subnets: Mutex<HashMap<IpAddr, Traffic>>,
+}
+
+impl AmplificationGuard {
+	/// Allow each subnet `ratio` times as many bytes out as came in.
+	pub fn new(ratio: f64) -> Self {
+		Self {
+			ratio,
+			subnets: Mutex::new(HashMap::new()),
+		}
+	}
+
+	pub fn ratio(&self) -> f64 {
+		self.ratio
+	}
+
+	/// Account a request of `request_len` bytes from `source` and tell whether its response
+	/// of `response_len` bytes may be sent, or has to be truncated. A truncated response is
+	/// accounted as being the size of the request.
+	pub fn allow(&self, source: IpAddr, request_len: usize, response_len: usize) -> bool {
+		let mut subnets = match self.subnets.lock() {
+			Ok(subnets) => subnets,
+			Err(_) => return true,
+		};
+
+		let now = Instant::now();
+		if subnets.len() >= MAX_SUBNETS {
+			subnets.retain(|_, traffic| now.duration_since(traffic.start) < WINDOW);
+		}
+
+		let traffic = subnets.entry(subnet(source)).or_insert(Traffic { start: now, received: 0, sent: 0 });
+		if now.duration_since(traffic.start) >= WINDOW {
+			*traffic = Traffic { start: now, received: 0, sent: 0 };
+		}
+
+		traffic.received += request_len as u64;
+		let allowed = (traffic.sent + response_len as u64) as f64 <= traffic.received as f64 * self.ratio;
+		traffic.sent += if allowed { response_len } else { request_len } as u64;
+		allowed
+	}
+}
+
+/// The subnet `addr` is counted under.
+fn subnet(addr: IpAddr) -> IpAddr {
+	match addr {
+		IpAddr::V4(v4) => {
+			let octets = v4.octets();
+			IpAddr::from([octets[0], octets[1], octets[2], 0])
+		}
+		IpAddr::V6(v6) => {
+			let mut octets = v6.octets();
+			octets[7..].iter_mut().for_each(|octet| *octet = 0);
+			IpAddr::from(octets)
+		}
+	}
+}
@@ -0,0 +1,188 @@
+//! Async variant of the client, for applications running on tokio
+
+use std::future::Future;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::{ IpAddr, SocketAddr };
+use std::time::Duration;
+
+use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use tokio::net::{ TcpStream, UdpSocket };
+use tokio::time::{ sleep, timeout };
+use tracing::{ debug_span, Instrument };
+
+use crate::server::buffer::VectorPacketBuffer;
+use crate::server::client::{ bind_query_socket, read_response, response_payload, tcp_frame, DNSClient };
+use crate::server::lookup::{ addresses, merge_addresses, search_names, Search };
+use crate::server::protocol::{ DNSPacket, DNSRecord, QueryType };
+use crate::server::retry::is_retryable;
+
+/// The async variant of `DNSClient`, its futures waiting on tokio sockets and timers rather
+/// than blocking a thread, for tokio applications (the `async-client` feature). Queries are
+/// built and responses matched and parsed by the code of the sync client, whose settings
+/// (the retry policy, EDNS, the search list) it goes by.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use rdns::server::async_client::AsyncDNSClient;
+///
+/// let client = AsyncDNSClient::new();
+/// let addrs = client.lookup_ip("example.com", "9.9.9.9:53".parse().unwrap()).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct AsyncDNSClient {
+	client: DNSClient,
+}
+
+impl AsyncDNSClient {
+	pub fn new() -> Self {
+		AsyncDNSClient::default()
+	}
+
+	pub fn with_timeout(timeout: Duration) -> Self {
+		AsyncDNSClient { client: DNSClient::with_timeout(timeout) }
+	}
+
+	/// The sync client building the queries, with the settings.
+	pub fn client(&self) -> &DNSClient {
+		&self.client
+	}
+
+	pub fn client_mut(&mut self) -> &mut DNSClient {
+		&mut self.client
+	}
+
+	/// Send a query for `qname` to `server` and return the response.
+	pub async fn send_query(&self, qname: &str, q_type: QueryType, server: SocketAddr, recursive: bool) -> Result<DNSPacket> {
+		let mut query = self.client.build_query(qname, q_type, recursive);
+		self.exchange(&mut query, server).await
+	}
+
+	/// Send an already built query to `server` over UDP and wait for the response carrying
+	/// the same ID and question, as `DNSClient::exchange` does, trying again as the retry
+	/// policy allows. A truncated response gets the query sent again over TCP.
+	pub async fn exchange(&self, query: &mut DNSPacket, server: SocketAddr) -> Result<DNSPacket> {
+		let mut req_buffer = VectorPacketBuffer::new();
+		query.write(&mut req_buffer)?;
+		let query: &DNSPacket = query;
+		self.retry(|timeout| self.exchange_udp(req_buffer.as_slice(), query, server, timeout)).await
+	}
+
+	/// Send an already built query to `server` over TCP.
+	pub async fn exchange_tcp(&self, query: &mut DNSPacket, server: SocketAddr) -> Result<DNSPacket> {
+		let mut req_buffer = VectorPacketBuffer::new();
+		query.write(&mut req_buffer)?;
+		let query: &DNSPacket = query;
+		self.retry(|timeout| self.exchange_stream(req_buffer.as_slice(), query, server, timeout)).await
+	}
+
+	/// The records answering `q_type` for `name`, asked of the recursive resolver at
+	/// `server`, searching the domains of the client like `DNSClient` lookups. A name that
+	/// doesn't exist gives an error of kind `NotFound`.
+	pub async fn lookup(&self, name: &str, q_type: QueryType, server: SocketAddr) -> Result<Vec<DNSRecord>> {
+		Ok(self.checked_query(name, q_type, server).await?.answers)
+	}
+
+	/// Resolve `name` to its addresses through the recursive resolver at `server`, sending the
+	/// A and AAAA queries at the same time, sorted as `DNSClient::lookup_ip` sorts them.
+	pub async fn lookup_ip(&self, name: &str, server: SocketAddr) -> Result<Vec<IpAddr>> {
+		let (v4, v6) = tokio::join!(
+			self.lookup_addresses(name, QueryType::A, server),
+			self.lookup_addresses(name, QueryType::AAAA, server),
+		);
+		merge_addresses(v4, v6)
+	}
+
+	async fn lookup_addresses(&self, name: &str, q_type: QueryType, server: SocketAddr) -> Result<Vec<IpAddr>> {
+		Ok(addresses(&self.checked_query(name, q_type, server).await?, q_type))
+	}
+
+	/// Send a recursive query for `name`, or for the names of the search list of the client
+	/// in turn, as `DNSClient` lookups do.
+	async fn checked_query(&self, name: &str, q_type: QueryType, server: SocketAddr) -> Result<DNSPacket> {
+		let mut search = Search::default();
+		for candidate in search_names(name, self.client.search(), self.client.ndots()) {
+			if let Some(response) = search.add(&candidate, self.send_query(&candidate, q_type, server, true).await?)? {
+				return Ok(response);
+			}
+		}
+		search.finish(name)
+	}
+
+	/// Run `exchange` with the tries of the retry policy of the client, as `RetryPolicy::run`
+	/// does, waiting between them on the timers of tokio.
+	async fn retry<T, F, R>(&self, mut exchange: F) -> Result<T>
+		where F: FnMut(Duration) -> R, R: Future<Output = Result<T>>
+	{
+		let mut tries = self.client.retry().start();
+		let mut last_err = None;
+		while let Some((wait, timeout)) = tries.next_try() {
+			sleep(wait).await;
+			match exchange(timeout).await {
+				Ok(response) => return Ok(response),
+				Err(e) if is_retryable(&e) => last_err = Some(e),
+				Err(e) => return Err(e),
+			}
+		}
+		Err(last_err.unwrap_or_else(|| Error::new(ErrorKind::TimedOut, "Retry deadline passed")))
+	}
+
+	/// Send `message`, the wire format of `query`, over UDP, falling back to TCP if the
+	/// response is truncated, waiting up to `wait` for each.
+	async fn exchange_udp(&self, message: &[u8], query: &DNSPacket, server: SocketAddr, wait: Duration) -> Result<DNSPacket> {
+		let response = self.exchange_datagram(message, query, server, wait).await?;
+		if response.header.truncated_message {
+			return self.exchange_stream(message, query, server, wait).await;
+		}
+		Ok(response)
+	}
+
+	/// Send `message`, the wire format of `query`, in a datagram and wait for the response.
+	async fn exchange_datagram(&self, message: &[u8], query: &DNSPacket, server: SocketAddr, wait: Duration) -> Result<DNSPacket> {
+		let socket = bind_query_socket(server)?;
+		socket.set_nonblocking(true)?;
+		let socket = UdpSocket::from_std(socket)?;
+		socket.send_to(message, server).await?;
+
+		let mut buf = vec![0; response_payload(query)];
+		let receive = async {
+			loop {
+				let (len, src) = socket.recv_from(&mut buf).await?;
+				if src != server {
+					continue;
+				}
+				if let Ok(Some((response, _))) = read_response(buf[..len].to_vec(), query) {
+					return Ok(response);
+				}
+			}
+		};
+		timeout(wait, receive.instrument(debug_span!("send", server = %server, transport = "udp"))).await
+			.map_err(|_| Error::new(ErrorKind::TimedOut, format!("No response from {}", server)))?
+	}
+
+	/// Send `message`, the wire format of `query`, over TCP with its length prefix and read
+	/// messages until the response to it.
+	async fn exchange_stream(&self, message: &[u8], query: &DNSPacket, server: SocketAddr, wait: Duration) -> Result<DNSPacket> {
+		let exchange = async {
+			let mut stream = TcpStream::connect(server).await?;
+			stream.write_all(&tcp_frame(message)?).await?;
+			loop {
+				let len = stream.read_u16().await?;
+				let mut message = vec![0; len as usize];
+				stream.read_exact(&mut message).await?;
+				if let Some((response, _)) = read_response(message, query)? {
+					return Ok(response);
+				}
+			}
+		};
+		timeout(wait, exchange.instrument(debug_span!("send", server = %server, transport = "tcp"))).await
+			.map_err(|_| Error::new(ErrorKind::TimedOut, format!("No response from {}", server)))?
+	}
+}
+
+impl From<DNSClient> for AsyncDNSClient {
+	fn from(client: DNSClient) -> Self {
+		AsyncDNSClient { client }
+	}
+}
@@ -0,0 +1,485 @@
+//! Zones the server is authoritative for
+
+use std::collections::{ BTreeMap, BTreeSet, HashMap, HashSet };
+use std::io::{ Error, ErrorKind, Result };
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::sync::{ Arc, Mutex, RwLock };
+
+use crate::server::delegation::{ check_delegation, expected_delegation, DelegationCheck, PublishedDelegation, PublishedZone };
+use crate::server::geoip::{ GeoLocation, GeoRecords, GeoZone };
+use crate::server::protocol::{ DNSPacket, DNSRecord, QueryType, ResultCode, TransientTTL };
+use crate::server::resolve::{ is_subdomain, parent_name };
+use crate::server::schedule::{ self, Schedule, ScheduledZone };
+
+/// Longest chain of CNAMEs followed within a zone...
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Read access to the data of a zone, whichever way it is stored.
+pub trait ZoneData {
+	/// Name of the zone apex, lowercased and without the trailing dot.
+	fn origin(&self) -> &str;
+
+	/// The SOA record of the zone.
+	fn soa(&self) -> Result<DNSRecord>;
+
+	/// Records of type `q_type` owned by `name`.
+	fn lookup(&self, name: &str, q_type: QueryType) -> Result<Vec<DNSRecord>>;
+
+	/// Whether any record is owned by `name` or by a name below it.
+	fn has_name(&self, name: &str) -> Result<bool>;
+}
+
+/// A zone: its SOA fields and the records it holds, indexed by owner name and type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Zone {
+	pub domain: String,
+	pub m_name: String,
+	pub r_name: String,
+	pub serial: u32,
+	pub refresh: u32,
+	pub retry: u32,
+	pub expire: u32,
+	pub minimum: u32,
+	records: BTreeMap<(String, u16), BTreeSet<DNSRecord>>,
+	// Every owner name and all of their ancestors, so that empty non-terminals exist...
+	nodes: HashSet<String>,
+	count: usize,
+}
+
+impl Zone {
+	pub fn new(domain: String, m_name: String, r_name: String) -> Zone {
+		Zone {
+			domain,
+			m_name,
+			r_name,
+			serial: 0,
+			refresh: 0,
+			retry: 0,
+			expire: 0,
+			minimum: 0,
+			records: BTreeMap::new(),
+			nodes: HashSet::new(),
+			count: 0,
+		}
+	}
+
+	/// Build a zone from its records, which have to include exactly one SOA naming the apex.
+	pub fn from_records(records: Vec<DNSRecord>) -> Result<Zone> {
+		let mut zone = Zone::new(String::new(), String::new(), String::new());
+		let mut has_soa = false;
+		for record in records {
+			if let DNSRecord::SOA { .. } = record {
+				if has_soa {
+					return Err(Error::new(ErrorKind::InvalidData, "A zone needs exactly one SOA record"));
+				}
+				zone.set_soa(&record);
+				has_soa = true;
+			} else {
+				zone.add_record(record);
+			}
+		}
+
+		if !has_soa {
+			return Err(Error::new(ErrorKind::InvalidData, "A zone needs exactly one SOA record"));
+		}
+		Ok(zone)
+	}
+
+	/// Take the apex and the SOA fields from an SOA record, other records are ignored.
+	pub fn set_soa(&mut self, soa: &DNSRecord) {
+		if let DNSRecord::SOA { ref domain, ref m_name, ref r_name, serial, refresh, retry, expire, minimum, .. } = *soa {
+			self.domain = domain.clone();
+			self.m_name = m_name.clone();
+			self.r_name = r_name.clone();
+			self.serial = serial;
+			self.refresh = refresh;
+			self.retry = retry;
+			self.expire = expire;
+			self.minimum = minimum;
+		}
+	}
+
+	/// Add a record, returns false if the zone already held it.
+	pub fn add_record(&mut self, record: DNSRecord) -> bool {
+		let owner = record.get_domain().unwrap_or_default();
+		let mut node = Some(owner.as_str());
+		while let Some(name) = node {
+			if !self.nodes.insert(name.to_string()) {
+				break;
+			}
+			node = parent_name(name);
+		}
+
+		let key = (owner, record.get_query_type().to_num());
+		let added = self.records.entry(key).or_default().insert(record);
+		if added {
+			self.count += 1;
+		}
+		added
+	}
+
+	/// All records of the zone except the SOA, ordered by owner name and type.
+	pub fn records(&self) -> impl Iterator<Item = &DNSRecord> {
+		self.records.values().flatten()
+	}
+
+	/// Number of records in the zone, not counting the SOA.
+	pub fn len(&self) -> usize {
+		self.count
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.count == 0
+	}
+
+	/// The SOA record built from the zone fields.
+	pub fn get_soa(&self) -> DNSRecord {
+		DNSRecord::SOA {
+			domain: self.domain.clone(),
+			m_name: self.m_name.clone(),
+			r_name: self.r_name.clone(),
+			serial: self.serial,
+			refresh: self.refresh,
+			retry: self.retry,
+			expire: self.expire,
+			minimum: self.minimum,
+			ttl: TransientTTL(self.minimum),
+		}
+	}
+}
+
+impl ZoneData for Zone {
+	fn origin(&self) -> &str {
+		&self.domain
+	}
+
+	fn soa(&self) -> Result<DNSRecord> {
+		Ok(self.get_soa())
+	}
+
+	fn lookup(&self, name: &str, q_type: QueryType) -> Result<Vec<DNSRecord>> {
+		if q_type == QueryType::SOA {
+			return Ok(if name == self.domain { vec![self.get_soa()] } else { Vec::new() });
+		}
+		let key = (name.to_string(), q_type.to_num());
+		Ok(self.records.get(&key).map(|records| records.iter().cloned().collect()).unwrap_or_default())
+	}
+
+	fn has_name(&self, name: &str) -> Result<bool> {
+		Ok(name == self.domain || self.nodes.contains(name))
+	}
+}
+// --------------------------------------------------------------------------------------------
+
+/// A zone as held by the authority, whichever way it is stored.
+pub type SharedZone = Arc<dyn ZoneData + Send + Sync>;
+
+/// The zones the server answers authoritatively for.
+#[derive(Default)]
+pub struct Authority {
+	zones: RwLock<Vec<SharedZone>>,
+	// Cancellation flags of the zone files being loaded in the background...
+	loads: Mutex<HashMap<PathBuf, Arc<AtomicBool>>>,
+	// Reject the obsolete record types in the zones loaded...
+	strict: AtomicBool,
+	schedule: RwLock<Schedule>,
+	geo_records: RwLock<Arc<GeoRecords>>,
+	// Delegations published over the records of their parents, and the number of issues the
+	// last check of the delegations found...
+	published: RwLock<Vec<PublishedDelegation>>,
+	drift: AtomicUsize,
+}
+
+impl Authority {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Add a zone, replacing any zone with the same origin.
+	pub fn add_zone(&self, zone: SharedZone) {
+		if let Ok(mut zones) = self.zones.write() {
+			zones.retain(|existing| existing.origin() != zone.origin());
+			zones.push(zone);
+		}
+	}
+
+	/// Stop serving the zone of `origin`, returning whether there was one.
+	pub fn remove_zone(&self, origin: &str) -> bool {
+		let origin = origin.trim_end_matches('.').to_lowercase();
+		match self.zones.write() {
+			Ok(mut zones) => {
+				let count = zones.len();
+				zones.retain(|zone| zone.origin() != origin);
+				zones.len() < count
+			}
+			Err(_) => false,
+		}
+	}
+
+	/// Origins of the zones served, sorted.
+	pub fn origins(&self) -> Vec<String> {
+		let mut origins: Vec<String> = match self.zones.read() {
+			Ok(zones) => zones.iter().map(|zone| zone.origin().to_string()).collect(),
+			Err(_) => Vec::new(),
+		};
+		origins.sort();
+		origins
+	}
+
+	/// Replace all the zones at once, with those of a configuration read again. The
+	/// delegations published are dropped along with the zones they were worked out from.
+	pub fn replace_zones(&self, zones: Vec<SharedZone>) {
+		if let Ok(mut current) = self.zones.write() {
+			*current = zones;
+		}
+		if let Ok(mut published) = self.published.write() {
+			published.clear();
+		}
+	}
+
+	/// Check the delegation of every zone whose parent zone is served too, with the records
+	/// published for it if any. Zones failing to be read are left out.
+	pub fn check_delegations(&self) -> Vec<DelegationCheck> {
+		let published = match self.published.read() {
+			Ok(published) => published.clone(),
+			Err(poisoned) => poisoned.into_inner().clone(),
+		};
+		let mut checks = Vec::new();
+		for (parent, child) in self.delegations() {
+			let parent = PublishedZone { zone: parent.as_ref(), delegations: &published };
+			match check_delegation(&parent, child.as_ref()) {
+				Ok(check) => checks.push(check),
+				Err(e) => info!("Failed to check the delegation of {}: {}", child.origin(), e),
+			}
+		}
+		self.drift.store(checks.iter().map(|check| check.issues.len()).sum(), Ordering::Relaxed);
+		checks
+	}
+
+	/// Number of issues the last check of the delegations found.
+	pub fn delegation_drift(&self) -> usize {
+		self.drift.load(Ordering::Relaxed)
+	}
+
+	/// Publish the NS, glue and DS records of the zone of `child` in its parent zone, in place
+	/// of those the parent holds, until the zones are replaced. Returns the number of records
+	/// published.
+	pub fn publish_delegation(&self, child: &str) -> Result<usize> {
+		let child = child.trim_end_matches('.').to_lowercase();
+		let (parent, zone) = self.delegations().into_iter()
+			.find(|(_, zone)| zone.origin() == child)
+			.ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No zone {} with a parent zone", child)))?;
+		let records = expected_delegation(zone.as_ref())?;
+		let count = records.len();
+		let delegation = PublishedDelegation { parent: parent.origin().to_string(), child, records };
+		if let Ok(mut published) = self.published.write() {
+			published.retain(|existing| existing.child != delegation.child);
+			published.push(delegation);
+		}
+		Ok(count)
+	}
+
+	/// Every zone whose parent zone is served too, along with that parent.
+	fn delegations(&self) -> Vec<(SharedZone, SharedZone)> {
+		let zones = match self.zones.read() {
+			Ok(zones) => zones.clone(),
+			Err(_) => return Vec::new(),
+		};
+		zones.iter()
+			.filter_map(|child| {
+				let parent = zones.iter()
+					.filter(|zone| zone.origin() != child.origin() && is_subdomain(child.origin(), zone.origin()))
+					.max_by_key(|zone| zone.origin().len())?;
+				Some((parent.clone(), child.clone()))
+			})
+			.collect()
+	}
+
+	/// Serve the record sets of `schedule` in place of those of the zones during their
+	/// windows, replacing the schedule there was.
+	pub fn set_schedule(&self, schedule: Schedule) {
+		match self.schedule.write() {
+			Ok(mut current) => *current = schedule,
+			Err(poisoned) => *poisoned.into_inner() = schedule,
+		}
+	}
+
+	/// Serve the record sets of `records` in place of those of the zones to the clients their
+	/// policies match, replacing the geo records there were.
+	pub fn set_geo_records(&self, records: GeoRecords) {
+		match self.geo_records.write() {
+			Ok(mut current) => *current = Arc::new(records),
+			Err(poisoned) => *poisoned.into_inner() = Arc::new(records),
+		}
+	}
+
+	/// The record sets served by where the clients are.
+	pub fn geo_records(&self) -> Arc<GeoRecords> {
+		match self.geo_records.read() {
+			Ok(records) => records.clone(),
+			Err(poisoned) => poisoned.into_inner().clone(),
+		}
+	}
+
+	/// Turn strict mode on or off: in strict mode zones holding records of the obsolete types
+	/// (see `protocol::is_legacy_type`) fail to load.
+	pub fn set_strict(&self, strict: bool) {
+		self.strict.store(strict, Ordering::SeqCst);
+	}
+
+	pub fn is_strict(&self) -> bool {
+		self.strict.load(Ordering::SeqCst)
+	}
+
+	/// Register a background load of `path`, cancelling an earlier load of the same file
+	/// which is still running.
+	pub(crate) fn begin_load(&self, path: &Path) -> Arc<AtomicBool> {
+		let cancelled = Arc::new(AtomicBool::new(false));
+		if let Ok(mut loads) = self.loads.lock() {
+			if let Some(previous) = loads.insert(path.to_path_buf(), cancelled.clone()) {
+				previous.store(true, Ordering::SeqCst);
+			}
+		}
+		cancelled
+	}
+
+	/// Unregister a finished load, unless a newer load of the file took its place.
+	pub(crate) fn end_load(&self, path: &Path, cancelled: &Arc<AtomicBool>) {
+		if let Ok(mut loads) = self.loads.lock() {
+			if loads.get(path).is_some_and(|current| Arc::ptr_eq(current, cancelled)) {
+				loads.remove(path);
+			}
+		}
+	}
+
+	/// The closest enclosing zone of `qname`, if the server is authoritative for it.
+	pub fn find_zone(&self, qname: &str) -> Option<SharedZone> {
+		let zones = self.zones.read().ok()?;
+		zones.iter()
+			.filter(|zone| is_subdomain(qname, zone.origin()))
+			.max_by_key(|zone| zone.origin().len())
+			.cloned()
+	}
+
+	/// Answer a query from the local zones, with the record sets scheduled for the time. None
+	/// if `qname` isn't in any of them.
+	pub fn query(&self, qname: &str, q_type: QueryType) -> Option<Result<DNSPacket>> {
+		self.query_from(qname, q_type, None)
+	}
+
+	/// Answer a query from the local zones for a client at `location`, if it's known, with
+	/// the geo records for it and the record sets scheduled for the time. None if `qname`
+	/// isn't in any of the zones.
+	pub fn query_from(&self, qname: &str, q_type: QueryType, location: Option<&GeoLocation>) -> Option<Result<DNSPacket>> {
+		let qname = qname.trim_end_matches('.').to_lowercase();
+		let mut zone = self.find_zone(&qname)?;
+		// The DS records of a zone are those of its parent, answered from there when it's
+		// served too (RFC 4035 section 3.1.4.1)...
+		if q_type == QueryType::DS && zone.origin() == qname {
+			if let Some(parent) = parent_name(&qname).and_then(|parent| self.find_zone(parent)) {
+				zone = parent;
+			}
+		}
+		let published = match self.published.read() {
+			Ok(published) => published,
+			Err(poisoned) => poisoned.into_inner(),
+		};
+		let published_zone;
+		let zone: &(dyn ZoneData + Send + Sync) = if published.iter().any(|delegation| delegation.parent == zone.origin()) {
+			published_zone = PublishedZone { zone: zone.as_ref(), delegations: &published };
+			&published_zone
+		} else {
+			zone.as_ref()
+		};
+		let geo_records = self.geo_records();
+		let geo_zone;
+		let zone: &(dyn ZoneData + Send + Sync) = match location {
+			Some(location) if !geo_records.is_empty() => {
+				geo_zone = GeoZone { zone, records: &geo_records, location };
+				&geo_zone
+			}
+			_ => zone,
+		};
+
+		let schedule = match self.schedule.read() {
+			Ok(schedule) => schedule,
+			Err(poisoned) => poisoned.into_inner(),
+		};
+		if schedule.is_empty() {
+			return Some(answer(zone, &qname, q_type));
+		}
+
+		let now = schedule::now();
+		let scheduled = ScheduledZone { zone, schedule: &schedule, now };
+		Some(answer(&scheduled, &qname, q_type).map(|mut packet| {
+			schedule.cap_ttls(&qname, &mut packet, now);
+			packet
+		}))
+	}
+}
+
+/// Build the response to a query for `qname` from `zone`: a referral if the name lies below
+/// a zone cut, otherwise an authoritative answer, following CNAMEs inside the zone.
+fn answer(zone: &(dyn ZoneData + Send + Sync), qname: &str, q_type: QueryType) -> Result<DNSPacket> {
+	let mut packet = DNSPacket::new();
+
+	// Look for a zone cut between the apex and qname, starting from the top. The DS records
+	// of a delegation live on the parent side, so they're answered here...
+	let labels: Vec<&str> = qname.split('.').filter(|label| !label.is_empty()).collect();
+	let apex_labels = if zone.origin().is_empty() { 0 } else { zone.origin().split('.').count() };
+	for count in (apex_labels + 1)..=labels.len() {
+		let name = labels[labels.len() - count..].join(".");
+		if name == qname && q_type == QueryType::DS {
+			break;
+		}
+		let nameservers = zone.lookup(&name, QueryType::NS)?;
+		if nameservers.is_empty() {
+			continue;
+		}
+
+		for ns in &nameservers {
+			if let DNSRecord::NS { ref host, .. } = *ns {
+				if is_subdomain(host, &name) {
+					packet.additional.extend(zone.lookup(host, QueryType::A)?);
+					packet.additional.extend(zone.lookup(host, QueryType::AAAA)?);
+				}
+			}
+		}
+		packet.authorities = nameservers;
+		return Ok(packet);
+	}
+
+	packet.header.authoritative_answer = true;
+	let mut name = qname.to_string();
+	for _ in 0..MAX_CNAME_CHAIN {
+		let records = zone.lookup(&name, q_type)?;
+		if !records.is_empty() {
+			packet.answers.extend(records);
+			return Ok(packet);
+		}
+		if q_type == QueryType::CNAME {
+			break;
+		}
+
+		let cname = zone.lookup(&name, QueryType::CNAME)?;
+		let target = match cname.first() {
+			Some(DNSRecord::CNAME { host, .. }) => host.clone(),
+			_ => break,
+		};
+		packet.answers.extend(cname);
+		// Targets outside of the zone are left for the client to chase...
+		if !is_subdomain(&target, zone.origin()) {
+			return Ok(packet);
+		}
+		name = target;
+	}
+
+	// Either the name or just the type doesn't exist, which is told apart by the rcode with
+	// the SOA added for negative caching...
+	if !zone.has_name(&name)? {
+		packet.header.rescode = ResultCode::NXDOMAIN;
+	}
+	packet.authorities.push(zone.soa()?);
+	Ok(packet)
+}
@@ -0,0 +1,126 @@
+//! Zone transfers (AXFR, RFC 5936) consumed as a stream of RRsets
+
+use std::collections::VecDeque;
+use std::io::{ Error, ErrorKind, Result };
+use std::mem;
+use std::net::{ SocketAddr, TcpStream };
+
+use crate::server::buffer::VectorPacketBuffer;
+use crate::server::client::{ read_tcp_message, write_tcp_message, DNSClient };
+use crate::server::protocol::{ DNSPacket, DNSRecord, QueryType, ResultCode };
+
+/// A running zone transfer. Records are read off the connection as the iterator advances,
+/// so only one message and the RRset being assembled are held in memory at any time, no
+/// matter how large the zone is.
+///
+/// Each item is an RRset: a run of consecutive records sharing owner name and type, the way
+/// servers group them in practice. The transfer starts with the SOA of the zone and ends
+/// when the SOA comes around again; that closing copy isn't yielded.
+pub struct ZoneTransfer {
+	stream: TcpStream,
+	query: DNSPacket,
+	pending: VecDeque<DNSRecord>,
+	rrset: Vec<DNSRecord>,
+	soa_count: usize,
+	done: bool,
+}
+
+impl ZoneTransfer {
+	/// Request a transfer of `zone` from `server`.
+	pub fn start(client: &DNSClient, zone: &str, server: SocketAddr) -> Result<ZoneTransfer> {
+		let mut query = client.build_query(zone, QueryType::AXFR, false);
+		let mut req_buffer = VectorPacketBuffer::new();
+		query.write(&mut req_buffer)?;
+
+		let mut stream = TcpStream::connect_timeout(&server, client.timeout())?;
+		stream.set_read_timeout(Some(client.timeout()))?;
+		stream.set_write_timeout(Some(client.timeout()))?;
+		write_tcp_message(&mut stream, req_buffer.as_slice())?;
+
+		Ok(ZoneTransfer {
+			stream,
+			query,
+			pending: VecDeque::new(),
+			rrset: Vec::new(),
+			soa_count: 0,
+			done: false,
+		})
+	}
+
+	/// Read the next message of the transfer into `pending`.
+	fn read_message(&mut self) -> Result<()> {
+		let message = read_tcp_message(&mut self.stream)?;
+		let response = DNSPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(message))?;
+
+		if response.header.id != self.query.header.id {
+			return Err(Error::new(ErrorKind::InvalidData, "Transfer message with a mismatched ID"));
+		}
+		if response.header.rescode != ResultCode::NOERROR {
+			return Err(Error::other(format!("Transfer refused: {:?}", response.header.rescode)));
+		}
+		self.pending.extend(response.answers);
+		Ok(())
+	}
+
+	/// Handle the next record, returning an RRset once a record of another one shows up.
+	fn next_record(&mut self, record: DNSRecord) -> Result<Option<Vec<DNSRecord>>> {
+		let is_soa = record.get_query_type() == QueryType::SOA;
+		if self.soa_count == 0 && !is_soa {
+			return Err(Error::new(ErrorKind::InvalidData, "Transfer doesn't start with an SOA record"));
+		}
+		if is_soa {
+			self.soa_count += 1;
+			if self.soa_count > 1 {
+				self.done = true;
+				return Ok(self.take_rrset());
+			}
+		}
+
+		let same_rrset = self.rrset.first().is_none_or(|first| {
+			first.get_query_type() == record.get_query_type() && first.get_domain() == record.get_domain()
+		});
+		if same_rrset {
+			self.rrset.push(record);
+			Ok(None)
+		} else {
+			Ok(Some(mem::replace(&mut self.rrset, vec![record])))
+		}
+	}
+
+	fn take_rrset(&mut self) -> Option<Vec<DNSRecord>> {
+		if self.rrset.is_empty() {
+			None
+		} else {
+			Some(mem::take(&mut self.rrset))
+		}
+	}
+}
+
+impl Iterator for ZoneTransfer {
+	type Item = Result<Vec<DNSRecord>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		while !self.done {
+			let record = match self.pending.pop_front() {
+				Some(record) => record,
+				None => {
+					if let Err(e) = self.read_message() {
+						self.done = true;
+						return Some(Err(e));
+					}
+					continue;
+				}
+			};
+
+			match self.next_record(record) {
+				Ok(Some(rrset)) => return Some(Ok(rrset)),
+				Ok(None) => (),
+				Err(e) => {
+					self.done = true;
+					return Some(Err(e));
+				}
+			}
+		}
+		self.take_rrset().map(Ok)
+	}
+}
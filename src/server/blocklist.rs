@@ -0,0 +1,324 @@
+//! Blocklists of domains, answered without being resolved, against ads and trackers
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::sync::{ Arc, RwLock };
+
+use crate::server::protocol::{ DNSPacket, DNSQuestion, DNSRecord, QueryType, ResultCode, TransientTTL };
+
+/// TTL of the addresses answered for blocked names, kept short for a name taken off a list
+/// to be reachable again soon...
+const BLOCK_TTL: u32 = 10;
+/// Names of hosts files which aren't there to block anything...
+const HOSTS_NAMES: [&str; 7] = ["localhost", "localhost.localdomain", "local", "broadcasthost", "ip6-localhost", "ip6-loopback", "0.0.0.0"];
+
+/// How the queries for blocked names are answered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockAction {
+	NxDomain,
+	/// With the unspecified address, 0.0.0.0 or ::, and NODATA for other types than A and
+	/// AAAA, which clients fail to connect to right away.
+	NullAddress,
+}
+
+impl BlockAction {
+	pub fn from_name(name: &str) -> Option<BlockAction> {
+		match name {
+			"nxdomain" => Some(BlockAction::NxDomain),
+			"null" => Some(BlockAction::NullAddress),
+			_ => None,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			BlockAction::NxDomain => "nxdomain",
+			BlockAction::NullAddress => "null",
+		}
+	}
+}
+
+/// The domains of a blocklist file, along with the number of queries it blocked.
+///
+/// Lines are either hosts file entries, `0.0.0.0 ads.example.com`, blocking the names
+/// following the address, or domains: `ads.example.com` blocks that name only, while
+/// `*.example.com` and the `||example.com^` of adblock lists block it and every name below
+/// it. Comments start with `#` or `!`.
+///
+/// A list can be disabled at runtime, blocking nothing until it's enabled again or loaded
+/// anew.
+#[derive(Debug)]
+pub struct BlockList {
+	path: PathBuf,
+	exact: HashSet<String>,
+	suffixes: HashSet<String>,
+	enabled: AtomicBool,
+	blocked: AtomicU64,
+}
+
+impl BlockList {
+	/// Read the blocklist of `path`, failing on lines which are neither hosts entries nor
+	/// domains.
+	pub fn load(path: &Path) -> Result<BlockList> {
+		let text = fs::read_to_string(path)?;
+		let mut list = BlockList { path: path.to_path_buf(), exact: HashSet::new(), suffixes: HashSet::new(), enabled: AtomicBool::new(true), blocked: AtomicU64::new(0) };
+		for (number, line) in text.lines().enumerate() {
+			let line = line.split('#').next().unwrap_or("").trim();
+			if line.is_empty() || line.starts_with('!') {
+				continue;
+			}
+			let invalid = || Error::new(ErrorKind::InvalidData, format!("Invalid blocklist entry on line {}: {}", number + 1, line));
+
+			let mut words = line.split_whitespace();
+			let first = words.next().unwrap_or("");
+			if first.parse::<IpAddr>().is_ok() {
+				for name in words.map(normalize).filter(|name| !HOSTS_NAMES.contains(&name.as_str())) {
+					list.exact.insert(name);
+				}
+				continue;
+			}
+			if words.next().is_some() {
+				return Err(invalid());
+			}
+			let suffix = first.strip_prefix("*.").or_else(|| first.strip_prefix("||").and_then(|rule| rule.strip_suffix('^')));
+			let name = normalize(suffix.unwrap_or(first));
+			if name.is_empty() || !name.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(&byte)) {
+				return Err(invalid());
+			}
+			if suffix.is_some() {
+				list.suffixes.insert(name);
+			} else {
+				list.exact.insert(name);
+			}
+		}
+		Ok(list)
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Number of domains and suffixes of the list.
+	pub fn len(&self) -> usize {
+		self.exact.len() + self.suffixes.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Whether the list blocks `name`, given lowercased without the trailing dot.
+	pub fn blocks(&self, name: &str) -> bool {
+		if self.exact.contains(name) {
+			return true;
+		}
+		let mut suffix = name;
+		loop {
+			if self.suffixes.contains(suffix) {
+				return true;
+			}
+			match suffix.split_once('.') {
+				Some((_, parent)) => suffix = parent,
+				None => return false,
+			}
+		}
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		self.enabled.load(Ordering::Relaxed)
+	}
+
+	pub fn set_enabled(&self, enabled: bool) {
+		self.enabled.store(enabled, Ordering::Relaxed);
+	}
+
+	/// Number of queries blocked by the list.
+	pub fn blocked(&self) -> u64 {
+		self.blocked.load(Ordering::Relaxed)
+	}
+}
+
+impl fmt::Display for BlockList {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.path.display())
+	}
+}
+
+fn normalize(name: &str) -> String {
+	name.trim_end_matches('.').to_lowercase()
+}
+
+/// The blocklists of the server: queries for the names of any of them are answered right
+/// away, with NXDOMAIN or the unspecified address, and counted for the first list blocking
+/// them. The lists are swapped as a whole when they're loaded again, enabled.
+#[derive(Debug)]
+pub struct Blocklists {
+	action: BlockAction,
+	lists: RwLock<Arc<Vec<BlockList>>>,
+}
+
+impl Default for Blocklists {
+	fn default() -> Self {
+		Blocklists { action: BlockAction::NxDomain, lists: RwLock::new(Arc::new(Vec::new())) }
+	}
+}
+
+impl Blocklists {
+	pub fn new() -> Self {
+		Blocklists::default()
+	}
+
+	pub fn action(&self) -> BlockAction {
+		self.action
+	}
+
+	pub fn set_action(&mut self, action: BlockAction) {
+		self.action = action;
+	}
+
+	/// The lists in use.
+	pub fn lists(&self) -> Arc<Vec<BlockList>> {
+		match self.lists.read() {
+			Ok(lists) => lists.clone(),
+			Err(poisoned) => poisoned.into_inner().clone(),
+		}
+	}
+
+	/// Use `lists` from now on, in place of the current ones.
+	pub fn replace(&self, lists: Vec<BlockList>) {
+		match self.lists.write() {
+			Ok(mut current) => *current = Arc::new(lists),
+			Err(poisoned) => *poisoned.into_inner() = Arc::new(lists),
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.lists().is_empty()
+	}
+
+	/// Enable or disable the list loaded from `path`, or all of them without one, returning
+	/// the number of lists changed.
+	pub fn set_enabled(&self, path: Option<&Path>, enabled: bool) -> usize {
+		let lists = self.lists();
+		let mut changed = 0;
+		for list in lists.iter().filter(|list| path.is_none_or(|path| list.path() == path)) {
+			list.set_enabled(enabled);
+			changed += 1;
+		}
+		changed
+	}
+
+	/// Whether any of the enabled lists blocks `name`.
+	pub fn blocks(&self, name: &str) -> bool {
+		let name = normalize(name);
+		self.lists().iter().any(|list| list.is_enabled() && list.blocks(&name))
+	}
+
+	/// The answer to `question` when its name is blocked, counted for the list blocking it.
+	/// Writes the response code and the answers into `packet`, and returns whether it did.
+	pub fn answer(&self, question: &DNSQuestion, packet: &mut DNSPacket) -> bool {
+		let name = normalize(&question.name);
+		let lists = self.lists();
+		let list = match lists.iter().find(|list| list.is_enabled() && list.blocks(&name)) {
+			Some(list) => list,
+			None => return false,
+		};
+		list.blocked.fetch_add(1, Ordering::Relaxed);
+		debug!("Blocked {} {} by {}", question.name, question.q_type, list);
+
+		match (self.action, question.q_type) {
+			(BlockAction::NxDomain, _) => packet.header.rescode = ResultCode::NXDOMAIN,
+			(BlockAction::NullAddress, QueryType::A) => {
+				packet.answers.push(DNSRecord::A { domain: question.name.clone(), addr: Ipv4Addr::UNSPECIFIED, ttl: TransientTTL(BLOCK_TTL) });
+			}
+			(BlockAction::NullAddress, QueryType::AAAA) => {
+				packet.answers.push(DNSRecord::AAAA { domain: question.name.clone(), addr: Ipv6Addr::UNSPECIFIED, ttl: TransientTTL(BLOCK_TTL) });
+			}
+			(BlockAction::NullAddress, _) => (),
+		}
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Load a blocklist of `text`, from a file named after `test`.
+	fn list(test: &str, text: &str) -> Result<BlockList> {
+		let path = std::env::temp_dir().join(format!("rdns-blocklist-{}-{}.txt", test, std::process::id()));
+		fs::write(&path, text)?;
+		let list = BlockList::load(&path);
+		let _ = fs::remove_file(&path);
+		list
+	}
+
+	fn question(name: &str, q_type: QueryType) -> DNSQuestion {
+		DNSQuestion::new(name.to_string(), q_type)
+	}
+
+	#[test]
+	fn hosts_format() {
+		let list = list("hosts", "# Ads\n127.0.0.1 localhost\n::1 ip6-localhost\n0.0.0.0 ads.example.com Tracker.example.com.  # both\n").unwrap();
+		assert_eq!(list.len(), 2);
+		assert!(list.blocks("ads.example.com"));
+		assert!(list.blocks("tracker.example.com"));
+		assert!(!list.blocks("localhost"));
+		assert!(!list.blocks("www.ads.example.com"));
+	}
+
+	#[test]
+	fn domain_format() {
+		let list = list("domains", "! Adblock header\nads.example.com\n*.tracker.example.net\n||metrics.example.org^\n").unwrap();
+		assert_eq!(list.len(), 3);
+		assert!(list.blocks("ads.example.com"));
+		assert!(!list.blocks("cdn.ads.example.com"));
+		assert!(!list.blocks("example.com"));
+	}
+
+	#[test]
+	fn wildcards_block_the_name_and_its_subdomains() {
+		let list = list("wildcards", "*.tracker.example.net\n||metrics.example.org^\n").unwrap();
+		for name in ["tracker.example.net", "a.tracker.example.net", "a.b.tracker.example.net", "metrics.example.org", "eu.metrics.example.org"] {
+			assert!(list.blocks(name), "{}", name);
+		}
+		for name in ["example.net", "nottracker.example.net", "tracker.example.net.evil.test", "example.org"] {
+			assert!(!list.blocks(name), "{}", name);
+		}
+	}
+
+	#[test]
+	fn invalid_lines_are_refused() {
+		for text in ["ads.example.com tracker.example.com\n", "ads example.com!\n", "*.\n"] {
+			let e = list("invalid", text).unwrap_err();
+			assert_eq!(e.kind(), ErrorKind::InvalidData, "{:?}", text);
+		}
+	}
+
+	#[test]
+	fn blocked_names_are_answered_and_counted() {
+		let mut lists = Blocklists::new();
+		lists.replace(vec![list("answer", "ads.example.com\n").unwrap()]);
+		assert!(lists.blocks("ADS.example.com."));
+		assert!(!lists.blocks("www.example.com"));
+
+		let mut packet = DNSPacket::new();
+		assert!(lists.answer(&question("ads.example.com", QueryType::A), &mut packet));
+		assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+
+		lists.set_action(BlockAction::NullAddress);
+		let mut packet = DNSPacket::new();
+		assert!(lists.answer(&question("ads.example.com", QueryType::AAAA), &mut packet));
+		assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+		assert!(matches!(packet.answers[..], [DNSRecord::AAAA { addr, .. }] if addr == Ipv6Addr::UNSPECIFIED));
+		assert_eq!(lists.lists()[0].blocked(), 2);
+
+		assert_eq!(lists.set_enabled(None, false), 1);
+		assert!(!lists.answer(&question("ads.example.com", QueryType::A), &mut DNSPacket::new()));
+	}
+}
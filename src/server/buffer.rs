@@ -1,6 +1,14 @@
+use std::collections::HashMap;
 use std::io::Result;
 use std::io::{Error, ErrorKind};
 
+/// Largest offset a compression pointer can hold, in its 14 bits...
+const MAX_POINTER: usize = 0x3FFF;
+/// Compression pointers followed while reading a single name...
+const MAX_JUMPS: usize = 64;
+/// Longest name in its wire form, the length bytes included (RFC 1035 2.3.4)...
+const MAX_NAME: usize = 255;
+
 pub trait PacketBuffer {	
 	fn get(&mut self, pos: usize) -> Result<u8>;
 	fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]>;	
@@ -20,7 +28,7 @@ pub trait PacketBuffer {
 	
 	fn write_u16(&mut self, val: u16) -> Result<()> {
 		self.write(((val >> 8) & 0xFF) as u8)?;
-		self.write(((val >> 0) & 0xFF) as u8)?;
+		self.write((val & 0xFF) as u8)?;
 
 		Ok(())
 	}
@@ -29,13 +37,13 @@ pub trait PacketBuffer {
 		self.write(((val >> 24) & 0xFF) as u8)?;
 		self.write(((val >> 16) & 0xFF) as u8)?;
 		self.write(((val >> 8) & 0xFF) as u8)?;
-		self.write(((val >> 0) & 0xFF) as u8)?;
+		self.write((val & 0xFF) as u8)?;
 
 		Ok(())
 	}
 
 	fn write_qname(&mut self, qname: &str) -> Result<()> {
-		for label in qname.split(".") {
+		for label in qname.split('.').filter(|label| !label.is_empty()) {
 			let len = label.len();
 			if len > 63 {
 				return Err(Error::new(ErrorKind::InvalidInput, "Single label exceeds 63 chars"));
@@ -45,14 +53,24 @@ pub trait PacketBuffer {
 				self.write(*b)?;
 			}
 		}
+		// The name always ends with the zero length root label...
+		self.write(0)?;
 		Ok(())
 	}
 
+	/// Write a name which may be compressed: the question, the owners of the records and
+	/// the names in the RDATA of the types of RFC 1035 (NS, CNAME, SOA, PTR and MX). Names in
+	/// the RDATA of other types are written with `write_qname`, never compressed (RFC 3597),
+	/// which is what this does too unless the buffer compresses.
+	fn write_name(&mut self, name: &str) -> Result<()> {
+		self.write_qname(name)
+	}
+
 	fn read(&mut self) -> Result<u8>;
 
 	fn read_u16(&mut self) -> Result<u16> {
 		let ret = ((self.read()? as u16) << 8) 
-				| ((self.read()? as u16) << 0);
+				| (self.read()? as u16);
 		Ok(ret)
 	}
 
@@ -60,7 +78,7 @@ pub trait PacketBuffer {
 		let ret = ((self.read()? as u32) << 24)
 				| ((self.read()? as u32) << 16)
 				| ((self.read()? as u32) << 8)
-				| ((self.read()? as u32) << 0);
+				| (self.read()? as u32);
 		Ok(ret)
 	}
 
@@ -68,9 +86,19 @@ pub trait PacketBuffer {
 	// Ex: [3]www[6]google[3]com[0] to www.google.com
 	// Ex: [3]www[5]yahoo[2]in[0] to www.yahoo.in
 	// www, google, yahoo, in, com in above ex are called labels preceded by the length of the label. The ending is 0.
+	//
+	// A name read from the wire is untrusted: a pointer has to go strictly back, before the labels read
+	// since the last jump, there are at most MAX_JUMPS of them and the name is at most MAX_NAME bytes
+	// long. Anything else is an error of kind InvalidData, so no packet can make this loop.
 	fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
 		let mut pos = self.pos();
 
+		// Pointers have to point before this, the start of the labels read since the last jump...
+		let mut limit = pos;
+		let mut jumps = 0;
+		// Length of the name in its wire form, the root label included...
+		let mut length = 1;
+
 		// The delimeter which will be appended for each label.
 		// Initially, it will be empty. Later it will be changed to '.'.
 		let mut delimeter = "";
@@ -92,11 +120,22 @@ pub trait PacketBuffer {
 				// These two bytes taken together, and removing the two MSB's, indicate the jump position.
 				// Calculate the jump position and update the local pos variable...
 				let next_byte = self.get(pos + 1)? as u16;
-				pos = ((((len as u16) ^ 0xC0) << 8) | next_byte) as usize;
+				let target = ((((len as u16) ^ 0xC0) << 8) | next_byte) as usize;
+
+				jumps += 1;
+				if target >= limit || jumps > MAX_JUMPS {
+					return Err(Error::new(ErrorKind::InvalidData, "Invalid compression pointer"));
+				}
+				pos = target;
+				limit = target;
 
 				jumped = true;
 				continue;
 			}
+			// The other label types (0x40 and 0x80) are obsolete or were never defined...
+			if (len & 0xC0) != 0 {
+				return Err(Error::new(ErrorKind::InvalidData, "Unknown label type"));
+			}
 			// Move forward a single byte i.e., the byte next to length, the start of lablel...
 			pos += 1;
 
@@ -105,6 +144,11 @@ pub trait PacketBuffer {
 				break;
 			}
 
+			length += len as usize + 1;
+			if length > MAX_NAME {
+				return Err(Error::new(ErrorKind::InvalidData, "Name exceeds 255 bytes"));
+			}
+
 			outstr.push_str(delimeter);
 
 			// Get the label of len length and append to outstr
@@ -137,6 +181,11 @@ impl BytePacketBuffer {
 			pos: 0,
 		}
 	}
+
+	/// The bytes up to the current position, i.e. what has been written so far.
+	pub fn as_slice(&self) -> &[u8] {
+		&self.buf[..self.pos]
+	}
 }
 
 impl Default for BytePacketBuffer {
@@ -200,4 +249,130 @@ impl PacketBuffer for BytePacketBuffer {
 		self.pos += steps;
 		Ok(())
 	}
+}
+
+/// A growable PacketBuffer, for messages which aren't bound to the 512 bytes of a plain UDP
+/// packet (TCP messages, canonical forms for DNSSEC etc.)...
+#[derive(Clone, Debug, Default)]
+pub struct VectorPacketBuffer {
+	buf: Vec<u8>,
+	pos: usize,
+	/// Where the names written so far start, by their lowercased suffixes, when compressing...
+	names: Option<HashMap<String, usize>>,
+}
+
+impl VectorPacketBuffer {
+	pub fn new() -> Self {
+		Self {
+			buf: Vec::new(),
+			pos: 0,
+			names: None,
+		}
+	}
+
+	/// An empty buffer compressing the names written with `write_name` (RFC 1035 4.1.4):
+	/// a name ending like one written before ends with a pointer to it. Pointers only go
+	/// back to names written earlier in the message, within the first 16 KB it can point
+	/// to, and never into the RDATA of types whose names aren't compressed.
+	pub fn with_compression() -> Self {
+		Self {
+			names: Some(HashMap::new()),
+			..VectorPacketBuffer::new()
+		}
+	}
+
+	pub fn from_bytes(buf: Vec<u8>) -> Self {
+		Self { buf, pos: 0, names: None }
+	}
+
+	pub fn as_slice(&self) -> &[u8] {
+		&self.buf
+	}
+
+	pub fn into_inner(self) -> Vec<u8> {
+		self.buf
+	}
+}
+
+impl PacketBuffer for VectorPacketBuffer {
+	fn get(&mut self, pos: usize) -> Result<u8> {
+		self.buf.get(pos).copied().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "End of Buffer"))
+	}
+
+	fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
+		if start + len > self.buf.len() {
+			return Err(Error::new(ErrorKind::InvalidInput, "End of Buffer"));
+		}
+		Ok(&self.buf[start..start + len])
+	}
+
+	fn read(&mut self) -> Result<u8> {
+		let ret = self.get(self.pos)?;
+		self.pos += 1;
+		Ok(ret)
+	}
+
+	fn write(&mut self, val: u8) -> Result<()> {
+		if self.pos < self.buf.len() {
+			self.buf[self.pos] = val;
+		} else {
+			self.buf.resize(self.pos, 0);
+			self.buf.push(val);
+		}
+		self.pos += 1;
+		Ok(())
+	}
+
+	fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+		match self.buf.get_mut(pos) {
+			Some(b) => {
+				*b = val;
+				Ok(())
+			}
+			None => Err(Error::new(ErrorKind::InvalidInput, "End of Buffer")),
+		}
+	}
+
+	fn pos(&self) -> usize {
+		self.pos
+	}
+
+	fn seek(&mut self, pos: usize) -> Result<()> {
+		self.pos = pos;
+		Ok(())
+	}
+
+	fn step(&mut self, steps: usize) -> Result<()> {
+		self.pos += steps;
+		Ok(())
+	}
+
+	fn write_name(&mut self, name: &str) -> Result<()> {
+		if self.names.is_none() {
+			return self.write_qname(name);
+		}
+		let labels: Vec<&str> = name.split('.').filter(|label| !label.is_empty()).collect();
+		for (i, label) in labels.iter().enumerate() {
+			let suffix = labels[i..].join(".").to_lowercase();
+			let target = self.names.as_ref().and_then(|names| names.get(&suffix).copied());
+			if let Some(target) = target {
+				return self.write_u16(0xC000 | target as u16);
+			}
+			if self.pos <= MAX_POINTER {
+				if let Some(ref mut names) = self.names {
+					names.insert(suffix, self.pos);
+				}
+			}
+
+			if label.len() > 63 {
+				return Err(Error::new(ErrorKind::InvalidInput, "Single label exceeds 63 chars"));
+			}
+			self.write(label.len() as u8)?;
+			for b in label.as_bytes() {
+				self.write(*b)?;
+			}
+		}
+		// The name always ends with the zero length root label...
+		self.write(0)
+	}
 }
\ No newline at end of file
@@ -0,0 +1,1000 @@
+//! Responses of the resolvers, kept until their TTLs run out
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{ self, File };
+use std::io::{ Error, ErrorKind, Read, Result, Write };
+use std::path::Path;
+use std::sync::atomic::{ AtomicBool, AtomicU32, AtomicU64, Ordering };
+use std::sync::{ Mutex, RwLock };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use crate::server::buffer::{ PacketBuffer, VectorPacketBuffer };
+use crate::server::protocol::{ DNSPacket, DNSRecord, QueryType, ResultCode };
+use crate::server::resolve::is_subdomain;
+
+/// Start of a cache snapshot file...
+const SNAPSHOT_MAGIC: &[u8; 8] = b"RDNSCCH1";
+/// Number of entries the cache holds unless told otherwise...
+pub const DEFAULT_MAX_ENTRIES: usize = 100_000;
+/// Memory taken by an entry besides its records, roughly...
+const ENTRY_OVERHEAD: usize = 128;
+/// Number of popular entries every thread keeps a copy of unless told otherwise...
+pub const DEFAULT_HOT_ENTRIES: usize = 16;
+/// Times an entry must have been served before threads keep a copy of it...
+const HOT_MIN_HITS: u32 = 16;
+
+/// Tells the caches apart in the copies threads keep...
+static NEXT_CACHE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The IN class, the only one the server deals with...
+pub const CLASS_IN: u16 = 1;
+/// TTL of stale records handed out, as recommended by RFC 8767...
+pub const STALE_TTL: u32 = 30;
+
+/// What a cached response answers.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+	pub qname: String,
+	pub q_type: QueryType,
+	pub q_class: u16,
+}
+
+impl CacheKey {
+	/// Key of a question of the IN class, the name being lowercased and stripped of the
+	/// trailing dot.
+	pub fn new(qname: &str, q_type: QueryType) -> CacheKey {
+		CacheKey {
+			qname: qname.trim_end_matches('.').to_lowercase(),
+			q_type,
+			q_class: CLASS_IN,
+		}
+	}
+}
+
+/// When entries get refreshed ahead of their expiry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Prefetch {
+	/// Refresh entries served within this last percentage of their TTL...
+	pub percent: u32,
+	/// ...if they were served at least this many times.
+	pub min_hits: u32,
+}
+
+/// Bounds put on the TTLs of the responses stored. Records of positive answers get their
+/// TTLs clamped between `min` and `max`, negative responses are cached for at most
+/// `max_negative`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TtlLimits {
+	pub min: u32,
+	pub max: u32,
+	pub max_negative: u32,
+}
+
+impl Default for TtlLimits {
+	fn default() -> Self {
+		TtlLimits { min: 0, max: u32::MAX, max_negative: u32::MAX }
+	}
+}
+
+/// A response as it was received, with the TTLs it came with.
+#[derive(Debug)]
+struct CacheEntry {
+	rescode: ResultCode,
+	answers: Vec<DNSRecord>,
+	authorities: Vec<DNSRecord>,
+	stored: Instant,
+	expires: Instant,
+	// Times the entry was served, and whether a refresh was started...
+	hits: AtomicU32,
+	prefetching: AtomicBool,
+	// Tick of the cache clock the entry was last served at, and its approximate size...
+	last_used: AtomicU64,
+	size: usize,
+}
+
+impl CacheEntry {
+	fn new(rescode: ResultCode, answers: Vec<DNSRecord>, authorities: Vec<DNSRecord>, stored: Instant, expires: Instant) -> CacheEntry {
+		let size = ENTRY_OVERHEAD + answers.iter().chain(&authorities).map(record_size).sum::<usize>();
+		CacheEntry {
+			rescode,
+			answers,
+			authorities,
+			stored,
+			expires,
+			hits: AtomicU32::new(0),
+			prefetching: AtomicBool::new(false),
+			last_used: AtomicU64::new(0),
+			size,
+		}
+	}
+}
+
+/// Approximate memory taken by a record, going by its size on the wire...
+fn record_size(record: &DNSRecord) -> usize {
+	let mut buffer = VectorPacketBuffer::new();
+	record.write(&mut buffer).map(|len| len * 2).unwrap_or(ENTRY_OVERHEAD)
+}
+
+/// The entries, along with the sum of their sizes.
+#[derive(Debug, Default)]
+struct Entries {
+	map: HashMap<CacheKey, CacheEntry>,
+	bytes: usize,
+}
+
+impl Entries {
+	fn insert(&mut self, key: CacheKey, entry: CacheEntry) {
+		self.bytes += entry.size;
+		if let Some(old) = self.map.insert(key, entry) {
+			self.bytes -= old.size;
+		}
+	}
+
+	fn remove(&mut self, key: &CacheKey) {
+		if let Some(old) = self.map.remove(key) {
+			self.bytes -= old.size;
+		}
+	}
+}
+
+/// A copy of a popular entry, kept by a thread.
+#[derive(Debug)]
+struct HotEntry {
+	key: CacheKey,
+	rescode: ResultCode,
+	answers: Vec<DNSRecord>,
+	authorities: Vec<DNSRecord>,
+	stored: Instant,
+	expires: Instant,
+	last_used: u64,
+}
+
+/// The copies a thread keeps, of the entries of one cache as of one of its generations.
+#[derive(Debug, Default)]
+struct HotEntries {
+	owner: (u64, u64),
+	entries: Vec<HotEntry>,
+	clock: u64,
+}
+
+thread_local! {
+	static HOT: RefCell<HotEntries> = RefCell::new(HotEntries::default());
+}
+
+/// Counters of the cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+	pub entries: usize,
+	/// Approximate memory taken by the entries...
+	pub bytes: usize,
+	/// Entries dropped to stay within the limits, expired entries don't count...
+	pub evictions: u64,
+	/// Lookups answered from the cache and lookups which weren't...
+	pub hits: u64,
+	pub misses: u64,
+	/// Hits answered from the copies threads keep of popular entries...
+	pub hot_hits: u64,
+}
+
+/// An entry as listed by `Cache::dump`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedResponse {
+	pub key: CacheKey,
+	pub rescode: ResultCode,
+	pub answers: Vec<DNSRecord>,
+	pub authorities: Vec<DNSRecord>,
+	/// Seconds left until the entry expires, 0 once it did...
+	pub ttl: u32,
+	/// Whether the entry expired and is only kept to be served stale...
+	pub stale: bool,
+	pub hits: u32,
+}
+
+impl fmt::Display for CachedResponse {
+	/// A comment line with the question, the response code, the time left and the hits,
+	/// followed by the records, a line each.
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, ";{}.\t\tIN\t{}\t; {:?}, expires in {}s, {} hits", self.key.qname, self.key.q_type, self.rescode, self.ttl, self.hits)?;
+		for record in self.answers.iter().chain(&self.authorities) {
+			writeln!(f, "{}", record)?;
+		}
+		Ok(())
+	}
+}
+
+/// Positive and negative (RFC 2308) responses by question. An entry expires along with the
+/// record having the lowest TTL, and the TTLs handed out count down from the time the entry
+/// was stored, so nothing is served beyond the TTL it was given.
+///
+/// With serve-stale (RFC 8767) turned on, expired entries are kept for a while longer to be
+/// given out when the data can't be refreshed.
+///
+/// The cache is bounded by a number of entries and optionally by the memory they take; the
+/// least recently used entries are evicted to stay within the bounds.
+///
+/// Every thread looking up entries also keeps copies of the few most popular ones, which it
+/// serves without taking the lock. A copy lasts until the entry expires, or until it's due
+/// to be prefetched, so responses stored in place of the entry reach the threads then;
+/// flushing the cache or loading a snapshot drops the copies right away.
+#[derive(Debug)]
+pub struct Cache {
+	entries: RwLock<Entries>,
+	// The cache the copies of the threads belong to, and how many times they were dropped...
+	id: u64,
+	generation: AtomicU64,
+	hot_entries: usize,
+	max_stale: Option<Duration>,
+	prefetch: Option<Prefetch>,
+	ttl_limits: TtlLimits,
+	max_entries: usize,
+	max_bytes: Option<usize>,
+	// Ticks on every hit, ordering the entries by when they were last used...
+	clock: AtomicU64,
+	evictions: AtomicU64,
+	hits: AtomicU64,
+	misses: AtomicU64,
+	hot_hits: AtomicU64,
+	// Held while writing a snapshot, so that two never go to the same file at once...
+	saving: Mutex<()>,
+}
+
+impl Cache {
+	pub fn new() -> Self {
+		Self {
+			entries: RwLock::new(Entries::default()),
+			id: NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed),
+			generation: AtomicU64::new(0),
+			hot_entries: DEFAULT_HOT_ENTRIES,
+			max_stale: None,
+			prefetch: None,
+			ttl_limits: TtlLimits::default(),
+			max_entries: DEFAULT_MAX_ENTRIES,
+			max_bytes: None,
+			clock: AtomicU64::new(0),
+			evictions: AtomicU64::new(0),
+			hits: AtomicU64::new(0),
+			misses: AtomicU64::new(0),
+			hot_hits: AtomicU64::new(0),
+			saving: Mutex::new(()),
+		}
+	}
+
+	/// Bound the cache to `max_entries` entries and, unless None, to about `max_bytes` bytes.
+	pub fn set_limits(&mut self, max_entries: usize, max_bytes: Option<usize>) {
+		self.max_entries = max_entries;
+		self.max_bytes = max_bytes;
+	}
+
+	pub fn stats(&self) -> CacheStats {
+		let (entries, bytes) = self.entries.read().map(|entries| (entries.map.len(), entries.bytes)).unwrap_or((0, 0));
+		CacheStats {
+			entries,
+			bytes,
+			evictions: self.evictions.load(Ordering::Relaxed),
+			hits: self.hits.load(Ordering::Relaxed),
+			misses: self.misses.load(Ordering::Relaxed),
+			hot_hits: self.hot_hits.load(Ordering::Relaxed),
+		}
+	}
+
+	fn over_limits(&self, entries: &Entries) -> bool {
+		entries.map.len() > self.max_entries || self.max_bytes.is_some_and(|max_bytes| entries.bytes > max_bytes)
+	}
+
+	/// Evict the least recently used entries until the cache is within its limits again,
+	/// with some room to spare so that this doesn't happen on every insert.
+	fn evict(&self, entries: &mut Entries) {
+		if !self.over_limits(entries) {
+			return;
+		}
+		let target_entries = self.max_entries - self.max_entries / 16;
+		let target_bytes = self.max_bytes.map(|max_bytes| max_bytes - max_bytes / 16);
+
+		let mut by_use: Vec<(u64, CacheKey)> = entries.map.iter()
+			.map(|(key, entry)| (entry.last_used.load(Ordering::Relaxed), key.clone()))
+			.collect();
+		by_use.sort_unstable_by_key(|(last_used, _)| *last_used);
+
+		for (_, key) in by_use {
+			if entries.map.len() <= target_entries && target_bytes.is_none_or(|target| entries.bytes <= target) {
+				break;
+			}
+			entries.remove(&key);
+			self.evictions.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	/// How long after expiring entries may still be served stale, None turns serve-stale off.
+	pub fn set_max_stale(&mut self, max_stale: Option<Duration>) {
+		self.max_stale = max_stale;
+	}
+
+	pub fn max_stale(&self) -> Option<Duration> {
+		self.max_stale
+	}
+
+	/// Refresh popular entries before they expire, None turns prefetching off.
+	pub fn set_prefetch(&mut self, prefetch: Option<Prefetch>) {
+		self.prefetch = prefetch;
+	}
+
+	/// Number of popular entries every thread keeps a copy of, 0 turns the copies off.
+	pub fn set_hot_entries(&mut self, hot_entries: usize) {
+		self.hot_entries = hot_entries;
+	}
+
+	pub fn set_ttl_limits(&mut self, ttl_limits: TtlLimits) {
+		self.ttl_limits = ttl_limits;
+	}
+
+	pub fn ttl_limits(&self) -> TtlLimits {
+		self.ttl_limits
+	}
+
+	/// Whether an entry is past the time it may be served stale, or just expired without
+	/// serve-stale.
+	fn is_dead(&self, entry: &CacheEntry, now: Instant) -> bool {
+		match self.max_stale {
+			Some(max_stale) => entry.expires + max_stale <= now,
+			None => entry.expires <= now,
+		}
+	}
+
+	/// The cached response to a question, None if there's none or it expired.
+	pub fn lookup(&self, qname: &str, q_type: QueryType) -> Option<DNSPacket> {
+		let key = CacheKey::new(qname, q_type);
+		let now = Instant::now();
+		if let Some(packet) = self.find_hot(&key, now) {
+			self.hits.fetch_add(1, Ordering::Relaxed);
+			self.hot_hits.fetch_add(1, Ordering::Relaxed);
+			return Some(packet);
+		}
+
+		let packet = self.find(key, now);
+		let counter = if packet.is_some() { &self.hits } else { &self.misses };
+		counter.fetch_add(1, Ordering::Relaxed);
+		packet
+	}
+
+	fn find(&self, key: CacheKey, now: Instant) -> Option<DNSPacket> {
+		{
+			let entries = self.entries.read().ok()?;
+			let entry = entries.map.get(&key)?;
+			if entry.expires > now {
+				let hits = entry.hits.fetch_add(1, Ordering::Relaxed) + 1;
+				entry.last_used.store(self.clock.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+				// Entries are only copied once they're popular enough to be prefetched, as the
+				// hits on the copies don't count...
+				let min_hits = self.prefetch.map_or(0, |prefetch| prefetch.min_hits).max(HOT_MIN_HITS);
+				if self.hot_entries > 0 && hits >= min_hits {
+					self.keep_hot(&key, entry, now);
+				}
+
+				let elapsed = now.duration_since(entry.stored).as_secs().min(u32::MAX as u64) as u32;
+				let mut packet = DNSPacket::new();
+				packet.header.rescode = entry.rescode;
+				packet.answers = aged(&entry.answers, elapsed);
+				packet.authorities = aged(&entry.authorities, elapsed);
+				return Some(packet);
+			}
+		}
+
+		// Expired entries are dropped once they're asked for again, unless they may still be
+		// served stale...
+		if let Ok(mut entries) = self.entries.write() {
+			if entries.map.get(&key).is_some_and(|entry| self.is_dead(entry, now)) {
+				entries.remove(&key);
+			}
+		}
+		None
+	}
+
+	/// The copy this thread keeps of an entry, None if it has none or it expired.
+	fn find_hot(&self, key: &CacheKey, now: Instant) -> Option<DNSPacket> {
+		if self.hot_entries == 0 {
+			return None;
+		}
+		HOT.with(|hot| {
+			let mut hot = hot.borrow_mut();
+			if hot.owner != (self.id, self.generation.load(Ordering::Relaxed)) {
+				return None;
+			}
+			hot.clock += 1;
+			let clock = hot.clock;
+			let entry = hot.entries.iter_mut().find(|entry| entry.key == *key && entry.expires > now)?;
+			entry.last_used = clock;
+			let elapsed = now.duration_since(entry.stored).as_secs().min(u32::MAX as u64) as u32;
+
+			let mut packet = DNSPacket::new();
+			packet.header.rescode = entry.rescode;
+			packet.answers = aged(&entry.answers, elapsed);
+			packet.authorities = aged(&entry.authorities, elapsed);
+			Some(packet)
+		})
+	}
+
+	/// Keep a copy of an entry in this thread, in place of the least recently used copy when
+	/// there's no room left. The copy expires along with the entry, or when the entry is due
+	/// to be prefetched.
+	fn keep_hot(&self, key: &CacheKey, entry: &CacheEntry, now: Instant) {
+		let expires = match self.prefetch {
+			Some(prefetch) => entry.stored + (entry.expires - entry.stored) * (100 - prefetch.percent.min(100)) / 100,
+			None => entry.expires,
+		};
+		if expires <= now {
+			return;
+		}
+
+		let owner = (self.id, self.generation.load(Ordering::Relaxed));
+		HOT.with(|hot| {
+			let mut hot = hot.borrow_mut();
+			if hot.owner != owner {
+				hot.owner = owner;
+				hot.entries.clear();
+			}
+			hot.entries.retain(|copy| copy.key != *key);
+			if hot.entries.len() >= self.hot_entries {
+				let oldest = hot.entries.iter().enumerate().min_by_key(|(_, copy)| copy.last_used).map(|(i, _)| i);
+				if let Some(oldest) = oldest {
+					hot.entries.swap_remove(oldest);
+				}
+			}
+
+			hot.clock += 1;
+			let last_used = hot.clock;
+			hot.entries.push(HotEntry {
+				key: key.clone(),
+				rescode: entry.rescode,
+				answers: entry.answers.clone(),
+				authorities: entry.authorities.clone(),
+				stored: entry.stored,
+				expires,
+				last_used,
+			});
+		});
+	}
+
+	/// Whether this thread keeps a copy of an entry which didn't expire.
+	fn is_hot(&self, key: &CacheKey, now: Instant) -> bool {
+		self.hot_entries > 0 && HOT.with(|hot| {
+			let hot = hot.borrow();
+			hot.owner == (self.id, self.generation.load(Ordering::Relaxed))
+				&& hot.entries.iter().any(|copy| copy.key == *key && copy.expires > now)
+		})
+	}
+
+	/// Whether the entry for a question, having just been served, is due to be refreshed
+	/// under the prefetch policy. True only once per entry, for the caller to start the
+	/// refresh.
+	pub fn needs_prefetch(&self, qname: &str, q_type: QueryType) -> bool {
+		let prefetch = match self.prefetch {
+			Some(prefetch) => prefetch,
+			None => return false,
+		};
+		// Copies go before entries are due, the entry needn't be looked at...
+		let key = CacheKey::new(qname, q_type);
+		let now = Instant::now();
+		if self.is_hot(&key, now) {
+			return false;
+		}
+		let entries = match self.entries.read() {
+			Ok(entries) => entries,
+			Err(_) => return false,
+		};
+		let entry = match entries.map.get(&key) {
+			Some(entry) => entry,
+			None => return false,
+		};
+
+		if entry.expires <= now || entry.hits.load(Ordering::Relaxed) < prefetch.min_hits {
+			return false;
+		}
+		let ttl = entry.expires.duration_since(entry.stored).as_secs_f64();
+		let remaining = entry.expires.duration_since(now).as_secs_f64();
+		remaining * 100.0 <= ttl * prefetch.percent as f64 && !entry.prefetching.swap(true, Ordering::Relaxed)
+	}
+
+	/// Let the entry for a question be prefetched again once it's due, the last prefetch
+	/// having ended. Entries stored by a prefetch start over anyway, this is for the prefetches
+	/// which failed, or whose response wasn't cached.
+	pub fn prefetch_finished(&self, qname: &str, q_type: QueryType) {
+		let key = CacheKey::new(qname, q_type);
+		if let Ok(entries) = self.entries.read() {
+			if let Some(entry) = entries.map.get(&key) {
+				entry.prefetching.store(false, Ordering::Relaxed);
+			}
+		}
+	}
+
+	/// An expired response which may still be served stale, with all TTLs set to `STALE_TTL`.
+	/// None without serve-stale, or if there's no such entry.
+	pub fn lookup_stale(&self, qname: &str, q_type: QueryType) -> Option<DNSPacket> {
+		self.max_stale?;
+		let now = Instant::now();
+		let entries = self.entries.read().ok()?;
+		let entry = entries.map.get(&CacheKey::new(qname, q_type))?;
+		if entry.expires > now || self.is_dead(entry, now) {
+			return None;
+		}
+
+		let stale = |records: &[DNSRecord]| -> Vec<DNSRecord> {
+			records.iter()
+				.map(|record| {
+					let mut record = record.clone();
+					record.set_ttl(STALE_TTL);
+					record
+				})
+				.collect()
+		};
+		let mut packet = DNSPacket::new();
+		packet.header.rescode = entry.rescode;
+		packet.answers = stale(&entry.answers);
+		packet.authorities = stale(&entry.authorities);
+		Some(packet)
+	}
+
+	/// Store the response to a question. Only answers and negative responses carrying an SOA
+	/// are cacheable, others (and anything with a TTL of 0 after applying the TTL limits) are
+	/// skipped.
+	pub fn store(&self, qname: &str, q_type: QueryType, response: &DNSPacket) {
+		let mut answers: Vec<DNSRecord> = response.answers.iter()
+			.filter(|record| record.get_query_type() != QueryType::OPT)
+			.cloned()
+			.collect();
+		let mut authorities = response.authorities.clone();
+
+		let limits = self.ttl_limits;
+		let negative = answers.is_empty();
+		if !negative {
+			for record in answers.iter_mut().chain(authorities.iter_mut()) {
+				let record_ttl = record.get_ttl();
+				record.set_ttl(record_ttl.clamp(limits.min, limits.max.max(limits.min)));
+			}
+		}
+		let ttl = match response.header.rescode {
+			ResultCode::NOERROR if !negative => answers.iter().chain(&authorities).map(DNSRecord::get_ttl).min(),
+			ResultCode::NOERROR | ResultCode::NXDOMAIN => negative_ttl(&authorities).map(|ttl| ttl.min(limits.max_negative)),
+			_ => None,
+		};
+		let ttl = match ttl {
+			Some(ttl) if ttl > 0 => ttl,
+			_ => return,
+		};
+		// The SOA of a negative response is handed out with the negative TTL...
+		if negative {
+			for record in authorities.iter_mut() {
+				let record_ttl = record.get_ttl();
+				record.set_ttl(record_ttl.min(ttl));
+			}
+		}
+
+		let stored = Instant::now();
+		let entry = CacheEntry::new(response.header.rescode, answers, authorities, stored, stored + Duration::from_secs(ttl as u64));
+		entry.last_used.store(self.clock.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+		if let Ok(mut entries) = self.entries.write() {
+			entries.insert(CacheKey::new(qname, q_type), entry);
+			self.evict(&mut entries);
+		}
+	}
+
+	/// Drop every entry, returning the number dropped.
+	pub fn flush(&self) -> usize {
+		self.remove_where(|_| true)
+	}
+
+	/// Drop the entries for `name`, of any type, returning the number dropped.
+	pub fn flush_name(&self, name: &str) -> usize {
+		let name = name.trim_end_matches('.').to_lowercase();
+		self.remove_where(|key| key.qname == name)
+	}
+
+	/// Drop the entries for `name` and every name below it, returning the number dropped.
+	pub fn flush_subtree(&self, name: &str) -> usize {
+		let name = name.trim_end_matches('.').to_lowercase();
+		self.remove_where(|key| is_subdomain(&key.qname, &name))
+	}
+
+	fn remove_where<F: Fn(&CacheKey) -> bool>(&self, matches: F) -> usize {
+		let mut entries = match self.entries.write() {
+			Ok(entries) => entries,
+			Err(_) => return 0,
+		};
+		let keys: Vec<CacheKey> = entries.map.keys().filter(|key| matches(key)).cloned().collect();
+		for key in &keys {
+			entries.remove(key);
+		}
+		self.generation.fetch_add(1, Ordering::Relaxed);
+		keys.len()
+	}
+
+	/// List the entries, ordered by name and type, with the TTLs they'd be served with.
+	pub fn dump(&self) -> Vec<CachedResponse> {
+		self.dump_where(|_| true)
+	}
+
+	/// List the entries for `name`, of any type, as `dump` does.
+	pub fn dump_name(&self, name: &str) -> Vec<CachedResponse> {
+		let name = name.trim_end_matches('.').to_lowercase();
+		self.dump_where(|key| key.qname == name)
+	}
+
+	fn dump_where<F: Fn(&CacheKey) -> bool>(&self, matches: F) -> Vec<CachedResponse> {
+		let now = Instant::now();
+		let entries = match self.entries.read() {
+			Ok(entries) => entries,
+			Err(_) => return Vec::new(),
+		};
+
+		let mut dump: Vec<CachedResponse> = entries.map.iter()
+			.filter(|(key, entry)| matches(key) && !self.is_dead(entry, now))
+			.map(|(key, entry)| {
+				let elapsed = now.duration_since(entry.stored).as_secs().min(u32::MAX as u64) as u32;
+				CachedResponse {
+					key: key.clone(),
+					rescode: entry.rescode,
+					answers: aged(&entry.answers, elapsed),
+					authorities: aged(&entry.authorities, elapsed),
+					ttl: (entry.expires.duration_since(entry.stored).as_secs().min(u32::MAX as u64) as u32).saturating_sub(elapsed),
+					stale: entry.expires <= now,
+					hits: entry.hits.load(Ordering::Relaxed),
+				}
+			})
+			.collect();
+		dump.sort_by(|a, b| (&a.key.qname, a.key.q_type.to_num()).cmp(&(&b.key.qname, b.key.q_type.to_num())));
+		dump
+	}
+
+	/// Number of entries, including expired ones which weren't dropped yet.
+	pub fn len(&self) -> usize {
+		self.entries.read().map(|entries| entries.map.len()).unwrap_or(0)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Write the entries which may still be served to a snapshot at `path`, returning the
+	/// number written.
+	///
+	/// A snapshot holds a magic, the number of entries and the entries one after another: the
+	/// question (name with a u16 length, type, class), the rcode, the times the entry was
+	/// stored and expires in seconds since the epoch (u64), the number of answer and authority
+	/// records (u16) and the records in wire format. Integers are big endian.
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<usize> {
+		let _saving = self.saving.lock().map_err(|_| Error::other("Cache snapshot lock poisoned"))?;
+		let now = Instant::now();
+		let wall_now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(Error::other)?.as_secs();
+		// Instants only make sense within the process, they're stored as wall clock times...
+		let to_wall = |instant: Instant| -> u64 {
+			if instant >= now {
+				wall_now + instant.duration_since(now).as_secs()
+			} else {
+				wall_now.saturating_sub(now.duration_since(instant).as_secs())
+			}
+		};
+
+		let mut buffer = VectorPacketBuffer::new();
+		let mut count = 0u32;
+		{
+			let entries = self.entries.read().map_err(|_| Error::other("Cache lock poisoned"))?;
+			for (key, entry) in entries.map.iter().filter(|(_, entry)| !self.is_dead(entry, now)) {
+				if key.qname.len() > u16::MAX as usize || entry.answers.len() > u16::MAX as usize || entry.authorities.len() > u16::MAX as usize {
+					continue;
+				}
+				buffer.write_u16(key.qname.len() as u16)?;
+				for b in key.qname.as_bytes() {
+					buffer.write(*b)?;
+				}
+				buffer.write_u16(key.q_type.to_num())?;
+				buffer.write_u16(key.q_class)?;
+				buffer.write(entry.rescode as u8)?;
+				write_u64(&mut buffer, to_wall(entry.stored))?;
+				write_u64(&mut buffer, to_wall(entry.expires))?;
+				buffer.write_u16(entry.answers.len() as u16)?;
+				buffer.write_u16(entry.authorities.len() as u16)?;
+				for record in entry.answers.iter().chain(&entry.authorities) {
+					record.write(&mut buffer)?;
+				}
+				count += 1;
+			}
+		}
+
+		// Written next to the target and renamed, so a crash never leaves half a snapshot...
+		let tmp_path = path.as_ref().with_extension("tmp");
+		{
+			let mut file = File::create(&tmp_path)?;
+			file.write_all(SNAPSHOT_MAGIC)?;
+			file.write_all(&count.to_be_bytes())?;
+			file.write_all(buffer.as_slice())?;
+			file.sync_all()?;
+		}
+		fs::rename(&tmp_path, path)?;
+		Ok(count as usize)
+	}
+
+	/// Add the entries of the snapshot at `path` which may still be served, returning the
+	/// number added. Entries already in the cache are kept.
+	pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<usize> {
+		let mut data = Vec::new();
+		File::open(path)?.read_to_end(&mut data)?;
+		if data.len() < SNAPSHOT_MAGIC.len() + 4 || &data[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC {
+			return Err(Error::new(ErrorKind::InvalidData, "Not a cache snapshot"));
+		}
+		let count = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+
+		let now = Instant::now();
+		let wall_now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(Error::other)?.as_secs();
+		let to_instant = |secs: u64| -> Option<Instant> {
+			if secs >= wall_now {
+				now.checked_add(Duration::from_secs(secs - wall_now))
+			} else {
+				now.checked_sub(Duration::from_secs(wall_now - secs))
+			}
+		};
+
+		let mut buffer = VectorPacketBuffer::from_bytes(data[12..].to_vec());
+		let mut loaded = Vec::new();
+		for _ in 0..count {
+			let name_len = buffer.read_u16()? as usize;
+			let pos = buffer.pos();
+			let qname = String::from_utf8_lossy(buffer.get_range(pos, name_len)?).to_string();
+			buffer.step(name_len)?;
+			let q_type = QueryType::from_num(buffer.read_u16()?);
+			let q_class = buffer.read_u16()?;
+			let rescode = ResultCode::from_num(buffer.read()?);
+			let stored = read_u64(&mut buffer)?;
+			let expires = read_u64(&mut buffer)?;
+			let answer_count = buffer.read_u16()?;
+			let authority_count = buffer.read_u16()?;
+			let answers = (0..answer_count).map(|_| DNSRecord::read(&mut buffer)).collect::<Result<Vec<_>>>()?;
+			let authorities = (0..authority_count).map(|_| DNSRecord::read(&mut buffer)).collect::<Result<Vec<_>>>()?;
+
+			let (stored, expires) = match (to_instant(stored), to_instant(expires)) {
+				(Some(stored), Some(expires)) => (stored, expires),
+				_ => continue,
+			};
+			let entry = CacheEntry::new(rescode, answers, authorities, stored, expires);
+			if !self.is_dead(&entry, now) {
+				loaded.push((CacheKey { qname, q_type, q_class }, entry));
+			}
+		}
+
+		let mut entries = self.entries.write().map_err(|_| Error::other("Cache lock poisoned"))?;
+		let mut added = 0;
+		for (key, entry) in loaded {
+			if !entries.map.contains_key(&key) {
+				entries.insert(key, entry);
+				added += 1;
+			}
+		}
+		self.evict(&mut entries);
+		self.generation.fetch_add(1, Ordering::Relaxed);
+		Ok(added)
+	}
+}
+
+impl Default for Cache {
+	fn default() -> Self {
+		Cache::new()
+	}
+}
+
+/// Copies of `records` with `elapsed` seconds taken off their TTLs.
+fn aged(records: &[DNSRecord], elapsed: u32) -> Vec<DNSRecord> {
+	records.iter()
+		.map(|record| {
+			let mut record = record.clone();
+			record.set_ttl(record.get_ttl().saturating_sub(elapsed));
+			record
+		})
+		.collect()
+}
+
+fn write_u64<T: PacketBuffer>(buffer: &mut T, val: u64) -> Result<()> {
+	buffer.write_u32((val >> 32) as u32)?;
+	buffer.write_u32(val as u32)
+}
+
+fn read_u64<T: PacketBuffer>(buffer: &mut T) -> Result<u64> {
+	Ok(((buffer.read_u32()? as u64) << 32) | buffer.read_u32()? as u64)
+}
+
+/// How long a negative response may be cached: the lower of the TTL and the MINIMUM field of
+/// the SOA in the authority section (RFC 2308 section 5). None without an SOA.
+fn negative_ttl(authorities: &[DNSRecord]) -> Option<u32> {
+	authorities.iter().find_map(|record| match *record {
+		DNSRecord::SOA { minimum, .. } => Some(record.get_ttl().min(minimum)),
+		_ => None,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::Ipv4Addr;
+
+	use super::*;
+	use crate::server::protocol::TransientTTL;
+
+	const ZONE: &str = "example.com";
+
+	fn answer(name: &str, ttl: u32) -> DNSPacket {
+		let mut packet = DNSPacket::new();
+		packet.header.response = true;
+		packet.answers.push(DNSRecord::A { domain: name.to_string(), addr: Ipv4Addr::new(192, 0, 2, 1), ttl: TransientTTL(ttl) });
+		packet
+	}
+
+	fn nxdomain(soa_ttl: u32, minimum: u32) -> DNSPacket {
+		let mut packet = DNSPacket::new();
+		packet.header.response = true;
+		packet.header.rescode = ResultCode::NXDOMAIN;
+		packet.authorities.push(DNSRecord::SOA {
+			domain: ZONE.to_string(),
+			m_name: format!("ns.{}", ZONE),
+			r_name: format!("hostmaster.{}", ZONE),
+			serial: 1,
+			refresh: 3600,
+			retry: 600,
+			expire: 86400,
+			minimum,
+			ttl: TransientTTL(soa_ttl),
+		});
+		packet
+	}
+
+	/// Move the entry of a question `by` into the past, as if it had been stored that long ago.
+	fn age(cache: &Cache, qname: &str, q_type: QueryType, by: Duration) {
+		let mut entries = cache.entries.write().unwrap();
+		let entry = entries.map.get_mut(&CacheKey::new(qname, q_type)).unwrap();
+		entry.stored -= by;
+		entry.expires -= by;
+	}
+
+	fn name(i: usize) -> String {
+		format!("host{:02}.{}", i, ZONE)
+	}
+
+	#[test]
+	fn ttls_count_down_from_when_the_entry_was_stored() {
+		let cache = Cache::new();
+		cache.store("www.example.com", QueryType::A, &answer("www.example.com", 300));
+		age(&cache, "www.example.com", QueryType::A, Duration::from_secs(100));
+
+		let response = cache.lookup("WWW.example.com.", QueryType::A).unwrap();
+		assert_eq!(response.answers[0].get_ttl(), 200);
+		assert_eq!(cache.dump()[0].ttl, 200);
+	}
+
+	#[test]
+	fn expired_entries_are_not_served_and_dropped() {
+		let cache = Cache::new();
+		cache.store("www.example.com", QueryType::A, &answer("www.example.com", 300));
+		age(&cache, "www.example.com", QueryType::A, Duration::from_secs(301));
+
+		assert!(cache.lookup("www.example.com", QueryType::A).is_none());
+		assert!(cache.is_empty());
+		assert_eq!(cache.stats().misses, 1);
+	}
+
+	#[test]
+	fn expired_entries_are_served_stale_for_a_while() {
+		let mut cache = Cache::new();
+		cache.set_max_stale(Some(Duration::from_secs(60)));
+		cache.store("www.example.com", QueryType::A, &answer("www.example.com", 300));
+		age(&cache, "www.example.com", QueryType::A, Duration::from_secs(330));
+
+		assert!(cache.lookup("www.example.com", QueryType::A).is_none());
+		let stale = cache.lookup_stale("www.example.com", QueryType::A).unwrap();
+		assert_eq!(stale.answers[0].get_ttl(), STALE_TTL);
+
+		age(&cache, "www.example.com", QueryType::A, Duration::from_secs(60));
+		assert!(cache.lookup_stale("www.example.com", QueryType::A).is_none());
+	}
+
+	#[test]
+	fn negative_ttl_is_the_soa_minimum() {
+		let mut cache = Cache::new();
+		cache.store("missing.example.com", QueryType::A, &nxdomain(3600, 60));
+		let response = cache.lookup("missing.example.com", QueryType::A).unwrap();
+		assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+		assert_eq!(response.authorities[0].get_ttl(), 60);
+		assert_eq!(cache.dump()[0].ttl, 60);
+
+		// The SOA TTL bounds it too, and so does the limit on negative TTLs...
+		cache.store("gone.example.com", QueryType::A, &nxdomain(30, 60));
+		assert_eq!(cache.dump_name("gone.example.com")[0].ttl, 30);
+		cache.set_ttl_limits(TtlLimits { max_negative: 10, ..TtlLimits::default() });
+		cache.store("missing.example.com", QueryType::A, &nxdomain(3600, 60));
+		assert_eq!(cache.dump_name("missing.example.com")[0].ttl, 10);
+
+		// Negative responses without an SOA aren't cached at all...
+		let mut packet = nxdomain(3600, 60);
+		packet.authorities.clear();
+		cache.store("nosoa.example.com", QueryType::A, &packet);
+		assert!(cache.lookup("nosoa.example.com", QueryType::A).is_none());
+	}
+
+	#[test]
+	fn least_recently_used_entries_are_evicted_at_the_entry_limit() {
+		let mut cache = Cache::new();
+		cache.set_hot_entries(0);
+		cache.set_limits(16, None);
+		for i in 0..16 {
+			cache.store(&name(i), QueryType::A, &answer(&name(i), 300));
+		}
+		assert!(cache.lookup(&name(0), QueryType::A).is_some());
+
+		// Going over evicts down to 15/16 of the limit, the oldest first...
+		cache.store(&name(16), QueryType::A, &answer(&name(16), 300));
+		assert_eq!(cache.len(), 15);
+		assert_eq!(cache.stats().evictions, 2);
+		for (i, kept) in [(0, true), (1, false), (2, false), (3, true), (16, true)] {
+			assert_eq!(cache.dump_name(&name(i)).len() == 1, kept, "{}", name(i));
+		}
+	}
+
+	#[test]
+	fn least_recently_used_entries_are_evicted_at_the_byte_limit() {
+		let mut cache = Cache::new();
+		cache.set_hot_entries(0);
+		cache.store(&name(0), QueryType::A, &answer(&name(0), 300));
+		let entry_size = cache.stats().bytes;
+		cache.set_limits(DEFAULT_MAX_ENTRIES, Some(entry_size * 4));
+		for i in 1..4 {
+			cache.store(&name(i), QueryType::A, &answer(&name(i), 300));
+		}
+		assert!(cache.lookup(&name(0), QueryType::A).is_some());
+
+		cache.store(&name(4), QueryType::A, &answer(&name(4), 300));
+		assert_eq!(cache.len(), 3);
+		assert!(cache.stats().bytes <= entry_size * 4);
+		for (i, kept) in [(0, true), (1, false), (2, false), (3, true), (4, true)] {
+			assert_eq!(cache.dump_name(&name(i)).len() == 1, kept, "{}", name(i));
+		}
+	}
+
+	#[test]
+	fn prefetch_is_due_once_until_it_finishes() {
+		let mut cache = Cache::new();
+		cache.set_hot_entries(0);
+		cache.set_prefetch(Some(Prefetch { percent: 10, min_hits: 1 }));
+		cache.store("www.example.com", QueryType::A, &answer("www.example.com", 100));
+		assert!(cache.lookup("www.example.com", QueryType::A).is_some());
+		assert!(!cache.needs_prefetch("www.example.com", QueryType::A));
+
+		age(&cache, "www.example.com", QueryType::A, Duration::from_secs(95));
+		assert!(cache.needs_prefetch("www.example.com", QueryType::A));
+		assert!(!cache.needs_prefetch("www.example.com", QueryType::A));
+		cache.prefetch_finished("www.example.com", QueryType::A);
+		assert!(cache.needs_prefetch("www.example.com", QueryType::A));
+	}
+
+	#[test]
+	fn snapshot_round_trip() {
+		let cache = Cache::new();
+		cache.store("www.example.com", QueryType::A, &answer("www.example.com", 300));
+		cache.store("missing.example.com", QueryType::AAAA, &nxdomain(3600, 60));
+		cache.store("old.example.com", QueryType::A, &answer("old.example.com", 300));
+		age(&cache, "old.example.com", QueryType::A, Duration::from_secs(400));
+
+		let path = std::env::temp_dir().join(format!("rdns-cache-test-{}.snapshot", std::process::id()));
+		assert_eq!(cache.save(&path).unwrap(), 2);
+		let loaded = Cache::new();
+		let added = loaded.load(&path);
+		let _ = fs::remove_file(&path);
+		assert_eq!(added.unwrap(), 2);
+
+		// Times go through the wall clock in whole seconds...
+		let (before, after) = (cache.dump(), loaded.dump());
+		assert_eq!(after.len(), 2);
+		for (before, after) in before.iter().filter(|entry| !entry.stale).zip(&after) {
+			assert_eq!((&before.key, before.rescode), (&after.key, after.rescode));
+			assert!(before.ttl.abs_diff(after.ttl) <= 1);
+			for (before, after) in before.answers.iter().chain(&before.authorities).zip(after.answers.iter().chain(&after.authorities)) {
+				assert_eq!((before.get_domain(), before.rdata_string()), (after.get_domain(), after.rdata_string()));
+			}
+		}
+	}
+}
@@ -0,0 +1,361 @@
+//! Capture of selected queries and their responses to a pcapng file
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{ BufWriter, Error, ErrorKind, Result, Write };
+use std::net::{ IpAddr, SocketAddr };
+use std::path::{ Path, PathBuf };
+use std::sync::Mutex;
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use crate::server::protocol::{ DNSPacket, QueryType };
+use crate::server::resolve::is_subdomain;
+use crate::server::upstream::Transport;
+
+/// Block types of pcapng...
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+/// Packets start with their IPv4 or IPv6 header...
+const LINKTYPE_RAW: u16 = 101;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+/// Messages over streams are cut into segments of the MSS of Ethernet, as they would be on
+/// the wire...
+const MAX_SEGMENT: usize = 1460;
+/// Flags of the TCP segments written, PSH and ACK...
+const TCP_PSH_ACK: u8 = 0x18;
+
+/// How long a capture runs unless told otherwise...
+pub const DEFAULT_CAPTURE_DURATION: Duration = Duration::from_secs(60);
+
+/// Which queries get captured. Written like a (much reduced) BPF expression, primitives
+/// joined by `and`:
+///
+/// `name example.com and client 192.0.2.0/24 and type AAAA`
+///
+/// `name` matches the name and everything below it, `client` an address or a prefix. An
+/// empty expression matches every query.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CaptureFilter {
+	pub suffix: Option<String>,
+	pub client: Option<(IpAddr, u8)>,
+	pub q_type: Option<QueryType>,
+}
+
+impl CaptureFilter {
+	pub fn parse(expr: &str) -> Result<CaptureFilter> {
+		let mut filter = CaptureFilter::default();
+		let words: Vec<&str> = expr.split_whitespace().collect();
+		let mut terms = words.split(|word| word.eq_ignore_ascii_case("and"));
+		if words.is_empty() {
+			terms.next();
+		}
+
+		for term in terms {
+			let (primitive, value) = match *term {
+				[primitive, value] => (primitive.to_lowercase(), value),
+				_ => return Err(invalid_filter(expr)),
+			};
+			let duplicate = match primitive.as_str() {
+				"name" => filter.suffix.replace(value.trim_end_matches('.').to_lowercase()).is_some(),
+				"client" => filter.client.replace(parse_prefix(value).ok_or_else(|| invalid_filter(expr))?).is_some(),
+				"type" => filter.q_type.replace(QueryType::from_name(value).ok_or_else(|| invalid_filter(expr))?).is_some(),
+				_ => return Err(invalid_filter(expr)),
+			};
+			if duplicate {
+				return Err(Error::new(ErrorKind::InvalidInput, format!("{} given twice in capture filter: {}", primitive, expr)));
+			}
+		}
+		Ok(filter)
+	}
+
+	/// Whether the query `request` from `source` is to be captured.
+	pub fn matches(&self, source: IpAddr, request: &DNSPacket) -> bool {
+		if let Some((network, len)) = self.client {
+			if !in_prefix(source, network, len) {
+				return false;
+			}
+		}
+		if self.suffix.is_none() && self.q_type.is_none() {
+			return true;
+		}
+		request.questions.iter().any(|question| {
+			self.q_type.is_none_or(|q_type| q_type == question.q_type)
+				&& self.suffix.as_ref().is_none_or(|suffix| {
+					is_subdomain(question.name.trim_end_matches('.').to_lowercase().as_str(), suffix)
+				})
+		})
+	}
+}
+
+fn invalid_filter(expr: &str) -> Error {
+	Error::new(ErrorKind::InvalidInput, format!("Invalid capture filter: {}", expr))
+}
+
+/// `ADDR` or `ADDR/LEN`.
+pub(crate) fn parse_prefix(value: &str) -> Option<(IpAddr, u8)> {
+	let (addr, len) = match value.split_once('/') {
+		Some((addr, len)) => (addr.parse::<IpAddr>().ok()?, len.parse::<u8>().ok()?),
+		None => {
+			let addr = value.parse::<IpAddr>().ok()?;
+			(addr, if addr.is_ipv4() { 32 } else { 128 })
+		}
+	};
+	let max = if addr.is_ipv4() { 32 } else { 128 };
+	if len > max {
+		return None;
+	}
+	Some((addr, len))
+}
+
+/// Whether `addr` is in the network `network/len`. Clients of dual-stack sockets show up with
+/// mapped addresses, which are taken as the IPv4 addresses they map, as are mapped networks.
+pub(crate) fn in_prefix(addr: IpAddr, network: IpAddr, len: u8) -> bool {
+	let (network, len) = match network {
+		IpAddr::V6(mapped) if len >= 96 => match mapped.to_ipv4_mapped() {
+			Some(network) => (IpAddr::V4(network), len - 96),
+			None => (network, len),
+		},
+		_ => (network, len),
+	};
+	let (addr, network, bits) = match (addr.to_canonical(), network) {
+		(IpAddr::V4(addr), IpAddr::V4(network)) => (u32::from(addr) as u128, u32::from(network) as u128, 32),
+		(IpAddr::V6(addr), IpAddr::V6(network)) => (u128::from(addr), u128::from(network), 128),
+		_ => return false,
+	};
+	let mask = u128::MAX.checked_shl(bits - len as u32).unwrap_or(0) & (u128::MAX >> (128 - bits));
+	addr & mask == network & mask
+}
+// --------------------------------------------------------------------------------------------
+
+/// A capture in progress: the queries matching its filter and their responses are written
+/// to a pcapng file until the capture runs out of time or is stopped.
+///
+/// Packets are written over raw IP between the client and the listen address, which
+/// Wireshark and tcpdump decode as DNS: queries over UDP as UDP datagrams, and those over
+/// TCP, TLS, HTTPS and QUIC as the plain text of DNS over TCP, one stream per client.
+#[derive(Debug)]
+pub struct Capture {
+	path: PathBuf,
+	filter: CaptureFilter,
+	local: SocketAddr,
+	until: Instant,
+	file: Mutex<BufWriter<File>>,
+	packets: Mutex<u64>,
+	/// Next sequence numbers of the client and of the server, per client over a stream...
+	streams: Mutex<HashMap<SocketAddr, (u32, u32)>>,
+}
+
+impl Capture {
+	/// Create the file at `path` and capture for `duration` the traffic of the listener
+	/// at `local`.
+	pub fn start(path: &Path, filter: CaptureFilter, local: SocketAddr, duration: Duration) -> Result<Capture> {
+		let mut file = BufWriter::new(File::create(path)?);
+
+		// The section header, with the byte order magic and version 1.0, then one interface...
+		let mut body = Vec::new();
+		body.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+		body.extend_from_slice(&1u16.to_le_bytes());
+		body.extend_from_slice(&0u16.to_le_bytes());
+		body.extend_from_slice(&u64::MAX.to_le_bytes());
+		write_block(&mut file, SECTION_HEADER_BLOCK, &body)?;
+
+		let mut body = Vec::new();
+		body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+		body.extend_from_slice(&0u16.to_le_bytes());
+		body.extend_from_slice(&0u32.to_le_bytes());
+		write_block(&mut file, INTERFACE_DESCRIPTION_BLOCK, &body)?;
+		file.flush()?;
+
+		Ok(Capture {
+			path: path.to_path_buf(),
+			filter,
+			local,
+			until: Instant::now() + duration,
+			file: Mutex::new(file),
+			packets: Mutex::new(0),
+			streams: Mutex::new(HashMap::new()),
+		})
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	pub fn filter(&self) -> &CaptureFilter {
+		&self.filter
+	}
+
+	/// Whether the capture ran out of time.
+	pub fn is_finished(&self) -> bool {
+		Instant::now() >= self.until
+	}
+
+	/// Number of packets written so far.
+	pub fn packets(&self) -> u64 {
+		self.packets.lock().map(|packets| *packets).unwrap_or(0)
+	}
+
+	/// Write `request` from `client` over `transport` and `response` back to it, if the
+	/// query matches the filter.
+	pub fn record(&self, client: SocketAddr, transport: Transport, request: &DNSPacket, raw_request: &[u8], response: &[u8]) -> Result<()> {
+		if self.is_finished() || !self.filter.matches(client.ip(), request) {
+			return Ok(());
+		}
+
+		let packets = match transport {
+			Transport::Udp => vec![udp_datagram(client, self.local, raw_request), udp_datagram(self.local, client, response)],
+			_ => {
+				let request = framed(raw_request);
+				let response = framed(response);
+				let mut streams = self.streams.lock().map_err(|_| Error::other("Capture streams lock poisoned"))?;
+				let (client_seq, server_seq) = streams.entry(client).or_insert((1, 1));
+				let mut packets = tcp_segments(client, self.local, *client_seq, *server_seq, &request);
+				*client_seq = client_seq.wrapping_add(request.len() as u32);
+				packets.extend(tcp_segments(self.local, client, *server_seq, *client_seq, &response));
+				*server_seq = server_seq.wrapping_add(response.len() as u32);
+				packets
+			}
+		};
+		let mut file = self.file.lock().map_err(|_| Error::other("Capture file lock poisoned"))?;
+		for packet in &packets {
+			write_packet(&mut *file, packet)?;
+		}
+		file.flush()?;
+		if let Ok(mut count) = self.packets.lock() {
+			*count += packets.len() as u64;
+		}
+		Ok(())
+	}
+}
+
+/// Write a block: type, length, the body padded to 32 bits, then the length again.
+fn write_block<W: Write>(out: &mut W, block_type: u32, body: &[u8]) -> Result<()> {
+	let padding = (4 - body.len() % 4) % 4;
+	let len = (12 + body.len() + padding) as u32;
+	out.write_all(&block_type.to_le_bytes())?;
+	out.write_all(&len.to_le_bytes())?;
+	out.write_all(body)?;
+	out.write_all(&[0; 3][..padding])?;
+	out.write_all(&len.to_le_bytes())
+}
+
+/// An enhanced packet block holding `packet`, timestamped in microseconds.
+fn write_packet<W: Write>(out: &mut W, packet: &[u8]) -> Result<()> {
+	let micros = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_micros() as u64).unwrap_or(0);
+	let mut body = Vec::with_capacity(20 + packet.len());
+	body.extend_from_slice(&0u32.to_le_bytes());
+	body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+	body.extend_from_slice(&(micros as u32).to_le_bytes());
+	body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+	body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+	body.extend_from_slice(packet);
+	write_block(out, ENHANCED_PACKET_BLOCK, &body)
+}
+
+/// `message` with its two byte length prefix, as over TCP. Messages are never longer than
+/// 65535 bytes, what the prefix holds...
+fn framed(message: &[u8]) -> Vec<u8> {
+	let mut data = Vec::with_capacity(message.len() + 2);
+	data.extend_from_slice(&(message.len().min(u16::MAX as usize) as u16).to_be_bytes());
+	data.extend_from_slice(message);
+	data
+}
+
+/// Wrap `payload` in UDP and IP headers.
+fn udp_datagram(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+	let mut udp = Vec::with_capacity(8 + payload.len());
+	udp.extend_from_slice(&src.port().to_be_bytes());
+	udp.extend_from_slice(&dst.port().to_be_bytes());
+	udp.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+	udp.extend_from_slice(&[0, 0]);
+	udp.extend_from_slice(payload);
+	ip_packet(src.ip(), dst.ip(), IPPROTO_UDP, udp)
+}
+
+/// Cut `data` into TCP segments from `src` to `dst`, the first at sequence number `seq`,
+/// each acknowledging `ack`, and wrap them in IP headers.
+fn tcp_segments(src: SocketAddr, dst: SocketAddr, seq: u32, ack: u32, data: &[u8]) -> Vec<Vec<u8>> {
+	data.chunks(MAX_SEGMENT).enumerate().map(|(index, chunk)| {
+		let mut tcp = Vec::with_capacity(20 + chunk.len());
+		tcp.extend_from_slice(&src.port().to_be_bytes());
+		tcp.extend_from_slice(&dst.port().to_be_bytes());
+		tcp.extend_from_slice(&seq.wrapping_add((index * MAX_SEGMENT) as u32).to_be_bytes());
+		tcp.extend_from_slice(&ack.to_be_bytes());
+		tcp.extend_from_slice(&[5 << 4, TCP_PSH_ACK, 0xFF, 0xFF, 0, 0, 0, 0]);
+		tcp.extend_from_slice(chunk);
+		ip_packet(src.ip(), dst.ip(), IPPROTO_TCP, tcp)
+	}).collect()
+}
+
+/// Fill in the checksum of `segment`, a UDP datagram or TCP segment, and wrap it in an IP
+/// header. Mapped IPv4 addresses are written as IPv4, unless the other end is IPv6...
+fn ip_packet(src: IpAddr, dst: IpAddr, protocol: u8, mut segment: Vec<u8>) -> Vec<u8> {
+	let (src_ip, dst_ip) = match (src.to_canonical(), dst.to_canonical()) {
+		(IpAddr::V4(src), IpAddr::V6(dst)) => (IpAddr::V6(src.to_ipv6_mapped()), IpAddr::V6(dst)),
+		(IpAddr::V6(src), IpAddr::V4(dst)) => (IpAddr::V6(src), IpAddr::V6(dst.to_ipv6_mapped())),
+		addrs => addrs,
+	};
+	let len = segment.len();
+
+	// The checksum covers a pseudo header of the addresses, protocol and length...
+	let mut pseudo = Vec::new();
+	match (src_ip, dst_ip) {
+		(IpAddr::V4(src), IpAddr::V4(dst)) => {
+			pseudo.extend_from_slice(&src.octets());
+			pseudo.extend_from_slice(&dst.octets());
+			pseudo.extend_from_slice(&[0, protocol]);
+			pseudo.extend_from_slice(&(len as u16).to_be_bytes());
+		}
+		(IpAddr::V6(src), IpAddr::V6(dst)) => {
+			pseudo.extend_from_slice(&src.octets());
+			pseudo.extend_from_slice(&dst.octets());
+			pseudo.extend_from_slice(&(len as u32).to_be_bytes());
+			pseudo.extend_from_slice(&[0, 0, 0, protocol]);
+		}
+		_ => unreachable!("both addresses are of the same family"),
+	}
+	pseudo.extend_from_slice(&segment);
+	// A UDP checksum of 0 means none was computed, so it's sent as its complement...
+	let (offset, sum) = match (protocol, checksum(&pseudo)) {
+		(IPPROTO_UDP, 0) => (6, 0xFFFF),
+		(IPPROTO_UDP, sum) => (6, sum),
+		(_, sum) => (16, sum),
+	};
+	segment[offset..offset + 2].copy_from_slice(&sum.to_be_bytes());
+
+	let mut packet = Vec::with_capacity(40 + len);
+	match (src_ip, dst_ip) {
+		(IpAddr::V4(src), IpAddr::V4(dst)) => {
+			packet.extend_from_slice(&[0x45, 0]);
+			packet.extend_from_slice(&((20 + len) as u16).to_be_bytes());
+			packet.extend_from_slice(&[0, 0, 0x40, 0, 64, protocol, 0, 0]);
+			packet.extend_from_slice(&src.octets());
+			packet.extend_from_slice(&dst.octets());
+			let header_checksum = checksum(&packet);
+			packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+		}
+		(IpAddr::V6(src), IpAddr::V6(dst)) => {
+			packet.extend_from_slice(&[0x60, 0, 0, 0]);
+			packet.extend_from_slice(&(len as u16).to_be_bytes());
+			packet.extend_from_slice(&[protocol, 64]);
+			packet.extend_from_slice(&src.octets());
+			packet.extend_from_slice(&dst.octets());
+		}
+		_ => unreachable!("both addresses are of the same family"),
+	}
+	packet.extend_from_slice(&segment);
+	packet
+}
+
+/// The internet checksum of RFC 1071.
+fn checksum(data: &[u8]) -> u16 {
+	let mut sum: u32 = data.chunks(2)
+		.map(|chunk| u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u32)
+		.sum();
+	while sum > 0xFFFF {
+		sum = (sum & 0xFFFF) + (sum >> 16);
+	}
+	!(sum as u16)
+}
@@ -0,0 +1,309 @@
+use std::io::{ Error, ErrorKind, Read, Result, Write };
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket };
+use std::time::Duration;
+
+use rand::Rng;
+use tracing::debug_span;
+
+use crate::server::buffer::VectorPacketBuffer;
+use crate::server::protocol::{ DNSPacket, DNSQuestion, DNSRecord, QueryType };
+use crate::server::retry::RetryPolicy;
+
+/// Default time to wait for the response to a try...
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Largest UDP response to a query without EDNS (RFC 1035 section 4.2.1)...
+const MAX_UDP_MESSAGE: usize = 512;
+
+/// Lowest source port picked for a query, those below being the reserved ones...
+const MIN_SOURCE_PORT: u16 = 1024;
+/// Random ports tried for a query before leaving the pick to the system...
+const SOURCE_PORT_TRIES: usize = 8;
+
+/// A simple synchronous client, sending a query to a DNS server over UDP or TCP and waiting
+/// for the matching response.
+///
+/// Every query gets an ID drawn from a CSPRNG, and every one sent over UDP a socket of its
+/// own bound to a random port, so a spoofed response has to guess both (RFC 5452).
+#[derive(Debug)]
+pub struct DNSClient {
+	/// Tries of the exchanges, and the time they get...
+	retry: RetryPolicy,
+	/// UDP payload size advertised in the queries built, None leaves EDNS out...
+	edns_payload: Option<u16>,
+	/// Domains the lookups search names with fewer than `ndots` dots under...
+	search: Vec<String>,
+	ndots: usize,
+}
+
+impl DNSClient {
+	pub fn new() -> Self {
+		Self {
+			retry: RetryPolicy::default(),
+			edns_payload: None,
+			search: Vec::new(),
+			ndots: 1,
+		}
+	}
+
+	/// A client waiting up to `timeout` for the response to each try.
+	pub fn with_timeout(timeout: Duration) -> Self {
+		DNSClient::with_retry(RetryPolicy::new().with_timeout(timeout))
+	}
+
+	pub fn with_retry(retry: RetryPolicy) -> Self {
+		let mut client = DNSClient::new();
+		client.retry = retry;
+		client
+	}
+
+	/// Time to wait for the response to a try.
+	pub fn timeout(&self) -> Duration {
+		self.retry.timeout
+	}
+
+	/// Make the exchanges from now on with the tries of `retry`: those of the client itself,
+	/// and those it's handed to for the upstreams over TLS, HTTPS and QUIC.
+	pub fn set_retry(&mut self, retry: RetryPolicy) {
+		self.retry = retry;
+	}
+
+	pub fn retry(&self) -> RetryPolicy {
+		self.retry
+	}
+
+	/// Advertise a UDP payload size of `payload` bytes in the queries built from now on, or
+	/// leave EDNS out with None.
+	pub fn set_edns_payload(&mut self, payload: Option<u16>) {
+		self.edns_payload = payload;
+	}
+
+	pub fn edns_payload(&self) -> Option<u16> {
+		self.edns_payload
+	}
+
+	/// Search the names looked up under `domains` from now on, as the resolver of the C
+	/// library does (see `search_names`). Queries sent as such aren't affected.
+	pub fn set_search(&mut self, domains: Vec<String>, ndots: usize) {
+		self.search = domains.iter().map(|domain| domain.trim_end_matches('.').to_lowercase()).collect();
+		self.ndots = ndots;
+	}
+
+	pub fn search(&self) -> &[String] {
+		&self.search
+	}
+
+	pub fn ndots(&self) -> usize {
+		self.ndots
+	}
+
+	/// Build a query packet for `qname` and `q_type` with a random ID.
+	pub fn build_query(&self, qname: &str, q_type: QueryType, recursive: bool) -> DNSPacket {
+		let mut packet = DNSPacket::new();
+		packet.header.id = random_id();
+		packet.header.recursion_desired = recursive;
+		packet.questions.push(DNSQuestion::new(qname.to_string(), q_type));
+		if let Some(payload) = self.edns_payload {
+			packet.additional.push(DNSRecord::OPT { packet_len: payload, flags: 0, data: Vec::new() });
+		}
+		packet
+	}
+
+	/// Send a query for `qname` to `server` and return the response.
+	pub fn send_query(&self, qname: &str, q_type: QueryType, server: SocketAddr, recursive: bool) -> Result<DNSPacket> {
+		let mut query = self.build_query(qname, q_type, recursive);
+		self.exchange(&mut query, server)
+	}
+
+	/// Send an already built query to `server` over UDP and wait for the response carrying
+	/// the same ID and question. Stray packets are ignored until the timeout expires, and the
+	/// query is sent again as the retry policy allows. A truncated response gets the query
+	/// sent again over TCP, for the full answer.
+	pub fn exchange(&self, query: &mut DNSPacket, server: SocketAddr) -> Result<DNSPacket> {
+		let mut req_buffer = VectorPacketBuffer::new();
+		query.write(&mut req_buffer)?;
+		self.retry.run(|timeout| self.exchange_udp(req_buffer.as_slice(), query, server, timeout)).map(|(response, _)| response)
+	}
+
+	/// Send an already built query to `server` over TCP, each message being preceded by its
+	/// length as described in RFC 1035 section 4.2.2.
+	pub fn exchange_tcp(&self, query: &mut DNSPacket, server: SocketAddr) -> Result<DNSPacket> {
+		let mut req_buffer = VectorPacketBuffer::new();
+		query.write(&mut req_buffer)?;
+		self.retry.run(|timeout| self.exchange_stream(req_buffer.as_slice(), query, server, timeout)).map(|(response, _)| response)
+	}
+
+	/// Send the query `message` to `server` as it is, apart from the ID which is replaced by a
+	/// random one, and return the response as it was received, with that ID.
+	pub fn exchange_raw(&self, message: &[u8], server: SocketAddr, tcp: bool) -> Result<Vec<u8>> {
+		let (query, message) = self.renumber(message)?;
+		let (_, response) = self.retry.run(|timeout| if tcp {
+			self.exchange_stream(&message, &query, server, timeout)
+		} else {
+			self.exchange_udp(&message, &query, server, timeout)
+		})?;
+		Ok(response)
+	}
+
+	/// Give the query `message` a random ID, returning it both parsed and as it is to be sent.
+	pub fn renumber(&self, message: &[u8]) -> Result<(DNSPacket, Vec<u8>)> {
+		let mut query = DNSPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(message.to_vec()))?;
+		query.header.id = random_id();
+		let mut message = message.to_vec();
+		message[..2].copy_from_slice(&query.header.id.to_be_bytes());
+		Ok((query, message))
+	}
+
+	/// Send `message`, the wire format of `query`, over UDP, falling back to TCP if the
+	/// response is truncated, waiting up to `timeout` for each. Returns the response both
+	/// parsed and as received.
+	fn exchange_udp(&self, message: &[u8], query: &DNSPacket, server: SocketAddr, timeout: Duration) -> Result<(DNSPacket, Vec<u8>)> {
+		let (response, bytes) = self.exchange_datagram(message, query, server, timeout)?;
+		if response.header.truncated_message {
+			return self.exchange_stream(message, query, server, timeout);
+		}
+		Ok((response, bytes))
+	}
+
+	/// Send `message`, the wire format of `query`, in a datagram and wait for the response.
+	fn exchange_datagram(&self, message: &[u8], query: &DNSPacket, server: SocketAddr, timeout: Duration) -> Result<(DNSPacket, Vec<u8>)> {
+		let _span = debug_span!("send", server = %server, transport = "udp").entered();
+		let socket = bind_query_socket(server)?;
+		socket.set_read_timeout(Some(timeout))?;
+		socket.send_to(message, server)?;
+
+		let mut buf = vec![0; response_payload(query)];
+		loop {
+			let (len, src) = socket.recv_from(&mut buf).map_err(|e| match e.kind() {
+				ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+					Error::new(ErrorKind::TimedOut, format!("No response from {}", server))
+				}
+				_ => e,
+			})?;
+			if src != server {
+				continue;
+			}
+			if let Ok(Some(response)) = read_response(buf[..len].to_vec(), query) {
+				return Ok(response);
+			}
+		}
+	}
+
+	/// Send `message`, the wire format of `query`, over TCP. Returns the response both parsed
+	/// and as received.
+	fn exchange_stream(&self, message: &[u8], query: &DNSPacket, server: SocketAddr, timeout: Duration) -> Result<(DNSPacket, Vec<u8>)> {
+		let _span = debug_span!("send", server = %server, transport = "tcp").entered();
+		let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+		stream.set_read_timeout(Some(timeout))?;
+		stream.set_write_timeout(Some(timeout))?;
+		self.exchange_on(&mut stream, message, query)
+	}
+
+	/// Send `message`, the wire format of `query`, on a connected stream with its length
+	/// prefix and read messages until the response to it. Returns the response both parsed
+	/// and as received.
+	pub fn exchange_on<S: Read + Write>(&self, stream: &mut S, message: &[u8], query: &DNSPacket) -> Result<(DNSPacket, Vec<u8>)> {
+		write_tcp_message(stream, message)?;
+
+		loop {
+			if let Some(response) = read_response(read_tcp_message(stream)?, query)? {
+				return Ok(response);
+			}
+		}
+	}
+}
+
+impl Default for DNSClient {
+	fn default() -> Self {
+		DNSClient::new()
+	}
+}
+
+/// An ID for a query, from the CSPRNG of the thread (ChaCha seeded by the system), which an
+/// attacker can't predict from the IDs of earlier queries as with a counter...
+fn random_id() -> u16 {
+	rand::thread_rng().gen()
+}
+
+/// A UDP socket to send a query to `server` from, bound to a port picked at random among
+/// those above 1024. Ports in use are passed over, the system picking one after a few tries.
+pub(crate) fn bind_query_socket(server: SocketAddr) -> Result<UdpSocket> {
+	let ip = if server.is_ipv4() { IpAddr::V4(Ipv4Addr::UNSPECIFIED) } else { IpAddr::V6(Ipv6Addr::UNSPECIFIED) };
+	let mut rng = rand::thread_rng();
+	for _ in 0..SOURCE_PORT_TRIES {
+		match UdpSocket::bind(SocketAddr::new(ip, rng.gen_range(MIN_SOURCE_PORT..=u16::MAX))) {
+			Ok(socket) => return Ok(socket),
+			Err(e) if matches!(e.kind(), ErrorKind::AddrInUse | ErrorKind::PermissionDenied) => continue,
+			Err(e) => return Err(e),
+		}
+	}
+	UdpSocket::bind(SocketAddr::new(ip, 0))
+}
+
+/// Names in a response may come back in a different case (and are lowercased while reading),
+/// so questions are compared case insensitively...
+pub fn same_questions(a: &[DNSQuestion], b: &[DNSQuestion]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.q_type == y.q_type && x.name.eq_ignore_ascii_case(&y.name))
+}
+
+/// The largest UDP response to `query`, which may take up as much as the query advertised.
+pub(crate) fn response_payload(query: &DNSPacket) -> usize {
+	match query.edns() {
+		Some(DNSRecord::OPT { packet_len, .. }) => (*packet_len as usize).max(MAX_UDP_MESSAGE),
+		_ => MAX_UDP_MESSAGE,
+	}
+}
+
+/// The message `data` parsed, along with its bytes, if it's the response to `query`: None
+/// when it carries another ID or question.
+pub(crate) fn read_response(data: Vec<u8>, query: &DNSPacket) -> Result<Option<(DNSPacket, Vec<u8>)>> {
+	let len = data.len();
+	let mut res_buffer = VectorPacketBuffer::from_bytes(data);
+	let response = debug_span!("parse", len).in_scope(|| DNSPacket::from_buffer(&mut res_buffer))?;
+	if response.header.id == query.header.id && same_questions(&response.questions, &query.questions) {
+		Ok(Some((response, res_buffer.into_inner())))
+	} else {
+		Ok(None)
+	}
+}
+
+/// `message` with its two byte length prefix, as sent over TCP.
+pub(crate) fn tcp_frame(message: &[u8]) -> Result<Vec<u8>> {
+	if message.len() > u16::MAX as usize {
+		return Err(Error::new(ErrorKind::InvalidInput, "Message too long for TCP"));
+	}
+	let mut data = Vec::with_capacity(message.len() + 2);
+	data.extend_from_slice(&(message.len() as u16).to_be_bytes());
+	data.extend_from_slice(message);
+	Ok(data)
+}
+
+/// Write a message to a stream with its two byte length prefix.
+pub fn write_tcp_message<W: Write>(stream: &mut W, message: &[u8]) -> Result<()> {
+	stream.write_all(&tcp_frame(message)?)?;
+	stream.flush()
+}
+
+/// Read one length prefixed message from a stream.
+pub fn read_tcp_message<R: Read>(stream: &mut R) -> Result<Vec<u8>> {
+	let mut len = [0; 2];
+	stream.read_exact(&mut len)?;
+	let mut message = vec![0; u16::from_be_bytes(len) as usize];
+	stream.read_exact(&mut message)?;
+	Ok(message)
+}
+
+/// Take the first complete length prefixed message out of `received`, the bytes read from a
+/// stream so far, if there is one.
+pub(crate) fn next_tcp_message(received: &mut Vec<u8>) -> Option<Vec<u8>> {
+	if received.len() < 2 {
+		return None;
+	}
+	let len = u16::from_be_bytes([received[0], received[1]]) as usize;
+	if received.len() < len + 2 {
+		return None;
+	}
+	let message = received[2..len + 2].to_vec();
+	received.drain(..len + 2);
+	Some(message)
+}
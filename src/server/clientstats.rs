@@ -0,0 +1,111 @@
+//! Anonymous statistics of what the clients support
+
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use crate::server::protocol::{ DNSPacket, DNSRecord, EDNS_FLAG_DO };
+use crate::server::upstream::Transport;
+
+/// Upper bounds of the ranges the EDNS payload sizes the clients advertise are counted in...
+pub const EDNS_SIZE_BUCKETS: [u16; 6] = [512, 1232, 1400, 1500, 4096, u16::MAX];
+/// Transports the queries are counted by...
+pub const TRANSPORTS: [Transport; 5] = [Transport::Udp, Transport::Tcp, Transport::Tls, Transport::Https, Transport::Quic];
+/// Query types counted on their own, the others being counted together...
+const QUERY_TYPES: [(u16, &str); 14] = [
+	(1, "A"), (2, "NS"), (5, "CNAME"), (6, "SOA"), (12, "PTR"), (15, "MX"), (16, "TXT"), (28, "AAAA"),
+	(33, "SRV"), (43, "DS"), (48, "DNSKEY"), (64, "SVCB"), (65, "HTTPS"), (255, "ANY"),
+];
+
+/// What the queries of the clients tell of their capabilities, added up over all of them:
+/// the transports they use, the EDNS payload sizes they advertise, the DNSSEC related flags
+/// they set and the types they ask for. Nothing is kept per client. Meant for telling when
+/// defaults like the maximum UDP payload can be changed without leaving clients behind.
+#[derive(Debug, Default)]
+pub struct ClientStats {
+	queries: AtomicU64,
+	transports: [AtomicU64; 5],
+	no_edns: AtomicU64,
+	edns_sizes: [AtomicU64; 6],
+	dnssec_ok: AtomicU64,
+	authed_data: AtomicU64,
+	checking_disabled: AtomicU64,
+	query_types: [AtomicU64; 14],
+	other_types: AtomicU64,
+}
+
+/// The counters of `ClientStats` at one point.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientStatsSnapshot {
+	pub queries: u64,
+	pub transports: Vec<(Transport, u64)>,
+	/// Queries without EDNS...
+	pub no_edns: u64,
+	/// Queries advertising a payload size up to each of `EDNS_SIZE_BUCKETS` and above the
+	/// previous one...
+	pub edns_sizes: Vec<(u16, u64)>,
+	/// Queries with the DO, AD and CD flags...
+	pub dnssec_ok: u64,
+	pub authed_data: u64,
+	pub checking_disabled: u64,
+	/// Queries by type, those of the types not tracked on their own under `OTHER`...
+	pub query_types: Vec<(&'static str, u64)>,
+}
+
+impl ClientStats {
+	pub fn new() -> Self {
+		ClientStats::default()
+	}
+
+	/// Count `request`, received over `transport`.
+	pub fn record(&self, request: &DNSPacket, transport: Transport) {
+		self.queries.fetch_add(1, Ordering::Relaxed);
+		if let Some(i) = TRANSPORTS.iter().position(|known| *known == transport) {
+			self.transports[i].fetch_add(1, Ordering::Relaxed);
+		}
+
+		match request.edns() {
+			Some(DNSRecord::OPT { packet_len, flags, .. }) => {
+				let bucket = EDNS_SIZE_BUCKETS.iter().position(|bound| packet_len <= bound).unwrap_or(EDNS_SIZE_BUCKETS.len() - 1);
+				self.edns_sizes[bucket].fetch_add(1, Ordering::Relaxed);
+				if flags & EDNS_FLAG_DO != 0 {
+					self.dnssec_ok.fetch_add(1, Ordering::Relaxed);
+				}
+			}
+			_ => {
+				self.no_edns.fetch_add(1, Ordering::Relaxed);
+			}
+		}
+		if request.header.authed_data {
+			self.authed_data.fetch_add(1, Ordering::Relaxed);
+		}
+		if request.header.checking_disabled {
+			self.checking_disabled.fetch_add(1, Ordering::Relaxed);
+		}
+
+		if let Some(question) = request.questions.first() {
+			let num = question.q_type.to_num();
+			match QUERY_TYPES.iter().position(|(known, _)| *known == num) {
+				Some(i) => self.query_types[i].fetch_add(1, Ordering::Relaxed),
+				None => self.other_types.fetch_add(1, Ordering::Relaxed),
+			};
+		}
+	}
+
+	pub fn snapshot(&self) -> ClientStatsSnapshot {
+		let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+		let mut query_types: Vec<_> = QUERY_TYPES.iter().zip(&self.query_types)
+			.map(|((_, name), count)| (*name, get(count)))
+			.collect();
+		query_types.push(("OTHER", get(&self.other_types)));
+
+		ClientStatsSnapshot {
+			queries: get(&self.queries),
+			transports: TRANSPORTS.iter().zip(&self.transports).map(|(transport, count)| (*transport, get(count))).collect(),
+			no_edns: get(&self.no_edns),
+			edns_sizes: EDNS_SIZE_BUCKETS.iter().zip(&self.edns_sizes).map(|(bound, count)| (*bound, get(count))).collect(),
+			dnssec_ok: get(&self.dnssec_ok),
+			authed_data: get(&self.authed_data),
+			checking_disabled: get(&self.checking_disabled),
+			query_types,
+		}
+	}
+}
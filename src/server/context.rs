@@ -0,0 +1,667 @@
+use std::collections::HashSet;
+use std::net::{ IpAddr, SocketAddr };
+use std::io::{ Error, ErrorKind, Result };
+use std::path::{ Path, PathBuf };
+use std::process;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Arc, RwLock, Weak };
+use std::thread;
+use std::time::Duration;
+
+use crate::server::acl::AccessControl;
+use crate::server::amplification::AmplificationGuard;
+use crate::server::authority::{ Authority, SharedZone };
+use crate::server::blocklist::Blocklists;
+use crate::server::cache::Cache;
+use crate::server::capture::{ in_prefix, parse_prefix, Capture, CaptureFilter };
+use crate::server::client::DNSClient;
+use crate::server::clientstats::ClientStats;
+use crate::server::doctor::DnsDoctor;
+use crate::server::endpoint::EndpointChecks;
+use crate::server::hosts::{ HostOverrides, HOSTS_POLL_INTERVAL };
+use crate::server::fallback::LastKnownGood;
+use crate::server::family::FamilyFilter;
+use crate::server::geoip::GeoDatabase;
+use crate::server::hints::load_root_hints;
+use crate::server::latency::{ LatencyTracker, QueryTiming };
+use crate::server::mdns::MdnsResponder;
+use crate::server::middleware::{ EdnsHooks, QueryHooks };
+use crate::server::mirror::QueryMirror;
+use crate::server::prefetch::Prefetcher;
+use crate::server::protocol::{ DNSQuestion, ResultCode };
+use crate::server::querylog::QueryLog;
+use crate::server::resolve::{ DNSResolver, DelegationCache, ForwardingResolver, RecursiveResolver };
+use crate::server::rotation::AnswerRotation;
+use crate::server::rrl::ResponseRateLimit;
+use crate::server::sanity::ResponseLimits;
+use crate::server::servfail::ServfailStats;
+use crate::server::shutdown::Shutdown;
+use crate::server::udp::UdpStats;
+use crate::server::upstream::{ Transport, UpstreamPool, DEFAULT_HEALTH_CHECK_INTERVAL };
+use crate::server::view::Views;
+
+/// Default time between two snapshots of the cache...
+pub const DEFAULT_CACHE_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Connections the TCP listener serves at a time unless told otherwise...
+pub const DEFAULT_TCP_MAX_CONNECTIONS: usize = 100;
+/// Time an idle TCP connection is kept open unless told otherwise (RFC 7766 section 6.2.3)...
+pub const DEFAULT_TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest UDP payload used with EDNS unless told otherwise, which avoids IP fragmentation
+/// on about every path (DNS flag day 2020)...
+pub const DEFAULT_EDNS_MAX_PAYLOAD: u16 = 1232;
+/// Wait before sending the priming query again after it failed, doubling every time up to
+/// the longest wait...
+const PRIME_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_PRIME_RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+/// What a listener serves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServerRole {
+	/// The local zones, and everything else to recursive queries.
+	Full,
+	/// The local zones only, as an authoritative server.
+	Authoritative,
+	/// Recursive queries only, the local zones being answered from directly.
+	Recursive,
+}
+
+impl ServerRole {
+	pub fn from_name(name: &str) -> Option<ServerRole> {
+		match name {
+			"full" => Some(ServerRole::Full),
+			"authoritative" => Some(ServerRole::Authoritative),
+			"recursive" => Some(ServerRole::Recursive),
+			_ => None,
+		}
+	}
+}
+
+/// The listener a query came in on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Listener {
+	pub role: ServerRole,
+	pub transport: Transport,
+}
+
+/// The response to queries for names outside of the local zones which won't be resolved:
+/// without RD, or received by an authoritative listener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NonRecursivePolicy {
+	Refuse,
+	/// A referral to the closest zone cut known, which is the root unless the recursor
+	/// learned better...
+	Referral,
+}
+
+/// A name answered with a LAN address to the clients inside the network, typically the
+/// dynamic DNS name of the network's public address, which clients inside couldn't reach
+/// without the router hairpinning their connections.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InternalOverride {
+	pub name: String,
+	pub addrs: Vec<IpAddr>,
+}
+
+impl InternalOverride {
+	/// Parse an override given as `NAME=ADDR[,ADDR]...`.
+	pub fn parse(spec: &str) -> Option<InternalOverride> {
+		let (name, addrs) = spec.split_once('=')?;
+		let name = name.trim_end_matches('.').to_lowercase();
+		let addrs = addrs.split(',').map(|addr| addr.parse().ok()).collect::<Option<Vec<IpAddr>>>()?;
+		if name.is_empty() {
+			return None;
+		}
+		Some(InternalOverride { name, addrs })
+	}
+
+	/// Whether `source` is inside the network: a loopback, private, shared (RFC 6598),
+	/// link-local or unique local address.
+	pub fn is_internal(source: IpAddr) -> bool {
+		match source.to_canonical() {
+			IpAddr::V4(v4) => {
+				let octets = v4.octets();
+				v4.is_loopback() || v4.is_private() || v4.is_link_local() || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+			}
+			IpAddr::V6(v6) => v6.is_loopback() || v6.segments()[0] & 0xfe00 == 0xfc00 || v6.segments()[0] & 0xffc0 == 0xfe80,
+		}
+	}
+}
+
+/// How queries which the server can't answer itself get resolved.
+#[derive(Clone, Debug)]
+pub enum ResolveStrategy {
+	/// Iterate from the root servers.
+	Recursive,
+	/// Send everything on to a pool of upstream resolvers.
+	Forward { upstreams: Arc<UpstreamPool> },
+}
+
+/// State shared by the listeners and the worker threads of the server.
+pub struct ServerContext {
+	pub client: DNSClient,
+	pub listen_addr: SocketAddr,
+	pub worker_threads: usize,
+	/// Sockets every UDP listener binds to its address with SO_REUSEPORT...
+	pub udp_sockets: usize,
+	pub tcp_max_connections: usize,
+	pub tcp_idle_timeout: Duration,
+	/// Largest UDP payload advertised, taken from clients and asked of upstreams...
+	edns_max_payload: u16,
+	pub allow_recursive: bool,
+	/// Clients allowed to query, to recurse and to transfer zones...
+	pub access_control: AccessControl,
+	pub non_recursive: NonRecursivePolicy,
+	/// Names answered with LAN addresses to clients inside the network...
+	pub internal_overrides: Vec<InternalOverride>,
+	/// A or AAAA records withheld from the clients of single stack networks...
+	pub family_filter: FamilyFilter,
+	/// Clients answered from zones, hosts and upstreams of their own...
+	pub views: Views,
+	/// Where the clients are, for the geo records of the zones...
+	pub geoip: Option<GeoDatabase>,
+	/// Order of the addresses answered from the zones rotated...
+	pub rotation: AnswerRotation,
+	/// Health of the endpoints of the zones, those down being withheld from the answers...
+	pub endpoints: EndpointChecks,
+	/// Hosts of the .local domain and their services announced and answered over multicast DNS...
+	pub mdns: MdnsResponder,
+	/// Names answered with the addresses of a hosts file or the configuration...
+	pub hosts: HostOverrides,
+	/// Names answered without being resolved...
+	pub blocklists: Blocklists,
+	/// Clients whose responses are written without name compression...
+	pub uncompressed_clients: Vec<(IpAddr, u8)>,
+	/// Addresses of the hosts behind the NAT rewritten by the side of the clients...
+	pub doctor: DnsDoctor,
+	/// Swapped on reload, queries keep the strategy they started with...
+	resolve_strategy: RwLock<ResolveStrategy>,
+	pub delegations: DelegationCache,
+	/// Responses of the resolver, consulted before resolving a query...
+	pub cache: Cache,
+	/// Snapshot of the cache loaded on start and written every `cache_snapshot_interval`...
+	pub cache_file: Option<PathBuf>,
+	pub cache_snapshot_interval: Duration,
+	/// Threads refreshing the entries of the cache due to be prefetched...
+	pub prefetcher: Prefetcher,
+	/// Answers for critical names, kept for when they can't be resolved at all...
+	pub last_known_good: LastKnownGood,
+	/// Send only the labels needed at each step of recursion (RFC 7816)...
+	pub qname_minimization: bool,
+	/// Relay queries to the upstreams and their responses back byte for byte, instead of
+	/// resolving them from the cache...
+	pub pass_through: bool,
+	/// Leave the authority and additional sections out of positive answers...
+	pub minimal_responses: bool,
+	/// Truncate UDP responses to subnets receiving too many bytes for what they sent...
+	pub amplification: Option<AmplificationGuard>,
+	/// Limit on the identical authoritative answers sent to a network over UDP...
+	pub rrl: Option<ResponseRateLimit>,
+	/// Caps on the responses of upstreams and name servers...
+	pub response_limits: ResponseLimits,
+	/// Middleware editing the EDNS options of the queries sent on and of the responses...
+	pub edns_hooks: EdnsHooks,
+	/// Middleware vetting the queries of the clients before they're answered...
+	pub query_hooks: QueryHooks,
+	/// What the queries of the clients tell of their capabilities...
+	pub client_stats: ClientStats,
+	/// Slow-query log and latency SLO...
+	pub latency: LatencyTracker,
+	/// Counters of the sockets of the UDP listeners...
+	pub udp_stats: UdpStats,
+	/// Resolver a sample of the queries is sent to as well...
+	pub mirror: Option<QueryMirror>,
+	/// JSON log of the queries answered...
+	pub query_log: Option<QueryLog>,
+	/// Abort on the first query whose handling panics instead of answering it with SERVFAIL,
+	/// for development...
+	pub crash_on_panic: bool,
+	/// Queries whose handling panicked...
+	panics: AtomicU64,
+	/// SERVFAILs answered, by reason...
+	pub servfails: ServfailStats,
+	/// Whether the server is stopping, and the queries it's still answering...
+	pub shutdown: Shutdown,
+	/// Capture of selected queries in progress, started and stopped by the administrator...
+	capture: RwLock<Option<Arc<Capture>>>,
+	/// Zones answered from local data...
+	pub authority: Arc<Authority>,
+	/// Root hints file to use instead of the compiled in hints...
+	pub root_hints_file: Option<PathBuf>,
+	/// Time between the health probes of the upstreams and of the endpoints of the zones, None
+	/// disables them...
+	pub health_check_interval: Option<Duration>,
+	/// Time between the checks of the delegations between the local zones, None disables
+	/// them, and whether to publish the delegations found drifting...
+	pub delegation_check_interval: Option<Duration>,
+	pub publish_delegations: bool,
+}
+
+impl ServerContext {
+	pub fn new() -> Self {
+		let mut client = DNSClient::new();
+		client.set_edns_payload(Some(DEFAULT_EDNS_MAX_PAYLOAD));
+
+		Self {
+			client,
+			listen_addr: SocketAddr::from(([0, 0, 0, 0], 53)),
+			worker_threads: 4,
+			udp_sockets: 1,
+			tcp_max_connections: DEFAULT_TCP_MAX_CONNECTIONS,
+			tcp_idle_timeout: DEFAULT_TCP_IDLE_TIMEOUT,
+			edns_max_payload: DEFAULT_EDNS_MAX_PAYLOAD,
+			allow_recursive: true,
+			access_control: AccessControl::new(),
+			non_recursive: NonRecursivePolicy::Refuse,
+			internal_overrides: Vec::new(),
+			family_filter: FamilyFilter::new(),
+			views: Views::new(),
+			geoip: None,
+			rotation: AnswerRotation::new(),
+			endpoints: EndpointChecks::new(),
+			mdns: MdnsResponder::new(),
+			hosts: HostOverrides::new(),
+			blocklists: Blocklists::new(),
+			uncompressed_clients: Vec::new(),
+			doctor: DnsDoctor::new(),
+			resolve_strategy: RwLock::new(ResolveStrategy::Recursive),
+			delegations: DelegationCache::new(),
+			cache: Cache::new(),
+			cache_file: None,
+			cache_snapshot_interval: DEFAULT_CACHE_SNAPSHOT_INTERVAL,
+			prefetcher: Prefetcher::new(),
+			last_known_good: LastKnownGood::new(),
+			qname_minimization: true,
+			pass_through: false,
+			minimal_responses: false,
+			amplification: None,
+			rrl: None,
+			response_limits: ResponseLimits::default(),
+			edns_hooks: EdnsHooks::new(),
+			query_hooks: QueryHooks::new(),
+			client_stats: ClientStats::new(),
+			latency: LatencyTracker::new(),
+			udp_stats: UdpStats::new(),
+			mirror: None,
+			query_log: None,
+			crash_on_panic: false,
+			panics: AtomicU64::new(0),
+			servfails: ServfailStats::new(),
+			shutdown: Shutdown::new(),
+			capture: RwLock::new(None),
+			authority: Arc::new(Authority::new()),
+			root_hints_file: None,
+			health_check_interval: Some(DEFAULT_HEALTH_CHECK_INTERVAL),
+			delegation_check_interval: None,
+			publish_delegations: false,
+		}
+	}
+
+	/// Account a query of `source` which has been answered, in the latency stats and the
+	/// query log.
+	pub fn record_query(&self, source: SocketAddr, question: Option<&DNSQuestion>, rescode: ResultCode, timing: &QueryTiming) {
+		self.latency.record(source, question, rescode, timing);
+		if let Some(ref query_log) = self.query_log {
+			query_log.record(source, question, rescode, timing);
+		}
+	}
+
+	/// Count a panic caught while handling a query, or abort when told to crash on panics.
+	pub fn count_panic(&self) {
+		if self.crash_on_panic {
+			process::abort();
+		}
+		self.panics.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Number of queries whose handling panicked.
+	pub fn panics(&self) -> u64 {
+		self.panics.load(Ordering::Relaxed)
+	}
+
+	/// Whether the names of the responses to `client` are compressed, which they are unless
+	/// it's one of the clients known to mishandle compression pointers.
+	pub fn compresses_for(&self, client: IpAddr) -> bool {
+		!self.uncompressed_clients.iter().any(|(network, len)| in_prefix(client, *network, *len))
+	}
+
+	/// Write the responses to the clients of `network`, given as `ADDR` or `ADDR/LEN`, without
+	/// name compression.
+	pub fn disable_compression_for(&mut self, network: &str) -> Result<()> {
+		let network = parse_prefix(network)
+			.ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Invalid network: {}", network)))?;
+		self.uncompressed_clients.push(network);
+		Ok(())
+	}
+
+	pub fn edns_max_payload(&self) -> u16 {
+		self.edns_max_payload
+	}
+
+	/// Use UDP payloads of up to `size` bytes, at least 512: responses to clients advertising
+	/// more are kept to that size, and it's what the queries to other servers advertise.
+	pub fn set_edns_max_payload(&mut self, size: u16) {
+		self.edns_max_payload = size.max(512);
+		self.client.set_edns_payload(Some(self.edns_max_payload));
+	}
+
+	pub fn resolve_strategy(&self) -> ResolveStrategy {
+		match self.resolve_strategy.read() {
+			Ok(strategy) => strategy.clone(),
+			Err(poisoned) => poisoned.into_inner().clone(),
+		}
+	}
+
+	pub fn set_resolve_strategy(&self, strategy: ResolveStrategy) {
+		match self.resolve_strategy.write() {
+			Ok(mut current) => *current = strategy,
+			Err(poisoned) => *poisoned.into_inner() = strategy,
+		}
+	}
+
+	/// Swap in the zones and the resolve strategy of a configuration read again, leaving the
+	/// cache as it is. The queries being answered finish with the zones and upstreams they
+	/// started with. The health of new upstreams gets checked like at startup.
+	pub fn reload(&self, zones: Vec<SharedZone>, strategy: ResolveStrategy) {
+		if let ResolveStrategy::Forward { ref upstreams } = strategy {
+			if let Some(interval) = self.health_check_interval {
+				if let Err(e) = upstreams.start_health_checks(interval) {
+					info!("Failed to start the upstream health checks: {}", e);
+				}
+			}
+		}
+		self.authority.replace_zones(zones);
+		self.set_resolve_strategy(strategy);
+	}
+
+	/// Get the resolver ready before serving. The cache is filled from its snapshot, if any,
+	/// and the delegations between the local zones get checked from then on if told to, the
+	/// endpoints of the zones probed every health check interval and the mDNS hosts announced.
+	/// Forwarders start the health checks of their upstreams. The recursor loads the root
+	/// hints file, if any, and primes the root zone in the background; a missing or broken
+	/// hints file leaves the compiled in hints in place, and the hints are what the recursor
+	/// starts from until a priming query succeeds.
+	///
+	/// Nothing here waits on the network, the listeners being meant to be bound first.
+	pub fn initialize(context: &Arc<ServerContext>) {
+		if let Some(ref path) = context.cache_file {
+			if path.exists() {
+				match context.cache.load(path) {
+					Ok(count) => info!("Loaded {} cache entries from {}", count, path.display()),
+					Err(e) => info!("Failed to load the cache from {}: {}", path.display(), e),
+				}
+			}
+			if let Err(e) = start_cache_snapshots(context, path.clone(), context.cache_snapshot_interval) {
+				info!("Failed to start the cache snapshots: {}", e);
+			}
+		}
+		if context.hosts.file().is_some() {
+			if let Err(e) = start_hosts_watch(context, HOSTS_POLL_INTERVAL) {
+				info!("Failed to start watching the hosts file: {}", e);
+			}
+		}
+		if let Some(interval) = context.delegation_check_interval {
+			if let Err(e) = start_delegation_checks(context, interval) {
+				info!("Failed to start the delegation checks: {}", e);
+			}
+		}
+		if !context.endpoints.is_empty() {
+			if let Some(interval) = context.health_check_interval {
+				if let Err(e) = start_endpoint_checks(context, interval) {
+					info!("Failed to start the endpoint health checks: {}", e);
+				}
+			}
+		}
+		if !context.mdns.is_empty() {
+			if let Err(e) = MdnsResponder::start(context) {
+				info!("Failed to start the mDNS responder: {}", e);
+			}
+		}
+
+		if let ResolveStrategy::Forward { ref upstreams } = context.resolve_strategy() {
+			if let Some(interval) = context.health_check_interval {
+				if let Err(e) = upstreams.start_health_checks(interval) {
+					info!("Failed to start the upstream health checks: {}", e);
+				}
+			}
+			return;
+		}
+
+		if let Some(ref path) = context.root_hints_file {
+			match load_root_hints(path) {
+				Ok(hints) => {
+					info!("Loaded {} root servers from {}", hints.len(), path.display());
+					context.delegations.set_root_hints(hints);
+				}
+				Err(e) => info!("Failed to load root hints from {}, using the compiled in hints: {}", path.display(), e),
+			}
+		}
+
+		if let Err(e) = start_priming(context) {
+			info!("Failed to start priming the root zone, using the root hints: {}", e);
+		}
+	}
+
+	/// Write the queries matching `filter` and their responses to the pcapng file at `path`
+	/// for `duration`, replacing any capture in progress.
+	pub fn start_capture(&self, path: &Path, filter: CaptureFilter, duration: Duration) -> Result<()> {
+		let capture = Capture::start(path, filter, self.listen_addr, duration)?;
+		info!("Capturing to {} for {}s", path.display(), duration.as_secs());
+		if let Ok(mut current) = self.capture.write() {
+			*current = Some(Arc::new(capture));
+		}
+		Ok(())
+	}
+
+	/// Stop the capture in progress, returning the number of packets it wrote.
+	pub fn stop_capture(&self) -> Option<u64> {
+		let capture = self.capture.write().ok()?.take()?;
+		info!("Captured {} packets to {}", capture.packets(), capture.path().display());
+		Some(capture.packets())
+	}
+
+	/// The capture in progress, if any. A capture which ran out of time gets stopped.
+	pub fn capture(&self) -> Option<Arc<Capture>> {
+		let capture = self.capture.read().ok()?.clone()?;
+		if capture.is_finished() {
+			self.stop_capture();
+			return None;
+		}
+		Some(capture)
+	}
+
+	/// Stop answering queries and wait up to `timeout` for those being answered, then stop
+	/// the capture in progress, flush the query log, say goodbye over mDNS and write the last
+	/// snapshot of the cache.
+	pub fn shut_down(&self, timeout: Duration) {
+		self.shutdown.stop();
+		let in_flight = self.shutdown.wait(timeout);
+		if in_flight > 0 {
+			info!("Gave up waiting for {} queries", in_flight);
+		}
+
+		self.stop_capture();
+		if let Some(ref query_log) = self.query_log {
+			query_log.close();
+		}
+		self.mdns.goodbye();
+		if let Some(ref path) = self.cache_file {
+			match self.cache.save(path) {
+				Ok(count) => info!("Saved {} cache entries to {}", count, path.display()),
+				Err(e) => info!("Failed to save the cache to {}: {}", path.display(), e),
+			}
+		}
+	}
+
+	/// A resolver for the queries of `source`, the client asking them if known.
+	pub fn create_resolver(context: Arc<ServerContext>, source: Option<IpAddr>) -> Box<dyn DNSResolver> {
+		match context.resolve_strategy() {
+			ResolveStrategy::Recursive => Box::new(RecursiveResolver::new(context)),
+			ResolveStrategy::Forward { upstreams } => Box::new(ForwardingResolver::new(context, upstreams, source)),
+		}
+	}
+}
+
+/// Send the priming query until it succeeds, waiting `PRIME_RETRY_INTERVAL` after the first
+/// failure and twice as long after every other one up to `MAX_PRIME_RETRY_INTERVAL`, for as long
+/// as the context is around and the server isn't stopping.
+fn start_priming(context: &Arc<ServerContext>) -> Result<()> {
+	let context: Weak<ServerContext> = Arc::downgrade(context);
+	thread::Builder::new()
+		.name("root-priming".to_string())
+		.spawn(move || {
+			let mut wait = PRIME_RETRY_INTERVAL;
+			loop {
+				let context = match context.upgrade() {
+					Some(context) => context,
+					None => return,
+				};
+				if context.shutdown.is_stopping() {
+					return;
+				}
+				match RecursiveResolver::new(context).prime() {
+					Ok(count) => {
+						info!("Primed the root zone with {} name servers", count);
+						return;
+					}
+					Err(e) => info!("Priming the root zone failed, using the root hints for now: {}", e),
+				}
+				thread::sleep(wait);
+				wait = (wait * 2).min(MAX_PRIME_RETRY_INTERVAL);
+			}
+		})?;
+	Ok(())
+}
+
+/// Write the cache to `path` every `interval`, for as long as the context is around and the
+/// server isn't stopping.
+fn start_cache_snapshots(context: &Arc<ServerContext>, path: PathBuf, interval: Duration) -> Result<()> {
+	let context: Weak<ServerContext> = Arc::downgrade(context);
+	thread::Builder::new()
+		.name("cache-snapshot".to_string())
+		.spawn(move || loop {
+			thread::sleep(interval);
+			let context = match context.upgrade() {
+				Some(context) => context,
+				None => return,
+			};
+			if context.shutdown.is_stopping() {
+				return;
+			}
+			if let Err(e) = context.cache.save(&path) {
+				info!("Failed to save the cache to {}: {}", path.display(), e);
+			}
+		})?;
+	Ok(())
+}
+
+/// Read the hosts file again whenever it changed, checking it every `interval` for as long as
+/// the context is around and the server isn't stopping.
+fn start_hosts_watch(context: &Arc<ServerContext>, interval: Duration) -> Result<()> {
+	let context: Weak<ServerContext> = Arc::downgrade(context);
+	thread::Builder::new()
+		.name("hosts-watch".to_string())
+		.spawn(move || {
+			// The last failure, logged once rather than on every check until the file is fixed...
+			let mut failure = None;
+			loop {
+				thread::sleep(interval);
+				let context = match context.upgrade() {
+					Some(context) => context,
+					None => return,
+				};
+				if context.shutdown.is_stopping() {
+					return;
+				}
+				let path = context.hosts.file().map(|path| path.display().to_string()).unwrap_or_default();
+				match context.hosts.reload_if_changed() {
+					Ok(reloaded) => {
+						if let Some(count) = reloaded {
+							info!("Reloaded {} hosts from {}", count, path);
+						}
+						failure = None;
+					}
+					Err(e) => {
+						let e = e.to_string();
+						if failure.as_ref() != Some(&e) {
+							info!("Failed to reload the hosts from {}, keeping the current ones: {}", path, e);
+							failure = Some(e);
+						}
+					}
+				}
+			}
+		})?;
+	Ok(())
+}
+
+/// Probe the endpoints of the zones right away and then every `interval`, for as long as the
+/// context is around and the server isn't stopping.
+fn start_endpoint_checks(context: &Arc<ServerContext>, interval: Duration) -> Result<()> {
+	let context: Weak<ServerContext> = Arc::downgrade(context);
+	thread::Builder::new()
+		.name("endpoint-check".to_string())
+		.spawn(move || loop {
+			let context = match context.upgrade() {
+				Some(context) => context,
+				None => return,
+			};
+			if context.shutdown.is_stopping() {
+				return;
+			}
+			context.endpoints.check_all();
+			drop(context);
+			thread::sleep(interval);
+		})?;
+	Ok(())
+}
+
+/// Check the delegations between the local zones every `interval`, for as long as the context
+/// is around and the server isn't stopping. Issues are logged as they appear and once they're
+/// gone, and the delegations found drifting are published when told to.
+fn start_delegation_checks(context: &Arc<ServerContext>, interval: Duration) -> Result<()> {
+	let context: Weak<ServerContext> = Arc::downgrade(context);
+	thread::Builder::new()
+		.name("delegation-check".to_string())
+		.spawn(move || {
+			let mut known = HashSet::new();
+			loop {
+				thread::sleep(interval);
+				let context = match context.upgrade() {
+					Some(context) => context,
+					None => return,
+				};
+				if context.shutdown.is_stopping() {
+					return;
+				}
+
+				let mut current = HashSet::new();
+				for check in context.authority.check_delegations() {
+					if context.publish_delegations && !check.is_ok() {
+						match context.authority.publish_delegation(&check.child) {
+							Ok(count) => info!("Published {} records for {} in {}", count, check.child, check.parent),
+							Err(e) => info!("Failed to publish the delegation of {}: {}", check.child, e),
+						}
+					}
+					let child = check.child;
+					current.extend(check.issues.into_iter().map(|issue| (child.clone(), issue)));
+				}
+				let (mut found, mut fixed): (Vec<_>, Vec<_>) = (current.difference(&known).collect(), known.difference(&current).collect());
+				found.sort();
+				fixed.sort();
+				for (child, issue) in found {
+					info!("Delegation of {}: {}", child, issue);
+				}
+				for (child, issue) in fixed {
+					info!("Delegation of {} fixed: {}", child, issue);
+				}
+				known = current;
+			}
+		})?;
+	Ok(())
+}
+
+impl Default for ServerContext {
+	fn default() -> Self {
+		ServerContext::new()
+	}
+}
@@ -0,0 +1,460 @@
+//! Control channel for managing the running server, in the spirit of rndc
+
+use std::fmt::{ self, Write as _ };
+use std::fs;
+use std::io::{ BufRead, BufReader, Error, ErrorKind, Read, Result, Write };
+use std::net::{ IpAddr, SocketAddr, TcpListener, TcpStream };
+#[cfg(unix)]
+use std::os::unix::fs::{ FileTypeExt, PermissionsExt };
+#[cfg(unix)]
+use std::os::unix::net::{ UnixListener, UnixStream };
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::Arc;
+use std::thread::{ self, JoinHandle };
+use std::time::Duration;
+
+use hmac::{ Hmac, Mac };
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::server::acl::Access;
+use crate::server::capture::{ CaptureFilter, DEFAULT_CAPTURE_DURATION };
+use crate::server::context::ServerContext;
+use crate::server::loader::read_zone;
+use crate::server::servfail::ServfailReason;
+
+/// First line the server sends, followed by the nonce...
+const GREETING: &str = "rdns-control 1";
+/// Longest command line read...
+const MAX_COMMAND: u64 = 4096;
+/// Time a client gets to send its command or the server to answer it...
+pub const CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections served at once...
+pub const MAX_CONNECTIONS: usize = 16;
+
+/// Port of the control channel unless told otherwise, the one of rndc...
+pub const DEFAULT_CONTROL_PORT: u16 = 953;
+
+/// Reads the configuration of the server again, returning what changed or why nothing did.
+pub type ReloadHook = Arc<dyn Fn(&ServerContext) -> std::result::Result<String, String> + Send + Sync>;
+
+/// Where the control channel listens: a TCP address, or the path of a Unix socket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlAddr {
+	Tcp(SocketAddr),
+	Unix(PathBuf),
+}
+
+impl ControlAddr {
+	/// An address given as `ADDR[:PORT]` for TCP, the port defaulting to 953, or as the path
+	/// of a Unix socket.
+	pub fn parse(spec: &str) -> ControlAddr {
+		if let Ok(ip) = spec.parse::<IpAddr>() {
+			return ControlAddr::Tcp(SocketAddr::new(ip, DEFAULT_CONTROL_PORT));
+		}
+		match spec.parse() {
+			Ok(addr) => ControlAddr::Tcp(addr),
+			Err(_) => ControlAddr::Unix(PathBuf::from(spec)),
+		}
+	}
+}
+
+impl fmt::Display for ControlAddr {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ControlAddr::Tcp(addr) => write!(f, "{}", addr),
+			ControlAddr::Unix(ref path) => write!(f, "{}", path.display()),
+		}
+	}
+}
+
+/// Control channel of the server, taking one command per connection from clients holding
+/// the shared key.
+///
+/// The server greets each connection with `rdns-control 1 NONCE`, a random nonce in hex, and
+/// the client answers with a single line `MAC COMMAND`, the MAC being the HMAC-SHA256 of
+/// `NONCE COMMAND` under the key, in hex; a command can't be replayed as the nonce changes
+/// every time. The reply is `ok` followed by the output of the command, or `error` followed
+/// by what went wrong, and the server closes the connection. See `send_command`.
+///
+/// The commands are `reload`, `cache [NAME]` (listing the entries, as `dump-cache` does),
+/// `flush [NAME]`, `flush-tree NAME`, `add-zone FILE`, `remove-zone ORIGIN`, `zones`,
+/// `delegations` (checking them), `publish-delegation ZONE`, `capture FILE [SECS [FILTER]]`,
+/// `stop-capture`, `blocklists`, `blocklist enable [FILE]`, `blocklist disable [FILE]` and
+/// `stats`. Zones added or removed here, and blocklists disabled, are back to those of the
+/// configuration after a reload. A capture writes the queries matching the filter (see
+/// `CaptureFilter`) over every transport and their responses to a pcapng file, on the
+/// server's side, for 60 seconds unless given; queries over streams are written as DNS over
+/// TCP whichever transport they came over (see `Capture`). A blocklist is named by the path
+/// it was loaded from, all of them being enabled or disabled without one.
+///
+/// Each client is served on a thread of its own, up to `MAX_CONNECTIONS` at once. A Unix
+/// socket is only accessible by its owner.
+pub struct ControlServer {
+	context: Arc<ServerContext>,
+	addr: ControlAddr,
+	key: Vec<u8>,
+	reload: Option<ReloadHook>,
+	/// Connections being served...
+	connections: AtomicUsize,
+}
+
+impl ControlServer {
+	pub fn new(context: Arc<ServerContext>, addr: ControlAddr, key: Vec<u8>) -> Self {
+		ControlServer { context, addr, key, reload: None, connections: AtomicUsize::new(0) }
+	}
+
+	/// Run `reload` on the `reload` command, which isn't available otherwise.
+	pub fn with_reload(mut self, reload: ReloadHook) -> Self {
+		self.reload = Some(reload);
+		self
+	}
+
+	/// Bind the socket and start accepting connections. The returned handle belongs to the
+	/// accepting thread, which runs for as long as the socket does.
+	pub fn run_server(self) -> Result<JoinHandle<()>> {
+		match self.addr.clone() {
+			ControlAddr::Tcp(addr) => {
+				let listener = TcpListener::bind(addr)?;
+				let server = Arc::new(self);
+				thread::Builder::new()
+					.name("ControlServer".to_string())
+					.spawn(move || server.accept(listener.incoming(), |stream| {
+						stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+						stream.set_write_timeout(Some(CONTROL_TIMEOUT))
+					}))
+			}
+			#[cfg(unix)]
+			ControlAddr::Unix(path) => {
+				// A socket left behind by a previous run is replaced, any other file isn't...
+				if fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_socket()) {
+					fs::remove_file(&path)?;
+				}
+				let listener = UnixListener::bind(&path)?;
+				fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+				let server = Arc::new(self);
+				thread::Builder::new()
+					.name("ControlServer".to_string())
+					.spawn(move || server.accept(listener.incoming(), |stream| {
+						stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+						stream.set_write_timeout(Some(CONTROL_TIMEOUT))
+					}))
+			}
+			#[cfg(not(unix))]
+			ControlAddr::Unix(_) => Err(Error::new(ErrorKind::Unsupported, "Unix sockets aren't supported on this platform")),
+		}
+	}
+
+	/// Serve the connections of `incoming` each on a thread of its own, a client slow to send
+	/// its command not holding up the others, after setting their timeouts with `configure`.
+	/// Connections beyond `MAX_CONNECTIONS` at once are closed right away.
+	fn accept<S, I, F>(self: Arc<Self>, incoming: I, configure: F)
+		where S: Read + Write + Send + 'static, I: Iterator<Item = Result<S>>, F: Fn(&S) -> Result<()>
+	{
+		for stream in incoming {
+			let mut stream = match stream.and_then(|stream| configure(&stream).map(|()| stream)) {
+				Ok(stream) => stream,
+				Err(e) => {
+					info!("Control connection failed: {}", e);
+					continue;
+				}
+			};
+			if self.connections.fetch_add(1, Ordering::AcqRel) >= MAX_CONNECTIONS {
+				self.connections.fetch_sub(1, Ordering::AcqRel);
+				info!("Refused a control connection, {} are being served", MAX_CONNECTIONS);
+				let _ = stream.write_all(b"error Too many control connections\n");
+				continue;
+			}
+			let server = self.clone();
+			let spawned = thread::Builder::new()
+				.name("ControlConnection".to_string())
+				.spawn(move || {
+					if let Err(e) = server.serve_connection(stream) {
+						info!("Control connection failed: {}", e);
+					}
+					server.connections.fetch_sub(1, Ordering::AcqRel);
+				});
+			if let Err(e) = spawned {
+				self.connections.fetch_sub(1, Ordering::AcqRel);
+				info!("Failed to start a control connection thread: {}", e);
+			}
+		}
+	}
+
+	/// Authenticate the command of a connection, run it and send back its outcome.
+	fn serve_connection<S: Read + Write>(&self, stream: S) -> Result<()> {
+		let nonce = new_nonce();
+		let mut reader = BufReader::new(stream);
+		reader.get_mut().write_all(format!("{} {}\n", GREETING, nonce).as_bytes())?;
+
+		let mut line = String::new();
+		(&mut reader).take(MAX_COMMAND).read_line(&mut line)?;
+		let reply = match line.trim().split_once(' ') {
+			Some((mac, command)) if verify(&self.key, &nonce, command, mac) => match self.execute(command) {
+				Ok(output) => format!("ok\n{}", output),
+				Err(e) => format!("error {}\n", e),
+			},
+			_ => {
+				info!("Rejected a control command failing authentication");
+				"error Authentication failed\n".to_string()
+			}
+		};
+		reader.get_mut().write_all(reply.as_bytes())
+	}
+
+	/// Run a command, returning its output.
+	pub fn execute(&self, command: &str) -> std::result::Result<String, String> {
+		info!("Control command: {}", command);
+		let context = &self.context;
+		let words: Vec<&str> = command.split_whitespace().collect();
+		match words.as_slice() {
+			["reload"] => match self.reload {
+				Some(ref reload) => reload(context),
+				None => Err("Reloading isn't available".to_string()),
+			},
+			["cache"] => Ok(context.cache.dump().iter().map(ToString::to_string).collect()),
+			["cache", name] => Ok(context.cache.dump_name(name).iter().map(ToString::to_string).collect()),
+			["flush"] => Ok(format!("Flushed {} entries\n", context.cache.flush())),
+			["flush", name] => Ok(format!("Flushed {} entries\n", context.cache.flush_name(name))),
+			["flush-tree", name] => Ok(format!("Flushed {} entries\n", context.cache.flush_subtree(name))),
+			["add-zone", path] => {
+				let zone = read_zone(path, "", context.authority.is_strict()).map_err(|e| format!("Failed to load zone {}: {}", path, e))?;
+				let output = format!("Added zone {} with {} records\n", zone.domain, zone.len());
+				context.authority.add_zone(zone);
+				Ok(output)
+			}
+			["remove-zone", origin] if context.authority.remove_zone(origin) => Ok(format!("Removed zone {}\n", origin)),
+			["remove-zone", origin] => Err(format!("No zone {}", origin)),
+			["zones"] => Ok(context.authority.origins().iter().map(|origin| format!("{}.\n", origin)).collect()),
+			["delegations"] => Ok(context.authority.check_delegations().iter().map(|check| {
+				let status = if check.is_ok() {
+					"ok".to_string()
+				} else {
+					check.issues.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+				};
+				format!("{}. from {}.: {}\n", check.child, check.parent, status)
+			}).collect()),
+			["publish-delegation", zone] => context.authority.publish_delegation(zone)
+				.map(|count| format!("Published {} records for {}\n", count, zone))
+				.map_err(|e| e.to_string()),
+			["capture", path, rest @ ..] => {
+				let duration = match rest.first() {
+					Some(secs) => secs.parse::<u64>().ok()
+						.filter(|secs| *secs > 0)
+						.map(Duration::from_secs)
+						.ok_or_else(|| format!("Invalid capture duration: {}", secs))?,
+					None => DEFAULT_CAPTURE_DURATION,
+				};
+				let filter = CaptureFilter::parse(&rest.iter().skip(1).copied().collect::<Vec<_>>().join(" "))
+					.map_err(|e| e.to_string())?;
+				context.start_capture(Path::new(path), filter, duration)
+					.map(|()| format!("Capturing to {} for {}s\n", path, duration.as_secs()))
+					.map_err(|e| format!("Failed to start the capture to {}: {}", path, e))
+			}
+			["stop-capture"] => match context.stop_capture() {
+				Some(packets) => Ok(format!("Captured {} packets\n", packets)),
+				None => Err("No capture in progress".to_string()),
+			},
+			["blocklists"] => Ok(context.blocklists.lists().iter().map(|list| {
+				let state = if list.is_enabled() { "enabled" } else { "disabled" };
+				format!("{}: {} domains, {}\n", list, list.len(), state)
+			}).collect()),
+			["blocklist", toggle @ ("enable" | "disable"), rest @ ..] if rest.len() <= 1 => {
+				let enabled = *toggle == "enable";
+				match context.blocklists.set_enabled(rest.first().map(Path::new), enabled) {
+					0 if rest.is_empty() => Err("No blocklists".to_string()),
+					0 => Err(format!("No blocklist {}", rest[0])),
+					changed => Ok(format!("{} {} blocklists\n", if enabled { "Enabled" } else { "Disabled" }, changed)),
+				}
+			}
+			["stats"] => Ok(self.stats()),
+			[] => Err("Missing command".to_string()),
+			_ => Err(format!("Unknown command: {}", command)),
+		}
+	}
+
+	/// The counters of the server, one per line.
+	fn stats(&self) -> String {
+		let context = &self.context;
+		let mut out = String::new();
+		let cache = context.cache.stats();
+		let _ = writeln!(out, "cache entries: {} ({} bytes)", cache.entries, cache.bytes);
+		let _ = writeln!(out, "cache hits: {} ({} hot), misses: {}, evictions: {}", cache.hits, cache.hot_hits, cache.misses, cache.evictions);
+		let _ = writeln!(out, "last known good answers: {}", context.last_known_good.len());
+		let _ = writeln!(out, "zones: {}", context.authority.origins().len());
+		let _ = writeln!(out, "delegation issues at the last check: {}", context.authority.delegation_drift());
+		for socket in context.udp_stats.sockets() {
+			let _ = writeln!(out, "udp {}#{}: received {}, malformed {}, overflowed {}, sent {}, send errors {}",
+				socket.addr, socket.socket, socket.received, socket.malformed, socket.overflowed, socket.sent, socket.send_errors);
+		}
+		if let Some(slo) = context.latency.slo_status() {
+			let _ = writeln!(out, "slo: {} slow of {} in the last hour, burn rate {:.2} (5m) {:.2} (1h)",
+				slo.slow, slo.total, slo.burn_rate_short, slo.burn_rate_long);
+		}
+		if let Some(ref mirror) = context.mirror {
+			let stats = mirror.stats();
+			let _ = writeln!(out, "mirror {}: mirrored {}, mismatches {}, failed {}, dropped {}",
+				mirror.upstream(), stats.mirrored, stats.mismatches, stats.failed, stats.dropped);
+		}
+		if let Some(ref query_log) = context.query_log {
+			let _ = writeln!(out, "query log entries dropped: {}", query_log.dropped());
+		}
+		if let Some(capture) = context.capture() {
+			let _ = writeln!(out, "capture {}: {} packets", capture.path().display(), capture.packets());
+		}
+		if !context.access_control.is_empty() {
+			let rejected: Vec<_> = Access::ALL.iter()
+				.map(|access| format!("{} {}", access.name(), context.access_control.rejected(*access)))
+				.collect();
+			let _ = writeln!(out, "acl rejected: {}", rejected.join(", "));
+		}
+		if !context.rotation.is_empty() {
+			let _ = writeln!(out, "rotated answers: {}", context.rotation.rotated());
+		}
+		if !context.endpoints.is_empty() {
+			let down: Vec<_> = context.endpoints.checks().iter()
+				.filter(|check| !check.is_healthy())
+				.map(|check| format!("{} {}", check.name, check.addr))
+				.collect();
+			let _ = writeln!(out, "endpoints: {} checked, {} down, {} records withheld", context.endpoints.checks().len(), down.len(), context.endpoints.withheld());
+			for endpoint in down {
+				let _ = writeln!(out, "endpoint down: {}", endpoint);
+			}
+		}
+		if !context.mdns.is_empty() {
+			let _ = writeln!(out, "mdns: {} answered, {} conflicts", context.mdns.answered(), context.mdns.conflicts());
+			for (name, _, state) in context.mdns.hosts() {
+				let _ = writeln!(out, "mdns host {}: {}", name, state);
+			}
+			for (registration, state) in context.mdns.services() {
+				let _ = writeln!(out, "mdns service {} on {}:{}: {}", registration.name(), registration.host, registration.port, state);
+			}
+		}
+		if let Some(ref database) = context.geoip {
+			let (lookups, unknown) = database.lookups();
+			let _ = writeln!(out, "geoip: {} record sets, {} lookups, {} unknown", context.authority.geo_records().len(), lookups, unknown);
+		}
+		if !context.hosts.is_empty() {
+			let _ = writeln!(out, "hosts: {} names, answered {}", context.hosts.len(), context.hosts.answered());
+		}
+		for list in context.blocklists.lists().iter() {
+			let state = if list.is_enabled() { "" } else { ", disabled" };
+			let _ = writeln!(out, "blocklist {}: {} domains, blocked {}{}", list, list.len(), list.blocked(), state);
+		}
+		if !context.doctor.is_empty() {
+			let (inside, outside) = context.doctor.rewrites();
+			let _ = writeln!(out, "doctored addresses: inside {}, outside {}", inside, outside);
+		}
+		for view in context.views.views().iter() {
+			let _ = writeln!(out, "view {}: {} zones, {} hosts, {} cached", view, view.authority().origins().len(), view.hosts().len(), view.cache().len());
+		}
+		if let Some(ref rrl) = context.rrl {
+			let _ = writeln!(out, "rrl: limited {}, slipped {}", rrl.limited(), rrl.slipped());
+		}
+		let clients = context.client_stats.snapshot();
+		let transports: Vec<_> = clients.transports.iter().map(|(transport, count)| format!("{} {}", transport, count)).collect();
+		let _ = writeln!(out, "client queries: {} ({})", clients.queries, transports.join(", "));
+		let sizes: Vec<_> = clients.edns_sizes.iter().map(|(bound, count)| format!("<={} {}", bound, count)).collect();
+		let _ = writeln!(out, "client edns sizes: none {}, {}", clients.no_edns, sizes.join(", "));
+		let _ = writeln!(out, "client flags: do {}, ad {}, cd {}", clients.dnssec_ok, clients.authed_data, clients.checking_disabled);
+		let q_types: Vec<_> = clients.query_types.iter()
+			.filter(|(_, count)| *count > 0)
+			.map(|(q_type, count)| format!("{} {}", q_type, count))
+			.collect();
+		let _ = writeln!(out, "client query types: {}", q_types.join(", "));
+		for hook in context.query_hooks.hooks() {
+			for (counter, value) in hook.counters() {
+				let _ = writeln!(out, "{} {}: {}", hook.name(), counter, value);
+			}
+		}
+		let _ = writeln!(out, "panics: {}", context.panics());
+		let reasons: Vec<_> = ServfailReason::ALL.iter()
+			.map(|reason| format!("{} {}", reason, context.servfails.get(*reason)))
+			.collect();
+		let _ = writeln!(out, "servfail: {} ({})", context.servfails.total(), reasons.join(", "));
+		out
+	}
+}
+
+/// Read the shared key of the control channel from `path`, surrounding whitespace left out.
+pub fn read_key<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+	let key = fs::read(path)?;
+	let key = String::from_utf8_lossy(&key).trim().as_bytes().to_vec();
+	if key.is_empty() {
+		return Err(Error::new(ErrorKind::InvalidData, "Empty control key"));
+	}
+	Ok(key)
+}
+
+/// Send `command` to the control channel at `addr`, returning its output.
+pub fn send_command(addr: &ControlAddr, key: &[u8], command: &str) -> Result<String> {
+	match *addr {
+		ControlAddr::Tcp(addr) => {
+			let stream = TcpStream::connect_timeout(&addr, CONTROL_TIMEOUT)?;
+			stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+			exchange(stream, key, command)
+		}
+		#[cfg(unix)]
+		ControlAddr::Unix(ref path) => {
+			let stream = UnixStream::connect(path)?;
+			stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+			exchange(stream, key, command)
+		}
+		#[cfg(not(unix))]
+		ControlAddr::Unix(_) => Err(Error::new(ErrorKind::Unsupported, "Unix sockets aren't supported on this platform")),
+	}
+}
+
+fn exchange<S: Read + Write>(stream: S, key: &[u8], command: &str) -> Result<String> {
+	let mut reader = BufReader::new(stream);
+	let mut greeting = String::new();
+	reader.read_line(&mut greeting)?;
+	if let Some(e) = greeting.trim().strip_prefix("error ") {
+		return Err(Error::other(e.to_string()));
+	}
+	let nonce = greeting.trim().strip_prefix(GREETING)
+		.map(str::trim)
+		.ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not an rdns control channel"))?;
+
+	let command = command.trim();
+	reader.get_mut().write_all(format!("{} {}\n", sign(key, nonce, command), command).as_bytes())?;
+
+	let mut reply = String::new();
+	reader.read_to_string(&mut reply)?;
+	match reply.split_once('\n') {
+		Some(("ok", output)) => Ok(output.to_string()),
+		Some((status, _)) => match status.strip_prefix("error ") {
+			Some(e) => Err(Error::other(e.to_string())),
+			None => Err(Error::new(ErrorKind::InvalidData, format!("Invalid reply: {}", status))),
+		},
+		None => Err(Error::new(ErrorKind::InvalidData, "Truncated reply")),
+	}
+}
+
+/// The MAC of `command` for the connection greeted with `nonce`, in hex.
+pub fn sign(key: &[u8], nonce: &str, command: &str) -> String {
+	mac(key, nonce, command).finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn verify(key: &[u8], nonce: &str, command: &str, signature: &str) -> bool {
+	let signature: Option<Vec<u8>> = (0..signature.len()).step_by(2)
+		.map(|i| signature.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+		.collect();
+	// Compared in constant time...
+	signature.is_some_and(|signature| mac(key, nonce, command).verify_slice(&signature).is_ok())
+}
+
+fn mac(key: &[u8], nonce: &str, command: &str) -> Hmac<Sha256> {
+	let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+	mac.update(nonce.as_bytes());
+	mac.update(b" ");
+	mac.update(command.as_bytes());
+	mac
+}
+
+fn new_nonce() -> String {
+	let mut nonce = [0u8; 16];
+	rand::thread_rng().fill_bytes(&mut nonce);
+	nonce.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,190 @@
+//! Checks of the delegations between the zones the server is authoritative for
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::Result;
+use std::net::IpAddr;
+
+use crate::server::authority::ZoneData;
+use crate::server::dnssec::key::{ ds_for_key, ds_matches, is_sep, key_tag };
+use crate::server::protocol::{ DNSRecord, QueryType };
+use crate::server::resolve::is_subdomain;
+
+/// Something the parent publishes about a child zone which doesn't match the child.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DelegationIssue {
+	/// The parent has no NS records for the child...
+	NotDelegated,
+	/// A name server of the child the parent doesn't list, or one the parent lists but the
+	/// child doesn't...
+	MissingNs(String),
+	ExtraNs(String),
+	/// An address of a name server below the child's apex the parent has no glue for, or
+	/// glue the child doesn't hold...
+	MissingGlue(String, IpAddr),
+	StaleGlue(String, IpAddr),
+	/// A secure entry point of the child, by key tag, without a DS in the parent...
+	MissingDs(u16),
+	/// A DS of the parent, by key tag, matching none of the keys of the child...
+	StaleDs(u16),
+}
+
+impl fmt::Display for DelegationIssue {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			DelegationIssue::NotDelegated => write!(f, "not delegated by the parent"),
+			DelegationIssue::MissingNs(ref host) => write!(f, "NS {} missing from the parent", host),
+			DelegationIssue::ExtraNs(ref host) => write!(f, "NS {} in the parent only", host),
+			DelegationIssue::MissingGlue(ref host, addr) => write!(f, "glue {} {} missing from the parent", host, addr),
+			DelegationIssue::StaleGlue(ref host, addr) => write!(f, "glue {} {} in the parent only", host, addr),
+			DelegationIssue::MissingDs(tag) => write!(f, "no DS in the parent for key {}", tag),
+			DelegationIssue::StaleDs(tag) => write!(f, "DS {} in the parent matches no key", tag),
+		}
+	}
+}
+
+/// The outcome of checking the delegation of `child` from `parent`, both zone origins.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DelegationCheck {
+	pub parent: String,
+	pub child: String,
+	pub issues: Vec<DelegationIssue>,
+}
+
+impl DelegationCheck {
+	pub fn is_ok(&self) -> bool {
+		self.issues.is_empty()
+	}
+}
+
+/// The records the parent should publish for `child`: the NS records of its apex, glue for
+/// the name servers below the apex, and the SHA-256 DS of each of its secure entry points
+/// (of every zone key when none is flagged as such).
+pub fn expected_delegation(child: &(dyn ZoneData + Send + Sync)) -> Result<Vec<DNSRecord>> {
+	let origin = child.origin();
+	let nameservers = child.lookup(origin, QueryType::NS)?;
+
+	let mut records = Vec::new();
+	for ns in &nameservers {
+		if let DNSRecord::NS { ref host, .. } = *ns {
+			if is_subdomain(host, origin) {
+				records.extend(child.lookup(host, QueryType::A)?);
+				records.extend(child.lookup(host, QueryType::AAAA)?);
+			}
+		}
+	}
+	records.extend(entry_points(child)?.iter().filter_map(|key| ds_for_key(key, key.get_ttl())));
+	records.extend(nameservers);
+	Ok(records)
+}
+
+/// Compare what `parent` publishes about `child` with the child itself.
+pub fn check_delegation(parent: &(dyn ZoneData + Send + Sync), child: &(dyn ZoneData + Send + Sync)) -> Result<DelegationCheck> {
+	let origin = child.origin();
+	let mut check = DelegationCheck { parent: parent.origin().to_string(), child: origin.to_string(), issues: Vec::new() };
+
+	let published = ns_hosts(&parent.lookup(origin, QueryType::NS)?);
+	if published.is_empty() {
+		check.issues.push(DelegationIssue::NotDelegated);
+		return Ok(check);
+	}
+	let actual = ns_hosts(&child.lookup(origin, QueryType::NS)?);
+	check.issues.extend(actual.difference(&published).cloned().map(DelegationIssue::MissingNs));
+	check.issues.extend(published.difference(&actual).cloned().map(DelegationIssue::ExtraNs));
+
+	// Glue only matters for the name servers below the cut, which can't be resolved without...
+	for host in actual.iter().filter(|host| is_subdomain(host, origin)) {
+		let glue = addresses(&parent.lookup(host, QueryType::A)?, &parent.lookup(host, QueryType::AAAA)?);
+		let addrs = addresses(&child.lookup(host, QueryType::A)?, &child.lookup(host, QueryType::AAAA)?);
+		check.issues.extend(addrs.difference(&glue).map(|addr| DelegationIssue::MissingGlue(host.clone(), *addr)));
+		check.issues.extend(glue.difference(&addrs).map(|addr| DelegationIssue::StaleGlue(host.clone(), *addr)));
+	}
+
+	let ds = parent.lookup(origin, QueryType::DS)?;
+	let keys = child.lookup(origin, QueryType::DNSKEY)?;
+	for key in entry_points(child)? {
+		if !ds.iter().any(|ds| ds_matches(ds, &key)) {
+			check.issues.push(DelegationIssue::MissingDs(key_tag(&key).unwrap_or_default()));
+		}
+	}
+	for ds in &ds {
+		if let DNSRecord::DS { key_tag, .. } = *ds {
+			if !keys.iter().any(|key| ds_matches(ds, key)) {
+				check.issues.push(DelegationIssue::StaleDs(key_tag));
+			}
+		}
+	}
+
+	Ok(check)
+}
+
+/// The keys of the child's apex the parent should have a DS for.
+fn entry_points(child: &(dyn ZoneData + Send + Sync)) -> Result<Vec<DNSRecord>> {
+	let keys = child.lookup(child.origin(), QueryType::DNSKEY)?;
+	let sep: Vec<DNSRecord> = keys.iter().filter(|key| is_sep(key)).cloned().collect();
+	Ok(if sep.is_empty() { keys } else { sep })
+}
+
+fn ns_hosts(records: &[DNSRecord]) -> BTreeSet<String> {
+	records.iter()
+		.filter_map(|record| match *record {
+			DNSRecord::NS { ref host, .. } => Some(host.trim_end_matches('.').to_lowercase()),
+			_ => None,
+		})
+		.collect()
+}
+
+fn addresses(a: &[DNSRecord], aaaa: &[DNSRecord]) -> BTreeSet<IpAddr> {
+	a.iter().chain(aaaa)
+		.filter_map(|record| match *record {
+			DNSRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+			DNSRecord::AAAA { addr, .. } => Some(IpAddr::V6(addr)),
+			_ => None,
+		})
+		.collect()
+}
+
+/// The records published in `parent` for a delegation, in place of its own NS and DS
+/// records at the cut and of its glue for the name servers covered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublishedDelegation {
+	pub parent: String,
+	pub child: String,
+	pub records: Vec<DNSRecord>,
+}
+
+/// A zone with the delegations published for it laid over its own records.
+pub(crate) struct PublishedZone<'a> {
+	pub zone: &'a (dyn ZoneData + Send + Sync),
+	pub delegations: &'a [PublishedDelegation],
+}
+
+impl ZoneData for PublishedZone<'_> {
+	fn origin(&self) -> &str {
+		self.zone.origin()
+	}
+
+	fn soa(&self) -> Result<DNSRecord> {
+		self.zone.soa()
+	}
+
+	fn lookup(&self, name: &str, q_type: QueryType) -> Result<Vec<DNSRecord>> {
+		for delegation in self.delegations.iter().filter(|delegation| delegation.parent == self.zone.origin()) {
+			let records: Vec<DNSRecord> = delegation.records.iter()
+				.filter(|record| record.get_query_type() == q_type && record.get_domain().as_deref() == Some(name))
+				.cloned()
+				.collect();
+			// The NS and DS records at the cut are all replaced, even by none...
+			if !records.is_empty() || (name == delegation.child && matches!(q_type, QueryType::NS | QueryType::DS)) {
+				return Ok(records);
+			}
+		}
+		self.zone.lookup(name, q_type)
+	}
+
+	fn has_name(&self, name: &str) -> Result<bool> {
+		let published = self.delegations.iter()
+			.any(|delegation| delegation.parent == self.zone.origin() && is_subdomain(&delegation.child, name));
+		Ok(published || self.zone.has_name(name)?)
+	}
+}
@@ -0,0 +1,567 @@
+//! DNS-Based Service Discovery: browsing for the instances of a service, over mDNS or a
+//! unicast server, and the services the mDNS responder registers (RFC 6763)
+
+use std::convert::TryFrom;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::{ IpAddr, Ipv4Addr, SocketAddr, UdpSocket };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ Arc, Mutex, MutexGuard, Weak };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use crate::server::buffer::VectorPacketBuffer;
+use crate::server::client::DNSClient;
+use crate::server::mdns::{ bind_v4, decode_name, decode_txt, Message, MdnsMessage, MdnsRecord,
+	MAX_MESSAGE, MDNS_GROUP_V4, MDNS_PORT, TYPE_A, TYPE_AAAA, TYPE_PTR, TYPE_SRV, TYPE_TXT };
+use crate::server::protocol::{ QueryType, ResultCode };
+
+/// Name browsed for the service types of the network (RFC 6763 9)...
+pub const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+
+/// Wait before the second browsing query, doubling after every query (RFC 6762 5.2)...
+const FIRST_QUERY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_QUERY_INTERVAL: Duration = Duration::from_secs(3600);
+/// Least time between two queries for the records of instances not resolved yet...
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(1);
+/// Records flushed by a cache-flush record are only those received longer ago than this, the
+/// rest being part of the same announcement (RFC 6762 10.2)...
+const FLUSH_GRACE: Duration = Duration::from_secs(1);
+/// Time a record said goodbye to with a TTL of 0 is kept (RFC 6762 10.1)...
+const GOODBYE_DELAY: Duration = Duration::from_secs(1);
+
+/// A service the mDNS responder announces on one of its hosts, given as
+/// `INSTANCE@HOST TYPE PORT [KEY=VALUE]...` on the command line: `Printer@office _ipp._tcp 631
+/// rp=printer`. The service type is given without `.local`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceRegistration {
+	/// Name of the instance, for users to pick it by, as `Office Printer`...
+	pub instance: String,
+	/// Type of the service, as `_http._tcp`...
+	pub service_type: String,
+	/// mDNS host the service runs on...
+	pub host: String,
+	pub port: u16,
+	/// Strings of the TXT record, as `KEY=VALUE` or `KEY`...
+	pub txt: Vec<String>,
+}
+
+impl ServiceRegistration {
+	pub fn new(instance: &str, service_type: &str, host: &str, port: u16) -> Self {
+		ServiceRegistration {
+			instance: instance.to_string(),
+			service_type: service_type.trim_end_matches('.').trim_end_matches(".local").to_lowercase(),
+			host: host.to_string(),
+			port,
+			txt: Vec::new(),
+		}
+	}
+
+	/// Add `entry`, `KEY=VALUE` or `KEY`, to the TXT record.
+	pub fn with_txt(mut self, entry: &str) -> Self {
+		self.txt.push(entry.to_string());
+		self
+	}
+
+	/// The service given as `INSTANCE@HOST TYPE PORT [KEY=VALUE]...`, the instance name
+	/// running up to the last `@`.
+	pub fn parse(spec: &str) -> Result<ServiceRegistration> {
+		let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid mDNS service: {}", spec));
+		let (instance, rest) = spec.trim().rsplit_once('@').ok_or_else(invalid)?;
+		let mut fields = rest.split_whitespace();
+		let host = fields.next().ok_or_else(invalid)?;
+		let service_type = fields.next().ok_or_else(invalid)?;
+		let port = fields.next().and_then(|port| port.parse::<u16>().ok()).ok_or_else(invalid)?;
+		let registration = fields.fold(ServiceRegistration::new(instance, service_type, host, port), |registration, entry| registration.with_txt(entry));
+		registration.check()?;
+		Ok(registration)
+	}
+
+	/// The full name of the instance, as `Office Printer._ipp._tcp.local`.
+	pub fn name(&self) -> String {
+		format!("{}.{}.local", self.instance, self.service_type)
+	}
+
+	pub(crate) fn check(&self) -> Result<()> {
+		if self.instance.is_empty() || self.instance.len() > 63 || self.instance.contains('.') {
+			return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid service instance name: {}", self.instance)));
+		}
+		check_service_type(&self.service_type)?;
+		if self.txt.iter().any(|entry| entry.is_empty() || entry.len() > 255 || entry.starts_with('=')) {
+			return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid TXT entry of {}", self.name())));
+		}
+		Ok(())
+	}
+}
+
+/// An instance of a service found browsing, with its SRV, TXT and address records.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceInstance {
+	/// Full name, as `Office Printer._ipp._tcp.local`...
+	pub name: String,
+	/// Name of the instance alone, as `Office Printer`...
+	pub instance: String,
+	/// Service browsed, as `_ipp._tcp.local`...
+	pub service: String,
+	/// Target of the SRV record, the host to connect to...
+	pub host: String,
+	pub port: u16,
+	pub priority: u16,
+	pub weight: u16,
+	/// Strings of the TXT record...
+	pub txt: Vec<String>,
+	/// Addresses of the host...
+	pub addrs: Vec<IpAddr>,
+}
+
+impl ServiceInstance {
+	/// The value of `key` in the TXT record: Some("") for a key given alone, None for a key
+	/// missing. Keys are matched ignoring case, the first entry winning (RFC 6763 6.4).
+	pub fn txt_value(&self, key: &str) -> Option<&str> {
+		self.txt.iter()
+			.map(|entry| entry.split_once('=').unwrap_or((entry, "")))
+			.find(|(name, _)| name.eq_ignore_ascii_case(key))
+			.map(|(_, value)| value)
+	}
+
+	/// The addresses to connect to for the service.
+	pub fn socket_addrs(&self) -> Vec<SocketAddr> {
+		self.addrs.iter().map(|addr| SocketAddr::new(*addr, self.port)).collect()
+	}
+}
+
+/// A change of the instances found by a `ServiceBrowser`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServiceEvent {
+	/// An instance was resolved for the first time...
+	Added(ServiceInstance),
+	/// The records of an instance changed, its port, TXT record or addresses...
+	Updated(ServiceInstance),
+	/// An instance said goodbye, or its records expired...
+	Removed(ServiceInstance),
+}
+
+impl ServiceEvent {
+	pub fn instance(&self) -> &ServiceInstance {
+		match *self {
+			ServiceEvent::Added(ref instance) | ServiceEvent::Updated(ref instance) | ServiceEvent::Removed(ref instance) => instance,
+		}
+	}
+}
+
+type Callback = Arc<dyn Fn(&ServiceEvent) + Send + Sync>;
+
+#[derive(Debug)]
+struct Cached {
+	record: MdnsRecord,
+	ttl: u32,
+	received: Instant,
+	expires: Instant,
+	/// Whether the record was asked for again, past 80% of its TTL (RFC 6762 5.2)...
+	refreshed: bool,
+}
+
+impl Cached {
+	fn remaining(&self, now: Instant) -> u32 {
+		self.expires.saturating_duration_since(now).as_secs() as u32
+	}
+}
+
+struct BrowserState {
+	service: String,
+	cache: Mutex<Vec<Cached>>,
+	instances: Mutex<Vec<ServiceInstance>>,
+	callbacks: Mutex<Vec<Callback>>,
+	stopping: AtomicBool,
+}
+
+/// Browses a service over mDNS in the background for as long as it's around, or until
+/// stopped: it asks for the instances of the service, backing off from a query a second
+/// up to one an hour, resolves their SRV, TXT and address records, and keeps them up to date
+/// from the announcements and goodbyes of their hosts.
+///
+/// ```no_run
+/// let browser = rdns::browse("_http._tcp.local")?;
+/// browser.on_change(|event| println!("{:?}", event));
+/// std::thread::sleep(std::time::Duration::from_secs(3));
+/// for instance in browser.instances() {
+///     println!("{} at {:?}", instance.instance, instance.socket_addrs());
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct ServiceBrowser {
+	state: Arc<BrowserState>,
+}
+
+impl ServiceBrowser {
+	/// Browse `service`, as `_http._tcp.local` or `_http._tcp`, on the interface of
+	/// `interface`, the default one being UNSPECIFIED.
+	pub fn start(service: &str, interface: Ipv4Addr) -> Result<ServiceBrowser> {
+		let service = service.trim_end_matches('.').trim_end_matches(".local").to_lowercase();
+		if service != SERVICE_TYPES.trim_end_matches(".local") {
+			check_service_type(&service)?;
+		}
+		let socket = bind_v4(interface)?;
+		let state = Arc::new(BrowserState {
+			service: format!("{}.local", service),
+			cache: Mutex::new(Vec::new()),
+			instances: Mutex::new(Vec::new()),
+			callbacks: Mutex::new(Vec::new()),
+			stopping: AtomicBool::new(false),
+		});
+		send(&socket, &state.browse_query(Instant::now()));
+		let weak = Arc::downgrade(&state);
+		thread::Builder::new()
+			.name("dnssd-browse".to_string())
+			.spawn(move || run_browser(weak, socket))?;
+		Ok(ServiceBrowser { state })
+	}
+
+	/// Service browsed, as `_http._tcp.local`.
+	pub fn service(&self) -> &str {
+		&self.state.service
+	}
+
+	/// The instances found and resolved so far, sorted by name.
+	pub fn instances(&self) -> Vec<ServiceInstance> {
+		lock(&self.state.instances).clone()
+	}
+
+	/// Call `callback` on every change of the instances from now on, from the browsing thread.
+	pub fn on_change<F>(&self, callback: F)
+	where
+		F: Fn(&ServiceEvent) + Send + Sync + 'static,
+	{
+		lock(&self.state.callbacks).push(Arc::new(callback));
+	}
+
+	/// Stop browsing, within a second. Dropping the browser does as well.
+	pub fn stop(&self) {
+		self.state.stopping.store(true, Ordering::Relaxed);
+	}
+}
+
+impl Drop for ServiceBrowser {
+	fn drop(&mut self) {
+		self.stop();
+	}
+}
+
+/// Browse `service`, as `_http._tcp.local`, over mDNS on the default interface. See
+/// `ServiceBrowser`, and `DNSClient::browse_services` for unicast DNS-SD.
+pub fn browse(service: &str) -> Result<ServiceBrowser> {
+	ServiceBrowser::start(service, Ipv4Addr::UNSPECIFIED)
+}
+
+
+/// Receive the responses of the network and query for the service, until the browser is
+/// dropped or stopped.
+fn run_browser(state: Weak<BrowserState>, socket: UdpSocket) {
+	let mut buf = vec![0u8; MAX_MESSAGE];
+	let mut interval = FIRST_QUERY_INTERVAL;
+	let mut next_query = Instant::now() + interval;
+	let mut last_resolve: Option<Instant> = None;
+	loop {
+		let received = socket.recv_from(&mut buf);
+		let state = match state.upgrade() {
+			Some(state) => state,
+			None => return,
+		};
+		if state.stopping.load(Ordering::Relaxed) {
+			return;
+		}
+		let now = Instant::now();
+		match received {
+			Ok((len, source)) => match MdnsMessage::read(&buf[..len]) {
+				Ok(message) if message.header.response && message.header.opcode == 0 => state.cache(&message, now),
+				Ok(_) => (),
+				Err(e) => debug!("Ignored an mDNS message from {}: {}", source, e),
+			},
+			Err(ref e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+			Err(e) => {
+				info!("Failed to receive on the mDNS group, no longer browsing {}: {}", state.service, e);
+				return;
+			}
+		}
+		state.update(now);
+
+		if now >= next_query {
+			interval = (interval * 2).min(MAX_QUERY_INTERVAL);
+			next_query = now + interval;
+			send(&socket, &state.browse_query(now));
+		}
+		if last_resolve.is_none_or(|last| now.duration_since(last) >= RESOLVE_INTERVAL) {
+			if let Some(query) = state.resolve_query(now) {
+				send(&socket, &query);
+				last_resolve = Some(now);
+			}
+		}
+	}
+}
+
+fn send(socket: &UdpSocket, message: &Message) {
+	let sent = message.encode().and_then(|data| socket.send_to(&data, SocketAddr::from((MDNS_GROUP_V4, MDNS_PORT))));
+	if let Err(e) = sent {
+		debug!("Failed to send an mDNS query: {}", e);
+	}
+}
+
+impl BrowserState {
+	/// Cache the records of `message` about the service: the PTRs to its instances, their SRV
+	/// and TXT records, and the addresses of their hosts.
+	fn cache(&self, message: &MdnsMessage, now: Instant) {
+		let mut cache = lock(&self.cache);
+		// The addresses come after the SRV records naming their host...
+		let (addresses, others): (Vec<_>, Vec<_>) = message.records()
+			.partition(|(record, _, _)| record.q_type == TYPE_A || record.q_type == TYPE_AAAA);
+		for (record, ttl, flush) in others.into_iter().chain(addresses) {
+			let wanted = match record.q_type {
+				TYPE_PTR => same_name(&record.name, &self.service),
+				TYPE_SRV | TYPE_TXT => is_instance_of(&record.name, &self.service),
+				_ => cache.iter().any(|cached| cached.record.q_type == TYPE_SRV && srv_target(&cached.record.rdata).is_some_and(|target| same_name(&target, &record.name))),
+			};
+			if wanted {
+				insert(&mut cache, record, *ttl, *flush, now);
+			}
+		}
+	}
+
+	/// Drop the records expired and tell the callbacks about the instances changed.
+	fn update(&self, now: Instant) {
+		let resolved: Vec<ServiceInstance> = {
+			let mut cache = lock(&self.cache);
+			cache.retain(|cached| cached.expires > now);
+			let records: Vec<MdnsRecord> = cache.iter().map(|cached| cached.record.clone()).collect();
+			build_instances(&self.service, &records).into_iter().filter(|instance| !instance.addrs.is_empty()).collect()
+		};
+		let events = {
+			let mut instances = lock(&self.instances);
+			if *instances == resolved {
+				return;
+			}
+			let mut events: Vec<ServiceEvent> = instances.iter()
+				.filter(|old| !resolved.iter().any(|new| same_name(&new.name, &old.name)))
+				.map(|old| ServiceEvent::Removed(old.clone()))
+				.collect();
+			for new in &resolved {
+				match instances.iter().find(|old| same_name(&old.name, &new.name)) {
+					None => events.push(ServiceEvent::Added(new.clone())),
+					Some(old) if old != new => events.push(ServiceEvent::Updated(new.clone())),
+					Some(_) => (),
+				}
+			}
+			*instances = resolved;
+			events
+		};
+		let callbacks = lock(&self.callbacks).clone();
+		for event in &events {
+			debug!("DNS-SD browsing {}: {:?}", self.service, event);
+			for callback in &callbacks {
+				callback(event);
+			}
+		}
+	}
+
+	/// The query for the instances of the service, with the PTRs known to have at least half
+	/// their TTL left for the responders not to answer them again (RFC 6762 7.1).
+	fn browse_query(&self, now: Instant) -> Message {
+		let cache = lock(&self.cache);
+		let known: Vec<&Cached> = cache.iter()
+			.filter(|cached| cached.record.q_type == TYPE_PTR && cached.remaining(now) > cached.ttl / 2)
+			.collect();
+		let ttl = known.iter().map(|cached| cached.remaining(now)).min().unwrap_or(0);
+		Message::query(vec![(self.service.clone(), TYPE_PTR)], known.iter().map(|cached| cached.record.clone()).collect(), ttl)
+	}
+
+	/// The query for the records of the instances not resolved yet, and for those cached
+	/// past 80% of their TTL, None without any.
+	fn resolve_query(&self, now: Instant) -> Option<Message> {
+		let mut cache = lock(&self.cache);
+		let mut questions = Vec::new();
+		for cached in cache.iter_mut().filter(|cached| !cached.refreshed) {
+			if cached.remaining(now) * 5 < cached.ttl {
+				cached.refreshed = true;
+				questions.push((cached.record.name.clone(), cached.record.q_type));
+			}
+		}
+		let records: Vec<MdnsRecord> = cache.iter().map(|cached| cached.record.clone()).collect();
+		for (name, _) in instance_names(&self.service, &records) {
+			let has = |q_type| records.iter().any(|record| record.q_type == q_type && same_name(&record.name, &name));
+			if !has(TYPE_SRV) {
+				questions.push((name.clone(), TYPE_SRV));
+			}
+			if !has(TYPE_TXT) {
+				questions.push((name.clone(), TYPE_TXT));
+			}
+		}
+		for instance in build_instances(&self.service, &records).iter().filter(|instance| instance.addrs.is_empty()) {
+			questions.push((instance.host.clone(), TYPE_A));
+			questions.push((instance.host.clone(), TYPE_AAAA));
+		}
+		questions.dedup();
+		if questions.is_empty() {
+			None
+		} else {
+			Some(Message::query(questions, Vec::new(), 0))
+		}
+	}
+}
+
+/// Cache `record`, a cache-flush record replacing the records of its name and type received
+/// earlier than the announcement it's part of, and a TTL of 0 saying goodbye to it.
+fn insert(cache: &mut Vec<Cached>, record: &MdnsRecord, ttl: u32, flush: bool, now: Instant) {
+	if flush {
+		cache.retain(|cached| {
+			cached.record.q_type != record.q_type
+				|| !same_name(&cached.record.name, &record.name)
+				|| now.duration_since(cached.received) < FLUSH_GRACE
+		});
+	}
+	let existing = cache.iter().position(|cached| cached.record.q_type == record.q_type && same_name(&cached.record.name, &record.name) && cached.record.rdata == record.rdata);
+	if ttl == 0 {
+		if let Some(index) = existing {
+			cache[index].expires = now + GOODBYE_DELAY;
+		}
+		return;
+	}
+	let cached = Cached {
+		record: record.clone(),
+		ttl,
+		received: now,
+		expires: now + Duration::from_secs(ttl as u64),
+		refreshed: false,
+	};
+	match existing {
+		Some(index) => cache[index] = cached,
+		None => cache.push(cached),
+	}
+}
+
+/// The instances of `service` among `records`, with the SRV, TXT and address records found
+/// of them, sorted by name. Those without an SRV record are left out.
+fn build_instances(service: &str, records: &[MdnsRecord]) -> Vec<ServiceInstance> {
+	let mut instances: Vec<ServiceInstance> = instance_names(service, records).into_iter()
+		.filter_map(|(name, instance)| {
+			let srv = records.iter().find(|record| record.q_type == TYPE_SRV && same_name(&record.name, &name))?;
+			let host = srv_target(&srv.rdata)?;
+			let field = |at: usize| u16::from_be_bytes([srv.rdata[at], srv.rdata[at + 1]]);
+			let txt = records.iter()
+				.find(|record| record.q_type == TYPE_TXT && same_name(&record.name, &name))
+				.map_or_else(Vec::new, |record| decode_txt(&record.rdata));
+			let addrs = records.iter()
+				.filter(|record| same_name(&record.name, &host))
+				.filter_map(|record| match (record.q_type, record.rdata.len()) {
+					(TYPE_A, 4) => Some(IpAddr::from(<[u8; 4]>::try_from(&record.rdata[..]).ok()?)),
+					(TYPE_AAAA, 16) => Some(IpAddr::from(<[u8; 16]>::try_from(&record.rdata[..]).ok()?)),
+					_ => None,
+				})
+				.collect();
+			Some(ServiceInstance {
+				name,
+				instance,
+				service: service.to_string(),
+				host,
+				port: field(4),
+				priority: field(0),
+				weight: field(2),
+				txt,
+				addrs,
+			})
+		})
+		.collect();
+	instances.sort_by(|a, b| a.name.cmp(&b.name));
+	instances
+}
+
+/// The full names of the instances `records` point `service` to, with their instance name.
+fn instance_names(service: &str, records: &[MdnsRecord]) -> Vec<(String, String)> {
+	let mut names: Vec<(String, String)> = records.iter()
+		.filter(|record| record.q_type == TYPE_PTR && same_name(&record.name, service))
+		.filter_map(|record| decode_name(&record.rdata).ok())
+		.filter(|(name, _)| is_instance_of(name, service))
+		.map(|(name, _)| {
+			let instance = name[..name.len() - service.len() - 1].to_string();
+			(name, instance)
+		})
+		.collect();
+	names.sort();
+	names.dedup_by(|a, b| same_name(&a.0, &b.0));
+	names
+}
+
+/// The host an SRV record points to.
+fn srv_target(rdata: &[u8]) -> Option<String> {
+	rdata.get(6..).and_then(|name| decode_name(name).ok()).map(|(name, _)| name)
+}
+
+/// Whether `name` is an instance of `service`, a label under it.
+fn is_instance_of(name: &str, service: &str) -> bool {
+	let name = name.trim_end_matches('.');
+	name.len() > service.len() + 1
+		&& name.is_char_boundary(name.len() - service.len() - 1)
+		&& name[name.len() - service.len()..].eq_ignore_ascii_case(service)
+		&& name.as_bytes()[name.len() - service.len() - 1] == b'.'
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+	a.trim_end_matches('.').eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+/// Check the type of a service, `_NAME._tcp` or `_NAME._udp` (RFC 6763 7).
+fn check_service_type(service_type: &str) -> Result<()> {
+	let valid = match service_type.split_once('.') {
+		Some((name, protocol)) => {
+			name.len() > 1 && name.len() <= 16 && name.starts_with('_') && !name[1..].starts_with('-') && !name.ends_with('-')
+				&& name[1..].bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+				&& (protocol == "_tcp" || protocol == "_udp")
+		}
+		None => false,
+	};
+	if valid {
+		Ok(())
+	} else {
+		Err(Error::new(ErrorKind::InvalidInput, format!("Invalid service type: {}", service_type)))
+	}
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl DNSClient {
+	/// Find the instances of `service`, as `_http._tcp.example.com`, through the recursive
+	/// resolver at `server`, with their SRV and TXT records and the addresses of their hosts
+	/// (unicast DNS-SD, RFC 6763). The records the server sends along with the PTRs aren't
+	/// asked for again. Instances without an SRV record are left out.
+	pub fn browse_services(&self, service: &str, server: SocketAddr) -> Result<Vec<ServiceInstance>> {
+		let service = service.trim_end_matches('.');
+		let mut records: Vec<MdnsRecord> = self.raw_query(service, QueryType::PTR, server)?;
+		for (name, _) in instance_names(service, &records) {
+			for (q_type, num) in [(QueryType::SRV, TYPE_SRV), (QueryType::TXT, TYPE_TXT)] {
+				if !records.iter().any(|record| record.q_type == num && same_name(&record.name, &name)) {
+					// An instance missing a record is still worth the others...
+					if let Ok(more) = self.raw_query(&name, q_type, server) {
+						records.extend(more);
+					}
+				}
+			}
+		}
+		let mut instances = build_instances(service, &records);
+		for instance in instances.iter_mut().filter(|instance| instance.addrs.is_empty()) {
+			instance.addrs = self.lookup_ip(&instance.host, server).unwrap_or_default();
+		}
+		Ok(instances)
+	}
+
+	/// The records of the answer and additional sections of the response for `name`, read
+	/// keeping the case of the instance names and the strings of the TXT records apart.
+	fn raw_query(&self, name: &str, q_type: QueryType, server: SocketAddr) -> Result<Vec<MdnsRecord>> {
+		let mut buffer = VectorPacketBuffer::new();
+		self.build_query(name, q_type, true).write(&mut buffer)?;
+		let response = MdnsMessage::read(&self.exchange_raw(buffer.as_slice(), server, false)?)?;
+		match response.header.rescode {
+			ResultCode::NOERROR => Ok(response.records().map(|(record, _, _)| record.clone()).collect()),
+			ResultCode::NXDOMAIN => Err(Error::new(ErrorKind::NotFound, format!("{} does not exist", name))),
+			rescode => Err(Error::other(format!("Lookup of {} failed: {:?}", name, rescode))),
+		}
+	}
+}
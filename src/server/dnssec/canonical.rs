@@ -0,0 +1,122 @@
+use std::cmp::Ordering;
+use std::io::{ Error, ErrorKind, Result };
+
+use crate::server::buffer::VectorPacketBuffer;
+use crate::server::protocol::DNSRecord;
+
+// Canonical form and ordering of names and records as defined in RFC 4034 section 6, which
+// is what signatures are computed over and validated against.
+
+/// Canonical wire format of a domain name: lowercased and uncompressed.
+pub fn canonical_name(name: &str) -> Vec<u8> {
+	let mut wire = Vec::with_capacity(name.len() + 2);
+	for label in name.split('.').filter(|l| !l.is_empty()) {
+		wire.push(label.len() as u8);
+		wire.extend(label.bytes().map(|b| b.to_ascii_lowercase()));
+	}
+	wire.push(0);
+	wire
+}
+
+/// Canonical ordering of names (RFC 4034 section 6.1): labels are compared from the most
+/// significant (rightmost) one, each as a lowercased octet string, and a name sorts before
+/// any name below it.
+pub fn compare_names(a: &str, b: &str) -> Ordering {
+	let a_labels = a.split('.').filter(|l| !l.is_empty()).rev();
+	let mut b_labels = b.split('.').filter(|l| !l.is_empty()).rev();
+
+	for a_label in a_labels {
+		let b_label = match b_labels.next() {
+			Some(label) => label,
+			None => return Ordering::Greater,
+		};
+		let ord = a_label.bytes().map(|b| b.to_ascii_lowercase())
+			.cmp(b_label.bytes().map(|b| b.to_ascii_lowercase()));
+		if ord != Ordering::Equal {
+			return ord;
+		}
+	}
+
+	if b_labels.next().is_some() {
+		Ordering::Less
+	} else {
+		Ordering::Equal
+	}
+}
+
+/// Returns a copy of `record` with the owner name and the domain names inside its RDATA
+/// lowercased (RFC 4034 section 6.2).
+pub fn canonical_record(record: &DNSRecord) -> DNSRecord {
+	let mut record = record.clone();
+	match record {
+		DNSRecord::NS { ref mut domain, ref mut host, .. }
+		| DNSRecord::CNAME { ref mut domain, ref mut host, .. }
+		| DNSRecord::PTR { ref mut domain, ref mut host, .. }
+		| DNSRecord::MX { ref mut domain, ref mut host, .. }
+		| DNSRecord::SRV { ref mut domain, ref mut host, .. } => {
+			*domain = domain.to_lowercase();
+			*host = host.to_lowercase();
+		}
+		DNSRecord::SOA { ref mut domain, ref mut m_name, ref mut r_name, .. } => {
+			*domain = domain.to_lowercase();
+			*m_name = m_name.to_lowercase();
+			*r_name = r_name.to_lowercase();
+		}
+		DNSRecord::A { ref mut domain, .. }
+		| DNSRecord::AAAA { ref mut domain, .. }
+		| DNSRecord::TXT { ref mut domain, .. }
+		| DNSRecord::DS { ref mut domain, .. }
+		| DNSRecord::DNSKEY { ref mut domain, .. }
+		| DNSRecord::UNKNOWN { ref mut domain, .. } => {
+			*domain = domain.to_lowercase();
+		}
+		DNSRecord::OPT { .. } => {}
+	}
+	record
+}
+
+/// Canonical wire format of a whole record. The TTL written is the one carried by `record`,
+/// so callers validating a signature should set it to the RRSIG's original TTL first.
+pub fn canonical_wire(record: &DNSRecord) -> Result<Vec<u8>> {
+	if let DNSRecord::OPT { .. } = *record {
+		return Err(Error::new(ErrorKind::InvalidInput, "OPT pseudo records have no canonical form"));
+	}
+
+	let mut buffer = VectorPacketBuffer::new();
+	canonical_record(record).write(&mut buffer)?;
+	Ok(buffer.into_inner())
+}
+
+/// Canonical RDATA of a record, i.e. the canonical wire format without owner, type, class,
+/// TTL and RDLENGTH.
+pub fn canonical_rdata(record: &DNSRecord) -> Result<Vec<u8>> {
+	let owner_len = canonical_name(&record.get_domain().unwrap_or_default()).len();
+	let wire = canonical_wire(record)?;
+	Ok(wire[owner_len + 10..].to_vec())
+}
+
+/// Sort an RRset into canonical order (RFC 4034 section 6.3), comparing the canonical RDATA
+/// as left justified unsigned octet sequences, and drop duplicate records.
+pub fn sort_rrset(records: &mut Vec<DNSRecord>) -> Result<()> {
+	let mut keyed = records.drain(..)
+		.map(|record| canonical_rdata(&record).map(|rdata| (rdata, record)))
+		.collect::<Result<Vec<_>>>()?;
+
+	keyed.sort_by(|a, b| a.0.cmp(&b.0));
+	keyed.dedup_by(|a, b| a.0 == b.0);
+
+	records.extend(keyed.into_iter().map(|(_, record)| record));
+	Ok(())
+}
+
+/// Canonical wire format of a whole RRset in canonical order, as covered by a signature.
+pub fn canonical_rrset(records: &[DNSRecord]) -> Result<Vec<u8>> {
+	let mut sorted = records.to_vec();
+	sort_rrset(&mut sorted)?;
+
+	let mut wire = Vec::new();
+	for record in &sorted {
+		wire.extend(canonical_wire(record)?);
+	}
+	Ok(wire)
+}
@@ -0,0 +1,94 @@
+// DNSKEY and DS records: key tags, secure entry points and the digests parents publish of the
+// keys of their children (RFC 4034).
+
+use sha2::{ Digest, Sha256, Sha384 };
+
+use crate::server::dnssec::canonical::canonical_name;
+use crate::server::protocol::{ DNSRecord, TransientTTL };
+
+/// DNSKEY flag bits (RFC 4034 section 2.1.1 and RFC 5011 section 3)...
+pub const DNSKEY_FLAG_ZONE: u16 = 0x0100;
+pub const DNSKEY_FLAG_REVOKE: u16 = 0x0080;
+pub const DNSKEY_FLAG_SEP: u16 = 0x0001;
+
+/// Key tag of a DNSKEY as defined in RFC 4034 Appendix B.
+pub fn key_tag(key: &DNSRecord) -> Option<u16> {
+	let rdata = dnskey_rdata(key)?;
+	let mut ac: u32 = 0;
+	for (i, b) in rdata.iter().enumerate() {
+		ac += if i & 1 == 1 { *b as u32 } else { (*b as u32) << 8 };
+	}
+	ac += (ac >> 16) & 0xFFFF;
+	Some((ac & 0xFFFF) as u16)
+}
+
+/// Whether a DNSKEY is a secure entry point, the keys parents publish a DS for.
+pub fn is_sep(key: &DNSRecord) -> bool {
+	match *key {
+		DNSRecord::DNSKEY { flags, .. } => flags & DNSKEY_FLAG_ZONE != 0 && flags & DNSKEY_FLAG_SEP != 0,
+		_ => false,
+	}
+}
+
+fn is_revoked(key: &DNSRecord) -> bool {
+	match *key {
+		DNSRecord::DNSKEY { flags, .. } => flags & DNSKEY_FLAG_REVOKE != 0,
+		_ => false,
+	}
+}
+
+fn dnskey_rdata(key: &DNSRecord) -> Option<Vec<u8>> {
+	match *key {
+		DNSRecord::DNSKEY { flags, protocol, algorithm, ref public_key, .. } => {
+			let mut rdata = vec![(flags >> 8) as u8, (flags & 0xFF) as u8, protocol, algorithm];
+			rdata.extend_from_slice(public_key);
+			Some(rdata)
+		}
+		_ => None,
+	}
+}
+
+/// Check a DNSKEY against a DS record (RFC 4034 section 5.1.4). Only the SHA-256 and
+/// SHA-384 digest types are supported.
+pub fn ds_matches(ds: &DNSRecord, key: &DNSRecord) -> bool {
+	let (tag, alg, digest_type, digest) = match *ds {
+		DNSRecord::DS { key_tag, algorithm, digest_type, ref digest, .. } => (key_tag, algorithm, digest_type, digest),
+		_ => return false,
+	};
+	let key_alg = match *key {
+		DNSRecord::DNSKEY { algorithm, .. } => algorithm,
+		_ => return false,
+	};
+	if is_revoked(key) || key_alg != alg || key_tag(key) != Some(tag) {
+		return false;
+	}
+
+	let mut data = canonical_name(&key.get_domain().unwrap_or_default());
+	data.extend(dnskey_rdata(key).unwrap_or_default());
+
+	match digest_type {
+		2 => Sha256::digest(&data).as_slice() == digest.as_slice(),
+		4 => Sha384::digest(&data).as_slice() == digest.as_slice(),
+		_ => false,
+	}
+}
+
+/// The SHA-256 DS record of a DNSKEY (RFC 4509), as its parent zone would publish it with
+/// `ttl`. None for revoked keys and records which aren't DNSKEYs.
+pub fn ds_for_key(key: &DNSRecord, ttl: u32) -> Option<DNSRecord> {
+	let algorithm = match *key {
+		DNSRecord::DNSKEY { algorithm, .. } if !is_revoked(key) => algorithm,
+		_ => return None,
+	};
+	let domain = key.get_domain()?;
+	let mut data = canonical_name(&domain);
+	data.extend(dnskey_rdata(key)?);
+	Some(DNSRecord::DS {
+		domain,
+		key_tag: key_tag(key)?,
+		algorithm,
+		digest_type: 2,
+		digest: Sha256::digest(&data).to_vec(),
+		ttl: TransientTTL(ttl),
+	})
+}
@@ -0,0 +1,160 @@
+//! Rewriting the addresses answered to the clients by the side of the NAT they're on
+
+use std::fmt;
+use std::io::{ Error, ErrorKind, Result };
+use std::mem;
+use std::net::IpAddr;
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use crate::server::buffer::VectorPacketBuffer;
+use crate::server::capture::{ in_prefix, parse_prefix };
+use crate::server::context::InternalOverride;
+use crate::server::editor::PacketEditor;
+use crate::server::protocol::{ DNSPacket, DNSRecord };
+
+/// A host behind the NAT: its address inside the network and the one it's reached at from
+/// outside.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AddressPair {
+	pub internal: IpAddr,
+	pub external: IpAddr,
+}
+
+impl AddressPair {
+	/// Parse a pair given as `INTERNAL=EXTERNAL`, both of the same family.
+	pub fn parse(spec: &str) -> Result<AddressPair> {
+		let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid address pair: {}", spec));
+		let (internal, external) = spec.split_once('=').ok_or_else(invalid)?;
+		let internal = internal.trim().parse::<IpAddr>().map_err(|_| invalid())?;
+		let external = external.trim().parse::<IpAddr>().map_err(|_| invalid())?;
+		if internal.is_ipv4() != external.is_ipv4() {
+			return Err(invalid());
+		}
+		Ok(AddressPair { internal, external })
+	}
+}
+
+impl fmt::Display for AddressPair {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}={}", self.internal, self.external)
+	}
+}
+
+/// DNS doctoring, as NAT gateways do it: clients inside the network asking for a host behind
+/// the NAT get its internal address in place of the external one, which the gateway may not
+/// hairpin, and clients outside get the external address in place of the internal one, which
+/// they can't reach. Only the A and AAAA records of the answer section are rewritten, once
+/// the response is complete, so the cache keeps the addresses as they were resolved.
+///
+/// Clients are inside when they're in one of the inside networks, or, without any, when
+/// their address is a private, loopback or link-local one (see `InternalOverride`).
+#[derive(Debug, Default)]
+pub struct DnsDoctor {
+	pairs: Vec<AddressPair>,
+	inside: Vec<(IpAddr, u8)>,
+	inside_rewrites: AtomicU64,
+	outside_rewrites: AtomicU64,
+}
+
+impl DnsDoctor {
+	pub fn new() -> Self {
+		DnsDoctor::default()
+	}
+
+	/// Add the pair given as `INTERNAL=EXTERNAL`.
+	pub fn add_pair(&mut self, spec: &str) -> Result<()> {
+		self.pairs.push(AddressPair::parse(spec)?);
+		Ok(())
+	}
+
+	/// Count the clients of `network`, given as `ADDR` or `ADDR/LEN`, as inside.
+	pub fn add_inside(&mut self, network: &str) -> Result<()> {
+		let network = parse_prefix(network)
+			.ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Invalid network: {}", network)))?;
+		self.inside.push(network);
+		Ok(())
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.pairs.is_empty()
+	}
+
+	pub fn pairs(&self) -> &[AddressPair] {
+		&self.pairs
+	}
+
+	/// The inside networks, none meaning the private address ranges.
+	pub fn inside(&self) -> &[(IpAddr, u8)] {
+		&self.inside
+	}
+
+	/// Whether `client` is inside the network.
+	pub fn is_inside(&self, client: IpAddr) -> bool {
+		if self.inside.is_empty() {
+			return InternalOverride::is_internal(client);
+		}
+		self.inside.iter().any(|(network, len)| in_prefix(client, *network, *len))
+	}
+
+	/// Number of addresses rewritten for the clients inside, and for those outside.
+	pub fn rewrites(&self) -> (u64, u64) {
+		(self.inside_rewrites.load(Ordering::Relaxed), self.outside_rewrites.load(Ordering::Relaxed))
+	}
+
+	/// What `addr` becomes for a client on the given side.
+	fn translate(&self, inside: bool, addr: IpAddr) -> Option<IpAddr> {
+		self.pairs.iter().find_map(|pair| match inside {
+			true if pair.external == addr => Some(pair.internal),
+			false if pair.internal == addr => Some(pair.external),
+			_ => None,
+		})
+	}
+
+	/// Rewrite the addresses of `response`, going back to `client` as `message`, for the side
+	/// of the client. Returns the message to send, edited in place when anything was
+	/// rewritten.
+	pub fn apply(&self, client: IpAddr, response: &mut DNSPacket, message: Vec<u8>) -> Result<Vec<u8>> {
+		if self.pairs.is_empty() {
+			return Ok(message);
+		}
+		let inside = self.is_inside(client);
+		let mut rewrites = Vec::new();
+		for record in response.answers.iter_mut() {
+			let (from, to) = match *record {
+				DNSRecord::A { ref mut addr, .. } => match self.translate(inside, IpAddr::V4(*addr)) {
+					Some(IpAddr::V4(to)) => (IpAddr::V4(mem::replace(addr, to)), IpAddr::V4(to)),
+					_ => continue,
+				},
+				DNSRecord::AAAA { ref mut addr, .. } => match self.translate(inside, IpAddr::V6(*addr)) {
+					Some(IpAddr::V6(to)) => (IpAddr::V6(mem::replace(addr, to)), IpAddr::V6(to)),
+					_ => continue,
+				},
+				_ => continue,
+			};
+			if !rewrites.contains(&(from, to)) {
+				rewrites.push((from, to));
+			}
+			let counter = if inside { &self.inside_rewrites } else { &self.outside_rewrites };
+			counter.fetch_add(1, Ordering::Relaxed);
+		}
+		if rewrites.is_empty() {
+			return Ok(message);
+		}
+		debug!("Rewrote {} for {}", rewrites.iter().map(|(from, to)| format!("{} to {}", from, to)).collect::<Vec<_>>().join(", "), client);
+
+		// The message is edited rather than written again, to keep what the upstream sent...
+		match PacketEditor::new(message) {
+			Ok(mut editor) => {
+				for (from, to) in rewrites {
+					editor.rewrite_address(from, to);
+				}
+				Ok(editor.into_bytes())
+			}
+			Err(_) => {
+				let mut buffer = VectorPacketBuffer::new();
+				response.write(&mut buffer)?;
+				Ok(buffer.into_inner())
+			}
+		}
+	}
+}
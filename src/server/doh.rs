@@ -0,0 +1,315 @@
+//! DNS over HTTPS listener (RFC 8484)
+
+use std::io::{ BufRead, BufReader, Error, ErrorKind, Read, Result, Write };
+use std::net::{ Shutdown, SocketAddr, TcpListener, TcpStream };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::Arc;
+use std::thread::{ self, JoinHandle };
+use std::time::Instant;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use base64::Engine;
+use rustls::{ ServerConfig, ServerConnection, StreamOwned };
+
+use crate::server::context::{ Listener, ServerContext, ServerRole };
+use crate::server::handler::{ handle_request, parse_request };
+use crate::server::latency::QueryTiming;
+use crate::server::protocol::{ DNSPacket, DNSRecord };
+use crate::server::resolve::{ DynamicResolvers, ResolverFactory };
+use crate::server::upstream::Transport;
+
+/// Port DNS over HTTPS is served on unless told otherwise...
+pub const DEFAULT_HTTPS_PORT: u16 = 443;
+/// Path of the endpoint unless told otherwise, the one RFC 8484 uses in its examples...
+pub const DEFAULT_HTTPS_PATH: &str = "/dns-query";
+
+/// Media type of DNS messages carried over HTTP (RFC 8484 section 6)...
+const DNS_MESSAGE: &str = "application/dns-message";
+/// Limits on the requests, against clients trying to exhaust memory...
+const MAX_LINE: usize = 8192;
+const MAX_HEADERS: usize = 64;
+const MAX_MESSAGE: usize = 65535;
+
+type HttpsStream = BufReader<StreamOwned<ServerConnection, TcpStream>>;
+
+/// The query of a request and its wire format, then the response and its wire format.
+type Answered = (DNSPacket, Vec<u8>, DNSPacket, Vec<u8>);
+
+/// DNS over HTTPS listener, speaking HTTP/1.1 over TLS. Queries are taken at a single path,
+/// in the `dns` parameter of GET requests, base64url encoded, or as the body of POST
+/// requests. Responses can be cached by HTTP caches for as long as their shortest TTL.
+///
+/// Requests on a connection are answered in turn, as HTTP/1.1 has it. Idle connections are
+/// closed and connections are limited like on the TCP listener, by `tcp_idle_timeout` and
+/// `tcp_max_connections`.
+pub struct DNSHttpsServer<F: ResolverFactory = DynamicResolvers> {
+	context: Arc<ServerContext>,
+	resolvers: Arc<F>,
+	config: Arc<ServerConfig>,
+	addr: SocketAddr,
+	path: String,
+	role: ServerRole,
+	connections: Arc<AtomicUsize>,
+}
+
+impl DNSHttpsServer {
+	pub fn new(context: Arc<ServerContext>, config: Arc<ServerConfig>, addr: SocketAddr) -> Self {
+		DNSHttpsServer::with_resolvers(context, config, addr, DynamicResolvers)
+	}
+}
+
+impl<F: ResolverFactory> DNSHttpsServer<F> {
+	/// The TLS configuration is the one of the DNS over TLS listener, with HTTP/1.1 as the
+	/// ALPN protocol instead.
+	pub fn with_resolvers(context: Arc<ServerContext>, config: Arc<ServerConfig>, addr: SocketAddr, resolvers: F) -> Self {
+		let mut config = (*config).clone();
+		config.alpn_protocols = vec![b"http/1.1".to_vec()];
+		Self {
+			context,
+			resolvers: Arc::new(resolvers),
+			config: Arc::new(config),
+			addr,
+			path: DEFAULT_HTTPS_PATH.to_string(),
+			role: ServerRole::Full,
+			connections: Arc::new(AtomicUsize::new(0)),
+		}
+	}
+
+	/// Take the queries at `path` instead.
+	pub fn with_path(mut self, path: &str) -> Self {
+		self.path = path.to_string();
+		self
+	}
+
+	/// Serve in `role` instead of the full one.
+	pub fn with_role(mut self, role: ServerRole) -> Self {
+		self.role = role;
+		self
+	}
+
+	/// Bind the socket and start accepting connections. The returned handle belongs to the
+	/// accepting thread, which runs for as long as the socket does.
+	pub fn run_server(self) -> Result<JoinHandle<()>> {
+		let listener = TcpListener::bind(self.addr)?;
+		let server = Arc::new(self);
+
+		let handle = thread::Builder::new()
+			.name("DNSHttpsServer-incoming".to_string())
+			.spawn(move || {
+				for stream in listener.incoming() {
+					let stream = match stream {
+						Ok(stream) => stream,
+						Err(e) => {
+							info!("Failed to accept HTTPS connection: {}", e);
+							continue;
+						}
+					};
+
+					if server.connections.fetch_add(1, Ordering::SeqCst) >= server.context.tcp_max_connections {
+						server.connections.fetch_sub(1, Ordering::SeqCst);
+						let _ = stream.shutdown(Shutdown::Both);
+						continue;
+					}
+
+					let connection = server.clone();
+					let spawned = thread::Builder::new()
+						.name("DNSHttpsServer-connection".to_string())
+						.spawn(move || {
+							if let Err(e) = connection.serve_connection(stream) {
+								info!("HTTPS connection failed: {}", e);
+							}
+							connection.connections.fetch_sub(1, Ordering::SeqCst);
+						});
+					if let Err(e) = spawned {
+						info!("Failed to start a thread for an HTTPS connection: {}", e);
+						server.connections.fetch_sub(1, Ordering::SeqCst);
+					}
+				}
+			})?;
+
+		Ok(handle)
+	}
+
+	/// Answer the requests of a connection until the client closes it, asks for it to be
+	/// closed, or sits idle for too long.
+	fn serve_connection(&self, stream: TcpStream) -> Result<()> {
+		let src = stream.peer_addr()?;
+		stream.set_read_timeout(Some(self.context.tcp_idle_timeout))?;
+		stream.set_write_timeout(Some(self.context.tcp_idle_timeout))?;
+		let tls = ServerConnection::new(self.config.clone()).map_err(Error::other)?;
+		let mut stream = BufReader::new(StreamOwned::new(tls, stream));
+
+		loop {
+			let request = match read_request(&mut stream) {
+				Ok(Some(request)) => request,
+				Ok(None) => break,
+				Err(ref e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::UnexpectedEof) => break,
+				Err(e) => {
+					let _ = write_response(&mut stream, 400, "Bad Request", None, &[], true);
+					return Err(e);
+				}
+			};
+			let close = !request.keep_alive;
+
+			let mut timing = QueryTiming::new(Instant::now());
+			match self.answer(&request, src, &mut timing) {
+				Ok((query, message, response, res_bytes)) => {
+					write_response(&mut stream, 200, "OK", max_age(&response), &res_bytes, close)?;
+					timing.stage("send");
+					self.context.record_query(src, query.questions.first(), response.header.rescode, &timing);
+					if let Some(capture) = self.context.capture() {
+						if let Err(e) = capture.record(src, Transport::Https, &query, &message, &res_bytes) {
+							info!("Failed to capture the query from {}: {}", src, e);
+						}
+					}
+				}
+				Err((status, reason)) => write_response(&mut stream, status, reason, None, &[], close)?,
+			}
+
+			if close {
+				break;
+			}
+		}
+
+		let stream = stream.get_mut();
+		stream.conn.send_close_notify();
+		let _ = stream.flush();
+		Ok(())
+	}
+
+	/// Resolve the query of a request. Returns the query and the response, each with its wire
+	/// format, or the status to answer the request with when it doesn't carry a valid query.
+	fn answer(&self, request: &HttpRequest, src: SocketAddr, timing: &mut QueryTiming) -> std::result::Result<Answered, (u16, &'static str)> {
+		let message = self.message_of(request)?;
+		let query = parse_request(&self.context, &message).map_err(|e| {
+			info!("Failed to parse HTTPS query packet from {}: {}", src, e);
+			(400, "Bad Request")
+		})?;
+		let (response, res_bytes) = handle_request(&self.context, &self.resolvers, Listener { role: self.role, transport: Transport::Https }, &query, &message, src, timing).map_err(|e| {
+			info!("Failed to write response to {}: {}", src, e);
+			(500, "Internal Server Error")
+		})?.ok_or((403, "Forbidden"))?;
+		Ok((query, message, response, res_bytes))
+	}
+
+	/// The DNS message a request carries, or the status to answer it with when it doesn't
+	/// carry one.
+	fn message_of(&self, request: &HttpRequest) -> std::result::Result<Vec<u8>, (u16, &'static str)> {
+		let (path, query) = request.target.split_once('?').unwrap_or((&request.target, ""));
+		if path != self.path {
+			return Err((404, "Not Found"));
+		}
+
+		match request.method.as_str() {
+			"GET" => {
+				let dns = query.split('&')
+					.find_map(|param| param.strip_prefix("dns="))
+					.ok_or((400, "Bad Request"))?;
+				// Padding isn't used (RFC 8484 section 4.1), but costs nothing to accept...
+				BASE64URL.decode(dns.trim_end_matches('=')).map_err(|_| (400, "Bad Request"))
+			}
+			"POST" => {
+				if request.content_type.as_deref() != Some(DNS_MESSAGE) {
+					return Err((415, "Unsupported Media Type"));
+				}
+				Ok(request.body.clone())
+			}
+			_ => Err((405, "Method Not Allowed")),
+		}
+	}
+}
+
+/// What's needed of an HTTP request.
+#[derive(Debug)]
+pub(crate) struct HttpRequest {
+	pub method: String,
+	pub target: String,
+	pub content_type: Option<String>,
+	pub body: Vec<u8>,
+	pub keep_alive: bool,
+}
+
+/// Read the next request of a connection, None if the client closed it in between requests.
+pub(crate) fn read_request<R: BufRead>(stream: &mut R) -> Result<Option<HttpRequest>> {
+	let line = match read_line(stream)? {
+		Some(line) => line,
+		None => return Ok(None),
+	};
+	let invalid = || Error::new(ErrorKind::InvalidData, format!("Invalid request line: {}", line));
+	let mut parts = line.split_whitespace();
+	let method = parts.next().ok_or_else(invalid)?.to_string();
+	let target = parts.next().ok_or_else(invalid)?.to_string();
+	let version = parts.next().ok_or_else(invalid)?;
+
+	let mut keep_alive = version == "HTTP/1.1";
+	let mut content_type = None;
+	let mut content_length = 0;
+	for _ in 0..=MAX_HEADERS {
+		let line = read_line(stream)?.ok_or_else(|| Error::from(ErrorKind::UnexpectedEof))?;
+		if line.is_empty() {
+			let mut body = vec![0; content_length];
+			stream.read_exact(&mut body)?;
+			return Ok(Some(HttpRequest { method, target, content_type, body, keep_alive }));
+		}
+
+		let (name, value) = line.split_once(':').ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid header"))?;
+		let value = value.trim();
+		match name.trim().to_lowercase().as_str() {
+			"content-type" => content_type = Some(value.to_lowercase()),
+			"content-length" => {
+				content_length = value.parse().ok()
+					.filter(|len| *len <= MAX_MESSAGE)
+					.ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid content length"))?;
+			}
+			"connection" if value.eq_ignore_ascii_case("close") => keep_alive = false,
+			"connection" if value.eq_ignore_ascii_case("keep-alive") => keep_alive = true,
+			"transfer-encoding" => return Err(Error::new(ErrorKind::InvalidData, "Transfer encodings aren't supported")),
+			_ => (),
+		}
+	}
+	Err(Error::new(ErrorKind::InvalidData, "Too many headers"))
+}
+
+/// Read a line without its CRLF, None at the end of the stream.
+fn read_line<R: BufRead>(stream: &mut R) -> Result<Option<String>> {
+	let mut line = Vec::new();
+	let len = stream.by_ref().take(MAX_LINE as u64).read_until(b'\n', &mut line)?;
+	if len == 0 {
+		return Ok(None);
+	}
+	if line.last() != Some(&b'\n') {
+		return Err(Error::new(ErrorKind::InvalidData, "Line too long"));
+	}
+	let line = String::from_utf8(line).map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid header encoding"))?;
+	Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+fn write_response(stream: &mut HttpsStream, status: u16, reason: &str, max_age: Option<u32>, body: &[u8], close: bool) -> Result<()> {
+	let mut head = format!("HTTP/1.1 {} {}\r\nContent-Length: {}\r\n", status, reason, body.len());
+	if status == 200 {
+		head.push_str(&format!("Content-Type: {}\r\n", DNS_MESSAGE));
+	}
+	if let Some(max_age) = max_age {
+		head.push_str(&format!("Cache-Control: max-age={}\r\n", max_age));
+	}
+	if close {
+		head.push_str("Connection: close\r\n");
+	}
+	head.push_str("\r\n");
+
+	let stream = stream.get_mut();
+	stream.write_all(head.as_bytes())?;
+	stream.write_all(body)?;
+	stream.flush()
+}
+
+/// Freshness of a response for HTTP caches: the smallest TTL of its records (RFC 8484
+/// section 5.1), None without any.
+fn max_age(response: &DNSPacket) -> Option<u32> {
+	response.answers.iter()
+		.chain(&response.authorities)
+		.chain(&response.additional)
+		.filter(|record| !matches!(record, DNSRecord::OPT { .. }))
+		.map(|record| record.get_ttl())
+		.min()
+}
@@ -0,0 +1,221 @@
+//! Editing messages in wire format, leaving everything that isn't edited as it was
+
+use std::convert::TryFrom;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::IpAddr;
+
+use crate::server::protocol::QueryType;
+
+/// Length of the header, and offsets of its section counts...
+const HEADER_LEN: usize = 12;
+const QDCOUNT: usize = 4;
+/// Compression pointers followed in a name before it's deemed a loop...
+const MAX_JUMPS: usize = 64;
+const TYPE_OPT: u16 = 41;
+
+/// Section of a message holding resource records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Section {
+	Answer,
+	Authority,
+	Additional,
+}
+
+/// A resource record of a message, and where its fields sit in the message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordSpan {
+	pub section: Section,
+	/// Owner name, pointers followed, in the case it's written in...
+	pub name: String,
+	pub r_type: u16,
+	pub class: u16,
+	ttl_offset: usize,
+	rdata_offset: usize,
+	rdata_len: usize,
+}
+
+impl RecordSpan {
+	pub fn q_type(&self) -> QueryType {
+		QueryType::from_num(self.r_type)
+	}
+
+	/// Whether the record is the OPT pseudo-record, whose TTL field holds the EDNS flags.
+	pub fn is_opt(&self) -> bool {
+		self.r_type == TYPE_OPT
+	}
+}
+
+/// Edits a few fields of a message in wire format, say the ID of a query relayed on behalf
+/// of a client, an address of an answer or the TTLs, for proxies and NAT helpers. The
+/// message is indexed rather than parsed into a `DNSPacket`, and every edit is made in place
+/// on fields of fixed size, so that what isn't edited comes out byte for byte as it came in:
+/// the case of the names and their compression, records of unknown types and EDNS options
+/// all included.
+#[derive(Clone, Debug)]
+pub struct PacketEditor {
+	message: Vec<u8>,
+	records: Vec<RecordSpan>,
+}
+
+impl PacketEditor {
+	/// Index the records of `message`, failing when it's cut short or a name loops.
+	pub fn new(message: Vec<u8>) -> Result<PacketEditor> {
+		if message.len() < HEADER_LEN {
+			return Err(invalid("The message is shorter than its header"));
+		}
+		let count = |index: usize| u16::from_be_bytes([message[QDCOUNT + 2 * index], message[QDCOUNT + 2 * index + 1]]);
+
+		let mut pos = HEADER_LEN;
+		for _ in 0..count(0) {
+			pos = read_name(&message, pos)?.1 + 4;
+		}
+
+		let mut records = Vec::new();
+		let sections = [(Section::Answer, count(1)), (Section::Authority, count(2)), (Section::Additional, count(3))];
+		for (section, count) in sections {
+			for _ in 0..count {
+				let (name, end) = read_name(&message, pos)?;
+				let fixed = message.get(end..end + 10).ok_or_else(|| invalid("A record is cut short"))?;
+				let rdata_len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+				let record = RecordSpan {
+					section,
+					name,
+					r_type: u16::from_be_bytes([fixed[0], fixed[1]]),
+					class: u16::from_be_bytes([fixed[2], fixed[3]]),
+					ttl_offset: end + 4,
+					rdata_offset: end + 10,
+					rdata_len,
+				};
+				pos = record.rdata_offset + rdata_len;
+				if pos > message.len() {
+					return Err(invalid("The data of a record is cut short"));
+				}
+				records.push(record);
+			}
+		}
+		Ok(PacketEditor { message, records })
+	}
+
+	pub fn id(&self) -> u16 {
+		u16::from_be_bytes([self.message[0], self.message[1]])
+	}
+
+	pub fn set_id(&mut self, id: u16) {
+		self.message[..2].copy_from_slice(&id.to_be_bytes());
+	}
+
+	/// The records of the message, in order; edits refer to them by their index.
+	pub fn records(&self) -> &[RecordSpan] {
+		&self.records
+	}
+
+	/// The data of the record at `index`, as it's written.
+	pub fn rdata(&self, index: usize) -> Option<&[u8]> {
+		let record = self.records.get(index)?;
+		Some(&self.message[record.rdata_offset..record.rdata_offset + record.rdata_len])
+	}
+
+	pub fn ttl(&self, index: usize) -> Option<u32> {
+		let record = self.records.get(index).filter(|record| !record.is_opt())?;
+		let ttl = &self.message[record.ttl_offset..record.ttl_offset + 4];
+		Some(u32::from_be_bytes([ttl[0], ttl[1], ttl[2], ttl[3]]))
+	}
+
+	/// Set the TTL of the record at `index`, which can't be the OPT record.
+	pub fn set_ttl(&mut self, index: usize, ttl: u32) -> Result<()> {
+		let record = self.records.get(index).ok_or_else(|| invalid("No such record"))?;
+		if record.is_opt() {
+			return Err(invalid("The OPT record has no TTL"));
+		}
+		self.message[record.ttl_offset..record.ttl_offset + 4].copy_from_slice(&ttl.to_be_bytes());
+		Ok(())
+	}
+
+	/// Set the TTL of every record but the OPT record to what `adjust` makes of it, say
+	/// capping them or taking the time spent in a cache off.
+	pub fn adjust_ttls<F: FnMut(u32) -> u32>(&mut self, mut adjust: F) {
+		for index in 0..self.records.len() {
+			if let Some(ttl) = self.ttl(index) {
+				let _ = self.set_ttl(index, adjust(ttl));
+			}
+		}
+	}
+
+	/// The address of the A or AAAA record at `index`.
+	pub fn address(&self, index: usize) -> Option<IpAddr> {
+		let record = self.records.get(index).filter(|record| record.class == 1)?;
+		let rdata = self.rdata(index)?;
+		match record.q_type() {
+			QueryType::A => <[u8; 4]>::try_from(rdata).ok().map(IpAddr::from),
+			QueryType::AAAA => <[u8; 16]>::try_from(rdata).ok().map(IpAddr::from),
+			_ => None,
+		}
+	}
+
+	/// Set the address of the A or AAAA record at `index` to `addr`, of the same family.
+	pub fn set_address(&mut self, index: usize, addr: IpAddr) -> Result<()> {
+		if self.address(index).is_none_or(|current| current.is_ipv4() != addr.is_ipv4()) {
+			return Err(invalid(&format!("Record {} isn't an address record for {}", index, addr)));
+		}
+		let offset = self.records[index].rdata_offset;
+		match addr {
+			IpAddr::V4(v4) => self.message[offset..offset + 4].copy_from_slice(&v4.octets()),
+			IpAddr::V6(v6) => self.message[offset..offset + 16].copy_from_slice(&v6.octets()),
+		}
+		Ok(())
+	}
+
+	/// Replace `from` with `to` in the A or AAAA records of the answer section. Returns how
+	/// many were.
+	pub fn rewrite_address(&mut self, from: IpAddr, to: IpAddr) -> usize {
+		let mut rewritten = 0;
+		for index in 0..self.records.len() {
+			if self.records[index].section == Section::Answer && self.address(index) == Some(from) && self.set_address(index, to).is_ok() {
+				rewritten += 1;
+			}
+		}
+		rewritten
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.message
+	}
+
+	/// The message with its edits.
+	pub fn into_bytes(self) -> Vec<u8> {
+		self.message
+	}
+}
+
+fn invalid(message: &str) -> Error {
+	Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Read the name at `pos` of `message` as it's written, following pointers. Returns it along
+/// with the position following it where it starts.
+fn read_name(message: &[u8], pos: usize) -> Result<(String, usize)> {
+	let mut labels = Vec::new();
+	let mut at = pos;
+	let mut end = None;
+	let mut jumps = 0;
+	loop {
+		let len = *message.get(at).ok_or_else(|| invalid("A name is cut short"))?;
+		if len & 0xC0 == 0xC0 {
+			let low = *message.get(at + 1).ok_or_else(|| invalid("A name is cut short"))?;
+			end.get_or_insert(at + 2);
+			jumps += 1;
+			if jumps > MAX_JUMPS {
+				return Err(invalid("A name loops"));
+			}
+			at = (((len & 0x3F) as usize) << 8) | low as usize;
+			continue;
+		}
+		if len == 0 {
+			break;
+		}
+		let label = message.get(at + 1..at + 1 + len as usize).ok_or_else(|| invalid("A name is cut short"))?;
+		labels.push(String::from_utf8_lossy(label));
+		at += 1 + len as usize;
+	}
+	Ok((labels.join("."), end.unwrap_or(at + 1)))
+}
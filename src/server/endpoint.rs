@@ -0,0 +1,232 @@
+//! Health checks of the endpoints the addresses of the local zones stand for
+
+use std::fmt;
+use std::io::{ Error, ErrorKind, Read, Result, Write };
+use std::net::{ IpAddr, SocketAddr, TcpStream };
+use std::sync::atomic::{ AtomicBool, AtomicU32, AtomicU64, Ordering };
+use std::thread;
+use std::time::Duration;
+
+use crate::server::protocol::{ DNSPacket, DNSRecord, QueryType };
+
+/// Time a probe gets to connect and, over HTTP, to get the status line back...
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Consecutive failed probes after which an endpoint is withheld...
+const FAILURE_THRESHOLD: u32 = 2;
+/// Consecutive successful probes after which a withheld endpoint is answered again...
+const RECOVERY_THRESHOLD: u32 = 2;
+
+/// How an endpoint is probed: by connecting over TCP, given as `tcp:PORT`, or by a GET
+/// request over HTTP, given as `http:PORT[/PATH]`, which has to get a 2xx or 3xx status.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Probe {
+	Tcp { port: u16 },
+	Http { port: u16, path: String },
+}
+
+impl Probe {
+	pub fn parse(spec: &str) -> Result<Probe> {
+		let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid probe: {}", spec));
+		let (scheme, rest) = spec.split_once(':').ok_or_else(invalid)?;
+		match scheme {
+			"tcp" => rest.parse::<u16>().map(|port| Probe::Tcp { port }).map_err(|_| invalid()),
+			"http" => {
+				let (port, path) = match rest.find('/') {
+					Some(slash) => (&rest[..slash], &rest[slash..]),
+					None => (rest, "/"),
+				};
+				let port = port.parse::<u16>().map_err(|_| invalid())?;
+				if path.chars().any(char::is_whitespace) {
+					return Err(invalid());
+				}
+				Ok(Probe::Http { port, path: path.to_string() })
+			}
+			_ => Err(invalid()),
+		}
+	}
+
+	/// Probe the endpoint at `addr`, asked for as `host` over HTTP.
+	fn run(&self, host: &str, addr: IpAddr) -> Result<()> {
+		let port = match *self {
+			Probe::Tcp { port } | Probe::Http { port, .. } => port,
+		};
+		let mut stream = TcpStream::connect_timeout(&SocketAddr::new(addr, port), PROBE_TIMEOUT)?;
+		let path = match *self {
+			Probe::Tcp { .. } => return Ok(()),
+			Probe::Http { ref path, .. } => path,
+		};
+		stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+		stream.set_write_timeout(Some(PROBE_TIMEOUT))?;
+		write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rdns\r\nConnection: close\r\n\r\n", path, host)?;
+
+		// The status line is all that's needed...
+		let mut head = [0u8; 64];
+		let mut len = 0;
+		while len < head.len() && !head[..len].contains(&b'\n') {
+			match stream.read(&mut head[len..])? {
+				0 => break,
+				read => len += read,
+			}
+		}
+		let line = String::from_utf8_lossy(&head[..len]);
+		let status = line.split_whitespace().nth(1).and_then(|status| status.parse::<u16>().ok());
+		match status {
+			Some(status) if (200..400).contains(&status) => Ok(()),
+			Some(status) => Err(Error::other(format!("HTTP status {}", status))),
+			None => Err(Error::new(ErrorKind::InvalidData, "Not an HTTP response")),
+		}
+	}
+}
+
+impl fmt::Display for Probe {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Probe::Tcp { port } => write!(f, "tcp:{}", port),
+			Probe::Http { port, ref path } => write!(f, "http:{}{}", port, path),
+		}
+	}
+}
+
+/// An address of a name of the local zones, and how it's probed.
+#[derive(Debug)]
+pub struct EndpointCheck {
+	pub name: String,
+	pub addr: IpAddr,
+	pub probe: Probe,
+	healthy: AtomicBool,
+	failures: AtomicU32,
+	successes: AtomicU32,
+}
+
+impl EndpointCheck {
+	pub fn is_healthy(&self) -> bool {
+		self.healthy.load(Ordering::Relaxed)
+	}
+
+	fn check(&self) {
+		match self.probe.run(&self.name, self.addr) {
+			Ok(()) => {
+				self.failures.store(0, Ordering::Relaxed);
+				let successes = self.successes.fetch_add(1, Ordering::Relaxed) + 1;
+				if successes >= RECOVERY_THRESHOLD && !self.healthy.swap(true, Ordering::Relaxed) {
+					info!("Endpoint {} of {} is back up, answering it again", self.addr, self.name);
+				}
+			}
+			Err(e) => {
+				self.successes.store(0, Ordering::Relaxed);
+				let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+				if failures >= FAILURE_THRESHOLD && self.healthy.swap(false, Ordering::Relaxed) {
+					info!("Endpoint {} of {} is down, withholding it: {}", self.addr, self.name, e);
+				}
+			}
+		}
+	}
+}
+
+/// Health checks of the addresses the local zones answer, for DNS based failover: the A and
+/// AAAA records of an endpoint failing its probes are left out of the answers until it
+/// passes them again. Endpoints are only withheld after failing twice in a row, and answered
+/// again after passing twice, so that a single lost probe doesn't make them flap.
+///
+/// Endpoints are given as `NAME ADDR PROBE` (see `Probe`), and start out healthy. When every
+/// address of an RRset is down the RRset is answered whole, there being nothing better to
+/// answer with.
+#[derive(Debug, Default)]
+pub struct EndpointChecks {
+	checks: Vec<EndpointCheck>,
+	withheld: AtomicU64,
+}
+
+impl EndpointChecks {
+	pub fn new() -> Self {
+		EndpointChecks::default()
+	}
+
+	/// Probe the endpoint given as `NAME ADDR PROBE`.
+	pub fn add(&mut self, spec: &str) -> Result<()> {
+		let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid endpoint check: {}", spec));
+		let fields: Vec<&str> = spec.split_whitespace().collect();
+		let (name, addr, probe) = match fields[..] {
+			[name, addr, probe] => (name, addr, probe),
+			_ => return Err(invalid()),
+		};
+		let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+		self.checks.push(EndpointCheck {
+			name: normalize(name),
+			addr,
+			probe: Probe::parse(probe)?,
+			healthy: AtomicBool::new(true),
+			failures: AtomicU32::new(0),
+			successes: AtomicU32::new(0),
+		});
+		Ok(())
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.checks.is_empty()
+	}
+
+	pub fn checks(&self) -> &[EndpointCheck] {
+		&self.checks
+	}
+
+	/// Number of records left out of the answers.
+	pub fn withheld(&self) -> u64 {
+		self.withheld.load(Ordering::Relaxed)
+	}
+
+	/// Probe every endpoint once, all at the same time.
+	pub fn check_all(&self) {
+		thread::scope(|scope| {
+			for check in &self.checks {
+				scope.spawn(move || check.check());
+			}
+		});
+	}
+
+	/// Whether the endpoint `addr` of `name` is down.
+	fn is_down(&self, name: &str, addr: IpAddr) -> bool {
+		self.checks.iter().any(|check| check.addr == addr && check.name == name && !check.is_healthy())
+	}
+
+	/// Leave the A and AAAA records of the endpoints down out of the answers of `packet`,
+	/// unless all the addresses of their RRset are.
+	pub fn apply(&self, packet: &mut DNSPacket) {
+		if self.checks.is_empty() {
+			return;
+		}
+		let endpoints: Vec<Option<(String, QueryType, bool)>> = packet.answers.iter()
+			.map(|record| endpoint(record).map(|(name, q_type, addr)| {
+				let down = self.is_down(&name, addr);
+				(name, q_type, down)
+			}))
+			.collect();
+		if !endpoints.iter().flatten().any(|(_, _, down)| *down) {
+			return;
+		}
+		// An address down is withheld only when another one of its RRset is up...
+		let withhold: Vec<bool> = endpoints.iter()
+			.map(|endpoint| match *endpoint {
+				Some((ref name, q_type, true)) => endpoints.iter().flatten()
+					.any(|(other, other_type, down)| !down && *other_type == q_type && other == name),
+				_ => false,
+			})
+			.collect();
+		let mut withhold = withhold.into_iter();
+		packet.answers.retain(|_| !withhold.next().unwrap_or(false));
+		self.withheld.fetch_add(endpoints.len() as u64 - packet.answers.len() as u64, Ordering::Relaxed);
+	}
+}
+
+/// The name, type and address of an A or AAAA record.
+fn endpoint(record: &DNSRecord) -> Option<(String, QueryType, IpAddr)> {
+	match *record {
+		DNSRecord::A { ref domain, addr, .. } => Some((normalize(domain), QueryType::A, IpAddr::V4(addr))),
+		DNSRecord::AAAA { ref domain, addr, .. } => Some((normalize(domain), QueryType::AAAA, IpAddr::V6(addr))),
+		_ => None,
+	}
+}
+
+fn normalize(name: &str) -> String {
+	name.trim_end_matches('.').to_lowercase()
+}
@@ -0,0 +1,71 @@
+//! Last known good answers for critical names
+
+use std::collections::{ HashMap, HashSet };
+use std::sync::RwLock;
+
+use crate::server::cache::{ CacheKey, STALE_TTL };
+use crate::server::protocol::{ DNSPacket, QueryType, ResultCode };
+
+/// The last successful answer to each question about a critical name, say the endpoints of
+/// a VPN or of single sign-on, kept for as long as the server runs whatever its TTL. It's
+/// served when resolving the name fails altogether and the cache has no stale answer left
+/// either, so that such names stay reachable through an outage of the upstreams.
+///
+/// Unlike serve-stale there's no limit on the age of the answers, which is why it's only
+/// done for the names given.
+#[derive(Debug, Default)]
+pub struct LastKnownGood {
+	names: HashSet<String>,
+	// The answer and authority sections of the responses...
+	answers: RwLock<HashMap<CacheKey, DNSPacket>>,
+}
+
+impl LastKnownGood {
+	pub fn new() -> Self {
+		LastKnownGood::default()
+	}
+
+	/// Keep the answers to the questions about `name`.
+	pub fn add_name(&mut self, name: &str) {
+		self.names.insert(name.trim_end_matches('.').to_lowercase());
+	}
+
+	pub fn is_critical(&self, qname: &str) -> bool {
+		!self.names.is_empty() && self.names.contains(&qname.trim_end_matches('.').to_lowercase())
+	}
+
+	/// Remember `response` to a question about a critical name, when it answers it.
+	pub fn store(&self, qname: &str, q_type: QueryType, response: &DNSPacket) {
+		if response.header.rescode != ResultCode::NOERROR || response.answers.is_empty() || !self.is_critical(qname) {
+			return;
+		}
+		if let Ok(mut answers) = self.answers.write() {
+			let mut packet = DNSPacket::new();
+			packet.answers = response.answers.clone();
+			packet.authorities = response.authorities.clone();
+			answers.insert(CacheKey::new(qname, q_type), packet);
+		}
+	}
+
+	/// The last successful answer to a question about a critical name, with all TTLs set to
+	/// `STALE_TTL` like stale answers.
+	pub fn lookup(&self, qname: &str, q_type: QueryType) -> Option<DNSPacket> {
+		if !self.is_critical(qname) {
+			return None;
+		}
+		let mut packet = self.answers.read().ok()?.get(&CacheKey::new(qname, q_type))?.clone();
+		for record in packet.answers.iter_mut().chain(packet.authorities.iter_mut()) {
+			record.set_ttl(STALE_TTL);
+		}
+		Some(packet)
+	}
+
+	/// Number of answers kept.
+	pub fn len(&self) -> usize {
+		self.answers.read().map_or(0, |answers| answers.len())
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
@@ -0,0 +1,78 @@
+//! Withholding the addresses of one family from groups of clients
+
+use std::io::{ Error, ErrorKind, Result };
+use std::net::IpAddr;
+
+use crate::server::capture::{ in_prefix, parse_prefix };
+use crate::server::protocol::{ DNSPacket, QueryType };
+
+/// Keeps AAAA records from the clients of IPv4 only networks, and A records from those of
+/// IPv6 only ones, for dual-stack setups where clients try addresses they can't reach and
+/// time out before falling back.
+///
+/// The records are removed from the answer and additional sections of the responses to the
+/// clients of the networks given, whatever the query. A query for the withheld type thus
+/// gets NODATA (NOERROR without answers, CNAMEs kept) rather than NXDOMAIN, as the name
+/// does exist; other clients and the cache are unaffected.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FamilyFilter {
+	no_aaaa: Vec<(IpAddr, u8)>,
+	no_a: Vec<(IpAddr, u8)>,
+}
+
+impl FamilyFilter {
+	pub fn new() -> Self {
+		FamilyFilter::default()
+	}
+
+	/// Withhold the records of `q_type`, A or AAAA, from the clients of `network`, given as
+	/// `ADDR` or `ADDR/LEN`.
+	pub fn add(&mut self, q_type: QueryType, network: &str) -> Result<()> {
+		let network = parse_prefix(network)
+			.ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Invalid network: {}", network)))?;
+		match q_type {
+			QueryType::AAAA => self.no_aaaa.push(network),
+			QueryType::A => self.no_a.push(network),
+			_ => return Err(Error::new(ErrorKind::InvalidInput, format!("Only A and AAAA records can be withheld, not {}", q_type))),
+		}
+		Ok(())
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.no_aaaa.is_empty() && self.no_a.is_empty()
+	}
+
+	/// The networks the records of `q_type` are withheld from.
+	pub fn networks(&self, q_type: QueryType) -> &[(IpAddr, u8)] {
+		match q_type {
+			QueryType::AAAA => &self.no_aaaa,
+			QueryType::A => &self.no_a,
+			_ => &[],
+		}
+	}
+
+	/// Whether the records of `q_type` are withheld from `client`.
+	pub fn withholds(&self, client: IpAddr, q_type: QueryType) -> bool {
+		self.networks(q_type).iter().any(|(network, len)| in_prefix(client, *network, *len))
+	}
+
+	/// Whether anything is withheld from `client`.
+	pub fn applies_to(&self, client: IpAddr) -> bool {
+		self.withholds(client, QueryType::AAAA) || self.withholds(client, QueryType::A)
+	}
+
+	/// Remove the records withheld from `client` from `response`. Returns how many were.
+	pub fn apply(&self, client: IpAddr, response: &mut DNSPacket) -> usize {
+		let mut removed = 0;
+		for q_type in [QueryType::AAAA, QueryType::A] {
+			if !self.withholds(client, q_type) {
+				continue;
+			}
+			let before = response.answers.len() + response.additional.len();
+			response.answers.retain(|record| record.get_query_type() != q_type);
+			response.additional.retain(|record| record.get_query_type() != q_type);
+			removed += before - response.answers.len() - response.additional.len();
+		}
+		removed
+	}
+}
@@ -0,0 +1,531 @@
+//! Records served by where the clients are, from a MaxMind DB of the locations of addresses
+//!
+//! The database is a MaxMind DB file (`.mmdb`), like GeoLite2-Country or GeoIP2-City: a binary
+//! search tree over the bits of the addresses, whose leaves point into a data section of
+//! maps, followed by the metadata of the database.
+//!
+//! ```text
+//! search tree  node_count nodes of two records of record_size bits, left (bit 0) first
+//! separator    16 null bytes
+//! data         the values the tree points to, typed by a control byte
+//! metadata     "\xAB\xCD\xEFMaxMind.com", then a map of node_count, record_size, ...
+//! ```
+
+use std::fmt;
+use std::fs::File;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::IpAddr;
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use memmap2::Mmap;
+
+use crate::server::authority::ZoneData;
+use crate::server::protocol::{ DNSRecord, QueryType };
+use crate::server::zonefile::parse_entry;
+
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+/// Null bytes between the search tree and the data section...
+const DATA_SEPARATOR: usize = 16;
+/// Levels of maps and arrays a value may nest, past which the database is taken as corrupt...
+const MAX_DEPTH: usize = 32;
+
+fn invalid(msg: &str) -> Error {
+	Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// A value of the data section.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+	String(String),
+	Uint(u128),
+	Int(i32),
+	Double(f64),
+	Bytes(Vec<u8>),
+	Bool(bool),
+	Map(Vec<(String, Value)>),
+	Array(Vec<Value>),
+}
+
+impl Value {
+	fn get(&self, key: &str) -> Option<&Value> {
+		match *self {
+			Value::Map(ref entries) => entries.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+			_ => None,
+		}
+	}
+
+	fn as_str(&self) -> Option<&str> {
+		match *self {
+			Value::String(ref text) => Some(text),
+			_ => None,
+		}
+	}
+
+	fn as_uint(&self) -> Option<u128> {
+		match *self {
+			Value::Uint(num) => Some(num),
+			_ => None,
+		}
+	}
+}
+
+/// Reads the values of a data section, `data` starting where its pointers are counted from.
+struct Decoder<'a> {
+	data: &'a [u8],
+}
+
+impl Decoder<'_> {
+	fn bytes(&self, offset: usize, len: usize) -> Result<&[u8]> {
+		self.data.get(offset..offset + len).ok_or_else(|| invalid("Value past the end of the database"))
+	}
+
+	fn uint(&self, offset: usize, len: usize) -> Result<u128> {
+		Ok(self.bytes(offset, len)?.iter().fold(0u128, |num, byte| (num << 8) | *byte as u128))
+	}
+
+	/// The type and size of the value at `offset`, and where its payload starts. Pointers
+	/// come back with their size bits as they are.
+	fn control(&self, offset: usize) -> Result<(u8, usize, usize)> {
+		let control = self.bytes(offset, 1)?[0];
+		let mut next = offset + 1;
+		let mut kind = control >> 5;
+		if kind == 0 {
+			kind = 7 + self.bytes(next, 1)?[0];
+			next += 1;
+		}
+		if kind == 1 {
+			return Ok((kind, (control & 0x1f) as usize, next));
+		}
+		let (size, next) = match control & 0x1f {
+			29 => (29 + self.uint(next, 1)? as usize, next + 1),
+			30 => (285 + self.uint(next, 2)? as usize, next + 2),
+			31 => (65821 + self.uint(next, 3)? as usize, next + 3),
+			size => (size as usize, next),
+		};
+		Ok((kind, size, next))
+	}
+
+	/// Where the pointer with size bits `bits` and payload at `offset` points, and where
+	/// the pointer ends.
+	fn pointer(&self, bits: usize, offset: usize) -> Result<(usize, usize)> {
+		let (len, base) = match (bits >> 3) & 0x3 {
+			0 => (1, 0),
+			1 => (2, 2048),
+			2 => (3, 526336),
+			_ => (4, 0),
+		};
+		let high = if len == 4 { 0 } else { ((bits & 0x7) as u128) << (8 * len) };
+		Ok(((high | self.uint(offset, len)?) as usize + base, offset + len))
+	}
+
+	/// The value at `offset` and where the next one starts.
+	fn decode(&self, offset: usize, depth: usize) -> Result<(Value, usize)> {
+		if depth > MAX_DEPTH {
+			return Err(invalid("Values nested too deep"));
+		}
+		let (kind, size, next) = self.control(offset)?;
+		let value = match kind {
+			1 => {
+				let (target, end) = self.pointer(size, next)?;
+				return Ok((self.decode(target, depth + 1)?.0, end));
+			}
+			2 => Value::String(String::from_utf8_lossy(self.bytes(next, size)?).into_owned()),
+			3 => {
+				let bytes = self.bytes(next, 8)?;
+				Value::Double(f64::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]]))
+			}
+			4 => Value::Bytes(self.bytes(next, size)?.to_vec()),
+			5 | 6 | 9 | 10 => Value::Uint(self.uint(next, size.min(16))?),
+			7 => {
+				let mut entries = Vec::with_capacity(size.min(64));
+				let mut next = next;
+				for _ in 0..size {
+					let (key, after_key) = self.decode(next, depth + 1)?;
+					let key = key.as_str().ok_or_else(|| invalid("Map key which isn't a string"))?.to_string();
+					let (value, after_value) = self.decode(after_key, depth + 1)?;
+					entries.push((key, value));
+					next = after_value;
+				}
+				return Ok((Value::Map(entries), next));
+			}
+			8 => Value::Int(self.uint(next, size.min(4))? as u32 as i32),
+			11 => {
+				let mut items = Vec::with_capacity(size.min(64));
+				let mut next = next;
+				for _ in 0..size {
+					let (item, after) = self.decode(next, depth + 1)?;
+					items.push(item);
+					next = after;
+				}
+				return Ok((Value::Array(items), next));
+			}
+			14 => return Ok((Value::Bool(size != 0), next)),
+			15 => {
+				let bytes = self.bytes(next, 4)?;
+				Value::Double(f32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64)
+			}
+			_ => return Err(invalid("Unknown value type")),
+		};
+		let len = match kind {
+			3 => 8,
+			15 => 4,
+			_ => size,
+		};
+		Ok((value, next + len))
+	}
+
+	/// Where the value at `offset` ends, without decoding it.
+	fn skip(&self, offset: usize, depth: usize) -> Result<usize> {
+		if depth > MAX_DEPTH {
+			return Err(invalid("Values nested too deep"));
+		}
+		let (kind, size, next) = self.control(offset)?;
+		match kind {
+			1 => Ok(self.pointer(size, next)?.1),
+			3 => Ok(next + 8),
+			15 => Ok(next + 4),
+			14 => Ok(next),
+			7 | 11 => {
+				let values = if kind == 7 { size * 2 } else { size };
+				(0..values).try_fold(next, |next, _| self.skip(next, depth + 1))
+			}
+			_ => Ok(next + size),
+		}
+	}
+
+	/// The value at the end of `path`, a key per level of maps, in the map at `offset`.
+	fn lookup(&self, offset: usize, path: &[&str]) -> Result<Option<Value>> {
+		let (key, rest) = match path.split_first() {
+			Some(split) => split,
+			None => return self.decode(offset, 0).map(|(value, _)| Some(value)),
+		};
+		let (mut kind, mut size, mut next) = self.control(offset)?;
+		if kind == 1 {
+			let target = self.pointer(size, next)?.0;
+			let control = self.control(target)?;
+			kind = control.0;
+			size = control.1;
+			next = control.2;
+		}
+		if kind != 7 {
+			return Ok(None);
+		}
+		for _ in 0..size {
+			let (name, after_key) = self.decode(next, 0)?;
+			if name.as_str() == Some(key) {
+				return self.lookup(after_key, rest);
+			}
+			next = self.skip(after_key, 0)?;
+		}
+		Ok(None)
+	}
+}
+
+/// Where an address is, as far as the database knows, and the length of the prefix of the
+/// network it was found in, which the answers for it hold for.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GeoLocation {
+	/// Continent code, like `EU`.
+	pub continent: Option<String>,
+	/// ISO 3166-1 country code, like `DE`.
+	pub country: Option<String>,
+	pub prefix: u8,
+}
+
+/// A MaxMind DB mapped into memory, telling the continent and country of addresses.
+///
+/// The file must not be modified while it is mapped; replacing it by renaming a new file
+/// over it, as the updaters of the databases do, leaves the mapping intact.
+pub struct GeoDatabase {
+	path: PathBuf,
+	map: Mmap,
+	node_count: usize,
+	record_size: usize,
+	ip_version: u16,
+	database_type: String,
+	tree_size: usize,
+	// The node the IPv4 addresses start from in an IPv6 tree, that of ::/96...
+	ipv4_start: usize,
+	lookups: AtomicU64,
+	misses: AtomicU64,
+}
+
+impl GeoDatabase {
+	pub fn open<P: AsRef<Path>>(path: P) -> Result<GeoDatabase> {
+		let file = File::open(&path)?;
+		// Safe as long as nobody writes to the file in place, see above...
+		let map = unsafe { Mmap::map(&file)? };
+
+		let marker = map.windows(METADATA_MARKER.len())
+			.rposition(|window| window == METADATA_MARKER)
+			.ok_or_else(|| invalid("Not a MaxMind DB"))?;
+		let metadata_start = marker + METADATA_MARKER.len();
+		let metadata = Decoder { data: &map[metadata_start..] }.decode(0, 0)?.0;
+		let field = |key: &str| metadata.get(key).and_then(Value::as_uint).ok_or_else(|| invalid(&format!("No {} in the metadata", key)));
+		let node_count = field("node_count")? as usize;
+		let record_size = field("record_size")? as usize;
+		let ip_version = field("ip_version")? as u16;
+		if ![24, 28, 32].contains(&record_size) {
+			return Err(invalid("Unsupported record size"));
+		}
+		let tree_size = node_count * record_size / 4;
+		if tree_size + DATA_SEPARATOR > marker {
+			return Err(invalid("Truncated MaxMind DB"));
+		}
+		let database_type = metadata.get("database_type").and_then(Value::as_str).unwrap_or("unknown").to_string();
+
+		let mut db = GeoDatabase {
+			path: path.as_ref().to_path_buf(),
+			map,
+			node_count,
+			record_size,
+			ip_version,
+			database_type,
+			tree_size,
+			ipv4_start: 0,
+			lookups: AtomicU64::new(0),
+			misses: AtomicU64::new(0),
+		};
+		if ip_version == 6 {
+			let mut node = 0;
+			for _ in 0..96 {
+				if node >= node_count {
+					break;
+				}
+				node = db.record(node, false)?;
+			}
+			db.ipv4_start = node;
+		}
+		Ok(db)
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// The kind of database, like `GeoLite2-Country`.
+	pub fn database_type(&self) -> &str {
+		&self.database_type
+	}
+
+	/// Number of addresses looked up, and of those the database knew nothing of.
+	pub fn lookups(&self) -> (u64, u64) {
+		(self.lookups.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+	}
+
+	/// The record of `node` for the bit `right`.
+	fn record(&self, node: usize, right: bool) -> Result<usize> {
+		let start = node * self.record_size / 4;
+		let bytes = self.map.get(start..start + self.record_size / 4).ok_or_else(|| invalid("Node past the end of the tree"))?;
+		let be = |bytes: &[u8]| bytes.iter().fold(0usize, |num, byte| (num << 8) | *byte as usize);
+		Ok(match (self.record_size, right) {
+			(24, false) => be(&bytes[..3]),
+			(24, true) => be(&bytes[3..]),
+			(28, false) => ((bytes[3] as usize & 0xf0) << 20) | be(&bytes[..3]),
+			(28, true) => ((bytes[3] as usize & 0x0f) << 24) | be(&bytes[4..]),
+			(_, false) => be(&bytes[..4]),
+			(_, true) => be(&bytes[4..]),
+		})
+	}
+
+	/// Where `addr` is, None when the database doesn't know it or fails to be read.
+	pub fn lookup(&self, addr: IpAddr) -> Option<GeoLocation> {
+		self.lookups.fetch_add(1, Ordering::Relaxed);
+		let location = match self.find(addr) {
+			Ok(location) => location,
+			Err(e) => {
+				debug!("Failed to look up {} in {}: {}", addr, self.path.display(), e);
+				None
+			}
+		};
+		if location.is_none() {
+			self.misses.fetch_add(1, Ordering::Relaxed);
+		}
+		location
+	}
+
+	fn find(&self, addr: IpAddr) -> Result<Option<GeoLocation>> {
+		let (bits, start) = match addr.to_canonical() {
+			IpAddr::V4(v4) => (v4.octets().to_vec(), self.ipv4_start),
+			IpAddr::V6(_) if self.ip_version == 4 => return Ok(None),
+			IpAddr::V6(v6) => (v6.octets().to_vec(), 0),
+		};
+		let mut node = start;
+		let mut depth = 0;
+		while node < self.node_count && depth < bits.len() * 8 {
+			let bit = bits[depth / 8] & (0x80 >> (depth % 8)) != 0;
+			node = self.record(node, bit)?;
+			depth += 1;
+		}
+		if node <= self.node_count {
+			return Ok(None);
+		}
+		let offset = node - self.node_count - DATA_SEPARATOR;
+		let decoder = Decoder { data: self.map.get(self.tree_size + DATA_SEPARATOR..).unwrap_or(&[]) };
+		let code = |path: &[&str]| -> Result<Option<String>> {
+			Ok(decoder.lookup(offset, path)?.and_then(|value| value.as_str().map(str::to_string)))
+		};
+		let country = match code(&["country", "iso_code"])? {
+			Some(country) => Some(country),
+			None => code(&["registered_country", "iso_code"])?,
+		};
+		Ok(Some(GeoLocation { continent: code(&["continent", "code"])?, country, prefix: depth as u8 }))
+	}
+}
+
+/// Where the clients of a record set have to be: in a continent, given as `continent:CODE`
+/// (`AF`, `AN`, `AS`, `EU`, `NA`, `OC`, `SA`), or in a country, given as `country:CODE` with
+/// its ISO 3166-1 code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GeoPolicy {
+	Continent(String),
+	Country(String),
+}
+
+impl GeoPolicy {
+	pub fn parse(spec: &str) -> Result<GeoPolicy> {
+		let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid geo policy: {}", spec));
+		let (scope, code) = spec.split_once(':').ok_or_else(invalid)?;
+		if code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+			return Err(invalid());
+		}
+		let code = code.to_ascii_uppercase();
+		match scope {
+			"continent" => Ok(GeoPolicy::Continent(code)),
+			"country" => Ok(GeoPolicy::Country(code)),
+			_ => Err(invalid()),
+		}
+	}
+
+	fn matches(&self, location: &GeoLocation) -> bool {
+		match *self {
+			GeoPolicy::Continent(ref code) => location.continent.as_deref() == Some(code),
+			GeoPolicy::Country(ref code) => location.country.as_deref() == Some(code),
+		}
+	}
+}
+
+impl fmt::Display for GeoPolicy {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			GeoPolicy::Continent(ref code) => write!(f, "continent:{}", code),
+			GeoPolicy::Country(ref code) => write!(f, "country:{}", code),
+		}
+	}
+}
+
+/// A record set of a local zone served instead of the one of the zone to the clients its
+/// policy matches.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GeoRecordSet {
+	pub policy: GeoPolicy,
+	pub records: Vec<DNSRecord>,
+}
+
+impl GeoRecordSet {
+	fn name(&self) -> Option<String> {
+		self.records.first().and_then(DNSRecord::get_domain)
+	}
+
+	fn q_type(&self) -> Option<QueryType> {
+		self.records.first().map(DNSRecord::get_query_type)
+	}
+}
+
+/// Record sets replacing those of the local zones for the clients of some continents or
+/// countries, for basic global server load balancing: the endpoint of the closest region,
+/// say. The clients are located by their address, or by the client subnet (RFC 7871) of
+/// their queries when a resolver forwards them; those the database doesn't know, or which no
+/// policy matches, get the records of the zone.
+///
+/// The names have to lie in a local zone. A country policy beats a continent one, and among
+/// policies of a kind the first one given wins.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GeoRecords {
+	entries: Vec<GeoRecordSet>,
+}
+
+impl GeoRecords {
+	pub fn new() -> Self {
+		GeoRecords::default()
+	}
+
+	/// Parse a geo record given as `POLICY RECORD`, the record as a zone file entry with a
+	/// fully qualified owner.
+	///
+	/// Ex: `continent:EU www.example.com. 60 IN A 192.0.2.10`
+	pub fn parse(spec: &str) -> Result<(GeoPolicy, DNSRecord)> {
+		let spec = spec.trim();
+		let (policy, entry) = spec.split_once(char::is_whitespace)
+			.ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Invalid geo record: {}", spec)))?;
+		Ok((GeoPolicy::parse(policy)?, parse_entry(entry)?))
+	}
+
+	/// Serve `record` to the clients `policy` matches, along with the other records of its
+	/// name and type given for the same policy.
+	pub fn add(&mut self, policy: GeoPolicy, record: DNSRecord) {
+		let (name, q_type) = (record.get_domain(), record.get_query_type());
+		match self.entries.iter_mut().find(|entry| entry.policy == policy && entry.name() == name && entry.q_type() == Some(q_type)) {
+			Some(entry) => entry.records.push(record),
+			None => self.entries.push(GeoRecordSet { policy, records: vec![record] }),
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+
+	/// The records of `name` and `q_type` for a client at `location`. None when no policy of
+	/// theirs matches it.
+	pub fn lookup(&self, name: &str, q_type: QueryType, location: &GeoLocation) -> Option<Vec<DNSRecord>> {
+		let candidates = || self.entries.iter()
+			.filter(|entry| entry.q_type() == Some(q_type) && entry.name().as_deref() == Some(name) && entry.policy.matches(location));
+		candidates().find(|entry| matches!(entry.policy, GeoPolicy::Country(_)))
+			.or_else(|| candidates().next())
+			.map(|entry| entry.records.clone())
+	}
+
+	/// Whether records are given for `name` or a name below it.
+	pub fn has_name(&self, name: &str) -> bool {
+		self.entries.iter().any(|entry| entry.name().is_some_and(|owner| owner == name || owner.ends_with(&format!(".{}", name))))
+	}
+
+	/// Whether the answers for `name` depend on where the client is.
+	pub fn varies(&self, name: &str) -> bool {
+		self.entries.iter().any(|entry| entry.name().as_deref() == Some(name))
+	}
+}
+
+/// A zone seen from where a client is: the records for its location replace those of the
+/// zone, and the names given records exist even for the clients elsewhere.
+pub(crate) struct GeoZone<'a> {
+	pub zone: &'a (dyn ZoneData + Send + Sync),
+	pub records: &'a GeoRecords,
+	pub location: &'a GeoLocation,
+}
+
+impl ZoneData for GeoZone<'_> {
+	fn origin(&self) -> &str {
+		self.zone.origin()
+	}
+
+	fn soa(&self) -> Result<DNSRecord> {
+		self.zone.soa()
+	}
+
+	fn lookup(&self, name: &str, q_type: QueryType) -> Result<Vec<DNSRecord>> {
+		match self.records.lookup(name, q_type, self.location) {
+			Some(records) => Ok(records),
+			None => self.zone.lookup(name, q_type),
+		}
+	}
+
+	fn has_name(&self, name: &str) -> Result<bool> {
+		Ok(self.records.has_name(name) || self.zone.has_name(name)?)
+	}
+}
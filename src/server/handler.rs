@@ -0,0 +1,523 @@
+use std::any::Any;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::{ IpAddr, SocketAddr };
+use std::panic::{ self, AssertUnwindSafe };
+use std::sync::Arc;
+
+use tracing::{ debug_span, field, info_span, Span };
+
+use crate::server::acl::Access;
+use crate::server::buffer::VectorPacketBuffer;
+use crate::server::context::{ InternalOverride, Listener, NonRecursivePolicy, ResolveStrategy, ServerContext, ServerRole };
+use crate::server::geoip::GeoLocation;
+use crate::server::latency::QueryTiming;
+use crate::server::middleware::QueryVerdict;
+use crate::server::protocol::{ DNSPacket, DNSQuestion, DNSRecord, EdnsOption, QueryType, ResultCode, TransientTTL, EDE_BLOCKED, EDE_STALE_ANSWER };
+use crate::server::resolve::{ DNSResolver, ForwardingResolver, ResolverFactory };
+use crate::server::servfail::ServfailReason;
+use crate::server::upstream::Transport;
+use crate::server::view::View;
+
+/// TTL of the records of referrals made up from the delegations known...
+const REFERRAL_TTL: u32 = 3600;
+
+/// TTL of the answers for names overridden inside the network, kept short for clients moving
+/// in and out of it...
+const INTERNAL_OVERRIDE_TTL: u32 = 60;
+
+/// Parse the query of a request. A message making the parser panic is reported as invalid,
+/// after counting the panic.
+pub fn parse_request(context: &ServerContext, message: &[u8]) -> Result<DNSPacket> {
+	let _span = debug_span!("parse", len = message.len()).entered();
+	panic::catch_unwind(|| DNSPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(message.to_vec())))
+		.unwrap_or_else(|cause| {
+			context.count_panic();
+			Err(Error::new(ErrorKind::InvalidData, format!("Parsing the query panicked: {}", panic_message(&*cause))))
+		})
+}
+
+/// Answer a request received by `listener` from `source`: relayed to the upstreams in
+/// pass-through mode, otherwise built by `execute_query`. Returns the response along with its
+/// wire format.
+///
+/// A panic while answering is caught and counted, and the request is answered with SERVFAIL
+/// rather than taking down the thread serving it. Requests coming in once the server is
+/// stopping are refused, as are those of clients the access control lists deny and those the
+/// query hooks refuse; those the hooks drop get no response, `None`. The responses are shown
+/// to the query hooks, and the requests answered are offered to the mirror, if there's one,
+/// before the addresses of the hosts behind the NAT are rewritten for the side of the client.
+///
+/// Requests are answered within a `query` span carrying their ID and question, which the
+/// spans of the cache lookups and upstream sends made for them belong to.
+pub fn handle_request<F: ResolverFactory>(context: &Arc<ServerContext>, resolvers: &Arc<F>, listener: Listener, request: &DNSPacket, raw_request: &[u8], source: SocketAddr, timing: &mut QueryTiming) -> Result<Option<(DNSPacket, Vec<u8>)>> {
+	let span = match request.questions.first() {
+		Some(question) => info_span!("query", id = request.header.id, qname = %question.name, qtype = %question.q_type, client = %source),
+		None => info_span!("query", id = request.header.id, client = %source),
+	};
+	let _entered = span.enter();
+	let role = listener.role;
+	context.client_stats.record(request, listener.transport);
+
+	// Clients are sent elsewhere once the server is stopping...
+	let _in_flight = match context.shutdown.begin() {
+		Some(in_flight) => in_flight,
+		None => {
+			let mut response = response_to(context, role, request);
+			response.header.rescode = ResultCode::REFUSED;
+			let bytes = encode(context, source.ip(), &mut response)?;
+			return Ok(Some((response, bytes)));
+		}
+	};
+
+	if !context.access_control.check_request(request, source.ip()) {
+		debug!("Refused the request from {}: denied by the access control lists", source);
+		let mut response = response_to(context, role, request);
+		response.header.rescode = ResultCode::REFUSED;
+		let bytes = encode(context, source.ip(), &mut response)?;
+		return Ok(Some((response, bytes)));
+	}
+
+	match context.query_hooks.check(request, source.ip()) {
+		QueryVerdict::Pass => (),
+		QueryVerdict::Refuse => {
+			let mut response = response_to(context, role, request);
+			response.header.rescode = ResultCode::REFUSED;
+			let bytes = encode(context, source.ip(), &mut response)?;
+			return Ok(Some((response, bytes)));
+		}
+		// Only UDP clients can be sent over to TCP, the others are dropped...
+		QueryVerdict::Truncate if listener.transport == Transport::Udp => {
+			let mut response = response_to(context, role, request);
+			response.header.truncated_message = true;
+			let bytes = encode(context, source.ip(), &mut response)?;
+			return Ok(Some((response, bytes)));
+		}
+		QueryVerdict::Truncate | QueryVerdict::Drop => return Ok(None),
+	}
+
+	let answered = panic::catch_unwind(AssertUnwindSafe(|| answer_request(context, resolvers, role, request, raw_request, source, timing)));
+	let answered = answered.unwrap_or_else(|cause| {
+		match request.questions.first() {
+			Some(question) => info!("Answering {} {} from {} panicked: {}", question.name, question.q_type, source, panic_message(&*cause)),
+			None => info!("Answering the request from {} panicked: {}", source, panic_message(&*cause)),
+		}
+		context.count_panic();
+		context.servfails.count(ServfailReason::Internal);
+
+		let mut response = response_to(context, role, request);
+		response.header.rescode = ResultCode::SERVFAIL;
+		let bytes = encode(context, source.ip(), &mut response)?;
+		Ok((response, bytes))
+	});
+
+	if let Ok((ref response, _)) = answered {
+		context.query_hooks.observe(request, response, source.ip());
+	}
+	if let (Some(mirror), Ok((response, _))) = (&context.mirror, &answered) {
+		mirror.offer(raw_request, response);
+	}
+	answered.and_then(|(mut response, bytes)| {
+		let bytes = context.doctor.apply(source.ip(), &mut response, bytes)?;
+		Ok(Some((response, bytes)))
+	})
+}
+
+fn answer_request<F: ResolverFactory>(context: &Arc<ServerContext>, resolvers: &Arc<F>, role: ServerRole, request: &DNSPacket, raw_request: &[u8], source: SocketAddr, timing: &mut QueryTiming) -> Result<(DNSPacket, Vec<u8>)> {
+	let relayed = if context.pass_through && role != ServerRole::Authoritative {
+		relay_query(context, request, raw_request, source, timing)
+	} else {
+		None
+	};
+	match relayed {
+		Some(Ok(bytes)) => match DNSPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(bytes.clone())) {
+			Ok(response) => {
+				if let Some(question) = request.questions.first() {
+					context.last_known_good.store(&question.name, question.q_type, &response);
+					if response.header.rescode == ResultCode::SERVFAIL {
+						let reason = ServfailReason::of_response(&response);
+						debug!("Relayed SERVFAIL for {} {} ({})", question.name, question.q_type, reason);
+						context.servfails.count(reason);
+					}
+				}
+				return Ok((response, bytes));
+			}
+			Err(e) => info!("Failed to parse the response relayed to {}: {}", source, e),
+		},
+		Some(Err(e)) => info!("Failed to relay the query from {}: {}", source, e),
+		None => (),
+	}
+
+	let mut response = execute_query(context, resolvers, role, request, source, timing);
+	let bytes = encode(context, source.ip(), &mut response)?;
+	Ok((response, bytes))
+}
+
+/// What a panic was raised with, when it's a message.
+fn panic_message(cause: &(dyn Any + Send)) -> &str {
+	match cause.downcast_ref::<&str>() {
+		Some(message) => message,
+		None => cause.downcast_ref::<String>().map_or("unknown cause", String::as_str),
+	}
+}
+
+/// The wire format of a response to `client`, its names compressed unless the client is
+/// known to mishandle compression pointers.
+pub fn encode(context: &ServerContext, client: IpAddr, response: &mut DNSPacket) -> Result<Vec<u8>> {
+	let mut buffer = if context.compresses_for(client) {
+		VectorPacketBuffer::with_compression()
+	} else {
+		VectorPacketBuffer::new()
+	};
+	response.write(&mut buffer)?;
+	Ok(buffer.into_inner())
+}
+
+/// Build the response to a request received by a listener of `role` from `source`, marking
+/// the stages it goes through in `timing`. Queries which aren't answered locally or from the
+/// cache go to a resolver of `resolvers`.
+pub fn execute_query<F: ResolverFactory>(context: &Arc<ServerContext>, resolvers: &Arc<F>, role: ServerRole, request: &DNSPacket, source: SocketAddr, timing: &mut QueryTiming) -> DNSPacket {
+	let mut packet = response_to(context, role, request);
+	// Extra text of the stale answer EDE, for answers served stale...
+	let mut stale = None;
+	// Why the query failed, when it's answered with SERVFAIL...
+	let mut servfail = None;
+	let mut blocked = false;
+	// Client subnet of the answer, when it depends on where the client is...
+	let mut subnet = None;
+	let view = context.views.select(source.ip());
+
+	if request.header.opcode != 0 {
+		packet.header.rescode = ResultCode::NOTIMP;
+	} else if request.questions.len() != 1 {
+		packet.header.rescode = ResultCode::FORMERR;
+	} else if role == ServerRole::Recursive && !request.header.recursion_desired {
+		packet.header.rescode = ResultCode::REFUSED;
+	} else if let Some(answers) = internal_override(context, &request.questions[0], source.ip()) {
+		timing.stage("local");
+		packet.answers = answers;
+	} else if let Some(result) = local_answer(context, view.as_deref(), request, source.ip(), &mut subnet) {
+		timing.stage("local");
+		match result {
+			Ok(result) => {
+				packet.header.authoritative_answer = result.header.authoritative_answer;
+				packet.header.rescode = result.header.rescode;
+				packet.answers = result.answers;
+				packet.authorities = result.authorities;
+				packet.additional = result.additional;
+				context.endpoints.apply(&mut packet);
+				context.rotation.apply(&mut packet);
+			}
+			Err(e) => {
+				let question = &request.questions[0];
+				info!("Failed to answer {} {} from the local zones: {}", question.name, question.q_type, e);
+				packet.header.rescode = ResultCode::SERVFAIL;
+				servfail = Some(ServfailReason::Internal);
+			}
+		}
+	} else if !request.header.recursion_desired || !context.allow_recursive || role == ServerRole::Authoritative {
+		// Names outside of the local zones are only resolved for recursive queries...
+		match context.non_recursive {
+			NonRecursivePolicy::Refuse => packet.header.rescode = ResultCode::REFUSED,
+			NonRecursivePolicy::Referral => {
+				let (authorities, additional) = referral(context, &request.questions[0].name);
+				packet.authorities = authorities;
+				packet.additional = additional;
+			}
+		}
+	} else if !context.access_control.check(Access::Recursion, source.ip()) {
+		packet.header.rescode = ResultCode::REFUSED;
+	} else if view.as_ref().is_some_and(|view| view.hosts().answer(&request.questions[0], &mut packet)) || context.hosts.answer(&request.questions[0], &mut packet) {
+		timing.stage("local");
+	} else if context.blocklists.answer(&request.questions[0], &mut packet) {
+		timing.stage("local");
+		blocked = true;
+	} else {
+		timing.stage("local");
+		let question = &request.questions[0];
+		// The names of a view with upstreams of its own are forwarded to them and cached apart,
+		// without being prefetched or kept as last known good answers...
+		let view_upstreams = view.as_ref().and_then(|view| view.upstreams().cloned());
+		let cache = match (&view, &view_upstreams) {
+			(Some(view), Some(_)) => view.cache(),
+			_ => &context.cache,
+		};
+		let cached = debug_span!("cache_lookup", hit = field::Empty).in_scope(|| {
+			let cached = cache.lookup(&question.name, question.q_type);
+			Span::current().record("hit", cached.is_some());
+			cached
+		});
+		timing.stage("cache");
+		let result = match cached {
+			Some(cached) => {
+				timing.cache_hit = true;
+				if view_upstreams.is_none() && context.cache.needs_prefetch(&question.name, question.q_type) {
+					prefetch(context, resolvers, &question.name, question.q_type, source.ip());
+				}
+				Ok(cached)
+			}
+			None => {
+				let result = match view_upstreams {
+					Some(ref upstreams) => resolve(ForwardingResolver::new(context.clone(), upstreams.clone(), Some(source.ip())), question, timing),
+					None => resolve(resolvers.create(context.clone(), Some(source.ip())), question, timing),
+				};
+				if let Ok(ref response) = result {
+					cache.store(&question.name, question.q_type, response);
+					if view_upstreams.is_none() {
+						context.last_known_good.store(&question.name, question.q_type, response);
+					}
+				}
+				result
+			}
+		};
+
+		// An expired answer beats none when the data can't be refreshed (RFC 8767)...
+		let failed = result.as_ref().map_or(true, |response| response.header.rescode == ResultCode::SERVFAIL);
+		let stale_answer = if failed { cache.lookup_stale(&question.name, question.q_type) } else { None };
+		let result = match stale_answer {
+			Some(cached) => {
+				if let Err(ref e) = result {
+					info!("Serving a stale answer for {} {}: {}", question.name, question.q_type, e);
+				}
+				stale = Some("");
+				Ok(cached)
+			}
+			// Or the last one known for a critical name, however old...
+			None if failed && view_upstreams.is_none() => match context.last_known_good.lookup(&question.name, question.q_type) {
+				Some(last) => {
+					info!("Serving the last known good answer for {} {}", question.name, question.q_type);
+					stale = Some("last known good answer");
+					Ok(last)
+				}
+				None => result,
+			},
+			_ => result,
+		};
+		match result {
+			Ok(result) => {
+				if result.header.rescode == ResultCode::SERVFAIL {
+					let reason = ServfailReason::of_response(&result);
+					debug!("SERVFAIL for {} {} ({})", question.name, question.q_type, reason);
+					servfail = Some(reason);
+				}
+				packet.header.rescode = result.header.rescode;
+				packet.answers = result.answers;
+				packet.authorities = result.authorities;
+				// The OPT record of the upstream is no business of the client's...
+				packet.additional = result.additional.into_iter()
+					.filter(|record| record.get_query_type() != QueryType::OPT)
+					.collect();
+			}
+			Err(e) => {
+				let reason = ServfailReason::of_error(&e);
+				info!("Failed to resolve {} {} ({}): {}", question.name, question.q_type, reason, e);
+				packet.header.rescode = ResultCode::SERVFAIL;
+				servfail = Some(reason);
+			}
+		}
+	}
+
+	if let Some(reason) = servfail {
+		context.servfails.count(reason);
+	}
+
+	withhold_addresses(context, request, source.ip(), &mut packet);
+
+	// Negative answers and referrals need their authority section, positive answers are
+	// complete without it...
+	if context.minimal_responses && packet.header.rescode == ResultCode::NOERROR && !packet.answers.is_empty() {
+		packet.authorities.clear();
+		packet.additional.clear();
+	}
+
+	// EDNS is only used towards clients using it themselves...
+	if request.edns().is_some() {
+		packet.additional.push(DNSRecord::OPT { packet_len: context.edns_max_payload(), flags: 0, data: Vec::new() });
+		if let Some(text) = stale {
+			packet.add_extended_error(EDE_STALE_ANSWER, text);
+		}
+		if blocked {
+			packet.add_extended_error(EDE_BLOCKED, "");
+		}
+		if let Some((addr, source_prefix, scope)) = subnet {
+			let mut option = EdnsOption::client_subnet(addr, source_prefix);
+			option.data[3] = scope;
+			packet.add_edns_option(option);
+		}
+		context.edns_hooks.edit_response(request, &mut packet, source.ip());
+	}
+
+	packet
+}
+
+/// Resolve `question` with `resolver`, marking the time it took in `timing`.
+fn resolve<R: DNSResolver>(mut resolver: R, question: &DNSQuestion, timing: &mut QueryTiming) -> Result<DNSPacket> {
+	let result = debug_span!("resolve").in_scope(|| resolver.resolve(&question.name, question.q_type, true));
+	timing.stage("resolve");
+	timing.server = resolver.last_server();
+	result
+}
+
+/// The answer to the question of `request` from the local zones, those of `view` first when
+/// the client belongs to one. None if its name isn't in any of them.
+///
+/// Clients are located by the client subnet of their request, or else by their address, when
+/// the answer depends on where they are. `subnet` is then set to the client subnet to
+/// answer with: that of the request, scoped to the network it was located in.
+fn local_answer(context: &ServerContext, view: Option<&View>, request: &DNSPacket, client: IpAddr, subnet: &mut Option<(IpAddr, u8, u8)>) -> Option<Result<DNSPacket>> {
+	let question = &request.questions[0];
+	let location = locate(context, request, client, subnet);
+	view.and_then(|view| view.authority().query_from(&question.name, question.q_type, location.as_ref()))
+		.or_else(|| context.authority.query_from(&question.name, question.q_type, location.as_ref()))
+}
+
+/// Where the client of `request` is, when there's a database to tell and the answer to the
+/// question depends on it.
+fn locate(context: &ServerContext, request: &DNSPacket, client: IpAddr, subnet: &mut Option<(IpAddr, u8, u8)>) -> Option<GeoLocation> {
+	let database = context.geoip.as_ref()?;
+	let qname = request.questions[0].name.trim_end_matches('.').to_lowercase();
+	if !context.authority.geo_records().varies(&qname) {
+		return None;
+	}
+	let request_subnet = request.edns_options().and_then(|options| options.iter().find_map(EdnsOption::as_client_subnet));
+	// A source prefix of 0 asks for the client not to be located by its subnet, and gets a
+	// scope of 0 back (RFC 7871 section 7.1.2)...
+	let location = database.lookup(match request_subnet {
+		Some((addr, prefix)) if prefix > 0 => addr,
+		_ => client,
+	});
+	if let Some((addr, prefix)) = request_subnet {
+		*subnet = Some((addr, prefix, location.as_ref().map_or(prefix, |location| location.prefix.min(prefix))));
+	}
+	location
+}
+
+/// Leave out the addresses of the family withheld from `client`, if any. A query for them
+/// gets NODATA, with the SOA of the zone when it's a local one, for the client to cache it
+/// (RFC 2308).
+fn withhold_addresses(context: &ServerContext, request: &DNSPacket, client: IpAddr, packet: &mut DNSPacket) {
+	if context.family_filter.apply(client, packet) == 0 || packet.header.rescode != ResultCode::NOERROR || !packet.authorities.is_empty() {
+		return;
+	}
+	let question = &request.questions[0];
+	if packet.answers.iter().any(|record| record.get_query_type() == question.q_type) {
+		return;
+	}
+	if let Some(zone) = context.authority.find_zone(&question.name.trim_end_matches('.').to_lowercase()) {
+		if let Ok(soa) = zone.soa() {
+			packet.authorities.push(soa);
+		}
+	}
+}
+
+/// An empty response to a request received by a listener of `role`.
+fn response_to(context: &ServerContext, role: ServerRole, request: &DNSPacket) -> DNSPacket {
+	let mut packet = DNSPacket::new();
+	packet.header.id = request.header.id;
+	packet.header.opcode = request.header.opcode;
+	packet.header.recursion_desired = request.header.recursion_desired;
+	packet.header.recursion_available = context.allow_recursive && role != ServerRole::Authoritative;
+	packet.header.response = true;
+	packet.questions = request.questions.clone();
+	packet
+}
+
+/// The answer to `question` when it's for a name overridden for the clients inside the network
+/// and `source` is one of them. Queries for the other family of addresses than those given get
+/// no answer, so that clients connect to the LAN address.
+fn internal_override(context: &ServerContext, question: &DNSQuestion, source: IpAddr) -> Option<Vec<DNSRecord>> {
+	if !matches!(question.q_type, QueryType::A | QueryType::AAAA) || !InternalOverride::is_internal(source) {
+		return None;
+	}
+	let name = question.name.trim_end_matches('.');
+	let entry = context.internal_overrides.iter().find(|entry| entry.name.eq_ignore_ascii_case(name))?;
+
+	Some(entry.addrs.iter()
+		.filter_map(|addr| match (*addr, question.q_type) {
+			(IpAddr::V4(addr), QueryType::A) => Some(DNSRecord::A { domain: question.name.clone(), addr, ttl: TransientTTL(INTERNAL_OVERRIDE_TTL) }),
+			(IpAddr::V6(addr), QueryType::AAAA) => Some(DNSRecord::AAAA { domain: question.name.clone(), addr, ttl: TransientTTL(INTERNAL_OVERRIDE_TTL) }),
+			_ => None,
+		})
+		.collect())
+}
+
+/// The NS records of the closest zone cut known above `qname`, and the addresses of those
+/// name servers.
+fn referral(context: &ServerContext, qname: &str) -> (Vec<DNSRecord>, Vec<DNSRecord>) {
+	let delegations = &context.delegations;
+	let zone = delegations.closest_zone(&qname.trim_end_matches('.').to_lowercase());
+	let nameservers = delegations.nameservers(&zone).unwrap_or_default();
+
+	let mut authorities = Vec::new();
+	let mut additional = Vec::new();
+	for host in nameservers {
+		for addr in delegations.addresses(&host) {
+			additional.push(match addr {
+				IpAddr::V4(addr) => DNSRecord::A { domain: host.clone(), addr, ttl: TransientTTL(REFERRAL_TTL) },
+				IpAddr::V6(addr) => DNSRecord::AAAA { domain: host.clone(), addr, ttl: TransientTTL(REFERRAL_TTL) },
+			});
+		}
+		authorities.push(DNSRecord::NS { domain: zone.clone(), host, ttl: TransientTTL(REFERRAL_TTL) });
+	}
+	(authorities, additional)
+}
+
+/// Relay `raw_request` to the upstreams as it is and return their response as it was
+/// received, apart from the ID, so that the order of the records and the EDNS options of the
+/// upstream reach the client untouched. None for queries which aren't passed through: those
+/// answered from the local zones or the hosts, those the server refuses or blocks, those of
+/// clients records are withheld from and everything when it isn't forwarding.
+pub fn relay_query(context: &Arc<ServerContext>, request: &DNSPacket, raw_request: &[u8], source: SocketAddr, timing: &mut QueryTiming) -> Option<Result<Vec<u8>>> {
+	let view = context.views.select(source.ip());
+	let upstreams = match (view.as_ref().and_then(|view| view.upstreams()), context.resolve_strategy()) {
+		(Some(upstreams), _) => upstreams.clone(),
+		(None, ResolveStrategy::Forward { upstreams }) => upstreams,
+		(None, ResolveStrategy::Recursive) => return None,
+	};
+	if request.header.opcode != 0 || request.questions.len() != 1 || !request.header.recursion_desired || !context.allow_recursive {
+		return None;
+	}
+	if !context.access_control.allows(Access::Recursion, source.ip()) {
+		return None;
+	}
+	let question = &request.questions[0];
+	let qname = question.name.trim_end_matches('.').to_lowercase();
+	let in_view = view.is_some_and(|view| view.authority().find_zone(&qname).is_some() || view.hosts().covers(question));
+	if in_view || context.authority.find_zone(&qname).is_some() || context.hosts.covers(question) || context.family_filter.applies_to(source.ip()) || context.blocklists.blocks(&qname) {
+		return None;
+	}
+	timing.stage("local");
+
+	let result = upstreams.relay(&context.client, raw_request, Some(source.ip()));
+	timing.stage("resolve");
+	Some(result.and_then(|(response, upstream)| {
+		timing.server = Some(upstream.to_string());
+		let mut response = context.response_limits.apply_raw(response)?;
+		response[..2].copy_from_slice(&request.header.id.to_be_bytes());
+		Ok(response)
+	}))
+}
+
+/// Refresh the cache entry of a question in the background, so that clients asking for it
+/// keep getting it from the cache. The entry may be prefetched again if this fails, or if
+/// too many prefetches are already waiting.
+fn prefetch<F: ResolverFactory>(context: &Arc<ServerContext>, resolvers: &Arc<F>, qname: &str, q_type: QueryType, source: IpAddr) {
+	let job_context = context.clone();
+	let resolvers = resolvers.clone();
+	let name = qname.to_string();
+	let queued = context.prefetcher.submit(move || {
+		let context = job_context;
+		let mut resolver = resolvers.create(context.clone(), Some(source));
+		match resolver.resolve(&name, q_type, true) {
+			Ok(response) => {
+				context.cache.store(&name, q_type, &response);
+				context.last_known_good.store(&name, q_type, &response);
+			}
+			Err(e) => info!("Failed to prefetch {} {}: {}", name, q_type, e),
+		}
+		context.cache.prefetch_finished(&name, q_type);
+	});
+	if !queued {
+		debug!("Too many prefetches waiting, not prefetching {} {}", qname, q_type);
+		context.cache.prefetch_finished(qname, q_type);
+	}
+}
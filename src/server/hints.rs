@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::server::protocol::DNSRecord;
+use crate::server::zonefile::parse_entry;
+
+/// Root name servers as (name server, addresses) pairs.
+pub type RootHints = Vec<(String, Vec<IpAddr>)>;
+
+/// The root name servers with their IPv4 and IPv6 addresses, used to start iterative
+/// resolution when nothing better is known. Taken from the IANA root hints file.
+pub const ROOT_HINTS: &[(&str, &str, &str)] = &[
+	("a.root-servers.net", "198.41.0.4", "2001:503:ba3e::2:30"),
+	("b.root-servers.net", "170.247.170.2", "2801:1b8:10::b"),
+	("c.root-servers.net", "192.33.4.12", "2001:500:2::c"),
+	("d.root-servers.net", "199.7.91.13", "2001:500:2d::d"),
+	("e.root-servers.net", "192.203.230.10", "2001:500:a8::e"),
+	("f.root-servers.net", "192.5.5.241", "2001:500:2f::f"),
+	("g.root-servers.net", "192.112.36.4", "2001:500:12::d0d"),
+	("h.root-servers.net", "198.97.190.53", "2001:500:1::53"),
+	("i.root-servers.net", "192.36.148.17", "2001:7fe::53"),
+	("j.root-servers.net", "192.58.128.30", "2001:503:c27::2:30"),
+	("k.root-servers.net", "193.0.14.129", "2001:7fd::1"),
+	("l.root-servers.net", "199.7.83.42", "2001:500:9f::42"),
+	("m.root-servers.net", "202.12.27.33", "2001:dc3::35"),
+];
+
+/// TTL given to the compiled in hints (the one used by the IANA file)...
+pub const ROOT_HINTS_TTL: u32 = 3600000;
+
+/// The compiled in root hints as (name server, addresses) pairs.
+pub fn root_hints() -> RootHints {
+	ROOT_HINTS.iter()
+		.map(|(name, v4, v6)| {
+			let addrs = [v4, v6].iter().filter_map(|addr| addr.parse().ok()).collect();
+			(name.to_string(), addrs)
+		})
+		.collect()
+}
+
+/// Load a root hints file in the format IANA publishes (`named.root`): NS records for the
+/// root zone plus A/AAAA records for each of the name servers.
+pub fn load_root_hints<P: AsRef<Path>>(path: P) -> Result<RootHints> {
+	let content = fs::read_to_string(path)?;
+
+	let mut names = Vec::new();
+	let mut addresses: HashMap<String, Vec<IpAddr>> = HashMap::new();
+	for line in content.lines() {
+		let line = line.split(';').next().unwrap_or("").trim();
+		if line.is_empty() {
+			continue;
+		}
+		match parse_entry(line)? {
+			DNSRecord::NS { ref domain, ref host, .. } if domain.is_empty() => names.push(host.clone()),
+			DNSRecord::A { domain, addr, .. } => addresses.entry(domain).or_default().push(IpAddr::V4(addr)),
+			DNSRecord::AAAA { domain, addr, .. } => addresses.entry(domain).or_default().push(IpAddr::V6(addr)),
+			_ => (),
+		}
+	}
+
+	let hints: RootHints = names.into_iter()
+		.filter_map(|name| addresses.remove(&name).map(|addrs| (name, addrs)))
+		.collect();
+	if hints.is_empty() {
+		return Err(Error::new(ErrorKind::InvalidData, "No root name server with an address in the hints file"));
+	}
+	Ok(hints)
+}
@@ -0,0 +1,238 @@
+//! Local host overrides, from a hosts file and the configuration, answered before resolving
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Arc, Mutex, RwLock };
+use std::time::{ Duration, SystemTime };
+
+use crate::server::protocol::{ DNSPacket, DNSQuestion, DNSRecord, QueryType, TransientTTL };
+
+/// TTL of the answers for the hosts, kept short for edits of the file to show soon...
+const HOSTS_TTL: u32 = 60;
+/// How often the hosts file is checked for changes...
+pub const HOSTS_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The names and addresses of the hosts, both ways.
+#[derive(Debug, Default)]
+struct HostsTable {
+	addrs: HashMap<String, Vec<IpAddr>>,
+	names: HashMap<IpAddr, Vec<String>>,
+}
+
+impl HostsTable {
+	fn add(&mut self, name: &str, addr: IpAddr) {
+		let name = normalize(name);
+		let addrs = self.addrs.entry(name.clone()).or_default();
+		if !addrs.contains(&addr) {
+			addrs.push(addr);
+		}
+		let names = self.names.entry(addr).or_default();
+		if !names.contains(&name) {
+			names.push(name);
+		}
+	}
+}
+
+/// Names answered with the addresses they're given rather than resolved, like `/etc/hosts`:
+/// the A and AAAA queries for them get those addresses (NODATA when there are none of the
+/// family asked for), and the PTR queries for the addresses get the names back, the first
+/// given for an address coming first.
+///
+/// Hosts come from a hosts file, whose lines are an address followed by its names, `#`
+/// starting comments, and from entries given as `NAME=ADDR[,ADDR]`, which add to the file.
+/// Nothing is answered until the hosts are loaded, and the file is read again when it changes
+/// (see `reload_if_changed`); a file which fails to load leaves the hosts as they were.
+#[derive(Debug, Default)]
+pub struct HostOverrides {
+	file: Option<PathBuf>,
+	entries: Vec<(String, IpAddr)>,
+	table: RwLock<Arc<HostsTable>>,
+	/// Modification time of the file when it was last read...
+	modified: Mutex<Option<SystemTime>>,
+	answered: AtomicU64,
+}
+
+impl HostOverrides {
+	pub fn new() -> Self {
+		HostOverrides::default()
+	}
+
+	/// Read the hosts of `path`, now and whenever it changes.
+	pub fn set_file(&mut self, path: &Path) {
+		self.file = Some(path.to_path_buf());
+	}
+
+	pub fn file(&self) -> Option<&Path> {
+		self.file.as_deref()
+	}
+
+	/// Add the host given as `NAME=ADDR[,ADDR]`.
+	pub fn add_entry(&mut self, spec: &str) -> Result<()> {
+		let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid host entry: {}", spec));
+		let (name, addrs) = spec.split_once('=').ok_or_else(invalid)?;
+		let name = normalize(name.trim());
+		if name.is_empty() {
+			return Err(invalid());
+		}
+		for addr in addrs.split(',') {
+			let addr = addr.trim().parse::<IpAddr>().map_err(|_| invalid())?;
+			self.entries.push((name.clone(), addr));
+		}
+		Ok(())
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.file.is_none() && self.entries.is_empty()
+	}
+
+	/// Read the hosts file, if any, along with the entries. Returns the number of names.
+	pub fn load(&self) -> Result<usize> {
+		let (text, modified) = match self.file {
+			Some(ref path) => (Some(fs::read_to_string(path)?), fs::metadata(path)?.modified().ok()),
+			None => (None, None),
+		};
+		let table = build_table(&self.entries, text.as_deref())?;
+		let count = table.addrs.len();
+		self.swap(table);
+		*self.modified.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = modified;
+		Ok(count)
+	}
+
+	/// Read the hosts file again when it was modified since it was last read. Returns the
+	/// number of names when it was.
+	pub fn reload_if_changed(&self) -> Result<Option<usize>> {
+		let path = match self.file {
+			Some(ref path) => path,
+			None => return Ok(None),
+		};
+		let modified = fs::metadata(path)?.modified().ok();
+		if modified.is_some() && modified == *self.modified.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+			return Ok(None);
+		}
+		self.load().map(Some)
+	}
+
+	/// Number of names of the hosts.
+	pub fn len(&self) -> usize {
+		self.table().addrs.len()
+	}
+
+	/// Number of queries answered for the hosts.
+	pub fn answered(&self) -> u64 {
+		self.answered.load(Ordering::Relaxed)
+	}
+
+	/// Whether `question` is answered for the hosts.
+	pub fn covers(&self, question: &DNSQuestion) -> bool {
+		let table = self.table();
+		match question.q_type {
+			QueryType::A | QueryType::AAAA => table.addrs.contains_key(&normalize(&question.name)),
+			QueryType::PTR => reverse_addr(&question.name).is_some_and(|addr| table.names.contains_key(&addr)),
+			_ => false,
+		}
+	}
+
+	/// The answer to `question` when it's for one of the hosts. Writes the answers into
+	/// `packet`, and returns whether it did.
+	pub fn answer(&self, question: &DNSQuestion, packet: &mut DNSPacket) -> bool {
+		let table = self.table();
+		let ttl = TransientTTL(HOSTS_TTL);
+		let answers: Vec<DNSRecord> = match question.q_type {
+			QueryType::A | QueryType::AAAA => {
+				let addrs = match table.addrs.get(&normalize(&question.name)) {
+					Some(addrs) => addrs,
+					None => return false,
+				};
+				addrs.iter()
+					.filter_map(|addr| match (*addr, question.q_type) {
+						(IpAddr::V4(addr), QueryType::A) => Some(DNSRecord::A { domain: question.name.clone(), addr, ttl }),
+						(IpAddr::V6(addr), QueryType::AAAA) => Some(DNSRecord::AAAA { domain: question.name.clone(), addr, ttl }),
+						_ => None,
+					})
+					.collect()
+			}
+			QueryType::PTR => match reverse_addr(&question.name).and_then(|addr| table.names.get(&addr)) {
+				Some(names) => names.iter().map(|host| DNSRecord::PTR { domain: question.name.clone(), host: host.clone(), ttl }).collect(),
+				None => return false,
+			},
+			_ => return false,
+		};
+		self.answered.fetch_add(1, Ordering::Relaxed);
+		debug!("Answered {} {} from the hosts", question.name, question.q_type);
+		packet.answers.extend(answers);
+		true
+	}
+
+	fn table(&self) -> Arc<HostsTable> {
+		match self.table.read() {
+			Ok(table) => table.clone(),
+			Err(poisoned) => poisoned.into_inner().clone(),
+		}
+	}
+
+	fn swap(&self, table: HostsTable) {
+		match self.table.write() {
+			Ok(mut current) => *current = Arc::new(table),
+			Err(poisoned) => *poisoned.into_inner() = Arc::new(table),
+		}
+	}
+}
+
+fn normalize(name: &str) -> String {
+	name.trim_end_matches('.').to_lowercase()
+}
+
+/// The hosts of the hosts file `text`, if any, and of `entries`, failing on lines which aren't
+/// an address followed by names.
+fn build_table(entries: &[(String, IpAddr)], text: Option<&str>) -> Result<HostsTable> {
+	let mut table = HostsTable::default();
+	for (number, line) in text.unwrap_or("").lines().enumerate() {
+		let line = line.split('#').next().unwrap_or("").trim();
+		if line.is_empty() {
+			continue;
+		}
+		let invalid = || Error::new(ErrorKind::InvalidData, format!("Invalid hosts entry on line {}: {}", number + 1, line));
+		let mut words = line.split_whitespace();
+		let addr = words.next().and_then(|addr| addr.parse::<IpAddr>().ok()).ok_or_else(invalid)?;
+		let mut names = words.peekable();
+		if names.peek().is_none() {
+			return Err(invalid());
+		}
+		for name in names {
+			table.add(name, addr);
+		}
+	}
+	for (name, addr) in entries {
+		table.add(name, *addr);
+	}
+	Ok(table)
+}
+
+/// The address of a reverse name, under `in-addr.arpa` or `ip6.arpa`.
+fn reverse_addr(name: &str) -> Option<IpAddr> {
+	let name = normalize(name);
+	if let Some(labels) = name.strip_suffix(".in-addr.arpa") {
+		let mut octets: Vec<u8> = labels.split('.').map(|label| label.parse().ok()).collect::<Option<_>>()?;
+		if octets.len() != 4 {
+			return None;
+		}
+		octets.reverse();
+		return Some(IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])));
+	}
+	let labels = name.strip_suffix(".ip6.arpa")?;
+	let nibbles: Vec<u8> = labels.split('.')
+		.map(|label| if label.len() == 1 { u8::from_str_radix(label, 16).ok() } else { None })
+		.collect::<Option<_>>()?;
+	if nibbles.len() != 32 {
+		return None;
+	}
+	let mut octets = [0u8; 16];
+	for (i, pair) in nibbles.rchunks(2).enumerate() {
+		octets[i] = (pair[1] << 4) | pair[0];
+	}
+	Some(IpAddr::V6(Ipv6Addr::from(octets)))
+}
@@ -0,0 +1,223 @@
+//! DNS over HTTPS towards the upstreams (RFC 8484), over HTTP/2
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use h2::client::SendRequest;
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use tokio::net::TcpStream;
+use tokio::runtime::{ Builder, Runtime };
+use tokio::sync::Mutex;
+use tokio_rustls::TlsConnector;
+
+use crate::server::buffer::VectorPacketBuffer;
+use crate::server::client::{ same_questions, DNSClient };
+use crate::server::protocol::DNSPacket;
+use crate::server::tls_upstream::{ client_config, TlsPolicy };
+
+/// Media type of the DNS messages posted and received (RFC 8484 section 6).
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// The HTTPS side of an upstream: how it's authenticated, where queries are posted, and the
+/// HTTP/2 connection to it.
+///
+/// Every query is a stream of its own on a single connection, so queries from any number of
+/// threads go out at the same time, up to the streams the upstream allows, and the connection
+/// is made again once the upstream closes it. The upstream is authenticated like over TLS,
+/// see `TlsUpstream`.
+pub struct HttpsUpstream {
+	addr: SocketAddr,
+	name: Option<String>,
+	path: String,
+	pins: Vec<[u8; 32]>,
+	policy: TlsPolicy,
+	config: Arc<ClientConfig>,
+	// Drives the connection, and the queries while their threads wait for them...
+	runtime: Runtime,
+	// The open connection and how many were made before it, telling whether it's been made
+	// again since a query failed on it...
+	connection: Mutex<Option<(u64, SendRequest<Bytes>)>>,
+}
+
+impl HttpsUpstream {
+	pub fn new(addr: SocketAddr, name: Option<String>, path: String, pins: Vec<[u8; 32]>, policy: TlsPolicy) -> Result<Self> {
+		let config = client_config(&pins, policy, b"h2")?;
+		let runtime = Builder::new_multi_thread()
+			.worker_threads(1)
+			.thread_name("HttpsUpstream")
+			.enable_all()
+			.build()?;
+		Ok(Self {
+			addr,
+			name,
+			path,
+			pins,
+			policy,
+			config,
+			runtime,
+			connection: Mutex::new(None),
+		})
+	}
+
+	pub fn name(&self) -> Option<&str> {
+		self.name.as_deref()
+	}
+
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+	pub fn policy(&self) -> TlsPolicy {
+		self.policy
+	}
+
+	/// The same upstream, with another policy.
+	pub fn with_policy(&self, policy: TlsPolicy) -> Result<Self> {
+		HttpsUpstream::new(self.addr, self.name.clone(), self.path.clone(), self.pins.clone(), policy)
+	}
+
+	/// Send `query` and wait for the response.
+	pub fn exchange(&self, client: &DNSClient, query: &mut DNSPacket) -> Result<DNSPacket> {
+		let mut req_buffer = VectorPacketBuffer::new();
+		query.write(&mut req_buffer)?;
+		client.retry().run(|timeout| self.send(req_buffer.as_slice(), query, timeout)).map(|(response, _)| response)
+	}
+
+	/// Send the query `message` as it is, apart from its ID, and return the response as it
+	/// was received.
+	pub fn relay(&self, client: &DNSClient, message: &[u8]) -> Result<Vec<u8>> {
+		let (query, message) = client.renumber(message)?;
+		client.retry().run(|timeout| self.send(&message, &query, timeout)).map(|(_, response)| response)
+	}
+
+	/// Post `message`, the wire format of `query`, on the open connection or a new one.
+	fn send(&self, message: &[u8], query: &DNSPacket, timeout: Duration) -> Result<(DNSPacket, Vec<u8>)> {
+		let body = self.runtime.block_on(async {
+			let posted = tokio::time::timeout(timeout, async {
+				// The upstream may have closed the connection since the last query, which is
+				// posted again on a new one then...
+				let (made, connection) = self.open(None).await?;
+				match self.post(connection, message).await {
+					Ok(body) => Ok(body),
+					Err(_) => {
+						let (_, connection) = self.open(Some(made)).await?;
+						self.post(connection, message).await
+					}
+				}
+			});
+			posted.await.map_err(|_| Error::new(ErrorKind::TimedOut, "DNS over HTTPS query timed out"))?
+		})?;
+
+		let mut res_buffer = VectorPacketBuffer::from_bytes(body);
+		let response = DNSPacket::from_buffer(&mut res_buffer)?;
+		if response.header.id != query.header.id || !same_questions(&response.questions, &query.questions) {
+			return Err(Error::new(ErrorKind::InvalidData, "Response doesn't match the query"));
+		}
+		Ok((response, res_buffer.into_inner()))
+	}
+
+	/// Post `message` on a stream of its own and read the response.
+	async fn post(&self, connection: SendRequest<Bytes>, message: &[u8]) -> Result<Vec<u8>> {
+		let authority = match self.name {
+			Some(ref name) if self.addr.port() == 443 => name.clone(),
+			Some(ref name) => format!("{}:{}", name, self.addr.port()),
+			None => self.addr.to_string(),
+		};
+		let request = http::Request::post(format!("https://{}{}", authority, self.path))
+			.header(http::header::CONTENT_TYPE, DNS_MESSAGE)
+			.header(http::header::ACCEPT, DNS_MESSAGE)
+			.body(())
+			.map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+		let mut connection = connection.ready().await.map_err(h2_error)?;
+		let (response, mut stream) = connection.send_request(request, false).map_err(h2_error)?;
+		stream.send_data(Bytes::copy_from_slice(message), true).map_err(h2_error)?;
+
+		let response = response.await.map_err(h2_error)?;
+		if response.status() != http::StatusCode::OK {
+			return Err(Error::other(format!("HTTP status {}", response.status())));
+		}
+		let mut body = response.into_body();
+		let mut data = Vec::new();
+		while let Some(chunk) = body.data().await {
+			let chunk = chunk.map_err(h2_error)?;
+			let _ = body.flow_control().release_capacity(chunk.len());
+			data.extend_from_slice(&chunk);
+			if data.len() > u16::MAX as usize {
+				return Err(Error::new(ErrorKind::InvalidData, "Response too long"));
+			}
+		}
+		Ok(data)
+	}
+
+	/// The open connection, or a new one when there's none or it's still the one made as
+	/// `failed`. Queries wait for the connection being made rather than all making one.
+	async fn open(&self, failed: Option<u64>) -> Result<(u64, SendRequest<Bytes>)> {
+		let mut open = self.connection.lock().await;
+		match *open {
+			Some((made, ref connection)) if Some(made) != failed => Ok((made, connection.clone())),
+			_ => {
+				let made = open.as_ref().map_or(0, |(made, _)| *made) + 1;
+				let connection = self.connect().await?;
+				*open = Some((made, connection.clone()));
+				Ok((made, connection))
+			}
+		}
+	}
+
+	/// Open a connection, leaving it to a task of the runtime.
+	async fn connect(&self) -> Result<SendRequest<Bytes>> {
+		let server_name = match self.name {
+			Some(ref name) => ServerName::try_from(name.clone()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?,
+			None => ServerName::IpAddress(self.addr.ip().into()),
+		};
+
+		let socket = TcpStream::connect(self.addr).await?;
+		socket.set_nodelay(true)?;
+		let tls = TlsConnector::from(self.config.clone()).connect(server_name, socket).await?;
+		let (connection, driver) = h2::client::handshake(tls).await.map_err(h2_error)?;
+
+		let addr = self.addr;
+		self.runtime.spawn(async move {
+			if let Err(e) = driver.await {
+				info!("HTTPS connection to {} failed: {}", addr, e);
+			}
+		});
+		Ok(connection)
+	}
+}
+
+/// The I/O error an HTTP/2 error comes from, if it does.
+fn h2_error(e: h2::Error) -> Error {
+	if e.is_io() {
+		e.into_io().unwrap_or_else(|| Error::other("HTTP/2 I/O error"))
+	} else {
+		Error::other(e)
+	}
+}
+
+impl fmt::Debug for HttpsUpstream {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("HttpsUpstream")
+			.field("addr", &self.addr)
+			.field("name", &self.name)
+			.field("path", &self.path)
+			.field("pins", &self.pins.len())
+			.field("policy", &self.policy)
+			.finish()
+	}
+}
+
+impl PartialEq for HttpsUpstream {
+	fn eq(&self, other: &Self) -> bool {
+		self.addr == other.addr && self.name == other.name && self.path == other.path && self.pins == other.pins && self.policy == other.policy
+	}
+}
+
+impl Eq for HttpsUpstream {}
@@ -0,0 +1,112 @@
+//! JSON representation of messages and records
+//!
+//! A packet is an object with `id`, `opcode`, `rcode`, `flags` (list of dig style flag names)
+//! and the `question`, `answer`, `authority` and `additional` lists. Questions carry `name`
+//! and `type`, records `name`, `ttl`, `class`, `type` and `data` (the RDATA in presentation
+//! format). Names are always fully qualified. New fields may be added, existing ones won't
+//! change.
+//!
+//! Records are read back from the same representation, the `class` being optional, which
+//! makes it fit for importing records as well (see `server::structured`).
+//!
+//! A cache entry carries the `name` and `type` of its question, its `rcode`, the `ttl` left,
+//! whether it's `stale`, its `hits` and its `answer` and `authority` lists.
+
+use serde::de::{ self, Deserialize, Deserializer };
+use serde::ser::{ Serialize, SerializeMap, Serializer };
+
+use crate::server::cache::CachedResponse;
+use crate::server::protocol::{ DNSHeader, DNSPacket, DNSQuestion, DNSRecord };
+use crate::server::zonefile::parse_record;
+
+fn fqdn(name: &str) -> String {
+	format!("{}.", name.trim_end_matches('.'))
+}
+
+/// Names of the flags set in a header, the way dig lists them.
+pub fn flag_names(header: &DNSHeader) -> Vec<&'static str> {
+	let mut flags = Vec::new();
+	if header.response { flags.push("qr"); }
+	if header.authoritative_answer { flags.push("aa"); }
+	if header.truncated_message { flags.push("tc"); }
+	if header.recursion_desired { flags.push("rd"); }
+	if header.recursion_available { flags.push("ra"); }
+	if header.authed_data { flags.push("ad"); }
+	if header.checking_disabled { flags.push("cd"); }
+	flags
+}
+
+impl Serialize for DNSQuestion {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		let mut map = serializer.serialize_map(Some(2))?;
+		map.serialize_entry("name", &fqdn(&self.name))?;
+		map.serialize_entry("type", &self.q_type.to_string())?;
+		map.end()
+	}
+}
+
+impl Serialize for DNSRecord {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		let mut map = serializer.serialize_map(Some(5))?;
+		map.serialize_entry("name", &fqdn(&self.get_domain().unwrap_or_default()))?;
+		map.serialize_entry("ttl", &self.get_ttl())?;
+		map.serialize_entry("class", "IN")?;
+		map.serialize_entry("type", &self.get_query_type().to_string())?;
+		map.serialize_entry("data", &self.rdata_string())?;
+		map.end()
+	}
+}
+
+/// The fields of a record as read, before its RDATA is parsed.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RecordFields {
+	name: String,
+	ttl: u32,
+	#[serde(default)]
+	class: Option<String>,
+	#[serde(rename = "type")]
+	r_type: String,
+	data: String,
+}
+
+impl<'de> Deserialize<'de> for DNSRecord {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let fields = RecordFields::deserialize(deserializer)?;
+		if fields.class.as_deref().is_some_and(|class| !class.eq_ignore_ascii_case("IN")) {
+			return Err(de::Error::custom(format!("Unsupported class of {}: {}", fields.name, fields.class.unwrap_or_default())));
+		}
+		parse_record(&fields.name, fields.ttl, &fields.r_type, &fields.data)
+			.map_err(|e| de::Error::custom(format!("Invalid {} record of {}: {}", fields.r_type, fields.name, e)))
+	}
+}
+
+impl Serialize for DNSPacket {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		let mut map = serializer.serialize_map(Some(8))?;
+		map.serialize_entry("id", &self.header.id)?;
+		map.serialize_entry("opcode", &self.header.opcode)?;
+		map.serialize_entry("rcode", &format!("{:?}", self.header.rescode))?;
+		map.serialize_entry("flags", &flag_names(&self.header))?;
+		map.serialize_entry("question", &self.questions)?;
+		map.serialize_entry("answer", &self.answers)?;
+		map.serialize_entry("authority", &self.authorities)?;
+		map.serialize_entry("additional", &self.additional)?;
+		map.end()
+	}
+}
+
+impl Serialize for CachedResponse {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		let mut map = serializer.serialize_map(Some(8))?;
+		map.serialize_entry("name", &fqdn(&self.key.qname))?;
+		map.serialize_entry("type", &self.key.q_type.to_string())?;
+		map.serialize_entry("rcode", &format!("{:?}", self.rescode))?;
+		map.serialize_entry("ttl", &self.ttl)?;
+		map.serialize_entry("stale", &self.stale)?;
+		map.serialize_entry("hits", &self.hits)?;
+		map.serialize_entry("answer", &self.answers)?;
+		map.serialize_entry("authority", &self.authorities)?;
+		map.end()
+	}
+}
@@ -0,0 +1,190 @@
+//! Latency of the queries served: the slow-query log and the latency SLO
+
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+use crate::server::log::{ verbosity, Verbosity };
+use crate::server::protocol::{ DNSQuestion, ResultCode };
+
+/// Width of the buckets queries are counted in, and how many of them are kept...
+const BUCKET_WIDTH: Duration = Duration::from_secs(60);
+const BUCKETS: usize = 60;
+/// Windows the burn rate is reported over, in buckets...
+const SHORT_WINDOW: usize = 5;
+const LONG_WINDOW: usize = 60;
+
+/// Where the time serving a query went. Stages are marked in the order they complete, each
+/// taking the time since the previous mark.
+#[derive(Clone, Debug)]
+pub struct QueryTiming {
+	start: Instant,
+	last: Instant,
+	pub stages: Vec<(&'static str, Duration)>,
+	/// The server the answer came from, when it wasn't served locally...
+	pub server: Option<String>,
+	/// Whether the answer came from the cache...
+	pub cache_hit: bool,
+}
+
+impl QueryTiming {
+	/// Start timing a query received at `start`.
+	pub fn new(start: Instant) -> QueryTiming {
+		QueryTiming {
+			start,
+			last: start,
+			stages: Vec::new(),
+			server: None,
+			cache_hit: false,
+		}
+	}
+
+	/// Mark the end of the stage `name`.
+	pub fn stage(&mut self, name: &'static str) {
+		let now = Instant::now();
+		self.stages.push((name, now.duration_since(self.last)));
+		self.last = now;
+	}
+
+	/// Time since the query was received.
+	pub fn total(&self) -> Duration {
+		self.start.elapsed()
+	}
+}
+
+/// Latency objective: `objective` of the queries (0.99 for 99%) answered within `target`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LatencySlo {
+	pub target: Duration,
+	pub objective: f64,
+}
+
+/// How the SLO is holding up. A burn rate of 1 spends the error budget exactly over the
+/// SLO period, alerting on a high rate over both windows catches fast degradations without
+/// paging for blips.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SloStatus {
+	/// Queries served and those slower than the target over the last hour...
+	pub total: u64,
+	pub slow: u64,
+	/// Burn rate over the last 5 minutes and the last hour...
+	pub burn_rate_short: f64,
+	pub burn_rate_long: f64,
+}
+
+/// Queries counted in one bucket.
+#[derive(Clone, Copy, Debug, Default)]
+struct Bucket {
+	index: u64,
+	total: u64,
+	slow: u64,
+}
+
+/// Logs the queries slower than a threshold with the time spent in each stage, and keeps
+/// count of the queries missing the latency SLO.
+#[derive(Debug)]
+pub struct LatencyTracker {
+	slow_threshold: Option<Duration>,
+	slo: Option<LatencySlo>,
+	epoch: Instant,
+	buckets: Mutex<Vec<Bucket>>,
+}
+
+impl LatencyTracker {
+	pub fn new() -> Self {
+		Self {
+			slow_threshold: None,
+			slo: None,
+			epoch: Instant::now(),
+			buckets: Mutex::new(vec![Bucket::default(); BUCKETS]),
+		}
+	}
+
+	/// Log the queries taking longer than `threshold`, None turns the log off.
+	pub fn set_slow_threshold(&mut self, threshold: Option<Duration>) {
+		self.slow_threshold = threshold;
+	}
+
+	pub fn set_slo(&mut self, slo: Option<LatencySlo>) {
+		self.slo = slo;
+	}
+
+	pub fn slo(&self) -> Option<LatencySlo> {
+		self.slo
+	}
+
+	/// Account a query of `source` which has been answered, logging it when it was slow or the
+	/// server is verbose.
+	pub fn record(&self, source: SocketAddr, question: Option<&DNSQuestion>, rescode: ResultCode, timing: &QueryTiming) {
+		let total = timing.total();
+
+		let slow = self.slow_threshold.is_some_and(|threshold| total >= threshold);
+		if slow || verbosity() >= Verbosity::Verbose {
+			let question = question.map_or("-".to_string(), |question| format!("{} {}", question.name, question.q_type));
+			let server = timing.server.as_ref().map_or(String::new(), |server| format!(" via {}", server));
+			if slow {
+				let stages: Vec<String> = timing.stages.iter()
+					.map(|(name, time)| format!("{}={:.1}ms", name, time.as_secs_f64() * 1000.0))
+					.collect();
+				info!("Slow query from {}: {} {:?} in {:.1}ms ({}){}",
+					source, question, rescode, total.as_secs_f64() * 1000.0, stages.join(" "), server);
+			} else {
+				debug!("Query from {}: {} {:?} in {:.1}ms{}", source, question, rescode, total.as_secs_f64() * 1000.0, server);
+			}
+		}
+
+		let slo = match self.slo {
+			Some(slo) => slo,
+			None => return,
+		};
+		let index = self.bucket_index(Instant::now());
+		if let Ok(mut buckets) = self.buckets.lock() {
+			let bucket = &mut buckets[index as usize % BUCKETS];
+			if bucket.index != index {
+				*bucket = Bucket { index, total: 0, slow: 0 };
+			}
+			bucket.total += 1;
+			if total > slo.target {
+				bucket.slow += 1;
+			}
+		}
+	}
+
+	/// The state of the SLO, None without one.
+	pub fn slo_status(&self) -> Option<SloStatus> {
+		let slo = self.slo?;
+		let current = self.bucket_index(Instant::now());
+		let buckets = self.buckets.lock().ok()?;
+
+		// Counts of the buckets within the last `window` ones...
+		let count = |window: usize| -> (u64, u64) {
+			buckets.iter()
+				.filter(|bucket| bucket.index <= current && current - bucket.index < window as u64)
+				.fold((0, 0), |(total, slow), bucket| (total + bucket.total, slow + bucket.slow))
+		};
+		let budget = (1.0 - slo.objective).max(f64::EPSILON);
+		let burn_rate = |(total, slow): (u64, u64)| -> f64 {
+			if total == 0 { 0.0 } else { slow as f64 / total as f64 / budget }
+		};
+
+		let (total, slow) = count(LONG_WINDOW);
+		Some(SloStatus {
+			total,
+			slow,
+			burn_rate_short: burn_rate(count(SHORT_WINDOW)),
+			burn_rate_long: burn_rate((total, slow)),
+		})
+	}
+
+	/// Index of the bucket `now` falls in. Indices start at 1, so that the empty buckets
+	/// never count.
+	fn bucket_index(&self, now: Instant) -> u64 {
+		now.duration_since(self.epoch).as_secs() / BUCKET_WIDTH.as_secs() + 1
+	}
+}
+
+impl Default for LatencyTracker {
+	fn default() -> Self {
+		LatencyTracker::new()
+	}
+}
@@ -0,0 +1,181 @@
+//! Loading zone files in the background
+//!
+//! Large zone files take a while to parse. They are loaded on a thread of their own with the
+//! records going straight into the index, while the previous version of the zone keeps being
+//! served. The new version only replaces it once it is complete.
+//!
+//! Files named `.json`, `.yaml` or `.yml` are read as structured documents (see
+//! `server::structured`) rather than as master files.
+
+use std::fs::File;
+use std::io::{ BufReader, Error, ErrorKind, Result };
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::Arc;
+use std::thread::{ self, JoinHandle };
+
+use crate::server::authority::{ Authority, Zone };
+use crate::server::protocol::{ is_legacy_type, DNSRecord };
+use crate::server::structured::{ RecordDocument, StructuredFormat };
+use crate::server::zonefile::ZoneFileParser;
+
+/// Number of records between two progress reports...
+const PROGRESS_INTERVAL: usize = 10_000;
+
+/// How far a load has got, handed to the progress callback.
+#[derive(Clone, Debug)]
+pub struct LoadProgress {
+	pub path: PathBuf,
+	pub bytes_read: u64,
+	pub total_bytes: u64,
+	pub records: usize,
+	/// Set on the last report, once the zone is complete and being served.
+	pub done: bool,
+}
+
+impl LoadProgress {
+	pub fn percent(&self) -> f64 {
+		if self.total_bytes == 0 {
+			return 100.0;
+		}
+		self.bytes_read as f64 * 100.0 / self.total_bytes as f64
+	}
+}
+
+/// A zone load running in the background.
+pub struct ZoneLoad {
+	cancelled: Arc<AtomicBool>,
+	handle: JoinHandle<Result<usize>>,
+}
+
+impl ZoneLoad {
+	/// Stop the load. The zone being served stays as it was.
+	pub fn cancel(&self) {
+		self.cancelled.store(true, Ordering::SeqCst);
+	}
+
+	pub fn is_finished(&self) -> bool {
+		self.handle.is_finished()
+	}
+
+	/// Wait for the load, returning the number of records loaded.
+	pub fn join(self) -> Result<usize> {
+		self.handle.join().map_err(|_| Error::other("Zone loading thread panicked"))?
+	}
+}
+
+/// Start loading the master file at `path` into `authority`. Loading the same file again
+/// while a load is running cancels the earlier one. `progress` is called every few thousand
+/// records and once more when the zone has been installed.
+pub fn load_zone<P, F>(authority: &Arc<Authority>, path: P, origin: &str, progress: F) -> Result<ZoneLoad>
+	where P: AsRef<Path>, F: FnMut(&LoadProgress) + Send + 'static
+{
+	let path = path.as_ref().to_path_buf();
+	let file = File::open(&path)?;
+	let total_bytes = file.metadata()?.len();
+
+	let cancelled = authority.begin_load(&path);
+	let authority = authority.clone();
+	let flag = cancelled.clone();
+	let origin = origin.to_string();
+
+	let handle = thread::Builder::new()
+		.name(format!("zone-load {}", path.display()))
+		.spawn(move || {
+			let mut progress = progress;
+			let result = match StructuredFormat::from_path(&path) {
+				Some(format) => build_structured_zone(file, format, &path, total_bytes, authority.is_strict()),
+				None => {
+					let parser = ZoneFileParser::new(BufReader::new(file), &origin).strict(authority.is_strict());
+					build_zone(parser, &path, total_bytes, &flag, &mut progress)
+				}
+			};
+			authority.end_load(&path, &flag);
+
+			let (zone, mut report) = result?;
+			// A newer load of the file may have been started after the last record...
+			if flag.load(Ordering::SeqCst) {
+				return Err(Error::new(ErrorKind::Interrupted, format!("Loading {} was cancelled", path.display())));
+			}
+			authority.add_zone(zone);
+			report.done = true;
+			progress(&report);
+			Ok(report.records)
+		})?;
+
+	Ok(ZoneLoad { cancelled, handle })
+}
+
+/// Parse the master file at `path` into a zone on the calling thread, without installing it.
+pub fn read_zone<P: AsRef<Path>>(path: P, origin: &str, strict: bool) -> Result<Arc<Zone>> {
+	let path = path.as_ref();
+	let file = File::open(path)?;
+	let total_bytes = file.metadata()?.len();
+	if let Some(format) = StructuredFormat::from_path(path) {
+		return build_structured_zone(file, format, path, total_bytes, strict).map(|(zone, _)| zone);
+	}
+	let parser = ZoneFileParser::new(BufReader::new(file), origin).strict(strict);
+	build_zone(parser, path, total_bytes, &AtomicBool::new(false), &mut |_: &LoadProgress| ()).map(|(zone, _)| zone)
+}
+
+fn build_zone<F>(mut parser: ZoneFileParser<BufReader<File>>, path: &Path, total_bytes: u64, cancelled: &AtomicBool, progress: &mut F)
+	-> Result<(Arc<Zone>, LoadProgress)>
+	where F: FnMut(&LoadProgress)
+{
+	let mut zone = Zone::new(String::new(), String::new(), String::new());
+	let mut has_soa = false;
+	let mut count = 0;
+
+	let mut report = LoadProgress {
+		path: path.to_path_buf(),
+		bytes_read: 0,
+		total_bytes,
+		records: 0,
+		done: false,
+	};
+
+	while let Some(record) = parser.next() {
+		if cancelled.load(Ordering::Relaxed) {
+			return Err(Error::new(ErrorKind::Interrupted, format!("Loading {} was cancelled", path.display())));
+		}
+
+		let record = record?;
+		if let DNSRecord::SOA { .. } = record {
+			if has_soa {
+				return Err(Error::new(ErrorKind::InvalidData, "A zone needs exactly one SOA record"));
+			}
+			zone.set_soa(&record);
+			has_soa = true;
+		} else {
+			zone.add_record(record);
+		}
+
+		count += 1;
+		if count % PROGRESS_INTERVAL == 0 {
+			report.bytes_read = parser.bytes_read();
+			report.records = count;
+			progress(&report);
+		}
+	}
+
+	if !has_soa {
+		return Err(Error::new(ErrorKind::InvalidData, "A zone needs exactly one SOA record"));
+	}
+
+	report.bytes_read = parser.bytes_read();
+	report.records = count;
+	Ok((Arc::new(zone), report))
+}
+
+/// Read a zone from a JSON or YAML document, all at once as the parsers work on the whole of
+/// it anyway.
+fn build_structured_zone(file: File, format: StructuredFormat, path: &Path, total_bytes: u64, strict: bool) -> Result<(Arc<Zone>, LoadProgress)> {
+	let document = RecordDocument::read(BufReader::new(file), format)?;
+	if let Some(record) = document.records.iter().find(|record| strict && is_legacy_type(record.get_query_type().to_num())) {
+		return Err(Error::new(ErrorKind::InvalidData, format!("Obsolete record type {} rejected in strict mode", record.get_query_type())));
+	}
+	let records = document.records.len();
+	let zone = document.into_zone()?;
+	let report = LoadProgress { path: path.to_path_buf(), bytes_read: total_bytes, total_bytes, records, done: false };
+	Ok((Arc::new(zone), report))
+}
@@ -0,0 +1,89 @@
+//! How much the server prints, and where its traces go
+
+use std::fs::OpenOptions;
+use std::io::{ self, Error, ErrorKind, Result };
+use std::path::Path;
+use std::sync::atomic::{ AtomicU8, Ordering };
+use std::sync::Mutex;
+
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+/// Messages printed, set for the whole process. `Quiet` leaves only the errors the command
+/// line reports, `Verbose` adds a line for every query answered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+	Quiet = 0,
+	Normal = 1,
+	Verbose = 2,
+}
+
+impl Verbosity {
+	pub fn from_name(name: &str) -> Option<Verbosity> {
+		match name.to_lowercase().as_str() {
+			"quiet" => Some(Verbosity::Quiet),
+			"normal" => Some(Verbosity::Normal),
+			"verbose" => Some(Verbosity::Verbose),
+			_ => None,
+		}
+	}
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+pub fn set_verbosity(verbosity: Verbosity) {
+	VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> Verbosity {
+	match VERBOSITY.load(Ordering::Relaxed) {
+		0 => Verbosity::Quiet,
+		1 => Verbosity::Normal,
+		_ => Verbosity::Verbose,
+	}
+}
+
+/// Print a line like `println!`, unless the server is told to be quiet.
+macro_rules! info {
+	($($arg:tt)*) => {
+		if $crate::server::log::verbosity() >= $crate::server::log::Verbosity::Normal {
+			println!($($arg)*);
+		}
+	};
+}
+
+/// Print a line like `println!`, only when the server is told to be verbose.
+macro_rules! debug {
+	($($arg:tt)*) => {
+		if $crate::server::log::verbosity() >= $crate::server::log::Verbosity::Verbose {
+			println!($($arg)*);
+		}
+	};
+}
+
+/// Whether `filter` is made of valid `tracing` directives.
+pub fn is_trace_filter(filter: &str) -> bool {
+	EnvFilter::try_new(filter).is_ok()
+}
+
+/// Report the spans of the server matching `filter`, directives like those of `RUST_LOG`
+/// (`info` for a span per query, `rdns=debug` adding those of the cache lookups, the upstream
+/// sends and the parsing), to the file at `path`, appending to it, or to stderr. A line is
+/// written as each span closes, with the time it was busy and idle.
+///
+/// The subscriber is set for the whole process, once; without it spans cost next to nothing.
+pub fn init_tracing(filter: &str, path: Option<&Path>) -> Result<()> {
+	let filter = EnvFilter::try_new(filter).map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+	let builder = tracing_subscriber::fmt()
+		.with_env_filter(filter)
+		.with_span_events(FmtSpan::CLOSE)
+		.with_thread_names(true);
+	let set = match path {
+		Some(path) => {
+			let file = OpenOptions::new().create(true).append(true).open(path)?;
+			builder.with_writer(Mutex::new(file)).try_init()
+		}
+		None => builder.with_writer(io::stderr).try_init(),
+	};
+	set.map_err(|e| Error::other(e.to_string()))
+}
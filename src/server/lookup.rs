@@ -0,0 +1,452 @@
+//! Higher level lookups built on top of the client
+
+use std::io::{ Error, ErrorKind, Result };
+use std::net::{ IpAddr, Ipv6Addr, SocketAddr, UdpSocket };
+use std::thread;
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::server::client::DNSClient;
+use crate::server::protocol::{ DNSPacket, DNSRecord, QueryType, ResultCode, SvcbRData, SVC_PARAM_ECH, SVC_PARAM_NO_DEFAULT_ALPN, TYPE_HTTPS };
+
+/// Aliases followed by `lookup_https` before giving up, against loops...
+const MAX_HTTPS_ALIASES: usize = 8;
+
+/// Where to connect to for an HTTPS origin, from its HTTPS records (RFC 9460 section 9).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpsEndpoint {
+	/// Endpoints with lower priorities are to be tried first, 0 is the host itself when it
+	/// has no HTTPS records...
+	pub priority: u16,
+	/// Name the addresses belong to, and to expect in the certificate...
+	pub target: String,
+	pub port: u16,
+	/// Protocols to offer with ALPN, on top of http/1.1 unless `no_default_alpn`...
+	pub alpn: Vec<String>,
+	pub no_default_alpn: bool,
+	/// Addresses of the target, those looked up and the hints of the record...
+	pub addrs: Vec<IpAddr>,
+	/// ECHConfigList to encrypt the ClientHello with (RFC 9849)...
+	pub ech_config: Option<Vec<u8>>,
+}
+
+/// A host accepting mail for a domain, from its MX records.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MailExchanger {
+	/// Exchangers with lower preferences are to be tried first, 0 is the domain itself when
+	/// it has no MX records...
+	pub preference: u16,
+	pub host: String,
+	/// Addresses of the host, sorted as `lookup_ip` sorts them...
+	pub addrs: Vec<IpAddr>,
+}
+
+impl DNSClient {
+	/// Resolve `name` to its addresses through the recursive resolver at `server`, sending the
+	/// A and AAAA queries at the same time.
+	///
+	/// The addresses are deduplicated and sorted the way RFC 6724 orders destinations, so
+	/// connecting to them in turn (or Happy Eyeballs style) tries the preferred ones first.
+	/// A name without addresses gives an empty list, a name that doesn't exist an error of
+	/// kind `NotFound`.
+	pub fn lookup_ip(&self, name: &str, server: SocketAddr) -> Result<Vec<IpAddr>> {
+		let (v4, v6) = thread::scope(|scope| {
+			let v6 = scope.spawn(|| self.lookup_addresses(name, QueryType::AAAA, server));
+			let v4 = self.lookup_addresses(name, QueryType::A, server);
+			(v4, v6.join().unwrap_or_else(|_| Err(Error::other("AAAA lookup panicked"))))
+		});
+		merge_addresses(v4, v6)
+	}
+
+	/// Find the endpoints of the HTTPS origin `host` through the recursive resolver at
+	/// `server`, sorted by priority.
+	///
+	/// Aliases are followed to the records of their target. Each service endpoint gets the
+	/// addresses of its target, along with the hints of the record which weren't looked up.
+	/// Without HTTPS records, the host itself is the single endpoint, on port 443.
+	pub fn lookup_https(&self, host: &str, server: SocketAddr) -> Result<Vec<HttpsEndpoint>> {
+		let mut name = host.trim_end_matches('.').to_lowercase();
+		let mut aliases = 0;
+		let records = loop {
+			let response = self.checked_query(&name, QueryType::from_num(TYPE_HTTPS), server)?;
+			// The resolver may have followed a CNAME to the name holding the records...
+			let records: Vec<(String, SvcbRData)> = response.answers.iter()
+				.filter_map(|record| Some((record.get_domain()?, record.svcb_rdata()?)))
+				.collect();
+
+			match records.iter().find(|(_, rdata)| rdata.is_alias()) {
+				Some((_, alias)) if alias.target.is_empty() => {
+					return Err(Error::new(ErrorKind::NotFound, format!("{} has no HTTPS service", host)));
+				}
+				Some(_) if aliases == MAX_HTTPS_ALIASES => {
+					return Err(Error::other(format!("Too many HTTPS aliases for {}", host)));
+				}
+				Some((_, alias)) => {
+					name = alias.target.clone();
+					aliases += 1;
+				}
+				None => break records,
+			}
+		};
+
+		if records.is_empty() {
+			let addrs = self.lookup_ip(&name, server)?;
+			return Ok(vec![HttpsEndpoint {
+				priority: 0,
+				target: name,
+				port: 443,
+				alpn: Vec::new(),
+				no_default_alpn: false,
+				addrs,
+				ech_config: None,
+			}]);
+		}
+
+		let mut records = records;
+		records.sort_by_key(|(_, rdata)| rdata.priority);
+		let mut endpoints = Vec::new();
+		for (owner, rdata) in records {
+			let target = if rdata.target.is_empty() { owner } else { rdata.target.clone() };
+			let mut addrs = self.lookup_ip(&target, server).unwrap_or_default();
+			for hint in rdata.hints() {
+				if !addrs.contains(&hint) {
+					addrs.push(hint);
+				}
+			}
+
+			endpoints.push(HttpsEndpoint {
+				priority: rdata.priority,
+				target,
+				port: rdata.port().unwrap_or(443),
+				alpn: rdata.alpn(),
+				no_default_alpn: rdata.param(SVC_PARAM_NO_DEFAULT_ALPN).is_some(),
+				addrs,
+				ech_config: rdata.param(SVC_PARAM_ECH).map(|ech| ech.to_vec()),
+			});
+		}
+		Ok(endpoints)
+	}
+
+	/// The addresses to connect to for `service` of `domain`, as `_imaps._tcp` of
+	/// `example.com`, from its SRV records asked of the recursive resolver at `server`
+	/// (RFC 2782).
+	///
+	/// The targets are ordered by priority, and those of a same priority drawn at random in
+	/// proportion to their weights, so each call spreads the load as the records ask. Each
+	/// target brings its addresses sorted as `lookup_ip` sorts them, the addresses the server
+	/// sends along with the records not being asked for again. Targets without addresses are
+	/// skipped. A domain without the service, or whose single record has the target `.`,
+	/// gives an error of kind `NotFound`.
+	pub fn lookup_service(&self, service: &str, domain: &str, server: SocketAddr) -> Result<Vec<SocketAddr>> {
+		let name = format!("{}.{}", service.trim_matches('.'), domain.trim_end_matches('.'));
+		let response = self.checked_query(&name, QueryType::SRV, server)?;
+		let targets: Vec<SrvTarget> = response.answers.iter()
+			.filter_map(|record| match *record {
+				DNSRecord::SRV { priority, weight, port, ref host, .. } => Some(SrvTarget {
+					priority,
+					weight,
+					port,
+					host: host.trim_end_matches('.').to_lowercase(),
+				}),
+				_ => None,
+			})
+			.collect();
+
+		match targets.as_slice() {
+			[] => return Err(Error::new(ErrorKind::NotFound, format!("{} has no SRV records", name))),
+			[target] if target.host.is_empty() => {
+				return Err(Error::new(ErrorKind::NotFound, format!("{} is not available", name)));
+			}
+			_ => (),
+		}
+
+		let mut addrs = Vec::new();
+		for target in order_targets(targets) {
+			let target_addrs = self.target_addresses(&response, &target.host, server);
+			addrs.extend(target_addrs.into_iter().map(|addr| SocketAddr::new(addr, target.port)));
+		}
+		if addrs.is_empty() {
+			return Err(Error::new(ErrorKind::NotFound, format!("No addresses for the targets of {}", name)));
+		}
+		Ok(addrs)
+	}
+
+	/// Find the hosts accepting mail for `domain` through the recursive resolver at `server`,
+	/// sorted by preference, those of a same preference in a random order to spread the load
+	/// (RFC 5321 section 5.1).
+	///
+	/// Each exchanger gets the addresses of its host, those the server sends along with the
+	/// records not being asked for again. Without MX records, the domain itself is the single
+	/// exchanger if it has addresses (the implicit MX). A domain that doesn't exist, that has
+	/// neither MX records nor addresses, or that publishes a null MX (RFC 7505) gives an error
+	/// of kind `NotFound`.
+	pub fn lookup_mx(&self, domain: &str, server: SocketAddr) -> Result<Vec<MailExchanger>> {
+		let domain = domain.trim_end_matches('.').to_lowercase();
+		let response = self.checked_query(&domain, QueryType::MX, server)?;
+		let mut exchangers: Vec<MailExchanger> = response.answers.iter()
+			.filter_map(|record| match *record {
+				DNSRecord::MX { priority, ref host, .. } => Some(MailExchanger {
+					preference: priority,
+					host: host.trim_end_matches('.').to_lowercase(),
+					addrs: Vec::new(),
+				}),
+				_ => None,
+			})
+			.collect();
+
+		match exchangers.as_slice() {
+			[] => {
+				let addrs = self.lookup_ip(&domain, server)?;
+				if addrs.is_empty() {
+					return Err(Error::new(ErrorKind::NotFound, format!("{} has no MX records nor addresses", domain)));
+				}
+				return Ok(vec![MailExchanger { preference: 0, host: domain, addrs }]);
+			}
+			[exchanger] if exchanger.host.is_empty() => {
+				return Err(Error::new(ErrorKind::NotFound, format!("{} does not accept mail", domain)));
+			}
+			_ => (),
+		}
+
+		// Shuffled before the stable sort, for those of a same preference to come in a random
+		// order...
+		exchangers.retain(|exchanger| !exchanger.host.is_empty());
+		exchangers.shuffle(&mut rand::thread_rng());
+		exchangers.sort_by_key(|exchanger| exchanger.preference);
+		for exchanger in exchangers.iter_mut() {
+			exchanger.addrs = self.target_addresses(&response, &exchanger.host, server);
+		}
+		Ok(exchangers)
+	}
+
+	/// Send a recursive query for `name`, or for the names of the search list in turn (see
+	/// `search_names`), turning NXDOMAIN into an error of kind `NotFound` and other failures
+	/// into errors of their own.
+	pub(crate) fn checked_query(&self, name: &str, q_type: QueryType, server: SocketAddr) -> Result<DNSPacket> {
+		let mut search = Search::default();
+		for candidate in search_names(name, self.search(), self.ndots()) {
+			if let Some(response) = search.add(&candidate, self.send_query(&candidate, q_type, server, true)?)? {
+				return Ok(response);
+			}
+		}
+		search.finish(name)
+	}
+
+	/// Addresses of `host`, a target of the records of `response`: those the server sent along
+	/// in the additional section, or else those looked up, none when the lookup fails.
+	fn target_addresses(&self, response: &DNSPacket, host: &str, server: SocketAddr) -> Vec<IpAddr> {
+		let glue: Vec<IpAddr> = response.additional.iter()
+			.filter(|record| record.get_domain().is_some_and(|domain| domain.trim_end_matches('.').eq_ignore_ascii_case(host)))
+			.filter_map(|record| match *record {
+				DNSRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+				DNSRecord::AAAA { addr, .. } => Some(IpAddr::V6(addr)),
+				_ => None,
+			})
+			.collect();
+		if glue.is_empty() {
+			self.lookup_ip(host, server).unwrap_or_default()
+		} else {
+			merge_addresses(Ok(glue), Ok(Vec::new())).unwrap_or_default()
+		}
+	}
+
+	/// Addresses of type `q_type` in the answer for `name`, CNAMEs having been followed by
+	/// the resolver.
+	fn lookup_addresses(&self, name: &str, q_type: QueryType, server: SocketAddr) -> Result<Vec<IpAddr>> {
+		Ok(addresses(&self.checked_query(name, q_type, server)?, q_type))
+	}
+}
+// --------------------------------------------------------------------------------------------
+
+/// The names to try in turn for `name`, as the resolver of the C library does: a name ending
+/// with a dot only as it is, a name with at least `ndots` dots as it is and then under each
+/// search domain, and a name with fewer dots under each search domain first and as it is
+/// last.
+pub fn search_names(name: &str, search: &[String], ndots: usize) -> Vec<String> {
+	if name.ends_with('.') || search.is_empty() {
+		return vec![name.trim_end_matches('.').to_string()];
+	}
+	let under = search.iter().filter(|domain| !domain.is_empty()).map(|domain| format!("{}.{}", name, domain));
+	if name.matches('.').count() >= ndots {
+		std::iter::once(name.to_string()).chain(under).collect()
+	} else {
+		under.chain(std::iter::once(name.to_string())).collect()
+	}
+}
+
+/// A target of the SRV records of a service...
+#[derive(Clone, Debug)]
+struct SrvTarget {
+	priority: u16,
+	weight: u16,
+	port: u16,
+	host: String,
+}
+
+/// The targets in the order to try them, as RFC 2782 selects them: by increasing priority,
+/// and among those of a same priority, drawing each next one with a chance proportional to
+/// its weight, those of weight 0 keeping a small chance of coming first.
+fn order_targets(mut targets: Vec<SrvTarget>) -> Vec<SrvTarget> {
+	targets.sort_by_key(|target| target.priority);
+	let mut rng = rand::thread_rng();
+	let mut ordered = Vec::with_capacity(targets.len());
+	for group in targets.chunk_by(|a, b| a.priority == b.priority) {
+		// The targets of weight 0 go first in the list the running sums are taken over...
+		let mut group = group.to_vec();
+		group.sort_by_key(|target| target.weight != 0);
+		while !group.is_empty() {
+			let total: u32 = group.iter().map(|target| target.weight as u32).sum();
+			let pick = rng.gen_range(0..=total);
+			let mut sum = 0;
+			let index = group.iter()
+				.position(|target| {
+					sum += target.weight as u32;
+					sum >= pick
+				})
+				.unwrap_or(0);
+			ordered.push(group.remove(index));
+		}
+	}
+	ordered
+}
+
+/// The responses to the names of a search list, the first with answers ending the search.
+/// As with the C library, a name without records of the type asked (NODATA), a name that
+/// doesn't exist or a server failing moves on to the next name, other errors end the
+/// search.
+#[derive(Default)]
+pub(crate) struct Search {
+	/// First response without answers, returned if no name has any...
+	nodata: Option<DNSPacket>,
+	failure: Option<Error>,
+}
+
+impl Search {
+	/// The response to `candidate` if it ends the search.
+	pub fn add(&mut self, candidate: &str, response: DNSPacket) -> Result<Option<DNSPacket>> {
+		match response.header.rescode {
+			ResultCode::NOERROR if !response.answers.is_empty() => return Ok(Some(response)),
+			ResultCode::NOERROR => {
+				self.nodata.get_or_insert(response);
+			}
+			ResultCode::NXDOMAIN => (),
+			ResultCode::SERVFAIL => self.failure = checked_response(candidate, response).err(),
+			_ => return checked_response(candidate, response).map(Some),
+		}
+		Ok(None)
+	}
+
+	/// The outcome of the search for `name` once every name was tried.
+	pub fn finish(self, name: &str) -> Result<DNSPacket> {
+		match (self.nodata, self.failure) {
+			(Some(response), _) => Ok(response),
+			(None, Some(failure)) => Err(failure),
+			(None, None) => Err(Error::new(ErrorKind::NotFound, format!("{} does not exist", name))),
+		}
+	}
+}
+
+/// `response`, the one to a query for `name`, unless it failed.
+pub(crate) fn checked_response(name: &str, response: DNSPacket) -> Result<DNSPacket> {
+	match response.header.rescode {
+		ResultCode::NOERROR => Ok(response),
+		ResultCode::NXDOMAIN => Err(Error::new(ErrorKind::NotFound, format!("{} does not exist", name))),
+		rescode => Err(Error::other(format!("Lookup of {} failed: {:?}", name, rescode))),
+	}
+}
+
+/// Addresses of type `q_type` in the answer of `response`.
+pub(crate) fn addresses(response: &DNSPacket, q_type: QueryType) -> Vec<IpAddr> {
+	response.answers.iter()
+		.filter_map(|record| match *record {
+			DNSRecord::A { addr, .. } if q_type == QueryType::A => Some(IpAddr::V4(addr)),
+			DNSRecord::AAAA { addr, .. } if q_type == QueryType::AAAA => Some(IpAddr::V6(addr)),
+			_ => None,
+		})
+		.collect()
+}
+
+/// The addresses of the A and AAAA lookups of a name, deduplicated and sorted.
+pub(crate) fn merge_addresses(v4: Result<Vec<IpAddr>>, v6: Result<Vec<IpAddr>>) -> Result<Vec<IpAddr>> {
+	// One family failing is fine as long as the other one answered...
+	let mut addrs = match (v4, v6) {
+		(Ok(v4), Ok(v6)) => [v4, v6].concat(),
+		(Ok(addrs), Err(_)) | (Err(_), Ok(addrs)) => addrs,
+		(Err(e), Err(_)) => return Err(e),
+	};
+
+	let mut seen = Vec::with_capacity(addrs.len());
+	addrs.retain(|addr| {
+		let new = !seen.contains(addr);
+		seen.push(*addr);
+		new
+	});
+	sort_addresses(&mut addrs);
+	Ok(addrs)
+}
+
+/// Sort destination addresses following the rules of RFC 6724 section 6 that don't need
+/// the details of the local interfaces: unreachable destinations go last (rule 1), then
+/// higher precedence (rule 6) and smaller scope (rule 8) come first. The sort is stable,
+/// so the order of the answer decides the rest (rule 10).
+pub fn sort_addresses(addrs: &mut [IpAddr]) {
+	addrs.sort_by_cached_key(|addr| {
+		let mapped = match *addr {
+			IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+			IpAddr::V6(v6) => v6,
+		};
+		(!is_reachable(addr), u8::MAX - precedence(&mapped), scope(&mapped))
+	});
+}
+
+/// Whether there's a route to `addr`. Connecting a UDP socket picks a source address
+/// without sending anything...
+fn is_reachable(addr: &IpAddr) -> bool {
+	let bind_addr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+	UdpSocket::bind(bind_addr)
+		.and_then(|socket| socket.connect(SocketAddr::new(*addr, 53)))
+		.is_ok()
+}
+
+/// Precedence from the default policy table of RFC 6724 section 2.1.
+fn precedence(addr: &Ipv6Addr) -> u8 {
+	let segments = addr.segments();
+	if *addr == Ipv6Addr::LOCALHOST {
+		50
+	} else if addr.to_ipv4_mapped().is_some() {
+		35
+	} else if segments[0] == 0x2002 {
+		30
+	} else if segments[0] == 0x2001 && segments[1] == 0 {
+		5
+	} else if segments[0] & 0xfe00 == 0xfc00 {
+		3
+	} else if segments[..6].iter().all(|&s| s == 0) || segments[0] & 0xffc0 == 0xfec0 || segments[0] == 0x3ffe {
+		1
+	} else {
+		40
+	}
+}
+
+/// Scope of an address as defined by RFC 4291 and, for IPv4, RFC 6724 section 3.2.
+/// Smaller values are more local.
+fn scope(addr: &Ipv6Addr) -> u8 {
+	const LINK_LOCAL: u8 = 0x2;
+	const SITE_LOCAL: u8 = 0x5;
+	const GLOBAL: u8 = 0xe;
+
+	if let Some(v4) = addr.to_ipv4_mapped() {
+		return if v4.is_loopback() || v4.is_link_local() { LINK_LOCAL } else { GLOBAL };
+	}
+	let segments = addr.segments();
+	if *addr == Ipv6Addr::LOCALHOST || segments[0] & 0xffc0 == 0xfe80 {
+		LINK_LOCAL
+	} else if segments[0] & 0xffc0 == 0xfec0 {
+		SITE_LOCAL
+	} else if segments[0] & 0xff00 == 0xff00 {
+		(segments[0] & 0x000f) as u8
+	} else {
+		GLOBAL
+	}
+}
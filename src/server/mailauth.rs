@@ -0,0 +1,570 @@
+//! The policies mail authentication publishes in TXT records: SPF records (RFC 7208), DMARC
+//! policies (RFC 7489) and DKIM keys (RFC 6376), parsed and checked
+
+use std::io::{ Error, ErrorKind, Result };
+use std::net::{ Ipv4Addr, Ipv6Addr, SocketAddr };
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use crate::server::client::DNSClient;
+use crate::server::protocol::QueryType;
+
+/// Terms of an SPF record making DNS lookups allowed while checking a host, the record and the
+/// ones it includes together (RFC 7208 4.6.4)...
+pub const SPF_MAX_LOOKUPS: usize = 10;
+
+/// The result a mechanism of an SPF record gives when it matches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpfQualifier {
+	/// `+`, the default...
+	Pass,
+	/// `-`...
+	Fail,
+	/// `~`...
+	SoftFail,
+	/// `?`...
+	Neutral,
+}
+
+/// A mechanism of an SPF record, what a sending host is matched against. The domains are
+/// domain-specs, which may hold macros (RFC 7208 7), kept as written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SpfMechanism {
+	All,
+	Include(String),
+	/// The addresses of the domain, that of the record without one, as networks of the
+	/// prefix lengths given...
+	A { domain: Option<String>, prefix_v4: Option<u8>, prefix_v6: Option<u8> },
+	/// The addresses of the mail exchangers of the domain...
+	Mx { domain: Option<String>, prefix_v4: Option<u8>, prefix_v6: Option<u8> },
+	/// Deprecated, the host names the address of the host maps to...
+	Ptr(Option<String>),
+	Ip4 { addr: Ipv4Addr, prefix: u8 },
+	Ip6 { addr: Ipv6Addr, prefix: u8 },
+	Exists(String),
+}
+
+impl SpfMechanism {
+	/// Whether checking a host against the mechanism looks names up.
+	pub fn needs_lookup(&self) -> bool {
+		!matches!(*self, SpfMechanism::All | SpfMechanism::Ip4 { .. } | SpfMechanism::Ip6 { .. })
+	}
+}
+
+/// A mechanism with its qualifier.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpfDirective {
+	pub qualifier: SpfQualifier,
+	pub mechanism: SpfMechanism,
+}
+
+/// An SPF record: `v=spf1 ip4:192.0.2.0/24 include:_spf.example.com -all`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpfRecord {
+	/// Directives in the order they're checked in...
+	pub directives: Vec<SpfDirective>,
+	/// Domain whose record applies when no directive matches...
+	pub redirect: Option<String>,
+	/// Domain whose TXT record explains a failure...
+	pub explanation: Option<String>,
+}
+
+impl SpfRecord {
+	/// The SPF record `text`, the strings of the TXT record joined. Unknown mechanisms and
+	/// malformed terms are errors (a permerror), unknown modifiers are ignored.
+	pub fn parse(text: &str) -> Result<SpfRecord> {
+		let invalid = |reason: String| Error::new(ErrorKind::InvalidData, format!("Invalid SPF record: {}", reason));
+		let mut terms = text.split(' ').filter(|term| !term.is_empty());
+		if !terms.next().is_some_and(|version| version.eq_ignore_ascii_case("v=spf1")) {
+			return Err(invalid("not v=spf1".to_string()));
+		}
+
+		let mut record = SpfRecord { directives: Vec::new(), redirect: None, explanation: None };
+		for term in terms {
+			// A modifier is NAME=VALUE, the name not holding a `:` or `/` a mechanism may...
+			if let Some((name, value)) = term.split_once('=').filter(|(name, _)| is_modifier_name(name)) {
+				let slot = match name.to_ascii_lowercase().as_str() {
+					"redirect" => &mut record.redirect,
+					"exp" => &mut record.explanation,
+					_ => continue,
+				};
+				if slot.is_some() {
+					return Err(invalid(format!("{} given twice", name)));
+				}
+				if value.is_empty() {
+					return Err(invalid(format!("{} without a domain", name)));
+				}
+				*slot = Some(value.to_string());
+				continue;
+			}
+			let directive = parse_directive(term).ok_or_else(|| invalid(format!("bad term {}", term)))?;
+			record.directives.push(directive);
+		}
+		Ok(record)
+	}
+
+	/// The DNS lookups checking a host against the record itself makes, at most, those of the
+	/// records it includes or redirects to aside.
+	pub fn lookups(&self) -> usize {
+		self.directives.iter().filter(|directive| directive.mechanism.needs_lookup()).count()
+			+ self.redirect.iter().count()
+	}
+
+	/// Whether `text`, the strings of a TXT record joined, is an SPF record rather than some
+	/// other TXT record of the domain.
+	pub fn is_spf(text: &str) -> bool {
+		let version = text.split(' ').next().unwrap_or("");
+		version.eq_ignore_ascii_case("v=spf1")
+	}
+}
+
+fn parse_directive(term: &str) -> Option<SpfDirective> {
+	let (qualifier, mechanism) = match term.as_bytes()[0] {
+		b'+' => (SpfQualifier::Pass, &term[1..]),
+		b'-' => (SpfQualifier::Fail, &term[1..]),
+		b'~' => (SpfQualifier::SoftFail, &term[1..]),
+		b'?' => (SpfQualifier::Neutral, &term[1..]),
+		_ => (SpfQualifier::Pass, term),
+	};
+	let (name, arg) = match mechanism.find([':', '/']) {
+		Some(pos) => mechanism.split_at(pos),
+		None => (mechanism, ""),
+	};
+	let domain = arg.strip_prefix(':');
+	let mechanism = match name.to_ascii_lowercase().as_str() {
+		"all" if arg.is_empty() => SpfMechanism::All,
+		"include" => SpfMechanism::Include(required_domain(domain?)?),
+		"exists" => SpfMechanism::Exists(required_domain(domain?)?),
+		"ptr" => SpfMechanism::Ptr(match domain {
+			Some(domain) => Some(required_domain(domain)?),
+			None if arg.is_empty() => None,
+			None => return None,
+		}),
+		"a" | "mx" => {
+			let (domain, prefix_v4, prefix_v6) = split_dual_cidr(arg)?;
+			let domain = match domain.strip_prefix(':') {
+				Some(domain) => Some(required_domain(domain)?),
+				None if domain.is_empty() => None,
+				None => return None,
+			};
+			if name.eq_ignore_ascii_case("a") {
+				SpfMechanism::A { domain, prefix_v4, prefix_v6 }
+			} else {
+				SpfMechanism::Mx { domain, prefix_v4, prefix_v6 }
+			}
+		}
+		"ip4" => {
+			let (addr, prefix) = domain?.split_once('/').unwrap_or((domain?, "32"));
+			SpfMechanism::Ip4 { addr: addr.parse().ok()?, prefix: parse_prefix(prefix, 32)? }
+		}
+		"ip6" => {
+			let (addr, prefix) = domain?.split_once('/').unwrap_or((domain?, "128"));
+			SpfMechanism::Ip6 { addr: addr.parse().ok()?, prefix: parse_prefix(prefix, 128)? }
+		}
+		_ => return None,
+	};
+	Some(SpfDirective { qualifier, mechanism })
+}
+
+/// `arg` of an `a` or `mx` mechanism split into its domain part and the prefix lengths of
+/// its dual CIDR, as `:example.com/24//64`.
+fn split_dual_cidr(arg: &str) -> Option<(&str, Option<u8>, Option<u8>)> {
+	let (rest, prefix_v6) = match arg.rsplit_once("//") {
+		Some((rest, prefix)) => (rest, Some(parse_prefix(prefix, 128)?)),
+		None => (arg, None),
+	};
+	match rest.rsplit_once('/') {
+		Some((rest, prefix)) if !prefix.is_empty() && prefix.bytes().all(|b| b.is_ascii_digit()) => {
+			Some((rest, Some(parse_prefix(prefix, 32)?), prefix_v6))
+		}
+		_ => Some((rest, None, prefix_v6)),
+	}
+}
+
+fn parse_prefix(prefix: &str, max: u8) -> Option<u8> {
+	// Leading zeros aren't allowed (RFC 7208 5.6)...
+	if prefix.starts_with('0') && prefix != "0" {
+		return None;
+	}
+	prefix.parse::<u8>().ok().filter(|&prefix| prefix <= max)
+}
+
+fn required_domain(domain: &str) -> Option<String> {
+	if domain.is_empty() || domain.contains('/') {
+		return None;
+	}
+	Some(domain.to_string())
+}
+
+fn is_modifier_name(name: &str) -> bool {
+	let mut chars = name.chars();
+	chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+		&& chars.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// What a DMARC policy asks receivers to do with mail failing authentication.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DmarcDisposition {
+	None,
+	Quarantine,
+	Reject,
+}
+
+impl DmarcDisposition {
+	fn parse(value: &str) -> Option<DmarcDisposition> {
+		match value.to_ascii_lowercase().as_str() {
+			"none" => Some(DmarcDisposition::None),
+			"quarantine" => Some(DmarcDisposition::Quarantine),
+			"reject" => Some(DmarcDisposition::Reject),
+			_ => None,
+		}
+	}
+}
+
+/// How closely the domain authenticated by DKIM or SPF has to match that of the From
+/// header: the same organizational domain (relaxed), or the same domain (strict).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DmarcAlignment {
+	Relaxed,
+	Strict,
+}
+
+impl DmarcAlignment {
+	fn parse(value: &str) -> Option<DmarcAlignment> {
+		match value.to_ascii_lowercase().as_str() {
+			"r" => Some(DmarcAlignment::Relaxed),
+			"s" => Some(DmarcAlignment::Strict),
+			_ => None,
+		}
+	}
+}
+
+/// The DMARC policy of a domain, from the TXT record of `_dmarc` under it:
+/// `v=DMARC1; p=reject; rua=mailto:dmarc@example.com`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DmarcPolicy {
+	pub policy: DmarcDisposition,
+	/// Policy of the subdomains, that of the domain when not given...
+	pub subdomain_policy: DmarcDisposition,
+	pub dkim_alignment: DmarcAlignment,
+	pub spf_alignment: DmarcAlignment,
+	/// Percentage of the failing mail the policy is applied to...
+	pub percent: u8,
+	/// URIs aggregate reports are sent to...
+	pub aggregate_reports: Vec<String>,
+	/// URIs failure reports are sent to...
+	pub failure_reports: Vec<String>,
+	/// When failure reports are sent, as `0`, `1`, `d` or `s` separated by colons...
+	pub failure_options: String,
+	/// Seconds between two aggregate reports...
+	pub report_interval: u32,
+}
+
+impl DmarcPolicy {
+	/// The DMARC record `text`, the strings of the TXT record joined. Unknown tags are
+	/// ignored, and a record without a valid policy but with aggregate reports gets the
+	/// policy `none` (RFC 7489 6.6.3).
+	pub fn parse(text: &str) -> Result<DmarcPolicy> {
+		let invalid = |reason: String| Error::new(ErrorKind::InvalidData, format!("Invalid DMARC record: {}", reason));
+		let tags = parse_tags(text).map_err(invalid)?;
+		match tags.first() {
+			Some((tag, value)) if tag == "v" && value == "DMARC1" => (),
+			_ => return Err(invalid("not v=DMARC1".to_string())),
+		}
+
+		let get = |name: &str| tags.iter().find(|(tag, _)| tag == name).map(|(_, value)| value.as_str());
+		let uris = |value: Option<&str>| -> Vec<String> {
+			value.unwrap_or("").split(',').map(str::trim).filter(|uri| !uri.is_empty()).map(str::to_string).collect()
+		};
+		let aggregate_reports = uris(get("rua"));
+		let policy = match get("p").and_then(DmarcDisposition::parse) {
+			Some(policy) => policy,
+			None if !aggregate_reports.is_empty() => DmarcDisposition::None,
+			None => return Err(invalid("no valid policy".to_string())),
+		};
+		let alignment = |name: &str| -> Result<DmarcAlignment> {
+			match get(name) {
+				Some(value) => DmarcAlignment::parse(value).ok_or_else(|| invalid(format!("bad {}", name))),
+				None => Ok(DmarcAlignment::Relaxed),
+			}
+		};
+
+		Ok(DmarcPolicy {
+			policy,
+			subdomain_policy: get("sp").and_then(DmarcDisposition::parse).unwrap_or(policy),
+			dkim_alignment: alignment("adkim")?,
+			spf_alignment: alignment("aspf")?,
+			percent: match get("pct") {
+				Some(pct) => pct.parse::<u8>().ok().filter(|&pct| pct <= 100).ok_or_else(|| invalid("bad pct".to_string()))?,
+				None => 100,
+			},
+			aggregate_reports,
+			failure_reports: uris(get("ruf")),
+			failure_options: get("fo").unwrap_or("0").to_string(),
+			report_interval: match get("ri") {
+				Some(ri) => ri.parse::<u32>().map_err(|_| invalid("bad ri".to_string()))?,
+				None => 86400,
+			},
+		})
+	}
+}
+
+/// The key a DKIM signer publishes under `SELECTOR._domainkey` of its domain:
+/// `v=DKIM1; k=rsa; p=MIIBIjANBgkqh...`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DkimKey {
+	/// Type of the key, as `rsa` or `ed25519`...
+	pub key_type: String,
+	/// Public key, DER SubjectPublicKeyInfo for RSA, empty when the key was revoked...
+	pub public_key: Vec<u8>,
+	/// Hash algorithms signatures may use, any when empty...
+	pub hash_algorithms: Vec<String>,
+	/// Services the key is for, `*` for all or `email`...
+	pub service_types: Vec<String>,
+	/// Flags, `y` for a domain testing DKIM and `s` for a key not valid for subdomains...
+	pub flags: Vec<String>,
+	pub notes: Option<String>,
+}
+
+impl DkimKey {
+	/// The DKIM key record `text`, the strings of the TXT record joined. Unknown tags are
+	/// ignored.
+	pub fn parse(text: &str) -> Result<DkimKey> {
+		let invalid = |reason: String| Error::new(ErrorKind::InvalidData, format!("Invalid DKIM key record: {}", reason));
+		let tags = parse_tags(text).map_err(invalid)?;
+		if let Some(position) = tags.iter().position(|(tag, _)| tag == "v") {
+			// The version is optional, but has to come first when given...
+			if position != 0 || tags[0].1 != "DKIM1" {
+				return Err(invalid("not v=DKIM1".to_string()));
+			}
+		}
+
+		let get = |name: &str| tags.iter().find(|(tag, _)| tag == name).map(|(_, value)| value.as_str());
+		let list = |value: &str| -> Vec<String> {
+			value.split(':').map(str::trim).filter(|entry| !entry.is_empty()).map(str::to_string).collect()
+		};
+		let key: String = get("p").ok_or_else(|| invalid("no public key".to_string()))?
+			.chars()
+			.filter(|c| !c.is_ascii_whitespace())
+			.collect();
+		let public_key = BASE64.decode(key).map_err(|e| invalid(format!("bad public key: {}", e)))?;
+
+		Ok(DkimKey {
+			key_type: get("k").unwrap_or("rsa").to_ascii_lowercase(),
+			public_key,
+			hash_algorithms: get("h").map(list).unwrap_or_default(),
+			service_types: list(get("s").unwrap_or("*")),
+			flags: get("t").map(list).unwrap_or_default(),
+			notes: get("n").map(str::to_string),
+		})
+	}
+
+	pub fn is_revoked(&self) -> bool {
+		self.public_key.is_empty()
+	}
+
+	/// Whether the domain is testing DKIM, failures not to be treated differently from mail
+	/// without signatures.
+	pub fn is_testing(&self) -> bool {
+		self.flags.iter().any(|flag| flag.eq_ignore_ascii_case("y"))
+	}
+}
+
+/// The tags of a tag list (RFC 6376 3.2), `TAG=VALUE` separated by semicolons, in order. A
+/// tag given twice is an error.
+fn parse_tags(text: &str) -> std::result::Result<Vec<(String, String)>, String> {
+	let mut tags: Vec<(String, String)> = Vec::new();
+	for spec in text.split(';').map(str::trim).filter(|spec| !spec.is_empty()) {
+		let (tag, value) = spec.split_once('=').ok_or_else(|| format!("bad tag {}", spec))?;
+		let tag = tag.trim();
+		if tag.is_empty() || !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+			return Err(format!("bad tag {}", spec));
+		}
+		if tags.iter().any(|(seen, _)| seen == tag) {
+			return Err(format!("{} given twice", tag));
+		}
+		tags.push((tag.to_string(), value.trim().to_string()));
+	}
+	Ok(tags)
+}
+
+impl DNSClient {
+	/// The SPF record of `domain`, asked of the recursive resolver at `server`. A domain
+	/// without one gives an error of kind `NotFound`, a domain with several, or with a
+	/// malformed one, an error of kind `InvalidData` (RFC 7208 4.5).
+	pub fn lookup_spf(&self, domain: &str, server: SocketAddr) -> Result<SpfRecord> {
+		let domain = domain.trim_end_matches('.');
+		let records: Vec<String> = self.txt_records(domain, server)?.into_iter().filter(|text| SpfRecord::is_spf(text)).collect();
+		match records.as_slice() {
+			[] => Err(Error::new(ErrorKind::NotFound, format!("{} has no SPF record", domain))),
+			[record] => SpfRecord::parse(record),
+			_ => Err(Error::new(ErrorKind::InvalidData, format!("{} has several SPF records", domain))),
+		}
+	}
+
+	/// The DMARC policy of `domain`, from the TXT record of `_dmarc.DOMAIN` asked of the
+	/// recursive resolver at `server`. The policy of the organizational domain, which applies
+	/// when a subdomain has none, isn't looked for. Errors are as with `lookup_spf`.
+	pub fn lookup_dmarc(&self, domain: &str, server: SocketAddr) -> Result<DmarcPolicy> {
+		let name = format!("_dmarc.{}", domain.trim_end_matches('.'));
+		let records: Vec<String> = self.txt_records(&name, server)?.into_iter()
+			.filter(|text| text.split(';').next().is_some_and(|version| version.trim().replace(' ', "") == "v=DMARC1"))
+			.collect();
+		match records.as_slice() {
+			[] => Err(Error::new(ErrorKind::NotFound, format!("{} has no DMARC record", domain))),
+			[record] => DmarcPolicy::parse(record),
+			_ => Err(Error::new(ErrorKind::InvalidData, format!("{} has several DMARC records", domain))),
+		}
+	}
+
+	/// The DKIM key of `selector` for `domain`, from the TXT record of
+	/// `SELECTOR._domainkey.DOMAIN` asked of the recursive resolver at `server`. Of several
+	/// records, the first valid one is taken. A selector without a key gives an error of kind
+	/// `NotFound`.
+	pub fn lookup_dkim(&self, selector: &str, domain: &str, server: SocketAddr) -> Result<DkimKey> {
+		let name = format!("{}._domainkey.{}", selector, domain.trim_end_matches('.'));
+		let mut first_error = None;
+		for text in self.txt_records(&name, server)? {
+			match DkimKey::parse(&text) {
+				Ok(key) => return Ok(key),
+				Err(e) => {
+					first_error.get_or_insert(e);
+				}
+			}
+		}
+		Err(first_error.unwrap_or_else(|| Error::new(ErrorKind::NotFound, format!("{} has no DKIM key", name))))
+	}
+
+	/// The TXT records of `name`, their strings joined. A name that doesn't exist has none.
+	/// The name is taken as absolute: a policy is published under the domain it's for, so the
+	/// search list of the client mustn't turn the lookup into one for another domain.
+	fn txt_records(&self, name: &str, server: SocketAddr) -> Result<Vec<String>> {
+		let name = format!("{}.", name.trim_end_matches('.'));
+		let response = match self.checked_query(&name, QueryType::TXT, server) {
+			Ok(response) => response,
+			Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+			Err(e) => return Err(e),
+		};
+		Ok(response.answers.into_iter()
+			.filter_map(|record| record.txt_string())
+			.collect())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn mechanisms(text: &str) -> Vec<SpfMechanism> {
+		SpfRecord::parse(text).unwrap().directives.into_iter().map(|directive| directive.mechanism).collect()
+	}
+
+	#[test]
+	fn spf_dual_cidr() {
+		assert_eq!(mechanisms("v=spf1 a/24//64 mx:example.com//64 a:example.com/24"), vec![
+			SpfMechanism::A { domain: None, prefix_v4: Some(24), prefix_v6: Some(64) },
+			SpfMechanism::Mx { domain: Some("example.com".to_string()), prefix_v4: None, prefix_v6: Some(64) },
+			SpfMechanism::A { domain: Some("example.com".to_string()), prefix_v4: Some(24), prefix_v6: None },
+		]);
+		// The colon has to be followed by a domain...
+		assert!(SpfRecord::parse("v=spf1 a:/24//64 -all").is_err());
+		assert!(SpfRecord::parse("v=spf1 a/33 -all").is_err());
+		assert!(SpfRecord::parse("v=spf1 a//129 -all").is_err());
+	}
+
+	#[test]
+	fn spf_ip6() {
+		assert_eq!(mechanisms("v=spf1 ip6:2001:db8::/32 ip6:::1 -all"), vec![
+			SpfMechanism::Ip6 { addr: "2001:db8::".parse().unwrap(), prefix: 32 },
+			SpfMechanism::Ip6 { addr: Ipv6Addr::LOCALHOST, prefix: 128 },
+			SpfMechanism::All,
+		]);
+		assert!(SpfRecord::parse("v=spf1 ip6:2001:db8::/129").is_err());
+		assert!(SpfRecord::parse("v=spf1 ip6:192.0.2.1").is_err());
+	}
+
+	#[test]
+	fn spf_leading_zeros() {
+		assert_eq!(mechanisms("v=spf1 ip4:192.0.2.0/0"), vec![
+			SpfMechanism::Ip4 { addr: Ipv4Addr::new(192, 0, 2, 0), prefix: 0 },
+		]);
+		assert!(SpfRecord::parse("v=spf1 ip4:192.0.2.0/024").is_err());
+		assert!(SpfRecord::parse("v=spf1 ip6:2001:db8::/032").is_err());
+		assert!(SpfRecord::parse("v=spf1 a/024").is_err());
+		assert!(SpfRecord::parse("v=spf1 mx//064").is_err());
+	}
+
+	#[test]
+	fn spf_modifiers() {
+		let record = SpfRecord::parse("v=spf1 include:_spf.example.com ~all redirect=example.net exp=explain.example.com unknown=ignored").unwrap();
+		assert_eq!(record.directives, vec![
+			SpfDirective { qualifier: SpfQualifier::Pass, mechanism: SpfMechanism::Include("_spf.example.com".to_string()) },
+			SpfDirective { qualifier: SpfQualifier::SoftFail, mechanism: SpfMechanism::All },
+		]);
+		assert_eq!(record.redirect.as_deref(), Some("example.net"));
+		assert_eq!(record.explanation.as_deref(), Some("explain.example.com"));
+		assert_eq!(record.lookups(), 2);
+
+		assert!(SpfRecord::parse("v=spf1 redirect=example.net redirect=example.org").is_err());
+		assert!(SpfRecord::parse("v=spf1 redirect=example.net REDIRECT=example.net").is_err());
+		assert!(SpfRecord::parse("v=spf1 redirect=").is_err());
+		assert!(SpfRecord::parse("v=spf2 -all").is_err());
+	}
+
+	#[test]
+	fn dmarc_policy() {
+		let policy = DmarcPolicy::parse("v=DMARC1; p=reject; sp=quarantine; adkim=s; pct=50; rua=mailto:a@example.com, mailto:b@example.com").unwrap();
+		assert_eq!(policy.policy, DmarcDisposition::Reject);
+		assert_eq!(policy.subdomain_policy, DmarcDisposition::Quarantine);
+		assert_eq!(policy.dkim_alignment, DmarcAlignment::Strict);
+		assert_eq!(policy.spf_alignment, DmarcAlignment::Relaxed);
+		assert_eq!(policy.percent, 50);
+		assert_eq!(policy.aggregate_reports, vec!["mailto:a@example.com", "mailto:b@example.com"]);
+		assert_eq!(policy.report_interval, 86400);
+
+		assert!(DmarcPolicy::parse("p=reject; v=DMARC1").is_err());
+		assert!(DmarcPolicy::parse("v=DMARC1; p=reject; pct=101").is_err());
+	}
+
+	#[test]
+	fn dmarc_missing_policy() {
+		// A record with aggregate reports but no valid policy is taken as p=none...
+		let policy = DmarcPolicy::parse("v=DMARC1; rua=mailto:dmarc@example.com").unwrap();
+		assert_eq!(policy.policy, DmarcDisposition::None);
+		assert_eq!(policy.subdomain_policy, DmarcDisposition::None);
+		let policy = DmarcPolicy::parse("v=DMARC1; p=bounce; rua=mailto:dmarc@example.com").unwrap();
+		assert_eq!(policy.policy, DmarcDisposition::None);
+
+		assert!(DmarcPolicy::parse("v=DMARC1").is_err());
+		assert!(DmarcPolicy::parse("v=DMARC1; p=bounce").is_err());
+	}
+
+	#[test]
+	fn dkim_key() {
+		let key = DkimKey::parse("v=DKIM1; k=ed25519; t=y:s; p=AQID").unwrap();
+		assert_eq!(key.key_type, "ed25519");
+		assert_eq!(key.public_key, vec![1, 2, 3]);
+		assert_eq!(key.service_types, vec!["*"]);
+		assert!(key.is_testing());
+		assert!(!key.is_revoked());
+
+		// The version is optional...
+		assert_eq!(DkimKey::parse("p=AQ ID").unwrap().public_key, vec![1, 2, 3]);
+		assert!(DkimKey::parse("v=DKIM1; k=rsa").is_err());
+		assert!(DkimKey::parse("v=DKIM1; p=AQID; p=AQID").is_err());
+	}
+
+	#[test]
+	fn dkim_version_not_first() {
+		assert!(DkimKey::parse("k=rsa; v=DKIM1; p=AQID").is_err());
+		assert!(DkimKey::parse("v=DKIM2; p=AQID").is_err());
+	}
+
+	#[test]
+	fn dkim_revoked_key() {
+		let key = DkimKey::parse("v=DKIM1; k=rsa; p=").unwrap();
+		assert!(key.is_revoked());
+		assert_eq!(key.key_type, "rsa");
+	}
+}
@@ -0,0 +1,924 @@
+//! Multicast DNS responder announcing hosts of the .local domain on the LAN (RFC 6762)
+
+use std::fmt;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Arc, Mutex, MutexGuard, Weak };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use rand::Rng;
+use socket2::{ Domain, Protocol, Socket, Type };
+
+use crate::server::buffer::{ PacketBuffer, VectorPacketBuffer };
+use crate::server::context::ServerContext;
+use crate::server::dnssd::{ ServiceRegistration, SERVICE_TYPES };
+use crate::server::protocol::{ DNSHeader, ResultCode };
+
+pub const MDNS_PORT: u16 = 5353;
+pub const MDNS_GROUP_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+/// TTL of the records of the hosts (RFC 6762 10)...
+const HOST_TTL: u32 = 120;
+/// Highest TTL of the records answered to legacy unicast queries (RFC 6762 6.7)...
+const LEGACY_TTL: u32 = 10;
+const PROBE_COUNT: usize = 3;
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+const ANNOUNCE_COUNT: usize = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+/// Wait after losing the tiebreak of simultaneous probes, before probing again (RFC 6762 8.2)...
+const TIEBREAK_DELAY: Duration = Duration::from_secs(1);
+/// Conflicts within the window after which probing slows down (RFC 6762 8.1)...
+const CONFLICT_LIMIT: usize = 15;
+const CONFLICT_WINDOW: Duration = Duration::from_secs(10);
+const CONFLICT_DELAY: Duration = Duration::from_secs(5);
+/// Time the receivers wait for a message before checking whether the server is stopping...
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Largest message received (RFC 6762 17)...
+pub(crate) const MAX_MESSAGE: usize = 9000;
+/// Compression pointers followed in a name, against loops...
+const MAX_POINTERS: usize = 16;
+
+pub(crate) const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+pub(crate) const TYPE_A: u16 = 1;
+pub(crate) const TYPE_PTR: u16 = 12;
+pub(crate) const TYPE_TXT: u16 = 16;
+pub(crate) const TYPE_AAAA: u16 = 28;
+pub(crate) const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+/// Top bit of the class of a question asking for a unicast response...
+const UNICAST_RESPONSE: u16 = 0x8000;
+/// Top bit of the class of a record replacing the cached records of its name and type...
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// Where a host is in claiming its names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostState {
+	/// Asking whether another host has the names already, not answered yet...
+	Probing,
+	/// Telling the network about the records of the names, answered from now on...
+	Announcing,
+	Announced,
+}
+
+impl HostState {
+	pub fn name(&self) -> &'static str {
+		match *self {
+			HostState::Probing => "probing",
+			HostState::Announcing => "announcing",
+			HostState::Announced => "announced",
+		}
+	}
+}
+
+impl fmt::Display for HostState {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.name())
+	}
+}
+
+/// A record as written on the wire, the names in the RDATA uncompressed. The order is the
+/// lexicographical one the tiebreak of simultaneous probes goes by.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct MdnsRecord {
+	pub name: String,
+	pub q_type: u16,
+	pub rdata: Vec<u8>,
+}
+
+impl MdnsRecord {
+	fn new(name: &str, q_type: u16, rdata: Vec<u8>) -> Self {
+		MdnsRecord { name: name.to_string(), q_type, rdata }
+	}
+
+	/// Whether the record may be held by several hosts, which the PTR records of DNS-SD are,
+	/// rather than unique to one (RFC 6762 2).
+	fn is_shared(&self) -> bool {
+		self.q_type == TYPE_PTR && !self.name.ends_with(".arpa")
+	}
+}
+
+/// A message received, read without `DNSPacket`, which lowercases the names and drops the top
+/// bit of the classes. The records come with their TTL and cache-flush bit.
+pub(crate) struct MdnsMessage {
+	pub header: DNSHeader,
+	/// Name and type of the questions for the IN class, and whether they ask for a unicast
+	/// response...
+	pub questions: Vec<(String, u16, bool)>,
+	pub answers: Vec<(MdnsRecord, u32, bool)>,
+	pub authorities: Vec<(MdnsRecord, u32, bool)>,
+	pub additional: Vec<(MdnsRecord, u32, bool)>,
+}
+
+impl MdnsMessage {
+	pub fn read(data: &[u8]) -> Result<MdnsMessage> {
+		let mut header = DNSHeader::new();
+		header.read(&mut VectorPacketBuffer::from_bytes(data.to_vec()))?;
+		let mut reader = Reader { data, pos: 12 };
+		let mut questions = Vec::new();
+		for _ in 0..header.questions {
+			let name = reader.name()?;
+			let q_type = reader.u16()?;
+			let class = reader.u16()?;
+			if matches!(class & !UNICAST_RESPONSE, CLASS_IN | CLASS_ANY) {
+				questions.push((name, q_type, class & UNICAST_RESPONSE != 0));
+			}
+		}
+		let answers = (0..header.answers).map(|_| reader.record()).collect::<Result<_>>()?;
+		let authorities = (0..header.authoritative_entries).map(|_| reader.record()).collect::<Result<_>>()?;
+		let additional = (0..header.additional_entries).map(|_| reader.record()).collect::<Result<_>>()?;
+		Ok(MdnsMessage { header, questions, answers, authorities, additional })
+	}
+
+	/// The records of the answer and additional sections.
+	pub fn records(&self) -> impl Iterator<Item = &(MdnsRecord, u32, bool)> {
+		self.answers.iter().chain(&self.additional)
+	}
+}
+
+struct Reader<'a> {
+	data: &'a [u8],
+	pos: usize,
+}
+
+impl Reader<'_> {
+	fn bytes(&mut self, len: usize) -> Result<&[u8]> {
+		let bytes = self.data.get(self.pos..self.pos + len).ok_or_else(truncated)?;
+		self.pos += len;
+		Ok(bytes)
+	}
+
+	fn u16(&mut self) -> Result<u16> {
+		self.bytes(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+	}
+
+	fn u32(&mut self) -> Result<u32> {
+		self.bytes(4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+	}
+
+	/// A name, following the compression pointers, with the case of its labels.
+	fn name(&mut self) -> Result<String> {
+		let mut labels = Vec::new();
+		let mut pos = self.pos;
+		let mut end = None;
+		let mut pointers = 0;
+		loop {
+			let len = *self.data.get(pos).ok_or_else(truncated)? as usize;
+			if len & 0xc0 == 0xc0 {
+				let low = *self.data.get(pos + 1).ok_or_else(truncated)? as usize;
+				end.get_or_insert(pos + 2);
+				pointers += 1;
+				if pointers > MAX_POINTERS {
+					return Err(Error::new(ErrorKind::InvalidData, "Too many compression pointers"));
+				}
+				pos = ((len & 0x3f) << 8) | low;
+				continue;
+			}
+			if len == 0 {
+				pos += 1;
+				break;
+			}
+			let label = self.data.get(pos + 1..pos + 1 + len).ok_or_else(truncated)?;
+			labels.push(String::from_utf8_lossy(label).into_owned());
+			pos += 1 + len;
+		}
+		self.pos = end.unwrap_or(pos);
+		Ok(labels.join("."))
+	}
+
+	fn record(&mut self) -> Result<(MdnsRecord, u32, bool)> {
+		let name = self.name()?;
+		let q_type = self.u16()?;
+		let class = self.u16()?;
+		let ttl = self.u32()?;
+		let len = self.u16()? as usize;
+		let end = self.pos + len;
+		if end > self.data.len() {
+			return Err(truncated());
+		}
+		let rdata = match q_type {
+			TYPE_PTR => encode_name(&self.name()?),
+			TYPE_SRV => {
+				let mut rdata = self.bytes(6)?.to_vec();
+				rdata.extend(encode_name(&self.name()?));
+				rdata
+			}
+			_ => self.bytes(len)?.to_vec(),
+		};
+		self.pos = end;
+		Ok((MdnsRecord { name, q_type, rdata }, ttl, class & CACHE_FLUSH != 0))
+	}
+}
+
+fn truncated() -> Error {
+	Error::new(ErrorKind::UnexpectedEof, "Truncated mDNS message")
+}
+
+/// A service of a host, under the instance name it claimed.
+#[derive(Debug)]
+struct MdnsService {
+	/// Instance name given, which the names tried after conflicts are derived from...
+	configured: String,
+	registration: ServiceRegistration,
+}
+
+#[derive(Debug)]
+struct MdnsHost {
+	/// Name given, which the names tried after conflicts are derived from...
+	configured: String,
+	name: String,
+	addrs: Vec<IpAddr>,
+	services: Vec<MdnsService>,
+	state: HostState,
+	/// Probes or announcements sent in the current state...
+	sent: usize,
+	/// Names of the host answered by others with records of their own, flagged by the
+	/// receivers...
+	conflicted: Vec<String>,
+	/// Set by the receivers when a simultaneous probe of a name wins the tiebreak...
+	lost_tiebreak: bool,
+	conflicts: Vec<Instant>,
+}
+
+impl MdnsHost {
+	/// The addresses of the host, the reverse names pointing to it and the records of its
+	/// services: browsing PTRs, SRV and TXT records (RFC 6763 4 to 6 and 9).
+	fn records(&self) -> Vec<MdnsRecord> {
+		let mut records = Vec::new();
+		for addr in &self.addrs {
+			records.push(match *addr {
+				IpAddr::V4(v4) => MdnsRecord::new(&self.name, TYPE_A, v4.octets().to_vec()),
+				IpAddr::V6(v6) => MdnsRecord::new(&self.name, TYPE_AAAA, v6.octets().to_vec()),
+			});
+			records.push(MdnsRecord::new(&reverse_name(*addr), TYPE_PTR, encode_name(&self.name)));
+		}
+		for service in &self.services {
+			let registration = &service.registration;
+			let service_name = format!("{}.local", registration.service_type);
+			let instance_name = registration.name();
+			let mut srv = vec![0, 0, 0, 0];
+			srv.extend_from_slice(&registration.port.to_be_bytes());
+			srv.extend(encode_name(&self.name));
+			records.push(MdnsRecord::new(SERVICE_TYPES, TYPE_PTR, encode_name(&service_name)));
+			records.push(MdnsRecord::new(&service_name, TYPE_PTR, encode_name(&instance_name)));
+			records.push(MdnsRecord::new(&instance_name, TYPE_SRV, srv));
+			records.push(MdnsRecord::new(&instance_name, TYPE_TXT, encode_txt(&registration.txt)));
+		}
+		records.sort();
+		records.dedup();
+		records
+	}
+
+	/// The names only this host may answer: its own and those of its service instances.
+	fn unique_names(&self) -> Vec<String> {
+		let mut names = vec![self.name.clone()];
+		names.extend(self.services.iter().map(|service| service.registration.name()));
+		names
+	}
+
+	/// The records of `name` unique to the host, sorted, as compared in the tiebreak.
+	fn unique_records(&self, name: &str) -> Vec<MdnsRecord> {
+		self.records().into_iter().filter(|record| !record.is_shared() && same_name(&record.name, name)).collect()
+	}
+
+	/// The records answered along with `answers` to spare the querier further queries: the
+	/// SRV and TXT records of the instances browsed and the addresses of the host, for the
+	/// records of its service or its name (RFC 6762 6.2 and RFC 6763 12).
+	fn additional(&self, answers: &[MdnsRecord]) -> Vec<MdnsRecord> {
+		let mut names: Vec<String> = self.services.iter()
+			.map(|service| service.registration.name())
+			.filter(|instance| answers.iter().any(|record| {
+				same_name(&record.name, instance) || (record.is_shared() && record.rdata == encode_name(instance))
+			}))
+			.collect();
+		if !names.is_empty() || answers.iter().any(|record| same_name(&record.name, &self.name)) {
+			names.push(self.name.clone());
+		}
+		self.records().into_iter()
+			.filter(|record| !record.is_shared() && names.iter().any(|name| same_name(&record.name, name)))
+			.filter(|record| !answers.contains(record))
+			.collect()
+	}
+}
+
+/// An mDNS message written by the responder or the browser.
+pub(crate) struct Message {
+	pub id: u16,
+	pub response: bool,
+	/// Name, type and class of the questions...
+	pub questions: Vec<(String, u16, u16)>,
+	pub answers: Vec<MdnsRecord>,
+	pub authorities: Vec<MdnsRecord>,
+	pub additional: Vec<MdnsRecord>,
+	pub ttl: u32,
+	/// Whether the unique records carry the cache-flush bit...
+	pub flush: bool,
+}
+
+impl Message {
+	fn response(answers: Vec<MdnsRecord>, ttl: u32) -> Message {
+		Message {
+			id: 0,
+			response: true,
+			questions: Vec::new(),
+			answers,
+			authorities: Vec::new(),
+			additional: Vec::new(),
+			ttl,
+			flush: true,
+		}
+	}
+
+	/// A query for the `questions` of the IN class, of the records the querier knows
+	/// already as `answers`.
+	pub fn query(questions: Vec<(String, u16)>, answers: Vec<MdnsRecord>, ttl: u32) -> Message {
+		Message {
+			id: 0,
+			response: false,
+			questions: questions.into_iter().map(|(name, q_type)| (name, q_type, CLASS_IN)).collect(),
+			answers,
+			authorities: Vec::new(),
+			additional: Vec::new(),
+			ttl,
+			flush: false,
+		}
+	}
+
+	pub fn encode(&self) -> Result<Vec<u8>> {
+		let mut header = DNSHeader::new();
+		header.id = self.id;
+		header.response = self.response;
+		header.authoritative_answer = self.response;
+		header.questions = self.questions.len() as u16;
+		header.answers = self.answers.len() as u16;
+		header.authoritative_entries = self.authorities.len() as u16;
+		header.additional_entries = self.additional.len() as u16;
+
+		let mut buffer = VectorPacketBuffer::new();
+		header.write(&mut buffer)?;
+		for (name, q_type, class) in &self.questions {
+			buffer.write_qname(name)?;
+			buffer.write_u16(*q_type)?;
+			buffer.write_u16(*class)?;
+		}
+		for record in self.answers.iter().chain(&self.authorities).chain(&self.additional) {
+			buffer.write_qname(&record.name)?;
+			buffer.write_u16(record.q_type)?;
+			buffer.write_u16(if self.flush && !record.is_shared() { CLASS_IN | CACHE_FLUSH } else { CLASS_IN })?;
+			buffer.write_u32(self.ttl)?;
+			buffer.write_u16(record.rdata.len() as u16)?;
+			for byte in &record.rdata {
+				buffer.write(*byte)?;
+			}
+		}
+		Ok(buffer.into_inner())
+	}
+}
+
+/// Multicast DNS responder for hosts of the .local domain, given as `NAME ADDR...`, for the
+/// other devices of the LAN to find them without a DNS server. Every host first probes its
+/// name and, unless another host answers it with other addresses, announces its A, AAAA and
+/// reverse PTR records and answers them from then on. A host losing its name is renamed
+/// `NAME-2`, `NAME-3` and so on, and probes again; of two hosts probing the same name at the
+/// same time the one with the lexicographically later records keeps it (RFC 6762 8 and 9).
+///
+/// The hosts also announce the DNS-SD services registered on them (see `ServiceRegistration`),
+/// whose instance names are claimed the same way, an instance losing its name being renamed
+/// `NAME (2)` and so on (RFC 6763 9).
+///
+/// Queries are answered on the multicast groups, or to the querier when asked for a unicast
+/// response or sent from another port than 5353 (legacy unicast), leaving out the records the
+/// querier knows already. The hosts say goodbye, announcing their records with a TTL of 0,
+/// when the server shuts down.
+#[derive(Debug)]
+pub struct MdnsResponder {
+	hosts: Mutex<Vec<MdnsHost>>,
+	/// Interface joining the IPv4 group and sending to it...
+	interface: Ipv4Addr,
+	/// The sockets joined to the groups, with their group...
+	sockets: Mutex<Vec<(Arc<UdpSocket>, SocketAddr)>>,
+	answered: AtomicU64,
+	conflicts: AtomicU64,
+}
+
+impl Default for MdnsResponder {
+	fn default() -> Self {
+		MdnsResponder {
+			hosts: Mutex::new(Vec::new()),
+			interface: Ipv4Addr::UNSPECIFIED,
+			sockets: Mutex::new(Vec::new()),
+			answered: AtomicU64::new(0),
+			conflicts: AtomicU64::new(0),
+		}
+	}
+}
+
+impl MdnsResponder {
+	pub fn new() -> Self {
+		MdnsResponder::default()
+	}
+
+	/// Announce the host given as `NAME ADDR...`, `.local` being added to single label names.
+	pub fn add_host(&mut self, spec: &str) -> Result<()> {
+		let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid mDNS host: {}", spec));
+		let mut fields = spec.split_whitespace();
+		let name = local_name(fields.next().ok_or_else(invalid)?)?;
+		let addrs = fields.map(|addr| addr.parse::<IpAddr>().map_err(|_| invalid())).collect::<Result<Vec<_>>>()?;
+		if addrs.is_empty() {
+			return Err(invalid());
+		}
+		let hosts = self.hosts.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+		hosts.retain(|host| host.configured != name);
+		hosts.push(MdnsHost {
+			configured: name.clone(),
+			name,
+			addrs,
+			services: Vec::new(),
+			state: HostState::Probing,
+			sent: 0,
+			conflicted: Vec::new(),
+			lost_tiebreak: false,
+			conflicts: Vec::new(),
+		});
+		Ok(())
+	}
+
+	/// Announce `registration` on its host, which has to be one of the responder's. Once the
+	/// responder runs, the host probes its names again before answering for the service, and
+	/// until then for itself.
+	pub fn add_service(&self, registration: ServiceRegistration) -> Result<()> {
+		registration.check()?;
+		let host_name = local_name(&registration.host)?;
+		let mut hosts = self.lock_hosts();
+		let host = hosts.iter_mut()
+			.find(|host| host.configured == host_name || host.name == host_name)
+			.ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Service {} on an unknown mDNS host: {}", registration.name(), host_name)))?;
+		host.services.retain(|service| !same_name(&service.configured, &registration.instance) || service.registration.service_type != registration.service_type);
+		host.services.push(MdnsService { configured: registration.instance.clone(), registration });
+		host.state = HostState::Probing;
+		host.sent = 0;
+		Ok(())
+	}
+
+	/// Join the IPv4 group and send to it on the interface of `addr` rather than the default.
+	pub fn set_interface(&mut self, addr: Ipv4Addr) {
+		self.interface = addr;
+	}
+
+	pub fn interface(&self) -> Ipv4Addr {
+		self.interface
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.lock_hosts().is_empty()
+	}
+
+	/// The name of every host, with its addresses and state.
+	pub fn hosts(&self) -> Vec<(String, Vec<IpAddr>, HostState)> {
+		self.lock_hosts().iter().map(|host| (host.name.clone(), host.addrs.clone(), host.state)).collect()
+	}
+
+	/// The services of every host, under the instance and host names they claimed, with the
+	/// state of their host.
+	pub fn services(&self) -> Vec<(ServiceRegistration, HostState)> {
+		self.lock_hosts().iter()
+			.flat_map(|host| host.services.iter().map(move |service| {
+				let mut registration = service.registration.clone();
+				registration.host = host.name.clone();
+				(registration, host.state)
+			}))
+			.collect()
+	}
+
+	/// Number of queries answered.
+	pub fn answered(&self) -> u64 {
+		self.answered.load(Ordering::Relaxed)
+	}
+
+	/// Number of names found taken by other hosts.
+	pub fn conflicts(&self) -> u64 {
+		self.conflicts.load(Ordering::Relaxed)
+	}
+
+	fn lock_hosts(&self) -> MutexGuard<'_, Vec<MdnsHost>> {
+		self.hosts.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+	}
+
+	/// Join the groups and start probing the names of the hosts of `context`, receiving and
+	/// probing in the background for as long as the context is around and the server isn't
+	/// stopping. Joining the IPv6 group is optional, the IPv4 one isn't.
+	pub fn start(context: &Arc<ServerContext>) -> Result<()> {
+		let responder = &context.mdns;
+		let mut sockets = vec![(Arc::new(bind_v4(responder.interface)?), SocketAddr::from((MDNS_GROUP_V4, MDNS_PORT)))];
+		match bind_v6() {
+			Ok(socket) => sockets.push((Arc::new(socket), SocketAddr::from((MDNS_GROUP_V6, MDNS_PORT)))),
+			Err(e) => info!("Failed to join the IPv6 mDNS group, answering over IPv4 only: {}", e),
+		}
+		for (socket, group) in &sockets {
+			start_receiver(context, socket.clone(), *group)?;
+		}
+		*responder.sockets.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = sockets;
+		for index in 0..responder.lock_hosts().len() {
+			start_prober(context, index)?;
+		}
+		Ok(())
+	}
+
+	/// Announce the records of the hosts announced with a TTL of 0, for the other devices to
+	/// forget them.
+	pub fn goodbye(&self) {
+		let records: Vec<MdnsRecord> = self.lock_hosts().iter()
+			.filter(|host| host.state != HostState::Probing)
+			.flat_map(|host| host.records())
+			.collect();
+		if !records.is_empty() {
+			self.multicast(&Message::response(records, 0));
+		}
+	}
+
+	/// Send `message` to every group joined.
+	fn multicast(&self, message: &Message) {
+		let data = match message.encode() {
+			Ok(data) => data,
+			Err(e) => {
+				info!("Failed to write an mDNS message: {}", e);
+				return;
+			}
+		};
+		let sockets = self.sockets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+		for (socket, group) in sockets.iter() {
+			if let Err(e) = socket.send_to(&data, group) {
+				debug!("Failed to send an mDNS message to {}: {}", group, e);
+			}
+		}
+	}
+
+	/// Take the next step of claiming the names of the host at `index`, returning how long to
+	/// wait before the next one.
+	fn step(&self, index: usize) -> Duration {
+		let mut hosts = self.lock_hosts();
+		let host = &mut hosts[index];
+		if !host.conflicted.is_empty() {
+			let conflicted = std::mem::take(&mut host.conflicted);
+			host.sent = 0;
+			if host.state != HostState::Probing {
+				info!("Another host answers {} over mDNS, probing again", conflicted.join(", "));
+				host.state = HostState::Probing;
+				return PROBE_INTERVAL;
+			}
+			self.conflicts.fetch_add(conflicted.len() as u64, Ordering::Relaxed);
+			let now = Instant::now();
+			host.conflicts.retain(|at| now.duration_since(*at) < CONFLICT_WINDOW);
+			host.conflicts.push(now);
+			for name in conflicted {
+				if same_name(&name, &host.name) {
+					let renamed = renamed(&host.configured, &host.name);
+					info!("The mDNS name {} is taken, probing {} instead", host.name, renamed);
+					host.name = renamed;
+				} else if let Some(service) = host.services.iter_mut().find(|service| same_name(&service.registration.name(), &name)) {
+					service.registration.instance = renamed_instance(&service.configured, &service.registration.instance);
+					info!("The mDNS service {} is taken, probing {} instead", name, service.registration.name());
+				}
+			}
+			return if host.conflicts.len() >= CONFLICT_LIMIT { CONFLICT_DELAY } else { PROBE_INTERVAL };
+		}
+		if host.lost_tiebreak {
+			host.lost_tiebreak = false;
+			host.sent = 0;
+			return TIEBREAK_DELAY;
+		}
+
+		match host.state {
+			HostState::Probing if host.sent < PROBE_COUNT => {
+				host.sent += 1;
+				let names = host.unique_names();
+				self.multicast(&Message {
+					id: 0,
+					response: false,
+					questions: names.iter().map(|name| (name.clone(), TYPE_ANY, CLASS_IN | UNICAST_RESPONSE)).collect(),
+					answers: Vec::new(),
+					authorities: names.iter().flat_map(|name| host.unique_records(name)).collect(),
+					additional: Vec::new(),
+					ttl: HOST_TTL,
+					flush: false,
+				});
+				PROBE_INTERVAL
+			}
+			HostState::Probing | HostState::Announcing => {
+				if host.state == HostState::Probing {
+					host.state = HostState::Announcing;
+					host.sent = 0;
+				}
+				host.sent += 1;
+				self.multicast(&Message::response(host.records(), HOST_TTL));
+				if host.sent >= ANNOUNCE_COUNT {
+					host.state = HostState::Announced;
+					info!("Announced {} over mDNS", host.unique_names().join(", "));
+				}
+				ANNOUNCE_INTERVAL
+			}
+			HostState::Announced => PROBE_INTERVAL,
+		}
+	}
+
+	/// Handle a message received on `socket` from `source`: responses are checked for
+	/// conflicts, queries for simultaneous probes, and answered.
+	fn receive(&self, socket: &UdpSocket, group: SocketAddr, data: &[u8], source: SocketAddr) -> Result<()> {
+		let message = MdnsMessage::read(data)?;
+		// Messages of other opcodes and response codes are ignored (RFC 6762 18.3 and 18.11)...
+		if message.header.opcode != 0 || message.header.rescode != ResultCode::NOERROR {
+			return Ok(());
+		}
+		if message.header.response {
+			self.check_conflicts(&message);
+			return Ok(());
+		}
+		self.check_probes(&message);
+		self.answer(socket, group, source, &message)
+	}
+
+	/// Flag the names of the hosts answered by others with records of their own.
+	fn check_conflicts(&self, message: &MdnsMessage) {
+		let mut hosts = self.lock_hosts();
+		for host in hosts.iter_mut() {
+			for name in host.unique_names() {
+				let own = host.unique_records(&name);
+				let conflict = message.records()
+					.any(|(record, _, _)| !record.is_shared() && same_name(&record.name, &name) && !own.contains(record));
+				if conflict && !host.conflicted.contains(&name) {
+					host.conflicted.push(name);
+				}
+			}
+		}
+	}
+
+	/// Flag the hosts probing a name another host probes at the same time with later records.
+	fn check_probes(&self, message: &MdnsMessage) {
+		let mut hosts = self.lock_hosts();
+		for host in hosts.iter_mut().filter(|host| host.state == HostState::Probing) {
+			for name in host.unique_names() {
+				if !message.questions.iter().any(|(question, _, _)| same_name(question, &name)) {
+					continue;
+				}
+				let mut theirs: Vec<MdnsRecord> = message.authorities.iter()
+					.map(|(record, _, _)| record)
+					.filter(|record| same_name(&record.name, &name))
+					.cloned()
+					.collect();
+				theirs.sort();
+				if !theirs.is_empty() && theirs > host.unique_records(&name) {
+					host.lost_tiebreak = true;
+				}
+			}
+		}
+	}
+
+	fn answer(&self, socket: &UdpSocket, group: SocketAddr, source: SocketAddr, message: &MdnsMessage) -> Result<()> {
+		let legacy = source.port() != MDNS_PORT;
+		let mut answers = Vec::new();
+		let mut additional = Vec::new();
+		let mut unicast = true;
+		for host in self.lock_hosts().iter().filter(|host| host.state != HostState::Probing) {
+			let records = host.records();
+			let mut matching = Vec::new();
+			for (name, q_type, unicast_response) in &message.questions {
+				let before = matching.len();
+				matching.extend(records.iter()
+					.filter(|record| same_name(&record.name, name) && (*q_type == TYPE_ANY || record.q_type == *q_type))
+					.cloned());
+				if matching.len() > before {
+					unicast &= *unicast_response;
+				}
+			}
+			additional.extend(host.additional(&matching));
+			answers.extend(matching);
+		}
+
+		// Known-answer suppression: the records the querier has with at least half their TTL
+		// left aren't answered again (RFC 6762 7.1)...
+		let known: Vec<&MdnsRecord> = message.answers.iter()
+			.filter(|(_, ttl, _)| *ttl >= HOST_TTL / 2)
+			.map(|(record, _, _)| record)
+			.collect();
+		answers.retain(|record| !known.contains(&record));
+		answers.sort();
+		answers.dedup();
+		if answers.is_empty() {
+			return Ok(());
+		}
+		additional.retain(|record| !answers.contains(record));
+		additional.sort();
+		additional.dedup();
+
+		let mut response = Message::response(answers, HOST_TTL);
+		response.additional = additional;
+		let destination = if legacy {
+			// Legacy unicast queries get a conventional response: their ID and questions, no
+			// cache-flush bits and short TTLs (RFC 6762 6.7)...
+			response.id = message.header.id;
+			response.questions = message.questions.iter().map(|(name, q_type, _)| (name.clone(), *q_type, CLASS_IN)).collect();
+			response.ttl = LEGACY_TTL;
+			response.flush = false;
+			source
+		} else if unicast {
+			source
+		} else {
+			group
+		};
+		socket.send_to(&response.encode()?, destination)?;
+		self.answered.fetch_add(1, Ordering::Relaxed);
+		Ok(())
+	}
+}
+
+/// Receive the messages sent to `group` on `socket`.
+fn start_receiver(context: &Arc<ServerContext>, socket: Arc<UdpSocket>, group: SocketAddr) -> Result<()> {
+	let context: Weak<ServerContext> = Arc::downgrade(context);
+	thread::Builder::new()
+		.name("mdns-receive".to_string())
+		.spawn(move || {
+			let mut buf = vec![0u8; MAX_MESSAGE];
+			loop {
+				let received = socket.recv_from(&mut buf);
+				let context = match context.upgrade() {
+					Some(context) => context,
+					None => return,
+				};
+				if context.shutdown.is_stopping() {
+					return;
+				}
+				match received {
+					Ok((len, source)) => {
+						if let Err(e) = context.mdns.receive(&socket, group, &buf[..len], source) {
+							debug!("Ignored an mDNS message from {}: {}", source, e);
+						}
+					}
+					Err(ref e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+					Err(e) => {
+						info!("Failed to receive on the mDNS group {}: {}", group, e);
+						return;
+					}
+				}
+			}
+		})?;
+	Ok(())
+}
+
+/// Probe, announce and defend the names of the host at `index`.
+fn start_prober(context: &Arc<ServerContext>, index: usize) -> Result<()> {
+	let context: Weak<ServerContext> = Arc::downgrade(context);
+	thread::Builder::new()
+		.name("mdns-probe".to_string())
+		.spawn(move || {
+			// Probing starts after a random delay, for devices powered on together not to
+			// probe at the same time (RFC 6762 8.1)...
+			let mut wait = Duration::from_millis(rand::thread_rng().gen_range(0..250));
+			loop {
+				thread::sleep(wait);
+				let context = match context.upgrade() {
+					Some(context) => context,
+					None => return,
+				};
+				if context.shutdown.is_stopping() {
+					return;
+				}
+				wait = context.mdns.step(index);
+			}
+		})?;
+	Ok(())
+}
+
+/// A socket joined to the IPv4 group on the interface of `interface`, shared with the other
+/// mDNS software of the machine.
+pub(crate) fn bind_v4(interface: Ipv4Addr) -> Result<UdpSocket> {
+	let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+	socket.set_reuse_address(true)?;
+	#[cfg(unix)]
+	socket.set_reuse_port(true)?;
+	socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+	socket.join_multicast_v4(&MDNS_GROUP_V4, &interface)?;
+	socket.set_multicast_if_v4(&interface)?;
+	socket.set_multicast_ttl_v4(255)?;
+	socket.set_multicast_loop_v4(true)?;
+	socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+	Ok(socket.into())
+}
+
+fn bind_v6() -> Result<UdpSocket> {
+	let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+	socket.set_only_v6(true)?;
+	socket.set_reuse_address(true)?;
+	#[cfg(unix)]
+	socket.set_reuse_port(true)?;
+	socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+	socket.join_multicast_v6(&MDNS_GROUP_V6, 0)?;
+	socket.set_multicast_hops_v6(255)?;
+	socket.set_multicast_loop_v6(true)?;
+	socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+	Ok(socket.into())
+}
+
+/// `name` under .local, `.local` being added to a single label.
+fn local_name(name: &str) -> Result<String> {
+	let name = name.trim_end_matches('.').to_lowercase();
+	if name.ends_with(".local") {
+		Ok(name)
+	} else if !name.is_empty() && !name.contains('.') {
+		Ok(format!("{}.local", name))
+	} else {
+		Err(Error::new(ErrorKind::InvalidInput, format!("mDNS host outside of .local: {}", name)))
+	}
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+	a.trim_end_matches('.').eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+/// The name tried after `current` is found taken: `NAME-2.local` after `NAME.local`, then
+/// `NAME-3.local` and so on.
+fn renamed(configured: &str, current: &str) -> String {
+	let base = configured.trim_end_matches(".local");
+	let next = current.trim_end_matches(".local")
+		.strip_prefix(base)
+		.and_then(|suffix| suffix.strip_prefix('-'))
+		.and_then(|number| number.parse::<u32>().ok())
+		.map_or(2, |number| number + 1);
+	format!("{}-{}.local", base, next)
+}
+
+/// The instance name tried after `current` is found taken: `NAME (2)` after `NAME`, then
+/// `NAME (3)` and so on.
+fn renamed_instance(configured: &str, current: &str) -> String {
+	let next = current.strip_prefix(configured)
+		.and_then(|suffix| suffix.strip_prefix(" ("))
+		.and_then(|suffix| suffix.strip_suffix(')'))
+		.and_then(|number| number.parse::<u32>().ok())
+		.map_or(2, |number| number + 1);
+	format!("{} ({})", configured, next)
+}
+
+/// The name under `in-addr.arpa` or `ip6.arpa` of an address.
+fn reverse_name(addr: IpAddr) -> String {
+	match addr {
+		IpAddr::V4(v4) => {
+			let octets = v4.octets();
+			format!("{}.{}.{}.{}.in-addr.arpa", octets[3], octets[2], octets[1], octets[0])
+		}
+		IpAddr::V6(v6) => {
+			let nibbles: Vec<String> = v6.octets().iter().rev()
+				.flat_map(|octet| [octet & 0xf, octet >> 4])
+				.map(|nibble| format!("{:x}", nibble))
+				.collect();
+			format!("{}.ip6.arpa", nibbles.join("."))
+		}
+	}
+}
+
+/// A name in wire format, uncompressed.
+pub(crate) fn encode_name(name: &str) -> Vec<u8> {
+	let mut encoded = Vec::new();
+	for label in name.split('.').filter(|label| !label.is_empty()) {
+		encoded.push(label.len() as u8);
+		encoded.extend_from_slice(label.as_bytes());
+	}
+	encoded.push(0);
+	encoded
+}
+
+/// A name from its wire format, uncompressed, and the bytes following it.
+pub(crate) fn decode_name(data: &[u8]) -> Result<(String, &[u8])> {
+	let mut reader = Reader { data, pos: 0 };
+	let name = reader.name()?;
+	Ok((name, &data[reader.pos..]))
+}
+
+/// The RDATA of a TXT record of the strings `entries`, a single empty string without any
+/// (RFC 6763 6.1).
+fn encode_txt(entries: &[String]) -> Vec<u8> {
+	let mut encoded = Vec::new();
+	for entry in entries {
+		let entry = &entry.as_bytes()[..entry.len().min(255)];
+		encoded.push(entry.len() as u8);
+		encoded.extend_from_slice(entry);
+	}
+	if encoded.is_empty() {
+		encoded.push(0);
+	}
+	encoded
+}
+
+/// The strings of the RDATA of a TXT record, the empty ones left out.
+pub(crate) fn decode_txt(mut rdata: &[u8]) -> Vec<String> {
+	let mut entries = Vec::new();
+	while let Some((&len, rest)) = rdata.split_first() {
+		let len = (len as usize).min(rest.len());
+		if len > 0 {
+			entries.push(String::from_utf8_lossy(&rest[..len]).into_owned());
+		}
+		rdata = &rest[len..];
+	}
+	entries
+}
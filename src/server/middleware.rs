@@ -0,0 +1,150 @@
+//! Hooks letting middleware vet queries and edit the EDNS options of queries and responses
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::server::protocol::{ DNSPacket, DNSQuestion, EdnsOption };
+
+/// Middleware editing the EDNS options of the queries the resolvers send to other servers,
+/// and of the responses going back to the clients: say adding a client subnet for some
+/// domains, stripping cookies or adding an option for internal tracing. Both methods leave
+/// the options alone unless implemented.
+///
+/// Only messages using EDNS have options to edit. Responses are cached by their question
+/// alone, whatever options the query went out with, and those relayed in pass-through mode
+/// aren't edited.
+pub trait EdnsHook: Send + Sync {
+	/// Edit the options of a query for `question` about to be sent on for `client`, if known.
+	fn query_options(&self, _question: &DNSQuestion, _client: Option<IpAddr>, _options: &mut Vec<EdnsOption>) {}
+
+	/// Edit the options of the response to `request` about to go back to `client`.
+	fn response_options(&self, _request: &DNSPacket, _client: IpAddr, _options: &mut Vec<EdnsOption>) {}
+}
+
+/// The hooks of the server, run in the order they were added.
+#[derive(Clone, Default)]
+pub struct EdnsHooks {
+	hooks: Vec<Arc<dyn EdnsHook>>,
+}
+
+impl EdnsHooks {
+	pub fn new() -> Self {
+		EdnsHooks::default()
+	}
+
+	pub fn add(&mut self, hook: Arc<dyn EdnsHook>) {
+		self.hooks.push(hook);
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.hooks.is_empty()
+	}
+
+	/// Run the hooks on a query about to be sent on for `client`.
+	pub fn edit_query(&self, query: &mut DNSPacket, client: Option<IpAddr>) {
+		if self.hooks.is_empty() {
+			return;
+		}
+		let question = match query.questions.first() {
+			Some(question) => question.clone(),
+			None => return,
+		};
+		let mut options = match query.edns_options() {
+			Some(options) => options,
+			None => return,
+		};
+		for hook in &self.hooks {
+			hook.query_options(&question, client, &mut options);
+		}
+		query.set_edns_options(&options);
+	}
+
+	/// Run the hooks on the response to `request` about to go back to `client`.
+	pub fn edit_response(&self, request: &DNSPacket, response: &mut DNSPacket, client: IpAddr) {
+		if self.hooks.is_empty() {
+			return;
+		}
+		let mut options = match response.edns_options() {
+			Some(options) => options,
+			None => return,
+		};
+		for hook in &self.hooks {
+			hook.response_options(request, client, &mut options);
+		}
+		response.set_edns_options(&options);
+	}
+}
+
+/// What a query hook makes of a query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryVerdict {
+	/// Answer it as usual, as far as the hook is concerned.
+	Pass,
+	/// Answer it with REFUSED.
+	Refuse,
+	/// Send no response at all.
+	Drop,
+	/// Answer it with an empty truncated response, sending the client over to TCP; queries
+	/// which didn't come over UDP are dropped instead.
+	Truncate,
+}
+
+/// Middleware vetting the queries of the clients before they're answered: say dropping
+/// those looking like tunneling, or refusing the clients of some network. Queries answered
+/// over HTTPS get a `403` rather than no response when dropped.
+pub trait QueryHook: Send + Sync {
+	/// Name of the hook in the stats.
+	fn name(&self) -> &str;
+
+	/// Decide what to do with `request` from `client`.
+	fn check_query(&self, request: &DNSPacket, client: IpAddr) -> QueryVerdict;
+
+	/// See the response sent for `request` to `client`, for hooks keeping track of what the
+	/// clients get. Nothing unless implemented.
+	fn observe_response(&self, _request: &DNSPacket, _response: &DNSPacket, _client: IpAddr) {}
+
+	/// Counters of the hook by name, for the stats. None unless implemented.
+	fn counters(&self) -> Vec<(String, u64)> {
+		Vec::new()
+	}
+}
+
+/// The query hooks of the server, run in the order they were added until one doesn't let the
+/// query pass.
+#[derive(Clone, Default)]
+pub struct QueryHooks {
+	hooks: Vec<Arc<dyn QueryHook>>,
+}
+
+impl QueryHooks {
+	pub fn new() -> Self {
+		QueryHooks::default()
+	}
+
+	pub fn add(&mut self, hook: Arc<dyn QueryHook>) {
+		self.hooks.push(hook);
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.hooks.is_empty()
+	}
+
+	pub fn hooks(&self) -> &[Arc<dyn QueryHook>] {
+		&self.hooks
+	}
+
+	/// Run the hooks on `request` from `client`.
+	pub fn check(&self, request: &DNSPacket, client: IpAddr) -> QueryVerdict {
+		self.hooks.iter()
+			.map(|hook| hook.check_query(request, client))
+			.find(|verdict| *verdict != QueryVerdict::Pass)
+			.unwrap_or(QueryVerdict::Pass)
+	}
+
+	/// Show the hooks the response sent for `request` to `client`.
+	pub fn observe(&self, request: &DNSPacket, response: &DNSPacket, client: IpAddr) {
+		for hook in &self.hooks {
+			hook.observe_response(request, response, client);
+		}
+	}
+}
@@ -0,0 +1,163 @@
+//! Mirroring a sample of the live queries to another resolver
+
+use std::io::Result;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::mpsc::{ self, Receiver, SyncSender, TrySendError };
+use std::sync::{ Arc, Mutex };
+use std::thread;
+
+use rand::Rng;
+
+use crate::server::buffer::VectorPacketBuffer;
+use crate::server::client::DNSClient;
+use crate::server::protocol::{ DNSPacket, ResultCode };
+use crate::server::upstream::Upstream;
+
+/// Queries waiting to be mirrored before new ones get dropped...
+const MIRROR_QUEUE: usize = 1000;
+/// Threads sending the mirrored queries, each waiting for its response in turn...
+const MIRROR_THREADS: usize = 4;
+
+/// Counters of a mirror.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MirrorStats {
+	/// Queries sent to the mirror and answered by it...
+	pub mirrored: u64,
+	/// Queries left out as the queue was full, and those the mirror failed to answer...
+	pub dropped: u64,
+	pub failed: u64,
+	/// Answers of the mirror differing from those the clients got, by RCODE or records...
+	pub mismatches: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+	mirrored: AtomicU64,
+	dropped: AtomicU64,
+	failed: AtomicU64,
+	mismatches: AtomicU64,
+}
+
+/// A query to mirror, along with what the client was answered.
+struct MirroredQuery {
+	message: Vec<u8>,
+	answer: Answer,
+}
+
+/// What's compared of two responses: the RCODE and the answers, TTLs and order left out.
+#[derive(Debug, PartialEq, Eq)]
+struct Answer {
+	rescode: ResultCode,
+	records: Vec<String>,
+}
+
+impl Answer {
+	fn of(response: &DNSPacket) -> Answer {
+		let mut records: Vec<String> = response.answers.iter()
+			.map(|record| format!("{} {} {}", record.get_domain().unwrap_or_default(), record.get_query_type(), record.rdata_string()))
+			.collect();
+		records.sort();
+		Answer { rescode: response.header.rescode, records }
+	}
+}
+
+/// Sends a sample of the queries the server answers to another resolver, say a new upstream
+/// or another rdns instance with a new configuration, to load it with real traffic and to see
+/// where it would answer differently. The clients are answered as usual, without waiting for
+/// the mirror: the queries are queued for a few threads of its own, and dropped when it
+/// can't keep up.
+///
+/// Answers of the mirror differing from those the clients got are counted, and logged with
+/// `--verbose`.
+#[derive(Debug)]
+pub struct QueryMirror {
+	upstream: Upstream,
+	sample: f64,
+	queue: SyncSender<MirroredQuery>,
+	counters: Arc<Counters>,
+}
+
+impl QueryMirror {
+	/// Mirror `percent` percent of the queries to `upstream`, starting the threads sending
+	/// them.
+	pub fn start(upstream: Upstream, percent: f64) -> Result<QueryMirror> {
+		let (queue, receiver) = mpsc::sync_channel(MIRROR_QUEUE);
+		let receiver = Arc::new(Mutex::new(receiver));
+		let counters = Arc::new(Counters::default());
+		let client = Arc::new(DNSClient::new());
+		for _ in 0..MIRROR_THREADS {
+			let (upstream, receiver, counters, client) = (upstream.clone(), receiver.clone(), counters.clone(), client.clone());
+			thread::Builder::new()
+				.name("QueryMirror".to_string())
+				.spawn(move || mirror_queries(&upstream, &client, &receiver, &counters))?;
+		}
+		Ok(QueryMirror { upstream, sample: percent / 100.0, queue, counters })
+	}
+
+	pub fn upstream(&self) -> &Upstream {
+		&self.upstream
+	}
+
+	/// Share of the queries mirrored, in percent.
+	pub fn percent(&self) -> f64 {
+		self.sample * 100.0
+	}
+
+	/// Mirror the query `message` if it's picked for the sample, `response` being what the
+	/// client got.
+	pub fn offer(&self, message: &[u8], response: &DNSPacket) {
+		if self.sample < 1.0 && !rand::thread_rng().gen_bool(self.sample) {
+			return;
+		}
+		let query = MirroredQuery { message: message.to_vec(), answer: Answer::of(response) };
+		match self.queue.try_send(query) {
+			Ok(()) => (),
+			Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+				self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+			}
+		}
+	}
+
+	pub fn stats(&self) -> MirrorStats {
+		MirrorStats {
+			mirrored: self.counters.mirrored.load(Ordering::Relaxed),
+			dropped: self.counters.dropped.load(Ordering::Relaxed),
+			failed: self.counters.failed.load(Ordering::Relaxed),
+			mismatches: self.counters.mismatches.load(Ordering::Relaxed),
+		}
+	}
+}
+
+/// Send the queries of the queue to `upstream` until the mirror is dropped.
+fn mirror_queries(upstream: &Upstream, client: &DNSClient, queue: &Mutex<Receiver<MirroredQuery>>, counters: &Counters) {
+	loop {
+		let query = match queue.lock() {
+			Ok(queue) => queue.recv(),
+			Err(poisoned) => poisoned.into_inner().recv(),
+		};
+		let query = match query {
+			Ok(query) => query,
+			Err(_) => return,
+		};
+
+		let response = upstream.relay(client, &query.message)
+			.and_then(|bytes| DNSPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(bytes)));
+		match response {
+			Ok(response) => {
+				counters.mirrored.fetch_add(1, Ordering::Relaxed);
+				let answer = Answer::of(&response);
+				if answer != query.answer {
+					counters.mismatches.fetch_add(1, Ordering::Relaxed);
+					if let Some(question) = response.questions.first() {
+						debug!("Mirror {} answered {} {} with {:?} {:?}, clients got {:?} {:?}", upstream, question.name, question.q_type,
+							answer.rescode, answer.records, query.answer.rescode, query.answer.records);
+					}
+				}
+			}
+			Err(e) => {
+				counters.failed.fetch_add(1, Ordering::Relaxed);
+				debug!("Mirror {} failed to answer: {}", upstream, e);
+			}
+		}
+	}
+}
@@ -1,2 +1,66 @@
+#[macro_use]
+pub mod log;
 pub mod protocol;
-pub mod buffer;
\ No newline at end of file
+pub mod acl;
+pub mod admin;
+pub mod amplification;
+#[cfg(feature = "async-client")]
+pub mod async_client;
+pub mod authority;
+pub mod axfr;
+pub mod blocklist;
+pub mod buffer;
+pub mod cache;
+pub mod capture;
+pub mod client;
+pub mod clientstats;
+pub mod context;
+pub mod control;
+pub mod delegation;
+pub mod dnssd;
+pub mod dnssec;
+pub mod doctor;
+pub mod doh;
+pub mod editor;
+pub mod endpoint;
+pub mod fallback;
+pub mod family;
+pub mod geoip;
+pub mod handler;
+pub mod hints;
+pub mod hosts;
+pub mod https_upstream;
+pub mod json;
+pub mod latency;
+pub mod loader;
+pub mod lookup;
+pub mod mailauth;
+pub mod mdns;
+pub mod middleware;
+pub mod mirror;
+pub mod platform;
+pub mod policy;
+pub mod prefetch;
+pub mod quic;
+pub mod quic_upstream;
+pub mod querylog;
+pub mod ratelimit;
+pub mod resolve;
+pub mod retry;
+pub mod rotation;
+pub mod rrl;
+pub mod sanity;
+pub mod servfail;
+pub mod schedule;
+pub mod selftest;
+pub mod shutdown;
+pub mod structured;
+pub mod tcp;
+pub mod tls;
+pub mod tls_upstream;
+pub mod tunnel;
+pub mod udp;
+pub mod upstream;
+pub mod view;
+pub mod zonedb;
+pub mod zonefile;
\ No newline at end of file
@@ -0,0 +1,319 @@
+//! The DNS configuration of the operating system: the nameservers and search domains its
+//! resolver uses
+
+use std::fs;
+use std::io::Result;
+use std::net::{ IpAddr, Ipv4Addr, SocketAddr };
+use std::path::Path;
+use std::time::Duration;
+
+use crate::server::client::DNSClient;
+use crate::server::retry::RetryPolicy;
+
+/// Configuration of the resolver of the C library on Unix...
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Nameservers and search domains taken from resolv.conf, the C library ignoring the others
+/// (MAXNS and MAXDNSRCH)...
+const MAX_NAMESERVERS: usize = 3;
+const MAX_SEARCH: usize = 6;
+/// Highest values of the options, those above being capped as the C library does...
+const MAX_NDOTS: usize = 15;
+const MAX_TIMEOUT: u64 = 30;
+const MAX_ATTEMPTS: u32 = 5;
+
+/// How the system resolves names: the nameservers it asks, in order, and the domains it
+/// searches names with fewer than `ndots` dots under. Without nameservers configured, the
+/// one on the loopback is asked, as the C library does.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SystemConfig {
+	pub nameservers: Vec<SocketAddr>,
+	pub search: Vec<String>,
+	pub ndots: usize,
+	/// Time to wait for a nameserver to answer...
+	pub timeout: Duration,
+	/// Times every nameserver is asked before giving up...
+	pub attempts: u32,
+	/// Whether the queries are spread over the nameservers rather than asking the first one
+	/// first...
+	pub rotate: bool,
+}
+
+impl Default for SystemConfig {
+	fn default() -> Self {
+		SystemConfig {
+			nameservers: vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53)],
+			search: Vec::new(),
+			ndots: 1,
+			timeout: Duration::from_secs(5),
+			attempts: 2,
+			rotate: false,
+		}
+	}
+}
+
+impl SystemConfig {
+	/// The configuration of the system: /etc/resolv.conf on Unix, along with the
+	/// `LOCALDOMAIN` and `RES_OPTIONS` variables, the nameservers and search domains of the
+	/// default resolver of `scutil --dns` on macOS, and those of the network adapters up on
+	/// Windows.
+	pub fn load() -> Result<SystemConfig> {
+		load_system()
+	}
+
+	/// The configuration of the resolv.conf file at `path`.
+	pub fn read<P: AsRef<Path>>(path: P) -> Result<SystemConfig> {
+		Ok(SystemConfig::parse(&fs::read_to_string(path)?))
+	}
+
+	/// The configuration of resolv.conf `text`: its `nameserver`, `domain`, `search` and
+	/// `options` lines (`ndots:N`, `timeout:N`, `attempts:N` and `rotate`, the others being
+	/// ignored), as resolv.conf(5) describes them.
+	pub fn parse(text: &str) -> SystemConfig {
+		let mut config = SystemConfig { nameservers: Vec::new(), ..SystemConfig::default() };
+		for line in text.lines() {
+			let line = line.split(['#', ';']).next().unwrap_or("");
+			let mut fields = line.split_whitespace();
+			match fields.next() {
+				Some("nameserver") => {
+					if let Some(addr) = fields.next().and_then(parse_nameserver) {
+						if config.nameservers.len() < MAX_NAMESERVERS {
+							config.nameservers.push(addr);
+						}
+					}
+				}
+				// The last of domain and search wins...
+				Some("domain") => config.search = fields.take(1).map(normalize).collect(),
+				Some("search") => config.search = fields.take(MAX_SEARCH).map(normalize).collect(),
+				Some("options") => config.apply_options(line.trim_start().trim_start_matches("options")),
+				_ => (),
+			}
+		}
+		if config.nameservers.is_empty() {
+			config.nameservers = SystemConfig::default().nameservers;
+		}
+		config
+	}
+
+	/// Apply the options of an `options` line or of `RES_OPTIONS`.
+	fn apply_options(&mut self, options: &str) {
+		for option in options.split_whitespace() {
+			let (name, value) = option.split_once(':').unwrap_or((option, ""));
+			match name {
+				"ndots" => if let Ok(ndots) = value.parse::<usize>() {
+					self.ndots = ndots.min(MAX_NDOTS);
+				},
+				"timeout" => if let Ok(secs) = value.parse::<u64>() {
+					self.timeout = Duration::from_secs(secs.clamp(1, MAX_TIMEOUT));
+				},
+				"attempts" => if let Ok(attempts) = value.parse::<u32>() {
+					self.attempts = attempts.clamp(1, MAX_ATTEMPTS);
+				},
+				"rotate" => self.rotate = true,
+				_ => (),
+			}
+		}
+	}
+
+	/// The nameserver asked first.
+	pub fn server(&self) -> SocketAddr {
+		self.nameservers.first().copied().unwrap_or_else(|| SystemConfig::default().nameservers[0])
+	}
+
+	/// A client trying as many times and waiting for the responses as long as the system
+	/// resolver does, and searching its domains.
+	pub fn client(&self) -> DNSClient {
+		let mut client = DNSClient::with_retry(RetryPolicy::new().with_attempts(self.attempts).with_timeout(self.timeout));
+		client.set_search(self.search.clone(), self.ndots);
+		client
+	}
+}
+
+/// The nameserver the system asks first, the one on the loopback when the configuration of
+/// the system can't be read.
+pub fn system_server() -> SocketAddr {
+	SystemConfig::load().map(|config| config.server()).unwrap_or_else(|_| SystemConfig::default().server())
+}
+
+/// A nameserver of resolv.conf, on port 53, with the scope of a link-local IPv6 address given
+/// by number.
+fn parse_nameserver(addr: &str) -> Option<SocketAddr> {
+	if let Ok(ip) = addr.parse::<IpAddr>() {
+		return Some(SocketAddr::new(ip, 53));
+	}
+	format!("[{}]:53", addr).parse::<SocketAddr>().ok()
+		.or_else(|| addr.split('%').next().and_then(|ip| ip.parse::<IpAddr>().ok()).map(|ip| SocketAddr::new(ip, 53)))
+}
+
+fn normalize(name: &str) -> String {
+	name.trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn load_system() -> Result<SystemConfig> {
+	let mut config = SystemConfig::read(RESOLV_CONF)?;
+	apply_environment(&mut config);
+	Ok(config)
+}
+
+/// resolv.conf isn't what macOS resolves with, only a copy of the default resolver kept for
+/// compatibility, missing the nameservers of VPNs among others, so the nameservers and search
+/// domains come from `scutil`, its options from resolv.conf.
+#[cfg(target_os = "macos")]
+fn load_system() -> Result<SystemConfig> {
+	let mut config = SystemConfig::read(RESOLV_CONF).unwrap_or_default();
+	let output = std::process::Command::new("scutil").arg("--dns").output()?;
+	let (nameservers, search) = parse_scutil(&String::from_utf8_lossy(&output.stdout));
+	if !nameservers.is_empty() {
+		config.nameservers = nameservers;
+		config.search = search;
+	}
+	apply_environment(&mut config);
+	Ok(config)
+}
+
+/// The search domains of `LOCALDOMAIN` and the options of `RES_OPTIONS`, which the C library
+/// lets override resolv.conf.
+#[cfg(unix)]
+fn apply_environment(config: &mut SystemConfig) {
+	if let Ok(domains) = std::env::var("LOCALDOMAIN") {
+		config.search = domains.split_whitespace().take(MAX_SEARCH).map(normalize).collect();
+	}
+	if let Ok(options) = std::env::var("RES_OPTIONS") {
+		config.apply_options(&options);
+	}
+}
+
+/// The nameservers and search domains of the first resolver of `scutil --dns` that isn't
+/// scoped to a domain, the default one.
+#[cfg(target_os = "macos")]
+fn parse_scutil(output: &str) -> (Vec<SocketAddr>, Vec<String>) {
+	// The resolvers for scoped queries, listed after the others, are left out...
+	let output = output.split("DNS configuration (for scoped queries)").next().unwrap_or("");
+	for resolver in output.split("resolver #").skip(1) {
+		let mut nameservers = Vec::new();
+		let mut search = Vec::new();
+		let mut scoped = false;
+		for line in resolver.lines() {
+			let (key, value) = match line.split_once(':') {
+				Some((key, value)) => (key.trim(), value.trim()),
+				None => continue,
+			};
+			if key.starts_with("nameserver[") {
+				nameservers.extend(parse_nameserver(value));
+			} else if key.starts_with("search domain[") {
+				search.push(normalize(value));
+			} else if key == "domain" {
+				scoped = true;
+			}
+		}
+		if !scoped && !nameservers.is_empty() {
+			return (nameservers, search);
+		}
+	}
+	(Vec::new(), Vec::new())
+}
+
+/// The nameservers and DNS suffixes of the network adapters up, from GetAdaptersAddresses, in
+/// the order of the adapters.
+#[cfg(windows)]
+fn load_system() -> Result<SystemConfig> {
+	use std::io::Error;
+	use windows_sys::Win32::Foundation::{ ERROR_BUFFER_OVERFLOW, NO_ERROR };
+	use windows_sys::Win32::NetworkManagement::IpHelper::{ GetAdaptersAddresses, GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_FRIENDLY_NAME,
+		GAA_FLAG_SKIP_MULTICAST, GAA_FLAG_SKIP_UNICAST, IP_ADAPTER_ADDRESSES_LH };
+	use windows_sys::Win32::NetworkManagement::Ndis::IfOperStatusUp;
+	use windows_sys::Win32::Networking::WinSock::AF_UNSPEC;
+
+	let flags = GAA_FLAG_SKIP_UNICAST | GAA_FLAG_SKIP_ANYCAST | GAA_FLAG_SKIP_MULTICAST | GAA_FLAG_SKIP_FRIENDLY_NAME;
+	let mut size: u32 = 16 * 1024;
+	// Words rather than bytes, for the adapters written in the buffer to be aligned...
+	let mut buffer: Vec<u64>;
+	loop {
+		buffer = vec![0; (size as usize).div_ceil(8)];
+		// SAFETY: the buffer holds `size` bytes, as the call is told...
+		let result = unsafe {
+			GetAdaptersAddresses(AF_UNSPEC as u32, flags, std::ptr::null(), buffer.as_mut_ptr() as *mut IP_ADAPTER_ADDRESSES_LH, &mut size)
+		};
+		match result {
+			NO_ERROR => break,
+			ERROR_BUFFER_OVERFLOW => continue,
+			error => return Err(Error::from_raw_os_error(error as i32)),
+		}
+	}
+
+	let mut config = SystemConfig { nameservers: Vec::new(), ..SystemConfig::default() };
+	let mut adapter = buffer.as_ptr() as *const IP_ADAPTER_ADDRESSES_LH;
+	// SAFETY: the adapters and their lists are linked within the buffer, which outlives the
+	// loop, the strings being terminated...
+	unsafe {
+		while let Some(current) = adapter.as_ref() {
+			if current.OperStatus == IfOperStatusUp {
+				let mut server = current.FirstDnsServerAddress;
+				while let Some(entry) = server.as_ref() {
+					if let Some(addr) = adapter_nameserver(entry.Address.lpSockaddr) {
+						if !config.nameservers.contains(&addr) {
+							config.nameservers.push(addr);
+						}
+					}
+					server = entry.Next;
+				}
+				let mut suffixes = Vec::new();
+				if !current.DnsSuffix.is_null() {
+					let len = (0..).take_while(|&i| *current.DnsSuffix.add(i) != 0).count();
+					suffixes.push(String::from_utf16_lossy(std::slice::from_raw_parts(current.DnsSuffix, len)));
+				}
+				let mut suffix = current.FirstDnsSuffix;
+				while let Some(entry) = suffix.as_ref() {
+					let len = entry.String.iter().position(|&c| c == 0).unwrap_or(entry.String.len());
+					suffixes.push(String::from_utf16_lossy(&entry.String[..len]));
+					suffix = entry.Next;
+				}
+				for suffix in suffixes.iter().filter(|suffix| !suffix.is_empty()).map(|suffix| normalize(suffix)) {
+					if !config.search.contains(&suffix) {
+						config.search.push(suffix);
+					}
+				}
+			}
+			adapter = current.Next;
+		}
+	}
+	if config.nameservers.is_empty() {
+		config.nameservers = SystemConfig::default().nameservers;
+	}
+	Ok(config)
+}
+
+/// The nameserver at `sockaddr`, on port 53, leaving out the site-local addresses Windows
+/// gives IPv6 adapters without nameservers (fec0:0:0:ffff::1 to 3).
+///
+/// # Safety
+///
+/// `sockaddr` has to be null or point to a SOCKADDR_IN or SOCKADDR_IN6 as its family says.
+#[cfg(windows)]
+unsafe fn adapter_nameserver(sockaddr: *const windows_sys::Win32::Networking::WinSock::SOCKADDR) -> Option<SocketAddr> {
+	use std::net::{ Ipv6Addr, SocketAddrV6 };
+	use windows_sys::Win32::Networking::WinSock::{ AF_INET, AF_INET6, SOCKADDR_IN, SOCKADDR_IN6 };
+
+	match sockaddr.as_ref()?.sa_family {
+		AF_INET => {
+			let sin = &*(sockaddr as *const SOCKADDR_IN);
+			Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.S_un.S_addr))), 53))
+		}
+		AF_INET6 => {
+			let sin6 = &*(sockaddr as *const SOCKADDR_IN6);
+			let ip = Ipv6Addr::from(sin6.sin6_addr.u.Byte);
+			let segments = ip.segments();
+			if segments[..4] == [0xfec0, 0, 0, 0xffff] && segments[4..7] == [0, 0, 0] && (1..=3).contains(&segments[7]) {
+				return None;
+			}
+			Some(SocketAddr::V6(SocketAddrV6::new(ip, 53, 0, sin6.Anonymous.sin6_scope_id)))
+		}
+		_ => None,
+	}
+}
+
+#[cfg(not(any(unix, windows)))]
+fn load_system() -> Result<SystemConfig> {
+	Ok(SystemConfig::default())
+}
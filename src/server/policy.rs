@@ -0,0 +1,326 @@
+//! Policies on the structure of the names queried, against DGA and tunneling patterns
+
+use std::fmt;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::IpAddr;
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use crate::server::middleware::{ QueryHook, QueryVerdict };
+use crate::server::protocol::DNSPacket;
+use crate::server::ratelimit::TokenBuckets;
+
+/// What's measured of a name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameMeasure {
+	/// Number of labels.
+	Labels,
+	/// Length of the name, dots included.
+	Length,
+	/// Length of the leftmost label.
+	LabelLength,
+	/// Shannon entropy of the characters of the leftmost label, in bits per character.
+	Entropy,
+}
+
+impl NameMeasure {
+	pub fn name(&self) -> &'static str {
+		match *self {
+			NameMeasure::Labels => "labels",
+			NameMeasure::Length => "length",
+			NameMeasure::LabelLength => "label-length",
+			NameMeasure::Entropy => "entropy",
+		}
+	}
+
+	/// The measure of `name`, given without the trailing dot.
+	pub fn of(&self, name: &str) -> f64 {
+		let leftmost = name.split('.').next().unwrap_or("");
+		match *self {
+			NameMeasure::Labels => name.split('.').filter(|label| !label.is_empty()).count() as f64,
+			NameMeasure::Length => name.len() as f64,
+			NameMeasure::LabelLength => leftmost.len() as f64,
+			NameMeasure::Entropy => entropy(leftmost),
+		}
+	}
+}
+
+/// Shannon entropy of the bytes of `label`, case ignored.
+fn entropy(label: &str) -> f64 {
+	if label.is_empty() {
+		return 0.0;
+	}
+	let mut counts = [0u32; 256];
+	for byte in label.bytes() {
+		counts[byte.to_ascii_lowercase() as usize] += 1;
+	}
+	let len = label.len() as f64;
+	counts.iter()
+		.filter(|count| **count > 0)
+		.map(|count| {
+			let p = *count as f64 / len;
+			-p * p.log2()
+		})
+		.sum()
+}
+
+/// How a measure is compared to the value of a predicate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+	Greater,
+	AtLeast,
+	Less,
+	AtMost,
+	Equal,
+}
+
+impl Comparison {
+	pub fn symbol(&self) -> &'static str {
+		match *self {
+			Comparison::Greater => ">",
+			Comparison::AtLeast => ">=",
+			Comparison::Less => "<",
+			Comparison::AtMost => "<=",
+			Comparison::Equal => "=",
+		}
+	}
+
+	fn holds(&self, measure: f64, value: f64) -> bool {
+		match *self {
+			Comparison::Greater => measure > value,
+			Comparison::AtLeast => measure >= value,
+			Comparison::Less => measure < value,
+			Comparison::AtMost => measure <= value,
+			Comparison::Equal => measure == value,
+		}
+	}
+}
+
+/// A condition on a name, like `entropy>3.5` or `labels>=6`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NamePredicate {
+	pub measure: NameMeasure,
+	pub comparison: Comparison,
+	pub value: f64,
+}
+
+impl NamePredicate {
+	/// Parse a predicate written `MEASURE OP VALUE`, without spaces, with `MEASURE` one of
+	/// `labels`, `length`, `label-length` and `entropy`, and `OP` one of `>`, `>=`, `<`, `<=`
+	/// and `=`.
+	pub fn parse(predicate: &str) -> Result<NamePredicate> {
+		let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid name predicate: {}", predicate));
+		let at = predicate.find(['<', '>', '=']).ok_or_else(invalid)?;
+		let (measure, rest) = predicate.split_at(at);
+		let measure = match measure {
+			"labels" => NameMeasure::Labels,
+			"length" => NameMeasure::Length,
+			"label-length" => NameMeasure::LabelLength,
+			"entropy" => NameMeasure::Entropy,
+			_ => return Err(invalid()),
+		};
+		let (comparison, value) = if let Some(value) = rest.strip_prefix(">=") {
+			(Comparison::AtLeast, value)
+		} else if let Some(value) = rest.strip_prefix("<=") {
+			(Comparison::AtMost, value)
+		} else if let Some(value) = rest.strip_prefix('>') {
+			(Comparison::Greater, value)
+		} else if let Some(value) = rest.strip_prefix('<') {
+			(Comparison::Less, value)
+		} else {
+			(Comparison::Equal, &rest[1..])
+		};
+		let value = value.parse::<f64>().ok().filter(|value| value.is_finite()).ok_or_else(invalid)?;
+		Ok(NamePredicate { measure, comparison, value })
+	}
+
+	/// Whether `name`, given without the trailing dot, meets the condition.
+	pub fn matches(&self, name: &str) -> bool {
+		self.comparison.holds(self.measure.of(name), self.value)
+	}
+}
+
+impl fmt::Display for NamePredicate {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}{}{}", self.measure.name(), self.comparison.symbol(), self.value)
+	}
+}
+
+/// What's done with the queries for the names a policy matches.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PolicyAction {
+	/// Log them, and answer them as usual.
+	Log,
+	/// Answer them with REFUSED.
+	Refuse,
+	/// Send no response.
+	Drop,
+	/// Drop those of each client beyond the number of queries a second given.
+	Limit(f64),
+}
+
+impl fmt::Display for PolicyAction {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			PolicyAction::Log => write!(f, "log"),
+			PolicyAction::Refuse => write!(f, "refuse"),
+			PolicyAction::Drop => write!(f, "drop"),
+			PolicyAction::Limit(rate) => write!(f, "limit {}", rate),
+		}
+	}
+}
+
+/// A policy: an action taken on the queries for names meeting all of its predicates, along
+/// with the number of queries it matched and, for a limit, dropped.
+#[derive(Debug)]
+pub struct NamePolicy {
+	action: PolicyAction,
+	predicates: Vec<NamePredicate>,
+	limits: Option<TokenBuckets>,
+	matched: AtomicU64,
+	limited: AtomicU64,
+}
+
+impl NamePolicy {
+	/// Parse a policy written `ACTION PREDICATE [and PREDICATE]...`, with `ACTION` one of
+	/// `log`, `refuse`, `drop` and `limit RATE`, like `drop entropy>3.8 and label-length>=20`
+	/// (see `NamePredicate::parse`).
+	pub fn parse(policy: &str) -> Result<NamePolicy> {
+		let invalid = |why: &str| Error::new(ErrorKind::InvalidInput, format!("Invalid name policy {}: {}", policy, why));
+		let mut words = policy.split_whitespace();
+		let action = match words.next() {
+			Some("log") => PolicyAction::Log,
+			Some("refuse") => PolicyAction::Refuse,
+			Some("drop") => PolicyAction::Drop,
+			Some("limit") => match words.next().and_then(|rate| rate.parse::<f64>().ok()) {
+				Some(rate) if rate > 0.0 && rate.is_finite() => PolicyAction::Limit(rate),
+				_ => return Err(invalid("limit takes a number of queries a second")),
+			},
+			Some(action) => return Err(invalid(&format!("unknown action {}", action))),
+			None => return Err(invalid("no action")),
+		};
+
+		let mut predicates = Vec::new();
+		loop {
+			let predicate = words.next().ok_or_else(|| invalid("missing predicate"))?;
+			predicates.push(NamePredicate::parse(predicate)?);
+			match words.next() {
+				Some("and") => (),
+				Some(word) => return Err(invalid(&format!("expected and, not {}", word))),
+				None => break,
+			}
+		}
+
+		// Bursts of a second's worth of queries...
+		let limits = match action {
+			PolicyAction::Limit(rate) => Some(TokenBuckets::new(rate, rate)),
+			_ => None,
+		};
+		Ok(NamePolicy { action, predicates, limits, matched: AtomicU64::new(0), limited: AtomicU64::new(0) })
+	}
+
+	pub fn action(&self) -> PolicyAction {
+		self.action
+	}
+
+	pub fn predicates(&self) -> &[NamePredicate] {
+		&self.predicates
+	}
+
+	/// Whether `name`, given without the trailing dot, meets all of the predicates.
+	pub fn matches(&self, name: &str) -> bool {
+		self.predicates.iter().all(|predicate| predicate.matches(name))
+	}
+
+	/// Number of queries the policy matched.
+	pub fn matched(&self) -> u64 {
+		self.matched.load(Ordering::Relaxed)
+	}
+
+	/// Number of queries dropped by a limit.
+	pub fn limited(&self) -> u64 {
+		self.limited.load(Ordering::Relaxed)
+	}
+}
+
+impl fmt::Display for NamePolicy {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.action)?;
+		for (i, predicate) in self.predicates.iter().enumerate() {
+			write!(f, "{}{}", if i == 0 { " " } else { " and " }, predicate)?;
+		}
+		Ok(())
+	}
+}
+
+/// Policies on the names queried, applied in order as a query hook: say dropping the queries
+/// for the long, random looking names of domain generation algorithms and of tunnels, or
+/// limiting how many of them each client gets answered. A query goes on to the next policy
+/// when the one it matched only logs it or lets it through its limit.
+#[derive(Debug, Default)]
+pub struct NamePolicies {
+	policies: Vec<NamePolicy>,
+}
+
+impl NamePolicies {
+	pub fn new() -> Self {
+		NamePolicies::default()
+	}
+
+	pub fn add(&mut self, policy: NamePolicy) {
+		self.policies.push(policy);
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.policies.is_empty()
+	}
+
+	pub fn policies(&self) -> &[NamePolicy] {
+		&self.policies
+	}
+}
+
+impl QueryHook for NamePolicies {
+	fn name(&self) -> &str {
+		"name-policy"
+	}
+
+	fn check_query(&self, request: &DNSPacket, client: IpAddr) -> QueryVerdict {
+		let question = match request.questions.first() {
+			Some(question) => question,
+			None => return QueryVerdict::Pass,
+		};
+		let name = question.name.trim_end_matches('.');
+		for policy in self.policies.iter().filter(|policy| policy.matches(name)) {
+			policy.matched.fetch_add(1, Ordering::Relaxed);
+			let verdict = match policy.action {
+				PolicyAction::Log => {
+					info!("Query for {} {} from {} matched name policy {}", question.name, question.q_type, client, policy);
+					continue;
+				}
+				PolicyAction::Refuse => QueryVerdict::Refuse,
+				PolicyAction::Drop => QueryVerdict::Drop,
+				PolicyAction::Limit(_) => {
+					if policy.limits.as_ref().is_none_or(|limits| limits.allow(client)) {
+						continue;
+					}
+					policy.limited.fetch_add(1, Ordering::Relaxed);
+					QueryVerdict::Drop
+				}
+			};
+			debug!("Query for {} {} from {} matched name policy {}: {:?}", question.name, question.q_type, client, policy, verdict);
+			return verdict;
+		}
+		QueryVerdict::Pass
+	}
+
+	fn counters(&self) -> Vec<(String, u64)> {
+		let mut counters = Vec::new();
+		for policy in &self.policies {
+			counters.push((format!("{} matched", policy), policy.matched()));
+			if policy.limits.is_some() {
+				counters.push((format!("{} limited", policy), policy.limited()));
+			}
+		}
+		counters
+	}
+}
@@ -0,0 +1,73 @@
+//! Refreshing cache entries in the background, on a few threads of their own
+
+use std::io::Result;
+use std::panic::{ self, AssertUnwindSafe };
+use std::sync::mpsc::{ self, Receiver, SyncSender };
+use std::sync::{ Arc, Mutex, OnceLock };
+use std::thread;
+
+/// Prefetches waiting for a thread before new ones are turned away...
+const PREFETCH_QUEUE: usize = 256;
+/// Threads running the prefetches, each resolving one question at a time...
+const PREFETCH_THREADS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs the prefetches of the server on a fixed number of threads, queueing a bounded
+/// number of them, so that a burst of popular entries coming due doesn't start a thread
+/// for each. The threads are started with the first prefetch.
+#[derive(Debug, Default)]
+pub struct Prefetcher {
+	queue: OnceLock<Option<SyncSender<Job>>>,
+}
+
+impl Prefetcher {
+	pub fn new() -> Prefetcher {
+		Prefetcher::default()
+	}
+
+	/// Queue `job` for a prefetch thread. False if the queue is full or the threads
+	/// couldn't be started, and the job was dropped.
+	pub fn submit<J: FnOnce() + Send + 'static>(&self, job: J) -> bool {
+		let queue = self.queue.get_or_init(|| match start() {
+			Ok(queue) => Some(queue),
+			Err(e) => {
+				info!("Failed to start the prefetch threads: {}", e);
+				None
+			}
+		});
+		match queue {
+			Some(queue) => queue.try_send(Box::new(job)).is_ok(),
+			None => false,
+		}
+	}
+}
+
+fn start() -> Result<SyncSender<Job>> {
+	let (queue, receiver) = mpsc::sync_channel(PREFETCH_QUEUE);
+	let receiver = Arc::new(Mutex::new(receiver));
+	for _ in 0..PREFETCH_THREADS {
+		let receiver = receiver.clone();
+		thread::Builder::new()
+			.name("Prefetch".to_string())
+			.spawn(move || run_jobs(&receiver))?;
+	}
+	Ok(queue)
+}
+
+/// Run the jobs of the queue until the prefetcher is dropped.
+fn run_jobs(queue: &Mutex<Receiver<Job>>) {
+	loop {
+		let job = match queue.lock() {
+			Ok(queue) => queue.recv(),
+			Err(poisoned) => poisoned.into_inner().recv(),
+		};
+		match job {
+			// A panicking prefetch mustn't take its thread along...
+			Ok(job) => {
+				let _ = panic::catch_unwind(AssertUnwindSafe(job));
+			}
+			Err(_) => return,
+		}
+	}
+}
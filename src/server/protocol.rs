@@ -1,9 +1,14 @@
 use std::cmp::Ordering;
+use std::fmt;
 use std::hash::{ Hash, Hasher };
-use std::io::Result;
-use std::net::{ Ipv4Addr, Ipv6Addr };
+use std::io::{ Error, ErrorKind, Result };
+use std::convert::TryFrom;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
 
-use crate::server::buffer::PacketBuffer;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use crate::server::buffer::{ PacketBuffer, VectorPacketBuffer };
 
 // --------------------------------------------------------------------------------------------
 /// DNSHeader Representation...
@@ -91,7 +96,7 @@ impl DNSHeader {
     	buffer.write_u16(self.id)?;
 
     	buffer.write(
-    			((self.recursion_desired as u8) << 0)
+    			(self.recursion_desired as u8)
     				| ((self.truncated_message as u8) << 1)
     				| ((self.authoritative_answer as u8) << 2)
     				| (self.opcode << 3)
@@ -99,7 +104,7 @@ impl DNSHeader {
     			)?;
 
     	buffer.write(
-    			(self.rescode.clone() as u8)
+    			(self.rescode as u8)
     				| ((self.checking_disabled as u8) << 4)
     				| ((self.authed_data as u8) << 5)
     				| ((self.z as u8) << 6)
@@ -124,11 +129,15 @@ pub enum QueryType {
 	NS,		//2
 	CNAME,	//5
 	SOA,	//6
+	PTR,	//12
 	MX,		//15
 	TXT,	//16
 	AAAA,	//28
 	SRV,	//33
-	OPT,	//44
+	OPT,	//41
+	DS,		//43
+	DNSKEY,	//48
+	AXFR,	//252
 }
 
 impl QueryType {
@@ -140,11 +149,15 @@ impl QueryType {
 			QueryType::NS => 2,
 			QueryType::CNAME => 5,
 			QueryType::SOA => 6,
+			QueryType::PTR => 12,
 			QueryType::MX => 15,
 			QueryType::TXT => 16,
 			QueryType::AAAA => 28,
 			QueryType::SRV => 33,
-			QueryType::OPT => 44,
+			QueryType::OPT => 41,
+			QueryType::DS => 43,
+			QueryType::DNSKEY => 48,
+			QueryType::AXFR => 252,
 		}
 	}
 
@@ -154,19 +167,88 @@ impl QueryType {
 			2 => QueryType::NS,
 			5 => QueryType::CNAME,
 			6 => QueryType::SOA,
+			12 => QueryType::PTR,
 			15 => QueryType::MX,
 			16 => QueryType:: TXT,
 			28 => QueryType::AAAA,
 			33 => QueryType::SRV,
-			44 => QueryType::OPT,
+			41 => QueryType::OPT,
+			43 => QueryType::DS,
+			48 => QueryType::DNSKEY,
+			252 => QueryType::AXFR,
 			_ => QueryType::UNKNOWN(num),
 		}
 	}
+
+	/// Parse the mnemonic of a record type, or the generic `TYPE<num>` form (RFC 3597).
+	pub fn from_name(name: &str) -> Option<QueryType> {
+		let name = name.to_uppercase();
+		let q_type = match name.as_str() {
+			"A" => QueryType::A,
+			"NS" => QueryType::NS,
+			"CNAME" => QueryType::CNAME,
+			"SOA" => QueryType::SOA,
+			"PTR" => QueryType::PTR,
+			"MX" => QueryType::MX,
+			"TXT" => QueryType::TXT,
+			"AAAA" => QueryType::AAAA,
+			"SRV" => QueryType::SRV,
+			"OPT" => QueryType::OPT,
+			"DS" => QueryType::DS,
+			"DNSKEY" => QueryType::DNSKEY,
+			"AXFR" => QueryType::AXFR,
+			"NULL" => QueryType::UNKNOWN(TYPE_NULL),
+			"WKS" => QueryType::UNKNOWN(TYPE_WKS),
+			"SVCB" => QueryType::UNKNOWN(TYPE_SVCB),
+			"HTTPS" => QueryType::UNKNOWN(TYPE_HTTPS),
+			_ => QueryType::from_num(name.strip_prefix("TYPE")?.parse().ok()?),
+		};
+		Some(q_type)
+	}
+}
+
+impl fmt::Display for QueryType {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			QueryType::UNKNOWN(TYPE_NULL) => write!(f, "NULL"),
+			QueryType::UNKNOWN(TYPE_WKS) => write!(f, "WKS"),
+			QueryType::UNKNOWN(TYPE_SVCB) => write!(f, "SVCB"),
+			QueryType::UNKNOWN(TYPE_HTTPS) => write!(f, "HTTPS"),
+			QueryType::UNKNOWN(x) => write!(f, "TYPE{}", x),
+			_ => write!(f, "{:?}", self),
+		}
+	}
+}
+
+/// Types of RFC 1035 which are obsolete but still turn up in old zones. Records of these types
+/// are carried as UNKNOWN, with their RDATA kept raw; see `DNSRecord::legacy_rdata`...
+pub const TYPE_NULL: u16 = 10;
+pub const TYPE_WKS: u16 = 11;
+
+/// Service binding types (RFC 9460), carried as UNKNOWN records as well; see
+/// `DNSRecord::svcb_rdata`...
+pub const TYPE_SVCB: u16 = 64;
+pub const TYPE_HTTPS: u16 = 65;
+
+/// Whether records of type `num` are of one of the obsolete types above.
+pub fn is_legacy_type(num: u16) -> bool {
+	num == TYPE_NULL || num == TYPE_WKS
 }
 
+/// EDNS options carrying a client subnet (RFC 7871) and a DNS cookie (RFC 7873)...
+pub const EDNS_OPTION_ECS: u16 = 8;
+pub const EDNS_OPTION_COOKIE: u16 = 10;
+/// EDNS option carrying an Extended DNS Error (RFC 8914), and the INFO-CODEs used...
+pub const EDNS_OPTION_EDE: u16 = 15;
+pub const EDE_STALE_ANSWER: u16 = 3;
+pub const EDE_BLOCKED: u16 = 15;
+/// The DNSSEC OK flag of an OPT record (RFC 3225), among the flags its TTL carries...
+pub const EDNS_FLAG_DO: u32 = 0x8000;
+
 // ResultCode for a DNS Query...
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
 pub enum ResultCode {
+	#[default]
 	NOERROR		= 0,
 	FORMERR		= 1,
 	SERVFAIL	= 2,
@@ -175,12 +257,6 @@ pub enum ResultCode {
 	REFUSED		= 5,
 }
 
-impl Default for ResultCode {
-	fn default() -> Self {
-		ResultCode::NOERROR
-	}
-}
-
 impl ResultCode {
 	pub fn from_num(num: u8) -> ResultCode {
 		match num {
@@ -189,7 +265,7 @@ impl ResultCode {
 			3 => ResultCode::NXDOMAIN,
 			4 => ResultCode::NOTIMP,
 			5 => ResultCode::REFUSED,
-			0 | _ => ResultCode::NOERROR,
+			_ => ResultCode::NOERROR,
 		}
 	}
 }
@@ -205,8 +281,8 @@ pub struct DNSQuestion {
 
 impl DNSQuestion {
 	/// Create a new DNSQuestion.
-	/// `name`	- The Domain Name to query
-	/// `qType`	- The record to Query from the domain.
+	/// `name`   - The Domain Name to query
+	/// `q_type` - The record to Query from the domain.
 	pub fn new(name: String, q_type: QueryType) -> Self {
 		Self { name, q_type }
 	}
@@ -220,7 +296,7 @@ impl DNSQuestion {
 	}
 
 	pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<()> {
-		buffer.write_qname(&self.name)?;			// Domain name
+		buffer.write_name(&self.name)?;			// Domain name
 		buffer.write_u16(self.q_type.to_num())?;	// QueryType
 		buffer.write_u16(1)?;						// Class
 		Ok(())
@@ -228,7 +304,7 @@ impl DNSQuestion {
 }
 // --------------------------------------------------------------------------------------------
 
-#[derive(Copy, Clone, Debug, Eq, Ord)]
+#[derive(Copy, Clone, Debug, Eq)]
 pub struct TransientTTL(pub u32);
 
 impl Hash for TransientTTL {
@@ -247,8 +323,14 @@ impl PartialEq<TransientTTL> for TransientTTL {
 }
 
 impl PartialOrd<TransientTTL> for TransientTTL {
-    fn partial_cmp(&self, _: &TransientTTL) -> Option<Ordering> {
-        Some(Ordering::Equal)
+    fn partial_cmp(&self, other: &TransientTTL) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TransientTTL {
+    fn cmp(&self, _: &TransientTTL) -> Ordering {
+        Ordering::Equal
     }
 }
 // --------------------------------------------------------------------------------------------
@@ -259,7 +341,7 @@ pub enum DNSRecord {
 	UNKNOWN {
 		domain: String,
 		q_type: u16,
-		data_len: u16,
+		data: Vec<u8>,
 		ttl: TransientTTL,
 	}, // 0
 	A {
@@ -288,6 +370,11 @@ pub enum DNSRecord {
 		minimum: u32,
 		ttl: TransientTTL,
 	}, // 6
+	PTR {
+		domain: String,
+		host: String,
+		ttl: TransientTTL,
+	}, // 12
 	MX {
 		domain: String,
 		priority: u16,
@@ -296,7 +383,8 @@ pub enum DNSRecord {
 	}, // 15
 	TXT {
 		domain: String,
-		data: String,
+		/// Character strings, kept apart and as bytes as they may hold anything...
+		data: Vec<Vec<u8>>,
 		ttl: TransientTTL,
 	}, // 16
 	AAAA {
@@ -315,8 +403,24 @@ pub enum DNSRecord {
 	OPT {
 		packet_len: u16,
 		flags: u32,
-		data: String,
+		data: Vec<u8>,
 	}, // 41
+	DS {
+		domain: String,
+		key_tag: u16,
+		algorithm: u8,
+		digest_type: u8,
+		digest: Vec<u8>,
+		ttl: TransientTTL,
+	}, // 43
+	DNSKEY {
+		domain: String,
+		flags: u16,
+		protocol: u8,
+		algorithm: u8,
+		public_key: Vec<u8>,
+		ttl: TransientTTL,
+	}, // 48
 }
 
 impl DNSRecord {
@@ -338,7 +442,7 @@ impl DNSRecord {
 								((raw_addr >> 24) & 0xFF) as u8,
 								((raw_addr >> 16) & 0xFF) as u8,
 								((raw_addr >> 8) & 0xFF) as u8,
-								((raw_addr >> 0) & 0xFF) as u8
+								(raw_addr & 0xFF) as u8
 							);
 				Ok(DNSRecord::A { domain, addr, ttl })
 			}
@@ -349,13 +453,13 @@ impl DNSRecord {
 				let raw_addr4 = buffer.read_u32()?;
 				let addr = 	Ipv6Addr::new(
 								((raw_addr1 >> 16) & 0xFFFF) as u16,
-								((raw_addr1 >> 0) & 0xFFFF) as u16,
+								(raw_addr1 & 0xFFFF) as u16,
 								((raw_addr2 >> 16) & 0xFFFF) as u16,
-								((raw_addr2 >> 0) & 0xFFFF) as u16,
+								(raw_addr2 & 0xFFFF) as u16,
 								((raw_addr3 >> 16) & 0xFFFF) as u16,
-								((raw_addr3 >> 0) & 0xFFFF) as u16,
+								(raw_addr3 & 0xFFFF) as u16,
 								((raw_addr4 >> 16) & 0xFFFF) as u16,
-								((raw_addr4 >> 0) & 0xFFFF) as u16,								
+								(raw_addr4 & 0xFFFF) as u16,								
 							);
 				Ok(DNSRecord::AAAA{ domain, addr, ttl })
 			}
@@ -369,6 +473,11 @@ impl DNSRecord {
 				buffer.read_qname(&mut host)?;
 				Ok(DNSRecord::CNAME{ domain, host, ttl })
 			}
+			QueryType::PTR => {
+				let mut host = String::new();
+				buffer.read_qname(&mut host)?;
+				Ok(DNSRecord::PTR{ domain, host, ttl })
+			}
 			QueryType::SRV => {
 				let priority = buffer.read_u16()?;
 				let weight = buffer.read_u16()?;
@@ -403,19 +512,26 @@ impl DNSRecord {
 				Ok(DNSRecord::SOA{ domain, m_name, r_name, serial, refresh, retry, expire, minimum, ttl })
 			}
 			QueryType::TXT => {
-				let mut data = String::new();
-
-				let pos = buffer.pos();
-				data.push_str(&String::from_utf8_lossy(buffer.get_range(pos, data_len as usize)?));
-				buffer.step(data_len as usize)?;
+				// The RDATA is a sequence of length prefixed character strings, none of which may
+				// run past its end...
+				let mut data = Vec::new();
+
+				let end = buffer.pos() + data_len as usize;
+				while buffer.pos() < end {
+					let len = buffer.read()? as usize;
+					let pos = buffer.pos();
+					if pos + len > end {
+						return Err(Error::new(ErrorKind::InvalidData, "TXT string runs past the end of the RDATA"));
+					}
+					data.push(buffer.get_range(pos, len)?.to_vec());
+					buffer.step(len)?;
+				}
 
 				Ok(DNSRecord::TXT{ domain, data, ttl })
 			}
 			QueryType::OPT => {
-				let mut data = String::new();
-
 				let pos = buffer.pos();
-				data.push_str(&String::from_utf8_lossy(buffer.get_range(pos, data_len as usize)?));
+				let data = buffer.get_range(pos, data_len as usize)?.to_vec();
 				buffer.step(data_len as usize)?;
 
 				Ok(DNSRecord::OPT{
@@ -424,9 +540,42 @@ impl DNSRecord {
 					data
 				})
 			}
-			QueryType::UNKNOWN(_) => {
+			QueryType::DS => {
+				if data_len < 4 {
+					return Err(Error::new(ErrorKind::InvalidData, "DS record too short"));
+				}
+
+				let key_tag = buffer.read_u16()?;
+				let algorithm = buffer.read()?;
+				let digest_type = buffer.read()?;
+
+				let pos = buffer.pos();
+				let digest = buffer.get_range(pos, data_len as usize - 4)?.to_vec();
+				buffer.step(data_len as usize - 4)?;
+
+				Ok(DNSRecord::DS{ domain, key_tag, algorithm, digest_type, digest, ttl })
+			}
+			QueryType::DNSKEY => {
+				if data_len < 4 {
+					return Err(Error::new(ErrorKind::InvalidData, "DNSKEY record too short"));
+				}
+
+				let flags = buffer.read_u16()?;
+				let protocol = buffer.read()?;
+				let algorithm = buffer.read()?;
+
+				let pos = buffer.pos();
+				let public_key = buffer.get_range(pos, data_len as usize - 4)?.to_vec();
+				buffer.step(data_len as usize - 4)?;
+
+				Ok(DNSRecord::DNSKEY{ domain, flags, protocol, algorithm, public_key, ttl })
+			}
+			QueryType::UNKNOWN(_) | QueryType::AXFR => {
+				// The RDATA is kept as is, so the record can be passed on unchanged...
+				let pos = buffer.pos();
+				let data = buffer.get_range(pos, data_len as usize)?.to_vec();
 				buffer.step(data_len as usize)?;
-				Ok(DNSRecord::UNKNOWN { domain, q_type: q_type_num, data_len, ttl })
+				Ok(DNSRecord::UNKNOWN { domain, q_type: q_type_num, data, ttl })
 			}
 		}
 	}
@@ -440,7 +589,7 @@ impl DNSRecord {
 				ref addr,
 				ttl: TransientTTL(ttl),
 			} => {
-				buffer.write_qname(domain)?;
+				buffer.write_name(domain)?;
 				buffer.write_u16(QueryType::A.to_num())?;	// QueryType
 				buffer.write_u16(1)?;						// Class
 				buffer.write_u32(ttl)?;						// TTL
@@ -457,11 +606,11 @@ impl DNSRecord {
 				ref addr,
 				ttl: TransientTTL(ttl),
 			} => {
-				buffer.write_qname(domain)?;
+				buffer.write_name(domain)?;
 				buffer.write_u16(QueryType::AAAA.to_num())?;	// QueryType
 				buffer.write_u16(1)?;							// Class
 				buffer.write_u32(ttl)?;							// TTL
-				buffer.write_u32(16)?;							// DataLength
+				buffer.write_u16(16)?;							// DataLength
 
 				for octet in &addr.segments() {					// IPV6Address
 					buffer.write_u16(*octet)?;
@@ -472,7 +621,7 @@ impl DNSRecord {
 				ref host,
 				ttl: TransientTTL(ttl),
 			} => {
-				buffer.write_qname(domain)?;
+				buffer.write_name(domain)?;
 				buffer.write_u16(QueryType::NS.to_num())?;		// QueryType
 				buffer.write_u16(1)?;							// Class
 				buffer.write_u32(ttl)?;							// TTL
@@ -480,7 +629,7 @@ impl DNSRecord {
 				let pos = buffer.pos();
 				buffer.write_u16(0)?;							// Dummy DataLength...Correct DataLength will be set after the data is set...
 
-				buffer.write_qname(host)?;
+				buffer.write_name(host)?;
 
 				let data_len = buffer.pos() - (pos + 2);
 				buffer.set_u16(pos, data_len as u16)?;			// DataLength at the correct pos
@@ -490,7 +639,7 @@ impl DNSRecord {
 				ref host,
 				ttl: TransientTTL(ttl),
 			} => {
-				buffer.write_qname(domain)?;
+				buffer.write_name(domain)?;
 				buffer.write_u16(QueryType::CNAME.to_num())?;	// QueryType
 				buffer.write_u16(1)?;							// Class
 				buffer.write_u32(ttl)?;							// TTL
@@ -498,11 +647,29 @@ impl DNSRecord {
 				let pos = buffer.pos();
 				buffer.write_u16(0)?;							// // Dummy DataLength...Correct DataLength will be set after the data is set...
 
-				buffer.write_qname(host)?;		
+				buffer.write_name(host)?;
 
 				let data_len = buffer.pos() - (pos + 2);
 				buffer.set_u16(pos, data_len as u16)?;			// DataLength at the correct pos
 			} //CNAME
+			DNSRecord::PTR {
+				ref domain,
+				ref host,
+				ttl: TransientTTL(ttl),
+			} => {
+				buffer.write_name(domain)?;
+				buffer.write_u16(QueryType::PTR.to_num())?;		// QueryType
+				buffer.write_u16(1)?;							// Class
+				buffer.write_u32(ttl)?;							// TTL
+
+				let pos = buffer.pos();
+				buffer.write_u16(0)?;							// Dummy DataLength...Correct DataLength will be set after the data is set...
+
+				buffer.write_name(host)?;
+
+				let data_len = buffer.pos() - (pos + 2);
+				buffer.set_u16(pos, data_len as u16)?;			// DataLength at the correct pos
+			} // PTR
 			DNSRecord::SRV {
 				ref domain,
 				priority,
@@ -511,7 +678,7 @@ impl DNSRecord {
 				ref host,
 				ttl: TransientTTL(ttl),
 			} => {
-				buffer.write_qname(domain)?;
+				buffer.write_name(domain)?;
 				buffer.write_u16(QueryType::SRV.to_num())?;	// QueryType
 				buffer.write_u16(1)?;						// Class
 				buffer.write_u32(ttl)?;						// TTL
@@ -522,7 +689,7 @@ impl DNSRecord {
 				buffer.write_u16(priority)?;
 				buffer.write_u16(weight)?;
 				buffer.write_u16(port)?;
-				buffer.write_qname(host)?;
+				buffer.write_qname(host)?;					// Never compressed (RFC 2782)...
 
 				let data_len = buffer.pos() - (pos + 2);
 				buffer.set_u16(pos, data_len as u16)?;		// DataLength at the correct pos
@@ -533,7 +700,7 @@ impl DNSRecord {
 				ref host,
 				ttl: TransientTTL(ttl),
 			} => {
-				buffer.write_qname(domain)?;
+				buffer.write_name(domain)?;
 				buffer.write_u16(QueryType::MX.to_num())?;	// QueryType
 				buffer.write_u16(1)?;						// Class
 				buffer.write_u32(ttl)?;						// TTL
@@ -542,7 +709,7 @@ impl DNSRecord {
 				buffer.write_u16(0)?;						// // Dummy DataLength...Correct DataLength will be set after the data is set...
 
 				buffer.write_u16(priority)?;
-				buffer.write_qname(host)?;
+				buffer.write_name(host)?;
 
 				let data_len = buffer.pos() - (pos + 2);
 				buffer.set_u16(pos, data_len as u16)?;		// DataLengh at the correct pos
@@ -558,7 +725,7 @@ impl DNSRecord {
 				minimum,
 				ttl: TransientTTL(ttl),
 			} => {
-				buffer.write_qname(domain)?;
+				buffer.write_name(domain)?;
 				buffer.write_u16(QueryType::SOA.to_num())?;	// QueryType
 				buffer.write_u16(1)?;						// Class
 				buffer.write_u32(ttl)?;						// TTL
@@ -566,8 +733,8 @@ impl DNSRecord {
 				let pos = buffer.pos();
 				buffer.write_u16(0)?;						// // Dummy DataLength...Correct DataLength will be set after the data is set...
 
-				buffer.write_qname(m_name)?;
-				buffer.write_qname(r_name)?;
+				buffer.write_name(m_name)?;
+				buffer.write_name(r_name)?;
 				buffer.write_u32(serial)?;
 				buffer.write_u32(refresh)?;
 				buffer.write_u32(retry)?;
@@ -582,19 +749,107 @@ impl DNSRecord {
 				ref data,
 				ttl: TransientTTL(ttl),
 			} => {
-				buffer.write_qname(domain)?;
+				buffer.write_name(domain)?;
 				buffer.write_u16(QueryType::TXT.to_num())?;	// QueryType
 				buffer.write_u16(1)?;						// Class
 				buffer.write_u32(ttl)?;						// TTL
+
+				let pos = buffer.pos();
+				buffer.write_u16(0)?;						// Dummy DataLength...Correct DataLength will be set after the data is set...
+
+				for string in data {
+					if string.len() > 255 {
+						return Err(Error::new(ErrorKind::InvalidInput, "TXT string exceeds 255 bytes"));
+					}
+					buffer.write(string.len() as u8)?;
+					for b in string {
+						buffer.write(*b)?;
+					}
+				}
+
+				let data_len = u16::try_from(buffer.pos() - (pos + 2))
+					.map_err(|_| Error::new(ErrorKind::InvalidInput, "TXT data too long"))?;
+				buffer.set_u16(pos, data_len)?;				// DataLength at the correct pos
+			} // TXT	
+			DNSRecord::OPT {
+				packet_len,
+				flags,
+				ref data,
+			} => {
+				buffer.write(0)?;							// Root domain
+				buffer.write_u16(QueryType::OPT.to_num())?;	// QueryType
+				buffer.write_u16(packet_len)?;				// Class holds the UDP payload size
+				buffer.write_u32(flags)?;					// TTL holds the extended RCODE and flags
 				buffer.write_u16(data.len() as u16)?;		// DataLength
 
-				for b in data.as_bytes() {
+				for b in data {
+					buffer.write(*b)?;
+				}
+			} // OPT
+			DNSRecord::DS {
+				ref domain,
+				key_tag,
+				algorithm,
+				digest_type,
+				ref digest,
+				ttl: TransientTTL(ttl),
+			} => {
+				let data_len = u16::try_from(4 + digest.len())
+					.map_err(|_| Error::new(ErrorKind::InvalidInput, "DS digest too long"))?;
+				buffer.write_name(domain)?;
+				buffer.write_u16(QueryType::DS.to_num())?;	// QueryType
+				buffer.write_u16(1)?;						// Class
+				buffer.write_u32(ttl)?;						// TTL
+				buffer.write_u16(data_len)?;				// DataLength
+
+				buffer.write_u16(key_tag)?;
+				buffer.write(algorithm)?;
+				buffer.write(digest_type)?;
+				for b in digest {
+					buffer.write(*b)?;
+				}
+			} // DS
+			DNSRecord::DNSKEY {
+				ref domain,
+				flags,
+				protocol,
+				algorithm,
+				ref public_key,
+				ttl: TransientTTL(ttl),
+			} => {
+				let data_len = u16::try_from(4 + public_key.len())
+					.map_err(|_| Error::new(ErrorKind::InvalidInput, "DNSKEY public key too long"))?;
+				buffer.write_name(domain)?;
+				buffer.write_u16(QueryType::DNSKEY.to_num())?;	// QueryType
+				buffer.write_u16(1)?;							// Class
+				buffer.write_u32(ttl)?;							// TTL
+				buffer.write_u16(data_len)?;					// DataLength
+
+				buffer.write_u16(flags)?;
+				buffer.write(protocol)?;
+				buffer.write(algorithm)?;
+				for b in public_key {
+					buffer.write(*b)?;
+				}
+			} // DNSKEY
+			DNSRecord::UNKNOWN {
+				ref domain,
+				q_type,
+				ref data,
+				ttl: TransientTTL(ttl),
+			} => {
+				if data.len() > u16::MAX as usize {
+					return Err(Error::new(ErrorKind::InvalidInput, "RDATA too long"));
+				}
+				buffer.write_name(domain)?;
+				buffer.write_u16(q_type)?;					// QueryType
+				buffer.write_u16(1)?;						// Class
+				buffer.write_u32(ttl)?;						// TTL
+				buffer.write_u16(data.len() as u16)?;		// DataLength
+
+				for b in data {
 					buffer.write(*b)?;
 				}
-			} // TXT	
-			DNSRecord::OPT { .. } => { } // OPT
-			DNSRecord::UNKNOWN {..} => {
-				println!("Skipping Record :: {:?}", self);
 			} // UNKNOWN
 		}
 
@@ -607,32 +862,407 @@ impl DNSRecord {
 			DNSRecord::AAAA { .. } => QueryType::AAAA,
 			DNSRecord::NS { .. } => QueryType::NS,
 			DNSRecord::CNAME { .. } => QueryType::CNAME,
+			DNSRecord::PTR { .. } => QueryType::PTR,
 			DNSRecord::SRV { .. } => QueryType::SRV,
 			DNSRecord::MX { .. } => QueryType::MX,
 			DNSRecord::SOA { .. } => QueryType::SOA,
 			DNSRecord::TXT { .. } => QueryType::TXT,
 			DNSRecord::OPT { .. } => QueryType::OPT,
+			DNSRecord::DS { .. } => QueryType::DS,
+			DNSRecord::DNSKEY { .. } => QueryType::DNSKEY,
 			DNSRecord::UNKNOWN { q_type, .. } => QueryType::UNKNOWN(q_type),
 		}
 	}
 
+	pub fn get_ttl(&self) -> u32 {
+		match *self {
+			DNSRecord::A { ttl: TransientTTL(ttl), .. }
+			| DNSRecord::AAAA { ttl: TransientTTL(ttl), .. }
+			| DNSRecord::NS { ttl: TransientTTL(ttl), .. }
+			| DNSRecord::CNAME { ttl: TransientTTL(ttl), .. }
+			| DNSRecord::PTR { ttl: TransientTTL(ttl), .. }
+			| DNSRecord::SRV { ttl: TransientTTL(ttl), .. }
+			| DNSRecord::MX { ttl: TransientTTL(ttl), .. }
+			| DNSRecord::SOA { ttl: TransientTTL(ttl), .. }
+			| DNSRecord::TXT { ttl: TransientTTL(ttl), .. }
+			| DNSRecord::DS { ttl: TransientTTL(ttl), .. }
+			| DNSRecord::DNSKEY { ttl: TransientTTL(ttl), .. }
+			| DNSRecord::UNKNOWN { ttl: TransientTTL(ttl), .. } => ttl,
+			DNSRecord::OPT { .. } => 0,
+		}
+	}
+
+	/// Replace the TTL of the record, OPT records have none and are left alone.
+	pub fn set_ttl(&mut self, new_ttl: u32) {
+		match *self {
+			DNSRecord::A { ref mut ttl, .. }
+			| DNSRecord::AAAA { ref mut ttl, .. }
+			| DNSRecord::NS { ref mut ttl, .. }
+			| DNSRecord::CNAME { ref mut ttl, .. }
+			| DNSRecord::PTR { ref mut ttl, .. }
+			| DNSRecord::SRV { ref mut ttl, .. }
+			| DNSRecord::MX { ref mut ttl, .. }
+			| DNSRecord::SOA { ref mut ttl, .. }
+			| DNSRecord::TXT { ref mut ttl, .. }
+			| DNSRecord::DS { ref mut ttl, .. }
+			| DNSRecord::DNSKEY { ref mut ttl, .. }
+			| DNSRecord::UNKNOWN { ref mut ttl, .. } => *ttl = TransientTTL(new_ttl),
+			DNSRecord::OPT { .. } => (),
+		}
+	}
+
 	pub fn get_domain(&self) -> Option<String> {
 		match *self {
 			DNSRecord::A { ref domain, .. }
 			| DNSRecord::AAAA { ref domain, .. }
 			| DNSRecord::NS { ref domain, .. }
 			| DNSRecord::CNAME { ref domain, .. }
+			| DNSRecord::PTR { ref domain, .. }
 			| DNSRecord::SRV { ref domain, .. }
 			| DNSRecord::MX { ref domain, .. }
 			| DNSRecord::SOA { ref domain, .. }
 			| DNSRecord::TXT { ref domain, .. }
+			| DNSRecord::DS { ref domain, .. }
+			| DNSRecord::DNSKEY { ref domain, .. }
 			| DNSRecord::UNKNOWN { ref domain, .. } => Some(domain.clone()),
 			DNSRecord::OPT { .. } => None,
 		}
 	}
 }
+
+/// Presentation format of a record, the way it would be written in a zone file.
+impl fmt::Display for DNSRecord {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.get_domain() {
+			Some(domain) => write!(f, "{}.\t{}\tIN\t{}\t{}", domain, self.get_ttl(), self.get_query_type(), self.rdata_string()),
+			None => write!(f, "{:?}", self),
+		}
+	}
+}
+
+impl DNSRecord {
+	/// Presentation format of just the RDATA of the record.
+	pub fn rdata_string(&self) -> String {
+		match *self {
+			DNSRecord::A { ref addr, .. } => addr.to_string(),
+			DNSRecord::AAAA { ref addr, .. } => addr.to_string(),
+			DNSRecord::NS { ref host, .. } | DNSRecord::CNAME { ref host, .. } | DNSRecord::PTR { ref host, .. } => format!("{}.", host),
+			DNSRecord::SRV { priority, weight, port, ref host, .. } => {
+				format!("{} {} {} {}.", priority, weight, port, host)
+			}
+			DNSRecord::MX { priority, ref host, .. } => format!("{} {}.", priority, host),
+			DNSRecord::SOA { ref m_name, ref r_name, serial, refresh, retry, expire, minimum, .. } => {
+				format!("{}. {}. {} {} {} {} {}", m_name, r_name, serial, refresh, retry, expire, minimum)
+			}
+			DNSRecord::TXT { ref data, .. } => {
+				let strings: Vec<String> = data.iter().map(|string| quote_character_string(string)).collect();
+				strings.join(" ")
+			}
+			DNSRecord::DS { key_tag, algorithm, digest_type, ref digest, .. } => {
+				let hex: String = digest.iter().map(|b| format!("{:02X}", b)).collect();
+				format!("{} {} {} {}", key_tag, algorithm, digest_type, hex)
+			}
+			DNSRecord::DNSKEY { flags, protocol, algorithm, ref public_key, .. } => {
+				format!("{} {} {} {}", flags, protocol, algorithm, BASE64.encode(public_key))
+			}
+			DNSRecord::UNKNOWN { ref data, .. } => match self.legacy_rdata() {
+				Some(LegacyRData::Wks { addr, protocol, ref ports }) => {
+					let ports: Vec<String> = ports.iter().map(|port| port.to_string()).collect();
+					format!("{} {} {}", addr, protocol, ports.join(" ")).trim_end().to_string()
+				}
+				// The generic format of RFC 3597...
+				_ if data.is_empty() => "\\# 0".to_string(),
+				_ => {
+					let hex: String = data.iter().map(|b| format!("{:02X}", b)).collect();
+					format!("\\# {} {}", data.len(), hex)
+				}
+			},
+			DNSRecord::OPT { packet_len, flags, .. } => format!("udp={} flags={:#x}", packet_len, flags),
+		}
+	}
+
+	/// The strings of a TXT record joined together, the way SPF, DMARC and DKIM records are
+	/// read (RFC 7208 section 3.3). None for records of other types.
+	pub fn txt_string(&self) -> Option<String> {
+		match *self {
+			DNSRecord::TXT { ref data, .. } => Some(String::from_utf8_lossy(&data.concat()).into_owned()),
+			_ => None,
+		}
+	}
+}
+
+/// A character string in presentation format: quoted, with quotes, backslashes and the bytes
+/// which aren't printable ASCII escaped (RFC 1035 section 5.1).
+fn quote_character_string(string: &[u8]) -> String {
+	let mut out = String::from("\"");
+	for &b in string {
+		match b {
+			b'"' | b'\\' => {
+				out.push('\\');
+				out.push(b as char);
+			}
+			0x20..=0x7e => out.push(b as char),
+			_ => out.push_str(&format!("\\{:03}", b)),
+		}
+	}
+	out.push('"');
+	out
+}
+
+/// Typed view of the RDATA of a record of one of the legacy types.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LegacyRData {
+	/// NULL (RFC 1035 section 3.3.10), opaque data.
+	Null(Vec<u8>),
+	/// WKS (RFC 1035 section 3.4.2), the well known services offered at an address: an IP
+	/// protocol number and the ports served over it.
+	Wks { addr: Ipv4Addr, protocol: u8, ports: Vec<u16> },
+}
+
+impl LegacyRData {
+	/// Decode the RDATA of a record of type `q_type`. None if the type isn't a legacy type or
+	/// the RDATA is malformed.
+	pub fn decode(q_type: u16, data: &[u8]) -> Option<LegacyRData> {
+		match q_type {
+			TYPE_NULL => Some(LegacyRData::Null(data.to_vec())),
+			TYPE_WKS if data.len() >= 5 => {
+				let addr = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+				// Bit n of the bitmap, counting from the MSB of the first byte, is port n...
+				let ports = data[5..].iter()
+					.enumerate()
+					.flat_map(|(idx, byte)| (0..8).filter(move |bit| byte & (0x80 >> bit) != 0).map(move |bit| (idx * 8 + bit) as u16))
+					.collect();
+				Some(LegacyRData::Wks { addr, protocol: data[4], ports })
+			}
+			_ => None,
+		}
+	}
+
+	pub fn q_type(&self) -> u16 {
+		match *self {
+			LegacyRData::Null(_) => TYPE_NULL,
+			LegacyRData::Wks { .. } => TYPE_WKS,
+		}
+	}
+
+	/// The RDATA in wire format.
+	pub fn encode(&self) -> Vec<u8> {
+		match *self {
+			LegacyRData::Null(ref data) => data.clone(),
+			LegacyRData::Wks { addr, protocol, ref ports } => {
+				let mut data = addr.octets().to_vec();
+				data.push(protocol);
+				let bitmap_len = ports.iter().max().map(|max| *max as usize / 8 + 1).unwrap_or(0);
+				let mut bitmap = vec![0u8; bitmap_len];
+				for port in ports {
+					bitmap[*port as usize / 8] |= 0x80 >> (port % 8);
+				}
+				data.extend(bitmap);
+				data
+			}
+		}
+	}
+}
+
+/// Keys of the SvcParams (RFC 9460 section 14.3.2)...
+pub const SVC_PARAM_ALPN: u16 = 1;
+pub const SVC_PARAM_NO_DEFAULT_ALPN: u16 = 2;
+pub const SVC_PARAM_PORT: u16 = 3;
+pub const SVC_PARAM_IPV4_HINT: u16 = 4;
+pub const SVC_PARAM_ECH: u16 = 5;
+pub const SVC_PARAM_IPV6_HINT: u16 = 6;
+
+/// Typed view of the RDATA of an SVCB or HTTPS record (RFC 9460 section 2.2). A priority of
+/// 0 makes it an alias to `target`, otherwise it describes a service endpoint at `target`,
+/// the owner name itself when that's the root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SvcbRData {
+	pub priority: u16,
+	pub target: String,
+	/// SvcParams by key, in the ascending order they're sent in...
+	pub params: Vec<(u16, Vec<u8>)>,
+}
+
+impl SvcbRData {
+	/// Decode the RDATA of an SVCB or HTTPS record, None if it's malformed. The target name
+	/// is never compressed.
+	pub fn decode(data: &[u8]) -> Option<SvcbRData> {
+		let priority = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+		let mut pos = 2;
+		let mut labels = Vec::new();
+		loop {
+			let len = *data.get(pos)? as usize;
+			pos += 1;
+			if len == 0 {
+				break;
+			}
+			labels.push(String::from_utf8_lossy(data.get(pos..pos + len)?).to_lowercase());
+			pos += len;
+		}
+
+		let mut params = Vec::new();
+		while pos < data.len() {
+			let key = u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]);
+			let len = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+			params.push((key, data.get(pos + 4..pos + 4 + len)?.to_vec()));
+			pos += 4 + len;
+		}
+		Some(SvcbRData { priority, target: labels.join("."), params })
+	}
+
+	pub fn is_alias(&self) -> bool {
+		self.priority == 0
+	}
+
+	pub fn param(&self, key: u16) -> Option<&[u8]> {
+		self.params.iter().find(|(k, _)| *k == key).map(|(_, value)| value.as_slice())
+	}
+
+	/// Protocols of the `alpn` parameter.
+	pub fn alpn(&self) -> Vec<String> {
+		let mut alpn = Vec::new();
+		let mut value = self.param(SVC_PARAM_ALPN).unwrap_or_default();
+		while let Some((&len, rest)) = value.split_first() {
+			let len = (len as usize).min(rest.len());
+			alpn.push(String::from_utf8_lossy(&rest[..len]).into_owned());
+			value = &rest[len..];
+		}
+		alpn
+	}
+
+	pub fn port(&self) -> Option<u16> {
+		match self.param(SVC_PARAM_PORT)? {
+			&[high, low] => Some(u16::from_be_bytes([high, low])),
+			_ => None,
+		}
+	}
+
+	/// Addresses of the `ipv4hint` and `ipv6hint` parameters.
+	pub fn hints(&self) -> Vec<IpAddr> {
+		let v4 = self.param(SVC_PARAM_IPV4_HINT).unwrap_or_default()
+			.chunks_exact(4)
+			.map(|octets| IpAddr::from([octets[0], octets[1], octets[2], octets[3]]));
+		let v6 = self.param(SVC_PARAM_IPV6_HINT).unwrap_or_default()
+			.chunks_exact(16)
+			.filter_map(|octets| <[u8; 16]>::try_from(octets).ok())
+			.map(IpAddr::from);
+		v4.chain(v6).collect()
+	}
+}
+
+impl DNSRecord {
+	/// Typed RDATA of an UNKNOWN record of type SVCB or HTTPS, None for anything else.
+	pub fn svcb_rdata(&self) -> Option<SvcbRData> {
+		match *self {
+			DNSRecord::UNKNOWN { q_type: TYPE_SVCB | TYPE_HTTPS, ref data, .. } => SvcbRData::decode(data),
+			_ => None,
+		}
+	}
+
+	/// Typed RDATA of an UNKNOWN record of one of the legacy types, None for anything else.
+	pub fn legacy_rdata(&self) -> Option<LegacyRData> {
+		match *self {
+			DNSRecord::UNKNOWN { q_type, ref data, .. } => LegacyRData::decode(q_type, data),
+			_ => None,
+		}
+	}
+}
 // --------------------------------------------------------------------------------------------
 
+/// An option of the OPT record (RFC 6891 section 6.1.2), its data left as it is on the wire.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EdnsOption {
+	pub code: u16,
+	pub data: Vec<u8>,
+}
+
+impl EdnsOption {
+	pub fn new(code: u16, data: Vec<u8>) -> EdnsOption {
+		EdnsOption { code, data }
+	}
+
+	/// An Extended DNS Error (RFC 8914).
+	pub fn extended_error(info_code: u16, text: &str) -> EdnsOption {
+		let mut data = info_code.to_be_bytes().to_vec();
+		data.extend_from_slice(text.as_bytes());
+		EdnsOption::new(EDNS_OPTION_EDE, data)
+	}
+
+	/// The client subnet of `addr` with `prefix` bits (RFC 7871 section 6), the address being
+	/// cut to those.
+	pub fn client_subnet(addr: IpAddr, prefix: u8) -> EdnsOption {
+		let (family, octets, prefix) = match addr {
+			IpAddr::V4(addr) => (1u16, addr.octets().to_vec(), prefix.min(32)),
+			IpAddr::V6(addr) => (2u16, addr.octets().to_vec(), prefix.min(128)),
+		};
+		let len = (prefix as usize).div_ceil(8);
+		let mut data = family.to_be_bytes().to_vec();
+		data.push(prefix);
+		data.push(0);
+		data.extend_from_slice(&octets[..len]);
+		if prefix % 8 != 0 {
+			data[3 + len] &= 0xff << (8 - prefix % 8);
+		}
+		EdnsOption::new(EDNS_OPTION_ECS, data)
+	}
+
+	/// The address and source prefix length of a client subnet option. None for the other
+	/// options and for those which are invalid.
+	pub fn as_client_subnet(&self) -> Option<(IpAddr, u8)> {
+		if self.code != EDNS_OPTION_ECS || self.data.len() < 4 {
+			return None;
+		}
+		let family = u16::from_be_bytes([self.data[0], self.data[1]]);
+		let prefix = self.data[2];
+		let address = &self.data[4..];
+		if address.len() != (prefix as usize).div_ceil(8) {
+			return None;
+		}
+		match family {
+			1 if prefix <= 32 => {
+				let mut octets = [0u8; 4];
+				octets[..address.len()].copy_from_slice(address);
+				Some((IpAddr::V4(Ipv4Addr::from(octets)), prefix))
+			}
+			2 if prefix <= 128 => {
+				let mut octets = [0u8; 16];
+				octets[..address.len()].copy_from_slice(address);
+				Some((IpAddr::V6(Ipv6Addr::from(octets)), prefix))
+			}
+			_ => None,
+		}
+	}
+
+	/// The options in the data of an OPT record. Data running past the end of the record
+	/// makes it invalid.
+	pub fn parse_all(data: &[u8]) -> Result<Vec<EdnsOption>> {
+		let mut options = Vec::new();
+		let mut rest = data;
+		while !rest.is_empty() {
+			if rest.len() < 4 {
+				return Err(Error::new(ErrorKind::InvalidData, "EDNS option too short"));
+			}
+			let code = u16::from_be_bytes([rest[0], rest[1]]);
+			let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+			let data = rest.get(4..4 + len).ok_or_else(|| Error::new(ErrorKind::InvalidData, "EDNS option too long"))?;
+			options.push(EdnsOption::new(code, data.to_vec()));
+			rest = &rest[4 + len..];
+		}
+		Ok(options)
+	}
+
+	/// The data of an OPT record holding `options`.
+	pub fn write_all(options: &[EdnsOption]) -> Vec<u8> {
+		let mut data = Vec::new();
+		for option in options {
+			data.extend_from_slice(&option.code.to_be_bytes());
+			data.extend_from_slice(&(option.data.len() as u16).to_be_bytes());
+			data.extend_from_slice(&option.data);
+		}
+		data
+	}
+}
+
 /// Representation of DNS Packet.
 // TODO: Change the struct variable to private.
 #[derive(Clone, Debug, Default)]
@@ -683,6 +1313,136 @@ impl DNSPacket {
 		Ok(dns_packet)
 	}
 
+	/// The OPT pseudo record of the packet, present when it uses EDNS (RFC 6891).
+	pub fn edns(&self) -> Option<&DNSRecord> {
+		self.additional.iter().find(|record| record.get_query_type() == QueryType::OPT)
+	}
+
+	/// Add an Extended DNS Error (RFC 8914) to the OPT record of the packet. Packets without
+	/// EDNS can't carry one, false is returned for those.
+	pub fn add_extended_error(&mut self, info_code: u16, text: &str) -> bool {
+		self.add_edns_option(EdnsOption::extended_error(info_code, text))
+	}
+
+	fn opt_data(&mut self) -> Option<&mut Vec<u8>> {
+		self.additional.iter_mut().find_map(|record| match *record {
+			DNSRecord::OPT { ref mut data, .. } => Some(data),
+			_ => None,
+		})
+	}
+
+	/// The options of the OPT record of the packet, None without EDNS or if they're invalid.
+	pub fn edns_options(&self) -> Option<Vec<EdnsOption>> {
+		match self.edns() {
+			Some(DNSRecord::OPT { data, .. }) => EdnsOption::parse_all(data).ok(),
+			_ => None,
+		}
+	}
+
+	/// Replace the options of the OPT record of the packet. False for packets without EDNS.
+	pub fn set_edns_options(&mut self, options: &[EdnsOption]) -> bool {
+		match self.opt_data() {
+			Some(data) => {
+				*data = EdnsOption::write_all(options);
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Add an option to the OPT record of the packet. False for packets without EDNS.
+	pub fn add_edns_option(&mut self, option: EdnsOption) -> bool {
+		match self.opt_data() {
+			Some(data) => {
+				data.extend_from_slice(&EdnsOption::write_all(&[option]));
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Drop the options with `code` from the OPT record of the packet, returning the number
+	/// dropped.
+	pub fn remove_edns_options(&mut self, code: u16) -> usize {
+		let mut options = match self.edns_options() {
+			Some(options) => options,
+			None => return 0,
+		};
+		let count = options.len();
+		options.retain(|option| option.code != code);
+		let removed = count - options.len();
+		if removed > 0 {
+			self.set_edns_options(&options);
+		}
+		removed
+	}
+
+	/// Drop whole RRsets from the end of the packet until it fits in `max_size` bytes. TC is
+	/// set when answer or authority records had to go; leaving out additional records doesn't
+	/// call for it (RFC 2181 section 9). The OPT record is always kept.
+	///
+	/// The RRsets are measured as the packet gets written, their names compressed when
+	/// `compress` is set, so a response is only cut down when it doesn't fit once encoded.
+	pub fn truncate_to(&mut self, max_size: usize, compress: bool) -> Result<()> {
+		// The packet is written as far as it fits, in the order `write` takes, an RRset taking
+		// the bytes it moves the end by, pointers to the names written before it included...
+		let mut buffer = if compress {
+			VectorPacketBuffer::with_compression()
+		} else {
+			VectorPacketBuffer::new()
+		};
+		self.header.write(&mut buffer)?;
+		for question in &self.questions {
+			question.write(&mut buffer)?;
+		}
+		// The OPT record is owned by the root and holds no names, so it takes as many bytes
+		// wherever it's written...
+		let mut reserved = 0;
+		for record in self.additional.iter().filter(|record| record.get_query_type() == QueryType::OPT) {
+			reserved += record.write(&mut VectorPacketBuffer::new())?;
+		}
+
+		let mut sections = [&mut self.answers, &mut self.authorities, &mut self.additional];
+		let mut full = false;
+		for (idx, section) in sections.iter_mut().enumerate() {
+			if full {
+				section.retain(|record| record.get_query_type() == QueryType::OPT);
+				continue;
+			}
+
+			let mut kept = 0;
+			while kept < section.len() {
+				// The RRset starting at `kept`, which is made of the records following with
+				// the same owner and type...
+				let first = &section[kept];
+				if first.get_query_type() == QueryType::OPT {
+					kept += 1;
+					continue;
+				}
+				let rrset_len = section[kept..].iter()
+					.take_while(|record| record.get_domain() == first.get_domain() && record.get_query_type() == first.get_query_type())
+					.count();
+				for record in &section[kept..kept + rrset_len] {
+					record.write(&mut buffer)?;
+				}
+				if buffer.pos() + reserved > max_size {
+					full = true;
+					break;
+				}
+				kept += rrset_len;
+			}
+
+			if full {
+				let dropped = section.split_off(kept);
+				section.extend(dropped.into_iter().filter(|record| record.get_query_type() == QueryType::OPT));
+				if idx < 2 {
+					self.header.truncated_message = true;
+				}
+			}
+		}
+		Ok(())
+	}
+
 	pub fn write<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<()> {
 		self.header.questions = self.questions.len() as u16;
 		self.header.answers = self.answers.len() as u16;
@@ -705,4 +1465,285 @@ impl DNSPacket {
 		}		
 		Ok(())
 	}
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::server::buffer::VectorPacketBuffer;
+	use crate::server::editor::PacketEditor;
+
+	const ZONE: &str = "example.com";
+
+	fn ttl() -> TransientTTL {
+		TransientTTL(3600)
+	}
+
+	/// `record` in a response to a query for the zone, its names compressed or not.
+	fn write_record(record: &DNSRecord, compress: bool) -> Vec<u8> {
+		let mut packet = DNSPacket::new();
+		packet.questions.push(DNSQuestion::new(ZONE.to_string(), record.get_query_type()));
+		if record.get_query_type() == QueryType::OPT {
+			packet.additional.push(record.clone());
+		} else {
+			packet.answers.push(record.clone());
+		}
+		let mut buffer = if compress { VectorPacketBuffer::with_compression() } else { VectorPacketBuffer::new() };
+		packet.write(&mut buffer).unwrap();
+		buffer.into_inner()
+	}
+
+	fn read_record(message: Vec<u8>) -> DNSRecord {
+		let packet = DNSPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(message)).unwrap();
+		packet.answers.into_iter().chain(packet.additional).next().unwrap()
+	}
+
+	fn rdata(message: &[u8]) -> Vec<u8> {
+		PacketEditor::new(message.to_vec()).unwrap().rdata(0).unwrap().to_vec()
+	}
+
+	/// Write `record` with and without compression, and check it reads back the same either
+	/// way and whether its RDATA got shorter.
+	fn check_round_trip(record: DNSRecord, rdata_compressed: bool) {
+		let plain = write_record(&record, false);
+		let compressed = write_record(&record, true);
+		assert_eq!(read_record(plain.clone()), record);
+		assert_eq!(read_record(compressed.clone()), record);
+		assert_eq!(read_record(compressed.clone()).get_ttl(), record.get_ttl());
+		if rdata_compressed {
+			assert!(rdata(&compressed).len() < rdata(&plain).len(), "{:?} RDATA not compressed", record);
+		} else {
+			assert_eq!(rdata(&compressed), rdata(&plain), "{:?} RDATA compressed", record);
+		}
+	}
+
+	#[test]
+	fn round_trip_a() {
+		check_round_trip(DNSRecord::A { domain: format!("www.{}", ZONE), addr: Ipv4Addr::new(192, 0, 2, 1), ttl: ttl() }, false);
+	}
+
+	#[test]
+	fn round_trip_ns() {
+		check_round_trip(DNSRecord::NS { domain: ZONE.to_string(), host: format!("ns1.{}", ZONE), ttl: ttl() }, true);
+	}
+
+	#[test]
+	fn round_trip_cname() {
+		check_round_trip(DNSRecord::CNAME { domain: format!("alias.{}", ZONE), host: format!("www.{}", ZONE), ttl: ttl() }, true);
+	}
+
+	#[test]
+	fn round_trip_soa() {
+		check_round_trip(DNSRecord::SOA {
+			domain: ZONE.to_string(),
+			m_name: format!("ns1.{}", ZONE),
+			r_name: format!("hostmaster.{}", ZONE),
+			serial: 2024050401,
+			refresh: 7200,
+			retry: 900,
+			expire: 1209600,
+			minimum: 300,
+			ttl: ttl(),
+		}, true);
+	}
+
+	#[test]
+	fn round_trip_ptr() {
+		check_round_trip(DNSRecord::PTR { domain: format!("1.2.0.192.in-addr.arpa.{}", ZONE), host: format!("www.{}", ZONE), ttl: ttl() }, true);
+	}
+
+	#[test]
+	fn round_trip_mx() {
+		check_round_trip(DNSRecord::MX { domain: ZONE.to_string(), priority: 10, host: format!("mail.{}", ZONE), ttl: ttl() }, true);
+	}
+
+	#[test]
+	fn round_trip_txt() {
+		check_round_trip(DNSRecord::TXT { domain: ZONE.to_string(), data: vec![b"v=spf1 -all".to_vec()], ttl: ttl() }, false);
+		// The strings stay apart, and bytes which aren't UTF-8 come back as they were...
+		let data = vec![b"first".to_vec(), Vec::new(), vec![0, 0xff, b'"', 0x80], vec![b'x'; 255]];
+		check_round_trip(DNSRecord::TXT { domain: ZONE.to_string(), data, ttl: ttl() }, false);
+	}
+
+	#[test]
+	fn txt_string_past_the_rdata_is_rejected() {
+		let mut message = write_record(&DNSRecord::TXT { domain: ZONE.to_string(), data: vec![b"abc".to_vec()], ttl: ttl() }, false);
+		// The length of the string is one more than the RDATA holds, the byte after it being
+		// there still...
+		let len = message.len();
+		message[len - 4] = 4;
+		message.push(0);
+		let e = parse(message).unwrap_err();
+		assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn txt_string_over_255_bytes_is_not_written() {
+		let record = DNSRecord::TXT { domain: ZONE.to_string(), data: vec![vec![b'x'; 256]], ttl: ttl() };
+		let mut packet = DNSPacket::new();
+		packet.answers.push(record);
+		assert!(packet.write(&mut VectorPacketBuffer::new()).is_err());
+	}
+
+	#[test]
+	fn txt_over_rdlength_is_not_written() {
+		// 256 strings of 255 bytes, with their lengths, are one byte over what RDLENGTH holds...
+		let data = vec![vec![b'x'; 255]; 256];
+		let mut packet = DNSPacket::new();
+		packet.answers.push(DNSRecord::TXT { domain: ZONE.to_string(), data, ttl: ttl() });
+		let e = packet.write(&mut VectorPacketBuffer::new()).unwrap_err();
+		assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+	}
+
+	#[test]
+	fn ds_and_dnskey_over_rdlength_are_not_written() {
+		let records = [
+			DNSRecord::DS { domain: ZONE.to_string(), key_tag: 1, algorithm: 8, digest_type: 2, digest: vec![0; 65532], ttl: ttl() },
+			DNSRecord::DNSKEY { domain: ZONE.to_string(), flags: 257, protocol: 3, algorithm: 8, public_key: vec![0; 65532], ttl: ttl() },
+		];
+		for record in records {
+			let mut packet = DNSPacket::new();
+			packet.answers.push(record);
+			let e = packet.write(&mut VectorPacketBuffer::new()).unwrap_err();
+			assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+		}
+	}
+
+	#[test]
+	fn round_trip_aaaa() {
+		let addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+		check_round_trip(DNSRecord::AAAA { domain: format!("www.{}", ZONE), addr, ttl: ttl() }, false);
+	}
+
+	#[test]
+	fn round_trip_srv_target_not_compressed() {
+		check_round_trip(DNSRecord::SRV {
+			domain: format!("_dns._udp.{}", ZONE),
+			priority: 10,
+			weight: 5,
+			port: 53,
+			host: format!("ns1.{}", ZONE),
+			ttl: ttl(),
+		}, false);
+	}
+
+	#[test]
+	fn round_trip_opt() {
+		check_round_trip(DNSRecord::OPT { packet_len: 1232, flags: 0, data: vec![0, 15, 0, 2, 0, 3] }, false);
+	}
+
+	#[test]
+	fn round_trip_ds() {
+		check_round_trip(DNSRecord::DS { domain: ZONE.to_string(), key_tag: 12345, algorithm: 13, digest_type: 2, digest: vec![0xab; 32], ttl: ttl() }, false);
+	}
+
+	#[test]
+	fn round_trip_dnskey() {
+		check_round_trip(DNSRecord::DNSKEY { domain: ZONE.to_string(), flags: 257, protocol: 3, algorithm: 13, public_key: vec![0xcd; 64], ttl: ttl() }, false);
+	}
+
+	#[test]
+	fn round_trip_unknown() {
+		check_round_trip(DNSRecord::UNKNOWN { domain: format!("www.{}", ZONE), q_type: 65280, data: vec![1, 2, 3, 4], ttl: ttl() }, false);
+	}
+
+	/// A query for `name`, the bytes of its question name given as is.
+	fn query_with_name(name: &[u8]) -> Vec<u8> {
+		let mut message = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+		message.extend_from_slice(name);
+		message.extend_from_slice(&[0, 1, 0, 1]);
+		message
+	}
+
+	fn parse(message: Vec<u8>) -> Result<DNSPacket> {
+		DNSPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(message))
+	}
+
+	#[test]
+	fn pointer_to_itself_is_rejected() {
+		let e = parse(query_with_name(&[0xc0, 0x0c])).unwrap_err();
+		assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn pointer_back_into_the_same_name_is_rejected() {
+		// a, then a pointer to the label a before it...
+		let e = parse(query_with_name(&[1, b'a', 0xc0, 0x0c])).unwrap_err();
+		assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn pointer_forward_is_rejected() {
+		let e = parse(query_with_name(&[0xc0, 0x0e, 1, b'a', 0])).unwrap_err();
+		assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn pointers_to_prior_names_are_followed() {
+		let record = DNSRecord::CNAME { domain: format!("a.{}", ZONE), host: format!("b.a.{}", ZONE), ttl: ttl() };
+		let message = write_record(&record, true);
+		// The owner points to the question, and the RDATA to the owner...
+		assert!(message.windows(2).filter(|pair| pair[0] & 0xc0 == 0xc0).count() >= 2);
+		assert_eq!(read_record(message), record);
+	}
+
+	#[test]
+	fn overlong_name_is_rejected() {
+		let mut name = Vec::new();
+		for _ in 0..5 {
+			name.push(63);
+			name.extend_from_slice(&[b'a'; 63]);
+		}
+		name.push(0);
+		let e = parse(query_with_name(&name)).unwrap_err();
+		assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+	}
+
+	/// A response for the NS records of the zone holding `count` of them, with an OPT record.
+	fn ns_response(count: usize) -> DNSPacket {
+		let mut packet = DNSPacket::new();
+		packet.header.response = true;
+		packet.questions.push(DNSQuestion::new(ZONE.to_string(), QueryType::NS));
+		for i in 0..count {
+			packet.answers.push(DNSRecord::NS { domain: ZONE.to_string(), host: format!("ns{}.{}", i, ZONE), ttl: ttl() });
+		}
+		packet.additional.push(DNSRecord::OPT { packet_len: 1232, flags: 0, data: Vec::new() });
+		packet
+	}
+
+	fn encoded_len(packet: &DNSPacket, compress: bool) -> usize {
+		let mut buffer = if compress { VectorPacketBuffer::with_compression() } else { VectorPacketBuffer::new() };
+		packet.clone().write(&mut buffer).unwrap();
+		buffer.as_slice().len()
+	}
+
+	#[test]
+	fn truncate_measures_compressed_names() {
+		let packet = ns_response(40);
+		let limit = encoded_len(&packet, true);
+		assert!(encoded_len(&packet, false) > limit);
+
+		let mut compressed = packet.clone();
+		compressed.truncate_to(limit, true).unwrap();
+		assert_eq!(compressed.answers.len(), 40);
+		assert!(!compressed.header.truncated_message);
+
+		let mut plain = packet;
+		plain.truncate_to(limit, false).unwrap();
+		assert!(plain.answers.is_empty());
+		assert!(plain.header.truncated_message);
+		assert_eq!(plain.additional.len(), 1);
+		assert!(encoded_len(&plain, false) <= limit);
+	}
+
+	#[test]
+	fn truncate_drops_rrsets_past_the_limit() {
+		let mut packet = ns_response(20);
+		packet.answers.push(DNSRecord::A { domain: format!("www.{}", ZONE), addr: Ipv4Addr::new(192, 0, 2, 1), ttl: ttl() });
+		let limit = encoded_len(&packet, true) - 1;
+		packet.truncate_to(limit, true).unwrap();
+		assert_eq!(packet.answers.len(), 20);
+		assert!(packet.header.truncated_message);
+		assert_eq!(packet.additional.len(), 1);
+		assert!(encoded_len(&packet, true) <= limit);
+	}
+}
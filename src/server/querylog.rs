@@ -0,0 +1,181 @@
+//! Structured query log: a JSON line for every query answered
+
+use std::fs::OpenOptions;
+use std::io::{ self, BufWriter, Result, Write };
+use std::net::SocketAddr;
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::mpsc::{ self, Receiver, RecvTimeoutError, SyncSender, TrySendError };
+use std::sync::{ Arc, Mutex, RwLock };
+use std::thread::{ self, JoinHandle };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use rand::Rng;
+use serde::Serialize;
+
+use crate::server::latency::QueryTiming;
+use crate::server::protocol::{ DNSQuestion, ResultCode };
+
+/// Entries waiting to be written before new ones get dropped...
+const QUERY_LOG_QUEUE: usize = 10000;
+/// Time entries may sit in the buffer of the writer when queries are few...
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A query as it's logged.
+#[derive(Debug, Serialize)]
+struct QueryLogEntry {
+	timestamp: String,
+	client: String,
+	qname: Option<String>,
+	qtype: Option<String>,
+	rcode: String,
+	latency_ms: f64,
+	cache_hit: bool,
+	upstream: Option<String>,
+}
+
+/// Logs the queries answered as JSON lines, to a file or to stdout:
+///
+/// ```json
+/// {"timestamp":"2024-05-04T12:00:00.125Z","client":"192.0.2.7:53124",
+///  "qname":"example.com","qtype":"A","rcode":"NOERROR","latency_ms":12.5,
+///  "cache_hit":false,"upstream":"udp://9.9.9.9:53"}
+/// ```
+///
+/// (on a single line). Entries are written by a thread of the log's own through a buffer,
+/// so that queries never wait for the disk; when it can't keep up they're dropped and
+/// counted. Only a sample of the queries is logged when told to, for busy servers. The
+/// entries left in the buffer are written when the log is closed.
+#[derive(Debug)]
+pub struct QueryLog {
+	path: PathBuf,
+	sample: f64,
+	/// Taken when the log is closed, which stops the writer...
+	queue: RwLock<Option<SyncSender<QueryLogEntry>>>,
+	writer: Mutex<Option<JoinHandle<()>>>,
+	dropped: Arc<AtomicU64>,
+}
+
+impl QueryLog {
+	/// Log `percent` percent of the queries to the file at `path`, appending to it, or to
+	/// stdout for `-`. Starts the thread writing them.
+	pub fn open(path: &Path, percent: f64) -> Result<QueryLog> {
+		let out: Box<dyn Write + Send> = if path == Path::new("-") {
+			Box::new(io::stdout())
+		} else {
+			Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+		};
+		let (queue, receiver) = mpsc::sync_channel(QUERY_LOG_QUEUE);
+		let writer = thread::Builder::new()
+			.name("QueryLog".to_string())
+			.spawn(move || write_entries(BufWriter::new(out), &receiver))?;
+
+		Ok(QueryLog {
+			path: path.to_path_buf(),
+			sample: percent / 100.0,
+			queue: RwLock::new(Some(queue)),
+			writer: Mutex::new(Some(writer)),
+			dropped: Arc::new(AtomicU64::new(0)),
+		})
+	}
+
+	/// Stop logging, and wait for the entries queued to be written and flushed.
+	pub fn close(&self) {
+		if let Ok(mut queue) = self.queue.write() {
+			queue.take();
+		}
+		let writer = self.writer.lock().ok().and_then(|mut writer| writer.take());
+		if let Some(writer) = writer {
+			let _ = writer.join();
+		}
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Share of the queries logged, in percent.
+	pub fn percent(&self) -> f64 {
+		self.sample * 100.0
+	}
+
+	/// Number of entries dropped as the writer couldn't keep up.
+	pub fn dropped(&self) -> u64 {
+		self.dropped.load(Ordering::Relaxed)
+	}
+
+	/// Log the query of `client` for `question` which was answered with `rescode`, if it's
+	/// picked for the sample.
+	pub fn record(&self, client: SocketAddr, question: Option<&DNSQuestion>, rescode: ResultCode, timing: &QueryTiming) {
+		if self.sample < 1.0 && !rand::thread_rng().gen_bool(self.sample) {
+			return;
+		}
+		let entry = QueryLogEntry {
+			timestamp: rfc3339(SystemTime::now()),
+			client: client.to_string(),
+			qname: question.map(|question| question.name.clone()),
+			qtype: question.map(|question| question.q_type.to_string()),
+			rcode: format!("{:?}", rescode),
+			latency_ms: timing.total().as_micros() as f64 / 1000.0,
+			cache_hit: timing.cache_hit,
+			upstream: timing.server.clone(),
+		};
+		let queue = match self.queue.read() {
+			Ok(queue) => queue,
+			Err(_) => return,
+		};
+		let sent = match *queue {
+			Some(ref queue) => queue.try_send(entry),
+			None => return,
+		};
+		match sent {
+			Ok(()) => (),
+			Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+				self.dropped.fetch_add(1, Ordering::Relaxed);
+			}
+		}
+	}
+}
+
+/// Write the entries of the queue to `out` until the log is closed or dropped, flushing when the queue
+/// runs dry for a while.
+fn write_entries<W: Write>(mut out: W, queue: &Receiver<QueryLogEntry>) {
+	loop {
+		let entry = match queue.recv_timeout(FLUSH_INTERVAL) {
+			Ok(entry) => entry,
+			Err(RecvTimeoutError::Timeout) => {
+				let _ = out.flush();
+				continue;
+			}
+			Err(RecvTimeoutError::Disconnected) => break,
+		};
+		let written = serde_json::to_writer(&mut out, &entry)
+			.map_err(io::Error::from)
+			.and_then(|()| writeln!(out));
+		if let Err(e) = written {
+			info!("Failed to write the query log: {}", e);
+		}
+	}
+	let _ = out.flush();
+}
+
+/// `time` in UTC as RFC 3339, to the millisecond.
+fn rfc3339(time: SystemTime) -> String {
+	let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+	let secs = since.as_secs();
+	let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+	// Civil date of a day count (Howard Hinnant's days_from_civil, reversed)...
+	let z = days + 719468;
+	let era = z.div_euclid(146097);
+	let doe = z.rem_euclid(146097);
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+	format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day,
+		secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60, since.subsec_millis())
+}
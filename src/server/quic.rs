@@ -0,0 +1,272 @@
+//! DNS over QUIC listener (RFC 9250)
+
+use std::convert::TryFrom;
+use std::io::{ Error, Result };
+use std::net::SocketAddr;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::Arc;
+use std::thread::{ self, JoinHandle };
+use std::time::Instant;
+
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{ Connection, Endpoint, IdleTimeout, RecvStream, SendStream, TransportConfig, VarInt };
+use rustls::server::ProducesTickets;
+use rustls::ServerConfig;
+use tokio::runtime::Builder;
+use tokio::sync::watch;
+
+use crate::server::client::write_tcp_message;
+use crate::server::context::{ Listener, ServerContext, ServerRole };
+use crate::server::handler::{ handle_request, parse_request };
+use crate::server::latency::QueryTiming;
+use crate::server::resolve::{ DynamicResolvers, ResolverFactory };
+use crate::server::upstream::Transport;
+
+/// Port DNS over QUIC is served on, the one of DNS over TLS over UDP (RFC 9250 section 4.1.1)...
+pub const DEFAULT_QUIC_PORT: u16 = 853;
+
+/// Application error codes connections and streams are closed with (RFC 9250 section 4.3).
+pub const DOQ_NO_ERROR: u32 = 0x0;
+pub const DOQ_INTERNAL_ERROR: u32 = 0x1;
+pub const DOQ_PROTOCOL_ERROR: u32 = 0x2;
+pub const DOQ_REQUEST_CANCELLED: u32 = 0x3;
+
+/// Queries of a connection being resolved at the same time, as over TCP. Clients can't open
+/// more streams until some are answered...
+const MAX_STREAMS: u32 = 16;
+/// Longest message a stream may carry, with its two byte length...
+pub const MAX_STREAM_DATA: usize = 65537;
+
+/// DNS over QUIC listener. Each query comes on a stream of its own, length framed like over
+/// TCP, and is answered on the same stream as soon as it's resolved.
+///
+/// Clients resuming a session may send queries in 0-RTT data; those are answered right away,
+/// except for the ones which aren't safe to replay (anything but a query, like dynamic
+/// updates), which wait for the handshake to complete. Idle connections are closed and
+/// connections are limited like on the TCP listener, by `tcp_idle_timeout` and
+/// `tcp_max_connections`.
+pub struct DNSQuicServer<F: ResolverFactory = DynamicResolvers> {
+	context: Arc<ServerContext>,
+	resolvers: Arc<F>,
+	config: Arc<ServerConfig>,
+	addr: SocketAddr,
+	role: ServerRole,
+	connections: Arc<AtomicUsize>,
+}
+
+impl DNSQuicServer {
+	pub fn new(context: Arc<ServerContext>, config: Arc<ServerConfig>, addr: SocketAddr) -> Self {
+		DNSQuicServer::with_resolvers(context, config, addr, DynamicResolvers)
+	}
+}
+
+impl<F: ResolverFactory> DNSQuicServer<F> {
+	/// The TLS configuration is the one of the DNS over TLS listener, with `doq` as the ALPN
+	/// protocol instead and early data accepted.
+	pub fn with_resolvers(context: Arc<ServerContext>, config: Arc<ServerConfig>, addr: SocketAddr, resolvers: F) -> Self {
+		let mut config = (*config).clone();
+		config.alpn_protocols = vec![b"doq".to_vec()];
+		config.max_early_data_size = u32::MAX;
+		// Early data is only taken in sessions resumed from the server's cache, not from
+		// tickets...
+		config.ticketer = Arc::new(NoTickets);
+		Self {
+			context,
+			resolvers: Arc::new(resolvers),
+			config: Arc::new(config),
+			addr,
+			role: ServerRole::Full,
+			connections: Arc::new(AtomicUsize::new(0)),
+		}
+	}
+
+	/// Serve in `role` instead of the full one.
+	pub fn with_role(mut self, role: ServerRole) -> Self {
+		self.role = role;
+		self
+	}
+
+	/// Bind the socket and start accepting connections. The returned handle belongs to the
+	/// accepting thread, which runs for as long as the socket does.
+	pub fn run_server(self) -> Result<JoinHandle<()>> {
+		let crypto = QuicServerConfig::try_from(self.config.clone()).map_err(Error::other)?;
+		let mut transport = TransportConfig::default();
+		transport.max_concurrent_bidi_streams(VarInt::from_u32(MAX_STREAMS))
+			.max_concurrent_uni_streams(VarInt::from_u32(0))
+			.max_idle_timeout(Some(IdleTimeout::try_from(self.context.tcp_idle_timeout).map_err(Error::other)?));
+		let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+		config.transport_config(Arc::new(transport));
+
+		let runtime = Builder::new_multi_thread()
+			.thread_name("DNSQuicServer")
+			.enable_all()
+			.build()?;
+		let endpoint = {
+			let _entered = runtime.enter();
+			Endpoint::server(config, self.addr)?
+		};
+
+		let handle = thread::Builder::new()
+			.name("DNSQuicServer-incoming".to_string())
+			.spawn(move || runtime.block_on(self.accept_connections(endpoint)))?;
+
+		Ok(handle)
+	}
+
+	/// Serve every connection accepted on `endpoint` in a task of its own.
+	async fn accept_connections(self, endpoint: Endpoint) {
+		let server = Arc::new(self);
+		while let Some(incoming) = endpoint.accept().await {
+			if server.connections.fetch_add(1, Ordering::SeqCst) >= server.context.tcp_max_connections {
+				server.connections.fetch_sub(1, Ordering::SeqCst);
+				incoming.refuse();
+				continue;
+			}
+
+			let server = server.clone();
+			tokio::spawn(async move {
+				let src = incoming.remote_address();
+				if let Err(e) = server.serve_connection(incoming).await {
+					info!("QUIC connection from {} failed: {}", src, e);
+				}
+				server.connections.fetch_sub(1, Ordering::SeqCst);
+			});
+		}
+	}
+
+	/// Answer the queries of a connection until the client closes it or it sits idle for
+	/// too long.
+	async fn serve_connection(self: &Arc<Self>, incoming: quinn::Incoming) -> Result<()> {
+		let connecting = incoming.accept().map_err(Error::other)?;
+		// Tells the streams which came in early data whether the handshake has completed...
+		let (completed, handshaken) = watch::channel(false);
+		let connection = match connecting.into_0rtt() {
+			Ok((connection, handshake)) => {
+				tokio::spawn(async move {
+					handshake.await;
+					let _ = completed.send(true);
+				});
+				connection
+			}
+			Err(connecting) => {
+				let connection = connecting.await.map_err(Error::other)?;
+				let _ = completed.send(true);
+				connection
+			}
+		};
+
+		loop {
+			let (send, recv) = match connection.accept_bi().await {
+				Ok(stream) => stream,
+				Err(quinn::ConnectionError::ApplicationClosed(_)) | Err(quinn::ConnectionError::TimedOut) => return Ok(()),
+				Err(e) => return Err(Error::other(e)),
+			};
+			let server = self.clone();
+			let connection = connection.clone();
+			let handshaken = handshaken.clone();
+			tokio::spawn(async move {
+				server.answer(connection, send, recv, handshaken).await;
+			});
+		}
+	}
+
+	/// Read the query of a stream, resolve it and write its response on the same stream.
+	async fn answer(self: Arc<Self>, connection: Connection, mut send: SendStream, mut recv: RecvStream, mut handshaken: watch::Receiver<bool>) {
+		let src = connection.remote_address();
+		let message = match recv.read_to_end(MAX_STREAM_DATA).await {
+			Ok(message) => message,
+			Err(e) => {
+				info!("Failed to read QUIC query from {}: {}", src, e);
+				connection.close(VarInt::from_u32(DOQ_PROTOCOL_ERROR), b"invalid stream");
+				return;
+			}
+		};
+		let started = Instant::now();
+
+		// A stream holds exactly one message, of the length it's prefixed with, and its ID
+		// is always 0 (RFC 9250 section 4.2.1)...
+		let request = match message.get(2..) {
+			Some(raw) if u16::from_be_bytes([message[0], message[1]]) as usize == raw.len() => {
+				parse_request(&self.context, raw).ok()
+			}
+			_ => None,
+		};
+		let request = match request {
+			Some(request) if request.header.id == 0 => request,
+			_ => {
+				info!("Invalid QUIC query from {}", src);
+				connection.close(VarInt::from_u32(DOQ_PROTOCOL_ERROR), b"invalid query");
+				return;
+			}
+		};
+
+		if request.header.opcode != 0 && handshaken.wait_for(|done| *done).await.is_err() {
+			return;
+		}
+
+		let server = self.clone();
+		let resolved = tokio::task::spawn_blocking(move || {
+			let mut timing = QueryTiming::new(started);
+			let result = handle_request(&server.context, &server.resolvers, Listener { role: server.role, transport: Transport::Quic }, &request, &message[2..], src, &mut timing);
+			(request, message, result, timing)
+		}).await;
+		let (request, message, result, mut timing) = match resolved {
+			Ok(resolved) => resolved,
+			Err(e) => {
+				info!("Failed to resolve QUIC query from {}: {}", src, e);
+				let _ = send.reset(VarInt::from_u32(DOQ_INTERNAL_ERROR));
+				return;
+			}
+		};
+		let (response, res_bytes) = match result {
+			Ok(Some(response)) => response,
+			Ok(None) => {
+				let _ = send.reset(VarInt::from_u32(DOQ_REQUEST_CANCELLED));
+				return;
+			}
+			Err(e) => {
+				info!("Failed to write response to {}: {}", src, e);
+				let _ = send.reset(VarInt::from_u32(DOQ_INTERNAL_ERROR));
+				return;
+			}
+		};
+
+		let mut data = Vec::with_capacity(res_bytes.len() + 2);
+		let sent = match write_tcp_message(&mut data, &res_bytes) {
+			Ok(()) => send.write_all(&data).await.map_err(Error::other).and_then(|_| send.finish().map_err(Error::other)),
+			Err(e) => Err(e),
+		};
+		if let Err(e) = sent {
+			info!("Failed to send response to {}: {}", src, e);
+		}
+		timing.stage("send");
+		self.context.record_query(src, request.questions.first(), response.header.rescode, &timing);
+		if let Some(capture) = self.context.capture() {
+			if let Err(e) = capture.record(src, Transport::Quic, &request, &message[2..], &res_bytes) {
+				info!("Failed to capture the query from {}: {}", src, e);
+			}
+		}
+	}
+}
+
+/// Ticketer of a listener resuming sessions from its cache only.
+#[derive(Debug)]
+struct NoTickets;
+
+impl ProducesTickets for NoTickets {
+	fn enabled(&self) -> bool {
+		false
+	}
+
+	fn lifetime(&self) -> u32 {
+		0
+	}
+
+	fn encrypt(&self, _plain: &[u8]) -> Option<Vec<u8>> {
+		None
+	}
+
+	fn decrypt(&self, _cipher: &[u8]) -> Option<Vec<u8>> {
+		None
+	}
+}
@@ -0,0 +1,194 @@
+//! DNS over QUIC towards the upstreams (RFC 9250)
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr };
+use std::sync::Arc;
+use std::time::Duration;
+
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{ ClientConfig, Connection, Endpoint, VarInt };
+use tokio::runtime::{ Builder, Runtime };
+use tokio::sync::Mutex;
+
+use crate::server::buffer::VectorPacketBuffer;
+use crate::server::client::{ read_tcp_message, same_questions, write_tcp_message, DNSClient };
+use crate::server::protocol::DNSPacket;
+use crate::server::quic::{ DOQ_NO_ERROR, MAX_STREAM_DATA };
+use crate::server::tls_upstream::{ client_config, TlsPolicy };
+
+/// The QUIC side of an upstream: how it's authenticated, and the connection to it.
+///
+/// Every query is a stream of its own on a single connection, so queries from any number of
+/// threads go out at the same time. The connection is made again once the upstream closes
+/// it, sending the queries in 0-RTT data when the upstream lets the session be resumed; a
+/// query rejected as such is sent again once the handshake completes. The upstream is
+/// authenticated like over TLS, see `TlsUpstream`.
+pub struct QuicUpstream {
+	addr: SocketAddr,
+	name: Option<String>,
+	pins: Vec<[u8; 32]>,
+	policy: TlsPolicy,
+	config: ClientConfig,
+	// Drives the endpoint and its connection, and the queries while their threads wait for
+	// them...
+	runtime: Runtime,
+	endpoint: Endpoint,
+	connection: Mutex<Option<Connection>>,
+}
+
+impl QuicUpstream {
+	pub fn new(addr: SocketAddr, name: Option<String>, pins: Vec<[u8; 32]>, policy: TlsPolicy) -> Result<Self> {
+		let mut tls = (*client_config(&pins, policy, b"doq")?).clone();
+		tls.enable_early_data = true;
+		let crypto = QuicClientConfig::try_from(Arc::new(tls)).map_err(Error::other)?;
+
+		let runtime = Builder::new_multi_thread()
+			.worker_threads(1)
+			.thread_name("QuicUpstream")
+			.enable_all()
+			.build()?;
+		let local = match addr {
+			SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+			SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+		};
+		let endpoint = {
+			let _entered = runtime.enter();
+			Endpoint::client(local)?
+		};
+
+		Ok(Self {
+			addr,
+			name,
+			pins,
+			policy,
+			config: ClientConfig::new(Arc::new(crypto)),
+			runtime,
+			endpoint,
+			connection: Mutex::new(None),
+		})
+	}
+
+	pub fn name(&self) -> Option<&str> {
+		self.name.as_deref()
+	}
+
+	pub fn policy(&self) -> TlsPolicy {
+		self.policy
+	}
+
+	/// The same upstream, with another policy.
+	pub fn with_policy(&self, policy: TlsPolicy) -> Result<Self> {
+		QuicUpstream::new(self.addr, self.name.clone(), self.pins.clone(), policy)
+	}
+
+	/// Send `query` and wait for the response.
+	pub fn exchange(&self, client: &DNSClient, query: &mut DNSPacket) -> Result<DNSPacket> {
+		let mut req_buffer = VectorPacketBuffer::new();
+		query.write(&mut req_buffer)?;
+		client.retry().run(|timeout| self.send(req_buffer.as_slice(), query, timeout)).map(|(response, _)| response)
+	}
+
+	/// Send the query `message` as it is, apart from its ID, and return the response as it
+	/// was received.
+	pub fn relay(&self, client: &DNSClient, message: &[u8]) -> Result<Vec<u8>> {
+		let (query, message) = client.renumber(message)?;
+		client.retry().run(|timeout| self.send(&message, &query, timeout)).map(|(_, response)| response)
+	}
+
+	/// Send `message`, the wire format of `query`, on the open connection or a new one. The
+	/// message goes out with an ID of 0, as DNS over QUIC has it, and the response comes
+	/// back with the ID of the query.
+	fn send(&self, message: &[u8], query: &DNSPacket, timeout: Duration) -> Result<(DNSPacket, Vec<u8>)> {
+		let mut message = message.to_vec();
+		message[..2].copy_from_slice(&[0, 0]);
+
+		let mut response = self.runtime.block_on(async {
+			let sent = tokio::time::timeout(timeout, async {
+				// The query is sent again if the upstream closed the connection since the last
+				// query or turned down the early data it was sent in...
+				let connection = self.open().await?;
+				match self.query(&connection, &message).await {
+					Ok(response) => Ok(response),
+					Err(_) => {
+						let connection = self.open().await?;
+						self.query(&connection, &message).await
+					}
+				}
+			});
+			sent.await.map_err(|_| Error::new(ErrorKind::TimedOut, "DNS over QUIC query timed out"))?
+		})?;
+
+		if response.len() < 2 {
+			return Err(Error::new(ErrorKind::InvalidData, "Response too short"));
+		}
+		response[..2].copy_from_slice(&query.header.id.to_be_bytes());
+		let mut res_buffer = VectorPacketBuffer::from_bytes(response);
+		let response = DNSPacket::from_buffer(&mut res_buffer)?;
+		if !same_questions(&response.questions, &query.questions) {
+			return Err(Error::new(ErrorKind::InvalidData, "Response doesn't match the query"));
+		}
+		Ok((response, res_buffer.into_inner()))
+	}
+
+	/// Send `message` on a stream of its own and read the response.
+	async fn query(&self, connection: &Connection, message: &[u8]) -> Result<Vec<u8>> {
+		let (mut send, mut recv) = connection.open_bi().await.map_err(Error::other)?;
+		let mut data = Vec::with_capacity(message.len() + 2);
+		write_tcp_message(&mut data, message)?;
+		send.write_all(&data).await.map_err(Error::other)?;
+		send.finish().map_err(Error::other)?;
+
+		let data = recv.read_to_end(MAX_STREAM_DATA).await.map_err(Error::other)?;
+		read_tcp_message(&mut data.as_slice())
+	}
+
+	/// The open connection, or a new one when there's none or the upstream closed it.
+	/// Queries wait for the connection being made rather than all making one.
+	async fn open(&self) -> Result<Connection> {
+		let mut open = self.connection.lock().await;
+		if let Some(ref connection) = *open {
+			if connection.close_reason().is_none() {
+				return Ok(connection.clone());
+			}
+		}
+
+		let server_name = match self.name {
+			Some(ref name) => name.clone(),
+			None => self.addr.ip().to_string(),
+		};
+		let connecting = self.endpoint.connect_with(self.config.clone(), self.addr, &server_name).map_err(Error::other)?;
+		let connection = match connecting.into_0rtt() {
+			Ok((connection, _)) => connection,
+			Err(connecting) => connecting.await.map_err(Error::other)?,
+		};
+		*open = Some(connection.clone());
+		Ok(connection)
+	}
+}
+
+impl Drop for QuicUpstream {
+	fn drop(&mut self) {
+		self.endpoint.close(VarInt::from_u32(DOQ_NO_ERROR), b"");
+	}
+}
+
+impl fmt::Debug for QuicUpstream {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("QuicUpstream")
+			.field("addr", &self.addr)
+			.field("name", &self.name)
+			.field("pins", &self.pins.len())
+			.field("policy", &self.policy)
+			.finish()
+	}
+}
+
+impl PartialEq for QuicUpstream {
+	fn eq(&self, other: &Self) -> bool {
+		self.addr == other.addr && self.name == other.name && self.pins == other.pins && self.policy == other.policy
+	}
+}
+
+impl Eq for QuicUpstream {}
@@ -0,0 +1,374 @@
+//! Token buckets limiting the rate of queries per client
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::server::middleware::{ QueryHook, QueryVerdict };
+use crate::server::protocol::DNSPacket;
+
+/// Number of buckets kept, the least recently used being replaced by new ones beyond...
+const MAX_CLIENTS: usize = 10_000;
+/// End of the list of buckets...
+const NONE: usize = usize::MAX;
+/// Length of the IPv6 prefixes clients are limited by unless told otherwise, what a site
+/// usually gets...
+pub const DEFAULT_IPV6_PREFIX: u8 = 56;
+
+/// The tokens left to a client, as of `last`.
+#[derive(Debug)]
+struct Bucket {
+	tokens: f64,
+	last: Instant,
+}
+
+/// A bucket with the key it's found by, and its neighbours in the order of use.
+#[derive(Debug)]
+struct Slot {
+	key: (IpAddr, u64),
+	bucket: Bucket,
+	prev: usize,
+	next: usize,
+}
+
+/// Up to `capacity` buckets by key, linked from the most recently used to the least, which
+/// makes room for a new one when they're all taken. Finding, adding and replacing a bucket
+/// take constant time however many clients show up.
+#[derive(Debug)]
+struct BucketMap {
+	index: HashMap<(IpAddr, u64), usize>,
+	slots: Vec<Slot>,
+	head: usize,
+	tail: usize,
+	capacity: usize,
+}
+
+impl BucketMap {
+	fn new(capacity: usize) -> Self {
+		BucketMap { index: HashMap::new(), slots: Vec::new(), head: NONE, tail: NONE, capacity: capacity.max(1) }
+	}
+
+	/// The bucket of `key`, a new one from `bucket` when it has none, in place of the least
+	/// recently used bucket when the map is full.
+	fn get_or_insert(&mut self, key: (IpAddr, u64), bucket: impl FnOnce() -> Bucket) -> &mut Bucket {
+		let slot = match self.index.get(&key) {
+			Some(&slot) => {
+				self.unlink(slot);
+				slot
+			}
+			None if self.slots.len() < self.capacity => {
+				self.slots.push(Slot { key, bucket: bucket(), prev: NONE, next: NONE });
+				self.slots.len() - 1
+			}
+			None => {
+				let slot = self.tail;
+				self.unlink(slot);
+				self.index.remove(&self.slots[slot].key);
+				self.slots[slot].key = key;
+				self.slots[slot].bucket = bucket();
+				slot
+			}
+		};
+		self.index.insert(key, slot);
+		self.push_front(slot);
+		&mut self.slots[slot].bucket
+	}
+
+	fn unlink(&mut self, slot: usize) {
+		let (prev, next) = (self.slots[slot].prev, self.slots[slot].next);
+		match prev {
+			NONE => self.head = next,
+			prev => self.slots[prev].next = next,
+		}
+		match next {
+			NONE => self.tail = prev,
+			next => self.slots[next].prev = prev,
+		}
+	}
+
+	fn push_front(&mut self, slot: usize) {
+		self.slots[slot].prev = NONE;
+		self.slots[slot].next = self.head;
+		match self.head {
+			NONE => self.tail = slot,
+			head => self.slots[head].prev = slot,
+		}
+		self.head = slot;
+	}
+}
+
+/// A token bucket for each client: a bucket holds up to `burst` tokens and gets `rate` new
+/// ones a second, a query taking one. Clients with an empty bucket are over the limit.
+///
+/// Clients may be grouped by prefix, sharing a bucket, as a single IPv6 client can pick
+/// from all the addresses of its network. They may also get several buckets, told apart by
+/// a tag, say one for each distinct response. Up to `MAX_CLIENTS` buckets are kept, those
+/// of the clients heard from least recently being forgotten beyond that.
+#[derive(Debug)]
+pub struct TokenBuckets {
+	rate: f64,
+	burst: f64,
+	v4_prefix: u8,
+	v6_prefix: u8,
+	buckets: Mutex<BucketMap>,
+}
+
+impl TokenBuckets {
+	/// Allow `rate` queries a second to each client address, and bursts of `burst` queries.
+	pub fn new(rate: f64, burst: f64) -> Self {
+		TokenBuckets::by_prefix(rate, burst, 32, 128)
+	}
+
+	/// Allow `rate` queries a second, and bursts of `burst` queries, to each IPv4 network of
+	/// `v4_prefix` bits and each IPv6 network of `v6_prefix` bits.
+	pub fn by_prefix(rate: f64, burst: f64, v4_prefix: u8, v6_prefix: u8) -> Self {
+		TokenBuckets {
+			rate,
+			burst: burst.max(1.0),
+			v4_prefix: v4_prefix.min(32),
+			v6_prefix: v6_prefix.min(128),
+			buckets: Mutex::new(BucketMap::new(MAX_CLIENTS)),
+		}
+	}
+
+	pub fn rate(&self) -> f64 {
+		self.rate
+	}
+
+	pub fn burst(&self) -> f64 {
+		self.burst
+	}
+
+	/// Lengths of the IPv4 and IPv6 prefixes sharing a bucket.
+	pub fn prefixes(&self) -> (u8, u8) {
+		(self.v4_prefix, self.v6_prefix)
+	}
+
+	/// Take a token from the bucket of `client`. Returns whether there was one.
+	pub fn allow(&self, client: IpAddr) -> bool {
+		self.allow_tagged(client, 0)
+	}
+
+	/// Take a token from the bucket of `client` for `tag`. Returns whether there was one.
+	pub fn allow_tagged(&self, client: IpAddr, tag: u64) -> bool {
+		self.allow_at(client, tag, Instant::now())
+	}
+
+	fn allow_at(&self, client: IpAddr, tag: u64, now: Instant) -> bool {
+		let mut buckets = match self.buckets.lock() {
+			Ok(buckets) => buckets,
+			Err(poisoned) => poisoned.into_inner(),
+		};
+
+		let burst = self.burst;
+		let bucket = buckets.get_or_insert((self.key(client), tag), || Bucket { tokens: burst, last: now });
+		bucket.tokens = (bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * self.rate).min(self.burst);
+		bucket.last = now;
+		if bucket.tokens >= 1.0 {
+			bucket.tokens -= 1.0;
+			true
+		} else {
+			false
+		}
+	}
+
+	/// The network `client` shares its bucket with.
+	fn key(&self, client: IpAddr) -> IpAddr {
+		let client = client.to_canonical();
+		match client {
+			IpAddr::V4(_) => mask(client, self.v4_prefix),
+			IpAddr::V6(_) => mask(client, self.v6_prefix),
+		}
+	}
+}
+
+/// The first `len` bits of `addr`, the others cleared.
+fn mask(addr: IpAddr, len: u8) -> IpAddr {
+	match addr {
+		IpAddr::V4(v4) => {
+			let bits = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+			IpAddr::from((u32::from(v4) & bits).to_be_bytes())
+		}
+		IpAddr::V6(v6) => {
+			let bits = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+			IpAddr::from((u128::from(v6) & bits).to_be_bytes())
+		}
+	}
+}
+
+/// What's done with the queries of a client over its limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitAction {
+	Drop,
+	/// Answer them with an empty truncated response over UDP, for genuine clients to retry
+	/// over TCP while those spoofing their address get nothing out of it.
+	Truncate,
+}
+
+impl LimitAction {
+	pub fn from_name(name: &str) -> Option<LimitAction> {
+		match name {
+			"drop" => Some(LimitAction::Drop),
+			"truncate" => Some(LimitAction::Truncate),
+			_ => None,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			LimitAction::Drop => "drop",
+			LimitAction::Truncate => "truncate",
+		}
+	}
+}
+
+/// Keeps any single client, or network of clients, from taking up the resolver: the queries
+/// of those going over their token bucket are dropped or truncated, as a query hook.
+#[derive(Debug)]
+pub struct ClientRateLimit {
+	buckets: TokenBuckets,
+	action: LimitAction,
+	allowed: AtomicU64,
+	limited: AtomicU64,
+}
+
+impl ClientRateLimit {
+	pub fn new(buckets: TokenBuckets, action: LimitAction) -> Self {
+		ClientRateLimit { buckets, action, allowed: AtomicU64::new(0), limited: AtomicU64::new(0) }
+	}
+
+	pub fn buckets(&self) -> &TokenBuckets {
+		&self.buckets
+	}
+
+	pub fn action(&self) -> LimitAction {
+		self.action
+	}
+
+	/// Number of queries let through.
+	pub fn allowed(&self) -> u64 {
+		self.allowed.load(Ordering::Relaxed)
+	}
+
+	/// Number of queries over the limit.
+	pub fn limited(&self) -> u64 {
+		self.limited.load(Ordering::Relaxed)
+	}
+}
+
+impl QueryHook for ClientRateLimit {
+	fn name(&self) -> &str {
+		"rate-limit"
+	}
+
+	fn check_query(&self, _request: &DNSPacket, client: IpAddr) -> QueryVerdict {
+		if self.buckets.allow(client) {
+			self.allowed.fetch_add(1, Ordering::Relaxed);
+			return QueryVerdict::Pass;
+		}
+		self.limited.fetch_add(1, Ordering::Relaxed);
+		match self.action {
+			LimitAction::Drop => QueryVerdict::Drop,
+			LimitAction::Truncate => QueryVerdict::Truncate,
+		}
+	}
+
+	fn counters(&self) -> Vec<(String, u64)> {
+		vec![
+			("allowed".to_string(), self.allowed()),
+			(format!("limited ({})", self.action.name()), self.limited()),
+		]
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::Ipv4Addr;
+	use std::time::Duration;
+
+	fn client(last: u8) -> IpAddr {
+		IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))
+	}
+
+	#[test]
+	fn burst_then_refill() {
+		let buckets = TokenBuckets::new(2.0, 3.0);
+		let start = Instant::now();
+		for _ in 0..3 {
+			assert!(buckets.allow_at(client(1), 0, start));
+		}
+		assert!(!buckets.allow_at(client(1), 0, start));
+		// Other clients and tags have buckets of their own...
+		assert!(buckets.allow_at(client(2), 0, start));
+		assert!(buckets.allow_at(client(1), 1, start));
+
+		// Two tokens a second: one after half a second, and no more than the burst later...
+		assert!(!buckets.allow_at(client(1), 0, start + Duration::from_millis(400)));
+		assert!(buckets.allow_at(client(1), 0, start + Duration::from_millis(500)));
+		assert!(!buckets.allow_at(client(1), 0, start + Duration::from_millis(500)));
+		let later = start + Duration::from_secs(60);
+		for _ in 0..3 {
+			assert!(buckets.allow_at(client(1), 0, later));
+		}
+		assert!(!buckets.allow_at(client(1), 0, later));
+	}
+
+	#[test]
+	fn clients_share_the_bucket_of_their_prefix() {
+		let buckets = TokenBuckets::by_prefix(1.0, 1.0, 24, 56);
+		let now = Instant::now();
+		assert!(buckets.allow_at(client(1), 0, now));
+		assert!(!buckets.allow_at(client(2), 0, now));
+		// Mapped addresses are those they map...
+		assert!(!buckets.allow_at("::ffff:192.0.2.3".parse().unwrap(), 0, now));
+		assert!(buckets.allow_at("2001:db8:0:1::1".parse().unwrap(), 0, now));
+		assert!(!buckets.allow_at("2001:db8:0:2::1".parse().unwrap(), 0, now));
+		assert!(buckets.allow_at("2001:db8:0:100::1".parse().unwrap(), 0, now));
+	}
+
+	#[test]
+	fn least_recently_used_bucket_evicted_at_the_cap() {
+		let mut buckets = TokenBuckets::new(1.0, 1.0);
+		buckets.buckets = Mutex::new(BucketMap::new(3));
+		let now = Instant::now();
+		for last in 1..=3 {
+			assert!(buckets.allow_at(client(last), 0, now));
+		}
+		// Client 1 is used again, so client 2 is the one making room for client 4...
+		assert!(!buckets.allow_at(client(1), 0, now));
+		assert!(buckets.allow_at(client(4), 0, now));
+
+		let map = buckets.buckets.lock().unwrap();
+		assert_eq!(map.slots.len(), 3);
+		assert_eq!(map.index.len(), 3);
+		assert!(!map.index.contains_key(&(client(2), 0)));
+		drop(map);
+		assert!(!buckets.allow_at(client(1), 0, now));
+		assert!(!buckets.allow_at(client(3), 0, now));
+		assert!(!buckets.allow_at(client(4), 0, now));
+		// Forgotten, client 2 starts over with a full bucket...
+		assert!(buckets.allow_at(client(2), 0, now));
+	}
+
+	#[test]
+	fn map_never_grows_past_its_capacity() {
+		let mut map = BucketMap::new(100);
+		let now = Instant::now();
+		for n in 0..10_000u64 {
+			map.get_or_insert((client((n % 251) as u8), n), || Bucket { tokens: 1.0, last: now });
+		}
+		assert_eq!(map.slots.len(), 100);
+		assert_eq!(map.index.len(), 100);
+		// The list holds every slot once, most recent first...
+		let mut order = Vec::new();
+		let mut slot = map.head;
+		while slot != NONE {
+			order.push(map.slots[slot].key.1);
+			slot = map.slots[slot].next;
+		}
+		assert_eq!(order, (9_900..10_000u64).rev().collect::<Vec<_>>());
+	}
+}
@@ -0,0 +1,659 @@
+use std::collections::HashMap;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::{ Arc, Mutex, RwLock };
+use std::time::{ Duration, Instant };
+
+use crate::server::context::ServerContext;
+use crate::server::hints::{ root_hints, RootHints };
+use crate::server::protocol::{ DNSPacket, DNSRecord, QueryType, ResultCode, TransientTTL };
+use crate::server::servfail::{ servfail_error, ServfailReason };
+use crate::server::upstream::UpstreamPool;
+
+/// Upper limits protecting the recursor from loops and from being used for amplification...
+const MAX_REFERRALS: usize = 32;
+const MAX_DEPTH: usize = 8;
+const MAX_CNAME_CHAIN: usize = 8;
+/// Most minimised queries sent for a name before asking for the full name (RFC 9156)...
+const MAX_MINIMISE_COUNT: usize = 10;
+/// Number of name servers of a zone whose addresses are looked up when no glue was given...
+const MAX_NS_LOOKUPS: usize = 3;
+/// Port the name servers are asked on unless told otherwise...
+pub const NAME_SERVER_PORT: u16 = 53;
+
+/// Resolves a query, either by forwarding it or by iterating from the root.
+pub trait DNSResolver {
+	fn resolve(&mut self, qname: &str, q_type: QueryType, recursive: bool) -> Result<DNSPacket>;
+
+	/// The server the last response came from, if the resolver keeps track.
+	fn last_server(&self) -> Option<String> {
+		None
+	}
+}
+
+impl<R: DNSResolver + ?Sized> DNSResolver for Box<R> {
+	fn resolve(&mut self, qname: &str, q_type: QueryType, recursive: bool) -> Result<DNSPacket> {
+		(**self).resolve(qname, q_type, recursive)
+	}
+
+	fn last_server(&self) -> Option<String> {
+		(**self).last_server()
+	}
+}
+
+/// Creates the resolvers of the server. The listeners and the handler are generic over it,
+/// so an embedder picking a concrete strategy gets code specialised for its resolver, with
+/// no boxing per query; `DynamicResolvers` picks the strategy at runtime instead.
+pub trait ResolverFactory: Send + Sync + 'static {
+	type Resolver: DNSResolver;
+
+	/// A resolver for the queries of `source`, the client asking them if known.
+	fn create(&self, context: Arc<ServerContext>, source: Option<IpAddr>) -> Self::Resolver;
+}
+
+/// Resolvers following the `resolve_strategy` of the context, as configured at runtime.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DynamicResolvers;
+
+impl ResolverFactory for DynamicResolvers {
+	type Resolver = Box<dyn DNSResolver>;
+
+	fn create(&self, context: Arc<ServerContext>, source: Option<IpAddr>) -> Self::Resolver {
+		ServerContext::create_resolver(context, source)
+	}
+}
+
+/// Always resolves recursively from the root.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RecursiveResolvers;
+
+impl ResolverFactory for RecursiveResolvers {
+	type Resolver = RecursiveResolver;
+
+	fn create(&self, context: Arc<ServerContext>, _: Option<IpAddr>) -> Self::Resolver {
+		RecursiveResolver::new(context)
+	}
+}
+
+/// Always forwards to the upstreams of a pool.
+#[derive(Clone, Debug)]
+pub struct ForwardingResolvers {
+	pub upstreams: Arc<UpstreamPool>,
+}
+
+impl ResolverFactory for ForwardingResolvers {
+	type Resolver = ForwardingResolver;
+
+	fn create(&self, context: Arc<ServerContext>, source: Option<IpAddr>) -> Self::Resolver {
+		ForwardingResolver::new(context, self.upstreams.clone(), source)
+	}
+}
+
+/// Returns true if `name` equals `zone` or is below it. Both are expected lowercased.
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+	zone.is_empty() || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+/// Number of labels of `name`, 0 for the root.
+pub fn label_count(name: &str) -> usize {
+	if name.is_empty() { 0 } else { name.split('.').count() }
+}
+
+/// The ancestor of `name` made of its last `labels` labels, None if `name` is shorter.
+pub fn ancestor(name: &str, labels: usize) -> Option<&str> {
+	let total = label_count(name);
+	if labels > total {
+		return None;
+	}
+	let skip = total - labels;
+	match skip {
+		0 => Some(name),
+		_ => name.match_indices('.').nth(skip - 1).map(|(idx, _)| &name[idx + 1..]),
+	}
+}
+
+/// The name one label up from `name`, None for the root.
+pub fn parent_name(name: &str) -> Option<&str> {
+	if name.is_empty() {
+		return None;
+	}
+	Some(name.find('.').map(|idx| &name[idx + 1..]).unwrap_or(""))
+}
+
+/// Addresses of the name server `ns` found in `records`, along with the lowest of their TTLs
+/// and `ttl`.
+fn glue(records: &[DNSRecord], ns: &str, ttl: u32) -> (Vec<IpAddr>, u32) {
+	let mut glue_ttl = ttl;
+	let addrs = records.iter()
+		.filter_map(|record| match *record {
+			DNSRecord::A { ref domain, addr, ttl: TransientTTL(t) } if domain == ns => {
+				glue_ttl = glue_ttl.min(t);
+				Some(IpAddr::V4(addr))
+			}
+			DNSRecord::AAAA { ref domain, addr, ttl: TransientTTL(t) } if domain == ns => {
+				glue_ttl = glue_ttl.min(t);
+				Some(IpAddr::V6(addr))
+			}
+			_ => None,
+		})
+		.collect();
+	(addrs, glue_ttl)
+}
+// --------------------------------------------------------------------------------------------
+
+/// Sends every query on with RD set to one of a pool of upstream servers.
+pub struct ForwardingResolver {
+	context: Arc<ServerContext>,
+	upstreams: Arc<UpstreamPool>,
+	// The client the queries are resolved for, if known...
+	source: Option<IpAddr>,
+	last_upstream: Option<String>,
+}
+
+impl ForwardingResolver {
+	pub fn new(context: Arc<ServerContext>, upstreams: Arc<UpstreamPool>, source: Option<IpAddr>) -> Self {
+		Self { context, upstreams, source, last_upstream: None }
+	}
+}
+
+impl DNSResolver for ForwardingResolver {
+	fn resolve(&mut self, qname: &str, q_type: QueryType, _: bool) -> Result<DNSPacket> {
+		let mut query = self.context.client.build_query(qname, q_type, true);
+		self.context.edns_hooks.edit_query(&mut query, self.source);
+		let (mut response, upstream) = self.upstreams.exchange_with_upstream(&self.context.client, &mut query, self.source)?;
+		self.last_upstream = Some(upstream.to_string());
+		self.context.response_limits.apply(&mut response)?;
+		Ok(response)
+	}
+
+	fn last_server(&self) -> Option<String> {
+		self.last_upstream.clone()
+	}
+}
+// --------------------------------------------------------------------------------------------
+
+/// Zone cuts and name server addresses learned while following referrals.
+#[derive(Debug, Default)]
+pub struct DelegationCache {
+	// Zone to the names of its name servers...
+	zones: RwLock<HashMap<String, (Vec<String>, Instant)>>,
+	// Name server to its addresses...
+	addresses: RwLock<HashMap<String, (Vec<IpAddr>, Instant)>>,
+	// Root hints loaded from a file, replacing the compiled in ones...
+	hints: RwLock<Option<RootHints>>,
+}
+
+impl DelegationCache {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Replace the compiled in root hints, e.g. with ones loaded from a hints file.
+	pub fn set_root_hints(&self, hints: RootHints) {
+		if let Ok(mut current) = self.hints.write() {
+			*current = Some(hints);
+		}
+	}
+
+	/// The root hints in use, the compiled in ones unless others were set.
+	pub fn root_hints(&self) -> RootHints {
+		match self.hints.read().ok().and_then(|hints| hints.clone()) {
+			Some(hints) => hints,
+			None => root_hints(),
+		}
+	}
+
+	/// Name servers of `zone`, falling back to the root hints for the root zone.
+	pub fn nameservers(&self, zone: &str) -> Option<Vec<String>> {
+		let zones = self.zones.read().ok()?;
+		match zones.get(zone) {
+			Some((names, expires)) if *expires > Instant::now() => Some(names.clone()),
+			_ if zone.is_empty() => Some(self.root_hints().into_iter().map(|(name, _)| name).collect()),
+			_ => None,
+		}
+	}
+
+	/// Addresses of the name server `name`, with the root hints always known.
+	pub fn addresses(&self, name: &str) -> Vec<IpAddr> {
+		if let Ok(addresses) = self.addresses.read() {
+			if let Some((addrs, expires)) = addresses.get(name) {
+				if *expires > Instant::now() {
+					return addrs.clone();
+				}
+			}
+		}
+		self.root_hints().into_iter()
+			.find(|(hint, _)| hint == name)
+			.map(|(_, addrs)| addrs)
+			.unwrap_or_default()
+	}
+
+	/// The deepest known zone cut `qname` falls under.
+	pub fn closest_zone(&self, qname: &str) -> String {
+		let mut name = qname;
+		loop {
+			if self.nameservers(name).is_some() {
+				return name.to_string();
+			}
+			name = match parent_name(name) {
+				Some(parent) => parent,
+				None => return String::new(),
+			};
+		}
+	}
+
+	pub fn insert_zone(&self, zone: &str, nameservers: Vec<String>, ttl: u32) {
+		if let Ok(mut zones) = self.zones.write() {
+			let expires = Instant::now() + Duration::from_secs(ttl as u64);
+			zones.insert(zone.to_string(), (nameservers, expires));
+		}
+	}
+
+	pub fn insert_addresses(&self, name: &str, addrs: Vec<IpAddr>, ttl: u32) {
+		if addrs.is_empty() {
+			return;
+		}
+		if let Ok(mut addresses) = self.addresses.write() {
+			let expires = Instant::now() + Duration::from_secs(ttl as u64);
+			addresses.insert(name.to_string(), (addrs, expires));
+		}
+	}
+}
+// --------------------------------------------------------------------------------------------
+
+/// Full iterative resolution: starts from the root hints and follows referrals downward,
+/// without relying on any upstream resolver.
+pub struct RecursiveResolver {
+	context: Arc<ServerContext>,
+	port: u16,
+	// The name server which gave the last response...
+	last_server: Mutex<Option<SocketAddr>>,
+}
+
+impl RecursiveResolver {
+	pub fn new(context: Arc<ServerContext>) -> Self {
+		Self { context, port: NAME_SERVER_PORT, last_server: Mutex::new(None) }
+	}
+
+	/// Ask the name servers on `port` instead of `NAME_SERVER_PORT`.
+	pub fn with_port(mut self, port: u16) -> Self {
+		self.port = port;
+		self
+	}
+
+	/// Priming query (RFC 8109): ask the root hint servers for the current root NS set and
+	/// cache it along with the addresses in the additional section. Returns the number of
+	/// root servers learned.
+	pub fn prime(&self) -> Result<usize> {
+		let delegations = &self.context.delegations;
+		let mut servers: Vec<IpAddr> = delegations.root_hints().into_iter().flat_map(|(_, addrs)| addrs).collect();
+		servers.sort_by_key(|addr| addr.is_ipv6());
+
+		let response = servers.iter()
+			.map(|addr| SocketAddr::new(*addr, self.port))
+			.find_map(|server| {
+				self.context.client.send_query("", QueryType::NS, server, false).ok()
+					.filter(|response| response.header.rescode == ResultCode::NOERROR)
+			})
+			.ok_or_else(|| Error::other("No root server answered the priming query"))?;
+
+		let mut nameservers = Vec::new();
+		let mut ttl = u32::MAX;
+		for record in &response.answers {
+			if let DNSRecord::NS { ref domain, ref host, ttl: TransientTTL(ns_ttl) } = *record {
+				if domain.is_empty() {
+					nameservers.push(host.to_lowercase());
+					ttl = ttl.min(ns_ttl);
+				}
+			}
+		}
+		if nameservers.is_empty() {
+			return Err(Error::new(ErrorKind::InvalidData, "Priming response holds no root NS records"));
+		}
+
+		for ns in &nameservers {
+			let (addrs, addr_ttl) = glue(&response.additional, ns, ttl);
+			delegations.insert_addresses(ns, addrs, addr_ttl);
+		}
+
+		let count = nameservers.len();
+		delegations.insert_zone("", nameservers, ttl);
+		Ok(count)
+	}
+
+	/// Resolve `qname`, following CNAMEs when the target wasn't answered in the same response.
+	fn resolve_iterative(&self, qname: &str, q_type: QueryType, depth: usize) -> Result<DNSPacket> {
+		if depth > MAX_DEPTH {
+			return Err(servfail_error(ErrorKind::Other, ServfailReason::BudgetExceeded, format!("Recursion depth exceeded resolving {}", qname)));
+		}
+
+		let mut qname = qname.trim_end_matches('.').to_lowercase();
+		let mut chain = Vec::new();
+
+		for _ in 0..MAX_CNAME_CHAIN {
+			let mut response = self.resolve_name(&qname, q_type, depth)?;
+
+			if q_type != QueryType::CNAME && response.header.rescode == ResultCode::NOERROR {
+				if let Some(target) = pending_cname(&response, &qname, q_type) {
+					chain.append(&mut response.answers);
+					qname = target;
+					continue;
+				}
+			}
+
+			chain.append(&mut response.answers);
+			response.answers = chain;
+			return Ok(response);
+		}
+
+		Err(servfail_error(ErrorKind::Other, ServfailReason::BudgetExceeded, format!("CNAME chain too long resolving {}", qname)))
+	}
+
+	/// Walk down the delegation tree until a server answers `qname` authoritatively.
+	///
+	/// With QNAME minimisation (RFC 7816) each server is only asked for the NS records of the
+	/// name one label below the zone it serves, so the full name is only sent to the servers
+	/// of the zone holding it. Servers failing the minimised queries get the full name.
+	fn resolve_name(&self, qname: &str, q_type: QueryType, depth: usize) -> Result<DNSPacket> {
+		let delegations = &self.context.delegations;
+		let mut zone = delegations.closest_zone(qname);
+		let mut minimise = self.context.qname_minimization;
+		let mut minimised_count = 0;
+		// Number of labels of the name sent in the next minimised query...
+		let mut labels = label_count(&zone) + 1;
+
+		for _ in 0..(MAX_REFERRALS + MAX_MINIMISE_COUNT) {
+			let servers = self.server_addresses(&zone, depth);
+			if servers.is_empty() {
+				return Err(Error::other(format!("No reachable name server for zone {:?}", zone)));
+			}
+
+			let name = match ancestor(qname, labels) {
+				Some(name) if minimise && minimised_count < MAX_MINIMISE_COUNT => name,
+				_ => qname,
+			};
+			let minimised = name != qname;
+			let send_type = if minimised { QueryType::NS } else { q_type };
+
+			let response = servers.iter()
+				.filter_map(|server| {
+					let mut query = self.context.client.build_query(name, send_type, false);
+					self.context.edns_hooks.edit_query(&mut query, None);
+					self.context.client.exchange(&mut query, *server).ok().map(|r| (*server, r))
+				})
+				.find(|(_, r)| r.header.rescode != ResultCode::SERVFAIL && r.header.rescode != ResultCode::REFUSED);
+			let mut response = match response {
+				Some((server, response)) => {
+					if let Ok(mut last_server) = self.last_server.lock() {
+						*last_server = Some(server);
+					}
+					response
+				}
+				None if minimised => {
+					minimise = false;
+					continue;
+				}
+				None => return Err(Error::new(ErrorKind::TimedOut, format!("No name server of {:?} answered", zone))),
+			};
+
+			if minimised {
+				minimised_count += 1;
+				// Nothing exists below a name which doesn't exist (RFC 8020)...
+				if response.header.rescode == ResultCode::NXDOMAIN {
+					return Ok(response);
+				}
+				if let Some(next_zone) = referral(&response, &zone, qname) {
+					self.store_referral(&response.authorities, &response.additional, &zone, &next_zone);
+					labels = label_count(&next_zone) + 1;
+					zone = next_zone;
+					continue;
+				}
+				// An NS answer means the name is a zone served by the same servers...
+				let is_cut = response.answers.iter()
+					.any(|record| matches!(*record, DNSRecord::NS { ref domain, .. } if domain == name));
+				if is_cut {
+					self.store_referral(&response.answers, &response.additional, &zone, name);
+					zone = name.to_string();
+				}
+				labels += 1;
+				continue;
+			}
+
+			response.answers = answers_in_zone(response.answers, qname, &zone);
+			if !response.answers.is_empty() || response.header.rescode == ResultCode::NXDOMAIN {
+				return Ok(response);
+			}
+
+			match referral(&response, &zone, qname) {
+				Some(next_zone) => {
+					self.store_referral(&response.authorities, &response.additional, &zone, &next_zone);
+					zone = next_zone;
+				}
+				// No data, or a lame referral which doesn't get us any closer...
+				None => return Ok(response),
+			}
+		}
+
+		Err(servfail_error(ErrorKind::Other, ServfailReason::BudgetExceeded, format!("Too many referrals resolving {}", qname)))
+	}
+
+	/// Remember the name servers of `next_zone` found in `records` and their glue. Glue is
+	/// only accepted when it lies within `zone`, the zone of the server which sent it;
+	/// addresses of out of bailiwick name servers are looked up separately.
+	fn store_referral(&self, records: &[DNSRecord], additional: &[DNSRecord], zone: &str, next_zone: &str) {
+		let delegations = &self.context.delegations;
+
+		let mut nameservers = Vec::new();
+		let mut ttl = u32::MAX;
+		for record in records {
+			if let DNSRecord::NS { ref domain, ref host, ttl: TransientTTL(ns_ttl) } = *record {
+				if domain == next_zone {
+					nameservers.push(host.to_lowercase());
+					ttl = ttl.min(ns_ttl);
+				}
+			}
+		}
+
+		for ns in &nameservers {
+			if !is_subdomain(ns, zone) {
+				continue;
+			}
+			let (addrs, glue_ttl) = glue(additional, ns, ttl);
+			delegations.insert_addresses(ns, addrs, glue_ttl);
+		}
+
+		delegations.insert_zone(next_zone, nameservers, ttl);
+	}
+
+	/// Addresses to send queries for `zone` to, resolving name server addresses which aren't
+	/// known yet. IPv4 addresses are tried first.
+	fn server_addresses(&self, zone: &str, depth: usize) -> Vec<SocketAddr> {
+		let delegations = &self.context.delegations;
+		let nameservers = delegations.nameservers(zone).unwrap_or_default();
+
+		let mut addrs: Vec<IpAddr> = nameservers.iter().flat_map(|ns| delegations.addresses(ns)).collect();
+		if addrs.is_empty() {
+			for ns in nameservers.iter().take(MAX_NS_LOOKUPS) {
+				// A name server inside the zone without glue can't be resolved through the zone
+				// itself...
+				if is_subdomain(ns, zone) && !zone.is_empty() {
+					continue;
+				}
+				if let Ok(response) = self.resolve_iterative(ns, QueryType::A, depth + 1) {
+					let found: Vec<IpAddr> = response.answers.iter()
+						.filter_map(|record| match *record {
+							DNSRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+							_ => None,
+						})
+						.collect();
+					let ttl = response.answers.iter().map(DNSRecord::get_ttl).min().unwrap_or(0);
+					delegations.insert_addresses(ns, found.clone(), ttl);
+					addrs.extend(found);
+				}
+				if !addrs.is_empty() {
+					break;
+				}
+			}
+		}
+
+		addrs.sort_by_key(|addr| addr.is_ipv6());
+		addrs.into_iter().map(|addr| SocketAddr::new(addr, self.port)).collect()
+	}
+}
+
+impl DNSResolver for RecursiveResolver {
+	fn resolve(&mut self, qname: &str, q_type: QueryType, _: bool) -> Result<DNSPacket> {
+		let mut response = self.resolve_iterative(qname, q_type, 0)?;
+		self.context.response_limits.apply(&mut response)?;
+		Ok(response)
+	}
+
+	fn last_server(&self) -> Option<String> {
+		self.last_server.lock().ok()?.map(|server| server.to_string())
+	}
+}
+
+/// If the response to a referral carries NS records for a zone strictly between `zone` and
+/// `qname`, returns that zone.
+pub fn referral(response: &DNSPacket, zone: &str, qname: &str) -> Option<String> {
+	response.authorities.iter()
+		.filter_map(|record| match *record {
+			DNSRecord::NS { ref domain, .. } => Some(domain.to_lowercase()),
+			_ => None,
+		})
+		.find(|domain| domain != zone && is_subdomain(domain, zone) && is_subdomain(qname, domain))
+}
+
+/// The records of `answers` which answer `qname` from a server of `zone`: those of `qname`
+/// and of the CNAMEs it leads to, as far as the chain stays within `zone`. Records of other
+/// names, or of names the server isn't authoritative for, aren't to be trusted; the part of
+/// the chain outside `zone` is resolved from its own servers.
+fn answers_in_zone(mut answers: Vec<DNSRecord>, qname: &str, zone: &str) -> Vec<DNSRecord> {
+	let mut kept = Vec::new();
+	let mut name = qname.to_lowercase();
+	// Bounded in case the chain loops...
+	for _ in 0..MAX_CNAME_CHAIN {
+		if !is_subdomain(&name, zone) {
+			break;
+		}
+		let (owned, rest): (Vec<DNSRecord>, Vec<DNSRecord>) = answers.into_iter()
+			.partition(|record| record.get_domain().is_some_and(|domain| domain.eq_ignore_ascii_case(&name)));
+		answers = rest;
+		let target = owned.iter().find_map(|record| match *record {
+			DNSRecord::CNAME { ref host, .. } => Some(host.to_lowercase()),
+			_ => None,
+		});
+		kept.extend(owned);
+		match target {
+			Some(target) => name = target,
+			None => break,
+		}
+	}
+	kept
+}
+
+/// If the answer to `qname` is only a CNAME, returns its target.
+fn pending_cname(response: &DNSPacket, qname: &str, q_type: QueryType) -> Option<String> {
+	let mut name = qname.to_string();
+	// Follow the chain as far as the response itself goes, bounded in case it loops...
+	for _ in 0..response.answers.len() {
+		match response.answers.iter().find_map(|record| match *record {
+			DNSRecord::CNAME { ref domain, ref host, .. } if *domain == name => Some(host.to_lowercase()),
+			_ => None,
+		}) {
+			Some(target) => name = target,
+			None => break,
+		}
+	}
+
+	let answered = response.answers.iter()
+		.any(|record| record.get_query_type() == q_type && record.get_domain().as_deref() == Some(name.as_str()));
+	if name != qname && !answered {
+		Some(name)
+	} else {
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::{ Ipv4Addr, UdpSocket };
+	use std::thread;
+
+	use super::*;
+	use crate::server::buffer::VectorPacketBuffer;
+
+	const STUB_NS: &str = "ns.stub.test";
+	const ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+	const TARGET_ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 2);
+	const FORGED_ADDR: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 66);
+
+	fn a(domain: &str, addr: Ipv4Addr) -> DNSRecord {
+		DNSRecord::A { domain: domain.to_string(), addr, ttl: TransientTTL(300) }
+	}
+
+	/// The records a name server of example.com and example.org answers with, along with
+	/// records it has no business giving.
+	fn stub_answers(qname: &str) -> Vec<DNSRecord> {
+		match qname {
+			"www.example.com" => vec![
+				a("www.example.com", ADDR),
+				a("other.example.com", FORGED_ADDR),
+				a("www.example.net", FORGED_ADDR),
+			],
+			"alias.example.com" => vec![
+				DNSRecord::CNAME { domain: "alias.example.com".to_string(), host: "www.example.org".to_string(), ttl: TransientTTL(300) },
+				a("www.example.org", FORGED_ADDR),
+			],
+			"www.example.org" => vec![a("www.example.org", TARGET_ADDR)],
+			_ => Vec::new(),
+		}
+	}
+
+	/// Answer the queries sent to a socket of its own with `stub_answers`, authoritatively.
+	fn stub_name_server() -> u16 {
+		let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+		let port = socket.local_addr().unwrap().port();
+		thread::spawn(move || {
+			let mut buf = [0; 512];
+			while let Ok((len, src)) = socket.recv_from(&mut buf) {
+				let mut query = match DNSPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(buf[..len].to_vec())) {
+					Ok(query) => query,
+					Err(_) => continue,
+				};
+				let qname = query.questions[0].name.to_lowercase();
+				query.header.response = true;
+				query.header.authoritative_answer = true;
+				query.answers = stub_answers(&qname);
+				let mut response = VectorPacketBuffer::new();
+				query.write(&mut response).unwrap();
+				let _ = socket.send_to(response.as_slice(), src);
+			}
+		});
+		port
+	}
+
+	fn resolver() -> RecursiveResolver {
+		let mut context = ServerContext::new();
+		context.qname_minimization = false;
+		for zone in ["example.com", "example.org"] {
+			context.delegations.insert_zone(zone, vec![STUB_NS.to_string()], 3600);
+		}
+		context.delegations.insert_addresses(STUB_NS, vec![IpAddr::V4(Ipv4Addr::LOCALHOST)], 3600);
+		RecursiveResolver::new(Arc::new(context)).with_port(stub_name_server())
+	}
+
+	#[test]
+	fn records_off_the_question_are_left_out() {
+		let response = resolver().resolve_iterative("www.example.com", QueryType::A, 0).unwrap();
+		assert_eq!(response.answers, vec![a("www.example.com", ADDR)]);
+	}
+
+	#[test]
+	fn cname_target_outside_the_zone_is_asked_of_its_own_servers() {
+		let response = resolver().resolve_iterative("alias.example.com", QueryType::A, 0).unwrap();
+		assert_eq!(response.answers.len(), 2);
+		assert!(matches!(response.answers[0], DNSRecord::CNAME { ref host, .. } if host == "www.example.org"));
+		assert_eq!(response.answers[1], a("www.example.org", TARGET_ADDR));
+	}
+}
@@ -0,0 +1,164 @@
+//! How exchanges with other servers are retried: the tries made, the time each of them and all
+//! of them together get, and the wait between two tries
+
+use std::io::{ Error, ErrorKind, Result };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use rand::Rng;
+
+use crate::server::client::DEFAULT_TIMEOUT;
+
+/// Wait before the second try unless told otherwise, doubling after every try...
+pub const DEFAULT_BACKOFF: Duration = Duration::from_millis(100);
+/// Longest wait between two tries...
+pub const MAX_BACKOFF: Duration = Duration::from_secs(2);
+/// Shortest time worth giving a try, closer to the deadline no try is made...
+const MIN_TRY: Duration = Duration::from_millis(1);
+
+/// What an exchange with a server is allowed: `attempts` tries, each waiting up to `timeout`
+/// for the response, all of them within `deadline` when given. Between two tries the wait
+/// starts at `backoff` and doubles every time up to `max_backoff`, only a random part of it
+/// being waited (from half to all of it), so the clients of a server that failed don't all
+/// come back at once.
+///
+/// Only failures a new try may get past are retried: timeouts, and connections refused, reset
+/// or closed. A response that doesn't parse, or a server answering with an error, isn't.
+///
+/// ```
+/// use std::time::Duration;
+/// use rdns::server::client::DNSClient;
+/// use rdns::server::retry::RetryPolicy;
+///
+/// let policy = RetryPolicy::new()
+///     .with_attempts(3)
+///     .with_timeout(Duration::from_millis(800))
+///     .with_deadline(Some(Duration::from_secs(2)));
+/// let client = DNSClient::with_retry(policy);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+	/// Tries of an exchange, the first one included, at least 1...
+	pub attempts: u32,
+	/// Time to wait for the response to a try...
+	pub timeout: Duration,
+	/// Time all the tries of an exchange get together, the waits between them included...
+	pub deadline: Option<Duration>,
+	pub backoff: Duration,
+	pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+	/// A single try of `DEFAULT_TIMEOUT`, the servers forwarding to a pool of upstreams
+	/// trying the next upstream rather than the same one again.
+	fn default() -> Self {
+		RetryPolicy {
+			attempts: 1,
+			timeout: DEFAULT_TIMEOUT,
+			deadline: None,
+			backoff: DEFAULT_BACKOFF,
+			max_backoff: MAX_BACKOFF,
+		}
+	}
+}
+
+impl RetryPolicy {
+	pub fn new() -> Self {
+		RetryPolicy::default()
+	}
+
+	pub fn with_attempts(mut self, attempts: u32) -> Self {
+		self.attempts = attempts.max(1);
+		self
+	}
+
+	pub fn with_timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = timeout;
+		self
+	}
+
+	pub fn with_deadline(mut self, deadline: Option<Duration>) -> Self {
+		self.deadline = deadline;
+		self
+	}
+
+	/// Wait `backoff` before the second try, twice as long before the third one, and so on up
+	/// to `max_backoff`.
+	pub fn with_backoff(mut self, backoff: Duration, max_backoff: Duration) -> Self {
+		self.backoff = backoff;
+		self.max_backoff = max_backoff.max(backoff);
+		self
+	}
+
+	/// The tries of an exchange starting now.
+	pub fn start(&self) -> Tries {
+		Tries { policy: *self, started: Instant::now(), made: 0 }
+	}
+
+	/// Run `exchange` until it succeeds, fails in a way another try won't fix, or the policy
+	/// allows no more tries, handing it the time the try gets. Returns the last failure when
+	/// no try succeeded.
+	pub fn run<T, F>(&self, mut exchange: F) -> Result<T>
+		where F: FnMut(Duration) -> Result<T>
+	{
+		let mut tries = self.start();
+		let mut last_err = None;
+		while let Some((wait, timeout)) = tries.next_try() {
+			thread::sleep(wait);
+			match exchange(timeout) {
+				Ok(response) => return Ok(response),
+				Err(e) if is_retryable(&e) => last_err = Some(e),
+				Err(e) => return Err(e),
+			}
+		}
+		Err(last_err.unwrap_or_else(|| Error::new(ErrorKind::TimedOut, "Retry deadline passed")))
+	}
+
+	/// The wait before try `made + 1`, once `made` tries failed: the backoff doubled for every
+	/// try after the first, with jitter.
+	fn wait(&self, made: u32) -> Duration {
+		if made == 0 {
+			return Duration::ZERO;
+		}
+		let backoff = self.backoff.saturating_mul(1 << (made - 1).min(16)).min(self.max_backoff);
+		let half = backoff / 2;
+		half + half.mul_f64(rand::thread_rng().gen::<f64>())
+	}
+}
+
+/// The tries made so far of an exchange, for exchanges running the tries themselves rather
+/// than through `RetryPolicy::run`, say async ones.
+#[derive(Debug)]
+pub struct Tries {
+	policy: RetryPolicy,
+	started: Instant,
+	made: u32,
+}
+
+impl Tries {
+	/// The wait before the next try and the time that try gets, None once the tries are used
+	/// up or the deadline is too close for another one.
+	pub fn next_try(&mut self) -> Option<(Duration, Duration)> {
+		if self.made >= self.policy.attempts {
+			return None;
+		}
+		let wait = self.policy.wait(self.made);
+		let timeout = match self.policy.deadline {
+			Some(deadline) => {
+				let left = deadline.checked_sub(self.started.elapsed() + wait).filter(|left| *left >= MIN_TRY)?;
+				self.policy.timeout.min(left)
+			}
+			None => self.policy.timeout,
+		};
+		self.made += 1;
+		Some((wait, timeout))
+	}
+}
+
+/// Whether another try may get past `e`: a timeout, or a connection refused, reset or
+/// closed early.
+pub fn is_retryable(e: &Error) -> bool {
+	matches!(e.kind(),
+		ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted | ErrorKind::ConnectionRefused
+		| ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof)
+}
@@ -0,0 +1,195 @@
+//! Rotating the order of the addresses answered, for naive load distribution
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::IpAddr;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Mutex;
+
+use rand::Rng;
+
+use crate::server::protocol::{ DNSPacket, DNSRecord, QueryType };
+use crate::server::resolve::is_subdomain;
+
+/// How the addresses of an RRset are ordered in the answers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RotationMode {
+	/// Each answer starts one address further than the previous one, cycling through them.
+	RoundRobin,
+	/// The addresses are shuffled, each coming first in proportion to its weight.
+	Weighted,
+}
+
+impl RotationMode {
+	pub fn from_name(name: &str) -> Option<RotationMode> {
+		match name {
+			"round-robin" => Some(RotationMode::RoundRobin),
+			"weighted" => Some(RotationMode::Weighted),
+			_ => None,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			RotationMode::RoundRobin => "round-robin",
+			RotationMode::Weighted => "weighted",
+		}
+	}
+}
+
+impl fmt::Display for RotationMode {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.name())
+	}
+}
+
+/// The order of the A and AAAA records answered from the local zones, for the clients, which
+/// mostly connect to the first address, to spread over the endpoints of a service. Zones
+/// are given a mode each, the closest enclosing one applying to a name; the answers for the
+/// names of the other zones keep the order of the zone.
+///
+/// Addresses are weighted 1 unless given a weight, as `NAME ADDR WEIGHT`, which only the
+/// weighted mode heeds: an address of weight 3 comes first three times as often as one of
+/// weight 1.
+#[derive(Debug, Default)]
+pub struct AnswerRotation {
+	zones: Vec<(String, RotationMode)>,
+	weights: HashMap<(String, IpAddr), u32>,
+	// Number of answers given for every RRset rotated round-robin...
+	turns: Mutex<HashMap<(String, QueryType), u64>>,
+	rotated: AtomicU64,
+}
+
+impl AnswerRotation {
+	pub fn new() -> Self {
+		AnswerRotation::default()
+	}
+
+	/// Rotate the answers for the names of the zone given as `ZONE[:MODE]`, round-robin
+	/// unless the mode is given.
+	pub fn add_zone(&mut self, spec: &str) -> Result<()> {
+		let (zone, mode) = match spec.split_once(':') {
+			Some((zone, mode)) => (zone, RotationMode::from_name(mode)
+				.ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Unknown rotation mode: {}", mode)))?),
+			None => (spec, RotationMode::RoundRobin),
+		};
+		let zone = normalize(zone);
+		self.zones.retain(|(known, _)| *known != zone);
+		self.zones.push((zone, mode));
+		Ok(())
+	}
+
+	/// Weigh the address of a name, given as `NAME ADDR WEIGHT`.
+	pub fn add_weight(&mut self, spec: &str) -> Result<()> {
+		let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid answer weight: {}", spec));
+		let fields: Vec<&str> = spec.split_whitespace().collect();
+		let (name, addr, weight) = match fields[..] {
+			[name, addr, weight] => (name, addr, weight),
+			_ => return Err(invalid()),
+		};
+		let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+		let weight = weight.parse::<u32>().ok().filter(|weight| *weight > 0).ok_or_else(invalid)?;
+		self.weights.insert((normalize(name), addr), weight);
+		Ok(())
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.zones.is_empty()
+	}
+
+	/// The zones rotated, with their mode.
+	pub fn zones(&self) -> &[(String, RotationMode)] {
+		&self.zones
+	}
+
+	/// Number of addresses weighted.
+	pub fn weights(&self) -> usize {
+		self.weights.len()
+	}
+
+	/// Number of answers reordered.
+	pub fn rotated(&self) -> u64 {
+		self.rotated.load(Ordering::Relaxed)
+	}
+
+	/// The mode of the closest zone rotated enclosing `name`, if any.
+	fn mode(&self, name: &str) -> Option<RotationMode> {
+		self.zones.iter()
+			.filter(|(zone, _)| is_subdomain(name, zone))
+			.max_by_key(|(zone, _)| zone.len())
+			.map(|(_, mode)| *mode)
+	}
+
+	/// Reorder the addresses of every RRset of several A or AAAA records in the answers of
+	/// `packet` whose name is in a zone rotated. The other records stay where they are.
+	pub fn apply(&self, packet: &mut DNSPacket) {
+		if self.zones.is_empty() {
+			return;
+		}
+		let mut start = 0;
+		while start < packet.answers.len() {
+			let key = rrset_key(&packet.answers[start]);
+			let end = start + packet.answers[start..].iter().take_while(|record| rrset_key(record) == key).count();
+			if let Some((name, q_type)) = key {
+				if end - start > 1 {
+					if let Some(mode) = self.mode(&name) {
+						self.reorder(&mut packet.answers[start..end], name, q_type, mode);
+					}
+				}
+			}
+			start = end.max(start + 1);
+		}
+	}
+
+	fn reorder(&self, rrset: &mut [DNSRecord], name: String, q_type: QueryType, mode: RotationMode) {
+		match mode {
+			RotationMode::RoundRobin => {
+				let mut turns = self.turns.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+				let turn = turns.entry((name, q_type)).or_insert(0);
+				rrset.rotate_left((*turn % rrset.len() as u64) as usize);
+				*turn = turn.wrapping_add(1);
+			}
+			RotationMode::Weighted => {
+				// Sorting by u^(1/weight) for u uniform in (0, 1) draws the addresses one after
+				// the other in proportion to their weights (Efraimidis and Spirakis)...
+				let mut rng = rand::thread_rng();
+				let mut keyed: Vec<(f64, DNSRecord)> = rrset.iter()
+					.map(|record| {
+						let weight = address(record)
+							.and_then(|addr| self.weights.get(&(name.clone(), addr)))
+							.copied()
+							.unwrap_or(1);
+						(rng.gen::<f64>().powf(1.0 / weight as f64), record.clone())
+					})
+					.collect();
+				keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+				for (slot, (_, record)) in rrset.iter_mut().zip(keyed) {
+					*slot = record;
+				}
+			}
+		}
+		self.rotated.fetch_add(1, Ordering::Relaxed);
+	}
+}
+
+fn normalize(name: &str) -> String {
+	name.trim_end_matches('.').to_lowercase()
+}
+
+/// The name and type of an A or AAAA record, None for the other records.
+fn rrset_key(record: &DNSRecord) -> Option<(String, QueryType)> {
+	match *record {
+		DNSRecord::A { ref domain, .. } => Some((normalize(domain), QueryType::A)),
+		DNSRecord::AAAA { ref domain, .. } => Some((normalize(domain), QueryType::AAAA)),
+		_ => None,
+	}
+}
+
+fn address(record: &DNSRecord) -> Option<IpAddr> {
+	match *record {
+		DNSRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+		DNSRecord::AAAA { addr, .. } => Some(IpAddr::V6(addr)),
+		_ => None,
+	}
+}
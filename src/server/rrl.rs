@@ -0,0 +1,177 @@
+//! Response rate limiting of the authoritative answers sent over UDP
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use std::net::IpAddr;
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use crate::server::protocol::{ DNSPacket, DNSRecord, ResultCode };
+use crate::server::ratelimit::TokenBuckets;
+
+/// Responses over the limit of which one is slipped unless told otherwise...
+pub const DEFAULT_SLIP: u32 = 2;
+
+/// What's done with a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RrlVerdict {
+	Send,
+	/// Send an empty truncated response in its place, for a genuine client to retry over TCP.
+	Slip,
+	Drop,
+}
+
+/// Response rate limiting (as BIND does it), keeping the authoritative server from being an
+/// efficient reflector for spoofed queries: each client network, /24 for IPv4 and /56 for
+/// IPv6, gets `rate` identical responses a second. Responses are identical when they answer
+/// the same name and type with the same code; NXDOMAIN and NODATA responses are told apart
+/// by zone only, so floods of random names share a limit, and errors by code only.
+///
+/// Of the responses over the limit, one in `slip` is slipped, sent truncated, so clients
+/// whose address is genuine retry over TCP, and the others are dropped (with a slip of 0 all
+/// are dropped, with 1 all are slipped).
+#[derive(Debug)]
+pub struct ResponseRateLimit {
+	buckets: TokenBuckets,
+	slip: u32,
+	limited: AtomicU64,
+	slipped: AtomicU64,
+}
+
+impl ResponseRateLimit {
+	/// Allow `rate` identical responses a second to each network, slipping one in `slip` of
+	/// the others.
+	pub fn new(rate: f64, slip: u32) -> Self {
+		ResponseRateLimit {
+			buckets: TokenBuckets::by_prefix(rate, rate, 24, 56),
+			slip,
+			limited: AtomicU64::new(0),
+			slipped: AtomicU64::new(0),
+		}
+	}
+
+	pub fn rate(&self) -> f64 {
+		self.buckets.rate()
+	}
+
+	pub fn slip(&self) -> u32 {
+		self.slip
+	}
+
+	/// Number of responses over the limit, slipped or dropped.
+	pub fn limited(&self) -> u64 {
+		self.limited.load(Ordering::Relaxed)
+	}
+
+	/// Number of responses slipped.
+	pub fn slipped(&self) -> u64 {
+		self.slipped.load(Ordering::Relaxed)
+	}
+
+	/// Account `response` to `client`, and tell what to do with it.
+	pub fn check(&self, client: IpAddr, response: &DNSPacket) -> RrlVerdict {
+		if self.buckets.allow_tagged(client, identity(response)) {
+			return RrlVerdict::Send;
+		}
+		let limited = self.limited.fetch_add(1, Ordering::Relaxed);
+		if self.slip > 0 && limited.is_multiple_of(self.slip as u64) {
+			self.slipped.fetch_add(1, Ordering::Relaxed);
+			RrlVerdict::Slip
+		} else {
+			RrlVerdict::Drop
+		}
+	}
+}
+
+/// What tells `response` apart from others for the limits.
+fn identity(response: &DNSPacket) -> u64 {
+	let mut hasher = DefaultHasher::new();
+	let rescode = response.header.rescode;
+	(rescode as u8).hash(&mut hasher);
+	match rescode {
+		ResultCode::NOERROR if !response.answers.is_empty() => {
+			if let Some(question) = response.questions.first() {
+				question.name.to_lowercase().hash(&mut hasher);
+				question.q_type.to_num().hash(&mut hasher);
+			}
+		}
+		ResultCode::NOERROR | ResultCode::NXDOMAIN => {
+			// The zone, going by the SOA of a negative answer or the NS records of a referral...
+			let zone = response.authorities.iter().find_map(|record| match record {
+				DNSRecord::SOA { domain, .. } | DNSRecord::NS { domain, .. } => Some(domain.to_lowercase()),
+				_ => None,
+			});
+			zone.hash(&mut hasher);
+		}
+		_ => (),
+	}
+	hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::Ipv4Addr;
+
+	use crate::server::protocol::{ DNSQuestion, QueryType, TransientTTL };
+
+	fn answer(name: &str) -> DNSPacket {
+		let mut packet = DNSPacket::new();
+		packet.header.response = true;
+		packet.header.authoritative_answer = true;
+		packet.questions.push(DNSQuestion::new(name.to_string(), QueryType::A));
+		packet.answers.push(DNSRecord::A { domain: name.to_string(), addr: Ipv4Addr::new(192, 0, 2, 1), ttl: TransientTTL(300) });
+		packet
+	}
+
+	fn verdicts(rrl: &ResponseRateLimit, client: IpAddr, response: &DNSPacket, count: usize) -> Vec<RrlVerdict> {
+		(0..count).map(|_| rrl.check(client, response)).collect()
+	}
+
+	#[test]
+	fn responses_over_the_rate_are_slipped_and_dropped() {
+		let rrl = ResponseRateLimit::new(3.0, 2);
+		let client = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7));
+		let response = answer("www.example.com");
+		assert_eq!(verdicts(&rrl, client, &response, 7), vec![
+			RrlVerdict::Send, RrlVerdict::Send, RrlVerdict::Send,
+			RrlVerdict::Slip, RrlVerdict::Drop, RrlVerdict::Slip, RrlVerdict::Drop,
+		]);
+		assert_eq!(rrl.limited(), 4);
+		assert_eq!(rrl.slipped(), 2);
+
+		// Other responses, and the same one to other networks, have limits of their own...
+		assert_eq!(rrl.check(client, &answer("mail.example.com")), RrlVerdict::Send);
+		assert_eq!(rrl.check(IpAddr::V4(Ipv4Addr::new(198, 51, 101, 7)), &response), RrlVerdict::Send);
+		assert_eq!(rrl.check(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 200)), &response), RrlVerdict::Slip);
+	}
+
+	#[test]
+	fn slip_of_zero_drops_and_one_slips_all() {
+		let client = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7));
+		let response = answer("www.example.com");
+
+		let rrl = ResponseRateLimit::new(1.0, 0);
+		assert_eq!(verdicts(&rrl, client, &response, 4), vec![RrlVerdict::Send, RrlVerdict::Drop, RrlVerdict::Drop, RrlVerdict::Drop]);
+		assert_eq!(rrl.slipped(), 0);
+
+		let rrl = ResponseRateLimit::new(1.0, 1);
+		assert_eq!(verdicts(&rrl, client, &response, 4), vec![RrlVerdict::Send, RrlVerdict::Slip, RrlVerdict::Slip, RrlVerdict::Slip]);
+	}
+
+	#[test]
+	fn flood_of_spoofed_sources_keeps_the_limit() {
+		let rrl = ResponseRateLimit::new(1.0, 2);
+		let victim = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7));
+		let response = answer("www.example.com");
+		assert_eq!(rrl.check(victim, &response), RrlVerdict::Send);
+		assert_eq!(rrl.check(victim, &response), RrlVerdict::Slip);
+
+		// Spoofed sources in the victim's network share its limit whatever their number...
+		for n in 0..5_000u32 {
+			let spoofed = IpAddr::V4(Ipv4Addr::new(198, 51, 100, (n % 256) as u8));
+			assert_ne!(rrl.check(spoofed, &response), RrlVerdict::Send);
+		}
+		assert_eq!(rrl.limited(), 5_001);
+		assert_eq!(rrl.slipped(), 2_501);
+	}
+}
@@ -0,0 +1,89 @@
+//! Sanity limits on the responses the server gets from upstreams and name servers
+
+use std::io::{ ErrorKind, Result };
+
+use crate::server::buffer::VectorPacketBuffer;
+use crate::server::protocol::{ DNSPacket, DNSRecord };
+use crate::server::servfail::{ servfail_error, ServfailReason };
+
+/// Caps on the responses received, applied before they're cached or passed on, against
+/// absurd or malicious servers. Responses with more answers or a longer CNAME chain than
+/// allowed are rejected; TTLs above the maximum are lowered to it.
+///
+/// Nothing is limited by default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResponseLimits {
+	pub max_answers: usize,
+	pub max_ttl: u32,
+	pub max_cname_chain: usize,
+}
+
+impl Default for ResponseLimits {
+	fn default() -> Self {
+		ResponseLimits { max_answers: usize::MAX, max_ttl: u32::MAX, max_cname_chain: usize::MAX }
+	}
+}
+
+impl ResponseLimits {
+	/// Check `response` against the limits, lowering the TTLs above the maximum. Returns
+	/// whether any was.
+	pub fn apply(&self, response: &mut DNSPacket) -> Result<bool> {
+		if response.answers.len() > self.max_answers {
+			return Err(servfail_error(ErrorKind::InvalidData, ServfailReason::Policy, format!("Response with {} answers, more than {}", response.answers.len(), self.max_answers)));
+		}
+		let chain = cname_chain(response);
+		if chain > self.max_cname_chain {
+			return Err(servfail_error(ErrorKind::InvalidData, ServfailReason::Policy, format!("CNAME chain of {} records, more than {}", chain, self.max_cname_chain)));
+		}
+
+		let mut lowered = false;
+		for record in response.answers.iter_mut().chain(response.authorities.iter_mut()).chain(response.additional.iter_mut()) {
+			if record.get_ttl() > self.max_ttl {
+				record.set_ttl(self.max_ttl);
+				lowered = true;
+			}
+		}
+		Ok(lowered)
+	}
+
+	/// Same as `apply` for a response in wire format, which is only written again when TTLs
+	/// were lowered.
+	pub fn apply_raw(&self, message: Vec<u8>) -> Result<Vec<u8>> {
+		if *self == ResponseLimits::default() {
+			return Ok(message);
+		}
+
+		let mut response = DNSPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(message.clone()))?;
+		if !self.apply(&mut response)? {
+			return Ok(message);
+		}
+		let mut res_buffer = VectorPacketBuffer::new();
+		response.write(&mut res_buffer)?;
+		Ok(res_buffer.as_slice().to_vec())
+	}
+}
+
+/// Number of CNAME records leading from the name asked for to the end of the chain in the
+/// answers, more than there are answers if it loops.
+fn cname_chain(response: &DNSPacket) -> usize {
+	let mut name = match response.questions.first() {
+		Some(question) => question.name.trim_end_matches('.').to_string(),
+		None => return 0,
+	};
+
+	let mut chain = 0;
+	while chain <= response.answers.len() {
+		let target = response.answers.iter().find_map(|record| match *record {
+			DNSRecord::CNAME { ref domain, ref host, .. } if domain.eq_ignore_ascii_case(&name) => Some(host.clone()),
+			_ => None,
+		});
+		match target {
+			Some(target) => {
+				name = target;
+				chain += 1;
+			}
+			None => break,
+		}
+	}
+	chain
+}
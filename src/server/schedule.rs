@@ -0,0 +1,249 @@
+//! Records served only during windows of time
+
+use std::io::{ Error, ErrorKind, Result };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use crate::server::authority::ZoneData;
+use crate::server::protocol::{ DNSPacket, DNSRecord, QueryType };
+use crate::server::zonefile::parse_entry;
+
+const DAY: u64 = 86400;
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+fn invalid(msg: String) -> Error {
+	Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Days of the week and a time of day, in UTC, given as `[DAYS@]HH:MM-HH:MM`: `DAYS` is a
+/// comma separated list of days (`mon`..`sun`) or ranges of days (`mon-fri`), every day when
+/// left out. A window ending before it starts runs past midnight, into the day after the one
+/// it starts on.
+///
+/// Ex: `09:00-17:00`, `mon-fri@08:00-18:00`, `sat,sun@22:00-06:00`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeWindow {
+	// A bit per day the window starts on, Monday being the lowest...
+	days: u8,
+	start: u32,
+	end: u32,
+}
+
+impl TimeWindow {
+	pub fn parse(spec: &str) -> Result<TimeWindow> {
+		let (days, times) = match spec.split_once('@') {
+			Some((days, times)) => (parse_days(days)?, times),
+			None => (0x7f, spec),
+		};
+		let (start, end) = times.split_once('-').ok_or_else(|| invalid(format!("Invalid time window: {}", spec)))?;
+		let (start, end) = (parse_time(start)?, parse_time(end)?);
+		if start == end || start == DAY as u32 {
+			return Err(invalid(format!("Empty time window: {}", spec)));
+		}
+		Ok(TimeWindow { days, start, end })
+	}
+
+	/// Seconds left until the window ends, if `now` (seconds since the epoch) is inside it.
+	pub fn remaining(&self, now: u64) -> Option<u32> {
+		let (weekday, time) = weekday_time(now);
+		let yesterday = (weekday + 6) % 7;
+		if self.start < self.end {
+			(self.starts_on(weekday) && time >= self.start && time < self.end).then(|| self.end - time)
+		} else if self.starts_on(weekday) && time >= self.start {
+			Some(DAY as u32 - time + self.end)
+		} else {
+			(self.starts_on(yesterday) && time < self.end).then(|| self.end - time)
+		}
+	}
+
+	/// Seconds until the window next starts after `now`, a week at most.
+	pub fn until_start(&self, now: u64) -> u32 {
+		let (weekday, time) = weekday_time(now);
+		(0..=7u32)
+			.filter(|days| self.starts_on((weekday + days) % 7))
+			.map(|days| days * DAY as u32 + self.start)
+			.find(|start| *start > time)
+			.map_or(7 * DAY as u32, |start| start - time)
+	}
+
+	fn starts_on(&self, weekday: u32) -> bool {
+		self.days & (1 << weekday) != 0
+	}
+}
+
+/// Day of the week (Monday being 0) and seconds since midnight, in UTC...
+fn weekday_time(now: u64) -> (u32, u32) {
+	// 1 January 1970 was a Thursday...
+	(((now / DAY + 3) % 7) as u32, (now % DAY) as u32)
+}
+
+fn parse_days(spec: &str) -> Result<u8> {
+	let day = |name: &str| DAY_NAMES.iter()
+		.position(|day| day.eq_ignore_ascii_case(name))
+		.ok_or_else(|| invalid(format!("Invalid day: {}", name)));
+	let mut days = 0u8;
+	for part in spec.split(',') {
+		let (first, last) = match part.split_once('-') {
+			Some((first, last)) => (day(first)?, day(last)?),
+			None => (day(part)?, day(part)?),
+		};
+		// Ranges may wrap around the week, like `fri-mon`...
+		let mut weekday = first;
+		loop {
+			days |= 1 << weekday;
+			if weekday == last {
+				break;
+			}
+			weekday = (weekday + 1) % 7;
+		}
+	}
+	Ok(days)
+}
+
+/// Seconds since midnight of `HH:MM`, up to `24:00`.
+fn parse_time(spec: &str) -> Result<u32> {
+	let (hours, minutes) = spec.split_once(':').ok_or_else(|| invalid(format!("Invalid time: {}", spec)))?;
+	match (hours.parse::<u32>(), minutes.parse::<u32>()) {
+		(Ok(hours), Ok(minutes)) if minutes < 60 && hours * 60 + minutes <= 24 * 60 => Ok((hours * 60 + minutes) * 60),
+		_ => Err(invalid(format!("Invalid time: {}", spec))),
+	}
+}
+
+/// A record set of a local zone served instead of the one of the zone during a window.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScheduledRecords {
+	pub window: TimeWindow,
+	pub records: Vec<DNSRecord>,
+}
+
+impl ScheduledRecords {
+	fn name(&self) -> Option<String> {
+		self.records.first().and_then(DNSRecord::get_domain)
+	}
+
+	fn q_type(&self) -> Option<QueryType> {
+		self.records.first().map(DNSRecord::get_query_type)
+	}
+}
+
+/// Record sets replacing those of the local zones during windows of time, say a maintenance
+/// page during the night or the endpoint of the region currently in office hours. Which ones
+/// are in effect is worked out when answering, and the TTLs of the answers for the names
+/// scheduled are capped to the time left until the next change, so that caches follow the
+/// schedule.
+///
+/// The names scheduled have to lie in a local zone. When several windows of a name and type
+/// overlap, the first one given wins.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Schedule {
+	entries: Vec<ScheduledRecords>,
+}
+
+impl Schedule {
+	pub fn new() -> Self {
+		Schedule::default()
+	}
+
+	/// Parse a scheduled record given as `WINDOW RECORD`, the record as a zone file entry with
+	/// a fully qualified owner.
+	///
+	/// Ex: `mon-fri@22:00-06:00 www.example.com. 300 IN A 192.0.2.80`
+	pub fn parse(spec: &str) -> Result<(TimeWindow, DNSRecord)> {
+		let spec = spec.trim();
+		let (window, entry) = spec.split_once(char::is_whitespace).ok_or_else(|| invalid(format!("Invalid scheduled record: {}", spec)))?;
+		Ok((TimeWindow::parse(window)?, parse_entry(entry)?))
+	}
+
+	/// Serve `record` during `window`, along with the other records of its name and type
+	/// scheduled for the same window.
+	pub fn add(&mut self, window: TimeWindow, record: DNSRecord) {
+		let (name, q_type) = (record.get_domain(), record.get_query_type());
+		match self.entries.iter_mut().find(|entry| entry.window == window && entry.name() == name && entry.q_type() == Some(q_type)) {
+			Some(entry) => entry.records.push(record),
+			None => self.entries.push(ScheduledRecords { window, records: vec![record] }),
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+
+	/// The records of `name` and `q_type` in effect at `now`, with their TTLs capped to the
+	/// time left in their window. None when none is scheduled then.
+	pub fn lookup(&self, name: &str, q_type: QueryType, now: u64) -> Option<Vec<DNSRecord>> {
+		self.entries.iter()
+			.filter(|entry| entry.q_type() == Some(q_type) && entry.name().as_deref() == Some(name))
+			.find_map(|entry| {
+				let remaining = entry.window.remaining(now)?;
+				Some(entry.records.iter().cloned().map(|mut record| {
+					record.set_ttl(record.get_ttl().min(remaining));
+					record
+				}).collect())
+			})
+	}
+
+	/// Whether records are scheduled for `name` or a name below it.
+	pub fn has_name(&self, name: &str) -> bool {
+		self.entries.iter().any(|entry| entry.name().is_some_and(|owner| owner == name || owner.ends_with(&format!(".{}", name))))
+	}
+
+	/// Seconds until the records scheduled for `name` next change after `now`, None if
+	/// there are none.
+	pub fn next_change(&self, name: &str, now: u64) -> Option<u32> {
+		self.entries.iter()
+			.filter(|entry| entry.name().as_deref() == Some(name))
+			.map(|entry| entry.window.remaining(now).unwrap_or_else(|| entry.window.until_start(now)))
+			.min()
+	}
+
+	/// Cap the TTLs of a response about `qname` to the time until the records scheduled for
+	/// it, or for the names its answers lead to, next change.
+	pub fn cap_ttls(&self, qname: &str, packet: &mut DNSPacket, now: u64) {
+		let mut names = vec![qname.to_string()];
+		names.extend(packet.answers.iter().filter_map(DNSRecord::get_domain));
+		let cap = match names.iter().filter_map(|name| self.next_change(name, now)).min() {
+			Some(cap) => cap,
+			None => return,
+		};
+		for record in packet.answers.iter_mut().chain(packet.authorities.iter_mut()).chain(packet.additional.iter_mut()) {
+			record.set_ttl(record.get_ttl().min(cap));
+		}
+	}
+}
+
+/// Seconds since the epoch, which windows are matched against.
+pub fn now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs())
+}
+
+/// A zone seen through a schedule: the records in effect replace those of the zone, and the
+/// names scheduled exist even outside of their windows.
+pub(crate) struct ScheduledZone<'a> {
+	pub zone: &'a (dyn ZoneData + Send + Sync),
+	pub schedule: &'a Schedule,
+	pub now: u64,
+}
+
+impl ZoneData for ScheduledZone<'_> {
+	fn origin(&self) -> &str {
+		self.zone.origin()
+	}
+
+	fn soa(&self) -> Result<DNSRecord> {
+		self.zone.soa()
+	}
+
+	fn lookup(&self, name: &str, q_type: QueryType) -> Result<Vec<DNSRecord>> {
+		match self.schedule.lookup(name, q_type, self.now) {
+			Some(records) => Ok(records),
+			None => self.zone.lookup(name, q_type),
+		}
+	}
+
+	fn has_name(&self, name: &str) -> Result<bool> {
+		Ok(self.schedule.has_name(name) || self.zone.has_name(name)?)
+	}
+}
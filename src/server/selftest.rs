@@ -0,0 +1,270 @@
+//! Self-test of the server, for packaging smoke tests and container health gates
+
+use std::fmt;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::{ Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener };
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+
+use crate::server::authority::Zone;
+use crate::server::buffer::VectorPacketBuffer;
+use crate::server::cache::Cache;
+use crate::server::client::DNSClient;
+use crate::server::context::ServerContext;
+use crate::server::editor::PacketEditor;
+use crate::server::protocol::{ DNSPacket, DNSQuestion, DNSRecord, QueryType, ResultCode, TransientTTL };
+use crate::server::tls::{ load_server_config, DNSTlsServer };
+use crate::server::tls_upstream::{ spki_sha256, TlsPolicy, TlsUpstream };
+use crate::server::udp::DNSUdpServer;
+
+/// Zone served on the loopback listeners, and the address of its test name...
+const ZONE: &str = "self-test.invalid";
+const ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+/// Time the loopback queries are given...
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How a check went.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheckOutcome {
+	Passed,
+	Failed(String),
+	/// The check couldn't be run, with why...
+	Skipped(String),
+}
+
+/// A check of the self-test and how it went.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelfTestCheck {
+	pub name: String,
+	pub outcome: CheckOutcome,
+}
+
+impl SelfTestCheck {
+	fn new(name: &str, result: Result<()>) -> SelfTestCheck {
+		let outcome = match result {
+			Ok(()) => CheckOutcome::Passed,
+			Err(e) => CheckOutcome::Failed(e.to_string()),
+		};
+		SelfTestCheck { name: name.to_string(), outcome }
+	}
+}
+
+impl fmt::Display for SelfTestCheck {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.outcome {
+			CheckOutcome::Passed => write!(f, "PASS {}", self.name),
+			CheckOutcome::Failed(ref e) => write!(f, "FAIL {}: {}", self.name, e),
+			CheckOutcome::Skipped(ref why) => write!(f, "SKIP {}: {}", self.name, why),
+		}
+	}
+}
+
+/// Run the checks of the self-test, in order:
+///
+/// - every record type the server knows is written to a packet and read back unchanged
+/// - and so with its names compressed, the RDATA of types other than NS, CNAME, SOA, PTR and
+///   MX coming out as it does uncompressed
+/// - a response is served from the cache, and no longer once it expired
+/// - a query is answered by a UDP listener on the loopback, from a local zone
+/// - a query is answered over TLS by a listener on the loopback using the certificate chain
+///   and key given, the client pinning the certificate's key; skipped without them
+///
+/// Listeners are left running on ports the system picks, the self-test being meant to run
+/// in a process of its own.
+pub fn run_self_test(tls: Option<(&Path, &Path)>) -> Vec<SelfTestCheck> {
+	let mut checks: Vec<SelfTestCheck> = sample_records().iter()
+		.map(|record| SelfTestCheck::new(&format!("record {}", record.get_query_type()), round_trip(record)))
+		.collect();
+	checks.extend(sample_records().iter()
+		.map(|record| SelfTestCheck::new(&format!("record {} compressed", record.get_query_type()), check_compression(record))));
+	checks.push(SelfTestCheck::new("cache insert and expiry", check_cache()));
+
+	let context = match loopback_context() {
+		Ok(context) => context,
+		Err(e) => {
+			checks.push(SelfTestCheck::new("loopback UDP query", Err(e)));
+			return checks;
+		}
+	};
+	checks.push(SelfTestCheck::new("loopback UDP query", check_udp(&context)));
+	checks.push(match tls {
+		Some((cert, key)) => SelfTestCheck::new("TLS listener handshake", check_tls(&context, cert, key)),
+		None => SelfTestCheck {
+			name: "TLS listener handshake".to_string(),
+			outcome: CheckOutcome::Skipped("no certificate and key given".to_string()),
+		},
+	});
+	checks
+}
+
+fn failed(message: String) -> Error {
+	Error::new(ErrorKind::InvalidData, message)
+}
+
+/// A record of every type.
+fn sample_records() -> Vec<DNSRecord> {
+	let domain = format!("www.{}", ZONE);
+	let ttl = TransientTTL(3600);
+	vec![
+		DNSRecord::A { domain: domain.clone(), addr: ADDR, ttl },
+		DNSRecord::NS { domain: ZONE.to_string(), host: format!("ns1.{}", ZONE), ttl },
+		DNSRecord::CNAME { domain: format!("alias.{}", ZONE), host: domain.clone(), ttl },
+		DNSRecord::SOA {
+			domain: ZONE.to_string(),
+			m_name: format!("ns1.{}", ZONE),
+			r_name: format!("hostmaster.{}", ZONE),
+			serial: 2024050401,
+			refresh: 7200,
+			retry: 900,
+			expire: 1209600,
+			minimum: 300,
+			ttl,
+		},
+		DNSRecord::PTR { domain: format!("ptr.{}", ZONE), host: domain.clone(), ttl },
+		DNSRecord::MX { domain: ZONE.to_string(), priority: 10, host: format!("mail.{}", ZONE), ttl },
+		DNSRecord::TXT { domain: ZONE.to_string(), data: vec![b"v=spf1 -all".to_vec()], ttl },
+		DNSRecord::AAAA { domain: domain.clone(), addr: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), ttl },
+		DNSRecord::SRV { domain: format!("_dns._udp.{}", ZONE), priority: 10, weight: 5, port: 53, host: format!("ns1.{}", ZONE), ttl },
+		DNSRecord::OPT { packet_len: 1232, flags: 0, data: vec![0, 15, 0, 2, 0, 3] },
+		DNSRecord::DS { domain: ZONE.to_string(), key_tag: 12345, algorithm: 13, digest_type: 2, digest: vec![0xab; 32], ttl },
+		DNSRecord::DNSKEY { domain: ZONE.to_string(), flags: 257, protocol: 3, algorithm: 13, public_key: vec![0xcd; 64], ttl },
+		DNSRecord::UNKNOWN { domain, q_type: 65280, data: vec![1, 2, 3, 4], ttl },
+	]
+}
+
+/// Write `record` to a packet and read it back, TTL included.
+fn round_trip(record: &DNSRecord) -> Result<()> {
+	read_back(record, write_record(record, false)?)
+}
+
+/// Write `record` to a packet with name compression, and read it back. Only the names of the
+/// RDATA of the types of RFC 1035 may be compressed, the others must come out as they're
+/// written without compression.
+fn check_compression(record: &DNSRecord) -> Result<()> {
+	let plain = write_record(record, false)?;
+	let compressed = write_record(record, true)?;
+	let rdata = |message: &[u8]| -> Result<Vec<u8>> {
+		let editor = PacketEditor::new(message.to_vec())?;
+		editor.rdata(0).map(<[u8]>::to_vec).ok_or_else(|| failed(format!("{:?} wasn't written", record)))
+	};
+	let (plain_rdata, compressed_rdata) = (rdata(&plain)?, rdata(&compressed)?);
+
+	let q_type = record.get_query_type();
+	if q_type != QueryType::OPT && compressed.len() >= plain.len() {
+		return Err(failed(format!("{:?} wasn't compressed", record)));
+	}
+	match q_type {
+		QueryType::NS | QueryType::CNAME | QueryType::SOA | QueryType::PTR | QueryType::MX if compressed_rdata.len() >= plain_rdata.len() => {
+			Err(failed(format!("The RDATA of {:?} wasn't compressed", record)))
+		}
+		QueryType::NS | QueryType::CNAME | QueryType::SOA | QueryType::PTR | QueryType::MX => read_back(record, compressed),
+		_ if compressed_rdata != plain_rdata => Err(failed(format!("The RDATA of {:?} was compressed", record))),
+		_ => read_back(record, compressed),
+	}
+}
+
+/// `record` in a response to a query for the test zone, its names compressed or not.
+fn write_record(record: &DNSRecord, compress: bool) -> Result<Vec<u8>> {
+	let mut packet = DNSPacket::new();
+	packet.questions.push(DNSQuestion::new(ZONE.to_string(), record.get_query_type()));
+	if record.get_query_type() == QueryType::OPT {
+		packet.additional.push(record.clone());
+	} else {
+		packet.answers.push(record.clone());
+	}
+	let mut buffer = if compress { VectorPacketBuffer::with_compression() } else { VectorPacketBuffer::new() };
+	packet.write(&mut buffer)?;
+	Ok(buffer.into_inner())
+}
+
+/// Read `record` back from `message`.
+fn read_back(record: &DNSRecord, message: Vec<u8>) -> Result<()> {
+	let parsed = DNSPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(message))?;
+
+	match parsed.answers.iter().chain(&parsed.additional).next() {
+		Some(read) if read == record && read.get_ttl() == record.get_ttl() => Ok(()),
+		Some(read) => Err(failed(format!("{:?} read back as {:?}", record, read))),
+		None => Err(failed(format!("{:?} didn't come back", record))),
+	}
+}
+
+/// Store an answer living a second, and look it up before and after it expired.
+fn check_cache() -> Result<()> {
+	let name = format!("cache.{}", ZONE);
+	let mut response = DNSPacket::new();
+	response.header.response = true;
+	response.answers.push(DNSRecord::A { domain: name.clone(), addr: ADDR, ttl: TransientTTL(1) });
+
+	let cache = Cache::new();
+	cache.store(&name, QueryType::A, &response);
+	match cache.lookup(&name, QueryType::A) {
+		Some(cached) if cached.answers == response.answers => (),
+		Some(cached) => return Err(failed(format!("Cached {:?} rather than {:?}", cached.answers, response.answers))),
+		None => return Err(failed("The answer wasn't cached".to_string())),
+	}
+	thread::sleep(Duration::from_millis(1100));
+	match cache.lookup(&name, QueryType::A) {
+		Some(_) => Err(failed("The answer was served past its TTL".to_string())),
+		None => Ok(()),
+	}
+}
+
+/// A server answering for the test zone on the loopback.
+fn loopback_context() -> Result<Arc<ServerContext>> {
+	let mut context = ServerContext::new();
+	context.listen_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+	context.worker_threads = 1;
+	context.health_check_interval = None;
+
+	let records = sample_records().into_iter()
+		.filter(|record| matches!(record.get_query_type(), QueryType::SOA | QueryType::NS | QueryType::A | QueryType::AAAA))
+		.collect();
+	context.authority.add_zone(Arc::new(Zone::from_records(records)?));
+	Ok(Arc::new(context))
+}
+
+/// Whether `response` holds the address of the test name.
+fn check_answer(response: &DNSPacket) -> Result<()> {
+	let answered = response.header.rescode == ResultCode::NOERROR
+		&& response.answers.iter().any(|record| matches!(record, DNSRecord::A { addr, .. } if *addr == ADDR));
+	if answered {
+		Ok(())
+	} else {
+		Err(failed(format!("Answered {:?} with {:?}", response.header.rescode, response.answers)))
+	}
+}
+
+fn check_udp(context: &Arc<ServerContext>) -> Result<()> {
+	DNSUdpServer::new(context.clone()).run_server()?;
+	let addr = context.udp_stats.sockets().first()
+		.map(|socket| socket.addr)
+		.ok_or_else(|| failed("The UDP listener didn't start".to_string()))?;
+	let client = DNSClient::with_timeout(QUERY_TIMEOUT);
+	check_answer(&client.send_query(&format!("www.{}", ZONE), QueryType::A, addr, true)?)
+}
+
+/// Serve TLS with the certificate chain of `cert` and the key of `key`, and query the
+/// listener pinning the key of the certificate.
+fn check_tls(context: &Arc<ServerContext>, cert: &Path, key: &Path) -> Result<()> {
+	let config = load_server_config(cert, key)?;
+	let leaf = CertificateDer::pem_file_iter(cert)
+		.ok()
+		.and_then(|mut certs| certs.next())
+		.and_then(|cert| cert.ok())
+		.ok_or_else(|| failed(format!("No certificate in {}", cert.display())))?;
+	let pin = spki_sha256(&leaf).ok_or_else(|| failed("Failed to read the key of the certificate".to_string()))?;
+
+	// A port the system isn't using, as the listener doesn't say which it got...
+	let addr = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()?;
+	DNSTlsServer::new(context.clone(), config, addr).run_server()?;
+
+	let upstream = TlsUpstream::new(addr, None, vec![pin], TlsPolicy::Strict)?;
+	let client = DNSClient::with_timeout(QUERY_TIMEOUT);
+	let mut query = client.build_query(&format!("www.{}", ZONE), QueryType::A, true);
+	check_answer(&upstream.exchange(&client, &mut query)?)
+}
@@ -0,0 +1,133 @@
+//! Why queries get answered with SERVFAIL
+
+use std::error;
+use std::fmt;
+use std::io::{ Error, ErrorKind };
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use crate::server::protocol::{ DNSPacket, EDNS_OPTION_EDE };
+
+/// The cause of a SERVFAIL, telling a network problem from a DNSSEC one or from a limit
+/// of the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ServfailReason {
+	/// No upstream or name server answered in time.
+	UpstreamTimeout,
+	/// The upstreams or name servers couldn't be reached, sent broken responses or answered
+	/// SERVFAIL themselves without saying why.
+	UpstreamFailure,
+	/// The answer failed DNSSEC validation, as reported by a validating upstream.
+	ValidationBogus,
+	/// A response was rejected by the limits set on them, or refused by the upstream's
+	/// policy.
+	Policy,
+	/// Resolving took more nested lookups, referrals or CNAMEs than allowed.
+	BudgetExceeded,
+	/// A failure of the server itself, like a panic or a local zone which can't be read.
+	Internal,
+}
+
+impl ServfailReason {
+	pub const ALL: [ServfailReason; 6] = [
+		ServfailReason::UpstreamTimeout,
+		ServfailReason::UpstreamFailure,
+		ServfailReason::ValidationBogus,
+		ServfailReason::Policy,
+		ServfailReason::BudgetExceeded,
+		ServfailReason::Internal,
+	];
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			ServfailReason::UpstreamTimeout => "upstream-timeout",
+			ServfailReason::UpstreamFailure => "upstream-failure",
+			ServfailReason::ValidationBogus => "validation-bogus",
+			ServfailReason::Policy => "policy",
+			ServfailReason::BudgetExceeded => "budget-exceeded",
+			ServfailReason::Internal => "internal",
+		}
+	}
+
+	/// The cause of a failure to resolve a query: the one it was raised with (see
+	/// `servfail_error`), otherwise a timeout or a failure of the servers asked.
+	pub fn of_error(e: &Error) -> ServfailReason {
+		if let Some(marked) = e.get_ref().and_then(|inner| inner.downcast_ref::<MarkedError>()) {
+			return marked.reason;
+		}
+		match e.kind() {
+			ErrorKind::TimedOut | ErrorKind::WouldBlock => ServfailReason::UpstreamTimeout,
+			_ => ServfailReason::UpstreamFailure,
+		}
+	}
+
+	/// The cause of a SERVFAIL received from an upstream, going by the Extended DNS Errors
+	/// (RFC 8914) it carries.
+	pub fn of_response(response: &DNSPacket) -> ServfailReason {
+		let codes = response.edns_options().unwrap_or_default().into_iter()
+			.filter(|option| option.code == EDNS_OPTION_EDE && option.data.len() >= 2)
+			.map(|option| u16::from_be_bytes([option.data[0], option.data[1]]));
+		for code in codes {
+			match code {
+				// From Unsupported DNSKEY Algorithm to NSEC Missing...
+				1..=12 => return ServfailReason::ValidationBogus,
+				// Blocked, Censored, Filtered and Prohibited...
+				15..=18 => return ServfailReason::Policy,
+				// No Reachable Authority...
+				22 => return ServfailReason::UpstreamTimeout,
+				_ => (),
+			}
+		}
+		ServfailReason::UpstreamFailure
+	}
+}
+
+impl fmt::Display for ServfailReason {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.name())
+	}
+}
+
+/// An error carrying the reason of the SERVFAIL it leads to.
+#[derive(Debug)]
+struct MarkedError {
+	reason: ServfailReason,
+	message: String,
+}
+
+impl fmt::Display for MarkedError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.message)
+	}
+}
+
+impl error::Error for MarkedError {}
+
+/// An error of `kind` failing the query it's raised for with `reason`, for the causes which
+/// can't be told apart by their kind.
+pub fn servfail_error(kind: ErrorKind, reason: ServfailReason, message: String) -> Error {
+	Error::new(kind, MarkedError { reason, message })
+}
+
+/// Number of SERVFAILs answered, by reason.
+#[derive(Debug, Default)]
+pub struct ServfailStats {
+	counts: [AtomicU64; 6],
+}
+
+impl ServfailStats {
+	pub fn new() -> Self {
+		ServfailStats::default()
+	}
+
+	pub fn count(&self, reason: ServfailReason) {
+		self.counts[reason as usize].fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn get(&self, reason: ServfailReason) -> u64 {
+		self.counts[reason as usize].load(Ordering::Relaxed)
+	}
+
+	pub fn total(&self) -> u64 {
+		ServfailReason::ALL.iter().map(|reason| self.get(*reason)).sum()
+	}
+}
@@ -0,0 +1,158 @@
+//! Stopping the server without cutting off the queries it's answering, and the signals
+//! telling it to
+
+use std::fmt;
+use std::future;
+use std::io::{ Error, Result };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::sync::{ Condvar, Mutex };
+use std::task::Poll;
+use std::time::{ Duration, Instant };
+
+use tokio::runtime::{ Builder, Runtime };
+#[cfg(unix)]
+use tokio::signal::unix::{ self, SignalKind };
+
+/// Time the queries being answered get to complete once the server stops, unless told
+/// otherwise...
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether the server is stopping, and the number of queries being answered, which it waits
+/// for before exiting. Queries coming in once it's stopping aren't answered.
+#[derive(Debug, Default)]
+pub struct Shutdown {
+	stopping: AtomicBool,
+	in_flight: AtomicUsize,
+	// Wakes the thread waiting for the last query to be answered...
+	lock: Mutex<()>,
+	idle: Condvar,
+}
+
+/// A query being answered, until dropped.
+#[derive(Debug)]
+pub struct InFlight<'a> {
+	shutdown: &'a Shutdown,
+}
+
+impl Shutdown {
+	pub fn new() -> Self {
+		Shutdown::default()
+	}
+
+	pub fn is_stopping(&self) -> bool {
+		self.stopping.load(Ordering::SeqCst)
+	}
+
+	/// Count a query as being answered until the returned guard is dropped. None once the
+	/// server is stopping, the query shouldn't be answered then.
+	pub fn begin(&self) -> Option<InFlight<'_>> {
+		self.in_flight.fetch_add(1, Ordering::SeqCst);
+		let in_flight = InFlight { shutdown: self };
+		if self.is_stopping() {
+			return None;
+		}
+		Some(in_flight)
+	}
+
+	/// Stop answering queries.
+	pub fn stop(&self) {
+		self.stopping.store(true, Ordering::SeqCst);
+	}
+
+	/// Wait up to `timeout` for the queries being answered, returning the number of those
+	/// which still are.
+	pub fn wait(&self, timeout: Duration) -> usize {
+		let deadline = Instant::now() + timeout;
+		let mut lock = match self.lock.lock() {
+			Ok(lock) => lock,
+			Err(_) => return self.in_flight.load(Ordering::SeqCst),
+		};
+		loop {
+			let in_flight = self.in_flight.load(Ordering::SeqCst);
+			let now = Instant::now();
+			if in_flight == 0 || now >= deadline {
+				return in_flight;
+			}
+			lock = match self.idle.wait_timeout(lock, deadline - now) {
+				Ok((lock, _)) => lock,
+				Err(_) => return in_flight,
+			};
+		}
+	}
+}
+
+impl Drop for InFlight<'_> {
+	fn drop(&mut self) {
+		let shutdown = self.shutdown;
+		if shutdown.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 && shutdown.is_stopping() {
+			let _lock = shutdown.lock.lock();
+			shutdown.idle.notify_all();
+		}
+	}
+}
+
+/// What the process is asked to do by a signal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+	/// SIGTERM or Ctrl-C: shut down.
+	Terminate,
+	/// SIGHUP: read the configuration again.
+	Reload,
+}
+
+/// The signals the server handles, caught from the time this is created so that none is
+/// missed between two waits.
+pub struct Signals {
+	runtime: Runtime,
+	#[cfg(unix)]
+	terminate: unix::Signal,
+	#[cfg(unix)]
+	interrupt: unix::Signal,
+	#[cfg(unix)]
+	hangup: unix::Signal,
+}
+
+impl Signals {
+	#[cfg(unix)]
+	pub fn new() -> Result<Self> {
+		let runtime = Builder::new_current_thread().enable_all().build()?;
+		let (terminate, interrupt, hangup) = {
+			let _entered = runtime.enter();
+			(unix::signal(SignalKind::terminate())?, unix::signal(SignalKind::interrupt())?, unix::signal(SignalKind::hangup())?)
+		};
+		Ok(Signals { runtime, terminate, interrupt, hangup })
+	}
+
+	#[cfg(not(unix))]
+	pub fn new() -> Result<Self> {
+		let runtime = Builder::new_current_thread().enable_all().build()?;
+		Ok(Signals { runtime })
+	}
+
+	/// Block until the next signal. Shutting down goes before reloading when both came in.
+	#[cfg(unix)]
+	pub fn wait(&mut self) -> Result<Signal> {
+		let Signals { ref runtime, ref mut terminate, ref mut interrupt, ref mut hangup } = *self;
+		runtime.block_on(future::poll_fn(|cx| {
+			if terminate.poll_recv(cx).is_ready() || interrupt.poll_recv(cx).is_ready() {
+				return Poll::Ready(Ok(Signal::Terminate));
+			}
+			match hangup.poll_recv(cx) {
+				Poll::Ready(Some(())) => Poll::Ready(Ok(Signal::Reload)),
+				Poll::Ready(None) => Poll::Ready(Err(Error::other("SIGHUP is no longer caught"))),
+				Poll::Pending => Poll::Pending,
+			}
+		}))
+	}
+
+	#[cfg(not(unix))]
+	pub fn wait(&mut self) -> Result<Signal> {
+		self.runtime.block_on(tokio::signal::ctrl_c()).map(|_| Signal::Terminate)
+	}
+}
+
+impl fmt::Debug for Signals {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Signals").finish()
+	}
+}
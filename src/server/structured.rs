@@ -0,0 +1,123 @@
+//! Zones and record sets in JSON and YAML
+//!
+//! Tooling generating or auditing zones is better served by structured documents than by
+//! master files. Records take the representation of `server::json` in both formats.
+
+use std::io::{ Error, ErrorKind, Read, Result, Write };
+use std::path::Path;
+
+use serde::{ Deserialize, Serialize };
+
+use crate::server::authority::Zone;
+use crate::server::protocol::{ DNSRecord, QueryType };
+use crate::server::zonefile::normalize_name;
+
+fn invalid<E: ToString>(e: E) -> Error {
+	Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+/// The structured formats records are imported from and exported to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StructuredFormat {
+	Json,
+	Yaml,
+}
+
+impl StructuredFormat {
+	pub fn from_name(name: &str) -> Option<StructuredFormat> {
+		match name.to_lowercase().as_str() {
+			"json" => Some(StructuredFormat::Json),
+			"yaml" | "yml" => Some(StructuredFormat::Yaml),
+			_ => None,
+		}
+	}
+
+	/// The format of a file going by its extension, None for master files.
+	pub fn from_path<P: AsRef<Path>>(path: P) -> Option<StructuredFormat> {
+		StructuredFormat::from_name(path.as_ref().extension()?.to_str()?)
+	}
+}
+
+/// A zone, or any set of records, as a structured document:
+///
+/// ```yaml
+/// origin: example.com.
+/// records:
+/// - name: example.com.
+///   ttl: 3600
+///   type: SOA
+///   data: ns1.example.com. hostmaster.example.com. 1 7200 900 1209600 300
+/// - { name: www.example.com., ttl: 300, type: A, data: 192.0.2.80 }
+/// ```
+///
+/// The origin is optional, and a bare list of records is read as well. Owner names are
+/// fully qualified, the trailing dot being optional.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordDocument {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub origin: Option<String>,
+	pub records: Vec<DNSRecord>,
+}
+
+impl RecordDocument {
+	/// The records of `zone`, its SOA first.
+	pub fn from_zone(zone: &Zone) -> RecordDocument {
+		let mut records = vec![zone.get_soa()];
+		records.extend(zone.records().cloned());
+		RecordDocument { origin: Some(format!("{}.", zone.domain)), records }
+	}
+
+	/// The records owned by `name`, all of them or only those of `q_type`.
+	pub fn record_set(records: &[DNSRecord], name: &str, q_type: Option<QueryType>) -> RecordDocument {
+		let name = normalize_name(name);
+		let records = records.iter()
+			.filter(|record| record.get_domain().as_deref() == Some(name.as_str()))
+			.filter(|record| q_type.is_none_or(|q_type| record.get_query_type() == q_type))
+			.cloned()
+			.collect();
+		RecordDocument { origin: None, records }
+	}
+
+	/// Read a document, or a bare list of records.
+	pub fn read<R: Read>(reader: R, format: StructuredFormat) -> Result<RecordDocument> {
+		match format {
+			StructuredFormat::Json => {
+				let value: serde_json::Value = serde_json::from_reader(reader).map_err(invalid)?;
+				match value {
+					serde_json::Value::Array(_) => Ok(RecordDocument { origin: None, records: serde_json::from_value(value).map_err(invalid)? }),
+					_ => serde_json::from_value(value).map_err(invalid),
+				}
+			}
+			StructuredFormat::Yaml => {
+				let value: serde_yaml::Value = serde_yaml::from_reader(reader).map_err(invalid)?;
+				match value {
+					serde_yaml::Value::Sequence(_) => Ok(RecordDocument { origin: None, records: serde_yaml::from_value(value).map_err(invalid)? }),
+					_ => serde_yaml::from_value(value).map_err(invalid),
+				}
+			}
+		}
+	}
+
+	pub fn write<W: Write>(&self, mut writer: W, format: StructuredFormat) -> Result<()> {
+		match format {
+			StructuredFormat::Json => {
+				serde_json::to_writer_pretty(&mut writer, self).map_err(invalid)?;
+				writeln!(writer)
+			}
+			StructuredFormat::Yaml => serde_yaml::to_writer(writer, self).map_err(invalid),
+		}
+	}
+
+	/// Build a zone of the records, which have to include exactly one SOA naming the apex,
+	/// the origin if there's one.
+	pub fn into_zone(self) -> Result<Zone> {
+		let zone = Zone::from_records(self.records)?;
+		match self.origin {
+			Some(origin) if normalize_name(&origin) != zone.domain => {
+				Err(invalid(format!("The SOA is owned by {}. rather than the origin {}", zone.domain, origin)))
+			}
+			_ => Ok(zone),
+		}
+	}
+}
@@ -0,0 +1,261 @@
+use std::io::{ ErrorKind, Read, Result };
+use std::net::{ Shutdown, SocketAddr, TcpListener, TcpStream };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::{ Arc, Mutex };
+use std::thread::{ self, JoinHandle };
+use std::time::Instant;
+
+use crate::server::client::{ next_tcp_message, write_tcp_message };
+use crate::server::context::{ Listener, ServerContext, ServerRole };
+use crate::server::handler::{ handle_request, parse_request };
+use crate::server::latency::QueryTiming;
+use crate::server::protocol::DNSPacket;
+use crate::server::resolve::{ DynamicResolvers, ResolverFactory };
+use crate::server::upstream::Transport;
+
+/// Queries of a connection being resolved at the same time. Further queries on the connection
+/// are answered one after the other until some finish...
+const MAX_PIPELINED: usize = 16;
+/// Queries of all the connections of a listener resolved on threads of their own at the same
+/// time, past which each connection answers its queries one after the other...
+const MAX_QUERY_THREADS: usize = 256;
+
+/// TCP listener following RFC 7766: messages are framed by their two byte length, clients
+/// may send several queries over a connection without waiting for the answers, which are
+/// sent back as soon as each is ready, in whatever order that is.
+///
+/// Connections without a query in progress are closed after `tcp_idle_timeout`, as are those
+/// taking longer than that to send a whole message once they started it, and no more than
+/// `tcp_max_connections` are served at a time; further connections are closed as soon as
+/// they're accepted. Up to `MAX_PIPELINED` queries of a connection, and `MAX_QUERY_THREADS`
+/// of all of them, are resolved at the same time.
+///
+/// Like the UDP server, it listens on the listen address of the context in the full role
+/// unless told otherwise.
+pub struct DNSTcpServer<F: ResolverFactory = DynamicResolvers> {
+	context: Arc<ServerContext>,
+	resolvers: Arc<F>,
+	addr: SocketAddr,
+	role: ServerRole,
+	connections: Arc<AtomicUsize>,
+	/// Queries being resolved on threads of their own...
+	query_threads: Arc<AtomicUsize>,
+}
+
+impl DNSTcpServer {
+	pub fn new(context: Arc<ServerContext>) -> Self {
+		DNSTcpServer::with_resolvers(context, DynamicResolvers)
+	}
+}
+
+impl<F: ResolverFactory> DNSTcpServer<F> {
+	pub fn with_resolvers(context: Arc<ServerContext>, resolvers: F) -> Self {
+		Self {
+			addr: context.listen_addr,
+			role: ServerRole::Full,
+			context,
+			resolvers: Arc::new(resolvers),
+			connections: Arc::new(AtomicUsize::new(0)),
+			query_threads: Arc::new(AtomicUsize::new(0)),
+		}
+	}
+
+	/// Listen on `addr` in `role` instead.
+	pub fn with_listener(mut self, addr: SocketAddr, role: ServerRole) -> Self {
+		self.addr = addr;
+		self.role = role;
+		self
+	}
+
+	/// Bind the socket and start accepting connections. The returned handle belongs to the
+	/// accepting thread, which runs for as long as the socket does.
+	pub fn run_server(self) -> Result<JoinHandle<()>> {
+		let listener = TcpListener::bind(self.addr)?;
+
+		let handle = thread::Builder::new()
+			.name("DNSTcpServer-incoming".to_string())
+			.spawn(move || {
+				for stream in listener.incoming() {
+					let stream = match stream {
+						Ok(stream) => stream,
+						Err(e) => {
+							info!("Failed to accept TCP connection: {}", e);
+							continue;
+						}
+					};
+
+					if self.connections.fetch_add(1, Ordering::SeqCst) >= self.context.tcp_max_connections {
+						self.connections.fetch_sub(1, Ordering::SeqCst);
+						let _ = stream.shutdown(Shutdown::Both);
+						continue;
+					}
+
+					let context = self.context.clone();
+					let resolvers = self.resolvers.clone();
+					let role = self.role;
+					let connections = self.connections.clone();
+					let query_threads = self.query_threads.clone();
+					let spawned = thread::Builder::new()
+						.name("DNSTcpServer-connection".to_string())
+						.spawn(move || {
+							if let Err(e) = serve_connection(&context, &resolvers, role, &query_threads, stream) {
+								info!("TCP connection failed: {}", e);
+							}
+							connections.fetch_sub(1, Ordering::SeqCst);
+						});
+					if let Err(e) = spawned {
+						info!("Failed to start a thread for a TCP connection: {}", e);
+						self.connections.fetch_sub(1, Ordering::SeqCst);
+					}
+				}
+			})?;
+
+		Ok(handle)
+	}
+}
+
+/// What the threads answering the queries of a connection share.
+struct Connection<F: ResolverFactory> {
+	context: Arc<ServerContext>,
+	resolvers: Arc<F>,
+	role: ServerRole,
+	src: SocketAddr,
+	writer: Mutex<TcpStream>,
+}
+
+/// Read the queries of a connection until the client closes it, it sits idle for too long or
+/// it's too slow to send a message, resolving them in threads of their own while there's room
+/// for them under `query_threads`.
+fn serve_connection<F: ResolverFactory>(context: &Arc<ServerContext>, resolvers: &Arc<F>, role: ServerRole, query_threads: &Arc<AtomicUsize>, stream: TcpStream) -> Result<()> {
+	let src = stream.peer_addr()?;
+	let idle_timeout = context.tcp_idle_timeout;
+	stream.set_write_timeout(Some(idle_timeout))?;
+	let mut reader = stream.try_clone()?;
+	let connection = Arc::new(Connection {
+		context: context.clone(),
+		resolvers: resolvers.clone(),
+		role,
+		src,
+		writer: Mutex::new(stream),
+	});
+	let pending = Arc::new(AtomicUsize::new(0));
+
+	// Bytes received and not yet made into messages, the rest of a message being left to the
+	// next reads, and when the message they start has to be in by...
+	let mut received = Vec::new();
+	let mut message_deadline: Option<Instant> = None;
+	let mut buf = [0; 4096];
+	loop {
+		let timeout = match message_deadline {
+			Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+			None => idle_timeout,
+		};
+		if timeout.is_zero() {
+			info!("Closed the TCP connection of {}, too slow to send a message", src);
+			break;
+		}
+		reader.set_read_timeout(Some(timeout))?;
+		let len = match reader.read(&mut buf) {
+			Ok(0) => break,
+			Ok(len) => len,
+			// The idle timeout only runs while no query is in progress, the deadline of a
+			// message always...
+			Err(ref e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+				if message_deadline.is_some() {
+					info!("Closed the TCP connection of {}, too slow to send a message", src);
+				} else if pending.load(Ordering::SeqCst) > 0 {
+					continue;
+				}
+				break;
+			}
+			Err(e) => return Err(e),
+		};
+		received.extend_from_slice(&buf[..len]);
+
+		let mut completed = false;
+		while let Some(message) = next_tcp_message(&mut received) {
+			completed = true;
+			let started = Instant::now();
+			let request = match parse_request(context, &message) {
+				Ok(request) => request,
+				Err(e) => {
+					info!("Failed to parse TCP query packet from {}: {}", src, e);
+					return Ok(());
+				}
+			};
+
+			// Past either cap the query is answered before reading on...
+			if !take_slot(&pending, MAX_PIPELINED) {
+				connection.answer(request, message, started);
+				continue;
+			}
+			if !take_slot(query_threads, MAX_QUERY_THREADS) {
+				connection.answer(request, message, started);
+				pending.fetch_sub(1, Ordering::SeqCst);
+				continue;
+			}
+			let connection = connection.clone();
+			let (thread_pending, thread_query_threads) = (pending.clone(), query_threads.clone());
+			let spawned = thread::Builder::new()
+				.name(format!("DNSTcpServer-query {}", src))
+				.spawn(move || {
+					connection.answer(request, message, started);
+					thread_pending.fetch_sub(1, Ordering::SeqCst);
+					thread_query_threads.fetch_sub(1, Ordering::SeqCst);
+				});
+			if let Err(e) = spawned {
+				pending.fetch_sub(1, Ordering::SeqCst);
+				query_threads.fetch_sub(1, Ordering::SeqCst);
+				return Err(e);
+			}
+		}
+		// The messages left over start their deadline with their first bytes...
+		if received.is_empty() {
+			message_deadline = None;
+		} else if completed || message_deadline.is_none() {
+			message_deadline = Some(Instant::now() + idle_timeout);
+		}
+	}
+
+	// The threads of the queries still in progress hold on to the stream, which stays open
+	// until they sent their answers...
+	Ok(())
+}
+
+/// Count one more of something there may be `max` of at once in `count`, if there's room.
+fn take_slot(count: &AtomicUsize, max: usize) -> bool {
+	if count.fetch_add(1, Ordering::SeqCst) < max {
+		return true;
+	}
+	count.fetch_sub(1, Ordering::SeqCst);
+	false
+}
+
+impl<F: ResolverFactory> Connection<F> {
+	/// Resolve one query of the connection and write its response.
+	fn answer(&self, request: DNSPacket, raw_request: Vec<u8>, received: Instant) {
+		let src = self.src;
+		let mut timing = QueryTiming::new(received);
+		let (response, res_bytes) = match handle_request(&self.context, &self.resolvers, Listener { role: self.role, transport: Transport::Tcp }, &request, &raw_request, src, &mut timing) {
+			Ok(Some(response)) => response,
+			Ok(None) => return,
+			Err(e) => {
+				info!("Failed to write response to {}: {}", src, e);
+				return;
+			}
+		};
+
+		if let Ok(mut stream) = self.writer.lock() {
+			if let Err(e) = write_tcp_message(&mut *stream, &res_bytes) {
+				info!("Failed to send response to {}: {}", src, e);
+			}
+		}
+		timing.stage("send");
+		self.context.record_query(src, request.questions.first(), response.header.rescode, &timing);
+		if let Some(capture) = self.context.capture() {
+			if let Err(e) = capture.record(src, Transport::Tcp, &request, &raw_request, &res_bytes) {
+				info!("Failed to capture the query from {}: {}", src, e);
+			}
+		}
+	}
+}
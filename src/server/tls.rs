@@ -0,0 +1,286 @@
+//! DNS over TLS listener (RFC 7858)
+
+use std::io::{ Error, ErrorKind, Read, Result, Write };
+use std::net::{ Shutdown, SocketAddr, TcpListener, TcpStream };
+use std::path::Path;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::{ Arc, Mutex };
+use std::thread::{ self, JoinHandle };
+use std::time::Instant;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{ CertificateDer, PrivateKeyDer };
+use rustls::{ ServerConfig, ServerConnection };
+
+use crate::server::client::{ next_tcp_message, write_tcp_message };
+use crate::server::context::{ Listener, ServerContext, ServerRole };
+use crate::server::handler::{ handle_request, parse_request };
+use crate::server::latency::QueryTiming;
+use crate::server::protocol::DNSPacket;
+use crate::server::resolve::{ DynamicResolvers, ResolverFactory };
+use crate::server::upstream::Transport;
+
+/// Port DNS over TLS is served on (RFC 7858 section 3.1)...
+pub const DEFAULT_TLS_PORT: u16 = 853;
+
+/// Queries of a connection being resolved at the same time, as over plain TCP...
+const MAX_PIPELINED: usize = 16;
+
+/// Build the TLS configuration of the listener from a PEM certificate chain and private key.
+/// Sessions can be resumed from the server's cache as well as from tickets.
+pub fn load_server_config(cert_file: &Path, key_file: &Path) -> Result<Arc<ServerConfig>> {
+	let certs = CertificateDer::pem_file_iter(cert_file)
+		.and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+		.map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid certificate file {}: {}", cert_file.display(), e)))?;
+	if certs.is_empty() {
+		return Err(Error::new(ErrorKind::InvalidData, format!("No certificate in {}", cert_file.display())));
+	}
+	let key = PrivateKeyDer::from_pem_file(key_file)
+		.map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid key file {}: {}", key_file.display(), e)))?;
+
+	let mut config = ServerConfig::builder()
+		.with_no_client_auth()
+		.with_single_cert(certs, key)
+		.map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+	config.ticketer = rustls::crypto::ring::Ticketer::new().map_err(Error::other)?;
+	config.alpn_protocols = vec![b"dot".to_vec()];
+	Ok(Arc::new(config))
+}
+
+/// DNS over TLS listener. Messages are framed by their two byte length as over TCP, and
+/// queries are pipelined the same way: each is resolved in a thread of its own and answered
+/// as soon as it's ready.
+///
+/// Idle connections are closed and connections are limited like on the TCP listener, by
+/// `tcp_idle_timeout` and `tcp_max_connections`.
+pub struct DNSTlsServer<F: ResolverFactory = DynamicResolvers> {
+	context: Arc<ServerContext>,
+	resolvers: Arc<F>,
+	config: Arc<ServerConfig>,
+	addr: SocketAddr,
+	role: ServerRole,
+	connections: Arc<AtomicUsize>,
+}
+
+impl DNSTlsServer {
+	pub fn new(context: Arc<ServerContext>, config: Arc<ServerConfig>, addr: SocketAddr) -> Self {
+		DNSTlsServer::with_resolvers(context, config, addr, DynamicResolvers)
+	}
+}
+
+impl<F: ResolverFactory> DNSTlsServer<F> {
+	pub fn with_resolvers(context: Arc<ServerContext>, config: Arc<ServerConfig>, addr: SocketAddr, resolvers: F) -> Self {
+		Self {
+			context,
+			resolvers: Arc::new(resolvers),
+			config,
+			addr,
+			role: ServerRole::Full,
+			connections: Arc::new(AtomicUsize::new(0)),
+		}
+	}
+
+	/// Serve in `role` instead of the full one.
+	pub fn with_role(mut self, role: ServerRole) -> Self {
+		self.role = role;
+		self
+	}
+
+	/// Bind the socket and start accepting connections. The returned handle belongs to the
+	/// accepting thread, which runs for as long as the socket does.
+	pub fn run_server(self) -> Result<JoinHandle<()>> {
+		let listener = TcpListener::bind(self.addr)?;
+
+		let handle = thread::Builder::new()
+			.name("DNSTlsServer-incoming".to_string())
+			.spawn(move || {
+				for stream in listener.incoming() {
+					let stream = match stream {
+						Ok(stream) => stream,
+						Err(e) => {
+							info!("Failed to accept TLS connection: {}", e);
+							continue;
+						}
+					};
+
+					if self.connections.fetch_add(1, Ordering::SeqCst) >= self.context.tcp_max_connections {
+						self.connections.fetch_sub(1, Ordering::SeqCst);
+						let _ = stream.shutdown(Shutdown::Both);
+						continue;
+					}
+
+					let context = self.context.clone();
+					let resolvers = self.resolvers.clone();
+					let config = self.config.clone();
+					let role = self.role;
+					let connections = self.connections.clone();
+					let spawned = thread::Builder::new()
+						.name("DNSTlsServer-connection".to_string())
+						.spawn(move || {
+							if let Err(e) = serve_connection(&context, &resolvers, config, role, stream) {
+								info!("TLS connection failed: {}", e);
+							}
+							connections.fetch_sub(1, Ordering::SeqCst);
+						});
+					if let Err(e) = spawned {
+						info!("Failed to start a thread for a TLS connection: {}", e);
+						self.connections.fetch_sub(1, Ordering::SeqCst);
+					}
+				}
+			})?;
+
+		Ok(handle)
+	}
+}
+
+/// The TLS session of a connection, and the socket records are written to. Threads answering
+/// queries encrypt and send their responses under its lock, while the connection's thread
+/// only takes it to decrypt what it read.
+struct TlsSession {
+	tls: ServerConnection,
+	socket: TcpStream,
+}
+
+impl TlsSession {
+	/// Send the TLS records pending, handshake messages as well as application data.
+	fn flush(&mut self) -> Result<()> {
+		while self.tls.wants_write() {
+			self.tls.write_tls(&mut self.socket)?;
+		}
+		self.socket.flush()
+	}
+}
+
+/// What the threads answering the queries of a connection share.
+struct Connection<F: ResolverFactory> {
+	context: Arc<ServerContext>,
+	resolvers: Arc<F>,
+	role: ServerRole,
+	src: SocketAddr,
+	session: Mutex<TlsSession>,
+}
+
+/// Read the queries of a connection until the client closes it or it sits idle for too long,
+/// resolving them in threads of their own.
+fn serve_connection<F: ResolverFactory>(context: &Arc<ServerContext>, resolvers: &Arc<F>, config: Arc<ServerConfig>, role: ServerRole, stream: TcpStream) -> Result<()> {
+	let src = stream.peer_addr()?;
+	stream.set_read_timeout(Some(context.tcp_idle_timeout))?;
+	stream.set_write_timeout(Some(context.tcp_idle_timeout))?;
+	let mut reader = stream.try_clone()?;
+	let tls = ServerConnection::new(config).map_err(Error::other)?;
+	let connection = Arc::new(Connection {
+		context: context.clone(),
+		resolvers: resolvers.clone(),
+		role,
+		src,
+		session: Mutex::new(TlsSession { tls, socket: stream }),
+	});
+	let pending = Arc::new(AtomicUsize::new(0));
+
+	// Plain text received and not yet made into messages...
+	let mut received = Vec::new();
+	let mut buf = [0; 4096];
+	loop {
+		let len = match reader.read(&mut buf) {
+			Ok(0) => break,
+			Ok(len) => len,
+			// The idle timeout only runs while no query is in progress...
+			Err(ref e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+				if pending.load(Ordering::SeqCst) > 0 {
+					continue;
+				}
+				break;
+			}
+			Err(e) => return Err(e),
+		};
+
+		let closed = {
+			let mut session = connection.session.lock().map_err(|_| Error::other("TLS session poisoned"))?;
+			let mut data = &buf[..len];
+			let mut closed = false;
+			while !data.is_empty() {
+				session.tls.read_tls(&mut data)?;
+				let state = session.tls.process_new_packets().map_err(|e| {
+					// Let the client know what went wrong before giving up...
+					let _ = session.flush();
+					Error::new(ErrorKind::InvalidData, e)
+				})?;
+				let mut plain = vec![0; state.plaintext_bytes_to_read()];
+				session.tls.reader().read_exact(&mut plain)?;
+				received.extend_from_slice(&plain);
+				closed |= state.peer_has_closed();
+			}
+			session.flush()?;
+			closed
+		};
+
+		while let Some(message) = next_tcp_message(&mut received) {
+			let started = Instant::now();
+			let request = match parse_request(context, &message) {
+				Ok(request) => request,
+				Err(e) => {
+					info!("Failed to parse TLS query packet from {}: {}", src, e);
+					return Ok(());
+				}
+			};
+
+			if pending.fetch_add(1, Ordering::SeqCst) >= MAX_PIPELINED {
+				connection.answer(request, message, started);
+				pending.fetch_sub(1, Ordering::SeqCst);
+				continue;
+			}
+			let connection = connection.clone();
+			let pending = pending.clone();
+			thread::Builder::new()
+				.name(format!("DNSTlsServer-query {}", src))
+				.spawn(move || {
+					connection.answer(request, message, started);
+					pending.fetch_sub(1, Ordering::SeqCst);
+				})?;
+		}
+
+		if closed {
+			break;
+		}
+	}
+
+	// The threads of the queries still in progress hold on to the session, which stays open
+	// until they sent their answers...
+	if pending.load(Ordering::SeqCst) == 0 {
+		if let Ok(mut session) = connection.session.lock() {
+			session.tls.send_close_notify();
+			let _ = session.flush();
+		}
+	}
+	Ok(())
+}
+
+impl<F: ResolverFactory> Connection<F> {
+	/// Resolve one query of the connection and write its response.
+	fn answer(&self, request: DNSPacket, raw_request: Vec<u8>, received: Instant) {
+		let src = self.src;
+		let mut timing = QueryTiming::new(received);
+		let (response, res_bytes) = match handle_request(&self.context, &self.resolvers, Listener { role: self.role, transport: Transport::Tls }, &request, &raw_request, src, &mut timing) {
+			Ok(Some(response)) => response,
+			Ok(None) => return,
+			Err(e) => {
+				info!("Failed to write response to {}: {}", src, e);
+				return;
+			}
+		};
+
+		if let Ok(mut session) = self.session.lock() {
+			let sent = write_tcp_message(&mut session.tls.writer(), &res_bytes).and_then(|_| session.flush());
+			if let Err(e) = sent {
+				info!("Failed to send response to {}: {}", src, e);
+			}
+		}
+		timing.stage("send");
+		self.context.record_query(src, request.questions.first(), response.header.rescode, &timing);
+		if let Some(capture) = self.context.capture() {
+			if let Err(e) = capture.record(src, Transport::Tls, &request, &raw_request, &res_bytes) {
+				info!("Failed to capture the query from {}: {}", src, e);
+			}
+		}
+	}
+}
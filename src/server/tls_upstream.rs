@@ -0,0 +1,320 @@
+//! DNS over TLS towards the upstreams (RFC 7858), authenticated as RFC 8310 describes
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::{ SocketAddr, TcpStream };
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rustls::client::danger::{ HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier };
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{ self, CryptoProvider };
+use rustls::pki_types::{ CertificateDer, ServerName, UnixTime };
+use rustls::{ ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme, StreamOwned };
+use sha2::{ Digest, Sha256 };
+
+use crate::server::buffer::VectorPacketBuffer;
+use crate::server::client::DNSClient;
+use crate::server::protocol::DNSPacket;
+
+/// Connections kept open to an upstream for the next queries...
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// What to do when an upstream can't be authenticated (RFC 8310 section 5).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TlsPolicy {
+	/// Give up on the upstream.
+	Strict,
+	/// Go on with an unauthenticated TLS session, or over clear text TCP when TLS can't be
+	/// had at all.
+	Opportunistic,
+}
+
+impl TlsPolicy {
+	pub fn from_name(name: &str) -> Option<TlsPolicy> {
+		match name.to_lowercase().as_str() {
+			"strict" => Some(TlsPolicy::Strict),
+			"opportunistic" => Some(TlsPolicy::Opportunistic),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for TlsPolicy {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			TlsPolicy::Strict => write!(f, "strict"),
+			TlsPolicy::Opportunistic => write!(f, "opportunistic"),
+		}
+	}
+}
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// The TLS side of an upstream: how it's authenticated, and the connections left open to it.
+///
+/// The upstream is authenticated by the SHA-256 digests of its public key (SPKI pins) when
+/// some are given, otherwise by its certificate chaining up to the compiled in web roots
+/// and matching the name it goes by, or its address without a name.
+pub struct TlsUpstream {
+	addr: SocketAddr,
+	name: Option<String>,
+	pins: Vec<[u8; 32]>,
+	policy: TlsPolicy,
+	config: Arc<ClientConfig>,
+	idle: Mutex<Vec<TlsStream>>,
+}
+
+impl TlsUpstream {
+	pub fn new(addr: SocketAddr, name: Option<String>, pins: Vec<[u8; 32]>, policy: TlsPolicy) -> Result<Self> {
+		let config = client_config(&pins, policy, b"dot")?;
+		Ok(Self {
+			addr,
+			name,
+			pins,
+			policy,
+			config,
+			idle: Mutex::new(Vec::new()),
+		})
+	}
+
+	/// Parse the authentication part of an upstream, after the `#` of `tls://ADDR#...`, as
+	/// `parse_auth` does.
+	pub fn parse(addr: SocketAddr, spec: &str, policy: TlsPolicy) -> Result<Self> {
+		let (name, pins) = parse_auth(spec)?;
+		TlsUpstream::new(addr, name, pins, policy)
+	}
+
+	pub fn name(&self) -> Option<&str> {
+		self.name.as_deref()
+	}
+
+	pub fn policy(&self) -> TlsPolicy {
+		self.policy
+	}
+
+	/// The same upstream, with another policy.
+	pub fn with_policy(&self, policy: TlsPolicy) -> Result<Self> {
+		TlsUpstream::new(self.addr, self.name.clone(), self.pins.clone(), policy)
+	}
+
+	/// Send `query` and wait for the response.
+	pub fn exchange(&self, client: &DNSClient, query: &mut DNSPacket) -> Result<DNSPacket> {
+		let mut req_buffer = VectorPacketBuffer::new();
+		query.write(&mut req_buffer)?;
+		client.retry().run(|timeout| self.send(client, req_buffer.as_slice(), query, timeout)).map(|(response, _)| response)
+	}
+
+	/// Send the query `message` as it is, apart from its ID, and return the response as it
+	/// was received.
+	pub fn relay(&self, client: &DNSClient, message: &[u8]) -> Result<Vec<u8>> {
+		let (query, message) = client.renumber(message)?;
+		client.retry().run(|timeout| self.send(client, &message, &query, timeout)).map(|(_, response)| response)
+	}
+
+	/// Send `message`, the wire format of `query`, on a connection left open by an earlier
+	/// query or a new one. Opportunistically, an upstream which can't be reached over TLS is
+	/// asked over TCP instead. Both wait up to `timeout`.
+	fn send(&self, client: &DNSClient, message: &[u8], query: &DNSPacket, timeout: Duration) -> Result<(DNSPacket, Vec<u8>)> {
+		match self.send_tls(client, message, query, timeout) {
+			Err(e) if self.policy == TlsPolicy::Opportunistic => {
+				info!("Falling back to TCP for {}: {}", self.addr, e);
+				let server = SocketAddr::new(self.addr.ip(), 53);
+				let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+				stream.set_read_timeout(Some(timeout))?;
+				stream.set_write_timeout(Some(timeout))?;
+				client.exchange_on(&mut stream, message, query)
+			}
+			result => result,
+		}
+	}
+
+	fn send_tls(&self, client: &DNSClient, message: &[u8], query: &DNSPacket, timeout: Duration) -> Result<(DNSPacket, Vec<u8>)> {
+		// The upstream may have closed a connection which sat idle, the query is sent again
+		// on a new one then...
+		if let Some(mut stream) = self.idle.lock().ok().and_then(|mut idle| idle.pop()) {
+			let timed = stream.sock.set_read_timeout(Some(timeout)).and_then(|_| stream.sock.set_write_timeout(Some(timeout)));
+			if let Ok(response) = timed.and_then(|_| client.exchange_on(&mut stream, message, query)) {
+				self.release(stream);
+				return Ok(response);
+			}
+		}
+
+		let mut stream = self.connect(timeout)?;
+		let response = client.exchange_on(&mut stream, message, query)?;
+		self.release(stream);
+		Ok(response)
+	}
+
+	fn connect(&self, timeout: Duration) -> Result<TlsStream> {
+		let server_name = match self.name {
+			Some(ref name) => ServerName::try_from(name.clone()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?,
+			None => ServerName::IpAddress(self.addr.ip().into()),
+		};
+		let tls = ClientConnection::new(self.config.clone(), server_name).map_err(Error::other)?;
+
+		let socket = TcpStream::connect_timeout(&self.addr, timeout)?;
+		socket.set_read_timeout(Some(timeout))?;
+		socket.set_write_timeout(Some(timeout))?;
+		let mut stream = StreamOwned::new(tls, socket);
+		while stream.conn.is_handshaking() {
+			stream.conn.complete_io(&mut stream.sock)?;
+		}
+		Ok(stream)
+	}
+
+	/// Keep a connection open for the next query, unless enough of them already are.
+	fn release(&self, stream: TlsStream) {
+		if let Ok(mut idle) = self.idle.lock() {
+			if idle.len() < MAX_IDLE_CONNECTIONS {
+				idle.push(stream);
+			}
+		}
+	}
+}
+
+impl fmt::Debug for TlsUpstream {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("TlsUpstream")
+			.field("addr", &self.addr)
+			.field("name", &self.name)
+			.field("pins", &self.pins.len())
+			.field("policy", &self.policy)
+			.finish()
+	}
+}
+
+impl PartialEq for TlsUpstream {
+	fn eq(&self, other: &Self) -> bool {
+		self.addr == other.addr && self.name == other.name && self.pins == other.pins && self.policy == other.policy
+	}
+}
+
+impl Eq for TlsUpstream {}
+// --------------------------------------------------------------------------------------------
+
+/// Parse the authentication of an upstream: its name and SPKI pins given as
+/// `pin-sha256=BASE64`, separated by commas.
+pub fn parse_auth(spec: &str) -> Result<(Option<String>, Vec<[u8; 32]>)> {
+	let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid TLS authentication: {}", spec));
+
+	let mut name = None;
+	let mut pins = Vec::new();
+	for item in spec.split(',').filter(|item| !item.is_empty()) {
+		match item.strip_prefix("pin-sha256=") {
+			Some(pin) => {
+				let pin = BASE64.decode(pin).ok().and_then(|pin| <[u8; 32]>::try_from(pin).ok()).ok_or_else(invalid)?;
+				pins.push(pin);
+			}
+			None => {
+				ServerName::try_from(item).map_err(|_| invalid())?;
+				name = Some(item.to_string());
+			}
+		}
+	}
+	Ok((name, pins))
+}
+
+/// TLS configuration for connecting to an upstream authenticated by `pins`, or by the web
+/// roots without any, negotiating the `alpn` protocol.
+pub fn client_config(pins: &[[u8; 32]], policy: TlsPolicy, alpn: &[u8]) -> Result<Arc<ClientConfig>> {
+	let provider = Arc::new(crypto::ring::default_provider());
+	let roots = Arc::new(RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() });
+	let webpki = WebPkiServerVerifier::builder_with_provider(roots, provider.clone())
+		.build()
+		.map_err(Error::other)?;
+	let verifier = UpstreamVerifier { webpki, pins: pins.to_vec(), policy, provider: provider.clone() };
+
+	let mut config = ClientConfig::builder_with_provider(provider)
+		.with_safe_default_protocol_versions()
+		.map_err(Error::other)?
+		.dangerous()
+		.with_custom_certificate_verifier(Arc::new(verifier))
+		.with_no_client_auth();
+	config.alpn_protocols = vec![alpn.to_vec()];
+	Ok(Arc::new(config))
+}
+
+/// Checks the certificate of an upstream against its pins, or the web roots and its name, and
+/// lets an unauthenticated upstream through under the opportunistic policy. The handshake
+/// signatures are always checked.
+#[derive(Debug)]
+struct UpstreamVerifier {
+	webpki: Arc<WebPkiServerVerifier>,
+	pins: Vec<[u8; 32]>,
+	policy: TlsPolicy,
+	provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for UpstreamVerifier {
+	fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, intermediates: &[CertificateDer<'_>], server_name: &ServerName<'_>, ocsp_response: &[u8], now: UnixTime) -> std::result::Result<ServerCertVerified, rustls::Error> {
+		let verified = if self.pins.is_empty() {
+			self.webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+		} else {
+			match spki_sha256(end_entity) {
+				Some(digest) if self.pins.contains(&digest) => Ok(ServerCertVerified::assertion()),
+				_ => Err(rustls::Error::General("No SPKI pin matches the certificate".to_string())),
+			}
+		};
+
+		match verified {
+			Err(e) if self.policy == TlsPolicy::Opportunistic => {
+				info!("Upstream {} isn't authenticated, going on without: {}", server_name.to_str(), e);
+				Ok(ServerCertVerified::assertion())
+			}
+			verified => verified,
+		}
+	}
+
+	fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+		crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+	}
+
+	fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+		crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.provider.signature_verification_algorithms.supported_schemes()
+	}
+}
+
+/// SHA-256 digest of the SubjectPublicKeyInfo of a DER certificate, as pinned (RFC 7469).
+pub(crate) fn spki_sha256(cert: &[u8]) -> Option<[u8; 32]> {
+	let (header, _) = der_header(cert)?;
+	let tbs = &cert[header..];
+	let (header, _) = der_header(tbs)?;
+	let mut fields = &tbs[header..];
+
+	// The key follows the version, which is optional, the serial number, the signature
+	// algorithm, the issuer, the validity and the subject...
+	let skipped = if fields.first() == Some(&0xa0) { 6 } else { 5 };
+	for _ in 0..skipped {
+		let (header, len) = der_header(fields)?;
+		fields = &fields[header + len..];
+	}
+	let (header, len) = der_header(fields)?;
+	Some(Sha256::digest(&fields[..header + len]).into())
+}
+
+/// Length of the header of the DER element `data` starts with, and of its content.
+fn der_header(data: &[u8]) -> Option<(usize, usize)> {
+	let first = *data.get(1)? as usize;
+	let (header, len) = if first < 0x80 {
+		(2, first)
+	} else {
+		let octets = first & 0x7f;
+		if octets == 0 || octets > 4 {
+			return None;
+		}
+		let len = data.get(2..2 + octets)?.iter().fold(0, |len, &octet| len << 8 | octet as usize);
+		(2 + octets, len)
+	};
+	if data.len() < header + len {
+		return None;
+	}
+	Some((header, len))
+}
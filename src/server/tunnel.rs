@@ -0,0 +1,259 @@
+//! Spotting clients tunneling data through DNS
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{ HashMap, HashSet };
+use std::hash::{ Hash, Hasher };
+use std::net::IpAddr;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+use crate::server::middleware::{ QueryHook, QueryVerdict };
+use crate::server::protocol::{ DNSPacket, DNSRecord, QueryType, TYPE_NULL };
+use crate::server::ratelimit::TokenBuckets;
+
+/// Period over which the queries of a client are scored...
+const WINDOW: Duration = Duration::from_secs(60);
+/// Time a client stays flagged after scoring as a tunnel...
+const PENALTY: Duration = Duration::from_secs(300);
+/// Number of clients tracked before idle ones are dropped...
+const MAX_CLIENTS: usize = 10_000;
+/// Number of parent domains tracked for a client in a window...
+const MAX_PARENTS: usize = 1_000;
+
+/// Levels at which the indicators of a client's queries count toward its score, over a
+/// minute.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TunnelThresholds {
+	/// Distinct names queried under a single parent domain.
+	pub unique_names: usize,
+	/// Responses carrying TXT or NULL records of more than `long_response_bytes` bytes.
+	pub long_responses: u32,
+	pub long_response_bytes: usize,
+	/// Share of the queries for TXT, NULL, CNAME, MX or ANY records, once there were at
+	/// least `min_queries` queries.
+	pub unusual_share: f64,
+	pub min_queries: u32,
+	/// Indicators to meet for the client to be flagged.
+	pub score: u32,
+}
+
+impl Default for TunnelThresholds {
+	fn default() -> Self {
+		TunnelThresholds {
+			unique_names: 50,
+			long_responses: 10,
+			long_response_bytes: 100,
+			unusual_share: 0.5,
+			min_queries: 20,
+			score: 2,
+		}
+	}
+}
+
+/// The queries of a client in the current window.
+#[derive(Debug)]
+struct ClientStream {
+	start: Instant,
+	queries: u32,
+	unusual: u32,
+	long_responses: u32,
+	/// Hashes of the leftmost labels queried, by the parent domain they were queried under...
+	subdomains: HashMap<String, HashSet<u64>>,
+	most_unique: (String, usize),
+	flagged_until: Option<Instant>,
+}
+
+impl ClientStream {
+	fn new(now: Instant) -> Self {
+		ClientStream {
+			start: now,
+			queries: 0,
+			unusual: 0,
+			long_responses: 0,
+			subdomains: HashMap::new(),
+			most_unique: (String::new(), 0),
+			flagged_until: None,
+		}
+	}
+
+	/// Start a new window once the current one is over, keeping the flag.
+	fn roll(&mut self, now: Instant) {
+		if now.duration_since(self.start) >= WINDOW {
+			let flagged_until = self.flagged_until.filter(|until| *until > now);
+			*self = ClientStream { flagged_until, ..ClientStream::new(now) };
+		}
+	}
+
+	fn is_flagged(&self, now: Instant) -> bool {
+		self.flagged_until.is_some_and(|until| until > now)
+	}
+
+	/// The indicators met, described.
+	fn indicators(&self, thresholds: &TunnelThresholds) -> Vec<String> {
+		let mut met = Vec::new();
+		if self.most_unique.1 >= thresholds.unique_names {
+			met.push(format!("{} unique names under {}", self.most_unique.1, self.most_unique.0));
+		}
+		if self.long_responses >= thresholds.long_responses {
+			met.push(format!("{} long TXT/NULL responses", self.long_responses));
+		}
+		if self.queries >= thresholds.min_queries && self.unusual as f64 >= self.queries as f64 * thresholds.unusual_share {
+			met.push(format!("{} of {} queries for TXT/NULL/CNAME/MX/ANY", self.unusual, self.queries));
+		}
+		met
+	}
+}
+
+/// Scores the query stream of every client for signs of DNS tunneling, as an analytics
+/// stage of the query hooks: many distinct names under a single domain, long TXT or NULL
+/// responses, and a mix of query types leaning on those carrying data. Clients meeting
+/// enough of the indicators within a minute are flagged for five minutes, logged and
+/// counted; with a throttle, the queries of flagged clients beyond that many a second are
+/// dropped.
+#[derive(Debug)]
+pub struct TunnelDetector {
+	thresholds: TunnelThresholds,
+	throttle: Option<TokenBuckets>,
+	clients: Mutex<HashMap<IpAddr, ClientStream>>,
+	alerts: AtomicU64,
+	throttled: AtomicU64,
+}
+
+impl TunnelDetector {
+	/// Flag clients by `thresholds`, and hold flagged ones to `throttle` queries a second if
+	/// given.
+	pub fn new(thresholds: TunnelThresholds, throttle: Option<f64>) -> Self {
+		TunnelDetector {
+			thresholds,
+			throttle: throttle.map(|rate| TokenBuckets::new(rate, rate)),
+			clients: Mutex::new(HashMap::new()),
+			alerts: AtomicU64::new(0),
+			throttled: AtomicU64::new(0),
+		}
+	}
+
+	pub fn thresholds(&self) -> &TunnelThresholds {
+		&self.thresholds
+	}
+
+	/// Queries a second flagged clients are held to.
+	pub fn throttle(&self) -> Option<f64> {
+		self.throttle.as_ref().map(TokenBuckets::rate)
+	}
+
+	/// Number of times a client was flagged.
+	pub fn alerts(&self) -> u64 {
+		self.alerts.load(Ordering::Relaxed)
+	}
+
+	/// Number of queries of flagged clients dropped by the throttle.
+	pub fn throttled(&self) -> u64 {
+		self.throttled.load(Ordering::Relaxed)
+	}
+
+	/// The clients flagged at the moment.
+	pub fn flagged(&self) -> Vec<IpAddr> {
+		let now = Instant::now();
+		let clients = match self.clients.lock() {
+			Ok(clients) => clients,
+			Err(poisoned) => poisoned.into_inner(),
+		};
+		clients.iter().filter(|(_, stream)| stream.is_flagged(now)).map(|(client, _)| *client).collect()
+	}
+
+	/// Update the stream of `client` with `update` and flag the client if it now scores as a
+	/// tunnel. Returns whether the client is flagged.
+	fn update<U: FnOnce(&mut ClientStream)>(&self, client: IpAddr, update: U) -> bool {
+		let mut clients = match self.clients.lock() {
+			Ok(clients) => clients,
+			Err(poisoned) => poisoned.into_inner(),
+		};
+
+		let now = Instant::now();
+		if clients.len() >= MAX_CLIENTS {
+			clients.retain(|_, stream| now.duration_since(stream.start) < WINDOW || stream.is_flagged(now));
+		}
+
+		let stream = clients.entry(client).or_insert_with(|| ClientStream::new(now));
+		stream.roll(now);
+		update(stream);
+		if stream.is_flagged(now) {
+			return true;
+		}
+
+		let indicators = stream.indicators(&self.thresholds);
+		if indicators.len() < self.thresholds.score as usize {
+			return false;
+		}
+		stream.flagged_until = Some(now + PENALTY);
+		self.alerts.fetch_add(1, Ordering::Relaxed);
+		info!("Client {} looks like a DNS tunnel: {}", client, indicators.join(", "));
+		true
+	}
+}
+
+impl QueryHook for TunnelDetector {
+	fn name(&self) -> &str {
+		"tunnel-detection"
+	}
+
+	fn check_query(&self, request: &DNSPacket, client: IpAddr) -> QueryVerdict {
+		let question = match request.questions.first() {
+			Some(question) => question,
+			None => return QueryVerdict::Pass,
+		};
+		let unusual = matches!(question.q_type, QueryType::TXT | QueryType::CNAME | QueryType::MX | QueryType::UNKNOWN(TYPE_NULL) | QueryType::UNKNOWN(255));
+		let name = question.name.trim_end_matches('.').to_lowercase();
+		let (label, parent) = name.split_once('.').unwrap_or((&name, ""));
+		let mut hasher = DefaultHasher::new();
+		label.hash(&mut hasher);
+		let label = hasher.finish();
+
+		let unique_names = self.thresholds.unique_names;
+		let flagged = self.update(client, |stream| {
+			stream.queries += 1;
+			stream.unusual += unusual as u32;
+			if !stream.subdomains.contains_key(parent) && stream.subdomains.len() >= MAX_PARENTS {
+				return;
+			}
+			// Names are only told apart up to the threshold, which is all the score needs...
+			let labels = stream.subdomains.entry(parent.to_string()).or_default();
+			if labels.len() < unique_names {
+				labels.insert(label);
+			}
+			if labels.len() > stream.most_unique.1 {
+				stream.most_unique = (parent.to_string(), labels.len());
+			}
+		});
+
+		match self.throttle {
+			Some(ref throttle) if flagged && !throttle.allow(client) => {
+				self.throttled.fetch_add(1, Ordering::Relaxed);
+				QueryVerdict::Drop
+			}
+			_ => QueryVerdict::Pass,
+		}
+	}
+
+	fn observe_response(&self, _request: &DNSPacket, response: &DNSPacket, client: IpAddr) {
+		let data: usize = response.answers.iter()
+			.map(|record| match record {
+				DNSRecord::TXT { data, .. } => data.iter().map(Vec::len).sum(),
+				DNSRecord::UNKNOWN { q_type: TYPE_NULL, data, .. } => data.len(),
+				_ => 0,
+			})
+			.sum();
+		if data > self.thresholds.long_response_bytes {
+			self.update(client, |stream| stream.long_responses += 1);
+		}
+	}
+
+	fn counters(&self) -> Vec<(String, u64)> {
+		vec![
+			("alerts".to_string(), self.alerts()),
+			("flagged clients".to_string(), self.flagged().len() as u64),
+			("throttled".to_string(), self.throttled()),
+		]
+	}
+}
@@ -0,0 +1,321 @@
+use std::collections::VecDeque;
+use std::io::Result;
+use std::net::{ SocketAddr, UdpSocket };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Arc, Condvar, Mutex, RwLock };
+use std::thread::{ self, JoinHandle };
+use std::time::Instant;
+
+#[cfg(unix)]
+use socket2::{ Domain, Protocol, Socket, Type };
+
+use crate::server::context::{ Listener, ServerContext, ServerRole };
+use crate::server::handler::{ encode, handle_request, parse_request };
+use crate::server::latency::QueryTiming;
+use crate::server::protocol::{ DNSPacket, DNSRecord, QueryType };
+use crate::server::resolve::{ DynamicResolvers, ResolverFactory };
+use crate::server::rrl::RrlVerdict;
+use crate::server::upstream::Transport;
+
+/// Largest response sent over UDP to clients which don't advertise a size with EDNS...
+const MAX_UDP_RESPONSE: usize = 512;
+/// Requests a socket queues up for each of its workers, those coming in beyond them being
+/// dropped until the workers catch up...
+const MAX_QUEUED_PER_WORKER: usize = 256;
+
+/// Counters of one socket of a UDP listener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UdpSocketStats {
+	pub addr: SocketAddr,
+	/// Index of the socket among those of the listener...
+	pub socket: usize,
+	pub received: u64,
+	/// Requests dropped as they couldn't be parsed...
+	pub malformed: u64,
+	/// Requests dropped as the queue of the workers was full...
+	pub overflowed: u64,
+	pub sent: u64,
+	pub send_errors: u64,
+}
+
+/// The counters of a socket, as its threads update them.
+#[derive(Debug, Default)]
+struct SocketCounters {
+	received: AtomicU64,
+	malformed: AtomicU64,
+	overflowed: AtomicU64,
+	sent: AtomicU64,
+	send_errors: AtomicU64,
+}
+
+/// Counters of the sockets of every UDP listener of the server.
+#[derive(Debug, Default)]
+pub struct UdpStats {
+	sockets: RwLock<Vec<(SocketAddr, usize, Arc<SocketCounters>)>>,
+}
+
+impl UdpStats {
+	pub fn new() -> Self {
+		UdpStats::default()
+	}
+
+	fn register(&self, addr: SocketAddr, socket: usize) -> Arc<SocketCounters> {
+		let counters = Arc::new(SocketCounters::default());
+		if let Ok(mut sockets) = self.sockets.write() {
+			sockets.push((addr, socket, counters.clone()));
+		}
+		counters
+	}
+
+	/// The counters of every socket, in the order they were bound.
+	pub fn sockets(&self) -> Vec<UdpSocketStats> {
+		let sockets = match self.sockets.read() {
+			Ok(sockets) => sockets,
+			Err(_) => return Vec::new(),
+		};
+		sockets.iter()
+			.map(|(addr, socket, counters)| UdpSocketStats {
+				addr: *addr,
+				socket: *socket,
+				received: counters.received.load(Ordering::Relaxed),
+				malformed: counters.malformed.load(Ordering::Relaxed),
+				overflowed: counters.overflowed.load(Ordering::Relaxed),
+				sent: counters.sent.load(Ordering::Relaxed),
+				send_errors: counters.send_errors.load(Ordering::Relaxed),
+			})
+			.collect()
+	}
+}
+
+/// A request waiting for a worker: its source, its raw bytes and when it was received.
+type QueuedRequest = (SocketAddr, Vec<u8>, Instant);
+
+/// UDP listener. One thread receives the requests and queues them up for a pool of worker
+/// threads, which parse and resolve them and send the responses back on the shared socket,
+/// the receiving thread never touching what a request holds. The
+/// listener may bind several sockets to spread the load over more cores, see `run_server`.
+/// The queue of a socket is bounded, requests coming in while it's full being dropped.
+///
+/// The resolvers come from `F`, by default those configured in the context. The server
+/// listens on the listen address of the context in the full role unless told otherwise.
+pub struct DNSUdpServer<F: ResolverFactory = DynamicResolvers> {
+	context: Arc<ServerContext>,
+	resolvers: Arc<F>,
+	addr: SocketAddr,
+	role: ServerRole,
+}
+
+impl DNSUdpServer {
+	pub fn new(context: Arc<ServerContext>) -> Self {
+		DNSUdpServer::with_resolvers(context, DynamicResolvers)
+	}
+}
+
+impl<F: ResolverFactory> DNSUdpServer<F> {
+	pub fn with_resolvers(context: Arc<ServerContext>, resolvers: F) -> Self {
+		Self {
+			addr: context.listen_addr,
+			role: ServerRole::Full,
+			context,
+			resolvers: Arc::new(resolvers),
+		}
+	}
+
+	/// Listen on `addr` in `role` instead.
+	pub fn with_listener(mut self, addr: SocketAddr, role: ServerRole) -> Self {
+		self.addr = addr;
+		self.role = role;
+		self
+	}
+
+	/// Bind the sockets and start the threads. The returned handle belongs to the receiving
+	/// thread of the first socket, which runs for as long as the socket does.
+	///
+	/// With more than one socket in `udp_sockets`, all of them are bound to the address with
+	/// SO_REUSEPORT for the kernel to balance the queries between them, and each has a
+	/// receiving thread and a share of the worker threads of its own.
+	pub fn run_server(self) -> Result<JoinHandle<()>> {
+		let sockets = self.context.udp_sockets.max(1);
+		let workers = self.context.worker_threads.max(1).div_ceil(sockets);
+
+		let first = if sockets == 1 { UdpSocket::bind(self.addr)? } else { bind_reuse_port(self.addr)? };
+		// The other sockets go to the port the first got, if it was left to the system...
+		let addr = first.local_addr()?;
+		let handle = self.serve_socket(first, 0, workers)?;
+		for index in 1..sockets {
+			self.serve_socket(bind_reuse_port(addr)?, index, workers)?;
+		}
+		Ok(handle)
+	}
+
+	/// Start the receiving thread of a socket and its `workers` worker threads, which
+	/// resolve the requests and send the responses back on the socket.
+	fn serve_socket(&self, socket: UdpSocket, index: usize, workers: usize) -> Result<JoinHandle<()>> {
+		let counters = self.context.udp_stats.register(socket.local_addr()?, index);
+		let request_queue: Arc<Mutex<VecDeque<QueuedRequest>>> = Arc::new(Mutex::new(VecDeque::new()));
+		let request_cond = Arc::new(Condvar::new());
+		let max_queued = workers * MAX_QUEUED_PER_WORKER;
+
+		for worker in 0..workers {
+			let socket = socket.try_clone()?;
+			let context = self.context.clone();
+			let resolvers = self.resolvers.clone();
+			let role = self.role;
+			let queue = request_queue.clone();
+			let cond = request_cond.clone();
+			let counters = counters.clone();
+
+			thread::Builder::new()
+				.name(format!("DNSUdpServer-{}-worker-{}", index, worker))
+				.spawn(move || loop {
+					let (src, raw_request, received) = {
+						let mut queue = match queue.lock() {
+							Ok(queue) => queue,
+							Err(_) => return,
+						};
+						loop {
+							if let Some(item) = queue.pop_front() {
+								break item;
+							}
+							queue = match cond.wait(queue) {
+								Ok(queue) => queue,
+								Err(_) => return,
+							};
+						}
+					};
+
+					let request = match parse_request(&context, &raw_request) {
+						Ok(request) => request,
+						Err(e) => {
+							info!("Failed to parse UDP query packet from {}: {}", src, e);
+							counters.malformed.fetch_add(1, Ordering::Relaxed);
+							continue;
+						}
+					};
+
+					let mut timing = QueryTiming::new(received);
+					timing.stage("queue");
+					let (mut response, mut res_bytes) = match handle_request(&context, &resolvers, Listener { role, transport: Transport::Udp }, &request, &raw_request, src, &mut timing) {
+						Ok(Some(response)) => response,
+						Ok(None) => continue,
+						Err(e) => {
+							info!("Failed to write response to {}: {}", src, e);
+							continue;
+						}
+					};
+
+					// Responses larger than the client takes lose RRsets from the tail, and those the
+					// amplification guard won't let through are stripped down; either way TC sends
+					// the client over to TCP...
+					let limit = udp_payload_size(&request, context.edns_max_payload());
+					if res_bytes.len() > limit {
+						res_bytes = match response.truncate_to(limit, context.compresses_for(src.ip())).and_then(|_| encode(&context, src.ip(), &mut response)) {
+							Ok(bytes) => bytes,
+							Err(e) => {
+								info!("Failed to write response to {}: {}", src, e);
+								continue;
+							}
+						};
+					}
+					let refused = context.amplification.as_ref()
+						.is_some_and(|guard| !guard.allow(src.ip(), raw_request.len(), res_bytes.len()));
+					// Authoritative answers over their rate are dropped, or slipped as truncated...
+					let verdict = context.rrl.as_ref()
+						.filter(|_| role == ServerRole::Authoritative || response.header.authoritative_answer)
+						.map_or(RrlVerdict::Send, |rrl| rrl.check(src.ip(), &response));
+					if verdict == RrlVerdict::Drop {
+						continue;
+					}
+					if refused || verdict == RrlVerdict::Slip {
+						truncate(&mut response);
+						res_bytes = match encode(&context, src.ip(), &mut response) {
+							Ok(bytes) => bytes,
+							Err(e) => {
+								info!("Failed to write response to {}: {}", src, e);
+								continue;
+							}
+						};
+					}
+					match socket.send_to(&res_bytes, src) {
+						Ok(_) => counters.sent.fetch_add(1, Ordering::Relaxed),
+						Err(e) => {
+							info!("Failed to send response to {}: {}", src, e);
+							counters.send_errors.fetch_add(1, Ordering::Relaxed)
+						}
+					};
+					timing.stage("send");
+					context.record_query(src, request.questions.first(), response.header.rescode, &timing);
+					if let Some(capture) = context.capture() {
+						if let Err(e) = capture.record(src, Transport::Udp, &request, &raw_request, &res_bytes) {
+							info!("Failed to capture the query from {}: {}", src, e);
+						}
+					}
+				})?;
+		}
+
+		// Queries using EDNS may be as large as the payloads taken...
+		let mut buf = vec![0; self.context.edns_max_payload() as usize];
+		let handle = thread::Builder::new()
+			.name(format!("DNSUdpServer-{}-incoming", index))
+			.spawn(move || {
+				loop {
+					let (len, src) = match socket.recv_from(&mut buf) {
+						Ok(received) => received,
+						Err(e) => {
+							info!("Failed to read from UDP socket: {}", e);
+							continue;
+						}
+					};
+					let received = Instant::now();
+					counters.received.fetch_add(1, Ordering::Relaxed);
+
+					let raw_request = buf[..len].to_vec();
+					if let Ok(mut queue) = request_queue.lock() {
+						// Clients get no answer when the workers fall behind, and retry...
+						if queue.len() >= max_queued {
+							counters.overflowed.fetch_add(1, Ordering::Relaxed);
+							continue;
+						}
+						queue.push_back((src, raw_request, received));
+						request_cond.notify_one();
+					}
+				}
+			})?;
+
+		Ok(handle)
+	}
+}
+
+/// Bind a socket to `addr` which other sockets may be bound to as well, the kernel balancing
+/// the datagrams between them.
+#[cfg(unix)]
+fn bind_reuse_port(addr: SocketAddr) -> Result<UdpSocket> {
+	let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+	socket.set_reuse_port(true)?;
+	socket.bind(&addr.into())?;
+	Ok(socket.into())
+}
+
+#[cfg(not(unix))]
+fn bind_reuse_port(_addr: SocketAddr) -> Result<UdpSocket> {
+	Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Several UDP sockets on the same address need SO_REUSEPORT"))
+}
+
+/// Largest response `request` takes over UDP: the payload size of its OPT record kept to
+/// `max_payload`, or 512 bytes without one. Sizes below 512 are treated as 512 (RFC 6891
+/// section 6.2.5).
+fn udp_payload_size(request: &DNSPacket, max_payload: u16) -> usize {
+	match request.edns() {
+		Some(DNSRecord::OPT { packet_len, .. }) => (*packet_len.min(&max_payload) as usize).max(MAX_UDP_RESPONSE),
+		_ => MAX_UDP_RESPONSE,
+	}
+}
+
+/// Strip a response down to its question and OPT record, with TC set to send the client
+/// over to TCP.
+fn truncate(response: &mut DNSPacket) {
+	response.header.truncated_message = true;
+	response.answers.clear();
+	response.authorities.clear();
+	response.additional.retain(|record| record.get_query_type() == QueryType::OPT);
+}
@@ -0,0 +1,503 @@
+//! Upstream servers the forwarding resolver sends its queries to
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{ Hash, Hasher };
+use std::io::{ Error, ErrorKind, Result };
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::atomic::{ AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering };
+use std::sync::{ Arc, Weak };
+use std::thread::{ self, JoinHandle };
+use std::time::{ Duration, Instant };
+
+use rand::Rng;
+use tracing::debug_span;
+
+use crate::server::client::DNSClient;
+use crate::server::doh::DEFAULT_HTTPS_PATH;
+use crate::server::https_upstream::HttpsUpstream;
+use crate::server::quic_upstream::QuicUpstream;
+use crate::server::protocol::{ DNSPacket, QueryType, ResultCode };
+use crate::server::tls_upstream::{ parse_auth, TlsPolicy, TlsUpstream };
+
+/// How queries are carried to an upstream.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Transport {
+	Udp,
+	Tcp,
+	Tls,
+	Https,
+	Quic,
+}
+
+impl Transport {
+	pub fn from_name(name: &str) -> Option<Transport> {
+		match name.to_lowercase().as_str() {
+			"udp" => Some(Transport::Udp),
+			"tcp" => Some(Transport::Tcp),
+			"tls" => Some(Transport::Tls),
+			"https" => Some(Transport::Https),
+			"quic" => Some(Transport::Quic),
+			_ => None,
+		}
+	}
+
+	pub fn default_port(&self) -> u16 {
+		match *self {
+			Transport::Udp | Transport::Tcp => 53,
+			Transport::Tls | Transport::Quic => 853,
+			Transport::Https => 443,
+		}
+	}
+}
+
+impl fmt::Display for Transport {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Transport::Udp => write!(f, "udp"),
+			Transport::Tcp => write!(f, "tcp"),
+			Transport::Tls => write!(f, "tls"),
+			Transport::Https => write!(f, "https"),
+			Transport::Quic => write!(f, "quic"),
+		}
+	}
+}
+// --------------------------------------------------------------------------------------------
+
+/// A single upstream server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upstream {
+	pub addr: SocketAddr,
+	pub transport: Transport,
+	/// Authentication and connections of a TLS upstream...
+	tls: Option<Arc<TlsUpstream>>,
+	/// Authentication, path and connection of an HTTPS upstream...
+	https: Option<Arc<HttpsUpstream>>,
+	/// Authentication and connection of a QUIC upstream...
+	quic: Option<Arc<QuicUpstream>>,
+}
+
+impl Upstream {
+	/// A TLS, HTTPS or QUIC upstream created this way is authenticated by its address and
+	/// HTTPS queries are posted to the default path, `parse` takes a name or pins and a path.
+	pub fn new(addr: SocketAddr, transport: Transport) -> Self {
+		let tls = match transport {
+			Transport::Tls => TlsUpstream::new(addr, None, Vec::new(), TlsPolicy::Strict).ok().map(Arc::new),
+			_ => None,
+		};
+		let https = match transport {
+			Transport::Https => HttpsUpstream::new(addr, None, DEFAULT_HTTPS_PATH.to_string(), Vec::new(), TlsPolicy::Strict).ok().map(Arc::new),
+			_ => None,
+		};
+		let quic = match transport {
+			Transport::Quic => QuicUpstream::new(addr, None, Vec::new(), TlsPolicy::Strict).ok().map(Arc::new),
+			_ => None,
+		};
+		Self { addr, transport, tls, https, quic }
+	}
+
+	/// Parse an upstream given as `[transport://]address[:port][/path][#auth]`, e.g.
+	/// `9.9.9.9`, `tcp://1.1.1.1`, `udp://[2620:fe::fe]:53`, `tls://9.9.9.9#dns.quad9.net`,
+	/// `https://1.1.1.1/dns-query#cloudflare-dns.com` or `quic://94.140.14.14#dns.adguard.com`.
+	/// UDP is used if no transport is given. Only HTTPS upstreams take a path, `/dns-query` by
+	/// default. The `#auth` of TLS, HTTPS and QUIC upstreams is described by `parse_auth`.
+	pub fn parse(spec: &str) -> Result<Upstream> {
+		let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid upstream: {}", spec));
+
+		let (transport, addr) = match spec.split_once("://") {
+			Some((scheme, addr)) => (Transport::from_name(scheme).ok_or_else(invalid)?, addr),
+			None => (Transport::Udp, spec),
+		};
+		let (addr, auth) = match addr.split_once('#') {
+			Some((addr, auth)) if matches!(transport, Transport::Tls | Transport::Https | Transport::Quic) => (addr, Some(auth)),
+			Some(_) => return Err(invalid()),
+			None => (addr, None),
+		};
+		let (addr, path) = match addr.find('/') {
+			Some(start) if transport == Transport::Https => (&addr[..start], Some(&addr[start..])),
+			Some(_) => return Err(invalid()),
+			None => (addr, None),
+		};
+
+		let addr = match addr.parse::<SocketAddr>() {
+			Ok(addr) => addr,
+			Err(_) => {
+				let ip = addr.trim_start_matches('[').trim_end_matches(']');
+				let ip = ip.parse().map_err(|_| invalid())?;
+				SocketAddr::new(ip, transport.default_port())
+			}
+		};
+		match (transport, auth) {
+			(Transport::Https, _) => {
+				let (name, pins) = parse_auth(auth.unwrap_or(""))?;
+				let path = path.unwrap_or(DEFAULT_HTTPS_PATH).to_string();
+				let https = HttpsUpstream::new(addr, name, path, pins, TlsPolicy::Strict)?;
+				Ok(Upstream { addr, transport, tls: None, https: Some(Arc::new(https)), quic: None })
+			}
+			(Transport::Quic, Some(auth)) => {
+				let (name, pins) = parse_auth(auth)?;
+				let quic = QuicUpstream::new(addr, name, pins, TlsPolicy::Strict)?;
+				Ok(Upstream { addr, transport, tls: None, https: None, quic: Some(Arc::new(quic)) })
+			}
+			(_, Some(auth)) => {
+				let tls = TlsUpstream::parse(addr, auth, TlsPolicy::Strict)?;
+				Ok(Upstream { addr, transport, tls: Some(Arc::new(tls)), https: None, quic: None })
+			}
+			(_, None) => Ok(Upstream::new(addr, transport)),
+		}
+	}
+
+	/// The same upstream, going on without authentication under the opportunistic policy
+	/// when it's a TLS, HTTPS or QUIC one.
+	pub fn with_tls_policy(self, policy: TlsPolicy) -> Result<Upstream> {
+		let tls = match self.tls {
+			Some(ref tls) if tls.policy() != policy => Some(Arc::new(tls.with_policy(policy)?)),
+			tls => tls,
+		};
+		let https = match self.https {
+			Some(ref https) if https.policy() != policy => Some(Arc::new(https.with_policy(policy)?)),
+			https => https,
+		};
+		let quic = match self.quic {
+			Some(ref quic) if quic.policy() != policy => Some(Arc::new(quic.with_policy(policy)?)),
+			quic => quic,
+		};
+		Ok(Upstream { tls, https, quic, ..self })
+	}
+
+	/// Send `query` to this upstream and wait for the response.
+	pub fn exchange(&self, client: &DNSClient, query: &mut DNSPacket) -> Result<DNSPacket> {
+		let _span = debug_span!("upstream", upstream = %self).entered();
+		match self.transport {
+			Transport::Udp => client.exchange(query, self.addr),
+			Transport::Tcp => client.exchange_tcp(query, self.addr),
+			Transport::Tls => self.tls()?.exchange(client, query),
+			Transport::Https => self.https()?.exchange(client, query),
+			Transport::Quic => self.quic()?.exchange(client, query),
+		}
+	}
+
+	/// Send the query `message` to this upstream as it is, apart from its ID, and return the
+	/// response as it was received.
+	pub fn relay(&self, client: &DNSClient, message: &[u8]) -> Result<Vec<u8>> {
+		let _span = debug_span!("upstream", upstream = %self).entered();
+		match self.transport {
+			Transport::Udp | Transport::Tcp => client.exchange_raw(message, self.addr, self.transport == Transport::Tcp),
+			Transport::Tls => self.tls()?.relay(client, message),
+			Transport::Https => self.https()?.relay(client, message),
+			Transport::Quic => self.quic()?.relay(client, message),
+		}
+	}
+
+	fn tls(&self) -> Result<&TlsUpstream> {
+		self.tls.as_deref().ok_or_else(|| Error::other(format!("No TLS configuration for {}", self)))
+	}
+
+	fn https(&self) -> Result<&HttpsUpstream> {
+		self.https.as_deref().ok_or_else(|| Error::other(format!("No HTTPS configuration for {}", self)))
+	}
+
+	fn quic(&self) -> Result<&QuicUpstream> {
+		self.quic.as_deref().ok_or_else(|| Error::other(format!("No QUIC configuration for {}", self)))
+	}
+}
+
+impl fmt::Display for Upstream {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}://{}", self.transport, self.addr)?;
+		if let Some(ref https) = self.https {
+			write!(f, "{}", https.path())?;
+		}
+		let name = self.tls.as_ref().and_then(|tls| tls.name())
+			.or_else(|| self.https.as_ref().and_then(|https| https.name()))
+			.or_else(|| self.quic.as_ref().and_then(|quic| quic.name()));
+		match name {
+			Some(name) => write!(f, "#{}", name),
+			None => Ok(()),
+		}
+	}
+}
+// --------------------------------------------------------------------------------------------
+
+/// How the pool picks the upstream to try first. Whichever it is, the others are tried in
+/// turn when it fails.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SelectionStrategy {
+	/// Always start with the first upstream.
+	Failover,
+	/// Spread the queries evenly by starting at the next upstream for each query.
+	RoundRobin,
+	/// Start at a random upstream.
+	Random,
+	/// Prefer the upstream with the lowest smoothed round trip time, picking randomly among
+	/// those within a band of the fastest the way Unbound does, so that the others still get
+	/// measured. Upstreams without a measurement yet are tried first.
+	Fastest,
+	/// Send the queries of a client to the same upstream, chosen by hashing its address.
+	Sticky,
+}
+
+impl SelectionStrategy {
+	pub fn from_name(name: &str) -> Option<SelectionStrategy> {
+		match name.to_lowercase().replace('_', "-").as_str() {
+			"failover" => Some(SelectionStrategy::Failover),
+			"round-robin" => Some(SelectionStrategy::RoundRobin),
+			"random" => Some(SelectionStrategy::Random),
+			"fastest" | "srtt" => Some(SelectionStrategy::Fastest),
+			"sticky" => Some(SelectionStrategy::Sticky),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for SelectionStrategy {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			SelectionStrategy::Failover => write!(f, "failover"),
+			SelectionStrategy::RoundRobin => write!(f, "round-robin"),
+			SelectionStrategy::Random => write!(f, "random"),
+			SelectionStrategy::Fastest => write!(f, "fastest"),
+			SelectionStrategy::Sticky => write!(f, "sticky"),
+		}
+	}
+}
+// --------------------------------------------------------------------------------------------
+
+/// Consecutive failures after which an upstream is considered down...
+const FAILURE_THRESHOLD: u32 = 3;
+
+/// Upstreams whose SRTT is within this many microseconds of the fastest are picked from at
+/// random by the `Fastest` strategy (Unbound uses the same 400 msec band)...
+const RTT_BAND: u64 = 400_000;
+
+/// Cap on the SRTT, which doubles on every failure...
+const MAX_SRTT: u64 = 120_000_000;
+
+/// Default time between two rounds of health probes.
+pub const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Health of an upstream, updated by the probes and by the queries forwarded to it.
+#[derive(Debug, Default)]
+struct HealthState {
+	failures: AtomicU32,
+	down: AtomicBool,
+	// Round trip time of the last successful exchange in microseconds, 0 if unknown...
+	last_rtt: AtomicU64,
+	// Smoothed round trip time in microseconds, 0 if unknown...
+	srtt: AtomicU64,
+}
+
+/// Snapshot of the health of an upstream, e.g. for reporting.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamHealth {
+	pub upstream: Upstream,
+	pub up: bool,
+	pub consecutive_failures: u32,
+	pub last_rtt: Option<Duration>,
+	pub srtt: Option<Duration>,
+}
+
+/// The set of upstreams queries get forwarded to. Each query is tried against the upstreams
+/// in the order the strategy gives until one of them answers, with upstreams which are down
+/// only tried after all the healthy ones.
+///
+/// An upstream goes down after a few consecutive failures, of forwarded queries or of the
+/// health probes, and comes back up as soon as it answers again.
+#[derive(Debug)]
+pub struct UpstreamPool {
+	upstreams: Vec<Upstream>,
+	health: Vec<HealthState>,
+	strategy: SelectionStrategy,
+	next: AtomicUsize,
+}
+
+impl UpstreamPool {
+	pub fn new(upstreams: Vec<Upstream>, strategy: SelectionStrategy) -> Self {
+		let health = upstreams.iter().map(|_| HealthState::default()).collect();
+		Self {
+			upstreams,
+			health,
+			strategy,
+			next: AtomicUsize::new(0),
+		}
+	}
+
+	pub fn upstreams(&self) -> &[Upstream] {
+		&self.upstreams
+	}
+
+	pub fn strategy(&self) -> SelectionStrategy {
+		self.strategy
+	}
+
+	/// Current health of every upstream.
+	pub fn health(&self) -> Vec<UpstreamHealth> {
+		self.upstreams.iter().zip(&self.health)
+			.map(|(upstream, state)| {
+				let micros = |value: &AtomicU64| match value.load(Ordering::Relaxed) {
+					0 => None,
+					us => Some(Duration::from_micros(us)),
+				};
+				UpstreamHealth {
+					upstream: upstream.clone(),
+					up: !state.down.load(Ordering::Relaxed),
+					consecutive_failures: state.failures.load(Ordering::Relaxed),
+					last_rtt: micros(&state.last_rtt),
+					srtt: micros(&state.srtt),
+				}
+			})
+			.collect()
+	}
+
+	/// Indices of the upstreams in the order they should be tried for a query from `client`.
+	fn order_indices(&self, client: Option<IpAddr>) -> Vec<usize> {
+		let len = self.upstreams.len();
+		if len == 0 {
+			return Vec::new();
+		}
+
+		let order: Vec<usize> = match self.strategy {
+			SelectionStrategy::Failover => (0..len).collect(),
+			SelectionStrategy::RoundRobin => {
+				let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+				(0..len).map(|i| (start + i) % len).collect()
+			}
+			SelectionStrategy::Random => {
+				let start = rand::thread_rng().gen_range(0..len);
+				(0..len).map(|i| (start + i) % len).collect()
+			}
+			SelectionStrategy::Sticky => {
+				let mut hasher = DefaultHasher::new();
+				client.hash(&mut hasher);
+				let start = (hasher.finish() % len as u64) as usize;
+				(0..len).map(|i| (start + i) % len).collect()
+			}
+			SelectionStrategy::Fastest => self.fastest_order(),
+		};
+
+		let (mut up, down): (Vec<usize>, Vec<usize>) = order.into_iter()
+			.partition(|&idx| !self.health[idx].down.load(Ordering::Relaxed));
+		up.extend(down);
+		up
+	}
+
+	/// Upstreams sorted by SRTT, with a random pick among those in the band of the fastest
+	/// moved to the front.
+	fn fastest_order(&self) -> Vec<usize> {
+		let mut order: Vec<usize> = (0..self.upstreams.len()).collect();
+		let srtt = |idx: usize| self.health[idx].srtt.load(Ordering::Relaxed);
+		order.sort_by_key(|&idx| srtt(idx));
+
+		let best = srtt(order[0]);
+		let in_band = order.iter().take_while(|&&idx| srtt(idx) <= best + RTT_BAND).count();
+		let pick = rand::thread_rng().gen_range(0..in_band);
+		let first = order.remove(pick);
+		order.insert(0, first);
+		order
+	}
+
+	/// The upstreams in the order they should be tried for the next query from `client`.
+	pub fn order(&self, client: Option<IpAddr>) -> Vec<&Upstream> {
+		self.order_indices(client).into_iter().map(|idx| &self.upstreams[idx]).collect()
+	}
+
+	fn record_success(&self, idx: usize, rtt: Duration) {
+		let state = &self.health[idx];
+		state.failures.store(0, Ordering::Relaxed);
+		let rtt = (rtt.as_micros() as u64).max(1);
+		state.last_rtt.store(rtt, Ordering::Relaxed);
+		// Exponentially weighted like TCP's SRTT, new samples weighing in at 1/8...
+		let srtt = match state.srtt.load(Ordering::Relaxed) {
+			0 => rtt,
+			srtt => (srtt * 7 + rtt) / 8,
+		};
+		state.srtt.store(srtt.max(1), Ordering::Relaxed);
+		if state.down.swap(false, Ordering::Relaxed) {
+			info!("Upstream {} is back up", self.upstreams[idx]);
+		}
+	}
+
+	fn record_failure(&self, idx: usize) {
+		let state = &self.health[idx];
+		let failures = state.failures.fetch_add(1, Ordering::Relaxed) + 1;
+		// Back off like a retransmission timeout, so slow or dead upstreams sort last...
+		let srtt = state.srtt.load(Ordering::Relaxed).max(RTT_BAND);
+		state.srtt.store((srtt * 2).min(MAX_SRTT), Ordering::Relaxed);
+		if failures >= FAILURE_THRESHOLD && !state.down.swap(true, Ordering::Relaxed) {
+			info!("Upstream {} is down after {} failures", self.upstreams[idx], failures);
+		}
+	}
+
+	/// Send `query` on behalf of `source`, the client which asked it, to the upstreams until
+	/// one answers. Returns the last error if none did.
+	pub fn exchange(&self, client: &DNSClient, query: &mut DNSPacket, source: Option<IpAddr>) -> Result<DNSPacket> {
+		self.exchange_with_upstream(client, query, source).map(|(response, _)| response)
+	}
+
+	/// Like `exchange`, also returning the upstream which answered.
+	pub fn exchange_with_upstream(&self, client: &DNSClient, query: &mut DNSPacket, source: Option<IpAddr>) -> Result<(DNSPacket, &Upstream)> {
+		self.try_upstreams(source, |upstream| upstream.exchange(client, query))
+	}
+
+	/// Relay the query `message` of `source` to the upstreams until one answers, returning
+	/// the response as it was received and the upstream which sent it.
+	pub fn relay(&self, client: &DNSClient, message: &[u8], source: Option<IpAddr>) -> Result<(Vec<u8>, &Upstream)> {
+		self.try_upstreams(source, |upstream| upstream.relay(client, message))
+	}
+
+	/// Run `exchange` with the upstreams in the order picked for `source` until it succeeds,
+	/// keeping their health up to date. Returns the last error if it never did.
+	fn try_upstreams<T, F>(&self, source: Option<IpAddr>, mut exchange: F) -> Result<(T, &Upstream)>
+		where F: FnMut(&Upstream) -> Result<T>
+	{
+		let mut last_err = Error::other("No upstream servers configured");
+		for idx in self.order_indices(source) {
+			let upstream = &self.upstreams[idx];
+			let start = Instant::now();
+			match exchange(upstream) {
+				Ok(response) => {
+					self.record_success(idx, start.elapsed());
+					return Ok((response, upstream));
+				}
+				Err(e) => {
+					self.record_failure(idx);
+					last_err = Error::new(e.kind(), format!("{}: {}", upstream, e));
+				}
+			}
+		}
+		Err(last_err)
+	}
+
+	/// Probe every upstream once with a query for the root NS set. An upstream passes if it
+	/// answers with NOERROR or NXDOMAIN.
+	pub fn check_health(&self, client: &DNSClient) {
+		for (idx, upstream) in self.upstreams.iter().enumerate() {
+			let mut probe = client.build_query("", QueryType::NS, true);
+			let start = Instant::now();
+			match upstream.exchange(client, &mut probe) {
+				Ok(ref response) if matches!(response.header.rescode, ResultCode::NOERROR | ResultCode::NXDOMAIN) => {
+					self.record_success(idx, start.elapsed());
+				}
+				_ => self.record_failure(idx),
+			}
+		}
+	}
+
+	/// Probe the upstreams every `interval` on a thread of its own, which ends once the pool
+	/// is dropped.
+	pub fn start_health_checks(self: &Arc<Self>, interval: Duration) -> Result<JoinHandle<()>> {
+		let pool: Weak<UpstreamPool> = Arc::downgrade(self);
+		thread::Builder::new()
+			.name("upstream-health".to_string())
+			.spawn(move || {
+				let client = DNSClient::new();
+				loop {
+					thread::sleep(interval);
+					match pool.upgrade() {
+						Some(pool) => pool.check_health(&client),
+						None => break,
+					}
+				}
+			})
+	}
+}
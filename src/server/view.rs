@@ -0,0 +1,167 @@
+//! Split-horizon views: clients told apart by their address, each answered from zones, hosts
+//! and upstreams of their own
+
+use std::fmt;
+use std::io::{ Error, ErrorKind, Result };
+use std::net::IpAddr;
+use std::sync::{ Arc, RwLock };
+
+use crate::server::authority::{ Authority, SharedZone };
+use crate::server::cache::Cache;
+use crate::server::capture::{ in_prefix, parse_prefix };
+use crate::server::hosts::HostOverrides;
+use crate::server::upstream::{ SelectionStrategy, Upstream, UpstreamPool };
+
+/// A view of the names for the clients of some networks, say the internal addresses of the
+/// servers of a company for the clients of its LAN. Clients of the view are answered from its
+/// zones and hosts first, which shadow those of the server, and have the other names forwarded
+/// to its upstreams, or resolved the way the server does without any.
+///
+/// The answers of the upstreams of a view are cached apart, for none to leak to the clients of
+/// the other views, in a cache of the default size.
+pub struct View {
+	name: String,
+	networks: Vec<(IpAddr, u8)>,
+	authority: Authority,
+	hosts: HostOverrides,
+	upstreams: Vec<Upstream>,
+	strategy: Option<SelectionStrategy>,
+	pool: Option<Arc<UpstreamPool>>,
+	cache: Cache,
+}
+
+impl View {
+	pub fn new(name: &str) -> Self {
+		View {
+			name: name.to_string(),
+			networks: Vec::new(),
+			authority: Authority::new(),
+			hosts: HostOverrides::new(),
+			upstreams: Vec::new(),
+			strategy: None,
+			pool: None,
+			cache: Cache::new(),
+		}
+	}
+
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// Count the clients of `network`, given as `ADDR` or `ADDR/LEN`, in the view.
+	pub fn add_network(&mut self, network: &str) -> Result<()> {
+		let network = parse_prefix(network)
+			.ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Invalid network: {}", network)))?;
+		self.networks.push(network);
+		Ok(())
+	}
+
+	pub fn networks(&self) -> &[(IpAddr, u8)] {
+		&self.networks
+	}
+
+	/// Answer the clients of the view for `zone`.
+	pub fn add_zone(&self, zone: SharedZone) {
+		self.authority.add_zone(zone);
+	}
+
+	/// The zones of the view.
+	pub fn authority(&self) -> &Authority {
+		&self.authority
+	}
+
+	/// The hosts of the view, loaded along with the view by `Views::replace`.
+	pub fn hosts(&self) -> &HostOverrides {
+		&self.hosts
+	}
+
+	pub fn hosts_mut(&mut self) -> &mut HostOverrides {
+		&mut self.hosts
+	}
+
+	/// Forward the names of the view to `upstream` as well, after those added before it.
+	pub fn add_upstream(&mut self, upstream: Upstream) {
+		self.upstreams.push(upstream);
+	}
+
+	/// Pick the upstreams of the view with `strategy` rather than the strategy of the server.
+	pub fn set_strategy(&mut self, strategy: SelectionStrategy) {
+		self.strategy = Some(strategy);
+	}
+
+	/// The upstreams of the view, None when it resolves the way the server does.
+	pub fn upstreams(&self) -> Option<&Arc<UpstreamPool>> {
+		self.pool.as_ref()
+	}
+
+	/// The answers of the upstreams of the view.
+	pub fn cache(&self) -> &Cache {
+		&self.cache
+	}
+
+	/// Whether `client` is one of the clients of the view.
+	pub fn contains(&self, client: IpAddr) -> bool {
+		self.networks.iter().any(|(network, len)| in_prefix(client, *network, *len))
+	}
+}
+
+impl fmt::Display for View {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.name)
+	}
+}
+
+/// The views of the server, in order: a client belongs to the first view one of whose
+/// networks it's in, and to none when it isn't in any, getting the answers of the server
+/// itself. The views are swapped as a whole when the configuration is read again.
+#[derive(Default)]
+pub struct Views {
+	views: RwLock<Arc<Vec<Arc<View>>>>,
+}
+
+impl Views {
+	pub fn new() -> Self {
+		Views::default()
+	}
+
+	/// The views in use.
+	pub fn views(&self) -> Arc<Vec<Arc<View>>> {
+		match self.views.read() {
+			Ok(views) => views.clone(),
+			Err(poisoned) => poisoned.into_inner().clone(),
+		}
+	}
+
+	/// Use `views` from now on, in place of the current ones, with the upstreams of each
+	/// picked with its own strategy, or `strategy` for those without one. Fails when a view has no networks or its hosts fail to load,
+	/// leaving the current views in use.
+	pub fn replace(&self, views: Vec<View>, strategy: SelectionStrategy) -> Result<()> {
+		let mut ready = Vec::with_capacity(views.len());
+		for mut view in views {
+			if view.networks.is_empty() {
+				return Err(Error::new(ErrorKind::InvalidInput, format!("View {} has no networks", view.name)));
+			}
+			if !view.hosts.is_empty() {
+				view.hosts.load().map_err(|e| Error::new(e.kind(), format!("Failed to load the hosts of view {}: {}", view.name, e)))?;
+			}
+			if !view.upstreams.is_empty() {
+				view.pool = Some(Arc::new(UpstreamPool::new(view.upstreams.clone(), view.strategy.unwrap_or(strategy))));
+			}
+			ready.push(Arc::new(view));
+		}
+		match self.views.write() {
+			Ok(mut current) => *current = Arc::new(ready),
+			Err(poisoned) => *poisoned.into_inner() = Arc::new(ready),
+		}
+		Ok(())
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.views().is_empty()
+	}
+
+	/// The view of `client`, if it belongs to one.
+	pub fn select(&self, client: IpAddr) -> Option<Arc<View>> {
+		self.views().iter().find(|view| view.contains(client)).cloned()
+	}
+}
@@ -0,0 +1,239 @@
+//! Compiled, memory mapped zone databases for serving large static zones
+//!
+//! A zone file is compiled once into a file holding an index sorted by owner name and type,
+//! followed by the RRsets in wire format. Opening the database only maps the file, lookups
+//! binary search the index and decode just the RRset asked for.
+//!
+//! Layout, all integers big endian:
+//!
+//! ```text
+//! magic       8 bytes    "RDNSZDB1"
+//! origin      u16 length, then the name
+//! count       u32        number of index entries
+//! index       count * 16 bytes: name offset u32, name length u16, type u16,
+//!                                data offset u32, data length u32
+//! names/data  referenced by the offsets, which are relative to the start of the file
+//! ```
+//!
+//! Empty non-terminals have an index entry of type 0 without any data.
+
+use std::collections::BTreeMap;
+use std::fs::{ self, File };
+use std::io::{ Error, ErrorKind, Result, Write };
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::server::authority::ZoneData;
+use crate::server::buffer::{ PacketBuffer, VectorPacketBuffer };
+use crate::server::protocol::{ is_legacy_type, DNSRecord, QueryType };
+use crate::server::resolve::{ is_subdomain, parent_name };
+
+const MAGIC: &[u8; 8] = b"RDNSZDB1";
+const ENTRY_SIZE: usize = 16;
+
+fn invalid(msg: &str) -> Error {
+	Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Compile the records of a zone, which must include its SOA, into a database at `path`.
+/// Returns the number of RRsets written.
+pub fn compile<P: AsRef<Path>>(records: &[DNSRecord], path: P) -> Result<usize> {
+	let origin = records.iter()
+		.find(|record| record.get_query_type() == QueryType::SOA)
+		.and_then(DNSRecord::get_domain)
+		.ok_or_else(|| invalid("A zone needs an SOA record"))?;
+
+	// BTreeMap keeps the RRsets sorted by name bytes and type, the order of the index...
+	let mut rrsets: BTreeMap<(String, u16), VectorPacketBuffer> = BTreeMap::new();
+	for record in records {
+		let domain = record.get_domain().ok_or_else(|| invalid("Record without an owner name"))?;
+		let key = (domain.to_lowercase(), record.get_query_type().to_num());
+		record.write(rrsets.entry(key).or_default())?;
+	}
+
+	// Empty non-terminals get an entry of type 0 without data, so that they're found by
+	// `has_name`...
+	let owners: Vec<String> = rrsets.keys().map(|(name, _)| name.clone()).collect();
+	for owner in owners {
+		let mut node = parent_name(&owner);
+		while let Some(name) = node {
+			let exists = rrsets.range((name.to_string(), 0)..).next().is_some_and(|((next, _), _)| next == name);
+			if exists || !is_subdomain(name, &origin) {
+				break;
+			}
+			rrsets.insert((name.to_string(), 0), VectorPacketBuffer::new());
+			node = parent_name(name);
+		}
+	}
+
+	let index_start = MAGIC.len() + 2 + origin.len() + 4;
+	let mut index = Vec::with_capacity(rrsets.len() * ENTRY_SIZE);
+	let mut data = Vec::new();
+	let mut offset = index_start + rrsets.len() * ENTRY_SIZE;
+	for ((name, q_type), buffer) in &rrsets {
+		let rdata = buffer.as_slice();
+		if name.len() > u16::MAX as usize || offset + name.len() + rdata.len() > u32::MAX as usize {
+			return Err(invalid("Zone too large for the database format"));
+		}
+		index.extend_from_slice(&(offset as u32).to_be_bytes());
+		index.extend_from_slice(&(name.len() as u16).to_be_bytes());
+		index.extend_from_slice(&q_type.to_be_bytes());
+		index.extend_from_slice(&((offset + name.len()) as u32).to_be_bytes());
+		index.extend_from_slice(&(rdata.len() as u32).to_be_bytes());
+
+		data.extend_from_slice(name.as_bytes());
+		data.extend_from_slice(rdata);
+		offset += name.len() + rdata.len();
+	}
+
+	// Written next to the target and renamed, so a server never maps a half written file...
+	let tmp_path = path.as_ref().with_extension("tmp");
+	{
+		let mut file = File::create(&tmp_path)?;
+		file.write_all(MAGIC)?;
+		file.write_all(&(origin.len() as u16).to_be_bytes())?;
+		file.write_all(origin.as_bytes())?;
+		file.write_all(&(rrsets.len() as u32).to_be_bytes())?;
+		file.write_all(&index)?;
+		file.write_all(&data)?;
+		file.sync_all()?;
+	}
+	fs::rename(&tmp_path, path)?;
+
+	Ok(rrsets.keys().filter(|(_, q_type)| *q_type != 0).count())
+}
+// --------------------------------------------------------------------------------------------
+
+/// A compiled zone mapped into memory.
+///
+/// The file must not be modified while it is mapped; `compile` replaces databases by
+/// renaming, which leaves existing mappings intact.
+pub struct ZoneDatabase {
+	map: Mmap,
+	origin: String,
+	count: usize,
+	index_start: usize,
+}
+
+impl ZoneDatabase {
+	pub fn open<P: AsRef<Path>>(path: P) -> Result<ZoneDatabase> {
+		let file = File::open(path)?;
+		// Safe as long as nobody writes to the file in place, see above...
+		let map = unsafe { Mmap::map(&file)? };
+
+		if map.len() < MAGIC.len() + 2 || &map[..MAGIC.len()] != MAGIC {
+			return Err(invalid("Not a compiled zone database"));
+		}
+		let origin_len = u16::from_be_bytes([map[8], map[9]]) as usize;
+		let origin_end = 10 + origin_len;
+		let origin = map.get(10..origin_end)
+			.and_then(|name| std::str::from_utf8(name).ok())
+			.ok_or_else(|| invalid("Invalid zone origin"))?
+			.to_string();
+		let count = map.get(origin_end..origin_end + 4)
+			.map(|num| u32::from_be_bytes([num[0], num[1], num[2], num[3]]) as usize)
+			.ok_or_else(|| invalid("Truncated zone database"))?;
+
+		let index_start = origin_end + 4;
+		if index_start + count * ENTRY_SIZE > map.len() {
+			return Err(invalid("Truncated zone database"));
+		}
+
+		Ok(ZoneDatabase { map, origin, count, index_start })
+	}
+
+	/// Number of RRsets in the database.
+	pub fn len(&self) -> usize {
+		self.count
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.count == 0
+	}
+
+	/// Whether the database holds records of any of the obsolete types, which strict mode
+	/// rejects.
+	pub fn has_legacy_records(&self) -> Result<bool> {
+		for idx in 0..self.count {
+			if is_legacy_type(self.entry_key(idx)?.1) {
+				return Ok(true);
+			}
+		}
+		Ok(false)
+	}
+
+	fn read_u32(&self, pos: usize) -> u32 {
+		let b = &self.map[pos..pos + 4];
+		u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+	}
+
+	fn read_u16(&self, pos: usize) -> u16 {
+		u16::from_be_bytes([self.map[pos], self.map[pos + 1]])
+	}
+
+	/// Owner name and type of index entry `idx`.
+	fn entry_key(&self, idx: usize) -> Result<(&[u8], u16)> {
+		let pos = self.index_start + idx * ENTRY_SIZE;
+		let name_off = self.read_u32(pos) as usize;
+		let name_len = self.read_u16(pos + 4) as usize;
+		let name = self.map.get(name_off..name_off + name_len).ok_or_else(|| invalid("Corrupt zone database index"))?;
+		Ok((name, self.read_u16(pos + 6)))
+	}
+
+	fn entry_data(&self, idx: usize) -> Result<&[u8]> {
+		let pos = self.index_start + idx * ENTRY_SIZE;
+		let data_off = self.read_u32(pos + 8) as usize;
+		let data_len = self.read_u32(pos + 12) as usize;
+		self.map.get(data_off..data_off + data_len).ok_or_else(|| invalid("Corrupt zone database index"))
+	}
+
+	/// Index of the first entry not sorting before (`name`, `q_type`).
+	fn lower_bound(&self, name: &[u8], q_type: u16) -> Result<usize> {
+		let (mut low, mut high) = (0, self.count);
+		while low < high {
+			let mid = low + (high - low) / 2;
+			if self.entry_key(mid)? < (name, q_type) {
+				low = mid + 1;
+			} else {
+				high = mid;
+			}
+		}
+		Ok(low)
+	}
+}
+
+impl ZoneData for ZoneDatabase {
+	fn origin(&self) -> &str {
+		&self.origin
+	}
+
+	fn soa(&self) -> Result<DNSRecord> {
+		let origin = self.origin.clone();
+		self.lookup(&origin, QueryType::SOA)?
+			.into_iter()
+			.next()
+			.ok_or_else(|| invalid("Zone database without an SOA record"))
+	}
+
+	fn lookup(&self, name: &str, q_type: QueryType) -> Result<Vec<DNSRecord>> {
+		let q_type = q_type.to_num();
+		let idx = self.lower_bound(name.as_bytes(), q_type)?;
+		if idx >= self.count || self.entry_key(idx)? != (name.as_bytes(), q_type) {
+			return Ok(Vec::new());
+		}
+
+		let data = self.entry_data(idx)?;
+		let mut buffer = VectorPacketBuffer::from_bytes(data.to_vec());
+		let mut records = Vec::new();
+		while buffer.pos() < data.len() {
+			records.push(DNSRecord::read(&mut buffer)?);
+		}
+		Ok(records)
+	}
+
+	fn has_name(&self, name: &str) -> Result<bool> {
+		let idx = self.lower_bound(name.as_bytes(), 0)?;
+		Ok(idx < self.count && self.entry_key(idx)?.0 == name.as_bytes())
+	}
+}
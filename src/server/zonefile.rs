@@ -0,0 +1,571 @@
+//! Parsing of records in master file (RFC 1035 section 5) presentation format
+
+use std::fs::File;
+use std::io::{ BufRead, BufReader, Error, ErrorKind, Result };
+use std::net::{ Ipv4Addr, Ipv6Addr };
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use crate::server::protocol::{ is_legacy_type, DNSRecord, LegacyRData, QueryType, TransientTTL, TYPE_WKS };
+
+fn invalid(msg: &str) -> Error {
+	Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Lowercase a domain name and strip the trailing dot, the way names are stored in records.
+pub fn normalize_name(name: &str) -> String {
+	name.trim_end_matches('.').to_lowercase()
+}
+
+/// Build a record from its owner name, TTL, type mnemonic and RDATA in presentation format.
+///
+/// The RDATA of TXT records is a sequence of character strings, quoted or not, the other
+/// types expect their fields separated by whitespace. Types without a typed representation take
+/// the generic `\# LENGTH HEX` form of RFC 3597.
+pub fn parse_record(domain: &str, ttl: u32, r_type: &str, rdata: &str) -> Result<DNSRecord> {
+	let domain = normalize_name(domain);
+	let ttl = TransientTTL(ttl);
+	let q_type = QueryType::from_name(r_type).ok_or_else(|| invalid(&format!("Unknown record type: {}", r_type)))?;
+
+	if q_type == QueryType::TXT {
+		let data = parse_character_strings(rdata)?;
+		if data.is_empty() {
+			return Err(invalid("Missing text"));
+		}
+		return Ok(DNSRecord::TXT { domain, data, ttl });
+	}
+
+	let mut tokens = rdata.split_whitespace().map(|t| t.trim_matches('"'));
+	let mut next = |name: &str| tokens.next().ok_or_else(|| invalid(&format!("Missing {}", name)));
+
+	let record = match q_type {
+		QueryType::A => {
+			let addr = next("address")?.parse::<Ipv4Addr>().map_err(|_| invalid("Invalid IPv4 address"))?;
+			DNSRecord::A { domain, addr, ttl }
+		}
+		QueryType::AAAA => {
+			let addr = next("address")?.parse::<Ipv6Addr>().map_err(|_| invalid("Invalid IPv6 address"))?;
+			DNSRecord::AAAA { domain, addr, ttl }
+		}
+		QueryType::NS => DNSRecord::NS { domain, host: normalize_name(next("host")?), ttl },
+		QueryType::CNAME => DNSRecord::CNAME { domain, host: normalize_name(next("host")?), ttl },
+		QueryType::PTR => DNSRecord::PTR { domain, host: normalize_name(next("host")?), ttl },
+		QueryType::MX => {
+			let priority = parse_num(next("priority")?, "priority")?;
+			DNSRecord::MX { domain, priority, host: normalize_name(next("host")?), ttl }
+		}
+		QueryType::SOA => {
+			let m_name = normalize_name(next("primary name server")?);
+			let r_name = normalize_name(next("responsible mailbox")?);
+			DNSRecord::SOA {
+				domain,
+				m_name,
+				r_name,
+				serial: parse_num(next("serial")?, "serial")?,
+				refresh: parse_num(next("refresh")?, "refresh")?,
+				retry: parse_num(next("retry")?, "retry")?,
+				expire: parse_num(next("expire")?, "expire")?,
+				minimum: parse_num(next("minimum")?, "minimum")?,
+				ttl,
+			}
+		}
+		QueryType::SRV => DNSRecord::SRV {
+			domain,
+			priority: parse_num(next("priority")?, "priority")?,
+			weight: parse_num(next("weight")?, "weight")?,
+			port: parse_num(next("port")?, "port")?,
+			host: normalize_name(next("target")?),
+			ttl,
+		},
+		QueryType::DS => {
+			let key_tag = parse_num(next("key tag")?, "key tag")?;
+			let algorithm = parse_num(next("algorithm")?, "algorithm")?;
+			let digest_type = parse_num(next("digest type")?, "digest type")?;
+			let hex: String = tokens.collect();
+			let digest = decode_hex(&hex).ok_or_else(|| invalid("Invalid DS digest"))?;
+			DNSRecord::DS { domain, key_tag, algorithm, digest_type, digest, ttl }
+		}
+		QueryType::DNSKEY => {
+			let flags = parse_num(next("flags")?, "flags")?;
+			let protocol = parse_num(next("protocol")?, "protocol")?;
+			let algorithm = parse_num(next("algorithm")?, "algorithm")?;
+			let b64: String = tokens.collect();
+			let public_key = BASE64.decode(b64.as_bytes()).map_err(|_| invalid("Invalid DNSKEY public key"))?;
+			DNSRecord::DNSKEY { domain, flags, protocol, algorithm, public_key, ttl }
+		}
+		QueryType::UNKNOWN(num) => {
+			let fields: Vec<&str> = rdata.split_whitespace().collect();
+			let data = match fields.split_first() {
+				Some((&"\\#", generic)) => parse_generic_rdata(generic)?,
+				_ if num == TYPE_WKS => parse_wks(&fields)?.encode(),
+				_ => return Err(invalid(&format!("Records of type {} need their RDATA in the \\# form", q_type))),
+			};
+			DNSRecord::UNKNOWN { domain, q_type: num, data, ttl }
+		}
+		_ => return Err(invalid(&format!("Records of type {} can't be parsed", q_type))),
+	};
+
+	Ok(record)
+}
+
+/// Parse a single record entry `owner [ttl] [class] type rdata`, with the TTL and class in
+/// either order. A missing TTL is taken as 0.
+pub fn parse_entry(entry: &str) -> Result<DNSRecord> {
+	let mut tokens = entry.split_whitespace();
+	let domain = tokens.next().ok_or_else(|| invalid("Missing owner name"))?;
+
+	let mut ttl = 0;
+	let mut r_type = None;
+	for token in tokens.by_ref() {
+		if let Ok(num) = token.parse::<u32>() {
+			ttl = num;
+		} else if token.eq_ignore_ascii_case("IN") {
+			continue;
+		} else {
+			r_type = Some(token);
+			break;
+		}
+	}
+	let r_type = r_type.ok_or_else(|| invalid("Missing record type"))?;
+
+	let rdata: Vec<&str> = tokens.collect();
+	parse_record(domain, ttl, r_type, &rdata.join(" "))
+}
+
+/// The character strings of `rdata`, separated by whitespace: quoted ones may hold whitespace,
+/// and either may hold `\X` for the character X and `\DDD` for the byte of decimal value DDD
+/// (RFC 1035 section 5.1). A string may be at most 255 bytes long.
+pub fn parse_character_strings(rdata: &str) -> Result<Vec<Vec<u8>>> {
+	let mut strings = Vec::new();
+	let mut bytes = rdata.bytes().peekable();
+	loop {
+		while bytes.next_if(|b| b.is_ascii_whitespace()).is_some() {}
+		let quoted = match bytes.peek() {
+			None => break,
+			Some(b'"') => {
+				bytes.next();
+				true
+			}
+			Some(_) => false,
+		};
+
+		let mut string = Vec::new();
+		loop {
+			match bytes.next() {
+				None if quoted => return Err(invalid("Unterminated quoted string")),
+				None => break,
+				Some(b'"') if quoted => break,
+				Some(b) if !quoted && b.is_ascii_whitespace() => break,
+				Some(b'\\') => match bytes.next() {
+					Some(d) if d.is_ascii_digit() => {
+						let digits = [d, bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+						let value = std::str::from_utf8(&digits).ok()
+							.filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()))
+							.and_then(|digits| digits.parse::<u8>().ok())
+							.ok_or_else(|| invalid("Invalid \\DDD escape"))?;
+						string.push(value);
+					}
+					Some(b) => string.push(b),
+					None => return Err(invalid("Escape at the end of the text")),
+				},
+				Some(b) => string.push(b),
+			}
+		}
+		if string.len() > 255 {
+			return Err(invalid("Character string exceeds 255 bytes"));
+		}
+		strings.push(string);
+	}
+	Ok(strings)
+}
+
+/// RDATA in the generic form of RFC 3597, the fields following `\#`: the length, then the data
+/// as hex which may be split up by whitespace.
+fn parse_generic_rdata(fields: &[&str]) -> Result<Vec<u8>> {
+	let (len, hex) = fields.split_first().ok_or_else(|| invalid("Missing RDATA length"))?;
+	let len: usize = parse_num(len, "RDATA length")?;
+	let data = decode_hex(&hex.concat()).ok_or_else(|| invalid("Invalid RDATA hex"))?;
+	if data.len() != len {
+		return Err(invalid("RDATA length doesn't match the data"));
+	}
+	Ok(data)
+}
+
+/// WKS RDATA: an address, the protocol by name or number and the ports by number or by the
+/// names of a few classic services.
+fn parse_wks(fields: &[&str]) -> Result<LegacyRData> {
+	let mut fields = fields.iter();
+	let addr = fields.next()
+		.and_then(|addr| addr.parse::<Ipv4Addr>().ok())
+		.ok_or_else(|| invalid("Invalid WKS address"))?;
+	let protocol = match fields.next().map(|protocol| protocol.to_lowercase()) {
+		Some(ref protocol) if protocol == "tcp" => 6,
+		Some(ref protocol) if protocol == "udp" => 17,
+		Some(protocol) => parse_num(&protocol, "WKS protocol")?,
+		None => return Err(invalid("Missing WKS protocol")),
+	};
+
+	let ports = fields
+		.map(|service| match service.to_lowercase().as_str() {
+			"ftp" => Ok(21),
+			"telnet" => Ok(23),
+			"smtp" => Ok(25),
+			"domain" => Ok(53),
+			"finger" => Ok(79),
+			"http" => Ok(80),
+			_ => parse_num(service, "WKS port"),
+		})
+		.collect::<Result<Vec<u16>>>()?;
+	Ok(LegacyRData::Wks { addr, protocol, ports })
+}
+
+fn parse_num<T: std::str::FromStr>(token: &str, name: &str) -> Result<T> {
+	token.parse::<T>().map_err(|_| invalid(&format!("Invalid {}", name)))
+}
+
+/// Decode a string of hex digits, None if it isn't valid hex.
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+	if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+		return None;
+	}
+	(0..hex.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+		.collect()
+}
+
+/// Glue the tokens `stringify!` produces for the RDATA of `record!` back together. Rust
+/// splits `1.2.3.4` into `1.2`, `.` and `3.4`, so no space goes around dots, colons and dashes.
+#[doc(hidden)]
+pub fn join_tokens(tokens: &[&str]) -> String {
+	let sticky = |t: &str| matches!(t, "." | ":" | "::" | "-" | "_");
+
+	let mut out = String::new();
+	let mut prev = None;
+	for token in tokens {
+		if let Some(prev) = prev {
+			if !sticky(prev) && !sticky(token) {
+				out.push(' ');
+			}
+		}
+		out.push_str(token);
+		prev = Some(*token);
+	}
+	out
+}
+// --------------------------------------------------------------------------------------------
+
+/// Reads the records of a master file one at a time, handling `$ORIGIN` and `$TTL`, comments,
+/// records spanning lines with parentheses, blank owners repeating the previous one, `@` and
+/// names relative to the origin.
+pub struct ZoneFileParser<R: BufRead> {
+	reader: R,
+	origin: String,
+	default_ttl: Option<u32>,
+	last_owner: Option<String>,
+	last_ttl: Option<u32>,
+	bytes_read: u64,
+	line_num: usize,
+	strict: bool,
+}
+
+impl<R: BufRead> ZoneFileParser<R> {
+	/// Parse the zone file in `reader`, with relative names taken to be below `origin` until a
+	/// `$ORIGIN` directive changes it.
+	pub fn new(reader: R, origin: &str) -> Self {
+		Self {
+			reader,
+			origin: normalize_name(origin),
+			default_ttl: None,
+			last_owner: None,
+			last_ttl: None,
+			bytes_read: 0,
+			line_num: 0,
+			strict: false,
+		}
+	}
+
+	/// In strict mode, records of the obsolete types (see `is_legacy_type`) are rejected
+	/// instead of being carried along.
+	pub fn strict(mut self, strict: bool) -> Self {
+		self.strict = strict;
+		self
+	}
+
+	/// Number of bytes consumed from the reader so far.
+	pub fn bytes_read(&self) -> u64 {
+		self.bytes_read
+	}
+
+	pub fn origin(&self) -> &str {
+		&self.origin
+	}
+
+	/// Read one entry, joining the lines of a parenthesized entry. Returns the tokens, whether
+	/// the entry started with whitespace (i.e. has no owner) and the line number it started on.
+	fn read_entry(&mut self) -> Result<Option<(Vec<String>, bool, usize)>> {
+		let mut tokens = Vec::new();
+		let mut blank_owner = false;
+		let mut start_line = 0;
+		let mut depth = 0;
+
+		loop {
+			let mut line = String::new();
+			let len = self.reader.read_line(&mut line)?;
+			if len == 0 {
+				if depth > 0 {
+					return Err(self.error(start_line, "Unbalanced parentheses"));
+				}
+				return Ok(None);
+			}
+			self.bytes_read += len as u64;
+			self.line_num += 1;
+
+			if depth == 0 {
+				start_line = self.line_num;
+				blank_owner = line.starts_with(' ') || line.starts_with('\t');
+			}
+			for token in tokenize(&line) {
+				match token.as_str() {
+					"(" => depth += 1,
+					")" if depth == 0 => return Err(self.error(self.line_num, "Unbalanced parentheses")),
+					")" => depth -= 1,
+					_ => tokens.push(token),
+				}
+			}
+
+			if depth == 0 && !tokens.is_empty() {
+				return Ok(Some((tokens, blank_owner, start_line)));
+			}
+		}
+	}
+
+	fn error(&self, line: usize, msg: &str) -> Error {
+		invalid(&format!("Line {}: {}", line, msg))
+	}
+
+	/// Make `name` fully qualified, `@` being the origin itself.
+	fn absolute(&self, name: &str) -> String {
+		if name == "@" {
+			self.origin.clone()
+		} else if name.ends_with('.') {
+			normalize_name(name)
+		} else if self.origin.is_empty() {
+			name.to_lowercase()
+		} else {
+			format!("{}.{}", name.to_lowercase(), self.origin)
+		}
+	}
+
+	fn parse_entry(&mut self, tokens: Vec<String>, blank_owner: bool, line: usize) -> Result<Option<DNSRecord>> {
+		if tokens[0].eq_ignore_ascii_case("$ORIGIN") {
+			let origin = tokens.get(1).ok_or_else(|| self.error(line, "$ORIGIN without a name"))?;
+			self.origin = self.absolute(origin);
+			return Ok(None);
+		}
+		if tokens[0].eq_ignore_ascii_case("$TTL") {
+			let ttl = tokens.get(1).and_then(|ttl| parse_ttl(ttl)).ok_or_else(|| self.error(line, "Invalid $TTL"))?;
+			self.default_ttl = Some(ttl);
+			return Ok(None);
+		}
+		if tokens[0].starts_with('$') {
+			return Err(self.error(line, &format!("Unsupported directive {}", tokens[0])));
+		}
+
+		let mut tokens = tokens.into_iter();
+		let owner = if blank_owner {
+			self.last_owner.clone().ok_or_else(|| self.error(line, "No previous owner name"))?
+		} else {
+			let owner = tokens.next().unwrap_or_default();
+			self.absolute(&owner)
+		};
+
+		let mut ttl = None;
+		let mut r_type = None;
+		for token in tokens.by_ref() {
+			if token.eq_ignore_ascii_case("IN") {
+				continue;
+			}
+			match parse_ttl(&token) {
+				Some(num) if ttl.is_none() && QueryType::from_name(&token).is_none() => ttl = Some(num),
+				_ => {
+					r_type = Some(token.to_uppercase());
+					break;
+				}
+			}
+		}
+		let r_type = r_type.ok_or_else(|| self.error(line, "Missing record type"))?;
+		let q_type = QueryType::from_name(&r_type).ok_or_else(|| self.error(line, &format!("Unknown record type {}", r_type)))?;
+
+		let mut rdata: Vec<String> = tokens.collect();
+		// Names within the RDATA may be relative as well...
+		let name_fields: &[usize] = match q_type {
+			QueryType::NS | QueryType::CNAME | QueryType::PTR => &[0],
+			QueryType::MX => &[1],
+			QueryType::SOA => &[0, 1],
+			QueryType::SRV => &[3],
+			_ => &[],
+		};
+		for idx in name_fields {
+			if let Some(name) = rdata.get(*idx).map(|name| self.absolute(name)) {
+				rdata[*idx] = name;
+			}
+		}
+		let rdata = rdata.join(" ");
+
+		let ttl = match ttl.or(self.default_ttl).or(self.last_ttl) {
+			Some(ttl) => ttl,
+			None => match q_type {
+				// Without any TTL given, the SOA minimum is the TTL of the zone (RFC 1035)...
+				QueryType::SOA => rdata.split_whitespace().nth(6).and_then(parse_ttl).unwrap_or(0),
+				_ => return Err(self.error(line, "No TTL given and no $TTL set")),
+			},
+		};
+
+		if self.strict && is_legacy_type(q_type.to_num()) {
+			return Err(self.error(line, &format!("Obsolete record type {} rejected in strict mode", q_type)));
+		}
+		let record = parse_record(&owner, ttl, &r_type, &rdata).map_err(|e| self.error(line, &e.to_string()))?;
+		self.last_owner = Some(owner);
+		self.last_ttl = Some(ttl);
+		Ok(Some(record))
+	}
+}
+
+impl<R: BufRead> Iterator for ZoneFileParser<R> {
+	type Item = Result<DNSRecord>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			let (tokens, blank_owner, line) = match self.read_entry() {
+				Ok(Some(entry)) => entry,
+				Ok(None) => return None,
+				Err(e) => return Some(Err(e)),
+			};
+			match self.parse_entry(tokens, blank_owner, line) {
+				Ok(Some(record)) => return Some(Ok(record)),
+				Ok(None) => continue,
+				Err(e) => return Some(Err(e)),
+			}
+		}
+	}
+}
+
+/// Parse all records of the master file at `path`.
+pub fn load_zone_file<P: AsRef<Path>>(path: P, origin: &str) -> Result<Vec<DNSRecord>> {
+	let file = File::open(path)?;
+	ZoneFileParser::new(BufReader::new(file), origin).collect()
+}
+
+/// A TTL given in seconds or with the BIND style units, e.g. `1h30m`.
+fn parse_ttl(token: &str) -> Option<u32> {
+	if let Ok(secs) = token.parse::<u32>() {
+		return Some(secs);
+	}
+
+	let mut total: u32 = 0;
+	let mut num = String::new();
+	for c in token.chars() {
+		if c.is_ascii_digit() {
+			num.push(c);
+			continue;
+		}
+		let unit = match c.to_ascii_lowercase() {
+			's' => 1,
+			'm' => 60,
+			'h' => 60 * 60,
+			'd' => 24 * 60 * 60,
+			'w' => 7 * 24 * 60 * 60,
+			_ => return None,
+		};
+		let value: u32 = num.parse().ok()?;
+		total = total.checked_add(value.checked_mul(unit)?)?;
+		num.clear();
+	}
+	if num.is_empty() {
+		Some(total)
+	} else {
+		None
+	}
+}
+
+/// Split a line into tokens, dropping the comment. Quoted strings stay one token with their
+/// quotes, parentheses are tokens of their own.
+fn tokenize(line: &str) -> Vec<String> {
+	let mut tokens = Vec::new();
+	let mut current = String::new();
+	let mut quoted = false;
+	let mut escaped = false;
+
+	for c in line.chars() {
+		if quoted {
+			current.push(c);
+			if escaped {
+				escaped = false;
+			} else if c == '\\' {
+				escaped = true;
+			} else if c == '"' {
+				quoted = false;
+			}
+			continue;
+		}
+		match c {
+			';' => break,
+			'"' => {
+				current.push(c);
+				quoted = true;
+			}
+			'(' | ')' => {
+				if !current.is_empty() {
+					tokens.push(std::mem::take(&mut current));
+				}
+				tokens.push(c.to_string());
+			}
+			c if c.is_whitespace() => {
+				if !current.is_empty() {
+					tokens.push(std::mem::take(&mut current));
+				}
+			}
+			c => current.push(c),
+		}
+	}
+	if !current.is_empty() {
+		tokens.push(current);
+	}
+	tokens
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn character_strings() {
+		let strings = parse_character_strings(r#""v=spf1 " "-all" plain "a \"quoted\" \\ \255\000""#).unwrap();
+		assert_eq!(strings, vec![b"v=spf1 ".to_vec(), b"-all".to_vec(), b"plain".to_vec(), b"a \"quoted\" \\ \xff\x00".to_vec()]);
+		assert_eq!(parse_character_strings(r#""""#).unwrap(), vec![Vec::<u8>::new()]);
+		assert!(parse_character_strings(r#""unterminated"#).is_err());
+		assert!(parse_character_strings(r#""\256""#).is_err());
+		assert!(parse_character_strings(&"x".repeat(256)).is_err());
+	}
+
+	#[test]
+	fn txt_presentation_round_trip() {
+		let record = DNSRecord::TXT {
+			domain: "example.com".to_string(),
+			data: vec![b"two words".to_vec(), Vec::new(), vec![b'"', b'\\', 0, 0xff]],
+			ttl: TransientTTL(300),
+		};
+		assert_eq!(parse_record("example.com", 300, "TXT", &record.rdata_string()).unwrap(), record);
+	}
+
+	#[test]
+	fn txt_in_zone_file() {
+		let zone = "$ORIGIN example.com.\n$TTL 300\n@ IN TXT ( \"v=DKIM1; k=rsa; \"\n\t\"p=MIGf\" )\n";
+		let records: Vec<DNSRecord> = ZoneFileParser::new(zone.as_bytes(), "").collect::<Result<_>>().unwrap();
+		assert_eq!(records[0].txt_string().as_deref(), Some("v=DKIM1; k=rsa; p=MIGf"));
+		assert!(matches!(records[0], DNSRecord::TXT { ref data, .. } if data.len() == 2));
+	}
+}
@@ -0,0 +1,225 @@
+//! A query whose name is a compression pointer to itself, over every transport: the listener
+//! has to drop it and go on answering
+
+use std::convert::TryFrom;
+use std::io::{ Read, Write };
+use std::net::{ Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket };
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::{ ClientConnection, ServerConfig, StreamOwned };
+use rustls::pki_types::ServerName;
+
+use rdns::server::authority::Zone;
+use rdns::server::buffer::VectorPacketBuffer;
+use rdns::server::client::{ read_tcp_message, write_tcp_message, DNSClient };
+use rdns::server::context::{ ServerContext, ServerRole };
+use rdns::server::doh::DNSHttpsServer;
+use rdns::server::protocol::{ DNSPacket, DNSRecord, QueryType, ResultCode, TransientTTL };
+use rdns::server::quic::DNSQuicServer;
+use rdns::server::quic_upstream::QuicUpstream;
+use rdns::server::tcp::DNSTcpServer;
+use rdns::server::tls::{ load_server_config, DNSTlsServer };
+use rdns::server::tls_upstream::{ client_config, TlsPolicy, TlsUpstream };
+use rdns::server::udp::DNSUdpServer;
+
+const ZONE: &str = "example.com";
+const ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A query for type A whose name is a pointer to itself, at offset 12...
+const POINTER_LOOP: [u8; 18] = [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 0x0c, 0, 1, 0, 1];
+
+/// A server answering for the zone on the loopback.
+fn context() -> Arc<ServerContext> {
+	let mut context = ServerContext::new();
+	context.listen_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+	context.worker_threads = 1;
+	context.health_check_interval = None;
+
+	let ttl = TransientTTL(3600);
+	let zone = Zone::from_records(vec![
+		DNSRecord::SOA {
+			domain: ZONE.to_string(),
+			m_name: format!("ns1.{}", ZONE),
+			r_name: format!("hostmaster.{}", ZONE),
+			serial: 1,
+			refresh: 7200,
+			retry: 900,
+			expire: 1209600,
+			minimum: 300,
+			ttl,
+		},
+		DNSRecord::NS { domain: ZONE.to_string(), host: format!("ns1.{}", ZONE), ttl },
+		DNSRecord::A { domain: format!("www.{}", ZONE), addr: ADDR, ttl },
+	]).unwrap();
+	context.authority.add_zone(Arc::new(zone));
+	Arc::new(context)
+}
+
+fn tls_config() -> Arc<ServerConfig> {
+	let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("data");
+	load_server_config(&data.join("localhost.pem"), &data.join("localhost.key")).unwrap()
+}
+
+/// A loopback port the system isn't using for TCP, or for UDP...
+fn free_tcp_port() -> SocketAddr {
+	TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap()
+}
+
+fn free_udp_port() -> SocketAddr {
+	UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap()
+}
+
+fn query(id: u16) -> Vec<u8> {
+	let client = DNSClient::new();
+	let mut query = client.build_query(&format!("www.{}", ZONE), QueryType::A, false);
+	query.header.id = id;
+	let mut buffer = VectorPacketBuffer::new();
+	query.write(&mut buffer).unwrap();
+	buffer.into_inner()
+}
+
+fn check_answer(response: &DNSPacket) {
+	assert_eq!(response.header.rescode, ResultCode::NOERROR);
+	assert!(response.answers.iter().any(|record| matches!(record, DNSRecord::A { addr, .. } if *addr == ADDR)));
+}
+
+fn parse(message: Vec<u8>) -> DNSPacket {
+	DNSPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(message)).unwrap()
+}
+
+/// Whether the stream is closed or reset rather than left hanging: a read mustn't time out.
+fn assert_closed<S: Read>(stream: &mut S) {
+	let mut buf = [0; 512];
+	match stream.read(&mut buf) {
+		Ok(0) => (),
+		Ok(len) => panic!("Got {} bytes back", len),
+		Err(e) => assert!(!matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut), "{}", e),
+	}
+}
+
+fn tls_stream(addr: SocketAddr, alpn: &[u8]) -> StreamOwned<ClientConnection, TcpStream> {
+	let config = client_config(&[], TlsPolicy::Opportunistic, alpn).unwrap();
+	let connection = ClientConnection::new(config, ServerName::try_from("localhost").unwrap()).unwrap();
+	let sock = TcpStream::connect_timeout(&addr, TIMEOUT).unwrap();
+	sock.set_read_timeout(Some(TIMEOUT)).unwrap();
+	StreamOwned::new(connection, sock)
+}
+
+#[test]
+fn udp() {
+	let context = context();
+	DNSUdpServer::new(context.clone()).run_server().unwrap();
+	let addr = context.udp_stats.sockets()[0].addr;
+
+	let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+	socket.send_to(&POINTER_LOOP, addr).unwrap();
+	let client = DNSClient::with_timeout(TIMEOUT);
+	check_answer(&client.send_query(&format!("www.{}", ZONE), QueryType::A, addr, false).unwrap());
+	assert_eq!(context.udp_stats.sockets()[0].malformed, 1);
+}
+
+#[test]
+fn tcp() {
+	let addr = free_tcp_port();
+	DNSTcpServer::new(context()).with_listener(addr, ServerRole::Full).run_server().unwrap();
+
+	let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT).unwrap();
+	stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+	write_tcp_message(&mut stream, &POINTER_LOOP).unwrap();
+	assert_closed(&mut stream);
+
+	let client = DNSClient::with_timeout(TIMEOUT);
+	let mut query = client.build_query(&format!("www.{}", ZONE), QueryType::A, false);
+	check_answer(&client.exchange_tcp(&mut query, addr).unwrap());
+}
+
+#[test]
+fn tls() {
+	let addr = free_tcp_port();
+	DNSTlsServer::new(context(), tls_config(), addr).run_server().unwrap();
+
+	let mut stream = tls_stream(addr, b"dot");
+	write_tcp_message(&mut stream, &POINTER_LOOP).unwrap();
+	stream.flush().unwrap();
+	assert_closed(&mut stream);
+
+	let mut stream = tls_stream(addr, b"dot");
+	write_tcp_message(&mut stream, &query(1)).unwrap();
+	check_answer(&parse(read_tcp_message(&mut stream).unwrap()));
+
+	let upstream = TlsUpstream::new(addr, Some("localhost".to_string()), Vec::new(), TlsPolicy::Opportunistic).unwrap();
+	let client = DNSClient::with_timeout(TIMEOUT);
+	let mut query = client.build_query(&format!("www.{}", ZONE), QueryType::A, false);
+	check_answer(&upstream.exchange(&client, &mut query).unwrap());
+}
+
+/// POST `message` to the DNS over HTTPS listener at `addr`, returning the status line and
+/// the body.
+fn https_post(addr: SocketAddr, message: &[u8]) -> (String, Vec<u8>) {
+	let mut stream = tls_stream(addr, b"http/1.1");
+	let head = format!(
+		"POST /dns-query HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+		message.len());
+	stream.write_all(head.as_bytes()).unwrap();
+	stream.write_all(message).unwrap();
+	stream.flush().unwrap();
+
+	let mut response = Vec::new();
+	match stream.read_to_end(&mut response) {
+		Ok(_) => (),
+		// Closed without a close_notify once the response is sent...
+		Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => (),
+		Err(e) => panic!("{}", e),
+	}
+	let end = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+	let status = String::from_utf8_lossy(&response[..end]).lines().next().unwrap().to_string();
+	(status, response[end + 4..].to_vec())
+}
+
+#[test]
+fn https() {
+	let addr = free_tcp_port();
+	DNSHttpsServer::new(context(), tls_config(), addr).run_server().unwrap();
+
+	let (status, _) = https_post(addr, &POINTER_LOOP);
+	assert!(status.contains(" 400 "), "{}", status);
+
+	let (status, body) = https_post(addr, &query(0));
+	assert!(status.contains(" 200 "), "{}", status);
+	check_answer(&parse(body));
+}
+
+#[test]
+fn quic() {
+	let addr = free_udp_port();
+	DNSQuicServer::new(context(), tls_config(), addr).run_server().unwrap();
+
+	let mut message = POINTER_LOOP.to_vec();
+	// DNS over QUIC has queries go out with an ID of 0...
+	message[..2].copy_from_slice(&[0, 0]);
+	let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	runtime.block_on(async {
+		let mut tls = (*client_config(&[], TlsPolicy::Opportunistic, b"doq").unwrap()).clone();
+		tls.enable_early_data = false;
+		let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(Arc::new(tls)).unwrap();
+		let endpoint = quinn::Endpoint::client(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+		let connection = endpoint.connect_with(quinn::ClientConfig::new(Arc::new(crypto)), addr, "localhost").unwrap()
+			.await
+			.unwrap();
+		let (mut send, mut recv) = connection.open_bi().await.unwrap();
+		let mut data = Vec::new();
+		write_tcp_message(&mut data, &message).unwrap();
+		send.write_all(&data).await.unwrap();
+		send.finish().unwrap();
+		let read = tokio::time::timeout(TIMEOUT, recv.read_to_end(65535)).await.expect("The stream was left hanging");
+		assert!(read.is_err());
+	});
+
+	let upstream = QuicUpstream::new(addr, Some("localhost".to_string()), Vec::new(), TlsPolicy::Opportunistic).unwrap();
+	let client = DNSClient::with_timeout(TIMEOUT);
+	let mut query = client.build_query(&format!("www.{}", ZONE), QueryType::A, false);
+	check_answer(&upstream.exchange(&client, &mut query).unwrap());
+}
@@ -0,0 +1,114 @@
+//! Messages sent over TCP in several writes: the listener has to keep what it read of a
+//! message while another query of the connection is being resolved and answer both, but
+//! not wait longer than the idle timeout for a whole message
+
+use std::io::{ Error, Read, Result, Write };
+use std::net::{ IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream };
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rdns::server::buffer::VectorPacketBuffer;
+use rdns::server::client::{ read_tcp_message, write_tcp_message, DNSClient };
+use rdns::server::context::{ ServerContext, ServerRole };
+use rdns::server::protocol::{ DNSPacket, DNSRecord, QueryType, ResultCode, TransientTTL };
+use rdns::server::resolve::{ DNSResolver, ResolverFactory };
+use rdns::server::tcp::DNSTcpServer;
+
+const ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const IDLE_TIMEOUT: Duration = Duration::from_millis(200);
+/// How long the slow name takes to resolve, well over the idle timeout...
+const SLOW: Duration = Duration::from_millis(1500);
+
+/// Resolvers answering every name with `ADDR`, `slow.example.com` only after `SLOW`.
+struct SlowResolvers;
+
+struct SlowResolver;
+
+impl ResolverFactory for SlowResolvers {
+	type Resolver = SlowResolver;
+
+	fn create(&self, _: Arc<ServerContext>, _: Option<IpAddr>) -> SlowResolver {
+		SlowResolver
+	}
+}
+
+impl DNSResolver for SlowResolver {
+	fn resolve(&mut self, qname: &str, q_type: QueryType, _: bool) -> Result<DNSPacket> {
+		if qname.starts_with("slow.") {
+			thread::sleep(SLOW);
+		}
+		if q_type != QueryType::A {
+			return Err(Error::other("Only A records here"));
+		}
+		let mut packet = DNSPacket::new();
+		packet.header.response = true;
+		packet.answers.push(DNSRecord::A { domain: qname.to_string(), addr: ADDR, ttl: TransientTTL(60) });
+		Ok(packet)
+	}
+}
+
+fn query(name: &str, id: u16) -> Vec<u8> {
+	let client = DNSClient::new();
+	let mut query = client.build_query(name, QueryType::A, true);
+	query.header.id = id;
+	let mut buffer = VectorPacketBuffer::new();
+	query.write(&mut buffer).unwrap();
+	let mut data = Vec::new();
+	write_tcp_message(&mut data, &buffer.into_inner()).unwrap();
+	data
+}
+
+fn listen() -> SocketAddr {
+	let mut context = ServerContext::new();
+	context.listen_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+	context.health_check_interval = None;
+	context.tcp_idle_timeout = IDLE_TIMEOUT;
+	let addr = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap();
+	DNSTcpServer::with_resolvers(Arc::new(context), SlowResolvers).with_listener(addr, ServerRole::Full).run_server().unwrap();
+	addr
+}
+
+fn read_response(stream: &mut TcpStream) -> DNSPacket {
+	let message = read_tcp_message(stream).unwrap();
+	DNSPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(message)).unwrap()
+}
+
+#[test]
+fn split_query_while_pending() {
+	let mut stream = TcpStream::connect(listen()).unwrap();
+	stream.set_read_timeout(Some(SLOW * 3)).unwrap();
+	stream.write_all(&query("slow.example.com", 1)).unwrap();
+
+	let split = query("fast.example.com", 2);
+	stream.write_all(&split[..7]).unwrap();
+	thread::sleep(IDLE_TIMEOUT / 2);
+	stream.write_all(&split[7..]).unwrap();
+
+	let first = read_response(&mut stream);
+	let second = read_response(&mut stream);
+	let mut ids = [first.header.id, second.header.id];
+	ids.sort();
+	assert_eq!(ids, [1, 2]);
+	for response in [first, second] {
+		assert_eq!(response.header.rescode, ResultCode::NOERROR);
+		assert!(response.answers.iter().any(|record| matches!(record, DNSRecord::A { addr, .. } if *addr == ADDR)));
+	}
+}
+
+#[test]
+fn trickled_query_is_cut_off() {
+	let mut stream = TcpStream::connect(listen()).unwrap();
+	stream.set_read_timeout(Some(SLOW * 3)).unwrap();
+
+	// Every byte comes well within the idle timeout, the whole message doesn't, and isn't
+	// answered...
+	for byte in query("fast.example.com", 1) {
+		if stream.write_all(&[byte]).is_err() {
+			break;
+		}
+		thread::sleep(IDLE_TIMEOUT / 4);
+	}
+	let mut rest = Vec::new();
+	assert_eq!(stream.read_to_end(&mut rest).unwrap_or(0), 0);
+}